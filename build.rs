@@ -1,11 +1,94 @@
 use rustc_cfg::Cfg;
 use std::env;
+use std::fs;
+use std::path::Path;
 use std::process::Command;
 
+/// Write the symbol table embedded in the kernel (see `symbols`), from the output of
+/// `nm -n -S --defined-only` of a first build of the kernel at the path in `KERNEL_SYMBOLS`, or an
+/// empty one.
+///
+/// The table starts with `KSYM` and the number of functions, as a little-endian `u32`. Each
+/// function follows, sorted by address, as its address as a `u64`, then its size, the offset of
+/// its name and the length of its name as `u32`s, and the names come last.
+fn symbols(out_dir: &str) {
+    println!("cargo:rerun-if-env-changed=KERNEL_SYMBOLS");
+
+    let mut table = Vec::new();
+    if let Some(path) = env::var("KERNEL_SYMBOLS").ok().filter(|path| !path.is_empty()) {
+        println!("cargo:rerun-if-changed={}", path);
+
+        let nm = fs::read_to_string(&path).expect("failed to read KERNEL_SYMBOLS");
+        let mut functions = Vec::new();
+        for line in nm.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (address, size, kind, name) = match fields[..] {
+                [address, size, kind, name] => (address, Some(size), kind, name),
+                [address, kind, name] => (address, None, kind, name),
+                _ => continue,
+            };
+            if !matches!(kind, "t" | "T" | "w" | "W") {
+                continue;
+            }
+            let address = u64::from_str_radix(address, 16).expect("invalid address in KERNEL_SYMBOLS");
+            let size = size.map(|size| u64::from_str_radix(size, 16).expect("invalid size in KERNEL_SYMBOLS"));
+            functions.push((address, size, name));
+        }
+        functions.sort_by_key(|&(address, _, _)| address);
+        functions.dedup_by_key(|&mut (address, _, _)| address);
+
+        let mut names = Vec::new();
+        table.extend_from_slice(b"KSYM");
+        table.extend_from_slice(&(functions.len() as u32).to_le_bytes());
+        for (i, &(address, size, name)) in functions.iter().enumerate() {
+            // Functions without a size end where the next one starts
+            let size = size.or_else(|| functions.get(i + 1).map(|&(next, _, _)| next - address)).unwrap_or(0);
+            table.extend_from_slice(&address.to_le_bytes());
+            table.extend_from_slice(&(size as u32).to_le_bytes());
+            table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+            table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            names.extend_from_slice(name.as_bytes());
+        }
+        table.extend_from_slice(&names);
+    }
+
+    fs::write(format!("{}/symbols", out_dir), table).expect("failed to write symbols");
+}
+
+/// Run git with `args`, returning its trimmed output, or `None` if it failed
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_string())
+}
+
 fn main() {
     println!("cargo:rustc-env=TARGET={}", env::var("TARGET").unwrap());
 
+    // The git revision reported by `uname`, empty if not built from a git checkout
+    let revision = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_default();
+    println!("cargo:rustc-env=GIT_REVISION={}", revision);
+    // Rebuild when HEAD moves, whether it is detached or the branch it points to changes
+    if let Some(head) = git(&["rev-parse", "--git-path", "HEAD"]) {
+        println!("cargo:rerun-if-changed={}", head);
+    }
+    if let Some(branch) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        // The branch is stored loose or packed. Paths which do not exist would rerun this every
+        // build, so they are left out.
+        for path in [branch.as_str(), "packed-refs"] {
+            if let Some(path) = git(&["rev-parse", "--git-path", path]).filter(|path| Path::new(path).exists()) {
+                println!("cargo:rerun-if-changed={}", path);
+            }
+        }
+    }
+
     let out_dir = env::var("OUT_DIR").unwrap();
+    symbols(&out_dir);
+
     let cfg = Cfg::new(env::var_os("TARGET").unwrap()).unwrap();
     match cfg.target_arch.as_str() {
         "aarch64" => {
@@ -43,6 +126,18 @@ fn main() {
             if ! status.success() {
                 panic!("nasm failed with exit status {}", status);
             }
+
+            println!("cargo:rerun-if-changed=src/asm/x86_64/kexec.asm");
+
+            let status = Command::new("nasm")
+                .arg("-f").arg("bin")
+                .arg("-o").arg(format!("{}/kexec", out_dir))
+                .arg("src/asm/x86_64/kexec.asm")
+                .status()
+                .expect("failed to run nasm");
+            if ! status.success() {
+                panic!("nasm failed with exit status {}", status);
+            }
         }
         _ => (),
     }
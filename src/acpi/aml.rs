@@ -0,0 +1,329 @@
+//! # AML
+//! The ACPI namespace, loaded from the AML of the DSDT and SSDTs and evaluated with the `aml`
+//! crate. The kernel evaluates the objects it uses itself, the sleep types of `\_S3` and `\_S5`
+//! (see `power`) and the performance states of `_PSS` (see `cpufreq`), and userspace evaluates
+//! others, such as `_PRT` for PCI interrupt routing, or the methods of batteries and AC adapters,
+//! through `acpi:aml` (see `scheme::acpi`).
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::ptr;
+
+use ::aml::{AmlContext, AmlError, AmlName, Args, DebugVerbosity, Handler};
+
+pub use ::aml::AmlValue;
+
+use crate::log::{info, warn};
+use crate::paging::entry::EntryFlags;
+use crate::paging::huge;
+use crate::paging::{KernelMapper, PageFlags, PhysicalAddress, RmmA, RmmArch};
+use crate::sync::Mutex;
+use crate::syscall::error::{Error, Result, EINVAL, EIO, ENODEV, ENOENT};
+use crate::syscall::io::{Io, Pio};
+use crate::time;
+
+use super::fadt::FADT;
+use super::{find_sdt, get_sdt};
+
+/// The namespace, once loaded. Evaluation holds it, so methods cannot switch contexts.
+static AML: Mutex<Option<AmlContext>> = Mutex::new(None);
+
+/// Ports of PCI configuration mechanism #1
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+
+/// Access to memory, ports and PCI configuration space for the operation regions of AML
+struct KernelHandler;
+
+impl KernelHandler {
+    /// The kernel address of physical `address`, mapping its page uncached if it is not yet
+    fn virt(address: usize) -> usize {
+        let virt = RmmA::phys_to_virt(PhysicalAddress::new(address));
+        let mut mapper = KernelMapper::lock();
+        if let Some(mapper) = mapper.get_mut() {
+            if huge::translate(mapper, virt).is_none() {
+                let base = PhysicalAddress::new(crate::paging::round_down_pages(address));
+                let flags = PageFlags::new().write(true).custom_flag(EntryFlags::NO_CACHE.bits(), true);
+                if let Ok((_, flush)) = unsafe { mapper.map_linearly(base, flags) } {
+                    flush.flush();
+                }
+            }
+        }
+        virt.data()
+    }
+
+    fn read<T>(address: usize) -> T {
+        unsafe { ptr::read_volatile(Self::virt(address) as *const T) }
+    }
+
+    fn write<T>(address: usize, value: T) {
+        unsafe { ptr::write_volatile(Self::virt(address) as *mut T, value) }
+    }
+
+    /// Select a register of a PCI function, if configuration mechanism #1 reaches it
+    fn select_pci(segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> bool {
+        if segment != 0 || offset > 0xFF {
+            return false;
+        }
+        let address = 1 << 31
+            | u32::from(bus) << 16
+            | u32::from(device & 0x1F) << 11
+            | u32::from(function & 0x7) << 8
+            | u32::from(offset & 0xFC);
+        Pio::<u32>::new(PCI_CONFIG_ADDRESS).write(address);
+        true
+    }
+}
+
+impl Handler for KernelHandler {
+    fn read_u8(&self, address: usize) -> u8 { Self::read(address) }
+    fn read_u16(&self, address: usize) -> u16 { Self::read(address) }
+    fn read_u32(&self, address: usize) -> u32 { Self::read(address) }
+    fn read_u64(&self, address: usize) -> u64 { Self::read(address) }
+
+    fn write_u8(&mut self, address: usize, value: u8) { Self::write(address, value) }
+    fn write_u16(&mut self, address: usize, value: u16) { Self::write(address, value) }
+    fn write_u32(&mut self, address: usize, value: u32) { Self::write(address, value) }
+    fn write_u64(&mut self, address: usize, value: u64) { Self::write(address, value) }
+
+    fn read_io_u8(&self, port: u16) -> u8 { Pio::<u8>::new(port).read() }
+    fn read_io_u16(&self, port: u16) -> u16 { Pio::<u16>::new(port).read() }
+    fn read_io_u32(&self, port: u16) -> u32 { Pio::<u32>::new(port).read() }
+
+    fn write_io_u8(&self, port: u16, value: u8) { Pio::<u8>::new(port).write(value) }
+    fn write_io_u16(&self, port: u16, value: u16) { Pio::<u16>::new(port).write(value) }
+    fn write_io_u32(&self, port: u16, value: u32) { Pio::<u32>::new(port).write(value) }
+
+    fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        if !Self::select_pci(segment, bus, device, function, offset) {
+            return u8::MAX;
+        }
+        Pio::<u8>::new(PCI_CONFIG_DATA + (offset & 3)).read()
+    }
+    fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        if !Self::select_pci(segment, bus, device, function, offset) {
+            return u16::MAX;
+        }
+        Pio::<u16>::new(PCI_CONFIG_DATA + (offset & 2)).read()
+    }
+    fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        if !Self::select_pci(segment, bus, device, function, offset) {
+            return u32::MAX;
+        }
+        Pio::<u32>::new(PCI_CONFIG_DATA).read()
+    }
+
+    fn write_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u8) {
+        if Self::select_pci(segment, bus, device, function, offset) {
+            Pio::<u8>::new(PCI_CONFIG_DATA + (offset & 3)).write(value);
+        }
+    }
+    fn write_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u16) {
+        if Self::select_pci(segment, bus, device, function, offset) {
+            Pio::<u16>::new(PCI_CONFIG_DATA + (offset & 2)).write(value);
+        }
+    }
+    fn write_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+        if Self::select_pci(segment, bus, device, function, offset) {
+            Pio::<u32>::new(PCI_CONFIG_DATA).write(value);
+        }
+    }
+
+    fn stall(&self, microseconds: u64) {
+        let end = time::monotonic() + u128::from(microseconds) * 1000;
+        while time::monotonic() < end {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn sleep(&self, milliseconds: u64) {
+        // The namespace is held while evaluating, so this cannot switch contexts either
+        self.stall(milliseconds * 1000);
+    }
+}
+
+/// Load the namespace from the DSDT and SSDTs, run the `_INI` methods of its devices, and use the
+/// objects the kernel needs. Called once the ACPI tables are read.
+pub fn init() {
+    let Some(dsdt_address) = FADT.get().map(|fadt| fadt.dsdt_address()).filter(|&address| address != 0) else {
+        return;
+    };
+
+    let mut context = AmlContext::new(Box::new(KernelHandler), DebugVerbosity::None);
+    let dsdt = get_sdt(dsdt_address, &mut KernelMapper::lock());
+    if let Err(err) = context.parse_table(dsdt.data()) {
+        warn!("Failed to parse the DSDT: {:?}", err);
+        return;
+    }
+    for ssdt in find_sdt("SSDT") {
+        if let Err(err) = context.parse_table(ssdt.data()) {
+            warn!("Failed to parse an SSDT: {:?}", err);
+        }
+    }
+    if let Err(err) = context.initialize_objects() {
+        warn!("Failed to initialize the ACPI namespace: {:?}", err);
+    }
+    *AML.lock() = Some(context);
+    info!("ACPI namespace loaded");
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if let Some(sleep_types) = sleep_types("\\_S3") {
+            crate::power::set_s3_sleep_types(Some(sleep_types));
+        }
+        if let Some(sleep_types) = sleep_types("\\_S5") {
+            crate::power::set_s5_sleep_types(Some(sleep_types));
+        }
+        if let Some(states) = pss() {
+            if !crate::arch::cpufreq::set_pss(states) {
+                warn!("Ignoring the _PSS states, which are not sorted fastest first");
+            }
+        }
+    }
+}
+
+/// Evaluate the object at `path`, invoking it with `args` if it is a method
+pub fn evaluate(path: &str, args: Vec<AmlValue>) -> Result<AmlValue> {
+    let name = AmlName::from_str(path).map_err(|_| Error::new(EINVAL))?;
+    let args = Args::from_list(args).map_err(|_| Error::new(EINVAL))?;
+
+    let mut aml = AML.lock();
+    let context = aml.as_mut().ok_or(Error::new(ENODEV))?;
+    context.invoke_method(&name, args).map_err(|err| match err {
+        AmlError::ValueDoesNotExist(_) | AmlError::LevelDoesNotExist(_) => Error::new(ENOENT),
+        err => {
+            warn!("Failed to evaluate {}: {:?}", path, err);
+            Error::new(EIO)
+        }
+    })
+}
+
+/// The paths of the objects in the namespace
+pub fn objects() -> Vec<String> {
+    let mut objects = Vec::new();
+    if let Some(context) = AML.lock().as_mut() {
+        let _ = context.namespace.traverse(|name, level| {
+            let scope = name.as_string();
+            for seg in level.values.keys() {
+                let separator = if scope.ends_with('\\') { "" } else { "." };
+                objects.push(format!("{}{}{}", scope, separator, seg.as_str()));
+            }
+            Ok(true)
+        });
+    }
+    objects
+}
+
+/// Paths of the levels of the namespace of type `typ`
+fn levels(typ: ::aml::LevelType) -> Vec<String> {
+    let mut levels = Vec::new();
+    if let Some(context) = AML.lock().as_mut() {
+        let _ = context.namespace.traverse(|name, level| {
+            if level.typ == typ {
+                levels.push(name.as_string());
+            }
+            Ok(true)
+        });
+    }
+    levels
+}
+
+/// The compressed EISA ID of a `_HID` such as `PNP0C0D`, as AML encodes them
+fn eisa_id(hid: &str) -> Option<u64> {
+    let bytes = hid.as_bytes();
+    if bytes.len() != 7 || !bytes[..3].iter().all(u8::is_ascii_uppercase) {
+        return None;
+    }
+    let vendor = bytes[..3].iter().fold(0_u32, |vendor, &c| vendor << 5 | u32::from(c - 0x40));
+    let product = u32::from_str_radix(&hid[3..], 16).ok()?;
+    Some(u64::from((vendor << 16 | product).swap_bytes()))
+}
+
+/// Paths of the devices whose `_HID` is `hid`
+pub fn devices(hid: &str) -> Vec<String> {
+    let eisa_id = eisa_id(hid);
+    levels(::aml::LevelType::Device).into_iter().filter(|device| {
+        match evaluate(&format!("{}._HID", device), Vec::new()) {
+            Ok(AmlValue::String(value)) => value == hid,
+            Ok(AmlValue::Integer(value)) => Some(value) == eisa_id,
+            _ => false,
+        }
+    }).collect()
+}
+
+/// The paths of the processor objects, and of the processor devices of newer firmware
+#[cfg(target_arch = "x86_64")]
+fn processors() -> Vec<String> {
+    let mut processors = levels(::aml::LevelType::Processor);
+    processors.extend(devices("ACPI0007"));
+    processors
+}
+
+/// SLP_TYPa and SLP_TYPb of the sleep state object at `path`
+#[cfg(target_arch = "x86_64")]
+fn sleep_types(path: &str) -> Option<(u8, u8)> {
+    match evaluate(path, Vec::new()).ok()? {
+        AmlValue::Package(values) => match values.as_slice() {
+            [AmlValue::Integer(slp_typa), AmlValue::Integer(slp_typb), ..] => Some(((*slp_typa & 7) as u8, (*slp_typb & 7) as u8)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The performance states of the first processor with a `_PSS` object
+#[cfg(target_arch = "x86_64")]
+fn pss() -> Option<Vec<crate::arch::cpufreq::PssState>> {
+    let states = processors().into_iter().find_map(|processor| match evaluate(&format!("{}._PSS", processor), Vec::new()) {
+        Ok(AmlValue::Package(states)) if !states.is_empty() => Some(states),
+        _ => None,
+    })?;
+    // Frequency in MHz, power, latencies, control and status
+    states.iter().map(|state| match state {
+        AmlValue::Package(values) => match values.as_slice() {
+            [AmlValue::Integer(mhz), _, _, _, AmlValue::Integer(control), _] => Some(crate::arch::cpufreq::PssState {
+                mhz: u32::try_from(*mhz).ok()?,
+                control: *control,
+            }),
+            _ => None,
+        },
+        _ => None,
+    }).collect()
+}
+
+/// Write `value` as ASL-like text
+pub fn render(string: &mut String, value: &AmlValue) {
+    match value {
+        AmlValue::Boolean(value) => {
+            let _ = write!(string, "{}", value);
+        }
+        AmlValue::Integer(value) => {
+            let _ = write!(string, "{:#x}", value);
+        }
+        AmlValue::String(value) => {
+            let _ = write!(string, "{:?}", value);
+        }
+        AmlValue::Buffer(bytes) => {
+            string.push_str("Buffer {");
+            for (i, byte) in bytes.lock().iter().enumerate() {
+                let _ = write!(string, "{}{:#04x}", if i == 0 { "" } else { ", " }, byte);
+            }
+            string.push('}');
+        }
+        AmlValue::Package(values) => {
+            string.push_str("Package {");
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    string.push_str(", ");
+                }
+                render(string, value);
+            }
+            string.push('}');
+        }
+        value => {
+            let _ = write!(string, "{:?}", value.type_of());
+        }
+    }
+}
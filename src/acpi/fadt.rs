@@ -0,0 +1,264 @@
+use core::{mem, ptr};
+
+use spin::Once;
+
+use crate::paging::entry::EntryFlags;
+use crate::paging::{KernelMapper, PageFlags, PhysicalAddress, RmmA, RmmArch};
+use crate::syscall::io::{Io, Pio};
+
+use super::hpet::GenericAddressStructure;
+use super::sdt::Sdt;
+use super::{find_sdt, map_linearly};
+
+/// Sleep enable and sleep type of the PM1 control registers
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CNT_SLP_TYP_MASK: u16 = 0b111 << PM1_CNT_SLP_TYP_SHIFT;
+/// Wake status of the PM1 status registers, set by the hardware once the system woke up
+const PM1_STS_WAK: u16 = 1 << 15;
+
+/// Whether the reset register is supported, in the FADT flags
+const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+/// Address spaces of the reset register
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+const ADDRESS_SPACE_IO: u8 = 1;
+const ADDRESS_SPACE_PCI: u8 = 2;
+
+pub static FADT: Once<Fadt> = Once::new();
+
+/// The Fixed ACPI Description Table, up to the 64-bit table addresses. Fields past the length of
+/// the table, as with ACPI 1.0, read as zero.
+#[repr(packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct Fadt {
+    pub header: Sdt,
+
+    pub firmware_control: u32,
+    pub dsdt: u32,
+    _reserved: u8,
+    pub preferred_power_management: u8,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4_bios_req: u8,
+    pub pstate_control: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm2_control_block: u32,
+    pub pm_timer_block: u32,
+    pub gpe0_block: u32,
+    pub gpe1_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub pm2_control_length: u8,
+    pub pm_timer_length: u8,
+    pub gpe0_length: u8,
+    pub gpe1_length: u8,
+    pub gpe1_base: u8,
+    pub c_state_control: u8,
+    pub worst_c2_latency: u16,
+    pub worst_c3_latency: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+    pub century: u8,
+    pub boot_architecture_flags: u16,
+    _reserved2: u8,
+    pub flags: u32,
+    pub reset_reg: GenericAddressStructure,
+    pub reset_value: u8,
+    pub arm_boot_architecture_flags: u16,
+    pub minor_version: u8,
+    pub x_firmware_control: u64,
+    pub x_dsdt: u64,
+}
+
+/// The Firmware ACPI Control Structure, with the waking vector the firmware jumps to when resuming
+/// from a sleep state
+#[repr(packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct Facs {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub hardware_signature: u32,
+    pub firmware_waking_vector: u32,
+    pub global_lock: u32,
+    pub flags: u32,
+    pub x_firmware_waking_vector: u64,
+    pub version: u8,
+}
+
+impl Fadt {
+    pub fn init() {
+        let fadt_sdt = find_sdt("FACP");
+        let fadt = if fadt_sdt.len() == 1 {
+            Fadt::new(fadt_sdt[0])
+        } else {
+            println!("Unable to find FADT");
+            return;
+        };
+
+        if let Some(fadt) = fadt {
+            println!("  FADT: PM1a {:X} PM1b {:X} FACS {:X}", { fadt.pm1a_control_block }, { fadt.pm1b_control_block }, fadt.facs_address());
+
+            if fadt.facs_address() != 0 {
+                unsafe {
+                    let mut mapper = KernelMapper::lock();
+                    let mapper = mapper.get_mut().expect("KernelMapper locked re-entrant while mapping FACS");
+                    map_linearly(PhysicalAddress::new(fadt.facs_address()), mem::size_of::<Facs>(), mapper);
+                }
+            }
+
+            if fadt.has_reset_reg() && fadt.reset_reg.address_space == ADDRESS_SPACE_MEMORY {
+                unsafe {
+                    let mut mapper = KernelMapper::lock();
+                    let mapper = mapper.get_mut().expect("KernelMapper locked re-entrant while mapping reset register");
+                    let base = PhysicalAddress::new(crate::paging::round_down_pages(fadt.reset_reg.address as usize));
+                    // The page may already be mapped
+                    if let Ok((_, flush)) = mapper.map_linearly(base, PageFlags::new().write(true).custom_flag(EntryFlags::NO_CACHE.bits(), true)) {
+                        flush.flush();
+                    }
+                }
+            }
+
+            FADT.call_once(|| fadt);
+        }
+    }
+
+    pub fn new(sdt: &'static Sdt) -> Option<Fadt> {
+        let length = sdt.length as usize;
+        if &sdt.signature != b"FACP" || length < mem::size_of::<Sdt>() {
+            return None;
+        }
+
+        // Older tables are shorter, copy what there is and leave the rest zeroed
+        let mut fadt: Fadt = unsafe { mem::zeroed() };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                sdt as *const Sdt as *const u8,
+                &mut fadt as *mut Fadt as *mut u8,
+                length.min(mem::size_of::<Fadt>()),
+            );
+        }
+        Some(fadt)
+    }
+
+    /// Physical address of the FACS, or zero if there is none
+    pub fn facs_address(&self) -> usize {
+        match self.x_firmware_control {
+            0 => self.firmware_control as usize,
+            address => address as usize,
+        }
+    }
+
+    /// Physical address of the DSDT, or zero if there is none
+    pub fn dsdt_address(&self) -> usize {
+        match self.x_dsdt {
+            0 => self.dsdt as usize,
+            address => address as usize,
+        }
+    }
+
+    /// The FACS, mapped by `init`
+    pub fn facs(&self) -> Option<*mut Facs> {
+        match self.facs_address() {
+            0 => None,
+            address => Some(RmmA::phys_to_virt(PhysicalAddress::new(address)).data() as *mut Facs),
+        }
+    }
+
+    /// Set the real mode address the firmware jumps to when resuming from a sleep state
+    pub unsafe fn set_waking_vector(&self, address: u32) -> bool {
+        let facs = match self.facs() {
+            Some(facs) => facs,
+            None => return false,
+        };
+        ptr::addr_of_mut!((*facs).firmware_waking_vector).write_unaligned(address);
+        // The 64-bit vector takes precedence, and would be entered in protected mode
+        if { (*facs).length } as usize >= mem::size_of::<Facs>() {
+            ptr::addr_of_mut!((*facs).x_firmware_waking_vector).write_unaligned(0);
+        }
+        true
+    }
+
+    /// Whether the reset register can be used to reset the system
+    pub fn has_reset_reg(&self) -> bool {
+        self.flags & FLAG_RESET_REG_SUP == FLAG_RESET_REG_SUP && { self.reset_reg.address } != 0
+    }
+
+    /// Reset the system through the reset register. Returns if there is none, or if the system did
+    /// not reset.
+    pub unsafe fn reset(&self) {
+        if !self.has_reset_reg() {
+            return;
+        }
+        let address = self.reset_reg.address;
+        match self.reset_reg.address_space {
+            ADDRESS_SPACE_MEMORY => {
+                let virt = RmmA::phys_to_virt(PhysicalAddress::new(address as usize));
+                ptr::write_volatile(virt.data() as *mut u8, self.reset_value);
+            }
+            ADDRESS_SPACE_IO => Pio::<u8>::new(address as u16).write(self.reset_value),
+            ADDRESS_SPACE_PCI => {
+                // Device, function and offset of a function on bus 0, through configuration mechanism #1
+                let device = ((address >> 32) & 0x1F) as u32;
+                let function = ((address >> 16) & 0x7) as u32;
+                let offset = (address & 0xFF) as u32;
+                Pio::<u32>::new(0xCF8).write(1 << 31 | device << 11 | function << 8 | offset & 0xFC);
+                Pio::<u8>::new(0xCFC + (offset & 3) as u16).write(self.reset_value);
+            }
+            _ => return,
+        }
+
+        // The reset is not instant
+        for _ in 0..1_000_000 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Enter the sleep state with the SLP_TYPa and SLP_TYPb values of its `\_Sx` object. Returns if
+    /// the state was entered and left again, as with S1, or if it could not be entered.
+    pub unsafe fn enter_sleep_state(&self, slp_typa: u8, slp_typb: u8) {
+        let controls = [
+            (self.pm1a_event_block, self.pm1a_control_block, slp_typa),
+            (self.pm1b_event_block, self.pm1b_control_block, slp_typb),
+        ];
+
+        // Clear the wake status, and set the sleep type before enabling sleep
+        for &(event_block, control_block, slp_typ) in controls.iter().filter(|&&(_, control, _)| control != 0) {
+            if event_block != 0 {
+                Pio::<u16>::new(event_block as u16).write(PM1_STS_WAK);
+            }
+            let mut control = Pio::<u16>::new(control_block as u16);
+            let value = control.read() & !(PM1_CNT_SLP_TYP_MASK | PM1_CNT_SLP_EN);
+            control.write(value | (u16::from(slp_typ) << PM1_CNT_SLP_TYP_SHIFT));
+        }
+
+        // Memory must be consistent before the caches lose power
+        core::arch::asm!("wbinvd");
+
+        for &(_, control_block, _) in controls.iter().filter(|&&(_, control, _)| control != 0) {
+            let mut control = Pio::<u16>::new(control_block as u16);
+            let value = control.read();
+            control.write(value | PM1_CNT_SLP_EN);
+        }
+
+        // Wait for the system to either lose power, or wake up again
+        if self.pm1a_event_block != 0 {
+            let status = Pio::<u16>::new(self.pm1a_event_block as u16);
+            for _ in 0..1_000_000 {
+                if status.read() & PM1_STS_WAK == PM1_STS_WAK {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
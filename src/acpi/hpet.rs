@@ -12,10 +12,10 @@ use super::{ACPI_TABLE, find_sdt};
 #[repr(packed)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct GenericAddressStructure {
-    _address_space: u8,
-    _bit_width: u8,
-    _bit_offset: u8,
-    _access_size: u8,
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
     pub address: u64,
 }
 
@@ -1,6 +1,6 @@
 use core::mem;
 
-use crate::memory::{allocate_frames, Frame};
+use crate::memory::{allocate_frames, zone, Frame};
 use crate::paging::{KernelMapper, Page, PageFlags, PhysicalAddress, RmmA, RmmArch, VirtualAddress};
 
 use super::sdt::Sdt;
@@ -21,12 +21,111 @@ pub struct Madt {
     pub flags: u32
 }
 
-const TRAMPOLINE: usize = 0x8000;
+pub const TRAMPOLINE: usize = 0x8000;
 static TRAMPOLINE_DATA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/trampoline"));
 
 pub static mut MADT: Option<Madt> = None;
 pub const FLAG_PCAT: u32 = 1;
 
+/// Map the trampoline at its physical address in the kernel page table and write it there,
+/// returning the physical address of the kernel page table for it to load
+pub unsafe fn map_trampoline() -> usize {
+    let trampoline_frame = Frame::containing_address(PhysicalAddress::new(TRAMPOLINE));
+    let trampoline_page = Page::containing_address(VirtualAddress::new(TRAMPOLINE));
+    let (result, page_table_physaddr) = {
+        //TODO: do not have writable and executable!
+        let mut mapper = KernelMapper::lock();
+
+        let result = mapper
+            .get_mut()
+            .expect("expected kernel page table not to be recursively locked while mapping trampoline")
+            .map_phys(trampoline_page.start_address(), trampoline_frame.start_address(), PageFlags::new().execute(true).write(true))
+            .expect("failed to map trampoline");
+
+        (result, mapper.table().phys().data())
+    };
+    result.flush();
+
+    // Write trampoline, make sure TRAMPOLINE page is free for use
+    for i in 0..TRAMPOLINE_DATA.len() {
+        atomic_store_seqcst((TRAMPOLINE as *mut u8).add(i), TRAMPOLINE_DATA[i]);
+    }
+
+    page_table_physaddr
+}
+
+/// Unmap the trampoline mapped by `map_trampoline`
+pub unsafe fn unmap_trampoline() {
+    let trampoline_page = Page::containing_address(VirtualAddress::new(TRAMPOLINE));
+    let (_frame, _, flush) = KernelMapper::lock()
+        .get_mut()
+        .expect("expected kernel page table not to be recursively locked while unmapping trampoline")
+        .unmap_phys(trampoline_page.start_address(), true)
+        .expect("failed to unmap trampoline page");
+    flush.flush();
+}
+
+/// Set the arguments of the mapped trampoline. It enters long mode with `page_table`, switches to
+/// `stack_end` and jumps to `code`, with the address of `arg` as the first argument.
+pub unsafe fn set_trampoline_args(arg: u64, page_table: usize, stack_start: usize, stack_end: usize, code: usize) {
+    let ap_ready = (TRAMPOLINE + 8) as *mut u64;
+    let ap_cpu_id = ap_ready.offset(1);
+    let ap_page_table = ap_ready.offset(2);
+    let ap_stack_start = ap_ready.offset(3);
+    let ap_stack_end = ap_ready.offset(4);
+    let ap_code = ap_ready.offset(5);
+
+    // Set the ap_ready to 0, volatile
+    atomic_store_seqcst(ap_ready, 0);
+    atomic_store_seqcst(ap_cpu_id, arg);
+    atomic_store_seqcst(ap_page_table, page_table as u64);
+    atomic_store_seqcst(ap_stack_start, stack_start as u64);
+    atomic_store_seqcst(ap_stack_end, stack_end as u64);
+    atomic_store_seqcst(ap_code, code as u64);
+}
+
+/// Start the AP with the local APIC ID `apic_id` in the mapped trampoline (see
+/// `set_trampoline_args`), returning once it left the trampoline
+pub unsafe fn start_ap(apic_id: u32, arg: u64, page_table: usize, stack_start: usize, stack_end: usize, code: usize) {
+    let local_apic = &mut LOCAL_APIC;
+
+    set_trampoline_args(arg, page_table, stack_start, stack_end, code);
+
+    // Send INIT IPI
+    {
+        let mut icr = 0x4500;
+        if local_apic.x2 {
+            icr |= (apic_id as u64) << 32;
+        } else {
+            icr |= (apic_id as u64) << 56;
+        }
+        print!(" IPI...");
+        local_apic.set_icr(icr);
+    }
+
+    // Send START IPI
+    {
+        //Start at 0x0800:0000 => 0x8000. Hopefully the bootloader code is still there
+        let ap_segment = (TRAMPOLINE >> 12) & 0xFF;
+        let mut icr = 0x4600 | ap_segment as u64;
+
+        if local_apic.x2 {
+            icr |= (apic_id as u64) << 32;
+        } else {
+            icr |= (apic_id as u64) << 56;
+        }
+
+        print!(" SIPI...");
+        local_apic.set_icr(icr);
+    }
+
+    // Wait for trampoline ready
+    print!(" Wait...");
+    while atomic_load_seqcst((TRAMPOLINE + 8) as *const u64) == 0 {
+        interrupt::pause();
+    }
+}
+
 impl Madt {
     pub fn init() {
         let madt_sdt = find_sdt("APIC");
@@ -52,30 +151,14 @@ impl Madt {
                 println!("    XAPIC {}: {:>08X}", me, local_apic.address);
             }
 
-            if cfg!(feature = "multi_core") {
-                // Map trampoline
-                let trampoline_frame = Frame::containing_address(PhysicalAddress::new(TRAMPOLINE));
-                let trampoline_page = Page::containing_address(VirtualAddress::new(TRAMPOLINE));
-                let (result, page_table_physaddr) = unsafe {
-                    //TODO: do not have writable and executable!
-                    let mut mapper = KernelMapper::lock();
-
-                    let result = mapper
-                        .get_mut()
-                        .expect("expected kernel page table not to be recursively locked while initializing MADT")
-                        .map_phys(trampoline_page.start_address(), trampoline_frame.start_address(), PageFlags::new().execute(true).write(true))
-                        .expect("failed to map trampoline");
-
-                    (result, mapper.table().phys().data())
-                };
-                result.flush();
+            // The trampoline is assembled for a fixed address, take it out of the low zone. It is
+            // also the waking vector when resuming from suspend to RAM.
+            if !zone::claim(PhysicalAddress::new(TRAMPOLINE)) {
+                println!("    Trampoline {:>08X} is not free memory, starting APs anyway", TRAMPOLINE);
+            }
 
-                // Write trampoline, make sure TRAMPOLINE page is free for use
-                for i in 0..TRAMPOLINE_DATA.len() {
-                    unsafe {
-                        atomic_store_seqcst((TRAMPOLINE as *mut u8).add(i), TRAMPOLINE_DATA[i]);
-                    }
-                }
+            if cfg!(feature = "multi_core") {
+                let page_table_physaddr = unsafe { map_trampoline() };
 
                 for madt_entry in madt.iter() {
                     println!("      {:?}", madt_entry);
@@ -91,57 +174,21 @@ impl Madt {
                                 let stack_start = allocate_frames(64).expect("no more frames in acpi stack_start").start_address().data() + crate::PHYS_OFFSET;
                                 let stack_end = stack_start + 64 * 4096;
 
-                                let ap_ready = (TRAMPOLINE + 8) as *mut u64;
-                                let ap_cpu_id = unsafe { ap_ready.offset(1) };
-                                let ap_page_table = unsafe { ap_ready.offset(2) };
-                                let ap_stack_start = unsafe { ap_ready.offset(3) };
-                                let ap_stack_end = unsafe { ap_ready.offset(4) };
-                                let ap_code = unsafe { ap_ready.offset(5) };
-
-                                // Set the ap_ready to 0, volatile
-                                unsafe { atomic_store_seqcst(ap_ready, 0) };
-                                unsafe { atomic_store_seqcst(ap_cpu_id, ap_local_apic.id as u64) };
-                                unsafe { atomic_store_seqcst(ap_page_table, page_table_physaddr as u64) };
-                                unsafe { atomic_store_seqcst(ap_stack_start, stack_start as u64) };
-                                unsafe { atomic_store_seqcst(ap_stack_end, stack_end as u64) };
-                                unsafe { atomic_store_seqcst(ap_code, kstart_ap as u64) };
                                 AP_READY.store(false, Ordering::SeqCst);
 
                                 print!("        AP {}:", ap_local_apic.id);
 
-                                // Send INIT IPI
-                                {
-                                    let mut icr = 0x4500;
-                                    if local_apic.x2 {
-                                        icr |= (ap_local_apic.id as u64) << 32;
-                                    } else {
-                                        icr |= (ap_local_apic.id as u64) << 56;
-                                    }
-                                    print!(" IPI...");
-                                    local_apic.set_icr(icr);
+                                unsafe {
+                                    start_ap(
+                                        u32::from(ap_local_apic.id),
+                                        u64::from(ap_local_apic.id),
+                                        page_table_physaddr,
+                                        stack_start,
+                                        stack_end,
+                                        kstart_ap as usize,
+                                    );
                                 }
 
-                                // Send START IPI
-                                {
-                                    //Start at 0x0800:0000 => 0x8000. Hopefully the bootloader code is still there
-                                    let ap_segment = (TRAMPOLINE >> 12) & 0xFF;
-                                    let mut icr = 0x4600 | ap_segment as u64;
-
-                                    if local_apic.x2 {
-                                        icr |= (ap_local_apic.id as u64) << 32;
-                                    } else {
-                                        icr |= (ap_local_apic.id as u64) << 56;
-                                    }
-
-                                    print!(" SIPI...");
-                                    local_apic.set_icr(icr);
-                                }
-
-                                // Wait for trampoline ready
-                                print!(" Wait...");
-                                while unsafe { atomic_load_seqcst(ap_ready) } == 0 {
-                                    interrupt::pause();
-                                }
                                 print!(" Trampoline...");
                                 while ! AP_READY.load(Ordering::SeqCst) {
                                     interrupt::pause();
@@ -157,15 +204,7 @@ impl Madt {
                     }
                 }
 
-                // Unmap trampoline
-                let (_frame, _, flush) = unsafe {
-                    KernelMapper::lock()
-                        .get_mut()
-                        .expect("expected kernel page table not to be recursively locked while initializing MADT")
-                        .unmap_phys(trampoline_page.start_address(), true)
-                        .expect("failed to unmap trampoline page")
-                };
-                flush.flush();
+                unsafe { unmap_trampoline(); }
             }
         }
     }
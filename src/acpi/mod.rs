@@ -6,11 +6,13 @@ use alloc::string::String;
 use alloc::vec::Vec;
 use alloc::boxed::Box;
 
-use spin::{Once, RwLock};
+use spin::Once;
 
 use crate::log::info;
 use crate::paging::{KernelMapper, PageFlags, PhysicalAddress, RmmA, RmmArch};
+use crate::sync::RwLock;
 
+use self::fadt::Fadt;
 use self::madt::Madt;
 use self::rsdt::Rsdt;
 use self::sdt::Sdt;
@@ -18,7 +20,10 @@ use self::xsdt::Xsdt;
 use self::hpet::Hpet;
 use self::rxsdt::Rxsdt;
 use self::rsdp::RSDP;
+use self::srat::Srat;
 
+pub mod aml;
+pub mod fadt;
 pub mod hpet;
 pub mod madt;
 mod rsdt;
@@ -26,6 +31,9 @@ pub mod sdt;
 mod xsdt;
 mod rxsdt;
 mod rsdp;
+pub mod sci;
+pub mod slit;
+pub mod srat;
 
 unsafe fn map_linearly(addr: PhysicalAddress, len: usize, mapper: &mut crate::paging::PageMapper) {
     let base = PhysicalAddress::new(crate::paging::round_down_pages(addr.data()));
@@ -142,6 +150,11 @@ pub unsafe fn init(already_supplied_rsdps: Option<(u64, u64)>) {
         // TODO: Let userspace setup HPET, and then provide an interface to specify which timer to
         // use?
         Hpet::init();
+        // TODO: Some day this should also be done by userspace, but frames are allocated long
+        // before that
+        Srat::init();
+        // The fixed hardware registers used to enter sleep states
+        Fadt::init();
     } else {
         println!("NO RSDP FOUND");
     }
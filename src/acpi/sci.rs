@@ -0,0 +1,333 @@
+//! # SCI
+//! The System Control Interrupt, through which ACPI raises fixed events, such as the power and
+//! sleep buttons, and general-purpose events (GPEs), whose `_Lxx` or `_Exx` methods in the `\_GPE`
+//! scope handle them, such as the lid switch changing. The kernel takes the SCI over once
+//! `acpi:events` is first opened (see `scheme::acpi`), and leaves it to userspace until then.
+//!
+//! The interrupt only records events, and disables the GPEs that fired, as methods cannot be
+//! evaluated in it. They are evaluated by the next reader of `acpi:events`, which enables the GPEs
+//! again, and is told of a change of the lid from `_LID` of its device afterwards.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::log::{info, warn};
+use crate::sync::Mutex;
+use crate::syscall::io::{Io, Pio};
+use crate::time;
+
+use super::aml::{self, AmlValue};
+use super::fadt::FADT;
+
+/// Bits of the PM1 status and enable registers
+const PM1_TMR: u16 = 1 << 0;
+const PM1_PWRBTN: u16 = 1 << 8;
+const PM1_SLPBTN: u16 = 1 << 9;
+const PM1_RTC: u16 = 1 << 10;
+
+/// Whether the kernel handles the SCI, in the PM1 control registers
+const PM1_CNT_SCI_EN: u16 = 1 << 0;
+
+/// Whether the power and sleep buttons are control method devices instead of fixed events, in
+/// the FADT flags
+const FLAG_PWR_BUTTON: u32 = 1 << 4;
+const FLAG_SLP_BUTTON: u32 = 1 << 5;
+
+/// Most GPEs, as numbered by ACPI
+const GPES: usize = 256;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    PowerButton,
+    SleepButton,
+    /// The lid opened, or closed
+    Lid(bool),
+    Gpe(u8),
+}
+
+impl Event {
+    pub fn name(self) -> String {
+        match self {
+            Event::PowerButton => String::from("power_button"),
+            Event::SleepButton => String::from("sleep_button"),
+            Event::Lid(true) => String::from("lid open"),
+            Event::Lid(false) => String::from("lid closed"),
+            Event::Gpe(number) => format!("gpe {:#04x}", number),
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Fixed events and GPEs that fired, not yet handled
+static FIXED_PENDING: AtomicU64 = AtomicU64::new(0);
+static GPE_PENDING: [AtomicU64; GPES / 64] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const PENDING: AtomicU64 = AtomicU64::new(0);
+    [PENDING; GPES / 64]
+};
+
+/// GPEs with a method, each numbered and whether it is edge-triggered, and the lid device and
+/// whether it was last open
+static GPES_ENABLED: Mutex<Vec<(u8, bool)>> = Mutex::new(Vec::new());
+static LID: Mutex<Option<(String, bool)>> = Mutex::new(None);
+
+/// The GPE registers, as the status port and enable port of each, and the number of its first GPE
+fn gpe_registers() -> impl Iterator<Item = (u16, u16, usize)> {
+    let blocks = match FADT.get() {
+        Some(fadt) => [
+            (fadt.gpe0_block, u16::from(fadt.gpe0_length) / 2, 0),
+            (fadt.gpe1_block, u16::from(fadt.gpe1_length) / 2, usize::from(fadt.gpe1_base)),
+        ],
+        None => [(0, 0, 0); 2],
+    };
+    blocks.into_iter().filter(|&(block, _, _)| block != 0).flat_map(|(block, half, base)| {
+        (0..half).map(move |i| (block as u16 + i, block as u16 + half + i, base + usize::from(i) * 8))
+    })
+}
+
+/// The status and enable ports of a GPE, and its bit in them
+fn gpe_ports(number: u8) -> Option<(u16, u16, u8)> {
+    let number = usize::from(number);
+    gpe_registers()
+        .find(|&(_, _, base)| (base..base + 8).contains(&number))
+        .map(|(status, enable, base)| (status, enable, (number - base) as u8))
+}
+
+fn set_gpe_enabled(number: u8, enabled: bool) {
+    if let Some((_, enable, bit)) = gpe_ports(number) {
+        let mut port = Pio::<u8>::new(enable);
+        let value = port.read();
+        port.write(if enabled { value | 1 << bit } else { value & !(1 << bit) });
+    }
+}
+
+fn clear_gpe(number: u8) {
+    if let Some((status, _, bit)) = gpe_ports(number) {
+        Pio::<u8>::new(status).write(1 << bit);
+    }
+}
+
+/// The PM1 event blocks, as the status port and enable port of each
+fn pm1_event_blocks() -> impl Iterator<Item = (u16, u16)> {
+    let (blocks, half) = match FADT.get() {
+        Some(fadt) => ([fadt.pm1a_event_block, fadt.pm1b_event_block], u16::from(fadt.pm1_event_length) / 2),
+        None => ([0, 0], 0),
+    };
+    blocks.into_iter().filter(|&block| block != 0).map(move |block| (block as u16, block as u16 + half))
+}
+
+/// Switch to ACPI mode if the firmware did not, for the SCI to be raised
+fn enable_acpi_mode() {
+    let Some(fadt) = FADT.get() else {
+        return;
+    };
+    let control = Pio::<u16>::new(fadt.pm1a_control_block as u16);
+    if fadt.pm1a_control_block == 0 || control.read() & PM1_CNT_SCI_EN != 0 {
+        return;
+    }
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        warn!("ACPI mode cannot be enabled");
+        return;
+    }
+    Pio::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable);
+
+    let initial = time::monotonic();
+    while control.read() & PM1_CNT_SCI_EN == 0 {
+        if time::monotonic() - initial > time::NANOS_PER_SEC {
+            warn!("ACPI mode was not enabled");
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Take the SCI over, enabling the fixed events and the GPEs that have a method. Returns false if
+/// there is no FADT.
+pub fn enable() -> bool {
+    let Some(fadt) = FADT.get() else {
+        return false;
+    };
+    let mut gpes = GPES_ENABLED.lock();
+    if ENABLED.load(Ordering::SeqCst) {
+        return true;
+    }
+
+    enable_acpi_mode();
+
+    // Only the buttons that are not control method devices are fixed events
+    let mut buttons = 0;
+    if fadt.flags & FLAG_PWR_BUTTON == 0 {
+        buttons |= PM1_PWRBTN;
+    }
+    if fadt.flags & FLAG_SLP_BUTTON == 0 {
+        buttons |= PM1_SLPBTN;
+    }
+    for (status, enable) in pm1_event_blocks() {
+        // Clear stale events before enabling them
+        Pio::<u16>::new(status).write(PM1_TMR | PM1_PWRBTN | PM1_SLPBTN | PM1_RTC);
+        let mut port = Pio::<u16>::new(enable);
+        let value = port.read();
+        port.write(value | buttons);
+    }
+
+    for object in aml::objects() {
+        let Some(method) = object.strip_prefix("\\_GPE._") else {
+            continue;
+        };
+        let edge = match method.as_bytes().first() {
+            Some(b'E') => true,
+            Some(b'L') => false,
+            _ => continue,
+        };
+        let Ok(number) = u8::from_str_radix(&method[1..], 16) else {
+            continue;
+        };
+        if gpe_ports(number).is_some() {
+            clear_gpe(number);
+            set_gpe_enabled(number, true);
+            gpes.push((number, edge));
+        }
+    }
+
+    *LID.lock() = aml::devices("PNP0C0D").into_iter().next().map(|path| {
+        let open = lid_open(&path).unwrap_or(true);
+        (path, open)
+    });
+
+    info!("SCI {} handled by the kernel, with {} GPEs", { fadt.sci_interrupt }, gpes.len());
+    ENABLED.store(true, Ordering::SeqCst);
+    drop(gpes);
+
+    // It may have been masked for userspace
+    unsafe { crate::arch::interrupt::irq::acknowledge(usize::from(fadt.sci_interrupt)) };
+    true
+}
+
+fn lid_open(path: &str) -> Option<bool> {
+    match aml::evaluate(&format!("{}._LID", path), Vec::new()) {
+        Ok(AmlValue::Integer(value)) => Some(value != 0),
+        Ok(AmlValue::Boolean(value)) => Some(value),
+        _ => None,
+    }
+}
+
+/// Whether the lid is open, if there is one
+pub fn lid() -> Option<bool> {
+    let path = LID.lock().as_ref()?.0.clone();
+    lid_open(&path)
+}
+
+/// Handle the SCI, if `irq` is it and the kernel took it over. Returns true if it raised an event,
+/// and false for other devices sharing the line.
+pub unsafe fn interrupt(irq: u8) -> bool {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return false;
+    }
+    let Some(fadt) = FADT.get() else {
+        return false;
+    };
+    if u16::from(irq) != fadt.sci_interrupt {
+        return false;
+    }
+
+    let mut handled = false;
+    for (status, enable) in pm1_event_blocks() {
+        let mut status = Pio::<u16>::new(status);
+        let active = status.read() & Pio::<u16>::new(enable).read();
+        if active != 0 {
+            status.write(active);
+            FIXED_PENDING.fetch_or(u64::from(active), Ordering::SeqCst);
+            handled = true;
+        }
+    }
+
+    // GPEs stay disabled, with their status set, until their method ran
+    for (status, enable, base) in gpe_registers() {
+        let mut enable = Pio::<u8>::new(enable);
+        let value = enable.read();
+        let active = Pio::<u8>::new(status).read() & value;
+        if active == 0 {
+            continue;
+        }
+        enable.write(value & !active);
+        for bit in (0..8).filter(|bit| active & 1 << bit != 0) {
+            let number = base + bit;
+            if let Some(pending) = GPE_PENDING.get(number / 64) {
+                pending.fetch_or(1 << (number % 64), Ordering::SeqCst);
+            }
+        }
+        handled = true;
+    }
+
+    if handled {
+        crate::scheme::acpi::sci_notify();
+    }
+    handled
+}
+
+/// Whether events are pending
+pub fn pending() -> bool {
+    FIXED_PENDING.load(Ordering::SeqCst) != 0 || GPE_PENDING.iter().any(|pending| pending.load(Ordering::SeqCst) != 0)
+}
+
+/// Handle the pending events, evaluating the methods of the GPEs that fired
+pub fn process() -> Vec<Event> {
+    let mut events = Vec::new();
+
+    let fixed = FIXED_PENDING.swap(0, Ordering::SeqCst) as u16;
+    if fixed & PM1_PWRBTN != 0 {
+        events.push(Event::PowerButton);
+    }
+    if fixed & PM1_SLPBTN != 0 {
+        events.push(Event::SleepButton);
+    }
+
+    let mut gpe_fired = false;
+    for (index, pending) in GPE_PENDING.iter().enumerate() {
+        let mut bits = pending.swap(0, Ordering::SeqCst);
+        while bits != 0 {
+            let number = (index * 64 + bits.trailing_zeros() as usize) as u8;
+            bits &= bits - 1;
+
+            let edge = GPES_ENABLED.lock().iter().find(|&&(gpe, _)| gpe == number).map(|&(_, edge)| edge);
+            match edge {
+                // Edge-triggered GPEs are cleared before their method runs, and level-triggered
+                // ones after, once the method dealt with their source
+                Some(true) => {
+                    clear_gpe(number);
+                    let _ = aml::evaluate(&format!("\\_GPE._E{:02X}", number), Vec::new());
+                }
+                Some(false) => {
+                    let _ = aml::evaluate(&format!("\\_GPE._L{:02X}", number), Vec::new());
+                    clear_gpe(number);
+                }
+                None => {
+                    // Enabled by something else, and left disabled as nothing handles it
+                    clear_gpe(number);
+                    continue;
+                }
+            }
+            set_gpe_enabled(number, true);
+            events.push(Event::Gpe(number));
+            gpe_fired = true;
+        }
+    }
+
+    // The lid notifies its device through a GPE method
+    if gpe_fired {
+        let mut lid = LID.lock();
+        if let Some((path, open)) = lid.as_mut() {
+            match lid_open(path) {
+                Some(now) if now != *open => {
+                    *open = now;
+                    events.push(Event::Lid(now));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    events
+}
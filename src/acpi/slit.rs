@@ -0,0 +1,52 @@
+use alloc::vec::Vec;
+use core::ptr;
+
+use super::find_sdt;
+use super::sdt::Sdt;
+
+/// The System Locality Information Table, relative distances between proximity domains
+#[derive(Clone, Copy, Debug)]
+pub struct Slit {
+    sdt: &'static Sdt,
+    pub localities: usize,
+}
+
+impl Slit {
+    pub fn new(sdt: &'static Sdt) -> Option<Slit> {
+        if &sdt.signature != b"SLIT" || sdt.data_len() < 8 {
+            return None;
+        }
+        let localities = unsafe { ptr::read_unaligned(sdt.data_address() as *const u64) } as usize;
+        if localities.checked_mul(localities)?.checked_add(8)? > sdt.data_len() {
+            return None;
+        }
+        Some(Slit { sdt, localities })
+    }
+
+    /// Distance from proximity domain `from` to `to`
+    pub fn distance(&self, from: usize, to: usize) -> Option<u8> {
+        if from >= self.localities || to >= self.localities {
+            return None;
+        }
+        Some(self.sdt.data()[8 + from * self.localities + to])
+    }
+
+    /// The matrix of distances between `domains`, in that order, if the SLIT describes them all
+    pub fn distances(domains: &[u32]) -> Option<Vec<u8>> {
+        let slit_sdt = find_sdt("SLIT");
+        let slit = if slit_sdt.len() == 1 {
+            Slit::new(slit_sdt[0])?
+        } else {
+            println!("Unable to find SLIT");
+            return None;
+        };
+
+        let mut distances = Vec::with_capacity(domains.len() * domains.len());
+        for &from in domains {
+            for &to in domains {
+                distances.push(slit.distance(from as usize, to as usize)?);
+            }
+        }
+        Some(distances)
+    }
+}
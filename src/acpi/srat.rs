@@ -0,0 +1,189 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::{mem, ptr};
+
+use crate::memory::numa::{self, NodeRange, Topology, MAX_NODES};
+use crate::memory::PAGE_SIZE;
+
+use super::find_sdt;
+use super::sdt::Sdt;
+use super::slit::Slit;
+
+/// The System Resource Affinity Table
+#[derive(Clone, Copy, Debug)]
+pub struct Srat {
+    sdt: &'static Sdt,
+}
+
+/// SRAT Processor Local APIC Affinity
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct SratLocalApic {
+    pub proximity_domain_low: u8,
+    pub apic_id: u8,
+    /// Flags. 1 means that the entry is enabled
+    pub flags: u32,
+    pub sapic_eid: u8,
+    pub proximity_domain_high: [u8; 3],
+    pub clock_domain: u32,
+}
+
+/// SRAT Memory Affinity
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct SratMemory {
+    pub proximity_domain: u32,
+    _reserved0: u16,
+    pub base: u64,
+    pub length: u64,
+    _reserved1: u32,
+    /// Flags. 1 means that the entry is enabled, 2 that the memory is hot-pluggable
+    pub flags: u32,
+    _reserved2: u64,
+}
+
+/// SRAT Processor Local x2APIC Affinity
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct SratLocalX2Apic {
+    _reserved0: u16,
+    pub proximity_domain: u32,
+    pub x2apic_id: u32,
+    /// Flags. 1 means that the entry is enabled
+    pub flags: u32,
+    pub clock_domain: u32,
+    _reserved1: u32,
+}
+
+/// SRAT Entries
+#[derive(Debug)]
+pub enum SratEntry {
+    LocalApic(SratLocalApic),
+    Memory(SratMemory),
+    LocalX2Apic(SratLocalX2Apic),
+    Invalid(u8, usize),
+    Unknown(u8),
+}
+
+const ENTRY_ENABLED: u32 = 1;
+
+impl Srat {
+    /// Describe the memory nodes to the frame allocator, see `memory::numa`
+    pub fn init() {
+        let srat_sdt = find_sdt("SRAT");
+        let srat = if srat_sdt.len() == 1 {
+            match Srat::new(srat_sdt[0]) {
+                Some(srat) => srat,
+                None => return,
+            }
+        } else {
+            println!("Unable to find SRAT");
+            return;
+        };
+
+        // Proximity domains can be any number, nodes are numbered in the order they are found
+        let mut domains = Vec::new();
+        let mut node_of_domain = |domain: u32| -> Option<usize> {
+            let node = match domains.iter().position(|&d| d == domain) {
+                Some(node) => node,
+                None => {
+                    domains.push(domain);
+                    domains.len() - 1
+                }
+            };
+            (node < MAX_NODES).then_some(node)
+        };
+
+        let mut topology = Topology::default();
+        let mut cpus = BTreeMap::new();
+        for entry in srat.iter() {
+            match entry {
+                SratEntry::LocalApic(apic) if apic.flags & ENTRY_ENABLED == ENTRY_ENABLED => {
+                    let high = apic.proximity_domain_high;
+                    let domain = u32::from_le_bytes([apic.proximity_domain_low, high[0], high[1], high[2]]);
+                    if let Some(node) = node_of_domain(domain) {
+                        // CPU IDs are local APIC IDs, see `kstart_ap`
+                        cpus.insert(apic.apic_id as usize, node);
+                    }
+                }
+                SratEntry::LocalX2Apic(apic) if apic.flags & ENTRY_ENABLED == ENTRY_ENABLED => {
+                    if let Some(node) = node_of_domain(apic.proximity_domain) {
+                        cpus.insert(apic.x2apic_id as usize, node);
+                    }
+                }
+                SratEntry::Memory(memory) if memory.flags & ENTRY_ENABLED == ENTRY_ENABLED && memory.length > 0 => {
+                    if let Some(node) = node_of_domain(memory.proximity_domain) {
+                        let (base, length) = (memory.base as usize, memory.length as usize);
+                        println!("  SRAT: node {} memory {:#X}:{:#X}", node, base, base + length);
+                        topology.ranges.push(NodeRange {
+                            start: base.div_ceil(PAGE_SIZE),
+                            end: (base + length) / PAGE_SIZE,
+                            node,
+                        });
+                    }
+                }
+                SratEntry::Invalid(entry_type, entry_len) => println!("  SRAT: invalid entry {} of length {}", entry_type, entry_len),
+                _ => (),
+            }
+        }
+        topology.node_count = domains.len().min(MAX_NODES);
+        topology.cpus = cpus;
+        topology.distances = Slit::distances(&domains[..topology.node_count]).unwrap_or_default();
+
+        numa::init(topology);
+    }
+
+    pub fn new(sdt: &'static Sdt) -> Option<Srat> {
+        // Not valid without the reserved fields before the entries
+        if &sdt.signature == b"SRAT" && sdt.data_len() >= 12 {
+            Some(Srat { sdt })
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> SratIter {
+        SratIter {
+            sdt: self.sdt,
+            i: 12 // Skip reserved fields
+        }
+    }
+}
+
+pub struct SratIter {
+    sdt: &'static Sdt,
+    i: usize
+}
+
+impl SratIter {
+    /// Read an entry of type `T` at the current offset, the table is not necessarily aligned
+    fn read<T>(&self) -> T {
+        unsafe { ptr::read_unaligned((self.sdt.data_address() + self.i + 2) as *const T) }
+    }
+}
+
+impl Iterator for SratIter {
+    type Item = SratEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.i + 1 >= self.sdt.data_len() {
+            return None;
+        }
+        let entry_type = unsafe { *(self.sdt.data_address() as *const u8).add(self.i) };
+        let entry_len = unsafe { *(self.sdt.data_address() as *const u8).add(self.i + 1) } as usize;
+        if entry_len < 2 || self.i + entry_len > self.sdt.data_len() {
+            return None;
+        }
+
+        let item = match entry_type {
+            0 if entry_len == mem::size_of::<SratLocalApic>() + 2 => SratEntry::LocalApic(self.read()),
+            1 if entry_len == mem::size_of::<SratMemory>() + 2 => SratEntry::Memory(self.read()),
+            2 if entry_len == mem::size_of::<SratLocalX2Apic>() + 2 => SratEntry::LocalX2Apic(self.read()),
+            0..=2 => SratEntry::Invalid(entry_type, entry_len),
+            _ => SratEntry::Unknown(entry_type),
+        };
+
+        self.i += entry_len;
+
+        Some(item)
+    }
+}
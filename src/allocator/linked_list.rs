@@ -1,9 +1,11 @@
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::{self, NonNull};
 use linked_list_allocator::Heap;
-use spin::Mutex;
 
 use crate::paging::KernelMapper;
+use crate::sync::Mutex;
+
+use super::{percpu, stats};
 
 static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
 
@@ -12,25 +14,92 @@ pub struct Allocator;
 impl Allocator {
     pub unsafe fn init(offset: usize, size: usize) {
         *HEAP.lock() = Some(Heap::new(offset, size));
+        stats::set_size(size);
+    }
+}
+
+/// Allocate from the global heap, extending it when it is full
+unsafe fn heap_alloc(heap: &mut Heap, layout: Layout) -> *mut u8 {
+    loop {
+        match heap.allocate_first_fit(layout) {
+            Err(()) => {
+                let size = heap.size();
+                super::map_heap(&mut KernelMapper::lock(), super::heap_base() + size, crate::KERNEL_HEAP_SIZE);
+                heap.extend(crate::KERNEL_HEAP_SIZE);
+                stats::set_size(heap.size());
+            },
+            other => return other.ok().map_or(ptr::null_mut(), |allocation| allocation.as_ptr()),
+        }
     }
 }
 
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        while let Some(ref mut heap) = *HEAP.lock() {
-            match heap.allocate_first_fit(layout) {
-                Err(()) => {
-                    let size = heap.size();
-                    super::map_heap(&mut KernelMapper::lock(), crate::KERNEL_HEAP_OFFSET + size, crate::KERNEL_HEAP_SIZE);
-                    heap.extend(crate::KERNEL_HEAP_SIZE);
-                },
-                other => return other.ok().map_or(ptr::null_mut(), |allocation| allocation.as_ptr()),
-            }
+        let ptr = self.alloc_inner(layout);
+        if !ptr.is_null() {
+            stats::record_alloc(layout.size());
         }
-        panic!("__rust_allocate: heap not initialized");
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        stats::record_dealloc(layout.size());
+        self.dealloc_inner(ptr, layout)
+    }
+}
+
+impl Allocator {
+    unsafe fn alloc_inner(&self, layout: Layout) -> *mut u8 {
+        // Small objects are allocated with the layout of their size class, so that they can be
+        // cached and reused for any allocation of that class
+        let layout = match percpu::class_of(layout) {
+            Some(class) => {
+                let refill = |objects: &mut [usize]| {
+                    let mut guard = HEAP.lock();
+                    let heap = guard.as_mut().expect("__rust_allocate: heap not initialized");
+                    let mut count = 0;
+                    for object in objects.iter_mut() {
+                        let ptr = heap_alloc(heap, percpu::class_layout(class));
+                        if ptr.is_null() {
+                            break;
+                        }
+                        *object = ptr as usize;
+                        count += 1;
+                    }
+                    count
+                };
+                if let Some(ptr) = percpu::alloc(class, refill) {
+                    return ptr;
+                }
+                percpu::class_layout(class)
+            },
+            None => layout,
+        };
+
+        match *HEAP.lock() {
+            Some(ref mut heap) => heap_alloc(heap, layout),
+            None => panic!("__rust_allocate: heap not initialized"),
+        }
+    }
+
+    unsafe fn dealloc_inner(&self, ptr: *mut u8, layout: Layout) {
+        let layout = match percpu::class_of(layout) {
+            Some(class) => {
+                let flush = |objects: &[usize]| {
+                    let mut guard = HEAP.lock();
+                    let heap = guard.as_mut().expect("__rust_deallocate: heap not initialized");
+                    for &object in objects {
+                        heap.deallocate(NonNull::new_unchecked(object as *mut u8), percpu::class_layout(class));
+                    }
+                };
+                if percpu::dealloc(class, ptr, flush) {
+                    return;
+                }
+                percpu::class_layout(class)
+            },
+            None => layout,
+        };
+
         if let Some(ref mut heap) = *HEAP.lock() {
             heap.deallocate(NonNull::new_unchecked(ptr), layout)
         } else {
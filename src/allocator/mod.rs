@@ -1,4 +1,8 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use rmm::Flusher;
+use crate::memory::{self, PAGE_SIZE};
+use crate::paging::huge::{self, HUGE_PAGE_SIZE};
 use crate::paging::{KernelMapper, Page, PageFlags, VirtualAddress, mapper::PageFlushAll};
 
 #[cfg(not(feature="slab"))]
@@ -10,17 +14,78 @@ pub use self::slab::Allocator;
 #[cfg(not(feature="slab"))]
 mod linked_list;
 
+#[cfg(not(feature="slab"))]
+pub mod percpu;
+
+#[cfg(not(feature="slab"))]
+pub mod stats;
+
 #[cfg(feature="slab")]
 mod slab;
 
+/// Number of `HEAP_ALIGN` steps over which the start of the heap is randomized, leaving the rest
+/// of its area for growth
+#[cfg(all(target_pointer_width = "64", not(feature = "riscv_sv39")))]
+const HEAP_SLOTS: usize = 1 << 14;
+// The heap area is a single 1 GiB entry with Sv39
+#[cfg(feature = "riscv_sv39")]
+const HEAP_SLOTS: usize = 1 << 8;
+#[cfg(target_pointer_width = "32")]
+const HEAP_SLOTS: usize = 16;
+
+/// Alignment of the start of the heap, a whole huge page where supported so that `map_heap` does
+/// not map below it
+const HEAP_ALIGN: usize = if huge::SUPPORTED && HUGE_PAGE_SIZE > crate::KERNEL_HEAP_SIZE {
+    HUGE_PAGE_SIZE
+} else {
+    crate::KERNEL_HEAP_SIZE
+};
+
+static HEAP_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// The start of the kernel heap, which is randomized at boot so that heap objects are not at
+/// predictable addresses. This is the only part of KASLR so far: the kernel image and the physical
+/// map stay at the addresses fixed by the linker script and `rmm`, as moving them requires a
+/// relocatable kernel image.
+pub fn heap_base() -> usize {
+    HEAP_BASE.load(Ordering::Relaxed)
+}
+
+/// Map the heap pages from `offset` to `offset + size`. Where huge pages are supported, whole huge
+/// pages are mapped instead, rounding the range out to them, to reduce TLB pressure. Pages which
+/// are already mapped are skipped, so that growing the heap in steps smaller than a huge page only
+/// maps each huge page once. If no aligned frames are left for a huge page, its heap pages are
+/// mapped individually.
 unsafe fn map_heap(mapper: &mut KernelMapper, offset: usize, size: usize) {
     let mapper = mapper.get_mut().expect("failed to obtain exclusive access to KernelMapper while extending heap");
     let mut flush_all = PageFlushAll::new();
+    let flags = PageFlags::new().write(true).global(cfg!(not(feature = "pti")));
+
+    if huge::SUPPORTED {
+        let start = offset / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+        for virt in (start..offset + size).step_by(HUGE_PAGE_SIZE) {
+            let virt = VirtualAddress::new(virt);
+            if huge::translate(mapper, virt).is_some() {
+                continue;
+            }
+            let Some(frame) = memory::allocate_aligned_frames(HUGE_PAGE_SIZE / PAGE_SIZE, HUGE_PAGE_SIZE / PAGE_SIZE) else {
+                break;
+            };
+            match huge::map_phys(mapper, virt, frame.start_address(), flags) {
+                Some(flush) => flush_all.consume(flush),
+                // Partially mapped by a previous fallback to regular pages
+                None => memory::deallocate_frames(frame, HUGE_PAGE_SIZE / PAGE_SIZE),
+            }
+        }
+    }
 
     let heap_start_page = Page::containing_address(VirtualAddress::new(offset));
     let heap_end_page = Page::containing_address(VirtualAddress::new(offset + size-1));
     for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        let result = mapper.map(page.start_address(), PageFlags::new().write(true).global(cfg!(not(feature = "pti"))))
+        if huge::translate(mapper, page.start_address()).is_some() {
+            continue;
+        }
+        let result = mapper.map(page.start_address(), flags)
             .expect("failed to map kernel heap");
         flush_all.consume(result);
     }
@@ -29,8 +94,9 @@ unsafe fn map_heap(mapper: &mut KernelMapper, offset: usize, size: usize) {
 }
 
 pub unsafe fn init() {
-    let offset = crate::KERNEL_HEAP_OFFSET;
+    let offset = crate::KERNEL_HEAP_OFFSET + (crate::entropy::next_u64() as usize % HEAP_SLOTS) * HEAP_ALIGN;
     let size = crate::KERNEL_HEAP_SIZE;
+    HEAP_BASE.store(offset, Ordering::Relaxed);
 
     // Map heap pages
     map_heap(&mut KernelMapper::lock(), offset, size);
@@ -0,0 +1,166 @@
+//! # Per-CPU caches
+//! Small allocations, like those of contexts, grants and file handles, are served from a
+//! magazine of free objects per CPU and size class, so that most allocations and frees do not
+//! take the global heap lock. An empty magazine is refilled, and a full one half flushed, with a
+//! single acquisition of that lock.
+//!
+//! A magazine is only used when its lock can be taken without waiting, which fails when an
+//! interrupt handler allocates while the code it interrupted was using the magazine. The global
+//! heap is used directly then.
+
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::sync::Mutex;
+
+/// Number of CPUs with caches, other CPUs use the global heap directly
+pub const CPUS: usize = 32;
+
+/// Object sizes of the size classes
+pub const CLASSES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+
+/// Alignment of cached objects, allocations needing more are not cached
+pub const ALIGN: usize = 16;
+
+/// Number of free objects a magazine can hold
+const MAGAZINE: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Magazine {
+    len: usize,
+    objects: [usize; MAGAZINE],
+}
+
+struct Cache {
+    magazines: [Magazine; CLASSES.len()],
+}
+
+static CACHES: [Mutex<Cache>; CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const CACHE: Mutex<Cache> = Mutex::new(Cache {
+        magazines: [Magazine { len: 0, objects: [0; MAGAZINE] }; CLASSES.len()],
+    });
+    [CACHE; CPUS]
+};
+
+/// Counters of a size class, summed over all CPUs
+struct ClassStats {
+    /// Allocations served from a magazine
+    hits: AtomicU64,
+    /// Allocations that had to refill a magazine, or could not use one
+    misses: AtomicU64,
+    /// Frees that had to flush a magazine, or could not use one
+    flushes: AtomicU64,
+}
+
+static STATS: [ClassStats; CLASSES.len()] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const STATS: ClassStats = ClassStats {
+        hits: AtomicU64::new(0),
+        misses: AtomicU64::new(0),
+        flushes: AtomicU64::new(0),
+    };
+    [STATS; CLASSES.len()]
+};
+
+/// The size class of `layout`, if it is cached
+pub fn class_of(layout: Layout) -> Option<usize> {
+    if layout.align() > ALIGN {
+        return None;
+    }
+    CLASSES.iter().position(|&size| layout.size() <= size)
+}
+
+/// The layout objects of `class` are allocated with from the global heap
+pub fn class_layout(class: usize) -> Layout {
+    Layout::from_size_align(CLASSES[class], ALIGN).expect("invalid size class")
+}
+
+fn cache() -> Option<&'static Mutex<Cache>> {
+    CACHES.get(crate::cpu_id())
+}
+
+/// Allocate an object of `class`, with `refill` allocating objects from the global heap into the
+/// given slice with one lock acquisition, and returning how many it allocated
+pub fn alloc(class: usize, refill: impl FnOnce(&mut [usize]) -> usize) -> Option<*mut u8> {
+    let stats = &STATS[class];
+    let mut cache = match cache().and_then(Mutex::try_lock) {
+        Some(cache) => cache,
+        None => {
+            stats.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+    };
+    let magazine = &mut cache.magazines[class];
+
+    if magazine.len == 0 {
+        stats.misses.fetch_add(1, Ordering::Relaxed);
+        magazine.len = refill(&mut magazine.objects[..MAGAZINE / 2]);
+        if magazine.len == 0 {
+            return None;
+        }
+    } else {
+        stats.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    magazine.len -= 1;
+    Some(magazine.objects[magazine.len] as *mut u8)
+}
+
+/// Free an object of `class`, with `flush` freeing the objects in the given slice to the global
+/// heap with one lock acquisition. Returns false if the object was not cached and has to be
+/// freed to the global heap.
+pub fn dealloc(class: usize, ptr: *mut u8, flush: impl FnOnce(&[usize])) -> bool {
+    let stats = &STATS[class];
+    let mut cache = match cache().and_then(Mutex::try_lock) {
+        Some(cache) => cache,
+        None => {
+            stats.flushes.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+    };
+    let magazine = &mut cache.magazines[class];
+
+    if magazine.len == MAGAZINE {
+        stats.flushes.fetch_add(1, Ordering::Relaxed);
+        // Keep the most recently freed objects, which are the most likely to be in cache
+        flush(&magazine.objects[..MAGAZINE / 2]);
+        magazine.objects.copy_within(MAGAZINE / 2.., 0);
+        magazine.len = MAGAZINE - MAGAZINE / 2;
+    }
+
+    magazine.objects[magazine.len] = ptr as usize;
+    magazine.len += 1;
+    true
+}
+
+/// Statistics of a size class
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub size: usize,
+    /// Free objects currently held by the magazines of all CPUs
+    pub cached: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub flushes: u64,
+}
+
+/// Statistics of each size class
+pub fn stats() -> [Stats; CLASSES.len()] {
+    let mut result = [Stats::default(); CLASSES.len()];
+    for (class, stats) in result.iter_mut().enumerate() {
+        stats.size = CLASSES[class];
+        stats.hits = STATS[class].hits.load(Ordering::Relaxed);
+        stats.misses = STATS[class].misses.load(Ordering::Relaxed);
+        stats.flushes = STATS[class].flushes.load(Ordering::Relaxed);
+    }
+    for cache in CACHES.iter().take(crate::cpu_count()) {
+        // Never wait on the lock of a CPU which may be allocating
+        if let Some(cache) = cache.try_lock() {
+            for (stats, magazine) in result.iter_mut().zip(cache.magazines.iter()) {
+                stats.cached += magazine.len;
+            }
+        }
+    }
+    result
+}
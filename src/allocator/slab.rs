@@ -1,7 +1,8 @@
 use core::alloc::{Alloc, AllocErr, Layout};
-use spin::Mutex;
 use slab_allocator::Heap;
 
+use crate::sync::Mutex;
+
 static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
 
 pub struct Allocator;
@@ -0,0 +1,88 @@
+//! # Heap statistics
+//! Counts of the allocations made through the global allocator, by requested size, to diagnose
+//! leaks in long running kernels. Memory held by the per-CPU caches counts as free.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Number of size buckets. Bucket `i` holds sizes up to `16 << i`, the last one everything larger.
+pub const BUCKETS: usize = 13;
+
+/// Bytes currently mapped for the heap
+static SIZE: AtomicUsize = AtomicUsize::new(0);
+/// Bytes currently allocated
+static USED: AtomicUsize = AtomicUsize::new(0);
+/// Highest value of `USED`
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+struct Bucket {
+    /// Allocations ever made
+    allocs: AtomicU64,
+    /// Allocations currently live
+    live: AtomicUsize,
+}
+
+static BUCKET_STATS: [Bucket; BUCKETS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const BUCKET: Bucket = Bucket {
+        allocs: AtomicU64::new(0),
+        live: AtomicUsize::new(0),
+    };
+    [BUCKET; BUCKETS]
+};
+
+/// The bucket of allocations of `size` bytes
+fn bucket(size: usize) -> usize {
+    let order = size.max(16).next_power_of_two().trailing_zeros() as usize;
+    (order - 4).min(BUCKETS - 1)
+}
+
+/// Largest size in `bucket`, or None for the last bucket which has no limit
+pub fn bucket_limit(bucket: usize) -> Option<usize> {
+    (bucket < BUCKETS - 1).then(|| 16 << bucket)
+}
+
+pub fn set_size(size: usize) {
+    SIZE.store(size, Ordering::Relaxed);
+}
+
+pub fn record_alloc(size: usize) {
+    let used = USED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(used, Ordering::Relaxed);
+
+    let bucket = &BUCKET_STATS[bucket(size)];
+    bucket.allocs.fetch_add(1, Ordering::Relaxed);
+    bucket.live.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_dealloc(size: usize) {
+    USED.fetch_sub(size, Ordering::Relaxed);
+    BUCKET_STATS[bucket(size)].live.fetch_sub(1, Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BucketStats {
+    pub allocs: u64,
+    pub live: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub size: usize,
+    pub used: usize,
+    pub peak: usize,
+    pub buckets: [BucketStats; BUCKETS],
+}
+
+pub fn stats() -> Stats {
+    let mut stats = Stats {
+        size: SIZE.load(Ordering::Relaxed),
+        used: USED.load(Ordering::Relaxed),
+        peak: PEAK.load(Ordering::Relaxed),
+        ..Stats::default()
+    };
+    for (stats, bucket) in stats.buckets.iter_mut().zip(BUCKET_STATS.iter()) {
+        stats.allocs = bucket.allocs.load(Ordering::Relaxed);
+        stats.live = bucket.live.load(Ordering::Relaxed);
+    }
+    stats
+}
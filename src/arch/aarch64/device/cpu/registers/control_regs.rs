@@ -76,6 +76,12 @@ pub unsafe fn cntfreq_el0() -> u32 {
     ret
 }
 
+pub unsafe fn cntvct_el0() -> u64 {
+    let ret: u64;
+    asm!("mrs {}, cntvct_el0", out(reg) ret);
+    ret
+}
+
 pub unsafe fn tmr_ctrl() -> u32 {
     let ret: u32;
     asm!("mrs {}, cntp_ctl_el0", out(reg) ret);
@@ -1,5 +1,6 @@
 use crate::arch::device::gic;
 use crate::device::cpu::registers::{control_regs};
+use crate::time::{self, Clocksource};
 
 bitflags! {
     struct TimerCtrlFlags: u32 {
@@ -16,6 +17,18 @@ pub static mut GENTIMER: GenericTimer = GenericTimer {
 
 pub unsafe fn init() {
     GENTIMER.init();
+    time::register(Clocksource {
+        name: "arch_sys_counter",
+        rating: 400,
+        read: nanoseconds,
+        verify: false,
+    });
+}
+
+/// Nanoseconds counted by the virtual counter, which runs at the timer frequency
+fn nanoseconds() -> u128 {
+    let clk_freq = unsafe { GENTIMER.clk_freq }.max(1);
+    u128::from(unsafe { control_regs::cntvct_el0() }) * time::NANOS_PER_SEC / u128::from(clk_freq)
 }
 
 /*
@@ -1,8 +1,16 @@
+//! # GIC
+//! The distributor tells which architecture version the GIC implements: GICv2 is driven through the
+//! memory mapped CPU interface below, GICv3 and later by `gicv3`. Either way, INTIDs 32 and up are
+//! SPIs, delivered as IRQ `intid - 32` by the irq scheme, and LPIs are mapped to IRQs by `gicv3`.
+
 use core::intrinsics::{volatile_load, volatile_store};
+use core::sync::atomic::{AtomicU8, Ordering};
 
 use crate::memory::Frame;
 use crate::paging::{KernelMapper, PhysicalAddress, Page, PageFlags, TableKind, VirtualAddress};
 
+use super::gicv3;
+
 static GICD_CTLR: u32 = 0x000;
 static GICD_TYPER: u32 = 0x004;
 static GICD_ISENABLER: u32 = 0x100;
@@ -16,6 +24,39 @@ static GICC_IAR: u32 = 0x000c;
 static GICC_CTLR: u32 = 0x0000;
 static GICC_PMR: u32 = 0x0004;
 
+static GICD_PIDR2: usize = 0xFFE8;
+
+const GICD_BASE: usize = 0x0800_0000;
+
+/// The INTID read when there is no pending interrupt
+pub const SPURIOUS: u32 = 1023;
+/// INTIDs below this are SGIs and PPIs, private to each CPU
+pub const SPI_BASE: u32 = 32;
+
+/// Architecture version of the GIC, 2 until `init` found out otherwise
+static VERSION: AtomicU8 = AtomicU8::new(2);
+
+fn is_v3() -> bool {
+    VERSION.load(Ordering::Relaxed) >= 3
+}
+
+/// Map `size` bytes of GIC registers at `address` in the physmap
+pub unsafe fn map_mmio(address: usize, size: usize) {
+    let mut mapper = KernelMapper::lock();
+
+    let start_frame = Frame::containing_address(PhysicalAddress::new(address));
+    let end_frame = Frame::containing_address(PhysicalAddress::new(address + size - 1));
+    for frame in Frame::range_inclusive(start_frame, end_frame) {
+        let page = Page::containing_address(VirtualAddress::new(frame.start_address().data() + crate::PHYS_OFFSET));
+        mapper
+            .get_mut()
+            .expect("failed to access KernelMapper for mapping GIC")
+            .map_phys(page.start_address(), frame.start_address(), PageFlags::new().write(true))
+            .expect("failed to map GIC")
+            .flush();
+    }
+}
+
 static mut GIC_DIST_IF: GicDistIf = GicDistIf {
     address: 0,
     ncpus: 0,
@@ -27,24 +68,94 @@ static mut GIC_CPU_IF: GicCpuIf = GicCpuIf {
 };
 
 pub unsafe fn init() {
-    GIC_DIST_IF.init();
-    GIC_CPU_IF.init();
+    map_mmio(GICD_BASE, 0x10000);
+    let pidr2 = volatile_load((crate::PHYS_OFFSET + GICD_BASE + GICD_PIDR2) as *const u32);
+    let version = ((pidr2 >> 4) & 0xF) as u8;
+    if version >= 3 {
+        println!("gic: GICv{}", version);
+        VERSION.store(version, Ordering::Relaxed);
+        gicv3::init();
+    } else {
+        GIC_DIST_IF.init();
+        GIC_CPU_IF.init();
+    }
+}
+
+/// Set up the CPU interface of an AP, only needed for GICv3, as GICv2 has no redistributors
+pub unsafe fn init_ap() {
+    if is_v3() {
+        gicv3::init_ap();
+    }
+}
+
+/// Number of SGIs, PPIs and SPIs the distributor supports
+pub fn irq_count() -> u32 {
+    if is_v3() {
+        gicv3::irq_count()
+    } else {
+        unsafe { GIC_DIST_IF.nirqs }
+    }
 }
 
 pub fn irq_enable(irq_num: u32) {
-    unsafe { GIC_DIST_IF.irq_enable(irq_num) };
+    if is_v3() {
+        gicv3::irq_enable(irq_num);
+    } else {
+        unsafe { GIC_DIST_IF.irq_enable(irq_num) };
+    }
 }
 
 pub fn irq_disable(irq_num: u32) {
-    unsafe { GIC_DIST_IF.irq_disable(irq_num) };
+    if is_v3() {
+        gicv3::irq_disable(irq_num);
+    } else {
+        unsafe { GIC_DIST_IF.irq_disable(irq_num) };
+    }
 }
 
+/// Route the SPI `irq_num` to the BSP. GICv2 SPIs already all target CPU 0.
+pub fn irq_route_bsp(irq_num: u32) {
+    if is_v3() {
+        gicv3::irq_route_bsp(irq_num);
+    }
+}
+
+/// Acknowledge the highest priority pending interrupt, returning its INTID, or `SPURIOUS`
 pub unsafe fn irq_ack() -> u32 {
-    GIC_CPU_IF.irq_ack()
+    if is_v3() {
+        gicv3::irq_ack()
+    } else {
+        GIC_CPU_IF.irq_ack()
+    }
 }
 
 pub unsafe fn irq_eoi(irq_num: u32) {
-    GIC_CPU_IF.irq_eoi(irq_num);
+    if is_v3() {
+        gicv3::irq_eoi(irq_num);
+    } else {
+        GIC_CPU_IF.irq_eoi(irq_num);
+    }
+}
+
+/// The irq scheme IRQ of an INTID, for SPIs and mapped LPIs
+pub fn intid_to_irq(intid: u32) -> Option<u8> {
+    if intid >= gicv3::LPI_BASE {
+        gicv3::lpi_to_irq(intid)
+    } else if intid >= SPI_BASE && intid < SPURIOUS {
+        u8::try_from(intid - SPI_BASE).ok()
+    } else {
+        None
+    }
+}
+
+/// The INTID delivering an irq scheme IRQ, the LPI it was mapped to if any, or its SPI
+pub fn irq_to_intid(irq: u8) -> u32 {
+    if is_v3() {
+        if let Some(lpi) = gicv3::irq_to_lpi(irq) {
+            return lpi;
+        }
+    }
+    u32::from(irq) + SPI_BASE
 }
 
 pub struct GicDistIf {
@@ -55,36 +166,11 @@ pub struct GicDistIf {
 
 impl GicDistIf {
     unsafe fn init(&mut self) {
-        // Map in the Distributor interface
-        let mut mapper = KernelMapper::lock();
-
-        let start_frame = Frame::containing_address(PhysicalAddress::new(0x08000000));
-        let end_frame = Frame::containing_address(PhysicalAddress::new(0x08000000 + 0x10000 - 1));
-        for frame in Frame::range_inclusive(start_frame, end_frame) {
-            let page = Page::containing_address(VirtualAddress::new(frame.start_address().data() + crate::PHYS_OFFSET));
-            mapper
-                .get_mut()
-                .expect("failed to access KernelMapper for mapping GIC distributor")
-                .map_phys(page.start_address(), frame.start_address(), PageFlags::new().write(true))
-                .expect("failed to map GIC distributor")
-                .flush();
-        }
-
-        self.address = crate::PHYS_OFFSET + 0x08000000;
+        // The distributor interface was mapped by init
+        self.address = crate::PHYS_OFFSET + GICD_BASE;
 
         // Map in CPU0's interface
-        let start_frame = Frame::containing_address(PhysicalAddress::new(0x08010000));
-        let end_frame = Frame::containing_address(PhysicalAddress::new(0x08010000 + 0x10000 - 1));
-        for frame in Frame::range_inclusive(start_frame, end_frame) {
-            let page = Page::containing_address(VirtualAddress::new(frame.start_address().data() + crate::PHYS_OFFSET));
-            mapper
-                .get_mut()
-                .expect("failed to access KernelMapper for mapping GIC interface")
-                .map_phys(page.start_address(), frame.start_address(), PageFlags::new().write(true))
-                .expect("failed to map GIC interface")
-                .flush();
-        }
-
+        map_mmio(0x08010000, 0x10000);
         GIC_CPU_IF.address = crate::PHYS_OFFSET + 0x08010000;
 
         // Disable IRQ Distribution
@@ -167,11 +253,7 @@ impl GicCpuIf {
     }
 
     unsafe fn irq_ack(&mut self) -> u32 {
-        let irq = self.read(GICC_IAR) & 0x1ff;
-        if irq == 1023 {
-            panic!("irq_ack: got ID 1023!!!");
-        }
-        irq
+        self.read(GICC_IAR) & 0x3ff
     }
 
     unsafe fn irq_eoi(&mut self, irq: u32) {
@@ -0,0 +1,559 @@
+//! # GICv3 and GICv4
+//! The distributor routes SPIs using affinity routing, each CPU has its own redistributor for its
+//! SGIs, PPIs and LPIs, and the CPU interface is accessed through system registers. GICv4 only
+//! adds virtual LPIs, which are not used, so it is driven the same way.
+//!
+//! LPIs are message-based interrupts, used for PCIe MSIs through the ITS: a device writes the
+//! event ID of the interrupt to the ITS doorbell, and the ITS translates the device ID and event ID
+//! to an LPI, using tables set up with commands. The LPIs of the irq scheme are mapped with
+//! `its_map`, and event `n` of a device is delivered as IRQ `n`, like vector `n + 32` is on x86.
+
+use alloc::collections::BTreeMap;
+use core::arch::asm;
+use core::intrinsics::{volatile_load, volatile_store};
+use core::ptr;
+
+use crate::memory::{allocate_aligned_frames, allocate_frames, PAGE_SIZE};
+use crate::paging::{PhysicalAddress, RmmA, RmmArch};
+use crate::sync::Mutex;
+use crate::syscall::error::{Error, Result, EEXIST, EINVAL, ENODEV, ENOENT, ENOMEM, ENOSPC};
+
+use super::gic::map_mmio;
+
+const GICD_BASE: usize = 0x0800_0000;
+const GICD_SIZE: usize = 0x1_0000;
+const GITS_BASE: usize = 0x0808_0000;
+const GITS_SIZE: usize = 0x2_0000;
+const GICR_BASE: usize = 0x080A_0000;
+const GICR_SIZE: usize = 0xF6_0000;
+
+const GICD_CTLR: usize = 0x0000;
+const GICD_TYPER: usize = 0x0004;
+const GICD_SETSPI_NSR: usize = 0x0040;
+const GICD_IGROUPR: usize = 0x0080;
+const GICD_ISENABLER: usize = 0x0100;
+const GICD_ICENABLER: usize = 0x0180;
+const GICD_IPRIORITYR: usize = 0x0400;
+const GICD_ICFGR: usize = 0x0C00;
+const GICD_IROUTER: usize = 0x6000;
+
+const GICD_CTLR_RWP: u32 = 1 << 31;
+const GICD_CTLR_ARE_NS: u32 = 1 << 4;
+const GICD_CTLR_ENABLE_G1NS: u32 = 1 << 1;
+const GICD_TYPER_LPIS: u32 = 1 << 17;
+const GICD_TYPER_MBIS: u32 = 1 << 16;
+
+// Offsets in the RD_base frame of a redistributor
+const GICR_CTLR: usize = 0x0000;
+const GICR_TYPER: usize = 0x0008;
+const GICR_WAKER: usize = 0x0014;
+const GICR_PROPBASER: usize = 0x0070;
+const GICR_PENDBASER: usize = 0x0078;
+// Offsets in the SGI_base frame, which follows RD_base
+const GICR_SGI_BASE: usize = 0x1_0000;
+const GICR_IGROUPR0: usize = GICR_SGI_BASE + 0x0080;
+const GICR_ISENABLER0: usize = GICR_SGI_BASE + 0x0100;
+const GICR_ICENABLER0: usize = GICR_SGI_BASE + 0x0180;
+const GICR_IPRIORITYR: usize = GICR_SGI_BASE + 0x0400;
+
+const GICR_CTLR_ENABLE_LPIS: u32 = 1 << 0;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+const GITS_CTLR: usize = 0x0000;
+const GITS_TYPER: usize = 0x0008;
+const GITS_CBASER: usize = 0x0080;
+const GITS_CWRITER: usize = 0x0088;
+const GITS_CREADR: usize = 0x0090;
+const GITS_BASER: usize = 0x0100;
+const GITS_TRANSLATER: usize = 0x1_0040;
+
+const GITS_CTLR_ENABLED: u32 = 1 << 0;
+const GITS_TYPER_PTA: u64 = 1 << 19;
+const GITS_BASER_VALID: u64 = 1 << 63;
+const GITS_BASER_INDIRECT: u64 = 1 << 62;
+const GITS_BASER_TYPE_DEVICES: u64 = 1;
+const GITS_BASER_TYPE_COLLECTIONS: u64 = 4;
+
+/// Inner shareable, write-back cacheable, for the tables the GIC reads from memory
+const TABLE_ATTRIBUTES: u64 = (0b111 << 59) | (0b01 << 10) | (0b111 << 7);
+// PROPBASER and PENDBASER keep the cacheability at bits 7..10 only
+const REDIST_TABLE_ATTRIBUTES: u64 = (0b01 << 10) | (0b111 << 7);
+const PENDBASER_PTZ: u64 = 1 << 62;
+
+/// First LPI INTID
+pub const LPI_BASE: u32 = 8192;
+/// Number of LPIs, INTIDs `LPI_BASE..2 * LPI_BASE`, which needs 14 INTID bits
+const LPI_COUNT: u32 = 8192;
+const LPI_ID_BITS: u64 = 14;
+/// Priority, group 1, and enabled, in the LPI configuration table
+const LPI_PROP_DEFAULT: u8 = 0xA0 | 0b10;
+const LPI_PROP_ENABLE: u8 = 0b1;
+
+/// Event IDs per device, each one being an IRQ number of the irq scheme
+const EVENT_ID_BITS: u64 = 8;
+/// Device IDs supported by the flat device table
+const MAX_DEVICE_ID_BITS: u64 = 16;
+
+const ICC_SRE_SRE: u64 = 1 << 0;
+
+macro_rules! sysreg_read {
+    ($name:literal) => {{
+        let value: u64;
+        asm!(concat!("mrs {}, ", $name), out(reg) value);
+        value
+    }};
+}
+macro_rules! sysreg_write {
+    ($name:literal, $value:expr) => {
+        asm!(concat!("msr ", $name, ", {}"), in(reg) $value);
+    };
+}
+
+// Encodings of the CPU interface registers, which not every assembler knows by name
+unsafe fn icc_sre_el1() -> u64 {
+    sysreg_read!("S3_0_C12_C12_5")
+}
+unsafe fn icc_sre_el1_write(value: u64) {
+    sysreg_write!("S3_0_C12_C12_5", value);
+    asm!("isb");
+}
+unsafe fn icc_pmr_el1_write(value: u64) {
+    sysreg_write!("S3_0_C4_C6_0", value);
+}
+unsafe fn icc_bpr1_el1_write(value: u64) {
+    sysreg_write!("S3_0_C12_C12_3", value);
+}
+unsafe fn icc_igrpen1_el1_write(value: u64) {
+    sysreg_write!("S3_0_C12_C12_7", value);
+    asm!("isb");
+}
+unsafe fn icc_iar1_el1() -> u64 {
+    sysreg_read!("S3_0_C12_C12_0")
+}
+unsafe fn icc_eoir1_el1_write(value: u64) {
+    sysreg_write!("S3_0_C12_C12_1", value);
+}
+
+/// The affinity of the current CPU, as found in `GICR_TYPER` and `GICD_IROUTER`
+unsafe fn current_affinity() -> u64 {
+    let mpidr: u64 = sysreg_read!("mpidr_el1");
+    (mpidr & 0x00FF_FFFF) | ((mpidr >> 32) & 0xFF) << 24
+}
+
+struct Mmio {
+    address: usize,
+}
+
+impl Mmio {
+    unsafe fn read32(&self, reg: usize) -> u32 {
+        volatile_load((self.address + reg) as *const u32)
+    }
+    unsafe fn write32(&self, reg: usize, value: u32) {
+        volatile_store((self.address + reg) as *mut u32, value);
+    }
+    unsafe fn read64(&self, reg: usize) -> u64 {
+        volatile_load((self.address + reg) as *const u64)
+    }
+    unsafe fn write64(&self, reg: usize, value: u64) {
+        volatile_store((self.address + reg) as *mut u64, value);
+    }
+}
+
+struct Its {
+    mmio: Mmio,
+    /// The command queue, one page
+    commands: usize,
+    /// Bytes of an ITT entry
+    itt_entry_size: usize,
+    /// The target address of the collection of the BSP, as used in `MAPC` and `SYNC`
+    rd_base: u64,
+    /// The ITT of each mapped device
+    devices: BTreeMap<u32, PhysicalAddress>,
+    /// LPI of each mapped IRQ, and the device and event IDs it was mapped from
+    irqs: BTreeMap<u8, (u32, u32)>,
+    lpis: BTreeMap<u32, u8>,
+}
+
+struct Gic {
+    dist: Mmio,
+    nirqs: u32,
+    mbis: bool,
+    /// The redistributor of the BSP
+    redist: Mmio,
+    bsp_affinity: u64,
+    /// The LPI configuration table, shared by all redistributors
+    lpi_props: usize,
+    its: Option<Its>,
+}
+
+static GIC: Mutex<Option<Gic>> = Mutex::new(None);
+
+impl Gic {
+    unsafe fn wait_rwp(&self) {
+        while self.dist.read32(GICD_CTLR) & GICD_CTLR_RWP != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    unsafe fn set_lpi_enabled(&mut self, lpi: u32, enabled: bool) {
+        let prop = (self.lpi_props + (lpi - LPI_BASE) as usize) as *mut u8;
+        let value = if enabled { LPI_PROP_DEFAULT | LPI_PROP_ENABLE } else { LPI_PROP_DEFAULT };
+        volatile_store(prop, value);
+        // Make the change visible to the GIC, then have the ITS reload it
+        asm!("dsb ishst");
+        if let Some(its) = self.its.as_mut() {
+            let mapping = its.lpis.get(&lpi).and_then(|irq| its.irqs.get(irq)).copied();
+            if let Some((device_id, event_id)) = mapping {
+                // INV
+                its.command([0x0C | u64::from(device_id) << 32, u64::from(event_id), 0, 0]);
+                its.sync();
+            }
+        }
+    }
+}
+
+impl Its {
+    /// Queue a command, and wait for the ITS to process it
+    unsafe fn command(&mut self, command: [u64; 4]) {
+        let offset = self.mmio.read64(GITS_CWRITER) as usize & (PAGE_SIZE - 1);
+        let slot = (self.commands + offset) as *mut [u64; 4];
+        ptr::write_volatile(slot, command);
+        asm!("dsb ishst");
+
+        let next = (offset + 32) % PAGE_SIZE;
+        self.mmio.write64(GITS_CWRITER, next as u64);
+        while self.mmio.read64(GITS_CREADR) as usize & (PAGE_SIZE - 1) != next {
+            core::hint::spin_loop();
+        }
+    }
+
+    unsafe fn sync(&mut self) {
+        self.command([0x05, 0, self.rd_base << 16, 0]);
+    }
+}
+
+/// Find the redistributor of the CPU with `affinity`
+unsafe fn find_redistributor(affinity: u64) -> Option<Mmio> {
+    let base = crate::PHYS_OFFSET + GICR_BASE;
+    let mut offset = 0;
+    while offset < GICR_SIZE {
+        let redist = Mmio { address: base + offset };
+        let typer = redist.read64(GICR_TYPER);
+        if typer >> 32 == affinity {
+            return Some(redist);
+        }
+        if typer & GICR_TYPER_LAST != 0 {
+            break;
+        }
+        // GICv4 redistributors have two more frames, for virtual LPIs
+        offset += if typer & GICR_TYPER_VLPIS != 0 { 0x4_0000 } else { 0x2_0000 };
+    }
+    None
+}
+
+/// Wake the redistributor, set up its SGIs and PPIs, and enable the CPU interface
+unsafe fn init_cpu(redist: &Mmio) {
+    let waker = redist.read32(GICR_WAKER);
+    redist.write32(GICR_WAKER, waker & !GICR_WAKER_PROCESSOR_SLEEP);
+    while redist.read32(GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+        core::hint::spin_loop();
+    }
+
+    // All SGIs and PPIs in group 1, disabled, at the highest priority
+    redist.write32(GICR_IGROUPR0, 0xFFFF_FFFF);
+    redist.write32(GICR_ICENABLER0, 0xFFFF_FFFF);
+    for reg in (0..32).step_by(4) {
+        redist.write32(GICR_IPRIORITYR + reg, 0);
+    }
+
+    icc_sre_el1_write(icc_sre_el1() | ICC_SRE_SRE);
+    icc_pmr_el1_write(0xFF);
+    icc_bpr1_el1_write(0);
+    icc_igrpen1_el1_write(1);
+}
+
+/// Give the redistributor its LPI tables, and enable LPIs
+unsafe fn init_lpis(redist: &Mmio, lpi_props: PhysicalAddress) -> Result<()> {
+    // One bit per INTID, including the ones below the first LPI, aligned to 64 KiB
+    let pending_size = (1 << LPI_ID_BITS) / 8;
+    let pending_pages = (pending_size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let pending = allocate_aligned_frames(pending_pages, 16).ok_or(Error::new(ENOMEM))?;
+    ptr::write_bytes(RmmA::phys_to_virt(pending.start_address()).data() as *mut u8, 0, pending_pages * PAGE_SIZE);
+
+    redist.write64(GICR_PROPBASER, lpi_props.data() as u64 | REDIST_TABLE_ATTRIBUTES | (LPI_ID_BITS - 1));
+    redist.write64(GICR_PENDBASER, pending.start_address().data() as u64 | REDIST_TABLE_ATTRIBUTES | PENDBASER_PTZ);
+    redist.write32(GICR_CTLR, redist.read32(GICR_CTLR) | GICR_CTLR_ENABLE_LPIS);
+    Ok(())
+}
+
+/// Allocate the tables of the ITS, map the collection of the BSP, and enable it
+unsafe fn init_its(redist: &Mmio) -> Result<Its> {
+    map_mmio(GITS_BASE, GITS_SIZE);
+    let mmio = Mmio { address: crate::PHYS_OFFSET + GITS_BASE };
+    let typer = mmio.read64(GITS_TYPER);
+    let itt_entry_size = ((typer >> 4) & 0xF) as usize + 1;
+    let device_bits = ((typer >> 13) & 0x1F) + 1;
+
+    for index in 0..8 {
+        let reg = GITS_BASER + index * 8;
+        let baser = mmio.read64(reg);
+        let entry_size = ((baser >> 48) & 0x1F) as usize + 1;
+        let entries = match (baser >> 56) & 0x7 {
+            GITS_BASER_TYPE_DEVICES => 1_usize << device_bits.min(MAX_DEVICE_ID_BITS),
+            // One collection per CPU
+            GITS_BASER_TYPE_COLLECTIONS => crate::cpu_count(),
+            _ => continue,
+        };
+        // Flat tables of 4 KiB pages, of at most 256 pages
+        let pages = ((entries * entry_size + PAGE_SIZE - 1) / PAGE_SIZE).clamp(1, 256);
+        let table = allocate_frames(pages).ok_or(Error::new(ENOMEM))?;
+        ptr::write_bytes(RmmA::phys_to_virt(table.start_address()).data() as *mut u8, 0, pages * PAGE_SIZE);
+        let value = (baser & (0x7 << 56 | 0x1F << 48)) & !GITS_BASER_INDIRECT
+            | GITS_BASER_VALID
+            | TABLE_ATTRIBUTES
+            | table.start_address().data() as u64
+            | (pages as u64 - 1);
+        mmio.write64(reg, value);
+    }
+
+    let commands = allocate_frames(1).ok_or(Error::new(ENOMEM))?;
+    let commands_virt = RmmA::phys_to_virt(commands.start_address()).data();
+    ptr::write_bytes(commands_virt as *mut u8, 0, PAGE_SIZE);
+    mmio.write64(GITS_CBASER, GITS_BASER_VALID | TABLE_ATTRIBUTES | commands.start_address().data() as u64);
+    mmio.write64(GITS_CWRITER, 0);
+
+    mmio.write32(GITS_CTLR, mmio.read32(GITS_CTLR) | GITS_CTLR_ENABLED);
+
+    // Collections target redistributors by physical address, or by processor number
+    let rd_base = if typer & GITS_TYPER_PTA != 0 {
+        ((redist.address - crate::PHYS_OFFSET) as u64) >> 16
+    } else {
+        (redist.read64(GICR_TYPER) >> 8) & 0xFFFF
+    };
+
+    let mut its = Its {
+        mmio,
+        commands: commands_virt,
+        itt_entry_size,
+        rd_base,
+        devices: BTreeMap::new(),
+        irqs: BTreeMap::new(),
+        lpis: BTreeMap::new(),
+    };
+    // MAPC: collection 0 to the BSP
+    its.command([0x09, 0, 1 << 63 | rd_base << 16, 0]);
+    // INVALL: collection 0
+    its.command([0x0D, 0, 0, 0]);
+    its.sync();
+    Ok(its)
+}
+
+pub unsafe fn init() {
+    map_mmio(GICD_BASE, GICD_SIZE);
+    map_mmio(GICR_BASE, GICR_SIZE);
+
+    let dist = Mmio { address: crate::PHYS_OFFSET + GICD_BASE };
+
+    // Disable the distributor, then enable affinity routing
+    dist.write32(GICD_CTLR, 0);
+    while dist.read32(GICD_CTLR) & GICD_CTLR_RWP != 0 {
+        core::hint::spin_loop();
+    }
+    dist.write32(GICD_CTLR, GICD_CTLR_ARE_NS);
+
+    let typer = dist.read32(GICD_TYPER);
+    let nirqs = (((typer & 0x1F) + 1) * 32).min(1020);
+    let lpis = typer & GICD_TYPER_LPIS != 0;
+    let mbis = typer & GICD_TYPER_MBIS != 0;
+    println!("gicv3: Distributor supports {} IRQs, LPIs: {}, message based SPIs: {}", nirqs, lpis, mbis);
+
+    let bsp_affinity = current_affinity();
+    for irq in (32..nirqs).step_by(32) {
+        // Group 1, disabled
+        dist.write32(GICD_IGROUPR + (irq / 32) as usize * 4, 0xFFFF_FFFF);
+        dist.write32(GICD_ICENABLER + (irq / 32) as usize * 4, 0xFFFF_FFFF);
+    }
+    for irq in (32..nirqs).step_by(16) {
+        // Level triggered
+        dist.write32(GICD_ICFGR + (irq / 16) as usize * 4, 0);
+    }
+    for irq in (32..nirqs).step_by(4) {
+        dist.write32(GICD_IPRIORITYR + irq as usize, 0);
+    }
+    for irq in 32..nirqs {
+        dist.write64(GICD_IROUTER + irq as usize * 8, bsp_affinity);
+    }
+
+    let redist = find_redistributor(bsp_affinity).expect("gicv3: no redistributor for the BSP");
+    init_cpu(&redist);
+
+    let mut gic = Gic {
+        dist,
+        nirqs,
+        mbis,
+        redist,
+        bsp_affinity,
+        lpi_props: 0,
+        its: None,
+    };
+
+    if lpis {
+        let pages = (LPI_COUNT as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+        match allocate_aligned_frames(pages, 16) {
+            Some(props) => {
+                gic.lpi_props = RmmA::phys_to_virt(props.start_address()).data();
+                ptr::write_bytes(gic.lpi_props as *mut u8, LPI_PROP_DEFAULT, LPI_COUNT as usize);
+                let result = init_lpis(&gic.redist, props.start_address())
+                    .and_then(|()| init_its(&gic.redist));
+                match result {
+                    Ok(its) => gic.its = Some(its),
+                    Err(err) => println!("gicv3: failed to set up LPIs: {:?}", err),
+                }
+            }
+            None => println!("gicv3: failed to allocate LPI configuration table"),
+        }
+    }
+
+    gic.dist.write32(GICD_CTLR, GICD_CTLR_ARE_NS | GICD_CTLR_ENABLE_G1NS);
+    gic.wait_rwp();
+
+    *GIC.lock() = Some(gic);
+}
+
+/// Set up the redistributor and CPU interface of an AP
+pub unsafe fn init_ap() {
+    let Some(redist) = find_redistributor(current_affinity()) else {
+        println!("gicv3: no redistributor for CPU {}", crate::cpu_id());
+        return;
+    };
+    init_cpu(&redist);
+}
+
+pub fn irq_count() -> u32 {
+    GIC.lock().as_ref().map_or(0, |gic| gic.nirqs)
+}
+
+pub fn irq_enable(intid: u32) {
+    let mut guard = GIC.lock();
+    let Some(gic) = guard.as_mut() else { return };
+    unsafe {
+        match intid {
+            0..=31 => gic.redist.write32(GICR_ISENABLER0, 1 << intid),
+            LPI_BASE.. => gic.set_lpi_enabled(intid, true),
+            _ => gic.dist.write32(GICD_ISENABLER + (intid / 32) as usize * 4, 1 << (intid % 32)),
+        }
+    }
+}
+
+pub fn irq_disable(intid: u32) {
+    let mut guard = GIC.lock();
+    let Some(gic) = guard.as_mut() else { return };
+    unsafe {
+        match intid {
+            0..=31 => gic.redist.write32(GICR_ICENABLER0, 1 << intid),
+            LPI_BASE.. => gic.set_lpi_enabled(intid, false),
+            _ => {
+                gic.dist.write32(GICD_ICENABLER + (intid / 32) as usize * 4, 1 << (intid % 32));
+                gic.wait_rwp();
+            }
+        }
+    }
+}
+
+/// Route the SPI `intid` to the BSP, the only CPU taking device interrupts
+pub fn irq_route_bsp(intid: u32) {
+    if let Some(gic) = GIC.lock().as_ref() {
+        if (32..gic.nirqs).contains(&intid) {
+            unsafe { gic.dist.write64(GICD_IROUTER + intid as usize * 8, gic.bsp_affinity) };
+        }
+    }
+}
+
+pub unsafe fn irq_ack() -> u32 {
+    (icc_iar1_el1() & 0xFF_FFFF) as u32
+}
+
+pub unsafe fn irq_eoi(intid: u32) {
+    icc_eoir1_el1_write(u64::from(intid));
+}
+
+/// The IRQ an LPI was mapped to
+pub fn lpi_to_irq(intid: u32) -> Option<u8> {
+    GIC.lock().as_ref()?.its.as_ref()?.lpis.get(&intid).copied()
+}
+
+/// The LPI an IRQ was mapped to
+pub fn irq_to_lpi(irq: u8) -> Option<u32> {
+    let guard = GIC.lock();
+    let its = guard.as_ref()?.its.as_ref()?;
+    its.lpis.iter().find(|&(_, &lpi_irq)| lpi_irq == irq).map(|(&lpi, _)| lpi)
+}
+
+/// The physical addresses devices write to for message based SPIs, and for LPIs through the ITS
+pub fn msi_doorbells() -> (Option<usize>, Option<usize>) {
+    match GIC.lock().as_ref() {
+        Some(gic) => (
+            gic.mbis.then_some(GICD_BASE + GICD_SETSPI_NSR),
+            gic.its.as_ref().map(|_| GITS_BASE + GITS_TRANSLATER),
+        ),
+        None => (None, None),
+    }
+}
+
+/// Map event `irq` of the device `device_id` to a new LPI delivered as IRQ `irq`, and enable it
+pub fn its_map(device_id: u32, irq: u8) -> Result<()> {
+    let mut guard = GIC.lock();
+    let gic = guard.as_mut().ok_or(Error::new(ENODEV))?;
+    let its = gic.its.as_mut().ok_or(Error::new(ENODEV))?;
+    if u64::from(device_id) >= 1 << MAX_DEVICE_ID_BITS {
+        return Err(Error::new(EINVAL));
+    }
+    if its.irqs.contains_key(&irq) {
+        return Err(Error::new(EEXIST));
+    }
+    let lpi = (LPI_BASE..LPI_BASE + LPI_COUNT)
+        .find(|lpi| !its.lpis.contains_key(lpi))
+        .ok_or(Error::new(ENOSPC))?;
+
+    unsafe {
+        if !its.devices.contains_key(&device_id) {
+            // The ITT needs 256 byte alignment, which a frame has
+            let itt_size = (1 << EVENT_ID_BITS) * its.itt_entry_size;
+            assert!(itt_size <= PAGE_SIZE);
+            let itt = allocate_frames(1).ok_or(Error::new(ENOMEM))?;
+            ptr::write_bytes(RmmA::phys_to_virt(itt.start_address()).data() as *mut u8, 0, PAGE_SIZE);
+            // MAPD
+            its.command([0x08 | u64::from(device_id) << 32, EVENT_ID_BITS - 1, 1 << 63 | itt.start_address().data() as u64, 0]);
+            its.devices.insert(device_id, itt.start_address());
+        }
+        // MAPTI: to collection 0
+        its.command([0x0A | u64::from(device_id) << 32, u64::from(irq) | u64::from(lpi) << 32, 0, 0]);
+        its.irqs.insert(irq, (device_id, u32::from(irq)));
+        its.lpis.insert(lpi, irq);
+        gic.set_lpi_enabled(lpi, true);
+    }
+    Ok(())
+}
+
+/// Undo `its_map`, if `irq` was mapped
+pub fn its_unmap(irq: u8) -> Result<()> {
+    let mut guard = GIC.lock();
+    let gic = guard.as_mut().ok_or(Error::new(ENODEV))?;
+    let lpi = {
+        let its = gic.its.as_ref().ok_or(Error::new(ENODEV))?;
+        its.lpis.iter().find(|&(_, &lpi_irq)| lpi_irq == irq).map(|(&lpi, _)| lpi).ok_or(Error::new(ENOENT))?
+    };
+    unsafe {
+        gic.set_lpi_enabled(lpi, false);
+        let its = gic.its.as_mut().ok_or(Error::new(ENODEV))?;
+        let (device_id, event_id) = its.irqs.remove(&irq).ok_or(Error::new(ENOENT))?;
+        // DISCARD
+        its.command([0x0F | u64::from(device_id) << 32, u64::from(event_id), 0, 0]);
+        its.sync();
+        its.lpis.remove(&lpi);
+    }
+    Ok(())
+}
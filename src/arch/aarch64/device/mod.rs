@@ -1,8 +1,10 @@
 pub mod cpu;
 pub mod gic;
+pub mod gicv3;
 pub mod generic_timer;
 pub mod serial;
 pub mod rtc;
+pub mod sp805;
 pub mod uart_pl011;
 
 pub unsafe fn init() {
@@ -20,4 +22,5 @@ pub unsafe fn init_noncore() {
 }
 
 pub unsafe fn init_ap() {
+    gic::init_ap();
 }
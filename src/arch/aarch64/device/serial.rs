@@ -1,17 +1,18 @@
 use core::sync::atomic::{Ordering};
-use spin::Mutex;
 
 use crate::device::uart_pl011::SerialPort;
 use crate::init::device_tree;
 use crate::memory::Frame;
 use crate::paging::mapper::PageFlushAll;
 use crate::paging::{KernelMapper, Page, PageFlags, PhysicalAddress, TableKind, VirtualAddress};
+use crate::sync::Mutex;
 
 pub static COM1: Mutex<Option<SerialPort>> = Mutex::new(None);
 
 pub unsafe fn init_early(dtb_base: usize, dtb_size: usize) {
     if COM1.lock().is_some() {
         // Hardcoded UART
+        crate::log::set_sinks_ready(crate::log::Sinks::SERIAL, true);
         return;
     }
 
@@ -22,6 +23,7 @@ pub unsafe fn init_early(dtb_base: usize, dtb_size: usize) {
             serial_port.init(false);
             *COM1.lock() = Some(serial_port);
         }
+        crate::log::set_sinks_ready(crate::log::Sinks::SERIAL, true);
         println!("UART at {:X}", virt);
     }
 }
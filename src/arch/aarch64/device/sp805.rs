@@ -0,0 +1,89 @@
+//! # ARM SP805 watchdog
+//! Counts down from the load register at the rate of its clock, raising an interrupt when it
+//! reaches zero, and reloading. If the interrupt is still raised the next time, it resets the
+//! system, so it is loaded with half the timeout. Clearing the interrupt reloads the counter.
+
+use core::ptr::write_volatile;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::memory::Frame;
+use crate::paging::{KernelMapper, Page, PageFlags, PhysicalAddress, VirtualAddress};
+use crate::watchdog::{self, Driver};
+
+const LOAD: usize = 0x000;
+const CONTROL: usize = 0x008;
+const INT_CLR: usize = 0x00C;
+const LOCK: usize = 0xC00;
+
+const CONTROL_INT_EN: u32 = 1 << 0;
+const CONTROL_RES_EN: u32 = 1 << 1;
+
+/// Written to the lock register to allow writes to the others, anything else locks them again
+const UNLOCK: u32 = 0x1ACC_E551;
+
+static BASE: AtomicUsize = AtomicUsize::new(0);
+/// Frequency of the clock, in Hz
+static RATE: AtomicU64 = AtomicU64::new(0);
+
+unsafe fn write(offset: usize, value: u32) {
+    write_volatile((BASE.load(Ordering::Relaxed) + offset) as *mut u32, value);
+}
+
+/// Write the registers with `f`, unlocking them first
+fn unlocked(f: impl FnOnce()) {
+    unsafe {
+        write(LOCK, UNLOCK);
+        f();
+        write(LOCK, 0);
+    }
+}
+
+fn start(timeout: u32) {
+    let load = (RATE.load(Ordering::Relaxed) / 2 * u64::from(timeout)).saturating_sub(1).min(u64::from(u32::MAX));
+    unlocked(|| unsafe {
+        write(LOAD, load as u32);
+        write(INT_CLR, 0);
+        write(CONTROL, CONTROL_INT_EN | CONTROL_RES_EN);
+    });
+}
+
+fn stop() {
+    unlocked(|| unsafe { write(CONTROL, 0) });
+}
+
+fn ping() {
+    unlocked(|| unsafe { write(INT_CLR, 0) });
+}
+
+/// Use the watchdog with registers at `address`, counting at `rate` Hz
+pub unsafe fn init(address: usize, rate: u32) {
+    if rate < 2 {
+        return;
+    }
+    {
+        let mut mapper = KernelMapper::lock();
+        let Some(mapper) = mapper.get_mut() else {
+            return;
+        };
+        let frame = Frame::containing_address(PhysicalAddress::new(address));
+        let page = Page::containing_address(VirtualAddress::new(frame.start_address().data() + crate::PHYS_OFFSET));
+        // Fails if already mapped
+        if let Some(flush) = mapper.map_phys(page.start_address(), frame.start_address(), PageFlags::new().write(true)) {
+            flush.flush();
+        }
+    }
+    BASE.store(address + crate::PHYS_OFFSET, Ordering::Relaxed);
+    RATE.store(u64::from(rate), Ordering::Relaxed);
+
+    stop();
+    watchdog::register(
+        Driver {
+            name: "sp805",
+            max_timeout: (u64::from(u32::MAX) / (u64::from(rate) / 2)).min(u64::from(watchdog::MAX_TIMEOUT)) as u32,
+            start,
+            stop,
+            ping,
+        },
+        false,
+    );
+}
@@ -0,0 +1,29 @@
+//! # Idle states
+//! `wfe` and `wfi`. An interrupt taken between enabling interrupts and `wfe` sets the event
+//! register when returning from it, so `wfe` returns at once instead of missing it, but events
+//! from other CPUs wake it up too. `wfi` only returns on interrupts, and lets the core save more
+//! power on many implementations, for longer idle durations.
+
+use core::arch::asm;
+
+use crate::idle::State;
+
+static STATES: [State; 2] = [
+    State { name: "wfe", exit_latency: 1, target_residency: 1 },
+    State { name: "wfi", exit_latency: 10, target_residency: 100 },
+];
+
+/// The idle states, shallowest first
+pub fn states() -> &'static [State] {
+    &STATES
+}
+
+/// Enable interrupts and wait for the next one in state `index`
+pub unsafe fn enter(index: usize) {
+    asm!("msr daifclr, #2");
+    if index == 0 {
+        asm!("wfe");
+    } else {
+        asm!("wfi");
+    }
+}
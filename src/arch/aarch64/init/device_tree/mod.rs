@@ -88,6 +88,67 @@ fn compatible_node_present<'a>(dt: &fdt::DeviceTree<'a>, compat_string: &str) ->
     false
 }
 
+/// Add the `virtio,mmio` devices, for the kernel to drive until userspace drivers start (see
+/// `devices::virtio`)
+pub fn add_virtio_mmio(dtb_base: usize, dtb_size: usize) {
+    let data = unsafe { slice::from_raw_parts(dtb_base as *const u8, dtb_size) };
+    let Ok(dt) = fdt::DeviceTree::new(data) else {
+        return;
+    };
+    let root = dt.nodes().next();
+    let cells = |name: &str, default: usize| {
+        root.as_ref()
+            .and_then(|root| root.properties().find(|p| p.name == name))
+            .map_or(default, |p| BE::read_u32(p.data) as usize)
+    };
+    let (address_cells, size_cells) = (cells("#address-cells", 2), cells("#size-cells", 1));
+    // Big-endian cells, the most significant first
+    let read = |data: &[u8]| data.chunks(4).fold(0, |value, chunk| value << 32 | BE::read_u32(chunk) as usize);
+
+    for node in dt.nodes() {
+        let compatible = node.properties().find(|p| p.name == "compatible");
+        if !compatible.map_or(false, |p| p.data.split(|&b| b == 0).any(|s| s == b"virtio,mmio")) {
+            continue;
+        }
+        let Some(reg) = node.properties().find(|p| p.name == "reg") else {
+            continue;
+        };
+        if reg.data.len() >= (address_cells + size_cells) * 4 {
+            let (base, size) = reg.data.split_at(address_cells * 4);
+            crate::devices::virtio::add_mmio(read(base), read(&size[..size_cells * 4]));
+        }
+    }
+}
+
+/// The physical address of the first `arm,sp805` watchdog, and the frequency of its clock, from
+/// its first `clocks` entry, which must be a fixed clock
+pub fn find_sp805(dtb_base: usize, dtb_size: usize) -> Option<(usize, u32)> {
+    let data = unsafe { slice::from_raw_parts(dtb_base as *const u8, dtb_size) };
+    let dt = fdt::DeviceTree::new(data).ok()?;
+    let root = dt.nodes().next();
+    let address_cells = root
+        .as_ref()
+        .and_then(|root| root.properties().find(|p| p.name == "#address-cells"))
+        .map_or(2, |p| BE::read_u32(p.data) as usize);
+    let read = |data: &[u8]| data.chunks(4).fold(0, |value, chunk| value << 32 | BE::read_u32(chunk) as usize);
+
+    let node = dt.nodes().find(|node| {
+        node.properties()
+            .find(|p| p.name == "compatible")
+            .map_or(false, |p| p.data.split(|&b| b == 0).any(|s| s == b"arm,sp805"))
+    })?;
+    let reg = node.properties().find(|p| p.name == "reg").filter(|p| p.data.len() >= address_cells * 4)?;
+    let clock = node.properties().find(|p| p.name == "clocks").filter(|p| p.data.len() >= 4)?;
+    let phandle = BE::read_u32(clock.data);
+
+    let rate = dt.nodes().find_map(|node| {
+        node.properties()
+            .find(|p| p.name == "phandle" && p.data.len() >= 4 && BE::read_u32(p.data) == phandle)?;
+        node.properties().find(|p| p.name == "clock-frequency").map(|p| BE::read_u32(p.data))
+    })?;
+    Some((read(&reg.data[..address_cells * 4]), rate))
+}
+
 pub fn fill_env_data(dtb_base: usize, dtb_size: usize, env_base: usize) -> usize {
     let data = unsafe { slice::from_raw_parts(dtb_base as *const u8, dtb_size) };
     let dt = fdt::DeviceTree::new(data).unwrap();
@@ -4,13 +4,52 @@ use crate::{
     context,
     cpu_id,
     interrupt::stack_trace,
+    paging::VirtualAddress,
+    ptrace,
     syscall,
+    syscall::abi::{BUS_ADRALN, SEGV_ACCERR, SEGV_MAPERR},
     syscall::flag::*,
 
     with_exception_stack,
     exception_stack,
 };
 
+/// Software step is enabled, taking effect when returning to EL0 with `SPSR_EL1.SS` set
+const MDSCR_SS: usize = 1 << 0;
+
+// Exception classes, from ESR_EL1
+const EC_UNKNOWN: u8 = 0b000000;
+const EC_FP_ACCESS: u8 = 0b000111;
+const EC_ILLEGAL_STATE: u8 = 0b001110;
+const EC_SVC64: u8 = 0b010101;
+const EC_SYSREG: u8 = 0b011000;
+const EC_INSTR_ABORT_LOWER: u8 = 0b100000;
+const EC_PC_ALIGNMENT: u8 = 0b100010;
+const EC_DATA_ABORT_LOWER: u8 = 0b100100;
+const EC_DATA_ABORT_SAME: u8 = 0b100101;
+const EC_SP_ALIGNMENT: u8 = 0b100110;
+const EC_FP_EXCEPTION: u8 = 0b101100;
+const EC_SERROR: u8 = 0b101111;
+const EC_BREAKPOINT_LOWER: u8 = 0b110000;
+const EC_SOFTWARE_STEP_LOWER: u8 = 0b110010;
+const EC_WATCHPOINT_LOWER: u8 = 0b110100;
+const EC_BRK64: u8 = 0b111100;
+
+/// Enable software step for ptrace, and unlock the debug registers, locked at reset
+pub unsafe fn init() {
+    asm!(
+        "
+        msr oslar_el1, xzr
+        mrs {tmp}, mdscr_el1
+        orr {tmp}, {tmp}, {ss}
+        msr mdscr_el1, {tmp}
+        isb
+        ",
+        tmp = out(reg) _,
+        ss = const MDSCR_SS,
+    );
+}
+
 exception_stack!(synchronous_exception_at_el1_with_sp0, |stack| {
     println!("Synchronous exception at EL1 with SP0");
     stack.dump();
@@ -24,23 +63,65 @@ fn exception_code(esr: usize) -> u8 {
 fn iss(esr: usize) -> u32 {
     (esr & 0x01ff_ffff) as u32
 }
+fn far() -> usize {
+    let far: usize;
+    unsafe { asm!("mrs {}, far_el1", out(reg) far) };
+    far
+}
+
+/// The fault status code of an instruction or data abort, decoded
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Abort {
+    /// No page, at any level of the page tables
+    Translation,
+    /// The access flag of the page was clear
+    AccessFlag,
+    /// The page does not allow the access
+    Permission,
+    Alignment,
+    /// External aborts, parity and ECC errors, TLB conflicts, and the rest
+    Other,
+}
+
+impl Abort {
+    fn from_iss(iss: u32) -> Self {
+        match iss & 0x3f {
+            0b000100..=0b000111 => Abort::Translation,
+            0b001000..=0b001011 => Abort::AccessFlag,
+            0b001100..=0b001111 => Abort::Permission,
+            0b100001 => Abort::Alignment,
+            _ => Abort::Other,
+        }
+    }
+}
+
+/// Write not Read, for data aborts
+fn is_write(iss: u32) -> bool {
+    iss & (1 << 6) != 0
+}
 
 exception_stack!(synchronous_exception_at_el1_with_spx, |stack| {
-    if exception_code(stack.iret.esr_el1) == 0b100101 {
+    if exception_code(stack.iret.esr_el1) == EC_DATA_ABORT_SAME {
         // "Data Abort taken without a change in Exception level"
 
         let iss = iss(stack.iret.esr_el1);
-
-        let was_translation_fault = iss >= 0b000100 && iss <= 0b000111;
-        let was_permission_fault = iss >= 0b001101 && iss <= 0b001111;
+        let abort = Abort::from_iss(iss);
 
         extern "C" {
             static __usercopy_start: u8;
             static __usercopy_end: u8;
         }
         let usercopy = (&__usercopy_start as *const _ as usize)..(&__usercopy_end as *const _ as usize);
+        let in_usercopy = usercopy.contains(&{stack.iret.elr_el1});
+
+        // Lazily allocated memory is backed by a frame on first access by usercopy, as it is on
+        // first access by userspace
+        let address = VirtualAddress::new(far());
+        if in_usercopy && abort == Abort::Translation && address.kind() == rmm::TableKind::User && crate::context::memory::try_demand_page(address, false) {
+            return;
+        }
 
-        if (was_translation_fault || was_permission_fault) && usercopy.contains(&{stack.iret.elr_el1}) {
+        if matches!(abort, Abort::Translation | Abort::Permission) && in_usercopy {
             // This was a usercopy page fault. Set the return value to nonzero to indicate usercopy
             // failure (EFAULT), and emulate the return instruction by setting the return pointer
             // to the saved LR value.
@@ -58,23 +139,116 @@ exception_stack!(synchronous_exception_at_el1_with_spx, |stack| {
     loop {}
 });
 
+/// Handle a page fault from EL0, returning false if the context has to be signaled
+unsafe fn user_page_fault(esr: usize) -> bool {
+    let address = VirtualAddress::new(far());
+    Abort::from_iss(iss(esr)) == Abort::Translation
+        && address.kind() == rmm::TableKind::User
+        && crate::context::memory::try_demand_page(address, true)
+}
+
 exception_stack!(synchronous_exception_at_el0, |stack| {
-    with_exception_stack!(|stack| {
-        if exception_code(stack.iret.esr_el1) != 0b010101 {
-            println!("FATAL: Not an SVC induced synchronous exception");
-            stack.dump();
-            stack_trace();
-            crate::ksignal(SIGSEGV);
-            stack.scratch.x0
-        } else {
+    let esr = stack.iret.esr_el1;
+    match exception_code(esr) {
+        EC_SVC64 => with_exception_stack!(|stack| {
             let scratch = &stack.scratch;
             syscall::syscall(scratch.x8, scratch.x0, scratch.x1, scratch.x2, scratch.x3, scratch.x4, stack)
+        }),
+        EC_INSTR_ABORT_LOWER | EC_DATA_ABORT_LOWER => {
+            if user_page_fault(esr) {
+                return;
+            }
+            let iss = iss(esr);
+            let address = far();
+            let abort = Abort::from_iss(iss);
+            println!("Page fault: {:>016X}", address);
+            println!("  Status: {:?}", abort);
+            println!("  Write: {}", exception_code(esr) == EC_DATA_ABORT_LOWER && is_write(iss));
+            println!("  Instruction fetch: {}", exception_code(esr) == EC_INSTR_ABORT_LOWER);
+            stack.dump();
+            stack_trace();
+            match abort {
+                Abort::Translation => crate::ksignal_fault(SIGSEGV, SEGV_MAPERR, address),
+                Abort::AccessFlag | Abort::Permission => crate::ksignal_fault(SIGSEGV, SEGV_ACCERR, address),
+                Abort::Alignment => crate::ksignal_fault(SIGBUS, BUS_ADRALN, address),
+                Abort::Other => crate::ksignal_fault(SIGBUS, 0, address),
+            }
+        }
+        EC_PC_ALIGNMENT | EC_SP_ALIGNMENT => {
+            println!("Alignment fault");
+            stack.dump();
+            stack_trace();
+            crate::ksignal_fault(SIGBUS, BUS_ADRALN, far());
+        }
+        EC_SOFTWARE_STEP_LOWER => {
+            // Disable singlestep before there is a breakpoint, since the breakpoint handler might
+            // end up setting it again but unless it does we want the default to be false.
+            let had_singlestep = stack.is_singlestep();
+            stack.set_singlestep(false);
+
+            if ptrace::breakpoint_callback(PTRACE_STOP_SINGLESTEP, None).is_none() {
+                // There was no breakpoint, restore original value
+                stack.set_singlestep(had_singlestep);
+
+                println!("Debug trap");
+                stack.dump();
+                crate::ksignal(SIGTRAP);
+            }
+        }
+        EC_BRK64 => {
+            // ELR_EL1 already points to the brk instruction, like RIP is adjusted to point to int3
+            // on x86_64
+            if ptrace::breakpoint_callback(PTRACE_STOP_BREAKPOINT, None).is_none() {
+                println!("Breakpoint trap");
+                stack.dump();
+                crate::ksignal(SIGTRAP);
+            }
+        }
+        EC_BREAKPOINT_LOWER | EC_WATCHPOINT_LOWER => {
+            println!("Debug trap");
+            stack.dump();
+            crate::ksignal(SIGTRAP);
+        }
+        EC_FP_EXCEPTION => {
+            println!("FPU floating point fault");
+            stack.dump();
+            stack_trace();
+            crate::ksignal(SIGFPE);
+        }
+        EC_UNKNOWN | EC_FP_ACCESS | EC_ILLEGAL_STATE | EC_SYSREG => {
+            println!("Invalid opcode fault");
+            stack.dump();
+            stack_trace();
+            crate::ksignal(SIGILL);
         }
-    });
+        code => {
+            println!("Unhandled synchronous exception at EL0, EC {:#08b}", code);
+            stack.dump();
+            stack_trace();
+            crate::ksignal(SIGILL);
+        }
+    }
+});
+
+exception_stack!(serror_at_el0, |stack| {
+    println!("SError at EL0, ISS {:#x}", iss(stack.iret.esr_el1));
+    stack.dump();
+    stack_trace();
+    crate::ksignal(SIGBUS);
+});
+
+// AArch32 is not supported at EL0, and the processor should never switch to it
+exception_stack!(exception_at_el0_aarch32, |stack| {
+    println!("Exception from AArch32 EL0");
+    stack.dump();
+    crate::ksignal(SIGKILL);
 });
 
 exception_stack!(unhandled_exception, |stack| {
     println!("Unhandled exception");
+    if exception_code(stack.iret.esr_el1) == EC_SERROR {
+        println!("SError, ISS {:#x}", iss(stack.iret.esr_el1));
+    }
     stack.dump();
     stack_trace();
     loop {}
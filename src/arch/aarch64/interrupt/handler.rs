@@ -1,5 +1,8 @@
 use crate::syscall::IntRegisters;
 
+/// Software step, in SPSR_EL1: set when returning to EL0 to step one instruction
+pub const SPSR_SS: usize = 1 << 21;
+
 #[derive(Default)]
 #[repr(packed)]
 pub struct ScratchRegisters {
@@ -204,9 +207,21 @@ impl InterruptStack {
         self.scratch.x0 = all.x0;
     }
 
-    //TODO
-    pub fn is_singlestep(&self) -> bool { false }
-    pub fn set_singlestep(&mut self, singlestep: bool) {}
+    /// Checks if the software step bit is set, which makes the next return to EL0 execute a
+    /// single instruction before a software step exception
+    pub fn is_singlestep(&self) -> bool {
+        self.iret.spsr_el1 & SPSR_SS == SPSR_SS
+    }
+
+    /// Sets the software step bit, the other half of software step, `MDSCR_EL1.SS`, always being
+    /// set (see `exception::init`)
+    pub fn set_singlestep(&mut self, singlestep: bool) {
+        if singlestep {
+            self.iret.spsr_el1 |= SPSR_SS;
+        } else {
+            self.iret.spsr_el1 &= !SPSR_SS;
+        }
+    }
 }
 
 #[macro_export]
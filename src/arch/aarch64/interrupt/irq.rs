@@ -13,32 +13,46 @@ use crate::{exception_stack};
 pub static PIT_TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
 
 exception_stack!(irq_at_el0, |stack| {
-    match gic::irq_ack() {
-        30 => irq_handler_gentimer(30),
-        33 => irq_handler_com1(33),
-        _ => panic!("irq_demux: unregistered IRQ"),
-    }
+    irq_demux();
 });
 
 exception_stack!(irq_at_el1, |stack| {
-    match gic::irq_ack() {
-        30 => irq_handler_gentimer(30),
-        33 => irq_handler_com1(33),
-        _ => panic!("irq_demux: unregistered IRQ"),
-    }
+    irq_demux();
 });
 
-unsafe fn trigger(irq: u32) {
-    extern {
-        fn irq_trigger(irq: u32);
+extern {
+    fn irq_trigger(irq: u8);
+}
+
+unsafe fn trigger(intid: u32) {
+    if let Some(irq) = gic::intid_to_irq(intid) {
+        irq_trigger(irq);
+    }
+    gic::irq_eoi(intid);
+}
+
+/// Unmask an IRQ masked by `irq_handler_generic`, once its userspace driver handled it
+pub unsafe fn acknowledge(irq: usize) {
+    if let Ok(irq) = u8::try_from(irq) {
+        gic::irq_enable(gic::irq_to_intid(irq));
     }
+}
 
-    irq_trigger(irq);
-    gic::irq_eoi(irq);
+/// Mask an IRQ regardless of whether it has been acknowledged, at the distributor for SPIs and in
+/// the LPI configuration table for LPIs. This is called from the IRQ scheme when the IRQ is firing
+/// too often, and is undone by `acknowledge`.
+pub unsafe fn mask(irq: usize) {
+    if let Ok(irq) = u8::try_from(irq) {
+        gic::irq_disable(gic::irq_to_intid(irq));
+    }
 }
 
-pub unsafe fn acknowledge(_irq: usize) {
-    // TODO
+/// SPIs and LPIs handled by userspace drivers through the irq scheme. The INTID stays masked until
+/// the driver acknowledges the IRQ, as level triggered SPIs would fire again right away.
+unsafe fn irq_handler_generic(intid: u32, irq: u8) {
+    gic::irq_disable(intid);
+    irq_trigger(irq);
+    gic::irq_eoi(intid);
 }
 
 pub unsafe fn irq_handler_com1(irq: u32) {
@@ -56,8 +70,18 @@ pub unsafe fn irq_handler_gentimer(irq: u32) {
 
     timeout::trigger();
 
-    // Switch after 3 ticks (about 6.75 ms)
-    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 {
+    crate::scheme::irq::storm_tick();
+
+    crate::time::watchdog();
+
+    crate::watchdog::poll();
+
+    crate::lockup::tick();
+
+    crate::perf::tick();
+
+    // Switch after 3 ticks (about 6.75 ms), or right away if a low-latency context is due
+    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 || context::latency::due() {
         let _ = context::switch();
     }
     trigger(irq);
@@ -68,6 +92,14 @@ unsafe fn irq_demux() {
     match gic::irq_ack() {
         30 => irq_handler_gentimer(30),
         33 => irq_handler_com1(33),
-        _ => panic!("irq_demux: unregistered IRQ"),
+        gic::SPURIOUS => (),
+        intid => match gic::intid_to_irq(intid) {
+            Some(irq) => irq_handler_generic(intid, irq),
+            None => {
+                println!("irq_demux: unregistered INTID {}", intid);
+                gic::irq_disable(intid);
+                gic::irq_eoi(intid);
+            }
+        },
     }
 }
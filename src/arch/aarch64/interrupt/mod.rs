@@ -1,6 +1,9 @@
 //! Interrupt instructions
 
 use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::device::gic;
 
 #[macro_use]
 pub mod handler;
@@ -56,22 +59,42 @@ pub fn pause() {
     unsafe { asm!("nop") };
 }
 
+/// Reserved vectors, which are GIC INTIDs. SGIs and PPIs are private, and the SPI of the PL011
+/// UART is used by the kernel.
+static RESERVATIONS: [AtomicU64; 4] = [
+    AtomicU64::new(0xFFFF_FFFF | 1 << 33),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// The vectors not reserved yet, only on the BSP, as SPIs are all routed to it
 pub fn available_irqs_iter(cpu_id: usize) -> impl Iterator<Item = u8> + 'static {
-    0..0
+    let count = gic::irq_count().min(256);
+    (32..=255).filter(move |&index| cpu_id == 0 && u32::from(index) < count && !is_reserved(cpu_id, index))
 }
 
 pub fn bsp_apic_id() -> Option<u32> {
-    //TODO
-    None
+    Some(0)
 }
 
 #[inline]
-pub fn is_reserved(cpu_id: usize, index: u8) -> bool {
-    //TODO
-    true
+pub fn is_reserved(_cpu_id: usize, index: u8) -> bool {
+    RESERVATIONS[usize::from(index / 64)].load(Ordering::Acquire) & (1 << (index % 64)) != 0
 }
 
+/// Reserve the SPI `index` and route it to the BSP, or release it, unmapping the LPI it was mapped
+/// to through the ITS if any
 #[inline]
-pub fn set_reserved(cpu_id: usize, index: u8, reserved: bool) {
-    //TODO
+pub fn set_reserved(_cpu_id: usize, index: u8, reserved: bool) {
+    let bit = 1 << (index % 64);
+    if reserved {
+        RESERVATIONS[usize::from(index / 64)].fetch_or(bit, Ordering::AcqRel);
+        gic::irq_route_bsp(u32::from(index));
+    } else {
+        RESERVATIONS[usize::from(index / 64)].fetch_and(!bit, Ordering::AcqRel);
+        if let Some(irq) = index.checked_sub(32) {
+            let _ = crate::device::gicv3::its_unmap(irq);
+        }
+    }
 }
@@ -1,10 +1,3 @@
-use crate::{
-    arch::{interrupt::InterruptStack},
-    context,
-    syscall,
-    syscall::flag::{PTRACE_FLAG_IGNORE, PTRACE_STOP_PRE_SYSCALL, PTRACE_STOP_POST_SYSCALL},
-};
-
 #[no_mangle]
 pub unsafe extern fn do_exception_unhandled() {}
 
@@ -57,8 +50,18 @@ pub struct SyscallStack {
 #[macro_export]
 macro_rules! with_exception_stack {
     (|$stack:ident| $code:block) => {{
+        use $crate::syscall::flag::{PTRACE_FLAG_IGNORE, PTRACE_STOP_PRE_SYSCALL, PTRACE_STOP_POST_SYSCALL};
+
+        let allowed = $crate::ptrace::breakpoint_callback(PTRACE_STOP_PRE_SYSCALL, None)
+            .and_then(|_| $crate::ptrace::next_breakpoint().map(|f| !f.contains(PTRACE_FLAG_IGNORE)));
+
+        if allowed.unwrap_or(true) {
+            // If the syscall is `clone`, the clone won't return here, like on x86_64
             let $stack = &mut *$stack;
             (*$stack).scratch.x0 = $code;
+        }
+
+        $crate::ptrace::breakpoint_callback(PTRACE_STOP_POST_SYSCALL, None);
     }}
 }
 
@@ -15,6 +15,9 @@ pub enum IpiTarget {
     Other = 3,
 }
 
+/// Recorded in the journal as the target of an IPI sent to a single CPU, plus its ID
+pub const IPI_TARGET_CPU: usize = 0x100;
+
 #[cfg(not(feature = "multi_core"))]
 #[inline(always)]
 pub fn ipi(_kind: IpiKind, _target: IpiTarget) {}
@@ -22,3 +25,14 @@ pub fn ipi(_kind: IpiKind, _target: IpiTarget) {}
 #[cfg(feature = "multi_core")]
 #[inline(always)]
 pub fn ipi(kind: IpiKind, target: IpiTarget) {}
+
+/// Send an IPI to the CPU `cpu_id`, which may be the current one
+#[inline(always)]
+pub fn ipi_single(_kind: IpiKind, _cpu_id: usize) {}
+
+/// Send an IPI to every CPU whose bit is set in `mask`, bit `n` standing for CPU `n`
+pub fn ipi_mask(kind: IpiKind, mask: u64) {
+    for cpu_id in (0..64).filter(|cpu_id| mask & (1 << cpu_id) != 0) {
+        ipi_single(kind, cpu_id);
+    }
+}
@@ -10,6 +10,9 @@ pub mod debug;
 /// Devices
 pub mod device;
 
+/// Idle states
+pub mod idle;
+
 /// Interrupt instructions
 pub mod interrupt;
 
@@ -19,6 +22,9 @@ pub mod ipi;
 /// Paging
 pub mod paging;
 
+/// Performance counters
+pub mod pmu;
+
 pub mod rmm;
 
 /// Initialization and start function
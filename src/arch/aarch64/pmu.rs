@@ -0,0 +1,107 @@
+//! # Performance counters
+//! The event counters of the PMUv3, programmed for `perf`. Their overflow interrupt is not routed,
+//! so they can only count: the period of sampling counters is limited to zero. Counters are 32
+//! bits wide, which `perf` extends by reading them at least on every timer tick.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::perf::Event;
+
+/// PMCR_EL0 enable bit
+const PMCR_E: u64 = 1 << 0;
+
+/// Sampling is not supported
+pub const PERIOD_MAX: u64 = 0;
+
+/// Counters usable by `perf`, zero until `init`
+static COUNTERS: AtomicUsize = AtomicUsize::new(0);
+/// PMCEID0_EL0, with a bit set for each common event below 32 that is implemented
+static IMPLEMENTED: AtomicU64 = AtomicU64::new(0);
+
+/// The common event number of `event`
+fn number(event: Event) -> u64 {
+    match event {
+        Event::Cycles => 0x11,
+        Event::Instructions => 0x08,
+        Event::CacheReferences => 0x04,
+        Event::CacheMisses => 0x03,
+        Event::Branches => 0x21,
+        Event::BranchMisses => 0x10,
+    }
+}
+
+/// Find the counters, on the BSP
+pub unsafe fn init() {
+    let dfr0: u64;
+    asm!("mrs {}, id_aa64dfr0_el1", out(reg) dfr0);
+    // PMUVer is 0 without a PMU, and 0xF for an implementation defined one
+    let version = (dfr0 >> 8) & 0xF;
+    if version == 0 || version == 0xF {
+        return;
+    }
+
+    let pmcr: u64;
+    let pmceid0: u64;
+    asm!("mrs {}, pmcr_el0", out(reg) pmcr);
+    asm!("mrs {}, pmceid0_el0", out(reg) pmceid0);
+    let counters = ((pmcr >> 11) & 0x1F) as usize;
+    if counters == 0 {
+        return;
+    }
+
+    IMPLEMENTED.store(pmceid0, Ordering::Relaxed);
+    COUNTERS.store(counters, Ordering::Relaxed);
+    log::info!("PMU: {} counters, version {}", counters, version);
+}
+
+/// Number of counters usable by `perf`
+pub fn counters() -> usize {
+    COUNTERS.load(Ordering::Relaxed)
+}
+
+/// Returns true if `event` can be counted
+pub fn supported(event: Event) -> bool {
+    IMPLEMENTED.load(Ordering::Relaxed) & (1 << number(event)) != 0
+}
+
+/// Mask of the bits of a counter
+pub fn mask() -> u64 {
+    u64::from(u32::MAX)
+}
+
+/// Start counter `index` at `value`, counting `event`. Overflow interrupts are not supported.
+pub unsafe fn start(index: usize, event: Event, value: u64, _interrupt: bool) {
+    let bit = 1_u64 << index;
+    asm!("msr pmcntenclr_el0, {}", in(reg) bit);
+    asm!("msr pmselr_el0, {}", "isb", in(reg) index as u64);
+    // Count at EL0 and EL1
+    asm!("msr pmxevtyper_el0, {}", in(reg) number(event));
+    asm!("msr pmxevcntr_el0, {}", in(reg) value & mask());
+
+    let mut pmcr: u64;
+    asm!("mrs {}, pmcr_el0", out(reg) pmcr);
+    pmcr |= PMCR_E;
+    asm!("msr pmcr_el0, {}", in(reg) pmcr);
+    asm!("msr pmcntenset_el0, {}", "isb", in(reg) bit);
+}
+
+/// Stop counter `index`, returning its value
+pub unsafe fn stop(index: usize) -> u64 {
+    asm!("msr pmcntenclr_el0, {}", "isb", in(reg) 1_u64 << index);
+    read(index)
+}
+
+/// The value of counter `index`
+pub unsafe fn read(index: usize) -> u64 {
+    let value: u64;
+    asm!("msr pmselr_el0, {}", "isb", "mrs {}, pmxevcntr_el0", in(reg) index as u64, out(reg) value);
+    value & mask()
+}
+
+/// Counters never raise interrupts
+pub unsafe fn overflowed(_index: usize) -> bool {
+    false
+}
+
+pub unsafe fn reload(_index: usize, _value: u64) {}
@@ -8,7 +8,6 @@ use rmm::{
     KILOBYTE,
     MEGABYTE,
     Arch,
-    BuddyAllocator,
     BumpAllocator,
     FrameAllocator,
     FrameCount,
@@ -20,7 +19,10 @@ use rmm::{
     TableKind,
     VirtualAddress,
 };
-use spin::Mutex;
+
+use crate::memory::buddy::BuddyAllocator;
+use crate::memory::{firmware, numa};
+use crate::sync::Mutex;
 
 use super::CurrentRmmArch as RmmA;
 
@@ -213,7 +215,7 @@ unsafe fn inner<A: Arch>(
     let offset = bump_allocator.offset();
     log::info!("Permanently used: {} KB", (offset + (KILOBYTE - 1)) / KILOBYTE);
 
-    BuddyAllocator::<A>::new(bump_allocator).expect("failed to create BuddyAllocator")
+    BuddyAllocator::<A>::new(areas, offset).expect("failed to create BuddyAllocator")
 }
 
 // There can only be one allocator (at the moment), so making this a ZST is great!
@@ -224,14 +226,33 @@ static INNER_ALLOCATOR: Mutex<Option<BuddyAllocator<RmmA>>> = Mutex::new(None);
 
 impl FrameAllocator for LockedAllocator {
     unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
-        if let Some(ref mut allocator) = *INNER_ALLOCATOR.lock() {
-            allocator.allocate(count)
+        // Single frames come from the free list of the current CPU's node first
+        if count.data() == 1 {
+            if let Some(address) = numa::allocate_local() {
+                return Some(address);
+            }
+        }
+        let address = if let Some(ref mut allocator) = *INNER_ALLOCATOR.lock() {
+            match allocator.allocate(count) {
+                // The missing frames may be on the node free lists
+                None if count.data() > 1 && numa::drain(|address| allocator.free(address, FrameCount::new(1))) > 0 => {
+                    allocator.allocate(count)
+                }
+                address => address,
+            }
         } else {
             None
+        };
+        match address {
+            None if count.data() == 1 => numa::allocate_remote(),
+            address => address,
         }
     }
 
     unsafe fn free(&mut self, address: PhysicalAddress, count: FrameCount) {
+        if count.data() == 1 && numa::free(address) {
+            return;
+        }
         if let Some(ref mut allocator) = *INNER_ALLOCATOR.lock() {
             allocator.free(address, count)
         }
@@ -239,7 +260,9 @@ impl FrameAllocator for LockedAllocator {
 
     unsafe fn usage(&self) -> FrameUsage {
         if let Some(ref allocator) = *INNER_ALLOCATOR.lock() {
-            allocator.usage()
+            // Frames on the node free lists are free, although the allocator does not know
+            let usage = allocator.usage();
+            FrameUsage::new(FrameCount::new(usage.used().data() - numa::free_frames()), usage.total())
         } else {
             FrameUsage::new(FrameCount::new(0), FrameCount::new(0))
         }
@@ -375,9 +398,19 @@ pub unsafe fn init(
     // Copy memory map from bootloader location, and page align it
     let mut area_i = 0;
     for bootloader_area in bootloader_areas.iter() {
-        if { bootloader_area.kind } != BootloaderMemoryKind::Free {
+        match { bootloader_area.kind } {
+            BootloaderMemoryKind::Free => (),
+            // Kept for reading through `physmem:`
+            BootloaderMemoryKind::Reclaim => {
+                firmware::add(bootloader_area.base, bootloader_area.size, firmware::Kind::Reclaim);
+                continue;
+            }
+            BootloaderMemoryKind::Reserved => {
+                firmware::add(bootloader_area.base, bootloader_area.size, firmware::Kind::Reserved);
+                continue;
+            }
             // Not a free area
-            continue;
+            BootloaderMemoryKind::Null => continue,
         }
 
         let mut base = bootloader_area.base as usize;
@@ -16,7 +16,7 @@ use crate::devices::graphical_debug;
 use crate::init::device_tree;
 use crate::interrupt;
 use crate::log::{self, info};
-use crate::paging::{self, KernelMapper};
+use crate::paging::{self, KernelMapper, RmmA, RmmArch, TableKind};
 
 /// Test of zero values in BSS.
 static BSS_TEST_ZERO: usize = 0;
@@ -95,13 +95,14 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
         log::init_logger(|r| {
             use core::fmt::Write;
             let _ = write!(
-                crate::debug::Writer::new(),
+                crate::debug::Writer::with(log::syslog_level(r.level()), log::FACILITY_KERNEL),
                 "{}:{} -- {}\n",
                 r.target(),
                 r.level(),
                 r.args()
             );
         });
+        log::configure(env);
 
         info!("Redox OS starting...");
         info!("Kernel: {:X}:{:X}", {args.kernel_base}, args.kernel_base + args.kernel_size);
@@ -121,6 +122,9 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
             tmp = out(reg) _,
         );
 
+        // Enable software step for ptrace
+        interrupt::exception::init();
+
         /* NOT USED WITH UEFI
         device_tree::fill_memory_map(crate::PHYS_OFFSET + dtb_base, dtb_size);
 
@@ -162,15 +166,28 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
         #[cfg(feature = "graphical_debug")]
         graphical_debug::init_heap();
 
-        // Activate memory logging
-        log::init();
-
         // Initialize devices
         device::init();
 
+        // Drive the virtio console and entropy device until userspace drivers start
+        if args.dtb_base != 0 {
+            device_tree::add_virtio_mmio(crate::PHYS_OFFSET + args.dtb_base, args.dtb_size);
+        }
+        crate::devices::virtio::init();
+
         // Initialize all of the non-core devices not otherwise needed to complete initialization
         device::init_noncore();
 
+        // Find the performance counters
+        crate::arch::pmu::init();
+
+        // Use the hardware watchdog, if any
+        if args.dtb_base != 0 {
+            if let Some((address, rate)) = device_tree::find_sp805(crate::PHYS_OFFSET + args.dtb_base, args.dtb_size) {
+                device::sp805::init(address, rate);
+            }
+        }
+
         // Stop graphical debug
         #[cfg(feature = "graphical_debug")]
         graphical_debug::fini();
@@ -180,6 +197,7 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
         crate::Bootstrap {
             base: crate::memory::Frame::containing_address(crate::paging::PhysicalAddress::new(args.bootstrap_base)),
             page_count: args.bootstrap_size / crate::memory::PAGE_SIZE,
+            size: args.bootstrap_size,
             entry: args.bootstrap_entry,
             env,
         }
@@ -198,7 +216,53 @@ pub struct KernelArgsAp {
 
 /// Entry to rust for an AP
 pub unsafe extern fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
-    loop{}
+    let cpu_id = {
+        let args = &*args_ptr;
+        let cpu_id = args.cpu_id as usize;
+        let bsp_table = args.page_table as usize;
+
+        assert_eq!(BSS_TEST_ZERO, 0);
+        assert_eq!(DATA_TEST_NONZERO, 0xFFFF_FFFF_FFFF_FFFF);
+
+        // Setup interrupt handlers
+        core::arch::asm!(
+            "
+            ldr {tmp}, =exception_vector_base
+            msr vbar_el1, {tmp}
+            ",
+            tmp = out(reg) _,
+        );
+
+        // Enable software step for ptrace
+        interrupt::exception::init();
+
+        // Initialize paging, with the kernel page table of the BSP
+        RmmA::set_table(TableKind::Kernel, PhysicalAddress::new(bsp_table));
+        paging::init_ap(cpu_id, &mut KernelMapper::lock());
+
+        // Test tdata and tbss
+        {
+            assert_eq!(TBSS_TEST_ZERO, 0);
+            TBSS_TEST_ZERO += 1;
+            assert_eq!(TBSS_TEST_ZERO, 1);
+            assert_eq!(TDATA_TEST_NONZERO, 0xFFFF_FFFF_FFFF_FFFF);
+            TDATA_TEST_NONZERO -= 1;
+            assert_eq!(TDATA_TEST_NONZERO, 0xFFFF_FFFF_FFFF_FFFE);
+        }
+
+        // Initialize devices (for AP)
+        device::init_ap();
+
+        AP_READY.store(true, Ordering::SeqCst);
+
+        cpu_id
+    };
+
+    while ! BSP_READY.load(Ordering::SeqCst) {
+        interrupt::pause();
+    }
+
+    crate::kmain_ap(cpu_id);
 }
 
 #[naked]
@@ -207,7 +271,10 @@ pub unsafe extern fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
 pub unsafe extern "C" fn usermode(_ip: usize, _sp: usize, _arg: usize, _is_singlestep: usize) -> ! {
     core::arch::asm!(
         "
-        msr spsr_el1, xzr // spsr
+        cmp x3, #0
+        cset x3, ne
+        lsl x3, x3, #21 // SPSR_EL1.SS, software step
+        msr spsr_el1, x3 // spsr
         msr elr_el1, x0 // ip
         msr sp_el0, x1 // sp
         mov x0, x2 // arg
@@ -3,7 +3,8 @@ core::arch::global_asm!(
     //  Exception vector stubs
     //
     //  The hex values in x18 are to aid debugging
-    //  Unhandled exceptions spin in a wfi loop for the moment
+    //  Exceptions from EL0 are all handled. At EL1, only IRQs and usercopy faults are, the
+    //  others spin in a loop for the moment. FIQs are not used.
     //  This can be macro-ified
 
 .globl exception_vector_base
@@ -94,21 +95,21 @@ __vec_10:
     .align 7
 __vec_11:
     mov     x18, #0xb0bb
-    b       unhandled_exception
+    b       serror_at_el0
     b       __vec_11
 
     // Synchronous
     .align 7
 __vec_12:
     mov     x18, #0xb0bc
-    b       unhandled_exception
+    b       exception_at_el0_aarch32
     b       __vec_12
 
     // IRQ
     .align 7
 __vec_13:
     mov     x18, #0xb0bd
-    b       unhandled_exception
+    b       irq_at_el0
     b       __vec_13
 
     // FIQ
@@ -122,7 +123,7 @@ __vec_14:
     .align 7
 __vec_15:
     mov     x18, #0xb0bf
-    b       unhandled_exception
+    b       exception_at_el0_aarch32
     b       __vec_15
     
     .align 7
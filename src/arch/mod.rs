@@ -4,6 +4,12 @@ pub mod aarch64;
 #[cfg(target_arch = "aarch64")]
 pub use self::aarch64::*;
 
+#[cfg(target_arch = "riscv64")]
+#[macro_use]
+pub mod riscv64;
+#[cfg(target_arch = "riscv64")]
+pub use self::riscv64::*;
+
 #[cfg(target_arch = "x86")]
 #[macro_use]
 pub mod x86;
@@ -0,0 +1,50 @@
+// Because the memory map is so important to not be aliased, it is defined here, in one place
+// The lower 256 top-level entries are reserved for userspace
+// Each top-level entry references up to 512 GB of memory with Sv48, and 1 GB with Sv39
+// The kernel image is in the last 2 GB, with Sv39 that is the second from the top entry (510), and
+// with Sv48 the top one (511). There is no recursive mapping on this architecture.
+/// The size of a single PML4
+#[cfg(not(feature = "riscv_sv39"))]
+pub const PML4_SIZE: usize = 0x0000_0080_0000_0000;
+#[cfg(not(feature = "riscv_sv39"))]
+pub const PML4_MASK: usize = 0x0000_ff80_0000_0000;
+#[cfg(feature = "riscv_sv39")]
+pub const PML4_SIZE: usize = 0x0000_0000_4000_0000;
+#[cfg(feature = "riscv_sv39")]
+pub const PML4_MASK: usize = 0x0000_007f_c000_0000;
+
+/// Offset of kernel
+pub const KERNEL_OFFSET: usize = 0xFFFF_FFFF_8000_0000;
+pub const KERNEL_PML4: usize = (KERNEL_OFFSET & PML4_MASK)/PML4_SIZE;
+/// Start of the top-level entry of the kernel
+const KERNEL_PML4_OFFSET: usize = KERNEL_OFFSET & !(PML4_SIZE - 1);
+
+/// Offset to kernel heap
+pub const KERNEL_HEAP_OFFSET: usize = KERNEL_PML4_OFFSET - PML4_SIZE;
+pub const KERNEL_HEAP_PML4: usize = (KERNEL_HEAP_OFFSET & PML4_MASK)/PML4_SIZE;
+/// Size of kernel heap
+pub const KERNEL_HEAP_SIZE: usize = 1 * 1024 * 1024; // 1 MB
+
+/// Offset of temporary mapping for misc kernel bring-up actions
+pub const KERNEL_TMP_MISC_OFFSET: usize = KERNEL_HEAP_OFFSET - PML4_SIZE;
+
+/// Offset to kernel percpu variables
+pub const KERNEL_PERCPU_OFFSET: usize = KERNEL_TMP_MISC_OFFSET - PML4_SIZE;
+pub const KERNEL_PERCPU_PML4: usize = (KERNEL_PERCPU_OFFSET & PML4_MASK)/PML4_SIZE;
+/// Size of kernel percpu variables
+pub const KERNEL_PERCPU_SHIFT: u8 = 16; // 2^16 = 64 KiB
+pub const KERNEL_PERCPU_SIZE: usize = 1_usize << KERNEL_PERCPU_SHIFT;
+
+/// Offset of physmap
+// This needs to match RMM's PHYS_OFFSET
+#[cfg(not(feature = "riscv_sv39"))]
+pub const PHYS_OFFSET: usize = 0xFFFF_8000_0000_0000;
+#[cfg(feature = "riscv_sv39")]
+pub const PHYS_OFFSET: usize = 0xFFFF_FFC0_0000_0000;
+pub const PHYS_PML4: usize = (PHYS_OFFSET & PML4_MASK)/PML4_SIZE;
+
+/// Offset to user image
+pub const USER_OFFSET: usize = 0;
+
+/// End offset of the user image, i.e. kernel start
+pub const USER_END_OFFSET: usize = 256 * PML4_SIZE;
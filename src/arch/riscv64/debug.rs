@@ -0,0 +1,83 @@
+use core::fmt;
+
+use crate::log::{Kmsg, Line, FACILITY_KERNEL, KMSG, LEVEL_INFO};
+
+#[cfg(feature = "graphical_debug")]
+use crate::devices::graphical_debug::{DEBUG_DISPLAY, DebugDisplay};
+#[cfg(feature = "virtio_debug")]
+use crate::devices::virtio::console::{CONSOLE as VIRTIO_CONSOLE, Console as VirtioConsole};
+use crate::sync::MutexGuard;
+#[cfg(feature = "serial_debug")]
+use super::device::serial::{COM1, SbiConsole};
+
+pub struct Writer<'a> {
+    log: MutexGuard<'a, Kmsg>,
+    level: u8,
+    facility: u8,
+    #[cfg(feature = "graphical_debug")]
+    display: MutexGuard<'a, Option<DebugDisplay>>,
+    #[cfg(feature = "virtio_debug")]
+    virtio: MutexGuard<'a, Option<VirtioConsole>>,
+    #[cfg(feature = "serial_debug")]
+    serial: MutexGuard<'a, SbiConsole>,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new() -> Writer<'a> {
+        Self::with(LEVEL_INFO, FACILITY_KERNEL)
+    }
+
+    /// A writer of lines with the syslog `level` and `facility`
+    pub fn with(level: u8, facility: u8) -> Writer<'a> {
+        Writer {
+            log: KMSG.lock(),
+            level,
+            facility,
+            #[cfg(feature = "graphical_debug")]
+            display: DEBUG_DISPLAY.lock(),
+            #[cfg(feature = "virtio_debug")]
+            virtio: VIRTIO_CONSOLE.lock(),
+            #[cfg(feature = "serial_debug")]
+            serial: COM1.lock(),
+        }
+    }
+
+    /// Add the lines in `buf` to the log, and print those completed on the consoles
+    pub fn write(&mut self, buf: &[u8]) {
+        self.log.write(self.level, self.facility, buf);
+        let mut line = Line::new();
+        while self.log.next_console(&mut line) {
+            self.write_console(line.as_bytes());
+        }
+    }
+
+    fn write_console(&mut self, buf: &[u8]) {
+        #[cfg(feature = "graphical_debug")]
+        {
+            if let Some(ref mut display) = *self.display {
+                let _ = display.write(buf);
+            }
+        }
+
+        #[cfg(feature = "virtio_debug")]
+        {
+            if crate::log::ready_sinks().contains(crate::log::Sinks::VIRTIO) {
+                if let Some(ref mut console) = *self.virtio {
+                    console.write(buf);
+                }
+            }
+        }
+
+        #[cfg(feature = "serial_debug")]
+        {
+            self.serial.write(buf);
+        }
+    }
+}
+
+impl<'a> fmt::Write for Writer<'a> {
+    fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,19 @@
+use core::fmt::{Result, Write};
+
+use crate::arch::{device_tree, sbi};
+
+pub fn cpu_info<W: Write>(w: &mut W) -> Result {
+    let (vendor, arch, imp) = sbi::machine_ids();
+
+    writeln!(w, "Vendor ID: {:#x}", vendor)?;
+    writeln!(w, "Architecture ID: {:#x}", arch)?;
+    writeln!(w, "Implementation ID: {:#x}", imp)?;
+    write!(w, "Harts:")?;
+    for cpu_id in 0..crate::cpu_count() {
+        write!(w, " {}", device_tree::hart_id(cpu_id))?;
+    }
+    writeln!(w)?;
+    writeln!(w)?;
+
+    Ok(())
+}
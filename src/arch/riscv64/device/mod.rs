@@ -0,0 +1,19 @@
+pub mod cpu;
+pub mod plic;
+pub mod serial;
+pub mod timer;
+
+pub unsafe fn init() {
+    println!("PLIC INIT");
+    plic::init();
+    println!("TIMER INIT");
+    timer::init();
+}
+
+/// The SBI console, the only non-core device, is set up early in `kstart`
+pub unsafe fn init_noncore() {}
+
+pub unsafe fn init_ap() {
+    plic::init_ap();
+    timer::init_ap();
+}
@@ -0,0 +1,140 @@
+//! # PLIC
+//! The Platform-Level Interrupt Controller routes the interrupts of devices, its sources, to the
+//! external interrupt of harts. Each hart has a context for each privilege mode, found through the
+//! device tree. Sources are all routed to the S-mode context of the BSP, and an IRQ number of the
+//! irq scheme is the source ID.
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::device_tree::{self, NO_CONTEXT, PLIC_CONTEXTS};
+use crate::memory::Frame;
+use crate::paging::{KernelMapper, Page, PageFlags, PhysicalAddress, VirtualAddress};
+
+const PRIORITY: usize = 0x0000;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
+/// Maximum number of sources defined by the specification
+const MAX_SOURCES: usize = 1024;
+
+/// Virtual address of the PLIC, zero if there is none
+static BASE: AtomicUsize = AtomicUsize::new(0);
+static SOURCES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe fn read(offset: usize) -> u32 {
+    ptr::read_volatile((BASE.load(Ordering::Relaxed) + offset) as *const u32)
+}
+
+unsafe fn write(offset: usize, value: u32) {
+    ptr::write_volatile((BASE.load(Ordering::Relaxed) + offset) as *mut u32, value)
+}
+
+fn context(cpu_id: usize) -> Option<usize> {
+    match PLIC_CONTEXTS.get(cpu_id)?.load(Ordering::Relaxed) {
+        NO_CONTEXT => None,
+        context => Some(context),
+    }
+}
+
+unsafe fn map_mmio(address: usize, size: usize) {
+    let mut mapper = KernelMapper::lock();
+
+    let start_frame = Frame::containing_address(PhysicalAddress::new(address));
+    let end_frame = Frame::containing_address(PhysicalAddress::new(address + size - 1));
+    for frame in Frame::range_inclusive(start_frame, end_frame) {
+        let page = Page::containing_address(VirtualAddress::new(frame.start_address().data() + crate::PHYS_OFFSET));
+        mapper
+            .get_mut()
+            .expect("failed to access KernelMapper for mapping PLIC")
+            .map_phys(page.start_address(), frame.start_address(), PageFlags::new().write(true))
+            .expect("failed to map PLIC")
+            .flush();
+    }
+}
+
+/// Map the PLIC and mask all sources, leaving the BSP to accept interrupts of any priority
+pub unsafe fn init() {
+    let address = device_tree::PLIC.load(Ordering::SeqCst);
+    if address == 0 {
+        println!("PLIC not found");
+        return;
+    }
+    let size = device_tree::PLIC_SIZE.load(Ordering::SeqCst);
+    map_mmio(address, size);
+    BASE.store(crate::PHYS_OFFSET + address, Ordering::SeqCst);
+
+    // Source 0 does not exist
+    let sources = (device_tree::PLIC_NDEV.load(Ordering::SeqCst) + 1).min(MAX_SOURCES);
+    SOURCES.store(sources, Ordering::SeqCst);
+    println!("PLIC at {:X}, {} sources", address, sources - 1);
+
+    for cpu_id in 0..device_tree::HART_COUNT.load(Ordering::SeqCst) {
+        if let Some(context) = context(cpu_id) {
+            for word in 0..(sources + 31) / 32 {
+                write(ENABLE + ENABLE_STRIDE * context + 4 * word, 0);
+            }
+        }
+    }
+    for source in 1..sources {
+        write(PRIORITY + 4 * source, 1);
+    }
+
+    init_ap();
+}
+
+/// Accept interrupts on the current hart
+pub unsafe fn init_ap() {
+    if BASE.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    if let Some(context) = context(crate::cpu_id()) {
+        write(CONTEXT + CONTEXT_STRIDE * context + CONTEXT_THRESHOLD, 0);
+    }
+}
+
+/// Number of IRQs, including the nonexistent source 0
+pub fn irq_count() -> usize {
+    SOURCES.load(Ordering::Relaxed)
+}
+
+unsafe fn set_enabled(source: u32, enabled: bool) {
+    let source = source as usize;
+    if BASE.load(Ordering::Relaxed) == 0 || source == 0 || source >= irq_count() {
+        return;
+    }
+    // Sources are only enabled on the BSP
+    if let Some(context) = context(0) {
+        let offset = ENABLE + ENABLE_STRIDE * context + 4 * (source / 32);
+        let bit = 1 << (source % 32);
+        let value = read(offset);
+        write(offset, if enabled { value | bit } else { value & !bit });
+    }
+}
+
+pub unsafe fn irq_enable(source: u32) {
+    set_enabled(source, true);
+}
+
+pub unsafe fn irq_disable(source: u32) {
+    set_enabled(source, false);
+}
+
+/// Claim the highest priority pending source of the current hart, zero if there is none
+pub unsafe fn irq_claim() -> u32 {
+    match (BASE.load(Ordering::Relaxed), context(crate::cpu_id())) {
+        (0, _) | (_, None) => 0,
+        (_, Some(context)) => read(CONTEXT + CONTEXT_STRIDE * context + CONTEXT_CLAIM),
+    }
+}
+
+/// Complete the handling of a claimed source, allowing it to be claimed again
+pub unsafe fn irq_complete(source: u32) {
+    if let Some(context) = context(crate::cpu_id()) {
+        write(CONTEXT + CONTEXT_STRIDE * context + CONTEXT_CLAIM, source);
+    }
+}
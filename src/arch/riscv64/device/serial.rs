@@ -0,0 +1,52 @@
+//! The SBI console, used as the serial port. The firmware owns the UART, and has no interrupt for
+//! received bytes, so they are polled on every timer tick.
+
+use crate::arch::sbi;
+use crate::scheme::debug::{debug_input, debug_notify};
+use crate::sync::Mutex;
+
+pub struct SbiConsole;
+
+impl SbiConsole {
+    pub fn receive(&mut self) {
+        let mut received = false;
+        while let Some(c) = sbi::console_getchar() {
+            if c != 0 {
+                debug_input(c);
+                received = true;
+            }
+        }
+        if received {
+            debug_notify();
+        }
+    }
+
+    pub fn send(&mut self, data: u8) {
+        sbi::console_putchar(data);
+    }
+
+    pub fn write(&mut self, buf: &[u8]) {
+        for &b in buf {
+            match b {
+                8 | 0x7F => {
+                    self.send(8);
+                    self.send(b' ');
+                    self.send(8);
+                }
+                b'\n' => {
+                    self.send(b'\r');
+                    self.send(b'\n');
+                }
+                _ => {
+                    self.send(b);
+                }
+            }
+        }
+    }
+}
+
+pub static COM1: Mutex<SbiConsole> = Mutex::new(SbiConsole);
+
+pub unsafe fn init() {
+    crate::log::set_sinks_ready(crate::log::Sinks::SERIAL, true);
+}
@@ -0,0 +1,27 @@
+//! The supervisor timer, programmed through SBI to fire every 10 ms on each hart
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::arch::{device_tree, sbi, time};
+
+/// Supervisor timer interrupt enable, in `sie`
+const SIE_STIE: usize = 1 << 5;
+
+/// Ticks of the `time` CSR between timer interrupts
+static RELOAD: AtomicU64 = AtomicU64::new(0);
+
+pub unsafe fn init() {
+    RELOAD.store(device_tree::TIMEBASE_FREQUENCY.load(Ordering::SeqCst) / 100, Ordering::SeqCst);
+    init_ap();
+}
+
+pub unsafe fn init_ap() {
+    reload();
+    asm!("csrs sie, {}", in(reg) SIE_STIE);
+}
+
+/// Program the next tick, which also clears the pending timer interrupt
+pub fn reload() {
+    sbi::set_timer(time::ticks() + RELOAD.load(Ordering::Relaxed));
+}
@@ -0,0 +1,176 @@
+//! # Device tree
+//! The bootloader passes the flattened device tree of the board, read once at boot for what the
+//! kernel needs itself: the harts, the frequency of the `time` CSR and the PLIC. Everything else
+//! is left to userspace drivers.
+
+extern crate byteorder;
+extern crate fdt;
+
+use core::slice;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use self::byteorder::{ByteOrder, BE};
+
+/// Maximum number of harts
+pub const MAX_HARTS: usize = 64;
+
+/// Hart ID of each CPU ID, the BSP being CPU 0
+pub static HART_IDS: [AtomicUsize; MAX_HARTS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    [ZERO; MAX_HARTS]
+};
+/// Number of harts able to run the kernel, with an MMU
+pub static HART_COUNT: AtomicUsize = AtomicUsize::new(1);
+/// Frequency of the `time` CSR, in Hz
+pub static TIMEBASE_FREQUENCY: AtomicU64 = AtomicU64::new(10_000_000);
+/// Physical address and size of the PLIC, if any
+pub static PLIC: AtomicUsize = AtomicUsize::new(0);
+pub static PLIC_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// Number of interrupt sources of the PLIC
+pub static PLIC_NDEV: AtomicUsize = AtomicUsize::new(0);
+/// PLIC context of the S-mode external interrupt of each CPU ID, `NO_CONTEXT` if there is none
+pub static PLIC_CONTEXTS: [AtomicUsize; MAX_HARTS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicUsize = AtomicUsize::new(NO_CONTEXT);
+    [NONE; MAX_HARTS]
+};
+pub const NO_CONTEXT: usize = !0;
+
+/// Supervisor external interrupt, in `interrupts-extended`
+const IRQ_S_EXT: usize = 9;
+
+/// Read a number of `cells` 32-bit big-endian cells
+fn read_cells(data: &[u8], cells: usize) -> Option<usize> {
+    let bytes = data.get(..cells * 4)?;
+    Some(bytes.chunks(4).fold(0, |value, chunk| (value << 32) | BE::read_u32(chunk) as usize))
+}
+
+fn property<'a>(node: &fdt::Node<'a>, name: &str) -> Option<&'a [u8]> {
+    node.properties().find(|p| p.name == name).map(|p| p.data)
+}
+
+/// Returns true if the string list of the property `name` contains `value`
+fn property_has(node: &fdt::Node, name: &str, value: &str) -> bool {
+    property(node, name).map_or(false, |data| {
+        data.split(|&b| b == 0).any(|s| s == value.as_bytes())
+    })
+}
+
+pub unsafe fn init(dtb_base: usize, dtb_size: usize) {
+    let data = slice::from_raw_parts(dtb_base as *const u8, dtb_size);
+    let dt = match fdt::DeviceTree::new(data) {
+        Ok(dt) => dt,
+        Err(_) => {
+            println!("Invalid device tree at {:X}:{:X}", dtb_base, dtb_base + dtb_size);
+            return;
+        }
+    };
+
+    let root = dt.nodes().next().expect("device tree without root node");
+    let address_cells = property(&root, "#address-cells").and_then(|d| read_cells(d, 1)).unwrap_or(2);
+    let size_cells = property(&root, "#size-cells").and_then(|d| read_cells(d, 1)).unwrap_or(1);
+
+    let boot_hart_id = dt.find_node("/chosen")
+        .and_then(|(chosen, _)| property(&chosen, "boot-hartid"))
+        .and_then(|data| read_cells(data, data.len() / 4));
+
+    if let Some((cpus, _)) = dt.find_node("/cpus") {
+        if let Some(freq) = property(&cpus, "timebase-frequency").and_then(|data| read_cells(data, data.len() / 4)) {
+            TIMEBASE_FREQUENCY.store(freq as u64, Ordering::SeqCst);
+        }
+    }
+
+    // Harts without an MMU, like the S7 monitor core of the JH7110, cannot run the kernel
+    let hart_of = |node: &fdt::Node| -> Option<usize> {
+        if !property_has(node, "device_type", "cpu")
+            || property_has(node, "status", "disabled")
+            || property(node, "mmu-type").is_none()
+        {
+            return None;
+        }
+        property(node, "reg").and_then(|data| read_cells(data, data.len() / 4))
+    };
+    // Without `boot-hartid`, the bootloader is assumed to run on the first hart
+    let boot_hart_id = boot_hart_id.or_else(|| dt.nodes().find_map(|node| hart_of(&node)));
+
+    let mut count = 1;
+    for hart_id in dt.nodes().filter_map(|node| hart_of(&node)) {
+        if Some(hart_id) == boot_hart_id {
+            HART_IDS[0].store(hart_id, Ordering::SeqCst);
+        } else if count < MAX_HARTS {
+            HART_IDS[count].store(hart_id, Ordering::SeqCst);
+            count += 1;
+        }
+    }
+    HART_COUNT.store(count, Ordering::SeqCst);
+
+    // The interrupt controller of each hart is a child of its CPU node, listed right after it
+    let mut intc_harts = [(0, 0); MAX_HARTS];
+    let mut intc_count = 0;
+    let mut last_hart = None;
+    for node in dt.nodes() {
+        if property_has(&node, "device_type", "cpu") {
+            last_hart = property(&node, "reg").and_then(|data| read_cells(data, data.len() / 4));
+        } else if property_has(&node, "compatible", "riscv,cpu-intc") {
+            let phandle = property(&node, "phandle").and_then(|data| read_cells(data, 1));
+            if let (Some(phandle), Some(hart_id), true) = (phandle, last_hart, intc_count < MAX_HARTS) {
+                intc_harts[intc_count] = (phandle, hart_id);
+                intc_count += 1;
+            }
+        }
+    }
+
+    for node in dt.nodes() {
+        if !property_has(&node, "compatible", "riscv,plic0") && !property_has(&node, "compatible", "sifive,plic-1.0.0") {
+            continue;
+        }
+        if let Some(reg) = property(&node, "reg") {
+            let base = read_cells(reg, address_cells);
+            let size = reg.get(address_cells * 4..).and_then(|data| read_cells(data, size_cells));
+            if let (Some(base), Some(size)) = (base, size) {
+                PLIC.store(base, Ordering::SeqCst);
+                PLIC_SIZE.store(size, Ordering::SeqCst);
+            }
+        }
+        if let Some(ndev) = property(&node, "riscv,ndev").and_then(|data| read_cells(data, 1)) {
+            PLIC_NDEV.store(ndev, Ordering::SeqCst);
+        }
+        // Contexts are numbered in the order of `interrupts-extended`, which pairs the phandle of a
+        // hart interrupt controller with the M-mode or S-mode external interrupt
+        if let Some(interrupts) = property(&node, "interrupts-extended") {
+            for (context, pair) in interrupts.chunks_exact(8).enumerate() {
+                let (phandle, irq) = (BE::read_u32(&pair[..4]) as usize, BE::read_u32(&pair[4..]) as usize);
+                if irq != IRQ_S_EXT {
+                    continue;
+                }
+                let hart_id = intc_harts[..intc_count].iter().find(|&&(p, _)| p == phandle).map(|&(_, hart_id)| hart_id);
+                if let Some(cpu_id) = hart_id.and_then(cpu_id_of) {
+                    PLIC_CONTEXTS[cpu_id].store(context, Ordering::SeqCst);
+                }
+            }
+        }
+        break;
+    }
+
+    // Driven by the kernel until userspace drivers start (see `devices::virtio`)
+    for node in dt.nodes().filter(|node| property_has(node, "compatible", "virtio,mmio")) {
+        if let Some(reg) = property(&node, "reg") {
+            let base = read_cells(reg, address_cells);
+            let size = reg.get(address_cells * 4..).and_then(|data| read_cells(data, size_cells));
+            if let (Some(base), Some(size)) = (base, size) {
+                crate::devices::virtio::add_mmio(base, size);
+            }
+        }
+    }
+}
+
+/// Returns the CPU ID of `hart_id`
+pub fn cpu_id_of(hart_id: usize) -> Option<usize> {
+    (0..HART_COUNT.load(Ordering::SeqCst)).find(|&cpu_id| HART_IDS[cpu_id].load(Ordering::SeqCst) == hart_id)
+}
+
+/// Returns the hart ID of `cpu_id`
+pub fn hart_id(cpu_id: usize) -> usize {
+    HART_IDS[cpu_id].load(Ordering::SeqCst)
+}
@@ -0,0 +1,142 @@
+use crate::{
+    interrupt::stack_trace,
+    paging::{PageMapper, TableKind, VirtualAddress},
+    ptrace,
+    syscall,
+    syscall::abi::{BUS_ADRALN, SEGV_ACCERR, SEGV_MAPERR},
+    syscall::flag::*,
+    with_exception_stack,
+};
+
+use super::InterruptStack;
+
+// Exception causes, from `scause`
+const INSTRUCTION_MISALIGNED: usize = 0;
+const INSTRUCTION_ACCESS_FAULT: usize = 1;
+const ILLEGAL_INSTRUCTION: usize = 2;
+const BREAKPOINT: usize = 3;
+const LOAD_MISALIGNED: usize = 4;
+const LOAD_ACCESS_FAULT: usize = 5;
+const STORE_MISALIGNED: usize = 6;
+const STORE_ACCESS_FAULT: usize = 7;
+const ECALL_FROM_U: usize = 8;
+const INSTRUCTION_PAGE_FAULT: usize = 12;
+const LOAD_PAGE_FAULT: usize = 13;
+const STORE_PAGE_FAULT: usize = 15;
+
+/// Size of `ecall`, which `sepc` points to
+const ECALL_SIZE: usize = 4;
+
+fn is_page_fault(scause: usize) -> bool {
+    matches!(scause, INSTRUCTION_PAGE_FAULT | LOAD_PAGE_FAULT | STORE_PAGE_FAULT)
+}
+
+/// Returns true if `address` is mapped in the current user page table, so that the fault was a
+/// permission fault
+fn is_user_mapped(address: VirtualAddress) -> bool {
+    let mapper = unsafe { PageMapper::current(TableKind::User, crate::rmm::FRAME_ALLOCATOR) };
+    mapper.translate(address).is_some()
+}
+
+pub unsafe fn handle(stack: &mut InterruptStack, scause: usize) {
+    if stack.is_user() {
+        user_exception(stack, scause);
+    } else {
+        kernel_exception(stack, scause);
+    }
+}
+
+unsafe fn kernel_exception(stack: &mut InterruptStack, scause: usize) {
+    let address = VirtualAddress::new(stack.iret.stval);
+
+    if is_page_fault(scause) && address.kind() == TableKind::User {
+        extern "C" {
+            static __usercopy_start: u8;
+            static __usercopy_end: u8;
+        }
+        let usercopy = (&__usercopy_start as *const _ as usize)..(&__usercopy_end as *const _ as usize);
+
+        if usercopy.contains(&{ stack.iret.sepc }) {
+            // Lazily allocated memory is backed by a frame on first access by usercopy, as it is
+            // on first access by userspace
+            if !is_user_mapped(address) && crate::context::memory::try_demand_page(address, false) {
+                return;
+            }
+
+            // This was a usercopy page fault. Set the return value to nonzero to indicate usercopy
+            // failure (EFAULT), and emulate the return instruction by setting the return pointer
+            // to the saved RA value.
+            stack.iret.sepc = stack.scratch.ra;
+            stack.scratch.a0 = 1;
+            return;
+        }
+    }
+
+    println!("Unhandled exception in S-mode, cause {}, value {:>016X}", scause, { stack.iret.stval });
+    stack.dump();
+    stack_trace();
+    loop {}
+}
+
+unsafe fn user_exception(stack: &mut InterruptStack, scause: usize) {
+    match scause {
+        ECALL_FROM_U => {
+            // Return after the ecall, also in the child of clone, which copies the stack
+            stack.iret.sepc += ECALL_SIZE;
+            with_exception_stack!(|stack| {
+                let scratch = &stack.scratch;
+                syscall::syscall(scratch.a7, scratch.a0, scratch.a1, scratch.a2, scratch.a3, scratch.a4, stack)
+            })
+        }
+        INSTRUCTION_PAGE_FAULT | LOAD_PAGE_FAULT | STORE_PAGE_FAULT => {
+            let address = VirtualAddress::new(stack.iret.stval);
+            let mapped = address.kind() == TableKind::User && is_user_mapped(address);
+            // Lazily allocated memory is backed by a frame on first access
+            if address.kind() == TableKind::User && !mapped && crate::context::memory::try_demand_page(address, true) {
+                return;
+            }
+            println!("Page fault: {:>016X}", address.data());
+            println!("  Present: {}", mapped);
+            println!("  Write: {}", scause == STORE_PAGE_FAULT);
+            println!("  Instruction fetch: {}", scause == INSTRUCTION_PAGE_FAULT);
+            stack.dump();
+            stack_trace();
+            let code = if mapped { SEGV_ACCERR } else { SEGV_MAPERR };
+            crate::ksignal_fault(SIGSEGV, code, address.data());
+        }
+        INSTRUCTION_MISALIGNED | LOAD_MISALIGNED | STORE_MISALIGNED => {
+            println!("Alignment fault");
+            stack.dump();
+            stack_trace();
+            crate::ksignal_fault(SIGBUS, BUS_ADRALN, stack.iret.stval);
+        }
+        INSTRUCTION_ACCESS_FAULT | LOAD_ACCESS_FAULT | STORE_ACCESS_FAULT => {
+            // Denied by physical memory protection, or a bus error
+            println!("Access fault: {:>016X}", { stack.iret.stval });
+            stack.dump();
+            stack_trace();
+            crate::ksignal_fault(SIGBUS, 0, stack.iret.stval);
+        }
+        BREAKPOINT => {
+            // SEPC already points to the ebreak instruction, like RIP is adjusted to point to int3
+            // on x86_64
+            if ptrace::breakpoint_callback(PTRACE_STOP_BREAKPOINT, None).is_none() {
+                println!("Breakpoint trap");
+                stack.dump();
+                crate::ksignal(SIGTRAP);
+            }
+        }
+        ILLEGAL_INSTRUCTION => {
+            println!("Invalid opcode fault");
+            stack.dump();
+            stack_trace();
+            crate::ksignal(SIGILL);
+        }
+        _ => {
+            println!("Unhandled exception in U-mode, cause {}", scause);
+            stack.dump();
+            stack_trace();
+            crate::ksignal(SIGILL);
+        }
+    }
+}
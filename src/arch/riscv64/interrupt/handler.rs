@@ -0,0 +1,216 @@
+use crate::syscall::IntRegisters;
+
+/// Previous privilege, in `sstatus`: set if the trap was taken from S-mode
+pub const SSTATUS_SPP: usize = 1 << 8;
+
+/// Registers not preserved across calls, in the order the trap entry saves them
+#[derive(Default)]
+#[repr(C)]
+pub struct ScratchRegisters {
+    pub ra: usize,
+    pub t0: usize,
+    pub t1: usize,
+    pub t2: usize,
+    pub a0: usize,
+    pub a1: usize,
+    pub a2: usize,
+    pub a3: usize,
+    pub a4: usize,
+    pub a5: usize,
+    pub a6: usize,
+    pub a7: usize,
+    pub t3: usize,
+    pub t4: usize,
+    pub t5: usize,
+    pub t6: usize,
+}
+
+impl ScratchRegisters {
+    pub fn dump(&self) {
+        println!("RA:    {:>016X}", { self.ra });
+        println!("T0:    {:>016X}", { self.t0 });
+        println!("T1:    {:>016X}", { self.t1 });
+        println!("T2:    {:>016X}", { self.t2 });
+        println!("A0:    {:>016X}", { self.a0 });
+        println!("A1:    {:>016X}", { self.a1 });
+        println!("A2:    {:>016X}", { self.a2 });
+        println!("A3:    {:>016X}", { self.a3 });
+        println!("A4:    {:>016X}", { self.a4 });
+        println!("A5:    {:>016X}", { self.a5 });
+        println!("A6:    {:>016X}", { self.a6 });
+        println!("A7:    {:>016X}", { self.a7 });
+        println!("T3:    {:>016X}", { self.t3 });
+        println!("T4:    {:>016X}", { self.t4 });
+        println!("T5:    {:>016X}", { self.t5 });
+        println!("T6:    {:>016X}", { self.t6 });
+    }
+}
+
+/// Registers preserved across calls, plus the stack, global and thread pointers
+#[derive(Default)]
+#[repr(C)]
+pub struct PreservedRegisters {
+    pub sp: usize,
+    pub gp: usize,
+    /// The user thread pointer when the trap was taken from U-mode, else the kernel one
+    pub tp: usize,
+    pub s0: usize,
+    pub s1: usize,
+    pub s2: usize,
+    pub s3: usize,
+    pub s4: usize,
+    pub s5: usize,
+    pub s6: usize,
+    pub s7: usize,
+    pub s8: usize,
+    pub s9: usize,
+    pub s10: usize,
+    pub s11: usize,
+}
+
+impl PreservedRegisters {
+    pub fn dump(&self) {
+        println!("SP:    {:>016X}", { self.sp });
+        println!("GP:    {:>016X}", { self.gp });
+        println!("TP:    {:>016X}", { self.tp });
+        println!("S0:    {:>016X}", { self.s0 });
+        println!("S1:    {:>016X}", { self.s1 });
+        println!("S2:    {:>016X}", { self.s2 });
+        println!("S3:    {:>016X}", { self.s3 });
+        println!("S4:    {:>016X}", { self.s4 });
+        println!("S5:    {:>016X}", { self.s5 });
+        println!("S6:    {:>016X}", { self.s6 });
+        println!("S7:    {:>016X}", { self.s7 });
+        println!("S8:    {:>016X}", { self.s8 });
+        println!("S9:    {:>016X}", { self.s9 });
+        println!("S10:   {:>016X}", { self.s10 });
+        println!("S11:   {:>016X}", { self.s11 });
+    }
+}
+
+#[derive(Default)]
+#[repr(C)]
+pub struct IretRegisters {
+    pub sepc: usize,
+    pub sstatus: usize,
+    pub scause: usize,
+    pub stval: usize,
+}
+
+impl IretRegisters {
+    pub fn dump(&self) {
+        println!("SEPC:    {:>016X}", { self.sepc });
+        println!("SSTATUS: {:>016X}", { self.sstatus });
+        println!("SCAUSE:  {:>016X}", { self.scause });
+        println!("STVAL:   {:>016X}", { self.stval });
+    }
+}
+
+/// The registers saved by the trap entry (see `trap`), whose offsets it hardcodes
+#[derive(Default)]
+#[repr(C)]
+pub struct InterruptStack {
+    pub iret: IretRegisters,
+    pub scratch: ScratchRegisters,
+    pub preserved: PreservedRegisters,
+    /// Keeps the stack 16-byte aligned
+    pub padding: usize,
+}
+
+impl InterruptStack {
+    pub fn dump(&self) {
+        self.iret.dump();
+        self.scratch.dump();
+        self.preserved.dump();
+    }
+
+    /// Returns true if the trap was taken from U-mode
+    pub fn is_user(&self) -> bool {
+        self.iret.sstatus & SSTATUS_SPP == 0
+    }
+
+    /// Saves all registers to a struct used by the proc:
+    /// scheme to read/write registers.
+    pub fn save(&self, all: &mut IntRegisters) {
+        all.pc = self.iret.sepc;
+        all.x1 = self.scratch.ra;
+        all.x2 = self.preserved.sp;
+        all.x3 = self.preserved.gp;
+        all.x4 = self.preserved.tp;
+        all.x5 = self.scratch.t0;
+        all.x6 = self.scratch.t1;
+        all.x7 = self.scratch.t2;
+        all.x8 = self.preserved.s0;
+        all.x9 = self.preserved.s1;
+        all.x10 = self.scratch.a0;
+        all.x11 = self.scratch.a1;
+        all.x12 = self.scratch.a2;
+        all.x13 = self.scratch.a3;
+        all.x14 = self.scratch.a4;
+        all.x15 = self.scratch.a5;
+        all.x16 = self.scratch.a6;
+        all.x17 = self.scratch.a7;
+        all.x18 = self.preserved.s2;
+        all.x19 = self.preserved.s3;
+        all.x20 = self.preserved.s4;
+        all.x21 = self.preserved.s5;
+        all.x22 = self.preserved.s6;
+        all.x23 = self.preserved.s7;
+        all.x24 = self.preserved.s8;
+        all.x25 = self.preserved.s9;
+        all.x26 = self.preserved.s10;
+        all.x27 = self.preserved.s11;
+        all.x28 = self.scratch.t3;
+        all.x29 = self.scratch.t4;
+        all.x30 = self.scratch.t5;
+        all.x31 = self.scratch.t6;
+    }
+
+    /// Loads all registers from a struct used by the proc:
+    /// scheme to read/write registers.
+    ///
+    /// The thread pointer is not loaded, as it is restored from the per-CPU trap data when
+    /// returning to userspace. It is set through the env registers instead.
+    pub fn load(&mut self, all: &IntRegisters) {
+        self.iret.sepc = all.pc;
+        self.scratch.ra = all.x1;
+        self.preserved.sp = all.x2;
+        self.preserved.gp = all.x3;
+        self.scratch.t0 = all.x5;
+        self.scratch.t1 = all.x6;
+        self.scratch.t2 = all.x7;
+        self.preserved.s0 = all.x8;
+        self.preserved.s1 = all.x9;
+        self.scratch.a0 = all.x10;
+        self.scratch.a1 = all.x11;
+        self.scratch.a2 = all.x12;
+        self.scratch.a3 = all.x13;
+        self.scratch.a4 = all.x14;
+        self.scratch.a5 = all.x15;
+        self.scratch.a6 = all.x16;
+        self.scratch.a7 = all.x17;
+        self.preserved.s2 = all.x18;
+        self.preserved.s3 = all.x19;
+        self.preserved.s4 = all.x20;
+        self.preserved.s5 = all.x21;
+        self.preserved.s6 = all.x22;
+        self.preserved.s7 = all.x23;
+        self.preserved.s8 = all.x24;
+        self.preserved.s9 = all.x25;
+        self.preserved.s10 = all.x26;
+        self.preserved.s11 = all.x27;
+        self.scratch.t3 = all.x28;
+        self.scratch.t4 = all.x29;
+        self.scratch.t5 = all.x30;
+        self.scratch.t6 = all.x31;
+    }
+
+    /// There is no hardware single step in the base ISA, and the debug trigger extension is not
+    /// used yet
+    pub fn is_singlestep(&self) -> bool {
+        false
+    }
+
+    /// Single step is not supported (see `is_singlestep`)
+    pub fn set_singlestep(&mut self, _singlestep: bool) {}
+}
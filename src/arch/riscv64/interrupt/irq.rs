@@ -0,0 +1,92 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::context;
+use crate::context::timeout;
+use crate::device::{plic, timer};
+use crate::device::serial::COM1;
+
+use super::InterruptStack;
+
+//resets to 0 in context::switch()
+pub static PIT_TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Interrupt causes, in `scause` without the interrupt bit
+const IRQ_S_SOFT: usize = 1;
+const IRQ_S_TIMER: usize = 5;
+const IRQ_S_EXT: usize = 9;
+
+extern {
+    fn irq_trigger(irq: u8);
+}
+
+/// Unmask an IRQ masked by `irq_handler_generic`, once its userspace driver handled it
+pub unsafe fn acknowledge(irq: usize) {
+    if let Ok(irq) = u32::try_from(irq) {
+        plic::irq_enable(irq);
+    }
+}
+
+pub unsafe fn mask(irq: usize) {
+    if let Ok(irq) = u32::try_from(irq) {
+        plic::irq_disable(irq);
+    }
+}
+
+/// PLIC sources handled by userspace drivers through the irq scheme. The source stays masked until
+/// the driver acknowledges the IRQ, as level triggered sources would be claimed again right away.
+unsafe fn irq_handler_generic(source: u32) {
+    plic::irq_disable(source);
+    match u8::try_from(source) {
+        Ok(irq) => irq_trigger(irq),
+        Err(_) => println!("irq_handler_generic: PLIC source {} out of range", source),
+    }
+    plic::irq_complete(source);
+}
+
+/// Each hart has its own timer. Only the BSP keeps track of timeouts, and polls the console, which
+/// has no interrupt.
+unsafe fn irq_handler_timer() {
+    timer::reload();
+
+    if crate::cpu_id() == 0 {
+        timeout::trigger();
+
+        crate::scheme::irq::storm_tick();
+
+        crate::time::watchdog();
+
+        crate::watchdog::poll();
+
+        COM1.lock().receive();
+    }
+
+    crate::lockup::tick();
+
+    // A parked CPU has nothing to switch to
+    if crate::hotplug::is_parked(crate::cpu_id()) {
+        return;
+    }
+
+    // Switch after 3 ticks (about 30 ms), or right away if a low-latency context is due
+    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 || context::latency::due() {
+        let _ = context::switch();
+    }
+}
+
+unsafe fn irq_handler_external() {
+    loop {
+        match plic::irq_claim() {
+            0 => break,
+            source => irq_handler_generic(source),
+        }
+    }
+}
+
+pub unsafe fn handle(_stack: &mut InterruptStack, cause: usize) {
+    match cause {
+        IRQ_S_SOFT => crate::ipi::handle(),
+        IRQ_S_TIMER => irq_handler_timer(),
+        IRQ_S_EXT => irq_handler_external(),
+        _ => println!("Unexpected interrupt, cause {}", cause),
+    }
+}
@@ -0,0 +1,104 @@
+//! Interrupt instructions
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::device::plic;
+
+#[macro_use]
+pub mod handler;
+
+pub mod exception;
+pub mod irq;
+pub mod syscall;
+pub mod trace;
+pub mod trap;
+
+pub use self::handler::InterruptStack;
+pub use self::trace::stack_trace;
+
+/// Supervisor interrupt enable, in `sstatus`
+const SSTATUS_SIE: usize = 1 << 1;
+
+/// Clear interrupts
+#[inline(always)]
+pub unsafe fn disable() {
+    asm!("csrc sstatus, {}", in(reg) SSTATUS_SIE);
+}
+
+/// Set interrupts
+#[inline(always)]
+pub unsafe fn enable() {
+    asm!("csrs sstatus, {}", in(reg) SSTATUS_SIE);
+}
+
+/// Set interrupts and halt
+/// This will atomically wait for the next interrupt
+/// Performing enable followed by halt is not guaranteed to be atomic, use this instead!
+///
+/// `wfi` returns once an interrupt is pending, even with interrupts disabled, so it is executed
+/// first, and the interrupt is taken right after.
+#[inline(always)]
+pub unsafe fn enable_and_halt() {
+    asm!("wfi");
+    asm!("csrs sstatus, {}", in(reg) SSTATUS_SIE);
+}
+
+/// Set interrupts and nop
+/// This will enable interrupts and allow the IF flag to be processed
+/// Simply enabling interrupts does not gurantee that they will trigger, use this instead!
+#[inline(always)]
+pub unsafe fn enable_and_nop() {
+    asm!("csrs sstatus, {}", in(reg) SSTATUS_SIE);
+    asm!("nop");
+}
+
+/// Halt instruction
+#[inline(always)]
+pub unsafe fn halt() {
+    asm!("wfi");
+}
+
+/// Pause instruction
+/// Safe because it is similar to a NOP, and has no memory effects
+#[inline(always)]
+pub fn pause() {
+    // Zihintpause, a hint which is a NOP on harts without it
+    unsafe { asm!(".insn i 0x0F, 0, x0, x0, 0x010") };
+}
+
+/// Reserved IRQs, which are PLIC sources. Source 0 does not exist.
+static RESERVATIONS: [AtomicU64; 4] = [
+    AtomicU64::new(1),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// The IRQs not reserved yet, only on the BSP, as sources are all routed to it
+pub fn available_irqs_iter(cpu_id: usize) -> impl Iterator<Item = u8> + 'static {
+    let count = plic::irq_count().min(256);
+    (1..=255).filter(move |&index| cpu_id == 0 && usize::from(index) < count && !is_reserved(cpu_id, index))
+}
+
+pub fn bsp_apic_id() -> Option<u32> {
+    Some(0)
+}
+
+#[inline]
+pub fn is_reserved(_cpu_id: usize, index: u8) -> bool {
+    RESERVATIONS[usize::from(index / 64)].load(Ordering::Acquire) & (1 << (index % 64)) != 0
+}
+
+/// Reserve the source `index` and unmask it, or release it and mask it
+#[inline]
+pub fn set_reserved(_cpu_id: usize, index: u8, reserved: bool) {
+    let bit = 1 << (index % 64);
+    if reserved {
+        RESERVATIONS[usize::from(index / 64)].fetch_or(bit, Ordering::AcqRel);
+        unsafe { plic::irq_enable(u32::from(index)) };
+    } else {
+        RESERVATIONS[usize::from(index / 64)].fetch_and(!bit, Ordering::AcqRel);
+        unsafe { plic::irq_disable(u32::from(index)) };
+    }
+}
@@ -0,0 +1,51 @@
+use core::{arch::asm, mem};
+
+use crate::paging::{KernelMapper, VirtualAddress};
+
+/// Get a stack trace
+///
+/// With frame pointers, the return address is saved right below the address `s0` points to, and
+/// the frame pointer of the caller below it.
+//TODO: Check for stack being mapped before dereferencing
+#[inline(never)]
+pub unsafe fn stack_trace() {
+    let mut fp: usize;
+    asm!("mv {}, s0", out(reg) fp);
+
+    println!("TRACE: {:>016x}", fp);
+
+    let mapper = KernelMapper::lock();
+
+    //Maximum 64 frames
+    for _frame in 0..64 {
+        if let (Some(pc_ptr), Some(fp_ptr)) = (fp.checked_sub(mem::size_of::<usize>()), fp.checked_sub(2 * mem::size_of::<usize>())) {
+            if fp_ptr != 0
+            && mapper.translate(VirtualAddress::new(fp_ptr)).is_some()
+            && mapper.translate(VirtualAddress::new(pc_ptr)).is_some() {
+                let pc = *(pc_ptr as *const usize);
+                if pc == 0 {
+                    println!(" {:>016x}: EMPTY RETURN", fp);
+                    break;
+                }
+                println!("  FP {:>016x}: PC {:>016x}", fp, pc);
+                fp = *(fp_ptr as *const usize);
+                symbol_trace(pc);
+            } else {
+                println!("  {:>016x}: GUARD PAGE", fp);
+                break;
+            }
+        } else {
+            println!("  {:>016x}: fp UNDERFLOW", fp);
+            break;
+        }
+    }
+}
+
+/// Print the function containing `addr`
+#[inline(never)]
+pub unsafe fn symbol_trace(addr: usize) {
+    if let Some(location) = crate::symbols::lookup(addr) {
+        println!("    {:>016X}+{:>04X}", location.symbol.address, location.offset);
+        println!("    {}", location.symbol);
+    }
+}
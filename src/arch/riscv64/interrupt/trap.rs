@@ -0,0 +1,212 @@
+//! # Trap entry
+//! Every trap, interrupt or exception, from U-mode or S-mode, goes through `trap_entry`, which
+//! saves an `InterruptStack` and calls `trap_handler`.
+//!
+//! While the kernel runs, `tp` points to the TLS block of the CPU and `sscratch` is zero. While
+//! userspace runs, `sscratch` holds the kernel `tp` instead, which is how the entry tells where the
+//! trap came from. Below the TLS block, the `TrapScratch` of the CPU holds the kernel stack to
+//! switch to and the user `tp`, which is switched along with contexts like `tpidr_el0` on aarch64.
+
+use core::arch::asm;
+use core::mem;
+
+use super::{exception, irq, InterruptStack};
+
+/// Interrupt bit of `scause`
+pub const SCAUSE_INTERRUPT: usize = 1 << 63;
+
+/// Per-CPU data of the trap entry, right below the TLS block `tp` points to (see
+/// `paging::init_tcb`). The entry hardcodes these offsets from `tp`.
+#[derive(Default)]
+#[repr(C)]
+pub struct TrapScratch {
+    /// The thread pointer of the current context in userspace
+    pub user_tp: usize,
+    /// The stack pointer of the trap, while the entry switches stacks
+    pub saved_sp: usize,
+    /// The stack to switch to when entering the kernel from userspace
+    pub kernel_sp: usize,
+}
+
+/// Space reserved for the `TrapScratch` below the TLS block, keeping it aligned
+pub const TRAP_SCRATCH_SIZE: usize = 64;
+
+const _: () = assert!(mem::size_of::<TrapScratch>() <= TRAP_SCRATCH_SIZE);
+const _: () = assert!(mem::size_of::<InterruptStack>() == 36 * 8);
+
+/// The trap data of the current CPU
+pub fn trap_scratch() -> *mut TrapScratch {
+    let tp: usize;
+    unsafe { asm!("mv {}, tp", out(reg) tp) };
+    (tp - mem::size_of::<TrapScratch>()) as *mut TrapScratch
+}
+
+/// The thread pointer the current context has in userspace
+pub fn user_tp() -> usize {
+    unsafe { (*trap_scratch()).user_tp }
+}
+
+/// Set the thread pointer of the current context, taking effect when it returns to userspace
+pub unsafe fn set_user_tp(tp: usize) {
+    (*trap_scratch()).user_tp = tp;
+}
+
+/// Set the trap vector of the current hart
+pub unsafe fn init() {
+    asm!("csrw stvec, {}", in(reg) trap_entry as usize);
+    asm!("csrw sscratch, zero");
+}
+
+#[no_mangle]
+unsafe extern "C" fn trap_handler(stack: &mut InterruptStack) {
+    let _guard = crate::ptrace::set_process_regs(stack);
+
+    let scause = stack.iret.scause;
+    if scause & SCAUSE_INTERRUPT != 0 {
+        irq::handle(stack, scause & !SCAUSE_INTERRUPT);
+    } else {
+        exception::handle(stack, scause);
+    }
+}
+
+extern "C" {
+    fn trap_entry();
+}
+
+// Must be 4-byte aligned, as `stvec` uses its low bits for the mode
+core::arch::global_asm!(
+    "
+    .section .text.trap_entry, \"ax\", @progbits
+    .global trap_entry
+    .p2align 2
+trap_entry:
+    csrrw tp, sscratch, tp
+    bnez tp, 1f
+
+    // From S-mode: restore the kernel tp, and keep the current stack
+    csrrw tp, sscratch, tp
+    sd sp, -16(tp)
+    j 2f
+
+1:
+    // From U-mode: switch to the kernel stack
+    sd sp, -16(tp)
+    ld sp, -8(tp)
+
+2:
+    addi sp, sp, -{frame}
+
+    sd ra, 4*8(sp)
+    sd t0, 5*8(sp)
+    sd t1, 6*8(sp)
+    sd t2, 7*8(sp)
+    sd a0, 8*8(sp)
+    sd a1, 9*8(sp)
+    sd a2, 10*8(sp)
+    sd a3, 11*8(sp)
+    sd a4, 12*8(sp)
+    sd a5, 13*8(sp)
+    sd a6, 14*8(sp)
+    sd a7, 15*8(sp)
+    sd t3, 16*8(sp)
+    sd t4, 17*8(sp)
+    sd t5, 18*8(sp)
+    sd t6, 19*8(sp)
+
+    ld t0, -16(tp)
+    sd t0, 20*8(sp)
+    sd gp, 21*8(sp)
+    sd s0, 23*8(sp)
+    sd s1, 24*8(sp)
+    sd s2, 25*8(sp)
+    sd s3, 26*8(sp)
+    sd s4, 27*8(sp)
+    sd s5, 28*8(sp)
+    sd s6, 29*8(sp)
+    sd s7, 30*8(sp)
+    sd s8, 31*8(sp)
+    sd s9, 32*8(sp)
+    sd s10, 33*8(sp)
+    sd s11, 34*8(sp)
+
+    // The user tp, zero if the trap came from S-mode, in which case sscratch already was
+    csrrw t0, sscratch, zero
+    beqz t0, 3f
+    sd t0, -24(tp)
+    j 4f
+3:
+    mv t0, tp
+4:
+    sd t0, 22*8(sp)
+
+    csrr t0, sepc
+    sd t0, 0*8(sp)
+    csrr t0, sstatus
+    sd t0, 1*8(sp)
+    csrr t0, scause
+    sd t0, 2*8(sp)
+    csrr t0, stval
+    sd t0, 3*8(sp)
+
+    // Terminate frame pointer chains at the trap
+    mv s0, zero
+    mv a0, sp
+    call {handler}
+
+    ld t0, 1*8(sp)
+    andi t1, t0, {spp}
+    bnez t1, 5f
+
+    // To U-mode: the kernel stack is empty again, and sscratch tells the next trap
+    addi t1, sp, {frame}
+    sd t1, -8(tp)
+    csrw sscratch, tp
+5:
+    csrw sstatus, t0
+    ld t0, 0*8(sp)
+    csrw sepc, t0
+
+    ld ra, 4*8(sp)
+    ld t1, 6*8(sp)
+    ld t2, 7*8(sp)
+    ld a0, 8*8(sp)
+    ld a1, 9*8(sp)
+    ld a2, 10*8(sp)
+    ld a3, 11*8(sp)
+    ld a4, 12*8(sp)
+    ld a5, 13*8(sp)
+    ld a6, 14*8(sp)
+    ld a7, 15*8(sp)
+    ld t3, 16*8(sp)
+    ld t4, 17*8(sp)
+    ld t5, 18*8(sp)
+    ld t6, 19*8(sp)
+
+    ld gp, 21*8(sp)
+    ld s0, 23*8(sp)
+    ld s1, 24*8(sp)
+    ld s2, 25*8(sp)
+    ld s3, 26*8(sp)
+    ld s4, 27*8(sp)
+    ld s5, 28*8(sp)
+    ld s6, 29*8(sp)
+    ld s7, 30*8(sp)
+    ld s8, 31*8(sp)
+    ld s9, 32*8(sp)
+    ld s10, 33*8(sp)
+    ld s11, 34*8(sp)
+
+    ld t0, 1*8(sp)
+    andi t0, t0, {spp}
+    bnez t0, 6f
+    ld tp, -24(tp)
+6:
+    ld t0, 5*8(sp)
+    ld sp, 20*8(sp)
+    sret
+    .text
+    ",
+    frame = const mem::size_of::<InterruptStack>(),
+    spp = const super::handler::SSTATUS_SPP,
+    handler = sym trap_handler,
+);
@@ -0,0 +1,120 @@
+//! Inter-processor interrupts are supervisor software interrupts sent through SBI, which carry no
+//! vector, so the kinds pending on each CPU are kept in a bitmask next to them.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::device_tree::MAX_HARTS;
+
+#[derive(Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum IpiKind {
+    Wakeup = 0x40,
+    Tlb = 0x41,
+    Switch = 0x42,
+    Pit = 0x43,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum IpiTarget {
+    Current = 1,
+    All = 2,
+    Other = 3,
+}
+
+/// Recorded in the journal as the target of an IPI sent to a single CPU, plus its ID
+pub const IPI_TARGET_CPU: usize = 0x100;
+
+/// Supervisor software interrupt pending, in `sip`
+const SIP_SSIP: usize = 1 << 1;
+
+/// IPI kinds pending on each CPU, bit `n` standing for the kind `0x40 + n`
+static PENDING: [AtomicUsize; MAX_HARTS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    [ZERO; MAX_HARTS]
+};
+
+fn bit(kind: IpiKind) -> usize {
+    1 << (kind as u8 - IpiKind::Wakeup as u8)
+}
+
+#[cfg(feature = "multi_core")]
+fn send(kind: IpiKind, cpu_id: usize) {
+    if cpu_id >= MAX_HARTS {
+        return;
+    }
+    PENDING[cpu_id].fetch_or(bit(kind), Ordering::SeqCst);
+    if let Err(err) = crate::arch::sbi::send_ipi(1, crate::arch::device_tree::hart_id(cpu_id)) {
+        println!("Failed to send IPI to CPU {}: {:?}", cpu_id, err);
+    }
+}
+
+#[cfg(not(feature = "multi_core"))]
+#[inline(always)]
+pub fn ipi(_kind: IpiKind, _target: IpiTarget) {}
+
+#[cfg(feature = "multi_core")]
+#[inline(always)]
+pub fn ipi(kind: IpiKind, target: IpiTarget) {
+    crate::journal::record(crate::journal::EventKind::Ipi, kind as usize, target as usize);
+
+    let current = crate::cpu_id();
+    for cpu_id in 0..crate::cpu_count() {
+        let selected = match target {
+            IpiTarget::Current => cpu_id == current,
+            IpiTarget::All => true,
+            IpiTarget::Other => cpu_id != current,
+        };
+        if selected {
+            send(kind, cpu_id);
+        }
+    }
+}
+
+/// Send an IPI to the CPU `cpu_id`, which may be the current one
+#[cfg(not(feature = "multi_core"))]
+#[inline(always)]
+pub fn ipi_single(_kind: IpiKind, _cpu_id: usize) {}
+
+/// Send an IPI to the CPU `cpu_id`, which may be the current one
+#[cfg(feature = "multi_core")]
+#[inline(always)]
+pub fn ipi_single(kind: IpiKind, cpu_id: usize) {
+    crate::journal::record(crate::journal::EventKind::Ipi, kind as usize, IPI_TARGET_CPU + cpu_id);
+
+    send(kind, cpu_id);
+}
+
+/// Send an IPI to every CPU whose bit is set in `mask`, bit `n` standing for CPU `n`
+pub fn ipi_mask(kind: IpiKind, mask: u64) {
+    for cpu_id in (0..64).filter(|cpu_id| mask & (1 << cpu_id) != 0) {
+        ipi_single(kind, cpu_id);
+    }
+}
+
+/// Handle the IPIs pending on the current CPU, on a supervisor software interrupt
+pub unsafe fn handle() {
+    asm!("csrc sip, {}", in(reg) SIP_SSIP);
+
+    let pending = match PENDING.get(crate::cpu_id()) {
+        Some(pending) => pending.swap(0, Ordering::SeqCst),
+        None => return,
+    };
+
+    if pending & bit(IpiKind::Tlb) != 0 {
+        asm!("sfence.vma");
+    }
+
+    // A parked CPU has nothing to switch to on a tick
+    if pending & bit(IpiKind::Switch) != 0 {
+        let _ = crate::context::switch();
+    } else if pending & bit(IpiKind::Pit) != 0 && !crate::hotplug::is_parked(crate::cpu_id()) {
+        use crate::interrupt::irq::PIT_TICKS;
+
+        if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 || crate::context::latency::due() {
+            let _ = crate::context::switch();
+        }
+    }
+}
@@ -0,0 +1,71 @@
+#[macro_use]
+pub mod macros;
+
+/// Constants like memory locations
+pub mod consts;
+
+/// Debugging support
+pub mod debug;
+
+/// Devices
+pub mod device;
+
+/// Flattened device tree parsing
+pub mod device_tree;
+
+/// Idle states
+pub mod idle;
+
+/// Interrupt instructions
+pub mod interrupt;
+
+/// Inter-processor interrupts
+pub mod ipi;
+
+/// Paging
+pub mod paging;
+
+pub mod rmm;
+
+/// Supervisor Binary Interface calls
+pub mod sbi;
+
+/// Initialization and start function
+pub mod start;
+
+/// Stop function
+pub mod stop;
+
+pub mod time;
+
+#[cfg(not(feature = "riscv_sv39"))]
+pub use ::rmm::RiscV64Sv48Arch as CurrentRmmArch;
+#[cfg(feature = "riscv_sv39")]
+pub use ::rmm::RiscV64Sv39Arch as CurrentRmmArch;
+
+pub use arch_copy_to_user as arch_copy_from_user;
+
+/// Copy `len` bytes, returning nonzero if a page fault could not be resolved (see
+/// `interrupt::exception`). Userspace memory is accessible as `sstatus.SUM` is always set.
+#[naked]
+#[link_section = ".usercopy-fns"]
+pub unsafe extern "C" fn arch_copy_to_user(dst: usize, src: usize, len: usize) -> u8 {
+    // a0, a1, a2
+    core::arch::asm!("
+        mv a4, a0
+        li a0, 0
+    2:
+        beqz a2, 3f
+
+        lbu a3, 0(a1)
+        sb a3, 0(a4)
+
+        addi a4, a4, 1
+        addi a1, a1, 1
+        addi a2, a2, -1
+
+        j 2b
+    3:
+        ret
+    ", options(noreturn));
+}
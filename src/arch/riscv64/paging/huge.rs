@@ -0,0 +1,27 @@
+//! # Huge pages
+//! Not yet supported on this architecture. Huge mappings are never created, and translation
+//! always goes through the generic mapper.
+
+use rmm::{Arch, FrameAllocator, PageFlags, PageFlush, PageMapper, PhysicalAddress, VirtualAddress};
+
+/// Whether huge pages can be mapped
+pub const SUPPORTED: bool = false;
+
+/// Size of a huge page
+pub const HUGE_PAGE_SIZE: usize = 2 * rmm::MEGABYTE;
+
+pub unsafe fn map_phys<A: Arch, F: FrameAllocator>(_mapper: &mut PageMapper<A, F>, _virt: VirtualAddress, _phys: PhysicalAddress, _flags: PageFlags<A>) -> Option<PageFlush<A>> {
+    None
+}
+
+pub unsafe fn remap<A: Arch, F: FrameAllocator>(_mapper: &mut PageMapper<A, F>, _virt: VirtualAddress, _flags: PageFlags<A>) -> Option<PageFlush<A>> {
+    None
+}
+
+pub unsafe fn unmap_phys<A: Arch, F: FrameAllocator>(_mapper: &mut PageMapper<A, F>, _virt: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<A>, PageFlush<A>)> {
+    None
+}
+
+pub fn translate<A: Arch, F: FrameAllocator>(mapper: &PageMapper<A, F>, virt: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<A>)> {
+    mapper.translate(virt)
+}
@@ -0,0 +1,60 @@
+use crate::ipi::{ipi, IpiKind, IpiTarget};
+
+use super::{PageMapper, RmmA, VirtualAddress};
+
+pub use rmm::{Flusher, PageFlush, PageFlushAll};
+
+pub struct InactiveFlusher { _inner: () }
+impl InactiveFlusher {
+    // TODO: cpu id
+    pub fn new() -> Self { Self { _inner: () } }
+}
+
+impl Flusher<RmmA> for InactiveFlusher {
+    fn consume(&mut self, flush: PageFlush<RmmA>) {
+        // TODO: Push to TLB "mailbox" or tell it to reload CR3 if there are too many entries.
+        unsafe { flush.ignore(); }
+    }
+}
+impl Drop for InactiveFlusher {
+    fn drop(&mut self) {
+        ipi(IpiKind::Tlb, IpiTarget::Other);
+    }
+}
+
+/// Flushes changes to a user page table from the TLB of the current CPU if it runs it, and from
+/// all other CPUs
+pub struct TlbShootdown {
+    local: bool,
+    dirty: bool,
+}
+impl TlbShootdown {
+    pub fn new(mapper: &PageMapper) -> Self {
+        Self {
+            local: mapper.is_current(),
+            dirty: false,
+        }
+    }
+    /// Only invalidate `page_count` pages from `start` on other CPUs. Ignored, other CPUs flush
+    /// their entire TLB.
+    pub fn with_range(self, _start: VirtualAddress, _page_count: usize) -> Self {
+        self
+    }
+}
+impl Flusher<RmmA> for TlbShootdown {
+    fn consume(&mut self, flush: PageFlush<RmmA>) {
+        self.dirty = true;
+        if self.local {
+            flush.flush();
+        } else {
+            unsafe { flush.ignore(); }
+        }
+    }
+}
+impl Drop for TlbShootdown {
+    fn drop(&mut self) {
+        if self.dirty {
+            ipi(IpiKind::Tlb, IpiTarget::Other);
+        }
+    }
+}
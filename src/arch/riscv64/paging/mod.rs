@@ -0,0 +1,226 @@
+//! # Paging
+//! Some code was borrowed from [Phil Opp's Blog](http://os.phil-opp.com/modifying-page-tables.html)
+
+use core::arch::asm;
+use core::ptr;
+
+use crate::interrupt::trap::{TrapScratch, TRAP_SCRATCH_SIZE};
+
+use self::mapper::PageFlushAll;
+
+pub use rmm::{
+    Arch as RmmArch,
+    Flusher,
+    PageFlags,
+    PhysicalAddress,
+    TableKind,
+    VirtualAddress,
+};
+pub use super::CurrentRmmArch as RmmA;
+
+pub type PageMapper = rmm::PageMapper<RmmA, crate::arch::rmm::LockedAllocator>;
+pub use crate::rmm::KernelMapper;
+
+pub mod huge;
+pub mod mapper;
+
+/// Number of entries per page table
+pub const ENTRY_COUNT: usize = RmmA::PAGE_ENTRIES;
+
+/// Size of pages
+pub const PAGE_SIZE: usize = RmmA::PAGE_SIZE;
+
+/// Map percpu
+unsafe fn map_percpu(cpu_id: usize, mapper: &mut PageMapper) -> PageFlushAll<RmmA> {
+    extern "C" {
+        /// The starting byte of the thread data segment
+        static mut __tdata_start: u8;
+        /// The ending byte of the thread data segment
+        static mut __tdata_end: u8;
+        /// The starting byte of the thread BSS segment
+        static mut __tbss_start: u8;
+        /// The ending byte of the thread BSS segment
+        static mut __tbss_end: u8;
+    }
+
+    let size = TRAP_SCRATCH_SIZE + (&__tbss_end as *const _ as usize - &__tdata_start as *const _ as usize);
+    let start = crate::KERNEL_PERCPU_OFFSET + crate::KERNEL_PERCPU_SIZE * cpu_id;
+    let end = start + size;
+
+    let mut flush_all = PageFlushAll::new();
+    let start_page = Page::containing_address(VirtualAddress::new(start));
+    let end_page = Page::containing_address(VirtualAddress::new(end - 1));
+    for page in Page::range_inclusive(start_page, end_page) {
+        let result = mapper.map(
+            page.start_address(),
+            PageFlags::new().write(true).global(cfg!(not(feature = "pti"))),
+        )
+        .expect("failed to allocate page table frames while mapping percpu");
+        flush_all.consume(result);
+    }
+    flush_all
+}
+
+/// Copy tdata, clear tbss, and point `tp` to the TLS block, which starts right at the thread
+/// pointer with the TLS variant of RISC-V. The trap data of the CPU is below it.
+unsafe fn init_tcb(cpu_id: usize) -> usize {
+    extern "C" {
+        /// The starting byte of the thread data segment
+        static mut __tdata_start: u8;
+        /// The ending byte of the thread data segment
+        static mut __tdata_end: u8;
+        /// The starting byte of the thread BSS segment
+        static mut __tbss_start: u8;
+        /// The ending byte of the thread BSS segment
+        static mut __tbss_end: u8;
+    }
+
+    let size = &__tbss_end as *const _ as usize - &__tdata_start as *const _ as usize;
+    let tbss_offset = &__tbss_start as *const _ as usize - &__tdata_start as *const _ as usize;
+
+    let start = crate::KERNEL_PERCPU_OFFSET + crate::KERNEL_PERCPU_SIZE * cpu_id;
+    let tls = start + TRAP_SCRATCH_SIZE;
+
+    ptr::write((tls - core::mem::size_of::<TrapScratch>()) as *mut TrapScratch, TrapScratch::default());
+    ptr::copy(&__tdata_start as *const u8, tls as *mut u8, tbss_offset);
+    ptr::write_bytes((tls + tbss_offset) as *mut u8, 0, size - tbss_offset);
+
+    asm!("mv tp, {}", in(reg) tls);
+
+    tls
+}
+
+/// Initialize paging
+///
+/// Returns the thread pointer
+pub unsafe fn init(
+    cpu_id: usize,
+) -> usize {
+    extern "C" {
+        /// The starting byte of the text (code) data segment.
+        static mut __text_start: u8;
+        /// The ending byte of the text (code) data segment.
+        static mut __text_end: u8;
+        /// The starting byte of the _.rodata_ (read-only data) segment.
+        static mut __rodata_start: u8;
+        /// The ending byte of the _.rodata_ (read-only data) segment.
+        static mut __rodata_end: u8;
+        /// The starting byte of the _.data_ segment.
+        static mut __data_start: u8;
+        /// The ending byte of the _.data_ segment.
+        static mut __data_end: u8;
+        /// The starting byte of the thread data segment
+        static mut __tdata_start: u8;
+        /// The ending byte of the thread data segment
+        static mut __tdata_end: u8;
+        /// The starting byte of the thread BSS segment
+        static mut __tbss_start: u8;
+        /// The ending byte of the thread BSS segment
+        static mut __tbss_end: u8;
+        /// The starting byte of the _.bss_ (uninitialized data) segment.
+        static mut __bss_start: u8;
+        /// The ending byte of the _.bss_ (uninitialized data) segment.
+        static mut __bss_end: u8;
+    }
+
+    let flush_all = map_percpu(cpu_id, KernelMapper::lock_manually(cpu_id).get_mut().expect("expected KernelMapper not to be locked re-entrant in paging::init"));
+    flush_all.flush();
+
+    return init_tcb(cpu_id);
+}
+
+pub unsafe fn init_ap(
+    cpu_id: usize,
+    bsp_table: &mut KernelMapper,
+) -> usize {
+    {
+        let flush_all = map_percpu(cpu_id, bsp_table.get_mut().expect("KernelMapper locked re-entrant for AP"));
+
+        // The flush can be ignored as this is not the active table. See later make_current().
+        flush_all.ignore();
+    };
+
+    bsp_table.make_current();
+
+    init_tcb(cpu_id)
+}
+
+/// Page
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Page {
+    number: usize,
+}
+
+impl Page {
+    pub fn start_address(self) -> VirtualAddress {
+        VirtualAddress::new(self.number * PAGE_SIZE)
+    }
+
+    pub fn p4_index(self) -> usize {
+        (self.number >> 27) & 0o777
+    }
+
+    pub fn p3_index(self) -> usize {
+        (self.number >> 18) & 0o777
+    }
+
+    pub fn p2_index(self) -> usize {
+        (self.number >> 9) & 0o777
+    }
+
+    pub fn p1_index(self) -> usize {
+        self.number & 0o777
+    }
+
+    pub fn containing_address(address: VirtualAddress) -> Page {
+        //TODO assert!(address.data() < 0x0000_8000_0000_0000 || address.data() >= 0xffff_8000_0000_0000,
+        //    "invalid address: 0x{:x}", address.data());
+        Page {
+            number: address.data() / PAGE_SIZE,
+        }
+    }
+
+    pub fn range_inclusive(start: Page, r#final: Page) -> PageIter {
+        PageIter { start, end: r#final.next() }
+    }
+    pub fn range_exclusive(start: Page, end: Page) -> PageIter {
+        PageIter { start, end }
+    }
+
+    pub fn next(self) -> Page {
+        self.next_by(1)
+    }
+    pub fn next_by(self, n: usize) -> Page {
+        Self {
+            number: self.number + n,
+        }
+    }
+}
+
+pub struct PageIter {
+    start: Page,
+    end: Page,
+}
+
+impl Iterator for PageIter {
+    type Item = Page;
+
+    fn next(&mut self) -> Option<Page> {
+        if self.start < self.end {
+            let page = self.start;
+            self.start = self.start.next();
+            Some(page)
+        } else {
+            None
+        }
+    }
+}
+
+/// Round down to the nearest multiple of page size
+pub fn round_down_pages(number: usize) -> usize {
+    number - number % PAGE_SIZE
+}
+/// Round up to the nearest multiple of page size
+pub fn round_up_pages(number: usize) -> usize {
+    round_down_pages(number + PAGE_SIZE - 1)
+}
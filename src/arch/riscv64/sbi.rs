@@ -0,0 +1,122 @@
+//! # SBI
+//! The Supervisor Binary Interface, implemented by the firmware running in M-mode (OpenSBI on
+//! QEMU and VisionFive boards). The kernel uses it for the console, the timer, IPIs, starting
+//! harts and resetting the system.
+
+use core::arch::asm;
+
+/// Legacy console putchar, still implemented by OpenSBI and much simpler than DBCN
+const EID_CONSOLE_PUTCHAR: usize = 0x01;
+/// Legacy console getchar
+const EID_CONSOLE_GETCHAR: usize = 0x02;
+const EID_BASE: usize = 0x10;
+const EID_TIME: usize = 0x5449_4D45;
+const EID_IPI: usize = 0x0073_5049;
+const EID_RFENCE: usize = 0x5246_4E43;
+const EID_HSM: usize = 0x0048_534D;
+const EID_SRST: usize = 0x5352_5354;
+
+/// System reset types, for `system_reset`
+pub const RESET_TYPE_SHUTDOWN: u32 = 0;
+pub const RESET_TYPE_COLD_REBOOT: u32 = 1;
+/// System reset reasons
+pub const RESET_REASON_NONE: u32 = 0;
+
+/// An SBI error code, as returned in a0
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SbiError(pub isize);
+
+#[inline(always)]
+unsafe fn call(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> Result<usize, SbiError> {
+    let error: isize;
+    let value: usize;
+    asm!(
+        "ecall",
+        inlateout("a0") arg0 => error,
+        inlateout("a1") arg1 => value,
+        in("a2") arg2,
+        in("a6") fid,
+        in("a7") eid,
+    );
+    if error == 0 {
+        Ok(value)
+    } else {
+        Err(SbiError(error))
+    }
+}
+
+/// Legacy extensions return their value in a0, and do not take a function ID
+#[inline(always)]
+unsafe fn call_legacy(eid: usize, arg0: usize) -> isize {
+    let ret: isize;
+    asm!(
+        "ecall",
+        inlateout("a0") arg0 => ret,
+        in("a7") eid,
+    );
+    ret
+}
+
+pub fn console_putchar(c: u8) {
+    unsafe { call_legacy(EID_CONSOLE_PUTCHAR, c as usize) };
+}
+
+/// Returns a byte received on the console, if any
+pub fn console_getchar() -> Option<u8> {
+    match unsafe { call_legacy(EID_CONSOLE_GETCHAR, 0) } {
+        -1 => None,
+        c => Some(c as u8),
+    }
+}
+
+/// Returns true if the firmware implements the extension `eid`
+pub fn probe_extension(eid: usize) -> bool {
+    unsafe { call(EID_BASE, 3, eid, 0, 0) }.map_or(false, |value| value != 0)
+}
+
+/// Machine vendor, architecture and implementation IDs
+pub fn machine_ids() -> (usize, usize, usize) {
+    unsafe {
+        (
+            call(EID_BASE, 4, 0, 0, 0).unwrap_or(0),
+            call(EID_BASE, 5, 0, 0, 0).unwrap_or(0),
+            call(EID_BASE, 6, 0, 0, 0).unwrap_or(0),
+        )
+    }
+}
+
+/// Program the timer of the current hart to fire when `time` reaches `stime_value`, clearing the
+/// pending timer interrupt
+pub fn set_timer(stime_value: u64) {
+    let _ = unsafe { call(EID_TIME, 0, stime_value as usize, 0, 0) };
+}
+
+/// Send a supervisor software interrupt to the harts `hart_mask_base + n` for each bit `n` set
+/// in `hart_mask`
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> Result<(), SbiError> {
+    unsafe { call(EID_IPI, 0, hart_mask, hart_mask_base, 0) }.map(|_| ())
+}
+
+/// Execute `sfence.vma` on the harts in the mask, for the whole address space
+pub fn remote_sfence_vma(hart_mask: usize, hart_mask_base: usize) -> Result<(), SbiError> {
+    unsafe { call(EID_RFENCE, 1, hart_mask, hart_mask_base, 0) }.map(|_| ())
+}
+
+/// Start `hart_id` in S-mode at the physical address `start_addr`, with `opaque` in a1 and its
+/// hart ID in a0, and the MMU off
+pub fn hart_start(hart_id: usize, start_addr: usize, opaque: usize) -> Result<(), SbiError> {
+    unsafe { call(EID_HSM, 0, hart_id, start_addr, opaque) }.map(|_| ())
+}
+
+/// Stop the current hart, returning it to the firmware so that `hart_start` can start it again
+pub fn hart_stop() -> Result<(), SbiError> {
+    unsafe { call(EID_HSM, 1, 0, 0, 0) }.map(|_| ())
+}
+
+/// Shut down or reboot the system, only returning on failure
+pub fn system_reset(reset_type: u32, reason: u32) -> SbiError {
+    match unsafe { call(EID_SRST, 0, reset_type as usize, reason as usize, 0) } {
+        Ok(_) => SbiError(0),
+        Err(err) => err,
+    }
+}
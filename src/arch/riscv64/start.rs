@@ -0,0 +1,364 @@
+/// This function is where the kernel sets up the trap vector and paging
+/// It is increcibly unsafe, and should be minimal in nature
+
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::allocator;
+use crate::device;
+#[cfg(feature = "graphical_debug")]
+use crate::devices::graphical_debug;
+use crate::interrupt;
+use crate::log::{self, info};
+use crate::paging::{self, KernelMapper, RmmA, RmmArch, TableKind, PAGE_SIZE};
+
+use super::{device_tree, sbi};
+
+/// Test of zero values in BSS.
+static BSS_TEST_ZERO: usize = 0;
+/// Test of non-zero values in data.
+static DATA_TEST_NONZERO: usize = 0xFFFF_FFFF_FFFF_FFFF;
+/// Test of zero values in thread BSS
+#[thread_local]
+static mut TBSS_TEST_ZERO: usize = 0;
+/// Test of non-zero values in thread data.
+#[thread_local]
+static mut TDATA_TEST_NONZERO: usize = 0xFFFF_FFFF_FFFF_FFFF;
+
+pub static KERNEL_BASE: AtomicUsize = AtomicUsize::new(0);
+pub static KERNEL_SIZE: AtomicUsize = AtomicUsize::new(0);
+pub static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+pub static AP_READY: AtomicBool = AtomicBool::new(false);
+static BSP_READY: AtomicBool = AtomicBool::new(false);
+
+/// Permit supervisor user memory access, in `sstatus`
+const SSTATUS_SUM: usize = 1 << 18;
+/// Floating point state Initial, in `sstatus`, so that FPU instructions do not trap
+const SSTATUS_FS_INITIAL: usize = 1 << 13;
+
+/// Translation mode of `satp`
+#[cfg(not(feature = "riscv_sv39"))]
+const SATP_MODE: usize = 9 << 60;
+#[cfg(feature = "riscv_sv39")]
+const SATP_MODE: usize = 8 << 60;
+
+/// Number of pages of the stack of an AP
+const AP_STACK_PAGES: usize = 64;
+
+#[repr(packed)]
+pub struct KernelArgs {
+    kernel_base: usize,
+    kernel_size: usize,
+    stack_base: usize,
+    stack_size: usize,
+    env_base: usize,
+    env_size: usize,
+    dtb_base: usize,
+    dtb_size: usize,
+    areas_base: usize,
+    areas_size: usize,
+
+    /// The physical base 64-bit pointer to the contiguous bootstrap/initfs.
+    bootstrap_base: usize,
+    /// Size of contiguous bootstrap/initfs physical region, not necessarily page aligned.
+    bootstrap_size: usize,
+    /// Entry point the kernel will jump to.
+    bootstrap_entry: usize,
+}
+
+/// Set up the trap vector and the `sstatus` bits the kernel runs with
+unsafe fn init_hart() {
+    interrupt::trap::init();
+    core::arch::asm!("csrs sstatus, {}", in(reg) SSTATUS_SUM | SSTATUS_FS_INITIAL);
+}
+
+/// The entry to Rust, all things must be initialized
+#[no_mangle]
+pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
+    let bootstrap = {
+        let args = &*args_ptr;
+
+        // BSS should already be zero
+        {
+            assert_eq!(BSS_TEST_ZERO, 0);
+            assert_eq!(DATA_TEST_NONZERO, 0xFFFF_FFFF_FFFF_FFFF);
+        }
+
+        KERNEL_BASE.store(args.kernel_base, Ordering::SeqCst);
+        KERNEL_SIZE.store(args.kernel_size, Ordering::SeqCst);
+
+        // Setup trap handler
+        init_hart();
+
+        // Find the harts, timer frequency and PLIC
+        device_tree::init(crate::PHYS_OFFSET + args.dtb_base, args.dtb_size);
+
+        // The SBI console needs no setup
+        device::serial::init();
+
+        // Convert env to slice
+        let env = slice::from_raw_parts((args.env_base + crate::PHYS_OFFSET) as *const u8, args.env_size);
+
+        // Set up graphical debug
+        #[cfg(feature = "graphical_debug")]
+        graphical_debug::init(env);
+
+        // Initialize logger
+        log::init_logger(|r| {
+            use core::fmt::Write;
+            let _ = write!(
+                crate::debug::Writer::with(log::syslog_level(r.level()), log::FACILITY_KERNEL),
+                "{}:{} -- {}\n",
+                r.target(),
+                r.level(),
+                r.args()
+            );
+        });
+        log::configure(env);
+
+        info!("Redox OS starting...");
+        info!("Kernel: {:X}:{:X}", {args.kernel_base}, args.kernel_base + args.kernel_size);
+        info!("Stack: {:X}:{:X}", {args.stack_base}, args.stack_base + args.stack_size);
+        info!("Env: {:X}:{:X}", {args.env_base}, args.env_base + args.env_size);
+        info!("DTB: {:X}:{:X}", {args.dtb_base}, args.dtb_base + args.dtb_size);
+        info!("Areas: {:X}:{:X}", {args.areas_base}, args.areas_base + args.areas_size);
+        info!("Bootstrap: {:X}:{:X}", {args.bootstrap_base}, args.bootstrap_base + args.bootstrap_size);
+        info!("Bootstrap entry point: {:X}", {args.bootstrap_entry});
+
+        // Initialize RMM
+        crate::arch::rmm::init(
+            args.kernel_base, args.kernel_size,
+            args.stack_base, args.stack_size,
+            args.env_base, args.env_size,
+            args.dtb_base, args.dtb_size,
+            args.areas_base, args.areas_size,
+            args.bootstrap_base, args.bootstrap_size,
+        );
+
+        // Initialize paging
+        let _tcb_offset = paging::init(0);
+
+        // Test tdata and tbss
+        {
+            assert_eq!(TBSS_TEST_ZERO, 0);
+            TBSS_TEST_ZERO += 1;
+            assert_eq!(TBSS_TEST_ZERO, 1);
+            assert_eq!(TDATA_TEST_NONZERO, 0xFFFF_FFFF_FFFF_FFFF);
+            TDATA_TEST_NONZERO -= 1;
+            assert_eq!(TDATA_TEST_NONZERO, 0xFFFF_FFFF_FFFF_FFFE);
+        }
+
+        // Reset AP variables
+        CPU_COUNT.store(1, Ordering::SeqCst);
+        AP_READY.store(false, Ordering::SeqCst);
+        BSP_READY.store(false, Ordering::SeqCst);
+
+        // Setup kernel heap
+        allocator::init();
+
+        // Set up double buffer for grpahical debug now that heap is available
+        #[cfg(feature = "graphical_debug")]
+        graphical_debug::init_heap();
+
+        // Initialize devices
+        device::init();
+
+        // Drive the virtio console and entropy device until userspace drivers start
+        crate::devices::virtio::init();
+
+        // Initialize all of the non-core devices not otherwise needed to complete initialization
+        device::init_noncore();
+
+        // Start the other harts
+        start_aps();
+
+        // Stop graphical debug
+        #[cfg(feature = "graphical_debug")]
+        graphical_debug::fini();
+
+        BSP_READY.store(true, Ordering::SeqCst);
+
+        crate::Bootstrap {
+            base: crate::memory::Frame::containing_address(crate::paging::PhysicalAddress::new(args.bootstrap_base)),
+            page_count: args.bootstrap_size / crate::memory::PAGE_SIZE,
+            size: args.bootstrap_size,
+            entry: args.bootstrap_entry,
+            env,
+        }
+    };
+
+    crate::kmain(CPU_COUNT.load(Ordering::SeqCst), bootstrap);
+}
+
+/// Arguments of an AP, read by `kstart_ap_trampoline` with the MMU off, which hardcodes the
+/// offsets of the fields
+#[repr(C)]
+pub struct KernelArgsAp {
+    cpu_id: usize,
+    satp: usize,
+    stack_start: usize,
+    stack_end: usize,
+    /// Virtual address of these arguments
+    args: usize,
+    /// Virtual address of `kstart_ap_virt`
+    entry: usize,
+}
+
+/// Start every hart of the device tree other than the BSP with SBI HSM, one after the other
+unsafe fn start_aps() {
+    extern "C" {
+        fn kstart_ap_trampoline();
+        fn kstart_ap_virt();
+    }
+
+    // The trampoline runs with the MMU off, at the physical address of the kernel image
+    let kernel_phys = |virt: usize| virt - crate::KERNEL_OFFSET + KERNEL_BASE.load(Ordering::SeqCst);
+    let satp = SATP_MODE | (RmmA::table(TableKind::Kernel).data() >> 12);
+
+    for cpu_id in 1..device_tree::HART_COUNT.load(Ordering::SeqCst) {
+        let hart_id = device_tree::hart_id(cpu_id);
+
+        let args_frame = match crate::memory::allocate_frames(1) {
+            Some(frame) => frame,
+            None => {
+                println!("No memory for the arguments of hart {}", hart_id);
+                break;
+            }
+        };
+        let stack_start = match crate::memory::allocate_frames(AP_STACK_PAGES) {
+            Some(frame) => frame.start_address().data() + crate::PHYS_OFFSET,
+            None => {
+                println!("No memory for the stack of hart {}", hart_id);
+                break;
+            }
+        };
+
+        let args_phys = args_frame.start_address().data();
+        let args_virt = args_phys + crate::PHYS_OFFSET;
+        (args_virt as *mut KernelArgsAp).write(KernelArgsAp {
+            cpu_id,
+            satp,
+            stack_start,
+            stack_end: stack_start + AP_STACK_PAGES * PAGE_SIZE,
+            args: args_virt,
+            entry: kstart_ap_virt as usize,
+        });
+
+        AP_READY.store(false, Ordering::SeqCst);
+
+        print!("        Hart {}:", hart_id);
+        if let Err(err) = sbi::hart_start(hart_id, kernel_phys(kstart_ap_trampoline as usize), args_phys) {
+            println!(" failed to start: {:?}", err);
+            continue;
+        }
+
+        print!(" Wait...");
+        while !AP_READY.load(Ordering::SeqCst) {
+            interrupt::pause();
+        }
+        println!(" Ready");
+
+        CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+// The hart starts in S-mode with the MMU off, the hart ID in a0 and the physical address of its
+// `KernelArgsAp` in a1. Enabling paging faults on the next instruction fetch, as the trampoline
+// is not identity mapped, which jumps to the virtual entry set in `stvec`.
+core::arch::global_asm!(
+    "
+    .section .text.kstart_ap, \"ax\", @progbits
+    .global kstart_ap_trampoline
+    .global kstart_ap_virt
+    .p2align 2
+kstart_ap_trampoline:
+    ld t0, 8(a1)
+    ld sp, 24(a1)
+    ld a0, 32(a1)
+    ld t1, 40(a1)
+    csrw stvec, t1
+    sfence.vma
+    csrw satp, t0
+    unimp
+
+    .p2align 2
+kstart_ap_virt:
+    sfence.vma
+    mv s0, zero
+    call {kstart_ap}
+    unimp
+    .text
+    ",
+    kstart_ap = sym kstart_ap,
+);
+
+/// Entry to rust for an AP
+unsafe extern "C" fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
+    let cpu_id = {
+        let args = &*args_ptr;
+        let cpu_id = args.cpu_id;
+
+        assert_eq!(BSS_TEST_ZERO, 0);
+        assert_eq!(DATA_TEST_NONZERO, 0xFFFF_FFFF_FFFF_FFFF);
+
+        // Setup trap handler
+        init_hart();
+
+        // Initialize paging, with the kernel page table of the BSP, which `satp` already points to
+        paging::init_ap(cpu_id, &mut KernelMapper::lock());
+
+        // Test tdata and tbss
+        {
+            assert_eq!(TBSS_TEST_ZERO, 0);
+            TBSS_TEST_ZERO += 1;
+            assert_eq!(TBSS_TEST_ZERO, 1);
+            assert_eq!(TDATA_TEST_NONZERO, 0xFFFF_FFFF_FFFF_FFFF);
+            TDATA_TEST_NONZERO -= 1;
+            assert_eq!(TDATA_TEST_NONZERO, 0xFFFF_FFFF_FFFF_FFFE);
+        }
+
+        // Initialize devices (for AP)
+        device::init_ap();
+
+        AP_READY.store(true, Ordering::SeqCst);
+
+        cpu_id
+    };
+
+    while ! BSP_READY.load(Ordering::SeqCst) {
+        interrupt::pause();
+    }
+
+    crate::kmain_ap(cpu_id);
+}
+
+/// Enter userspace for the first time. The kernel stack of the context is taken again on the
+/// next trap, and the user thread pointer is the one in the trap data of the CPU.
+///
+/// Single step is not supported (see `InterruptStack::is_singlestep`).
+#[naked]
+//TODO: clear all regs?
+pub unsafe extern "C" fn usermode(_ip: usize, _sp: usize, _arg: usize, _is_singlestep: usize) -> ! {
+    core::arch::asm!(
+        "
+        // No trap may be taken between setting sscratch and sret
+        csrci sstatus, 2
+        sd sp, -8(tp)
+        csrw sscratch, tp
+
+        li t0, {spp}
+        csrc sstatus, t0
+        li t0, {spie}
+        csrs sstatus, t0
+
+        csrw sepc, a0 // ip
+        mv a0, a2 // arg
+        ld tp, -24(tp)
+        mv sp, a1 // sp
+        sret
+        ",
+        spp = const interrupt::handler::SSTATUS_SPP,
+        spie = const 1 << 5,
+        options(noreturn),
+    );
+}
@@ -0,0 +1,17 @@
+use crate::arch::sbi;
+
+#[no_mangle]
+pub unsafe extern fn kreset() -> ! {
+    println!("kreset");
+
+    let err = sbi::system_reset(sbi::RESET_TYPE_COLD_REBOOT, sbi::RESET_REASON_NONE);
+    panic!("SBI system reset failed: {:?}", err);
+}
+
+#[no_mangle]
+pub unsafe extern fn kstop() -> ! {
+    println!("kstop");
+
+    let err = sbi::system_reset(sbi::RESET_TYPE_SHUTDOWN, sbi::RESET_REASON_NONE);
+    panic!("SBI system shutdown failed: {:?}", err);
+}
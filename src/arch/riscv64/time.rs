@@ -0,0 +1,18 @@
+use core::arch::asm;
+use core::sync::atomic::Ordering;
+
+use crate::arch::device_tree::TIMEBASE_FREQUENCY;
+use crate::time::NANOS_PER_SEC;
+
+/// The `time` CSR, counting at the timebase frequency since reset
+#[inline(always)]
+pub fn ticks() -> u64 {
+    let ticks: u64;
+    unsafe { asm!("rdtime {}", out(reg) ticks) };
+    ticks
+}
+
+/// Nanoseconds since reset
+pub fn counter() -> u128 {
+    u128::from(ticks()) * NANOS_PER_SEC / u128::from(TIMEBASE_FREQUENCY.load(Ordering::Relaxed))
+}
@@ -1,8 +1,8 @@
 use crate::devices::uart_16550::SerialPort;
 #[cfg(feature = "lpss_debug")]
+use crate::sync::Mutex;
 use crate::syscall::io::Mmio;
 use crate::syscall::io::Pio;
-use spin::Mutex;
 
 pub static COM1: Mutex<SerialPort<Pio<u8>>> = Mutex::new(SerialPort::<Pio<u8>>::new(0x3F8));
 pub static COM2: Mutex<SerialPort<Pio<u8>>> = Mutex::new(SerialPort::<Pio<u8>>::new(0x2F8));
@@ -12,8 +12,17 @@ pub static COM4: Mutex<SerialPort<Pio<u8>>> = Mutex::new(SerialPort::<Pio<u8>>::
 #[cfg(feature = "lpss_debug")]
 pub static LPSS: Mutex<Option<&'static mut SerialPort<Mmio<u32>>>> = Mutex::new(None);
 
-pub unsafe fn init() {
+/// Set up the debug serial port as early as possible, so boot output is visible over serial even
+/// when it is also going to the framebuffer
+pub unsafe fn init_early() {
     COM1.lock().init();
+    crate::log::set_sinks_ready(crate::log::Sinks::SERIAL, true);
+}
+
+pub unsafe fn init() {
+    if !crate::log::ready_sinks().contains(crate::log::Sinks::SERIAL) {
+        init_early();
+    }
     COM2.lock().init();
 
     #[cfg(feature = "lpss_debug")]
@@ -38,5 +47,6 @@ pub unsafe fn init() {
         lpss.init();
 
         *LPSS.lock() = Some(lpss);
+        crate::log::set_sinks_ready(crate::log::Sinks::LPSS, true);
     }
 }
@@ -1,10 +1,13 @@
-use spin::Mutex;
 use syscall::io::{Io, Pio};
 
+use crate::sync::Mutex;
+
 pub static SYSTEM76_EC: Mutex<Option<System76Ec>> = Mutex::new(None);
 
 pub fn init() {
-    *SYSTEM76_EC.lock() = System76Ec::new();
+    let system76_ec = System76Ec::new();
+    crate::log::set_sinks_ready(crate::log::Sinks::SYSTEM76_EC, system76_ec.is_some());
+    *SYSTEM76_EC.lock() = system76_ec;
 }
 
 pub struct System76Ec {
@@ -14,12 +14,14 @@ use crate::paging::{RmmA, RmmArch, PAGE_SIZE};
 
 use super::cpuid::cpuid;
 
+// SYSENTER and SYSEXIT derive the segments from IA32_SYSENTER_CS, which requires kernel code,
+// kernel data, user code and user data to be consecutive, in this order.
 pub const GDT_NULL: usize = 0;
 pub const GDT_KERNEL_CODE: usize = 1;
 pub const GDT_KERNEL_DATA: usize = 2;
-pub const GDT_KERNEL_PERCPU: usize = 3;
-pub const GDT_USER_CODE: usize = 4;
-pub const GDT_USER_DATA: usize = 5;
+pub const GDT_USER_CODE: usize = 3;
+pub const GDT_USER_DATA: usize = 4;
+pub const GDT_KERNEL_PERCPU: usize = 5;
 pub const GDT_USER_FS: usize = 6;
 pub const GDT_USER_GS: usize = 7;
 pub const GDT_TSS: usize = 8;
@@ -58,12 +60,12 @@ const BASE_GDT: [GdtEntry; 9] = [
     GdtEntry::new(0, 0xFFFFF, GDT_A_PRESENT | GDT_A_RING_0 | GDT_A_SYSTEM | GDT_A_EXECUTABLE | GDT_A_PRIVILEGE, GDT_F_PAGE_SIZE | GDT_F_PROTECTED_MODE),
     // Kernel data
     GdtEntry::new(0, 0xFFFFF, GDT_A_PRESENT | GDT_A_RING_0 | GDT_A_SYSTEM | GDT_A_PRIVILEGE, GDT_F_PAGE_SIZE | GDT_F_PROTECTED_MODE),
-    // Kernel TLS
-    GdtEntry::new(0, 0xFFFFF, GDT_A_PRESENT | GDT_A_RING_0 | GDT_A_SYSTEM | GDT_A_PRIVILEGE, GDT_F_PAGE_SIZE | GDT_F_PROTECTED_MODE),
     // User (32-bit) code
     GdtEntry::new(0, 0xFFFFF, GDT_A_PRESENT | GDT_A_RING_3 | GDT_A_SYSTEM | GDT_A_EXECUTABLE | GDT_A_PRIVILEGE, GDT_F_PAGE_SIZE | GDT_F_PROTECTED_MODE),
     // User data
     GdtEntry::new(0, 0xFFFFF, GDT_A_PRESENT | GDT_A_RING_3 | GDT_A_SYSTEM | GDT_A_PRIVILEGE, GDT_F_PAGE_SIZE | GDT_F_PROTECTED_MODE),
+    // Kernel TLS
+    GdtEntry::new(0, 0xFFFFF, GDT_A_PRESENT | GDT_A_RING_0 | GDT_A_SYSTEM | GDT_A_PRIVILEGE, GDT_F_PAGE_SIZE | GDT_F_PROTECTED_MODE),
     // User FS (for TLS)
     GdtEntry::new(0, 0xFFFFF, GDT_A_PRESENT | GDT_A_RING_3 | GDT_A_SYSTEM | GDT_A_PRIVILEGE, GDT_F_PAGE_SIZE | GDT_F_PROTECTED_MODE),
     // User GS (for TLS)
@@ -72,12 +74,20 @@ const BASE_GDT: [GdtEntry; 9] = [
     GdtEntry::new(0, 0, GDT_A_PRESENT | GDT_A_RING_3 | GDT_A_TSS_AVAIL, 0),
 ];
 
+/// Size of the SYSENTER entry stack, in words
+pub const SYSENTER_STACK_WORDS: usize = 256;
+
 #[repr(C, align(4096))]
 pub struct ProcessorControlRegion {
     pub tcb_end: usize,
     pub user_rsp_tmp: usize,
+    // The stack IA32_SYSENTER_ESP points to the top of. The entry only reads the kernel stack of
+    // the current context from the TSS right above it, but a debug trap taken on the first
+    // instruction of the entry, when single stepping over SYSENTER, runs on it.
+    pub sysenter_stack: [usize; SYSENTER_STACK_WORDS],
     pub tss: TssWrapper,
     pub self_ref: usize,
+    // The GDT is stored in the PCR, like on x86_64
     pub gdt: [GdtEntry; 9],
 }
 
@@ -86,6 +96,28 @@ pub struct ProcessorControlRegion {
 #[repr(C, align(16))]
 pub struct TssWrapper(pub TaskStateSegment);
 
+const _: () = {
+    if memoffset::offset_of!(ProcessorControlRegion, tss) % 16 != 0 {
+        panic!("PCR is incorrectly defined, TSS alignment is too small");
+    }
+    if memoffset::offset_of!(ProcessorControlRegion, gdt) % 8 != 0 {
+        panic!("PCR is incorrectly defined, GDT alignment is too small");
+    }
+    if mem::size_of::<ProcessorControlRegion>() > PAGE_SIZE {
+        panic!("PCR is incorrectly defined, it does not fit in its page");
+    }
+    // The interrupt entries load the kernel TLS segment with a hardcoded selector
+    if GDT_KERNEL_PERCPU << 3 != 0x28 {
+        panic!("GDT is incorrectly defined, the kernel TLS selector differs from `enter_gs`");
+    }
+};
+
+/// Offset of the top of the SYSENTER entry stack in the PCR
+pub const PCR_SYSENTER_STACK_END: usize = memoffset::offset_of!(ProcessorControlRegion, sysenter_stack) + SYSENTER_STACK_WORDS * mem::size_of::<usize>();
+/// Offset of the kernel stack pointer of the current context in the PCR, loaded by the SYSENTER
+/// entry
+pub const PCR_KERNEL_STACK: usize = memoffset::offset_of!(ProcessorControlRegion, tss) + memoffset::offset_of!(TaskStateSegment, esp0);
+
 pub unsafe fn pcr() -> *mut ProcessorControlRegion {
     let mut ret: *mut ProcessorControlRegion;
     core::arch::asm!("mov {}, gs:[{}]", out(reg) ret, const(memoffset::offset_of!(ProcessorControlRegion, self_ref)));
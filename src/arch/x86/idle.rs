@@ -0,0 +1,17 @@
+//! # Idle states
+//! Only `hlt`.
+
+use crate::idle::State;
+use crate::interrupt;
+
+static STATES: [State; 1] = [State { name: "hlt", exit_latency: 1, target_residency: 1 }];
+
+/// The idle states, shallowest first
+pub fn states() -> &'static [State] {
+    &STATES
+}
+
+/// Enable interrupts and wait for the next one
+pub unsafe fn enter(_index: usize) {
+    interrupt::enable_and_halt();
+}
@@ -4,6 +4,7 @@ use x86::irq::PageFaultError;
 use crate::{
     interrupt::stack_trace,
     ptrace,
+    syscall::abi::{SEGV_ACCERR, SEGV_MAPERR},
     syscall::flag::*,
 
     interrupt_stack,
@@ -12,6 +13,7 @@ use crate::{
 
 extern {
     fn ksignal(signal: usize);
+    fn ksignal_fault(signal: usize, code: i32, addr: usize);
 }
 
 interrupt_stack!(divide_by_zero, |stack| {
@@ -22,6 +24,14 @@ interrupt_stack!(divide_by_zero, |stack| {
 });
 
 interrupt_stack!(debug, @paranoid, |stack| {
+    // SYSENTER does not clear TF, so single stepping over it traps on the first instruction of
+    // the entry, still on the entry stack. Defer the trap to the return to userspace instead.
+    if { stack.iret.eip } == super::syscall::sysenter_instruction as usize {
+        stack.set_singlestep(false);
+        super::syscall::SYSENTER_SINGLESTEP.set(true);
+        return;
+    }
+
     let mut handled = false;
 
     // Disable singlestep before there is a breakpoint, since the breakpoint
@@ -163,7 +173,13 @@ interrupt_error!(page, |stack| {
     let caused_by_user = flags.contains(PageFaultError::US);
     let caused_by_instr_fetch = flags.contains(PageFaultError::ID);
 
-    if address_is_user && !caused_by_user && !caused_by_instr_fetch && !invalid_page_tables && usercopy_region.contains(&{ stack.inner.iret.eip }) {
+    // Lazily allocated memory is backed by a frame on first access, by userspace or usercopy
+    let in_usercopy = usercopy_region.contains(&{ stack.inner.iret.eip });
+    if address_is_user && !flags.contains(PageFaultError::P) && !invalid_page_tables && (caused_by_user || in_usercopy) && crate::context::memory::try_demand_page(rmm::VirtualAddress::new(cr2), caused_by_user) {
+        return;
+    }
+
+    if address_is_user && !caused_by_user && !caused_by_instr_fetch && !invalid_page_tables && in_usercopy {
         // Unlike on x86_64, Protected Mode interrupts will not save/restore esp and ss unless
         // privilege rings changed, which they won't here as we are catching a kernel-induced page
         // fault.
@@ -186,7 +202,8 @@ interrupt_error!(page, |stack| {
 
     stack.dump();
     stack_trace();
-    ksignal(SIGSEGV);
+    let code = if flags.contains(PageFaultError::P) { SEGV_ACCERR } else { SEGV_MAPERR };
+    ksignal_fault(SIGSEGV, code, cr2);
 });
 
 interrupt_stack!(fpu_fault, |stack| {
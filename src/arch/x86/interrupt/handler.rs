@@ -207,9 +207,9 @@ macro_rules! pop_preserved {
 
 macro_rules! enter_gs {
     () => { "
-        // Enter kernel GS segment
+        // Enter kernel GS segment, GDT_KERNEL_PERCPU
         push gs
-        push 0x18
+        push 0x28
         pop gs
     " }
 }
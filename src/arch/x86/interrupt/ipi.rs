@@ -24,8 +24,15 @@ interrupt!(switch, || {
 interrupt!(pit, || {
     LOCAL_APIC.eoi();
 
-    // Switch after 3 ticks (about 6.75 ms)
-    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 {
+    crate::lockup::tick();
+
+    // A parked CPU has nothing to switch to
+    if crate::hotplug::is_parked(crate::cpu_id()) {
+        return;
+    }
+
+    // Switch after 3 ticks (about 6.75 ms), or right away if a low-latency context is due
+    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 || context::latency::due() {
         let _ = context::switch();
     }
 });
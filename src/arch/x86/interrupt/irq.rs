@@ -67,6 +67,12 @@ extern {
 /// Notify the IRQ scheme that an IRQ has been registered. This should mask the IRQ until the
 /// scheme user unmasks it ("acknowledges" it).
 unsafe fn trigger(irq: u8) {
+    // The SCI, once the kernel took it over, unless another device sharing it raised it
+    #[cfg(feature = "acpi")]
+    if crate::acpi::sci::interrupt(irq) {
+        return;
+    }
+
     match irq_method() {
         IrqMethod::Pic => if irq < 16 { pic_mask(irq) },
         IrqMethod::Apic => ioapic_mask(irq),
@@ -83,6 +89,15 @@ pub unsafe fn acknowledge(irq: usize) {
     }
 }
 
+/// Mask the IRQ regardless of whether it has been acknowledged. This is called from the IRQ
+/// scheme when the IRQ is firing too often, and is undone by `acknowledge`.
+pub unsafe fn mask(irq: usize) {
+    match irq_method() {
+        IrqMethod::Pic => if irq < 16 { pic_mask(irq as u8) },
+        IrqMethod::Apic => ioapic_mask(irq as u8),
+    }
+}
+
 /// Sends an end-of-interrupt, so that the interrupt controller can go on to the next one.
 pub unsafe fn eoi(irq: u8) {
     match irq_method() {
@@ -149,8 +164,16 @@ interrupt_stack!(pit_stack, |_stack| {
     // Any better way of doing this?
     timeout::trigger();
 
-    // Switch after 3 ticks (about 6.75 ms)
-    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 {
+    crate::scheme::irq::storm_tick();
+
+    crate::time::watchdog();
+
+    crate::watchdog::poll();
+
+    crate::lockup::tick();
+
+    // Switch after 3 ticks (about 6.75 ms), or right away if a low-latency context is due
+    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 || context::latency::due() {
         let _ = context::switch();
     }
 });
@@ -1,14 +1,49 @@
+//! # Syscall entries
+//! Syscalls enter the kernel either through `int 0x80`, or through the faster `sysenter` on CPUs
+//! that support it. Both take the syscall number in eax and the arguments in ebx, ecx, edx, esi
+//! and edi, and return the result in eax.
+//!
+//! As `sysenter` saves neither the instruction nor the stack pointer, userspace calls a stub
+//! doing `push ebp; mov ebp, esp; sysenter`. The kernel returns to the address above the saved
+//! ebp with `sysexit`, as if the stub had returned. `sysexit` clobbers ecx and edx, unless the
+//! kernel needs to return with `iretd` instead, for example when single stepping.
+
+use core::cell::Cell;
+use core::mem;
+
 use crate::{
-    arch::{gdt, interrupt::InterruptStack},
-    context,
+    arch::{cpuid::cpuid, flags::FLAG_INTERRUPTS, gdt, interrupt::InterruptStack},
     ptrace,
     syscall,
-    syscall::flag::{PTRACE_FLAG_IGNORE, PTRACE_STOP_PRE_SYSCALL, PTRACE_STOP_POST_SYSCALL},
+    syscall::flag::{PTRACE_FLAG_IGNORE, PTRACE_STOP_PRE_SYSCALL, PTRACE_STOP_POST_SYSCALL, SIGSEGV},
+    syscall::usercopy::UserSliceRo,
 };
-use memoffset::offset_of;
-use x86::{bits32::task::TaskStateSegment, msr, segmentation::SegmentSelector};
+use x86::{msr, segmentation::SegmentSelector};
+
+/// Set by the debug trap when it was taken on the first instruction of `sysenter_instruction`,
+/// because userspace single stepped over `sysenter`, which does not clear TF
+#[thread_local]
+pub static SYSENTER_SINGLESTEP: Cell<bool> = Cell::new(false);
 
-pub unsafe fn init() {}
+/// Returns true if the CPU supports `sysenter`. The Pentium Pro reports SEP without supporting
+/// the instructions.
+fn has_sysenter() -> bool {
+    cpuid().and_then(|cpuid| cpuid.get_feature_info()).map_or(false, |info| {
+        info.has_sysenter_sysexit() && !(info.family_id() == 6 && info.model_id() < 3 && info.stepping_id() < 3)
+    })
+}
+
+pub unsafe fn init() {
+    if !has_sysenter() {
+        return;
+    }
+
+    // SYSENTER loads CS from IA32_SYSENTER_CS and SS from the next descriptor. SYSEXIT loads the
+    // user code and data descriptors following them (see `gdt`).
+    msr::wrmsr(msr::IA32_SYSENTER_CS, (gdt::GDT_KERNEL_CODE << 3) as u64);
+    msr::wrmsr(msr::IA32_SYSENTER_ESP, (gdt::pcr() as usize + gdt::PCR_SYSENTER_STACK_END) as u64);
+    msr::wrmsr(msr::IA32_SYSENTER_EIP, sysenter_instruction as usize as u64);
+}
 
 macro_rules! with_interrupt_stack {
     (|$stack:ident| $code:block) => {{
@@ -36,6 +71,121 @@ interrupt_stack!(syscall, |stack| {
     })
 });
 
+/// Returns nonzero if the entry can return with `sysexit`
+#[no_mangle]
+pub unsafe extern "C" fn __inner_sysenter_instruction(stack: *mut InterruptStack) -> usize {
+    let _guard = ptrace::set_process_regs(stack);
+
+    {
+        let stack = &mut *stack;
+
+        // The stub pushed the address to return to and ebp, and passed its stack pointer in ebp
+        let frame = stack.preserved.ebp;
+        let saved = UserSliceRo::ro(frame, 2 * mem::size_of::<usize>()).and_then(|slice| {
+            let (ebp, eip) = slice.split_at(mem::size_of::<usize>()).expect("split within bounds");
+            Ok((ebp.read_usize()?, eip.read_usize()?))
+        });
+        let (ebp, eip) = match saved {
+            Ok(saved) => saved,
+            Err(_) => {
+                println!("Invalid sysenter stack: {:08x}", frame);
+                stack.dump();
+                crate::ksignal(SIGSEGV);
+                return 0;
+            }
+        };
+        stack.preserved.ebp = ebp;
+        stack.iret.eip = eip;
+        stack.iret.esp = frame + 2 * mem::size_of::<usize>();
+
+        if SYSENTER_SINGLESTEP.replace(false) {
+            stack.set_singlestep(true);
+        }
+    }
+
+    let (ecx, edx) = ((*stack).scratch.ecx, (*stack).scratch.edx);
+
+    with_interrupt_stack!(|stack| {
+        let scratch = &stack.scratch;
+        let preserved = &stack.preserved;
+        syscall::syscall(scratch.eax, preserved.ebx, scratch.ecx, scratch.edx, preserved.esi, preserved.edi, stack)
+    });
+
+    // SYSEXIT cannot restore TF, nor ecx and edx if a tracer changed them
+    let stack = &*stack;
+    let fast = !stack.is_singlestep() && { stack.scratch.ecx } == ecx && { stack.scratch.edx } == edx;
+    usize::from(fast)
+}
+
+#[naked]
+pub unsafe extern "C" fn sysenter_instruction() {
+    core::arch::asm!(concat!(
+    "
+        // ESP points to the top of the entry stack in the PCR, below the TSS
+        mov esp, [esp + {ksp}]      // Load kernel stack pointer
+
+        push {ss_sel}               // Push userspace SS (resembling iret frame)
+        push ebp                    // Push userspace esp, fixed up by the inner function
+        pushfd                      // Push eflags, with IF that SYSENTER cleared
+        or DWORD PTR [esp], {flag_interrupts}
+        push {cs_sel}               // Push userspace CS (resembling iret frame)
+        push 0                      // Push userspace return pointer, read by the inner function
+    ",
+
+    // Push context registers
+    "push eax\n",
+    push_scratch!(),
+    push_preserved!(),
+
+    // Enter kernel TLS segment
+    enter_gs!(),
+
+    // Call inner function
+    "
+        push esp
+        call {inner}
+        pop esp
+    ",
+
+    // Exit kernel TLS segment
+    exit_gs!(),
+
+    // Pop context registers
+    "
+        test eax, eax
+        jz 1f
+    ",
+    pop_preserved!(),
+    pop_scratch!(),
+    "
+        // Fast sysexit, with interrupts enabled by STI right before it
+        mov edx, [esp]              // Userspace return pointer
+        mov ecx, [esp + 12]         // Userspace stack pointer
+        add esp, 8
+        and DWORD PTR [esp], ~{flag_interrupts}
+        popfd
+        sti
+        sysexit
+
+    1:
+    ",
+    pop_preserved!(),
+    pop_scratch!(),
+    "
+        // Slow iretd
+        iretd
+    "),
+
+    ksp = const(gdt::PCR_KERNEL_STACK - gdt::PCR_SYSENTER_STACK_END),
+    ss_sel = const(SegmentSelector::new(gdt::GDT_USER_DATA as u16, x86::Ring::Ring3).bits()),
+    cs_sel = const(SegmentSelector::new(gdt::GDT_USER_CODE as u16, x86::Ring::Ring3).bits()),
+    flag_interrupts = const(FLAG_INTERRUPTS),
+    inner = sym __inner_sysenter_instruction,
+
+    options(noreturn),
+    );
+}
+
 #[naked]
 pub unsafe extern "C" fn clone_ret() {
     core::arch::asm!(concat!(
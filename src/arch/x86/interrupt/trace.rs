@@ -1,7 +1,4 @@
-use core::{mem, str};
-
-use goblin::elf::sym;
-use rustc_demangle::demangle;
+use core::mem;
 
 use crate::{paging::{KernelMapper, VirtualAddress}};
 
@@ -41,55 +38,11 @@ pub unsafe fn stack_trace() {
     }
 }
 
-/// Get a symbol
-//TODO: Do not create Elf object for every symbol lookup
+/// Print the function containing `addr`
 #[inline(never)]
 pub unsafe fn symbol_trace(addr: usize) {
-    use core::slice;
-    use core::sync::atomic::Ordering;
-
-    use crate::elf::Elf;
-    use crate::start::{KERNEL_BASE, KERNEL_SIZE};
-
-    let kernel_ptr = (KERNEL_BASE.load(Ordering::SeqCst) + crate::PHYS_OFFSET) as *const u8;
-    let kernel_slice = slice::from_raw_parts(kernel_ptr, KERNEL_SIZE.load(Ordering::SeqCst));
-    if let Ok(elf) = Elf::from(kernel_slice) {
-        let mut strtab_opt = None;
-        for section in elf.sections() {
-            if section.sh_type == ::goblin::elf::section_header::SHT_STRTAB {
-                strtab_opt = Some(section);
-                break;
-            }
-        }
-
-        if let Some(symbols) = elf.symbols() {
-            for sym in symbols {
-                if sym::st_type(sym.st_info) == sym::STT_FUNC
-                && addr >= sym.st_value as usize
-                && addr < (sym.st_value + sym.st_size) as usize
-                {
-                    println!("    {:>016X}+{:>04X}", sym.st_value, addr - sym.st_value as usize);
-
-                    if let Some(strtab) = strtab_opt {
-                        let start = strtab.sh_offset as usize + sym.st_name as usize;
-                        let mut end = start;
-                        while end < elf.data.len() {
-                            let b = elf.data[end];
-                            end += 1;
-                            if b == 0 {
-                                break;
-                            }
-                        }
-
-                        if end > start {
-                            let sym_slice = &elf.data[start .. end - 1];
-                            if let Ok(sym_name) = str::from_utf8(sym_slice) {
-                                println!("    {:#}", demangle(sym_name));
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    if let Some(location) = crate::symbols::lookup(addr) {
+        println!("    {:>016X}+{:>04X}", location.symbol.address, location.offset);
+        println!("    {}", location.symbol);
     }
 }
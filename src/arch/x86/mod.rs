@@ -16,6 +16,9 @@ pub mod device;
 /// Global descriptor table
 pub mod gdt;
 
+/// Idle states
+pub mod idle;
+
 /// Interrupt instructions
 #[macro_use]
 pub mod interrupt;
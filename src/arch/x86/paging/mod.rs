@@ -1,7 +1,7 @@
 //! # Paging
 //! Some code was borrowed from [Phil Opp's Blog](http://os.phil-opp.com/modifying-page-tables.html)
 
-use core::{mem, ptr};
+use core::{mem, ops::Range, ptr};
 use x86::msr;
 
 use self::mapper::PageFlushAll;
@@ -20,6 +20,7 @@ pub type PageMapper = rmm::PageMapper<RmmA, crate::arch::rmm::LockedAllocator>;
 pub use crate::rmm::KernelMapper;
 
 pub mod entry;
+pub mod huge;
 pub mod mapper;
 
 /// Number of entries per page table
@@ -66,6 +67,12 @@ pub unsafe fn init() {
     init_pat();
 }
 
+/// Indexes of the top level page table entries mapping the kernel, which every page table shares
+pub fn kernel_entries() -> Range<usize> {
+    let shift = RmmA::PAGE_SHIFT + RmmA::PAGE_ENTRY_SHIFT * (RmmA::PAGE_LEVELS - 1);
+    (crate::USER_END_OFFSET >> shift)..((usize::MAX >> shift) + 1)
+}
+
 /// Page
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Page {
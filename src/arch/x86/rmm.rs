@@ -8,7 +8,6 @@ use rmm::{
     KILOBYTE,
     MEGABYTE,
     Arch,
-    BuddyAllocator,
     BumpAllocator,
     FrameAllocator,
     FrameCount,
@@ -21,7 +20,10 @@ use rmm::{
     TableKind,
     VirtualAddress,
 };
-use spin::Mutex;
+
+use crate::memory::buddy::BuddyAllocator;
+use crate::memory::{firmware, numa, zone};
+use crate::sync::Mutex;
 
 use super::CurrentRmmArch as RmmA;
 
@@ -108,7 +110,7 @@ unsafe fn inner<A: Arch>(
 
         // Pre-allocate all kernel PD entries so that when the page table is copied,
         // these entries are synced between processes
-        for i in 512..1024 {
+        for i in crate::paging::kernel_entries() {
             let phys = mapper.allocator_mut().allocate_one().expect("failed to map page table");
             let flags = A::ENTRY_FLAG_READWRITE | A::ENTRY_FLAG_DEFAULT_TABLE;
             mapper.table().set_entry(i, PageEntry::new(phys.data() | flags));
@@ -170,6 +172,17 @@ unsafe fn inner<A: Arch>(
         identity_map(acpi_base, acpi_size_aligned);
         identity_map(initfs_base, initfs_size_aligned);
 
+        // Map the memory kept out of the allocator for zones, see `memory::zone`
+        zone::for_each_free_frame(|phys| {
+            let virt = A::phys_to_virt(phys);
+            let flush = mapper.map_phys(
+                virt,
+                phys,
+                page_flags::<A>(virt)
+            ).expect("failed to map frame");
+            flush.ignore(); // Not the active table
+        });
+
         // Ensure graphical debug region remains paged
         #[cfg(feature = "graphical_debug")]
         {
@@ -211,7 +224,7 @@ unsafe fn inner<A: Arch>(
     let offset = bump_allocator.offset();
     log::info!("Permanently used: {} KB", (offset + (KILOBYTE - 1)) / KILOBYTE);
 
-    BuddyAllocator::<A>::new(bump_allocator).expect("failed to create BuddyAllocator")
+    BuddyAllocator::<A>::new(areas, offset).expect("failed to create BuddyAllocator")
 }
 
 // There can only be one allocator (at the moment), so making this a ZST is great!
@@ -222,14 +235,33 @@ static INNER_ALLOCATOR: Mutex<Option<BuddyAllocator<RmmA>>> = Mutex::new(None);
 
 impl FrameAllocator for LockedAllocator {
     unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
-        if let Some(ref mut allocator) = *INNER_ALLOCATOR.lock() {
-            allocator.allocate(count)
+        // Single frames come from the free list of the current CPU's node first
+        if count.data() == 1 {
+            if let Some(address) = numa::allocate_local() {
+                return Some(address);
+            }
+        }
+        let address = if let Some(ref mut allocator) = *INNER_ALLOCATOR.lock() {
+            match allocator.allocate(count) {
+                // The missing frames may be on the node free lists
+                None if count.data() > 1 && numa::drain(|address| allocator.free(address, FrameCount::new(1))) > 0 => {
+                    allocator.allocate(count)
+                }
+                address => address,
+            }
         } else {
             None
+        };
+        match address {
+            None if count.data() == 1 => numa::allocate_remote(),
+            address => address,
         }
     }
 
     unsafe fn free(&mut self, address: PhysicalAddress, count: FrameCount) {
+        if count.data() == 1 && numa::free(address) {
+            return;
+        }
         if let Some(ref mut allocator) = *INNER_ALLOCATOR.lock() {
             allocator.free(address, count)
         }
@@ -237,7 +269,9 @@ impl FrameAllocator for LockedAllocator {
 
     unsafe fn usage(&self) -> FrameUsage {
         if let Some(ref allocator) = *INNER_ALLOCATOR.lock() {
-            allocator.usage()
+            // Frames on the node free lists are free, although the allocator does not know
+            let usage = allocator.usage();
+            FrameUsage::new(FrameCount::new(usage.used().data() - numa::free_frames()), usage.total())
         } else {
             FrameUsage::new(FrameCount::new(0), FrameCount::new(0))
         }
@@ -370,12 +404,31 @@ pub unsafe fn init(
         areas_size / mem::size_of::<BootloaderMemoryEntry>()
     );
 
+    // Memory that was in use while booting, and must not be given to the low zone
+    let boot_data = [
+        (kernel_base, kernel_end),
+        (stack_base, stack_end),
+        (env_base, env_end),
+        (acpi_base, acpi_end),
+        (initfs_base, initfs_end),
+    ];
+
     // Copy memory map from bootloader location, and page align it
     let mut area_i = 0;
     for bootloader_area in bootloader_areas.iter() {
-        if { bootloader_area.kind } != BootloaderMemoryKind::Free {
+        match { bootloader_area.kind } {
+            BootloaderMemoryKind::Free => (),
+            // Kept for reading through `physmem:`
+            BootloaderMemoryKind::Reclaim => {
+                firmware::add(bootloader_area.base, bootloader_area.size, firmware::Kind::Reclaim);
+                continue;
+            }
+            BootloaderMemoryKind::Reserved => {
+                firmware::add(bootloader_area.base, bootloader_area.size, firmware::Kind::Reserved);
+                continue;
+            }
             // Not a free area
-            continue;
+            BootloaderMemoryKind::Null => continue,
         }
 
         let mut base = bootloader_area.base as usize;
@@ -398,6 +451,13 @@ pub unsafe fn init(
 
         let mut new_base = base;
 
+        // Keep free real-mode memory for the low zone
+        for frame in (base..cmp::min(base + size, real_end)).step_by(A::PAGE_SIZE) {
+            if !boot_data.iter().any(|&(start, end)| frame < end && frame + A::PAGE_SIZE > start) {
+                zone::add_low(PhysicalAddress::new(frame));
+            }
+        }
+
         // Ensure real-mode areas are not used
         if base < real_end && base + size > real_base {
             log::warn!("{:X}:{:X} overlaps with real mode {:X}:{:X}", base, size, real_base, real_size);
@@ -86,6 +86,13 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
         // Convert env to slice
         let env = slice::from_raw_parts((args.env_base as usize + crate::PHYS_OFFSET) as *const u8, args.env_size as usize);
 
+        // Set up the debug sinks that need no memory management, all of which receive early output
+        #[cfg(feature = "qemu_debug")]
+        log::set_sinks_ready(log::Sinks::QEMU, true);
+
+        #[cfg(feature = "serial_debug")]
+        device::serial::init_early();
+
         // Set up graphical debug
         #[cfg(feature = "graphical_debug")]
         graphical_debug::init(env);
@@ -97,13 +104,14 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
         log::init_logger(|r| {
             use core::fmt::Write;
             let _ = writeln!(
-                super::debug::Writer::new(),
+                super::debug::Writer::with(log::syslog_level(r.level()), log::FACILITY_KERNEL),
                 "{}:{} -- {}",
                 r.target(),
                 r.level(),
                 r.args()
             );
         });
+        log::configure(env);
 
         info!("Redox OS starting...");
         info!("Kernel: {:X}:{:X}", { args.kernel_base }, { args.kernel_base } + { args.kernel_size });
@@ -165,12 +173,12 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
 
         idt::init_paging_post_heap(true, 0);
 
-        // Activate memory logging
-        log::init();
-
         // Initialize devices
         device::init();
 
+        // Drive the virtio console and entropy device until userspace drivers start
+        crate::devices::virtio::init();
+
         // Read ACPI tables, starts APs
         #[cfg(feature = "acpi")]
         {
@@ -180,6 +188,9 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
                 None
             });
             device::init_after_acpi();
+
+            // Load the ACPI namespace, once the timers it may wait on are running
+            acpi::aml::init();
         }
 
         // Initialize all of the non-core devices not otherwise needed to complete initialization
@@ -194,6 +205,7 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
         crate::Bootstrap {
             base: crate::memory::Frame::containing_address(crate::paging::PhysicalAddress::new(args.bootstrap_base as usize)),
             page_count: (args.bootstrap_size as usize) / crate::memory::PAGE_SIZE,
+            size: args.bootstrap_size as usize,
             entry: args.bootstrap_entry as usize,
             env,
         }
@@ -1,5 +1,6 @@
 #[cfg(feature = "acpi")]
 use crate::{
+    acpi::fadt::FADT,
     context,
     scheme::acpi,
     time,
@@ -11,6 +12,13 @@ use crate::syscall::io::{Io, Pio};
 pub unsafe extern fn kreset() -> ! {
     println!("kreset");
 
+    // Reset register of the FADT
+    #[cfg(feature = "acpi")]
+    if let Some(fadt) = FADT.get().filter(|fadt| fadt.has_reset_reg()) {
+        println!("Reset with ACPI reset register");
+        fadt.reset();
+    }
+
     // 8042 reset
     {
         println!("Reset with 8042");
@@ -20,6 +20,10 @@
     /// Size of kernel heap
     pub const KERNEL_HEAP_SIZE: usize = 1 * 1024 * 1024; // 1 MB
 
+    /// Offset to kernel stacks, each with an unmapped guard area below it
+    pub const KERNEL_STACK_OFFSET: usize = KERNEL_HEAP_OFFSET - PML4_SIZE;
+    pub const KERNEL_STACK_PML4: usize = (KERNEL_STACK_OFFSET & PML4_MASK)/PML4_SIZE;
+
     /// Offset of physmap
     // This needs to match RMM's PHYS_OFFSET
     pub const PHYS_OFFSET: usize = 0xFFFF_8000_0000_0000;
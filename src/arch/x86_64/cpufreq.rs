@@ -0,0 +1,293 @@
+//! # CPU frequency scaling drivers
+//! Performance states of Intel CPUs with Enhanced SpeedStep, requested as a ratio of the 100 MHz
+//! bus clock in `IA32_PERF_CTL`, and of AMD CPUs with hardware P-states, requested by number in
+//! the P-state control register. Older Intel CPUs, whose ratios cannot be turned into frequencies,
+//! use the states of the ACPI `_PSS` object instead, evaluated by the kernel (see `acpi::aml`) or
+//! written to `cpufreq:pss` by the ACPI driver. Their control values are written to `IA32_PERF_CTL`, which
+//! is what functional fixed hardware means for Intel.
+//!
+//! States are numbered from the fastest, and each CPU sets its own (see `cpufreq`).
+
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid_count;
+use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+use x86::msr;
+
+use crate::sync::RwLock;
+
+/// Number of CPUs whose state is tracked
+pub const CPUS: usize = 32;
+
+/// Most hardware P-states of AMD CPUs
+const AMD_PSTATES: usize = 8;
+
+// CPUID.0:EBX
+const VENDOR_INTEL: u32 = 0x756E_6547; // "Genu"
+const VENDOR_AMD: u32 = 0x6874_7541; // "Auth"
+
+// CPUID.1:ECX, CPUID.6:EAX and CPUID.80000007h:EDX bits
+const CPUID_EIST: u32 = 1 << 7;
+const CPUID_TURBO: u32 = 1 << 1;
+const CPUID_HW_PSTATE: u32 = 1 << 7;
+
+const MSR_PLATFORM_INFO: u32 = 0xCE;
+const IA32_PERF_CTL: u32 = 0x199;
+const IA32_MISC_ENABLE: u32 = 0x1A0;
+const MSR_TURBO_RATIO_LIMIT: u32 = 0x1AD;
+const MSR_AMD_PSTATE_LIMIT: u32 = 0xC001_0061;
+const MSR_AMD_PSTATE_CTL: u32 = 0xC001_0062;
+const MSR_AMD_PSTATE_DEF: u32 = 0xC001_0064;
+
+const MISC_ENABLE_EIST: u64 = 1 << 16;
+const MISC_ENABLE_TURBO_DISABLE: u64 = 1 << 38;
+const AMD_PSTATE_ENABLED: u64 = 1 << 63;
+
+/// Intel bus clock, in MHz, from Sandy Bridge on
+const INTEL_BUS_MHZ: u32 = 100;
+/// First Intel model with a 100 MHz bus clock, Sandy Bridge
+const INTEL_MODEL_SANDY_BRIDGE: u32 = 0x2A;
+
+/// No state set yet
+const NONE: usize = usize::MAX;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+enum Driver {
+    None,
+    Intel,
+    Amd,
+    /// ACPI `_PSS`, on Intel CPUs with Enhanced SpeedStep
+    Pss,
+}
+
+impl Driver {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Driver::Intel,
+            2 => Driver::Amd,
+            3 => Driver::Pss,
+            _ => Driver::None,
+        }
+    }
+}
+
+/// A state of the ACPI `_PSS` object
+#[derive(Clone, Copy, Debug)]
+pub struct PssState {
+    pub mhz: u32,
+    /// Value to write to `IA32_PERF_CTL`
+    pub control: u64,
+}
+
+struct CpuState {
+    driver: AtomicU8,
+    /// Intel: lowest and highest ratios, turbo included if enabled. AMD: number of states.
+    low: AtomicU32,
+    high: AtomicU32,
+    /// AMD: P-state number in the upper byte, and frequency in MHz, of each state
+    amd: [AtomicU32; AMD_PSTATES],
+    /// The state set, or `NONE`
+    state: AtomicUsize,
+}
+
+static CPU_STATE: [CpuState; CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const PSTATE: AtomicU32 = AtomicU32::new(0);
+    #[allow(clippy::declare_interior_mutable_const)]
+    const STATE: CpuState = CpuState {
+        driver: AtomicU8::new(Driver::None as u8),
+        low: AtomicU32::new(0),
+        high: AtomicU32::new(0),
+        amd: [PSTATE; AMD_PSTATES],
+        state: AtomicUsize::new(NONE),
+    };
+    [STATE; CPUS]
+};
+
+/// States of the ACPI `_PSS` object, fastest first
+static PSS: RwLock<Vec<PssState>> = RwLock::new(Vec::new());
+
+/// Detect the performance states of the current CPU. Called on each CPU while starting, and when
+/// resuming, as the state set is lost.
+pub unsafe fn init(cpu_id: usize) {
+    let state = match CPU_STATE.get(cpu_id) {
+        Some(state) => state,
+        None => return,
+    };
+    state.state.store(NONE, Ordering::Relaxed);
+
+    let driver = match __cpuid_count(0, 0).ebx {
+        VENDOR_INTEL => init_intel(state),
+        VENDOR_AMD => init_amd(state),
+        _ => Driver::None,
+    };
+    state.driver.store(driver as u8, Ordering::Relaxed);
+}
+
+unsafe fn init_intel(state: &CpuState) -> Driver {
+    let signature = __cpuid_count(1, 0);
+    if signature.ecx & CPUID_EIST == 0 || msr::rdmsr(IA32_MISC_ENABLE) & MISC_ENABLE_EIST == 0 {
+        return Driver::None;
+    }
+
+    // MSR_PLATFORM_INFO and the bus clock are only known for family 6 from Sandy Bridge on
+    let family = (signature.eax >> 8) & 0xF;
+    let model = (signature.eax >> 4) & 0xF | (signature.eax >> 12) & 0xF0;
+    if family != 6 || model < INTEL_MODEL_SANDY_BRIDGE {
+        return Driver::Pss;
+    }
+
+    let platform_info = msr::rdmsr(MSR_PLATFORM_INFO);
+    let low = ((platform_info >> 40) & 0xFF) as u32;
+    let mut high = ((platform_info >> 8) & 0xFF) as u32;
+    if low == 0 || high < low {
+        return Driver::Pss;
+    }
+
+    // Requesting a ratio above the highest guaranteed one allows turbo
+    let turbo = __cpuid_count(0, 0).eax >= 6 && __cpuid_count(6, 0).eax & CPUID_TURBO != 0;
+    if turbo && msr::rdmsr(IA32_MISC_ENABLE) & MISC_ENABLE_TURBO_DISABLE == 0 {
+        high = high.max((msr::rdmsr(MSR_TURBO_RATIO_LIMIT) & 0xFF) as u32);
+    }
+
+    state.low.store(low, Ordering::Relaxed);
+    state.high.store(high, Ordering::Relaxed);
+    Driver::Intel
+}
+
+unsafe fn init_amd(state: &CpuState) -> Driver {
+    if __cpuid_count(0x8000_0000, 0).eax < 0x8000_0007 || __cpuid_count(0x8000_0007, 0).edx & CPUID_HW_PSTATE == 0 {
+        return Driver::None;
+    }
+
+    let signature = __cpuid_count(1, 0).eax;
+    let base_family = (signature >> 8) & 0xF;
+    let family = if base_family == 0xF { base_family + ((signature >> 20) & 0xFF) } else { base_family };
+    if family < 0x10 {
+        return Driver::None;
+    }
+
+    // Only the states up to the highest allowed one can be requested
+    let max = ((msr::rdmsr(MSR_AMD_PSTATE_LIMIT) >> 4) & 0x7) as u32;
+    let mut count = 0;
+    for pstate in 0..=max {
+        let definition = msr::rdmsr(MSR_AMD_PSTATE_DEF + pstate);
+        if definition & AMD_PSTATE_ENABLED == 0 {
+            continue;
+        }
+        let mhz = if family >= 0x17 {
+            let (fid, did) = ((definition & 0xFF) as u32, ((definition >> 8) & 0x3F) as u32);
+            if did == 0 { continue } else { fid * 200 / did }
+        } else {
+            let (fid, did) = ((definition & 0x3F) as u32, ((definition >> 6) & 0x7) as u32);
+            (100 * (fid + 0x10)) >> did
+        };
+        state.amd[count].store(pstate << 24 | mhz & 0xFF_FFFF, Ordering::Relaxed);
+        count += 1;
+    }
+    if count == 0 {
+        return Driver::None;
+    }
+
+    state.high.store(count as u32, Ordering::Relaxed);
+    Driver::Amd
+}
+
+fn driver_of(state: &CpuState) -> Driver {
+    match Driver::from_u8(state.driver.load(Ordering::Relaxed)) {
+        Driver::Pss if PSS.read().is_empty() => Driver::None,
+        driver => driver,
+    }
+}
+
+/// The name of the driver of `cpu_id`, if it has one
+pub fn driver(cpu_id: usize) -> Option<&'static str> {
+    match driver_of(CPU_STATE.get(cpu_id)?) {
+        Driver::None => None,
+        Driver::Intel => Some("intel"),
+        Driver::Amd => Some("amd"),
+        Driver::Pss => Some("acpi"),
+    }
+}
+
+/// Number of states of `cpu_id`, zero if it has no driver
+pub fn count(cpu_id: usize) -> usize {
+    let state = match CPU_STATE.get(cpu_id) {
+        Some(state) => state,
+        None => return 0,
+    };
+    match driver_of(state) {
+        Driver::None => 0,
+        Driver::Intel => (state.high.load(Ordering::Relaxed) - state.low.load(Ordering::Relaxed) + 1) as usize,
+        Driver::Amd => state.high.load(Ordering::Relaxed) as usize,
+        Driver::Pss => PSS.read().len(),
+    }
+}
+
+/// The frequency of state `index` of `cpu_id`, in MHz
+pub fn frequency(cpu_id: usize, index: usize) -> Option<u32> {
+    let state = CPU_STATE.get(cpu_id)?;
+    if index >= count(cpu_id) {
+        return None;
+    }
+    match driver_of(state) {
+        Driver::None => None,
+        Driver::Intel => Some((state.high.load(Ordering::Relaxed) - index as u32) * INTEL_BUS_MHZ),
+        Driver::Amd => Some(state.amd[index].load(Ordering::Relaxed) & 0xFF_FFFF),
+        Driver::Pss => PSS.read().get(index).map(|pss| pss.mhz),
+    }
+}
+
+/// The state last set on `cpu_id`
+pub fn state(cpu_id: usize) -> Option<usize> {
+    Some(CPU_STATE.get(cpu_id)?.state.load(Ordering::Relaxed)).filter(|&state| state != NONE)
+}
+
+/// Set state `index` on the current CPU
+pub unsafe fn set_state(index: usize) {
+    let state = match CPU_STATE.get(crate::cpu_id()) {
+        Some(state) => state,
+        None => return,
+    };
+    match driver_of(state) {
+        Driver::None => return,
+        Driver::Intel => {
+            let ratio = state.high.load(Ordering::Relaxed) - index as u32;
+            if ratio < state.low.load(Ordering::Relaxed) {
+                return;
+            }
+            msr::wrmsr(IA32_PERF_CTL, msr::rdmsr(IA32_PERF_CTL) & !0xFFFF | u64::from(ratio) << 8);
+        }
+        Driver::Amd => {
+            let pstate = match state.amd.get(index) {
+                Some(pstate) if index < state.high.load(Ordering::Relaxed) as usize => pstate.load(Ordering::Relaxed) >> 24,
+                _ => return,
+            };
+            msr::wrmsr(MSR_AMD_PSTATE_CTL, u64::from(pstate));
+        }
+        Driver::Pss => match PSS.read().get(index) {
+            Some(pss) => msr::wrmsr(IA32_PERF_CTL, pss.control),
+            None => return,
+        },
+    }
+    state.state.store(index, Ordering::Relaxed);
+}
+
+/// The states of the ACPI `_PSS` object, fastest first
+pub fn pss() -> Vec<PssState> {
+    PSS.read().clone()
+}
+
+/// Set the states of the ACPI `_PSS` object, which must be sorted fastest first. The states set
+/// on CPUs using them are forgotten, to be set again.
+pub fn set_pss(states: Vec<PssState>) -> bool {
+    if states.windows(2).any(|pair| pair[0].mhz < pair[1].mhz) {
+        return false;
+    }
+    *PSS.write() = states;
+    for state in CPU_STATE.iter().filter(|state| Driver::from_u8(state.driver.load(Ordering::Relaxed)) == Driver::Pss) {
+        state.state.store(NONE, Ordering::Relaxed);
+    }
+    true
+}
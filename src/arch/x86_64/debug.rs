@@ -1,12 +1,11 @@
 use core::fmt;
 #[cfg(feature = "qemu_debug")]
-use spin::Mutex;
-use spin::MutexGuard;
 
-use crate::log::{LOG, Log};
+use crate::log::{Kmsg, Line, FACILITY_KERNEL, KMSG, LEVEL_INFO};
 #[cfg(feature = "qemu_debug")]
 use syscall::io::Io;
 #[cfg(any(feature = "qemu_debug", feature = "serial_debug"))]
+use crate::sync::{Mutex, MutexGuard};
 use crate::syscall::io::Pio;
 #[cfg(feature = "lpss_debug")]
 use crate::syscall::io::Mmio;
@@ -15,6 +14,8 @@ use crate::devices::uart_16550::SerialPort;
 
 #[cfg(feature = "graphical_debug")]
 use crate::devices::graphical_debug::{DEBUG_DISPLAY, DebugDisplay};
+#[cfg(feature = "virtio_debug")]
+use crate::devices::virtio::console::{CONSOLE as VIRTIO_CONSOLE, Console as VirtioConsole};
 #[cfg(feature = "lpss_debug")]
 use super::device::serial::LPSS;
 #[cfg(feature = "serial_debug")]
@@ -26,9 +27,13 @@ use super::device::system76_ec::{SYSTEM76_EC, System76Ec};
 pub static QEMU: Mutex<Pio<u8>> = Mutex::new(Pio::<u8>::new(0x402));
 
 pub struct Writer<'a> {
-    log: MutexGuard<'a, Option<Log>>,
+    log: MutexGuard<'a, Kmsg>,
+    level: u8,
+    facility: u8,
     #[cfg(feature = "graphical_debug")]
     display: MutexGuard<'a, Option<DebugDisplay>>,
+    #[cfg(feature = "virtio_debug")]
+    virtio: MutexGuard<'a, Option<VirtioConsole>>,
     #[cfg(feature = "lpss_debug")]
     lpss: MutexGuard<'a, Option<&'static mut SerialPort<Mmio<u32>>>>,
     #[cfg(feature = "qemu_debug")]
@@ -41,10 +46,19 @@ pub struct Writer<'a> {
 
 impl<'a> Writer<'a> {
     pub fn new() -> Writer<'a> {
+        Self::with(LEVEL_INFO, FACILITY_KERNEL)
+    }
+
+    /// A writer of lines with the syslog `level` and `facility`
+    pub fn with(level: u8, facility: u8) -> Writer<'a> {
         Writer {
-            log: LOG.lock(),
+            log: KMSG.lock(),
+            level,
+            facility,
             #[cfg(feature = "graphical_debug")]
             display: DEBUG_DISPLAY.lock(),
+            #[cfg(feature = "virtio_debug")]
+            virtio: VIRTIO_CONSOLE.lock(),
             #[cfg(feature = "lpss_debug")]
             lpss: LPSS.lock(),
             #[cfg(feature = "qemu_debug")]
@@ -56,13 +70,16 @@ impl<'a> Writer<'a> {
         }
     }
 
+    /// Add the lines in `buf` to the log, and print those completed on the consoles
     pub fn write(&mut self, buf: &[u8]) {
-        {
-            if let Some(ref mut log) = *self.log {
-                log.write(buf);
-            }
+        self.log.write(self.level, self.facility, buf);
+        let mut line = Line::new();
+        while self.log.next_console(&mut line) {
+            self.write_console(line.as_bytes());
         }
+    }
 
+    fn write_console(&mut self, buf: &[u8]) {
         #[cfg(feature = "graphical_debug")]
         {
             if let Some(ref mut display) = *self.display {
@@ -70,6 +87,15 @@ impl<'a> Writer<'a> {
             }
         }
 
+        #[cfg(feature = "virtio_debug")]
+        {
+            if crate::log::ready_sinks().contains(crate::log::Sinks::VIRTIO) {
+                if let Some(ref mut console) = *self.virtio {
+                    console.write(buf);
+                }
+            }
+        }
+
         #[cfg(feature = "lpss_debug")]
         {
             if let Some(ref mut lpss) = *self.lpss {
@@ -79,14 +105,18 @@ impl<'a> Writer<'a> {
 
         #[cfg(feature = "qemu_debug")]
         {
-            for &b in buf {
-                self.qemu.write(b);
+            if crate::log::ready_sinks().contains(crate::log::Sinks::QEMU) {
+                for &b in buf {
+                    self.qemu.write(b);
+                }
             }
         }
 
         #[cfg(feature = "serial_debug")]
         {
-            self.serial.write(buf);
+            if crate::log::ready_sinks().contains(crate::log::Sinks::SERIAL) {
+                self.serial.write(buf);
+            }
         }
 
         #[cfg(feature = "system76_ec_debug")]
@@ -14,6 +14,7 @@ const GENERAL_INTERRUPT_OFFSET: usize = 0x20;
 pub(crate) const MAIN_COUNTER_OFFSET: usize = 0xF0;
 // const NUM_TIMER_CAP_MASK: u64 = 0x0f00;
 const LEG_RT_CAP: u64 = 0x8000;
+const COUNT_SIZE_CAP: u64 = 0x2000;
 const T0_CONFIG_CAPABILITY_OFFSET: usize = 0x100;
 pub(crate) const T0_COMPARATOR_OFFSET: usize = 0x108;
 
@@ -33,15 +34,16 @@ pub unsafe fn init(hpet: &mut Hpet) -> bool {
     let capability = hpet.base_address.read_u64(CAPABILITY_OFFSET);
     if capability & LEG_RT_CAP == 0 {
         log::warn!("HPET missing capability LEG_RT_CAP");
+        enable_counter(hpet);
         return false;
     }
 
-    let period_fs = capability >> 32;
-    let divisor = (pit::RATE as u64 * 1_000_000) / period_fs;
+    let divisor = (pit::RATE as u64 * 1_000_000) / period_fs(hpet);
 
     let t0_capabilities = hpet.base_address.read_u64(T0_CONFIG_CAPABILITY_OFFSET);
     if t0_capabilities & PER_INT_CAP == 0 {
         log::warn!("HPET T0 missing capability PER_INT_CAP");
+        enable_counter(hpet);
         return false;
     }
 
@@ -67,6 +69,39 @@ pub unsafe fn init(hpet: &mut Hpet) -> bool {
     true
 }
 
+/// Run the main counter without interrupts, for it to serve as a clocksource while the PIT is the
+/// system timer
+unsafe fn enable_counter(hpet: &mut Hpet) {
+    let config_word = hpet.base_address.read_u64(GENERAL_CONFIG_OFFSET);
+    hpet.base_address.write_u64(GENERAL_CONFIG_OFFSET, config_word | ENABLE_CNF);
+}
+
+/// Period of the main counter in femtoseconds
+pub unsafe fn period_fs(hpet: &Hpet) -> u64 {
+    // There seems to be a bug in qemu on macos that causes the calculation to produce 0 for
+    // period_fs and hence a divide by zero calculating the divisor - workaround it while we
+    // try and get a fix from qemu: https://gitlab.com/qemu-project/qemu/-/issues/1570
+    match hpet.base_address.read_u64(CAPABILITY_OFFSET) >> 32 {
+        0 => 10_000_000,
+        period_fs => period_fs,
+    }
+}
+
+/// Whether T0 is the system timer, in legacy replacement mode
+pub unsafe fn is_timer(hpet: &Hpet) -> bool {
+    hpet.base_address.read_u64(GENERAL_CONFIG_OFFSET) & LEG_RT_CNF == LEG_RT_CNF
+}
+
+/// Whether the main counter is 64 bits wide, so that it does not wrap around as a clocksource
+pub unsafe fn is_64bit(hpet: &Hpet) -> bool {
+    hpet.base_address.read_u64(CAPABILITY_OFFSET) & COUNT_SIZE_CAP == COUNT_SIZE_CAP
+}
+
+/// Nanoseconds counted by the main counter
+pub unsafe fn nanoseconds(hpet: &Hpet) -> u128 {
+    u128::from(hpet.base_address.read_u64(MAIN_COUNTER_OFFSET)) * u128::from(period_fs(hpet)) / 1_000_000
+}
+
 pub unsafe fn debug(hpet: &mut Hpet) {
     println!("HPET @ {:#x}", { hpet.base_address.address });
 
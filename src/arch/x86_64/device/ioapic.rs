@@ -1,7 +1,6 @@
 use core::{fmt, ptr};
 
 use alloc::vec::Vec;
-use spin::Mutex;
 
 #[cfg(feature = "acpi")]
 use crate::acpi::madt::{self, Madt, MadtEntry, MadtIoApic, MadtIntSrcOverride};
@@ -10,6 +9,7 @@ use crate::arch::interrupt::irq;
 use crate::memory::Frame;
 use crate::paging::{KernelMapper, Page, PageFlags, PhysicalAddress, RmmA, RmmArch};
 use crate::paging::entry::EntryFlags;
+use crate::sync::Mutex;
 
 use super::super::cpuid::cpuid;
 use super::pic;
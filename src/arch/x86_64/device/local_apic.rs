@@ -19,6 +19,11 @@ pub unsafe fn init_ap() {
     LOCAL_APIC.init_ap();
 }
 
+/// IA32_APIC_BASE bit enabling the local APIC
+const APIC_BASE_EN: u64 = 1 << 11;
+/// IA32_APIC_BASE bit enabling x2APIC mode, where registers are accessed through MSRs
+const APIC_BASE_EXTD: u64 = 1 << 10;
+
 /// Local APIC
 pub struct LocalApic {
     pub address: usize,
@@ -74,7 +79,8 @@ impl LocalApic {
 
     unsafe fn init_ap(&mut self) {
         if self.x2 {
-            wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | 1 << 10);
+            // x2APIC mode can only be entered from enabled xAPIC mode, so set both bits
+            wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | APIC_BASE_EN | APIC_BASE_EXTD);
             wrmsr(IA32_X2APIC_SIVR, 0x100);
         } else {
             self.write(0xF0, 0x100);
@@ -144,6 +150,11 @@ impl LocalApic {
         }
         self.set_icr(icr);
     }
+    /// Send the IPI `vector` to the CPU with the local APIC ID `apic_id`
+    pub fn ipi_vector(&mut self, apic_id: u32, vector: u8) {
+        let shift = if self.x2 { 32 } else { 56 };
+        self.set_icr((u64::from(apic_id) << shift) | (1 << 14) | u64::from(vector));
+    }
     // Not used just yet, but allows triggering an NMI to another processor.
     pub fn ipi_nmi(&mut self, apic_id: u32) {
         let shift = if self.x2 { 32 } else { 56 };
@@ -232,6 +243,13 @@ impl LocalApic {
             self.write(0x370, lvt_error);
         }
     }
+    pub unsafe fn set_lvt_pmi(&mut self, lvt_pmi: u32) {
+        if self.x2 {
+            wrmsr(IA32_X2APIC_LVT_PMI, u64::from(lvt_pmi));
+        } else {
+            self.write(0x340, lvt_pmi);
+        }
+    }
     unsafe fn setup_error_int(&mut self) {
         let vector = 49u32;
         self.set_lvt_error(vector);
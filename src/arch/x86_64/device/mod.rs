@@ -5,6 +5,8 @@ pub mod pic;
 pub mod pit;
 pub mod rtc;
 pub mod serial;
+pub mod tco;
+pub mod tsc;
 #[cfg(feature = "acpi")]
 pub mod hpet;
 #[cfg(feature = "system76_ec_debug")]
@@ -43,11 +45,46 @@ pub unsafe fn init_noncore() {
         pit::init();
         log::info!("PIT used as system timer");
     }
+    crate::arch::time::init();
+    tco::init();
 
     rtc::init();
     serial::init();
 }
 
+/// The PIC masks while suspended to RAM
+#[cfg(feature = "acpi")]
+static mut PIC_MASKS: (u8, u8) = (0, 0);
+
+/// Save what `resume` needs to restore the devices after suspend to RAM
+#[cfg(feature = "acpi")]
+pub unsafe fn suspend() {
+    PIC_MASKS = (pic::MASTER.mask(), pic::SLAVE.mask());
+    crate::time::suspend();
+    crate::watchdog::suspend();
+}
+
+/// Set up the devices of the BSP again after suspend to RAM, which lost their state
+#[cfg(feature = "acpi")]
+pub unsafe fn resume() {
+    pic::init();
+    pic::MASTER.set_mask(PIC_MASKS.0);
+    pic::SLAVE.set_mask(PIC_MASKS.1);
+
+    if !init_hpet() {
+        pit::init();
+    }
+    crate::time::resume();
+    crate::watchdog::resume();
+
+    // The monotonic clock does not count the time asleep, unlike the RTC
+    let now = rtc::Rtc::new().time() as u128 * crate::time::NANOS_PER_SEC;
+    *crate::time::START.lock() = now.saturating_sub(crate::time::monotonic());
+
+    serial::COM1.lock().init();
+    serial::COM2.lock().init();
+}
+
 pub unsafe fn init_ap() {
     local_apic::init_ap();
 }
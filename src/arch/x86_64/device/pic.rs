@@ -70,6 +70,15 @@ impl Pic {
         mask &= !(1 << irq);
         self.data.write(mask);
     }
+    /// The interrupt mask register
+    pub fn mask(&self) -> u8 {
+        self.data.read()
+    }
+
+    pub fn set_mask(&mut self, mask: u8) {
+        self.data.write(mask);
+    }
+
     /// A bitmap of all currently servicing IRQs. Spurious IRQs will not have this bit set
     pub fn isr(&mut self) -> u8 {
         self.cmd.write(0x0A);
@@ -0,0 +1,153 @@
+//! # Intel TCO watchdog
+//! The TCO timer of the ICH6 to 8 series chipsets, found through the LPC bridge at 00:1F.0. Its
+//! I/O registers follow the ACPI power management ones, and it only resets the system once the
+//! `NO_REBOOT` bit of the general control and status register, in the root complex register
+//! block, is cleared. Later chipsets, which moved it to the SMBus controller, are not supported.
+//!
+//! The timer counts down in steps of 0.6 s, and is restarted by writing the reload register.
+
+use core::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+
+use crate::memory::Frame;
+use crate::paging::{KernelMapper, Page, PageFlags, PhysicalAddress, VirtualAddress};
+use crate::syscall::io::{Io, Mmio, Pio};
+use crate::watchdog::{self, Driver};
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// The LPC bridge, 00:1F.0
+const LPC_DEVICE: u32 = 31;
+
+const INTEL: u32 = 0x8086;
+/// Class and subclass of an ISA bridge
+const CLASS_ISA_BRIDGE: u32 = 0x0601;
+
+// LPC configuration space
+const PMBASE: u8 = 0x40;
+const ACPI_CNTL: u8 = 0x44;
+const RCBA: u8 = 0xF0;
+
+const ACPI_CNTL_ACPI_EN: u32 = 1 << 7;
+const RCBA_EN: u32 = 1 << 0;
+
+/// General control and status, in the root complex register block
+const GCS: usize = 0x3410;
+const GCS_NO_REBOOT: u32 = 1 << 5;
+
+// From the ACPI power management base
+const SMI_EN: u16 = 0x30;
+const TCO_BASE: u16 = 0x60;
+
+const SMI_EN_TCO_EN: u32 = 1 << 13;
+
+// From the TCO base
+const TCO_RLD: u16 = 0x00;
+const TCO1_STS: u16 = 0x04;
+const TCO2_STS: u16 = 0x06;
+const TCO1_CNT: u16 = 0x08;
+const TCO_TMR: u16 = 0x12;
+
+const TCO1_STS_TIMEOUT: u16 = 1 << 3;
+const TCO2_STS_SECOND_TO: u16 = 1 << 1;
+const TCO2_STS_BOOT: u16 = 1 << 2;
+const TCO1_CNT_TMR_HLT: u16 = 1 << 11;
+
+/// Shortest and longest count of the timer, which ignores lower values
+const TMR_MIN: u16 = 0x04;
+const TMR_MAX: u16 = 0x3FF;
+
+static BASE: AtomicU16 = AtomicU16::new(0);
+/// Virtual address of the general control and status register
+static GCS_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
+fn config_read(offset: u8) -> u32 {
+    Pio::<u32>::new(CONFIG_ADDRESS).write(1 << 31 | LPC_DEVICE << 11 | u32::from(offset & 0xFC));
+    Pio::<u32>::new(CONFIG_DATA).read()
+}
+
+fn read(offset: u16) -> u16 {
+    Pio::<u16>::new(BASE.load(Ordering::Relaxed) + offset).read()
+}
+
+fn write(offset: u16, value: u16) {
+    Pio::<u16>::new(BASE.load(Ordering::Relaxed) + offset).write(value)
+}
+
+/// Allow the timer to reset the system, or prevent it. Returns false if it cannot be allowed.
+fn set_no_reboot(no_reboot: bool) -> bool {
+    let gcs = unsafe { &mut *(GCS_ADDRESS.load(Ordering::Relaxed) as *mut Mmio<u32>) };
+    gcs.writef(GCS_NO_REBOOT, no_reboot);
+    gcs.readf(GCS_NO_REBOOT) == no_reboot
+}
+
+fn start(timeout: u32) {
+    let ticks = (timeout * 10 / 6).clamp(u32::from(TMR_MIN), u32::from(TMR_MAX)) as u16;
+    if !set_no_reboot(false) {
+        log::warn!("TCO: reset prevented by the firmware");
+    }
+    write(TCO_TMR, read(TCO_TMR) & !TMR_MAX | ticks);
+    write(TCO_RLD, 1);
+    write(TCO1_CNT, read(TCO1_CNT) & !TCO1_CNT_TMR_HLT);
+}
+
+fn stop() {
+    write(TCO1_CNT, read(TCO1_CNT) | TCO1_CNT_TMR_HLT);
+    set_no_reboot(true);
+}
+
+fn ping() {
+    write(TCO_RLD, 1);
+}
+
+/// Map the page of the general control and status register, returning its virtual address
+unsafe fn map_gcs(rcba: usize) -> Option<usize> {
+    let address = rcba + GCS;
+    let mut mapper = KernelMapper::lock();
+    let frame = Frame::containing_address(PhysicalAddress::new(address));
+    let page = Page::containing_address(VirtualAddress::new(frame.start_address().data() + crate::PHYS_OFFSET));
+    // Fails if already mapped
+    if let Some(flush) = mapper.get_mut()?.map_phys(page.start_address(), frame.start_address(), PageFlags::new().write(true)) {
+        flush.flush();
+    }
+    Some(address + crate::PHYS_OFFSET)
+}
+
+/// Find the TCO timer, and use it as the hardware watchdog
+pub unsafe fn init() {
+    let id = config_read(0x00);
+    if id & 0xFFFF != INTEL || config_read(0x08) >> 16 != CLASS_ISA_BRIDGE {
+        return;
+    }
+    let pmbase = config_read(PMBASE) & 0xFF80;
+    let rcba = config_read(RCBA);
+    if pmbase == 0 || config_read(ACPI_CNTL) & ACPI_CNTL_ACPI_EN == 0 || rcba & RCBA_EN == 0 {
+        return;
+    }
+    let Some(gcs) = map_gcs((rcba & 0xFFFF_C000) as usize) else {
+        return;
+    };
+    GCS_ADDRESS.store(gcs, Ordering::Relaxed);
+    BASE.store(pmbase as u16 + TCO_BASE, Ordering::Relaxed);
+
+    // The firmware would otherwise handle the first timeout, and may restart the timer
+    let mut smi_en = Pio::<u32>::new(pmbase as u16 + SMI_EN);
+    smi_en.write(smi_en.read() & !SMI_EN_TCO_EN);
+
+    let caused_reset = read(TCO2_STS) & TCO2_STS_SECOND_TO != 0;
+    write(TCO1_STS, TCO1_STS_TIMEOUT);
+    write(TCO2_STS, TCO2_STS_SECOND_TO);
+    write(TCO2_STS, TCO2_STS_BOOT);
+    stop();
+
+    watchdog::register(
+        Driver {
+            name: "tco",
+            max_timeout: u32::from(TMR_MAX) * 6 / 10,
+            start,
+            stop,
+            ping,
+        },
+        caused_reset,
+    );
+}
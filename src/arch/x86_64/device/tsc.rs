@@ -0,0 +1,78 @@
+//! # Time stamp counter
+//! The TSC counts at a constant rate on CPUs with an invariant TSC, and is then the cheapest
+//! clocksource to read (see `time`). Its frequency is measured against the HPET, or read from
+//! CPUID on machines without one.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// How long the frequency is measured for, in nanoseconds
+const CALIBRATION_TIME: u128 = 20_000_000;
+
+// CPUID.80000007H:EDX bits
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+
+/// Frequency in kHz, or zero if the TSC is not used
+static KHZ: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the TSC counts at a constant rate, regardless of frequency scaling and idle states
+pub fn invariant() -> bool {
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & CPUID_INVARIANT_TSC != 0 }
+}
+
+/// The frequency from the TSC and crystal clock leaf of CPUID, or the base frequency leaf
+fn cpuid_khz() -> Option<u64> {
+    let max = unsafe { __cpuid(0).eax };
+    if max >= 0x15 {
+        let leaf = unsafe { __cpuid(0x15) };
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return Some(u64::from(leaf.ecx) * u64::from(leaf.ebx) / u64::from(leaf.eax) / 1000);
+        }
+    }
+    if max >= 0x16 {
+        let mhz = unsafe { __cpuid(0x16).eax } & 0xFFFF;
+        if mhz != 0 {
+            return Some(u64::from(mhz) * 1000);
+        }
+    }
+    None
+}
+
+/// Measure the frequency of the TSC against `reference`, which reads a clock in nanoseconds, or
+/// read it from CPUID without one. Returns false if the TSC cannot be used.
+pub unsafe fn init(reference: Option<fn() -> u128>) -> bool {
+    if !invariant() {
+        return false;
+    }
+
+    let khz = match reference {
+        Some(now) => {
+            let start = now();
+            let start_cycles = _rdtsc();
+            while now() - start < CALIBRATION_TIME {
+                core::hint::spin_loop();
+            }
+            let cycles = _rdtsc() - start_cycles;
+            (u128::from(cycles) * 1_000_000 / (now() - start)) as u64
+        }
+        None => match cpuid_khz() {
+            Some(khz) => khz,
+            None => return false,
+        },
+    };
+    log::info!("TSC: {}.{:03} MHz", khz / 1000, khz % 1000);
+
+    KHZ.store(khz, Ordering::Relaxed);
+    khz != 0
+}
+
+/// Frequency in kHz, if the TSC is used
+pub fn khz() -> Option<u64> {
+    Some(KHZ.load(Ordering::Relaxed)).filter(|&khz| khz != 0)
+}
+
+/// Nanoseconds counted by the TSC
+pub fn nanoseconds() -> u128 {
+    let khz = KHZ.load(Ordering::Relaxed).max(1);
+    u128::from(unsafe { _rdtsc() }) * 1_000_000 / u128::from(khz)
+}
@@ -12,6 +12,7 @@ use x86::segmentation::{self, Descriptor as SegmentDescriptor, SegmentSelector};
 use x86::task;
 
 use super::cpuid::cpuid;
+use super::tlb::Mailbox;
 
 pub const GDT_NULL: usize = 0;
 pub const GDT_KERNEL_CODE: usize = 1;
@@ -69,10 +70,22 @@ const BASE_GDT: [GdtEntry; 8] = [
     GdtEntry::new(0, 0, 0, 0),
 ];
 
+// Interrupt stack table indices, counting from 1 as in IDT entries. Double faults, NMIs and machine
+// checks can occur when the current kernel stack cannot be used, such as when it overflowed, or in
+// the middle of the syscall entry before the stack was switched. Each of them runs on a dedicated
+// per-CPU stack, so that one of them occurring while another is being handled does not clobber
+// its stack.
+pub const IST_DOUBLE_FAULT: u8 = 1;
+pub const IST_NMI: u8 = 2;
+pub const IST_MACHINE_CHECK: u8 = 3;
+
+/// Size of each interrupt stack table stack
+pub const IST_STACK_SIZE: usize = 65536;
+
 #[repr(C, align(4096))]
 pub struct ProcessorControlRegion {
-    // TODO: When both KASLR and KPTI are implemented, the PCR may need to be split into two pages,
-    // such that "secret" kernel addresses are only stored in the protected half.
+    // TODO: Once KASLR randomizes the kernel image as well, the PCR may need to be split into two
+    // pages with PTI, such that "secret" kernel addresses are only stored in the protected half.
 
     pub tcb_end: usize,
     pub user_rsp_tmp: usize,
@@ -82,8 +95,15 @@ pub struct ProcessorControlRegion {
     // The GDT *must* be stored in the PCR! The paranoid interrupt handler, lacking a reliable way
     // to correctly obtain GSBASE, uses SGDT to calculate the PCR offset.
     pub gdt: [GdtEntry; 8],
-    // TODO: Put mailbox queues here, e.g. for TLB shootdown? Just be sure to 128-byte align it
-    // first to avoid cache invalidation.
+    // With page-table isolation, the page tables to switch to when entering and leaving the
+    // kernel, and the kernel stack of the current context, as the TSS points to the entry stack
+    // (see `pti`).
+    pub pti_kernel_cr3: usize,
+    pub pti_user_cr3: usize,
+    pub pti_rsp0: usize,
+    // Aligned to 128 bytes, so that other CPUs posting requests do not invalidate the cache lines
+    // of the fields above.
+    pub tlb: Mailbox,
 }
 
 const _: () = {
@@ -104,11 +124,17 @@ pub unsafe fn pcr() -> *mut ProcessorControlRegion {
     ret
 }
 
+/// Offset of the kernel stack pointer of the current context in the PCR, loaded by the syscall
+/// entry
+#[cfg(feature = "pti")]
+pub const PCR_KERNEL_STACK: usize = memoffset::offset_of!(ProcessorControlRegion, pti_rsp0);
+#[cfg(not(feature = "pti"))]
+pub const PCR_KERNEL_STACK: usize = memoffset::offset_of!(ProcessorControlRegion, tss) + memoffset::offset_of!(TaskStateSegment, rsp);
+
 #[cfg(feature = "pti")]
 pub unsafe fn set_tss_stack(stack: usize) {
-    use super::pti::{PTI_CPU_STACK, PTI_CONTEXT_STACK};
-    core::ptr::addr_of_mut!((*pcr()).tss.rsp[0]).write((PTI_CPU_STACK.as_ptr() as usize + PTI_CPU_STACK.len()) as u64);
-    PTI_CONTEXT_STACK = stack;
+    // The TSS points to the entry stack, from which the entry code moves to this one
+    core::ptr::addr_of_mut!((*pcr()).pti_rsp0).write(stack);
 }
 
 #[cfg(not(feature = "pti"))]
@@ -154,6 +180,8 @@ pub unsafe fn init_paging(stack_offset: usize) {
     // Setup the GDT.
     pcr.gdt = BASE_GDT;
 
+    pcr.tlb = Mailbox::new();
+
     let limit = (pcr.gdt.len() * mem::size_of::<GdtEntry>() - 1)
         .try_into()
         .expect("main GDT way too large");
@@ -169,6 +197,11 @@ pub unsafe fn init_paging(stack_offset: usize) {
     {
         pcr.tss.iomap_base = 0xFFFF;
 
+        // Allocated before `pti::init`, which maps them into the entry area
+        for index in [IST_DOUBLE_FAULT, IST_NMI, IST_MACHINE_CHECK] {
+            pcr.tss.ist[usize::from(index - 1)] = alloc_ist_stack() as u64;
+        }
+
         let tss = &mut pcr.tss as *mut TaskStateSegment as usize as u64;
         let tss_lo = (tss & 0xFFFF_FFFF) as u32;
         let tss_hi = (tss >> 32) as u32;
@@ -196,6 +229,10 @@ pub unsafe fn init_paging(stack_offset: usize) {
     // Set the userspace FSBASE to zero.
     x86::msr::wrmsr(x86::msr::IA32_FS_BASE, 0);
 
+    // Map the entry area, and point the TSS to the entry stack.
+    #[cfg(feature = "pti")]
+    super::pti::init(pcr);
+
     // Set the stack pointer to use when coming back from userspace.
     set_tss_stack(stack_offset);
 
@@ -215,6 +252,26 @@ pub unsafe fn init_paging(stack_offset: usize) {
     }
 }
 
+/// Load the task register again, after the GDT was restored with the TSS descriptor still marked
+/// busy, as when resuming from suspend to RAM
+pub unsafe fn reload_tss() {
+    let pcr = &mut *pcr();
+    pcr.gdt[GDT_TSS].access = GDT_A_PRESENT | GDT_A_RING_3 | GDT_A_TSS_AVAIL;
+    task::load_tr(SegmentSelector::new(GDT_TSS as u16, Ring::Ring0));
+}
+
+/// Allocate an interrupt stack table stack, returning its top, as stacks grow downwards
+#[cold]
+unsafe fn alloc_ist_stack() -> usize {
+    const _: () = assert!(IST_STACK_SIZE % PAGE_SIZE == 0);
+
+    let frames = crate::memory::allocate_frames(IST_STACK_SIZE / PAGE_SIZE)
+        .expect("failed to allocate pages for interrupt stack table stack");
+
+    // Physical pages are mapped linearly. So is the linearly mapped virtual memory.
+    RmmA::phys_to_virt(frames.start_address()).data() + IST_STACK_SIZE
+}
+
 /// Copy tdata, clear tbss, calculate TCB end pointer
 #[cold]
 unsafe fn init_percpu() -> usize {
@@ -0,0 +1,107 @@
+//! # Idle states
+//! `hlt`, or the C-states entered with MWAIT on Intel CPUs that enumerate them in CPUID leaf 5.
+//! MWAIT is used with interrupts enabled right before it, like `hlt`, so that an interrupt pending
+//! since they were disabled is taken instead of waited for. Their exit latencies and target
+//! residencies are not reported by the CPU, and are those of recent Intel Core CPUs, which are
+//! conservative for older ones.
+
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid_count;
+
+use spin::Once;
+
+use crate::idle::{State, MAX_STATES};
+use crate::interrupt;
+
+// CPUID.0:EBX
+const VENDOR_INTEL: u32 = 0x756E_6547; // "Genu"
+
+// CPUID.1:ECX and CPUID.5:ECX bits
+const CPUID_MONITOR: u32 = 1 << 3;
+const CPUID_MWAIT_EXTENSIONS: u32 = 1 << 0;
+
+/// Number of CPUs with their own line to monitor
+const CPUS: usize = 32;
+
+static HLT: [State; 1] = [State { name: "hlt", exit_latency: 1, target_residency: 1 }];
+
+/// Names, exit latencies and target residencies of the MWAIT C-states from C1, with MWAIT hints
+/// of zero, 0x10 and so on
+const MWAIT_STATES: [State; 7] = [
+    State { name: "C1", exit_latency: 2, target_residency: 2 },
+    State { name: "C3", exit_latency: 70, target_residency: 100 },
+    State { name: "C6", exit_latency: 85, target_residency: 200 },
+    State { name: "C7", exit_latency: 124, target_residency: 800 },
+    State { name: "C8", exit_latency: 200, target_residency: 800 },
+    State { name: "C9", exit_latency: 480, target_residency: 5000 },
+    State { name: "C10", exit_latency: 890, target_residency: 5000 },
+];
+/// The first sub-state of C1, which is the only one with a name
+const C1E: State = State { name: "C1E", exit_latency: 10, target_residency: 20 };
+
+struct Mwait {
+    states: Vec<State>,
+    /// MWAIT hint of each state
+    hints: Vec<u32>,
+}
+
+static MWAIT: Once<Mwait> = Once::new();
+
+/// The line each CPU monitors, which nothing writes to, as CPUs are woken by interrupts
+#[repr(align(64))]
+struct Line(u64);
+
+static LINES: [Line; CPUS] = {
+    const LINE: Line = Line(0);
+    [LINE; CPUS]
+};
+
+/// Detect the MWAIT C-states. Called on the BSP while starting, the APs having the same.
+pub unsafe fn init() {
+    if __cpuid_count(0, 0).ebx != VENDOR_INTEL || __cpuid_count(0, 0).eax < 5 || __cpuid_count(1, 0).ecx & CPUID_MONITOR == 0 {
+        return;
+    }
+    let leaf = __cpuid_count(5, 0);
+    if leaf.ecx & CPUID_MWAIT_EXTENSIONS == 0 {
+        return;
+    }
+
+    // EDX has the number of sub-states of C0 to C7 in MWAIT numbering, four bits each
+    let mut states = Vec::new();
+    let mut hints = Vec::new();
+    for (i, state) in MWAIT_STATES.iter().enumerate() {
+        let substates = (leaf.edx >> (4 * (i + 1))) & 0xF;
+        if substates == 0 || states.len() >= MAX_STATES {
+            continue;
+        }
+        states.push(*state);
+        hints.push((i as u32) << 4);
+        if i == 0 && substates > 1 {
+            states.push(C1E);
+            hints.push(1);
+        }
+    }
+    if !states.is_empty() {
+        log::info!("Idle states: {}", states.iter().map(|state| state.name).collect::<Vec<_>>().join(" "));
+        MWAIT.call_once(|| Mwait { states, hints });
+    }
+}
+
+/// The idle states, shallowest first
+pub fn states() -> &'static [State] {
+    match MWAIT.get() {
+        Some(mwait) => &mwait.states,
+        None => &HLT,
+    }
+}
+
+/// Enable interrupts and wait for the next one in state `index`
+pub unsafe fn enter(index: usize) {
+    let hint = match MWAIT.get().and_then(|mwait| mwait.hints.get(index)) {
+        Some(&hint) => hint,
+        None => return interrupt::enable_and_halt(),
+    };
+    let line = &LINES[crate::cpu_id() % CPUS] as *const Line;
+    core::arch::asm!("monitor", in("rax") line, in("ecx") 0, in("edx") 0, options(nostack));
+    core::arch::asm!("sti; mwait", in("eax") hint, in("ecx") 0, options(nomem, nostack));
+}
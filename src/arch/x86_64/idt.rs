@@ -10,8 +10,7 @@ use x86::dtables::{self, DescriptorTablePointer};
 
 use crate::interrupt::*;
 use crate::ipi::IpiKind;
-
-use spin::RwLock;
+use crate::sync::RwLock;
 
 pub static mut INIT_IDTR: DescriptorTablePointer<X86IdtEntry> = DescriptorTablePointer {
     limit: 0,
@@ -21,7 +20,8 @@ pub static mut INIT_IDTR: DescriptorTablePointer<X86IdtEntry> = DescriptorTableP
 pub type IdtEntries = [IdtEntry; 256];
 pub type IdtReservations = [AtomicU64; 4];
 
-#[repr(C)]
+// Page aligned, so that no other data shares its pages when mapped into the PTI entry area
+#[repr(C, align(4096))]
 pub struct Idt {
     entries: IdtEntries,
     reservations: IdtReservations,
@@ -131,6 +131,8 @@ pub unsafe fn init_paging_post_heap(is_bsp: bool, cpu_id: usize) {
         idts_btree.insert(cpu_id, &mut INIT_BSP_IDT);
     } else {
         let idt = idts_btree.entry(cpu_id).or_insert_with(|| Box::leak(Box::new(Idt::new())));
+        #[cfg(feature = "pti")]
+        super::pti::map_idt(&**idt);
         init_generic(is_bsp, idt);
     }
 }
@@ -138,6 +140,8 @@ pub unsafe fn init_paging_post_heap(is_bsp: bool, cpu_id: usize) {
 /// Initializes a fully functional IDT for use before it be moved into the map. This is ONLY called
 /// on the BSP, since the kernel heap is ready for the APs.
 pub unsafe fn init_paging_bsp() {
+    #[cfg(feature = "pti")]
+    super::pti::map_idt(&INIT_BSP_IDT);
     init_generic(true, &mut INIT_BSP_IDT);
 }
 
@@ -150,42 +154,11 @@ pub unsafe fn init_generic(is_bsp: bool, idt: &mut Idt) {
         base: current_idt.as_ptr() as *const X86IdtEntry,
     };
 
-    let backup_ist = {
-        // We give Non-Maskable Interrupts, Double Fault, and Machine Check exceptions separate
-        // stacks, since these (unless we are going to set up NMI watchdogs like Linux does) are
-        // considered the most fatal, especially Double Faults which are caused by errors __when
-        // accessing the system IDT__. If that goes wrong, then kernel memory may be partially
-        // corrupt, and we want a separate stack.
-        //
-        // Note that each CPU has its own "backup interrupt stack".
-        let index = 1_u8;
-
-        // Allocate 64 KiB of stack space for the backup stack.
-        const BACKUP_STACK_SIZE: usize = 65536;
-        assert_eq!(BACKUP_STACK_SIZE % crate::memory::PAGE_SIZE, 0);
-        let page_count = BACKUP_STACK_SIZE / crate::memory::PAGE_SIZE;
-        let frames = crate::memory::allocate_frames(page_count)
-            .expect("failed to allocate pages for backup interrupt stack");
-
-        use crate::paging::{RmmA, RmmArch};
-
-        // Physical pages are mapped linearly. So is the linearly mapped virtual memory.
-        let base_address = RmmA::phys_to_virt(frames.start_address());
-
-        // Stack always grows downwards.
-        let address = base_address.data() + BACKUP_STACK_SIZE;
-
-        // Put them in the 1st entry of the IST.
-        (*crate::gdt::pcr()).tss.ist[usize::from(index - 1)] = address as u64;
-
-        index
-    };
-
     // Set up exceptions
     current_idt[0].set_func(exception::divide_by_zero);
     current_idt[1].set_func(exception::debug);
     current_idt[2].set_func(exception::non_maskable);
-    current_idt[2].set_ist(backup_ist);
+    current_idt[2].set_ist(crate::gdt::IST_NMI);
     current_idt[3].set_func(exception::breakpoint);
     current_idt[3].set_flags(IdtFlags::PRESENT | IdtFlags::RING_3 | IdtFlags::INTERRUPT);
     current_idt[4].set_func(exception::overflow);
@@ -193,7 +166,7 @@ pub unsafe fn init_generic(is_bsp: bool, idt: &mut Idt) {
     current_idt[6].set_func(exception::invalid_opcode);
     current_idt[7].set_func(exception::device_not_available);
     current_idt[8].set_func(exception::double_fault);
-    current_idt[8].set_ist(backup_ist);
+    current_idt[8].set_ist(crate::gdt::IST_DOUBLE_FAULT);
     // 9 no longer available
     current_idt[10].set_func(exception::invalid_tss);
     current_idt[11].set_func(exception::segment_not_present);
@@ -204,7 +177,7 @@ pub unsafe fn init_generic(is_bsp: bool, idt: &mut Idt) {
     current_idt[16].set_func(exception::fpu_fault);
     current_idt[17].set_func(exception::alignment_check);
     current_idt[18].set_func(exception::machine_check);
-    current_idt[18].set_ist(backup_ist);
+    current_idt[18].set_ist(crate::gdt::IST_MACHINE_CHECK);
     current_idt[19].set_func(exception::simd);
     current_idt[20].set_func(exception::virtualization);
     // 21 through 29 reserved
@@ -253,10 +226,12 @@ pub unsafe fn init_generic(is_bsp: bool, idt: &mut Idt) {
     current_idt[IpiKind::Switch as usize].set_func(ipi::switch);
     current_idt[IpiKind::Tlb as usize].set_func(ipi::tlb);
     current_idt[IpiKind::Pit as usize].set_func(ipi::pit);
+    current_idt[IpiKind::TlbShootdown as usize].set_func(ipi::tlb_shootdown);
     idt.set_reserved_mut(IpiKind::Wakeup as u8, true);
     idt.set_reserved_mut(IpiKind::Switch as u8, true);
     idt.set_reserved_mut(IpiKind::Tlb as u8, true);
     idt.set_reserved_mut(IpiKind::Pit as u8, true);
+    idt.set_reserved_mut(IpiKind::TlbShootdown as u8, true);
     let current_idt = &mut idt.entries;
 
     // Set syscall function
@@ -2,9 +2,12 @@ use rmm::TableKind;
 use x86::irq::PageFaultError;
 
 use crate::{
+    arch::mce,
+    context::kstack,
     interrupt::stack_trace,
     paging::VirtualAddress,
     ptrace,
+    syscall::abi::{BUS_MCEERR_AR, SEGV_ACCERR, SEGV_MAPERR},
     syscall::flag::*,
 
     interrupt_stack,
@@ -13,6 +16,7 @@ use crate::{
 
 extern {
     fn ksignal(signal: usize);
+    fn ksignal_fault(signal: usize, code: i32, addr: usize);
 }
 
 interrupt_stack!(divide_by_zero, |stack| {
@@ -23,6 +27,13 @@ interrupt_stack!(divide_by_zero, |stack| {
 });
 
 interrupt_stack!(debug, @paranoid, |stack| {
+    if crate::debugger::kprobe::debug_trap(stack) {
+        return;
+    }
+    if crate::debugger::gdbstub::debug_trap(stack) {
+        return;
+    }
+
     let mut handled = false;
 
     // Disable singlestep before there is a breakpoint, since the breakpoint
@@ -46,6 +57,14 @@ interrupt_stack!(debug, @paranoid, |stack| {
 });
 
 interrupt_stack!(non_maskable, @paranoid, |stack| {
+    crate::arch::kexec::nmi();
+    // The NMI may also be from the performance counters, of the watchdog or of perf
+    let stopped = crate::debugger::gdbstub::nmi(stack);
+    let sampled = crate::perf::nmi(stack.iret.rip, stack.iret.cs & 0b11 == 0b11);
+    crate::profile::nmi(stack.iret.rip, stack.preserved.rbp, stack.iret.cs & 0b11 == 0b11, stack.iret.rflags & 1 << 9 != 0);
+    if crate::arch::nmi::handle(stack) || stopped || sampled {
+        return;
+    }
     println!("Non-maskable interrupt");
     stack.dump();
 });
@@ -64,6 +83,13 @@ interrupt_stack!(breakpoint, |stack| {
     // int3 instruction. After all, it's the sanest thing to do.
     stack.iret.rip -= 1;
 
+    if crate::debugger::kprobe::breakpoint_trap(stack) {
+        return;
+    }
+    if crate::debugger::gdbstub::breakpoint_trap(stack) {
+        return;
+    }
+
     if ptrace::breakpoint_callback(PTRACE_STOP_BREAKPOINT, None).is_none() {
         println!("Breakpoint trap");
         stack.dump();
@@ -93,6 +119,11 @@ interrupt_stack!(invalid_opcode, |stack| {
 });
 
 interrupt_stack!(device_not_available, |stack| {
+    // First use of a lazily allocated extended register component
+    if crate::arch::x86_64::xsave::handle_xfd_fault() {
+        return;
+    }
+
     println!("Device not available fault");
     stack.dump();
     stack_trace();
@@ -100,6 +131,16 @@ interrupt_stack!(device_not_available, |stack| {
 });
 
 interrupt_error!(double_fault, |stack| {
+    // Runs on its own stack, so that an overflowing kernel stack ends up here instead of triple
+    // faulting
+    let cr2 = unsafe { x86::controlregs::cr2() };
+    if kstack::guard_hit(cr2) || kstack::guard_hit(stack.inner.iret.rsp) {
+        println!("Kernel stack overflow: {:>016X}", cr2);
+        stack.dump();
+        stack_trace();
+        panic!("kernel stack overflow");
+    }
+
     println!("Double fault");
     stack.dump();
     stack_trace();
@@ -137,6 +178,7 @@ interrupt_error!(protection, |stack| {
 interrupt_error!(page, |stack| {
     let cr2 = unsafe { x86::controlregs::cr2() };
     let flags = PageFaultError::from_bits_truncate(stack.code as u32);
+    crate::tracepoint!(PageFault, cr2, stack.code);
 
     extern "C" {
         static __usercopy_start: u8;
@@ -151,7 +193,13 @@ interrupt_error!(page, |stack| {
     let caused_by_user = flags.contains(PageFaultError::US);
     let caused_by_instr_fetch = flags.contains(PageFaultError::ID);
 
-    if address_is_user && !caused_by_user && !caused_by_instr_fetch && !invalid_page_tables && usercopy_region.contains(&{ stack.inner.iret.rip }) {
+    // Lazily allocated memory is backed by a frame on first access, by userspace or usercopy
+    let in_usercopy = usercopy_region.contains(&{ stack.inner.iret.rip });
+    if address_is_user && !flags.contains(PageFaultError::P) && !invalid_page_tables && (caused_by_user || in_usercopy) && crate::context::memory::try_demand_page(VirtualAddress::new(cr2), caused_by_user) {
+        return;
+    }
+
+    if address_is_user && !caused_by_user && !caused_by_instr_fetch && !invalid_page_tables && in_usercopy {
         // We were inside a usercopy function that failed. This is handled by setting rax to a
         // nonzero value, and emulating the ret instruction.
         stack.inner.scratch.rax = 1;
@@ -162,6 +210,13 @@ interrupt_error!(page, |stack| {
         return;
     }
 
+    if !caused_by_user && kstack::guard_hit(cr2) {
+        println!("Kernel stack overflow: {:>016X}", cr2);
+        stack.dump();
+        stack_trace();
+        panic!("kernel stack overflow");
+    }
+
     println!("Page fault: {:>016X}", cr2);
     println!("  Present: {}", flags.contains(PageFaultError::P));
     println!("  Write: {}", flags.contains(PageFaultError::WR));
@@ -170,7 +225,8 @@ interrupt_error!(page, |stack| {
     println!("  Instruction fetch: {}", flags.contains(PageFaultError::ID));
     stack.dump();
     stack_trace();
-    ksignal(SIGSEGV);
+    let code = if flags.contains(PageFaultError::P) { SEGV_ACCERR } else { SEGV_MAPERR };
+    ksignal_fault(SIGSEGV, code, cr2);
 });
 
 interrupt_stack!(fpu_fault, |stack| {
@@ -188,10 +244,23 @@ interrupt_error!(alignment_check, |stack| {
 });
 
 interrupt_stack!(machine_check, @paranoid, |stack| {
-    println!("Machine check fault");
-    stack.dump();
-    stack_trace();
-    ksignal(SIGBUS);
+    match mce::handle(stack.iret.cs & 0b11 == 0b11) {
+        mce::Action::Resume => (),
+        mce::Action::Signal(addr) => {
+            println!("Machine check fault, sending SIGBUS");
+            ksignal_fault(SIGBUS, BUS_MCEERR_AR, addr.unwrap_or(0) as usize);
+        }
+        mce::Action::Kill => {
+            println!("Machine check fault, killing context");
+            ksignal(SIGKILL);
+        }
+        mce::Action::Panic => {
+            println!("Machine check fault");
+            stack.dump();
+            stack_trace();
+            panic!("unrecoverable machine check");
+        }
+    }
 });
 
 interrupt_stack!(simd, |stack| {
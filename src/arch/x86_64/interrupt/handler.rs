@@ -259,25 +259,127 @@ macro_rules! pop_preserved {
 }
 macro_rules! swapgs_iff_ring3_fast {
     // TODO: Spectre V1: LFENCE?
+    () => { enter_iff_ring3!(8, 5) };
+}
+macro_rules! swapgs_iff_ring3_fast_errorcode {
+    // TODO: Spectre V1: LFENCE?
+    () => { enter_iff_ring3!(16, 6) };
+}
+
+// With page-table isolation, interrupts from userspace arrive on the entry stack, running on the
+// user PML4 (see `pti`). After SWAPGS, switch to the kernel PML4, and move the interrupt frame,
+// of $frame quadwords including the error code if any, to the kernel stack of the context.
+#[cfg(feature = "pti")]
+macro_rules! enter_iff_ring3 {
+    ($cs:literal, $frame:literal) => { concat!("
+        test QWORD PTR [rsp + ", $cs, "], 0x3
+        jz 7f
+        swapgs
+        push rax
+        push rcx
+        mov rax, gs:[{PTI_KERNEL_CR3}]
+        mov cr3, rax
+        mov rax, gs:[{PTI_RSP0}]
+        sub rax, (", $frame, " + 2) * 8
+        .set pti_offset, 0
+        .rept ", $frame, " + 2
+        mov rcx, [rsp + pti_offset]
+        mov [rax + pti_offset], rcx
+        .set pti_offset, pti_offset + 8
+        .endr
+        mov rsp, rax
+        pop rcx
+        pop rax
+        7:
+    ") };
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! enter_iff_ring3 {
+    ($cs:literal, $frame:literal) => { concat!("
+        // Unused: {PTI_KERNEL_CR3} {PTI_RSP0}
+        test QWORD PTR [rsp + ", $cs, "], 0x3
+        jz 7f
+        swapgs
+        7:
+    ") };
+}
+
+// Return to userspace from the interrupt frame at RSP, with all other registers restored. With
+// page-table isolation, the frame is moved to the entry stack first, as the kernel stack is not
+// mapped in the user PML4.
+#[cfg(feature = "pti")]
+macro_rules! return_to_user {
     () => { "
-        // Check whether the last two bits RSP+8 (code segment) are equal to zero.
-        test QWORD PTR [rsp + 8], 0x3
-        // Skip the SWAPGS instruction if CS & 0b11 == 0b00.
-        jz 1f
+        push rcx
+        push rax
+        mov rax, gs:[{PTI_ENTRY_STACK}]
+        sub rax, 7 * 8
+        .set pti_offset, 0
+        .rept 7
+        mov rcx, [rsp + pti_offset]
+        mov [rax + pti_offset], rcx
+        .set pti_offset, pti_offset + 8
+        .endr
+        mov rsp, rax
+        mov rax, gs:[{PTI_USER_CR3}]
+        mov cr3, rax
+        pop rax
+        pop rcx
         swapgs
-        1:
+        iretq
     " };
 }
-macro_rules! swapgs_iff_ring3_fast_errorcode {
-    // TODO: Spectre V1: LFENCE?
+#[cfg(not(feature = "pti"))]
+macro_rules! return_to_user {
     () => { "
-        test QWORD PTR [rsp + 16], 0x3
-        jz 1f
+        // Unused: {PTI_ENTRY_STACK} {PTI_USER_CR3}
         swapgs
-        1:
+        iretq
     " };
 }
 
+macro_rules! exit_iff_ring3 {
+    () => { concat!("
+        test QWORD PTR [rsp + 8], 0x3
+        jz 8f
+        ", return_to_user!(), "
+        8:
+        iretq
+    ") };
+}
+
+// Paranoid interrupts can arrive while the kernel runs on the user PML4, in the entry code. Keep
+// the previous CR3 in R12, which is preserved by the handler, and switch to the kernel PML4.
+#[cfg(feature = "pti")]
+macro_rules! save_cr3_paranoid {
+    () => { "
+        mov r12, cr3
+        mov rax, gs:[{PTI_KERNEL_CR3}]
+        cmp r12, rax
+        je 3f
+        mov cr3, rax
+        3:
+    " };
+}
+#[cfg(feature = "pti")]
+macro_rules! restore_cr3_paranoid {
+    () => { "
+        mov rax, cr3
+        cmp rax, r12
+        je 3f
+        mov cr3, r12
+        3:
+    " };
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! save_cr3_paranoid {
+    () => { "" };
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! restore_cr3_paranoid {
+    () => { "" };
+}
+
 #[cfg(feature = "x86_fsbase")]
 macro_rules! read_gsbase_into_rdx {
     () => { "rdgsbase rdx;" }
@@ -361,6 +463,32 @@ macro_rules! nop {
         // Unused: {IA32_GS_BASE} {PCR_GDT_OFFSET}
         " }
 }
+macro_rules! paranoid_save {
+    () => { concat!(conditional_swapgs_paranoid!(), save_cr3_paranoid!()) }
+}
+macro_rules! paranoid_restore {
+    () => { concat!(restore_cr3_paranoid!(), conditional_swapgs_back_paranoid!()) }
+}
+// With page-table isolation, paranoid interrupts from userspace move to the kernel stack like
+// regular ones, as they may context switch.
+#[cfg(feature = "pti")]
+macro_rules! paranoid_enter {
+    () => { enter_iff_ring3!(8, 5) }
+}
+#[cfg(feature = "pti")]
+macro_rules! paranoid_exit {
+    () => { exit_iff_ring3!() }
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! paranoid_enter {
+    () => { "
+        // Unused: {PTI_KERNEL_CR3} {PTI_RSP0} {PTI_ENTRY_STACK} {PTI_USER_CR3}
+        " }
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! paranoid_exit {
+    () => { "iretq\n" }
+}
 
 #[macro_export]
 macro_rules! interrupt_stack {
@@ -368,8 +496,16 @@ macro_rules! interrupt_stack {
     // use idents directly instead.
     ($name:ident, $save1:ident!, $save2:ident!, $rstor2:ident!, $rstor1:ident!, is_paranoid: $is_paranoid:expr, |$stack:ident| $code:block) => {
         #[naked]
+        #[link_section = ".entry-text"]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner($stack: &mut $crate::arch::x86_64::interrupt::InterruptStack) {
+                // The interrupted code may have been a usercopy function, with user accesses
+                // allowed. RFLAGS.AC is restored by IRETQ.
+                $crate::arch::x86_64::misc::clac();
+
+                // Updates the user page table when returning, see `pti`
+                let _pti = $crate::arch::x86_64::pti::SyncGuard;
+
                 let _guard;
 
                 if !$is_paranoid {
@@ -401,48 +537,52 @@ macro_rules! interrupt_stack {
 
                 $save2!(),
 
-                // TODO: Map PTI
-                // $crate::arch::x86_64::pti::map();
-
                 // Call inner function with pointer to stack
                 "
                 mov rdi, rsp
                 call {inner}
                 ",
 
-                // TODO: Unmap PTI
-                // $crate::arch::x86_64::pti::unmap();
-
                 $rstor2!(),
 
                 // Restore all userspace registers
                 pop_preserved!(),
                 pop_scratch!(),
 
+                // Swap back GSBASE and return, to userspace through the entry stack with
+                // page-table isolation
                 $rstor1!(),
-                "iretq\n",
             ),
 
             inner = sym inner,
             IA32_GS_BASE = const(x86::msr::IA32_GS_BASE),
 
             PCR_GDT_OFFSET = const(memoffset::offset_of!(crate::gdt::ProcessorControlRegion, gdt)),
+            PTI_KERNEL_CR3 = const(memoffset::offset_of!(crate::gdt::ProcessorControlRegion, pti_kernel_cr3)),
+            PTI_USER_CR3 = const(memoffset::offset_of!(crate::gdt::ProcessorControlRegion, pti_user_cr3)),
+            PTI_RSP0 = const(memoffset::offset_of!(crate::gdt::ProcessorControlRegion, pti_rsp0)),
+            PTI_ENTRY_STACK = const(memoffset::offset_of!(crate::gdt::ProcessorControlRegion, tss) + memoffset::offset_of!(x86::bits64::task::TaskStateSegment, rsp)),
 
             options(noreturn),
 
             );
         }
     };
-    ($name:ident, |$stack:ident| $code:block) => { interrupt_stack!($name, swapgs_iff_ring3_fast!, nop!, nop!, swapgs_iff_ring3_fast!, is_paranoid: false, |$stack| $code); };
-    ($name:ident, @paranoid, |$stack:ident| $code:block) => { interrupt_stack!($name, nop!, conditional_swapgs_paranoid!, conditional_swapgs_back_paranoid!, nop!, is_paranoid: true, |$stack| $code); }
+    ($name:ident, |$stack:ident| $code:block) => { interrupt_stack!($name, swapgs_iff_ring3_fast!, nop!, nop!, exit_iff_ring3!, is_paranoid: false, |$stack| $code); };
+    ($name:ident, @paranoid, |$stack:ident| $code:block) => { interrupt_stack!($name, paranoid_enter!, paranoid_save!, paranoid_restore!, paranoid_exit!, is_paranoid: true, |$stack| $code); }
 }
 
 #[macro_export]
 macro_rules! interrupt {
     ($name:ident, || $code:block) => {
         #[naked]
+        #[link_section = ".entry-text"]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner() {
+                // See interrupt_stack!
+                $crate::arch::x86_64::misc::clac();
+                let _pti = $crate::arch::x86_64::pti::SyncGuard;
+
                 $code
             }
 
@@ -455,23 +595,20 @@ macro_rules! interrupt {
                 "push rax\n",
                 push_scratch!(),
 
-                // TODO: Map PTI
-                // $crate::arch::x86_64::pti::map();
-
                 // Call inner function with pointer to stack
                 "call {inner}\n",
 
-                // TODO: Unmap PTI
-                // $crate::arch::x86_64::pti::unmap();
-
                 // Restore all userspace registers
                 pop_scratch!(),
 
-                swapgs_iff_ring3_fast!(),
-                "iretq\n",
+                exit_iff_ring3!(),
             ),
 
             inner = sym inner,
+            PTI_KERNEL_CR3 = const(memoffset::offset_of!(crate::gdt::ProcessorControlRegion, pti_kernel_cr3)),
+            PTI_USER_CR3 = const(memoffset::offset_of!(crate::gdt::ProcessorControlRegion, pti_user_cr3)),
+            PTI_RSP0 = const(memoffset::offset_of!(crate::gdt::ProcessorControlRegion, pti_rsp0)),
+            PTI_ENTRY_STACK = const(memoffset::offset_of!(crate::gdt::ProcessorControlRegion, tss) + memoffset::offset_of!(x86::bits64::task::TaskStateSegment, rsp)),
 
             options(noreturn),
             );
@@ -483,8 +620,13 @@ macro_rules! interrupt {
 macro_rules! interrupt_error {
     ($name:ident, |$stack:ident| $code:block) => {
         #[naked]
+        #[link_section = ".entry-text"]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner($stack: &mut $crate::arch::x86_64::interrupt::handler::InterruptErrorStack) {
+                // See interrupt_stack!
+                $crate::arch::x86_64::misc::clac();
+                let _pti = $crate::arch::x86_64::pti::SyncGuard;
+
                 let _guard;
 
                 // Only set_ptrace_process_regs if this error occured from userspace. If this fault
@@ -520,18 +662,12 @@ macro_rules! interrupt_error {
                 // Put code in, it's now in rax
                 "push rax\n",
 
-                // TODO: Map PTI
-                // $crate::arch::x86_64::pti::map();
-
                 // Call inner function with pointer to stack
                 "
                 mov rdi, rsp
                 call {inner}
                 ",
 
-                // TODO: Unmap PTI
-                // $crate::arch::x86_64::pti::unmap();
-
                 // Pop code
                 "add rsp, 8\n",
 
@@ -540,11 +676,14 @@ macro_rules! interrupt_error {
                 pop_scratch!(),
 
                 // The error code has already been popped, so use the regular macro.
-                swapgs_iff_ring3_fast!(),
-                "iretq\n",
+                exit_iff_ring3!(),
             ),
 
             inner = sym inner,
+            PTI_KERNEL_CR3 = const(memoffset::offset_of!(crate::gdt::ProcessorControlRegion, pti_kernel_cr3)),
+            PTI_USER_CR3 = const(memoffset::offset_of!(crate::gdt::ProcessorControlRegion, pti_user_cr3)),
+            PTI_RSP0 = const(memoffset::offset_of!(crate::gdt::ProcessorControlRegion, pti_rsp0)),
+            PTI_ENTRY_STACK = const(memoffset::offset_of!(crate::gdt::ProcessorControlRegion, tss) + memoffset::offset_of!(x86::bits64::task::TaskStateSegment, rsp)),
 
             options(noreturn));
         }
@@ -15,17 +15,40 @@ interrupt!(tlb, || {
     tlb::flush_all();
 });
 
+interrupt!(tlb_shootdown, || {
+    LOCAL_APIC.eoi();
+
+    crate::arch::tlb::handle_pending();
+});
+
 interrupt!(switch, || {
     LOCAL_APIC.eoi();
 
     let _ = context::switch();
 });
 
-interrupt!(pit, || {
+interrupt_stack!(pit, |stack| {
     LOCAL_APIC.eoi();
 
-    // Switch after 3 ticks (about 6.75 ms)
-    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 {
+    crate::lockup::tick();
+
+    crate::arch::nmi::poll();
+
+    crate::perf::tick();
+
+    crate::profile::tick(stack.iret.rip, stack.preserved.rbp, stack.iret.cs & 0b11 == 0b11);
+
+    crate::arch::mce::poll();
+
+    crate::thermal::poll();
+
+    // A parked CPU has nothing to switch to
+    if crate::hotplug::is_parked(crate::cpu_id()) {
+        return;
+    }
+
+    // Switch after 3 ticks (about 6.75 ms), or right away if a low-latency context is due
+    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 || context::latency::due() {
         let _ = context::switch();
     }
 });
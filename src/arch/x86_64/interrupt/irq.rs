@@ -4,12 +4,12 @@ use alloc::vec::Vec;
 
 use crate::{interrupt, interrupt_stack};
 use crate::context::timeout;
-use crate::device::{local_apic, ioapic, pic, pit};
+use crate::device::{local_apic, ioapic, pic};
 use crate::device::serial::{COM1, COM2};
 use crate::ipi::{ipi, IpiKind, IpiTarget};
 use crate::scheme::debug::{debug_input, debug_notify};
 use crate::scheme::serio::serio_input;
-use crate::{context, time};
+use crate::context;
 
 //resets to 0 in context::switch()
 #[thread_local]
@@ -67,6 +67,12 @@ extern {
 /// Notify the IRQ scheme that an IRQ has been registered. This should mask the IRQ until the
 /// scheme user unmasks it ("acknowledges" it).
 unsafe fn trigger(irq: u8) {
+    // The SCI, once the kernel took it over, unless another device sharing it raised it
+    #[cfg(feature = "acpi")]
+    if crate::acpi::sci::interrupt(irq) {
+        return;
+    }
+
     match irq_method() {
         IrqMethod::Pic => if irq < 16 { pic_mask(irq) },
         IrqMethod::Apic => ioapic_mask(irq),
@@ -83,6 +89,15 @@ pub unsafe fn acknowledge(irq: usize) {
     }
 }
 
+/// Mask the IRQ regardless of whether it has been acknowledged. This is called from the IRQ
+/// scheme when the IRQ is firing too often, and is undone by `acknowledge`.
+pub unsafe fn mask(irq: usize) {
+    match irq_method() {
+        IrqMethod::Pic => if irq < 16 { pic_mask(irq as u8) },
+        IrqMethod::Apic => ioapic_mask(irq as u8),
+    }
+}
+
 /// Sends an end-of-interrupt, so that the interrupt controller can go on to the next one.
 pub unsafe fn eoi(irq: u8) {
     match irq_method() {
@@ -134,12 +149,10 @@ unsafe fn ioapic_unmask(irq: usize) {
     ioapic::unmask(irq as u8);
 }
 
-interrupt_stack!(pit_stack, |_stack| {
+interrupt_stack!(pit_stack, |stack| {
     // Saves CPU time by not sending IRQ event irq_trigger(0);
 
-    {
-        *time::OFFSET.lock() += pit::RATE;
-    }
+    crate::arch::time::tick();
 
     eoi(0);
 
@@ -149,8 +162,26 @@ interrupt_stack!(pit_stack, |_stack| {
     // Any better way of doing this?
     timeout::trigger();
 
-    // Switch after 3 ticks (about 6.75 ms)
-    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 {
+    crate::scheme::irq::storm_tick();
+
+    crate::time::watchdog();
+
+    crate::watchdog::poll();
+
+    crate::lockup::tick();
+
+    crate::arch::nmi::poll();
+
+    crate::perf::tick();
+
+    crate::profile::tick(stack.iret.rip, stack.preserved.rbp, stack.iret.cs & 0b11 == 0b11);
+
+    crate::arch::mce::poll();
+
+    crate::thermal::poll();
+
+    // Switch after 3 ticks (about 6.75 ms), or right away if a low-latency context is due
+    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 2 || context::latency::due() {
         let _ = context::switch();
     }
 });
@@ -171,7 +202,9 @@ interrupt!(cascade, || {
 
 interrupt!(com2, || {
     while let Some(c) = COM2.lock().receive() {
-        debug_input(c);
+        if !crate::debugger::gdbstub::input(c) {
+            debug_input(c);
+        }
     }
     debug_notify();
     eoi(3);
@@ -265,9 +298,7 @@ interrupt!(lapic_error, || {
 });
 
 interrupt!(calib_pit, || {
-    {
-        *time::OFFSET.lock() += pit::RATE;
-    }
+    crate::arch::time::tick();
 
     eoi(0);
 });
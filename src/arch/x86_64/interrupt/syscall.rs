@@ -33,9 +33,10 @@ pub unsafe fn init() {
     // TF needs to be cleared, as enabling userspace-rflags-controlled singlestep in the kernel
     // would be a bad idea.
     //
-    // AC is not currently used, but when SMAP is enabled, it should always be cleared when
+    // AC is set by the usercopy functions when SMAP is enabled, and must always be cleared when
     // entering the kernel (and never be set except in usercopy functions), if for some reason AC
-    // was set before entering userspace (AC can only be modified by kernel code).
+    // was set before entering userspace (AC can only be modified by kernel code). Interrupts do
+    // not clear it, which their handlers do instead (see `misc::clac`).
     //
     // The other flags could indeed be preserved and excluded from FMASK, but since they are not
     // used to pass data to the kernel, they might as well be masked with *marginal* security
@@ -73,6 +74,8 @@ macro_rules! with_interrupt_stack {
 
 #[no_mangle]
 pub unsafe extern "C" fn __inner_syscall_instruction(stack: *mut InterruptStack) {
+    // Updates the user page table when returning, see `pti`
+    let _pti = crate::arch::x86_64::pti::SyncGuard;
     let _guard = ptrace::set_process_regs(stack);
     with_interrupt_stack!(|stack| {
         let scratch = &stack.scratch;
@@ -80,13 +83,44 @@ pub unsafe extern "C" fn __inner_syscall_instruction(stack: *mut InterruptStack)
     });
 }
 
+#[cfg(feature = "pti")]
+macro_rules! switch_to_kernel_cr3 {
+    () => { "
+        mov rsp, gs:[{PTI_KERNEL_CR3}]  // Switch to the kernel page table, see `pti`
+        mov cr3, rsp
+    " };
+}
+#[cfg(feature = "pti")]
+macro_rules! switch_to_user_cr3 {
+    () => { "
+        mov rsp, gs:[{PTI_USER_CR3}]    // Switch to the user page table, see `pti`
+        mov cr3, rsp
+    " };
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! switch_to_kernel_cr3 {
+    () => { "
+        // Unused: {PTI_KERNEL_CR3}
+    " };
+}
+#[cfg(not(feature = "pti"))]
+macro_rules! switch_to_user_cr3 {
+    () => { "
+        // Unused: {PTI_USER_CR3}
+    " };
+}
+
 #[naked]
+#[link_section = ".entry-text"]
 pub unsafe extern "C" fn syscall_instruction() {
     core::arch::asm!(concat!(
     // Yes, this is magic. No, you don't need to understand
     "
         swapgs                    // Set gs segment to TSS
         mov gs:[{sp}], rsp        // Save userspace stack pointer
+    ",
+        switch_to_kernel_cr3!(),
+    "
         mov rsp, gs:[{ksp}]       // Load kernel stack pointer
         push QWORD PTR {ss_sel}   // Push fake userspace SS (resembling iret frame)
         push QWORD PTR gs:[{sp}]  // Push userspace rsp
@@ -100,16 +134,10 @@ pub unsafe extern "C" fn syscall_instruction() {
     push_scratch!(),
     push_preserved!(),
 
-    // TODO: Map PTI
-    // $crate::arch::x86_64::pti::map();
-
     // Call inner funtion
     "mov rdi, rsp\n",
     "call __inner_syscall_instruction\n",
 
-    // TODO: Unmap PTI
-    // $crate::arch::x86_64::pti::unmap();
-
     // Pop context registers
     pop_preserved!(),
     pop_scratch!(),
@@ -138,6 +166,9 @@ pub unsafe extern "C" fn syscall_instruction() {
         add rsp, 8              // Pop fake userspace CS
         pop r11                 // Pop rflags
         pop QWORD PTR gs:[{sp}] // Pop userspace stack pointer
+    ",
+        switch_to_user_cr3!(),
+    "
         mov rsp, gs:[{sp}]      // Restore userspace stack pointer
         swapgs                  // Restore gs from TSS to user data
         sysretq                 // Return into userspace; RCX=>RIP,R11=>RFLAGS
@@ -147,12 +178,15 @@ pub unsafe extern "C" fn syscall_instruction() {
         // Slow iretq
         xor rcx, rcx
         xor r11, r11
-        swapgs
-        iretq
-    "),
+    ",
+        return_to_user!(),
+    ),
 
     sp = const(offset_of!(gdt::ProcessorControlRegion, user_rsp_tmp)),
-    ksp = const(offset_of!(gdt::ProcessorControlRegion, tss) + offset_of!(TaskStateSegment, rsp)),
+    ksp = const(gdt::PCR_KERNEL_STACK),
+    PTI_KERNEL_CR3 = const(offset_of!(gdt::ProcessorControlRegion, pti_kernel_cr3)),
+    PTI_USER_CR3 = const(offset_of!(gdt::ProcessorControlRegion, pti_user_cr3)),
+    PTI_ENTRY_STACK = const(offset_of!(gdt::ProcessorControlRegion, tss) + offset_of!(TaskStateSegment, rsp)),
     ss_sel = const(SegmentSelector::new(gdt::GDT_USER_DATA as u16, x86::Ring::Ring3).bits()),
     cs_sel = const(SegmentSelector::new(gdt::GDT_USER_CODE as u16, x86::Ring::Ring3).bits()),
 
@@ -5,6 +5,7 @@ pub enum IpiKind {
     Tlb = 0x41,
     Switch = 0x42,
     Pit = 0x43,
+    TlbShootdown = 0x44,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -15,6 +16,9 @@ pub enum IpiTarget {
     Other = 3,
 }
 
+/// Recorded in the journal as the target of an IPI sent to a single CPU, plus its ID
+pub const IPI_TARGET_CPU: usize = 0x100;
+
 #[cfg(not(feature = "multi_core"))]
 #[inline(always)]
 pub fn ipi(_kind: IpiKind, _target: IpiTarget) {}
@@ -24,6 +28,41 @@ pub fn ipi(_kind: IpiKind, _target: IpiTarget) {}
 pub fn ipi(kind: IpiKind, target: IpiTarget) {
     use crate::device::local_apic::LOCAL_APIC;
 
+    crate::journal::record(crate::journal::EventKind::Ipi, kind as usize, target as usize);
+
     let icr = (target as u64) << 18 | 1 << 14 | (kind as u64);
     unsafe { LOCAL_APIC.set_icr(icr) };
 }
+
+/// Send an IPI to the CPU `cpu_id`, which may be the current one
+#[cfg(not(feature = "multi_core"))]
+#[inline(always)]
+pub fn ipi_single(_kind: IpiKind, _cpu_id: usize) {}
+
+/// Send an IPI to the CPU `cpu_id`, which may be the current one
+#[cfg(feature = "multi_core")]
+#[inline(always)]
+pub fn ipi_single(kind: IpiKind, cpu_id: usize) {
+    use crate::device::local_apic::LOCAL_APIC;
+
+    crate::journal::record(crate::journal::EventKind::Ipi, kind as usize, IPI_TARGET_CPU + cpu_id);
+
+    // CPU IDs are local APIC IDs
+    unsafe { LOCAL_APIC.ipi_vector(cpu_id as u32, kind as u8) };
+}
+
+/// Send an IPI to every CPU whose bit is set in `mask`, bit `n` standing for CPU `n`
+pub fn ipi_mask(kind: IpiKind, mask: u64) {
+    // A single hypercall, instead of an exit for each CPU
+    #[cfg(feature = "multi_core")]
+    if super::paravirt::send_ipi_mask(kind as u8, mask) {
+        for cpu_id in (0..64).filter(|cpu_id| mask & (1 << cpu_id) != 0) {
+            crate::journal::record(crate::journal::EventKind::Ipi, kind as usize, IPI_TARGET_CPU + cpu_id);
+        }
+        return;
+    }
+
+    for cpu_id in (0..64).filter(|cpu_id| mask & (1 << cpu_id) != 0) {
+        ipi_single(kind, cpu_id);
+    }
+}
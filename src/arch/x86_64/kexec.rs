@@ -0,0 +1,203 @@
+//! # Kexec
+//! Jumping into a kernel laid out in memory by `kexec`, in the state the bootloader would have left
+//! it in: in long mode with interrupts disabled, with the kernel mapped at `KERNEL_OFFSET` and the
+//! memory it was given at `PHYS_OFFSET`, on its own stack, and with its arguments in rdi.
+//!
+//! The page table of the new kernel maps its image where the running kernel is, so the switch goes
+//! through a trampoline identity mapped in both it and a transition table, which only maps the code
+//! of the running kernel besides. Both tables are built when loading, in the memory of the new
+//! kernel, as the frame allocator cannot be relied on once panicking.
+//!
+//! On panic, the other CPUs are stopped with an NMI. The new kernel starts them again with INIT.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use rmm::{FrameAllocator, PageFlags, PageMapper, TableKind};
+
+use crate::kexec::Layout;
+use crate::memory::PAGE_SIZE;
+use crate::paging::{PhysicalAddress, RmmA, RmmArch, VirtualAddress};
+
+use super::start::{KernelArgs, KERNEL_BASE, KERNEL_SIZE};
+
+static TRAMPOLINE_DATA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/kexec"));
+
+/// The RSDPs handed over by the bootloader, as physical base and size
+static RSDPS_BASE: AtomicUsize = AtomicUsize::new(0);
+static RSDPS_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once the other CPUs are told to stop, and counting those that did
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Iterations of the wait for the other CPUs to stop, well over a second
+const STOP_SPINS: usize = 100_000_000;
+
+/// Where to jump to, prepared by `prepare`
+#[derive(Clone, Copy, Debug)]
+pub struct Jump {
+    /// Physical addresses of the transition table, the trampoline and the new page table
+    transition_table: usize,
+    trampoline: usize,
+    table: usize,
+    /// Virtual addresses of the top of the stack and of the arguments, in the new page table
+    stack: usize,
+    args: usize,
+    entry: usize,
+}
+
+/// Remember the RSDPs handed over by the bootloader, to hand them over again
+pub fn set_rsdps(base: usize, size: usize) {
+    RSDPS_BASE.store(base, Ordering::Relaxed);
+    RSDPS_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// The RSDPs handed over by the bootloader, as physical base and size
+pub fn rsdps() -> (usize, usize) {
+    (RSDPS_BASE.load(Ordering::Relaxed), RSDPS_SIZE.load(Ordering::Relaxed))
+}
+
+/// Map `size` bytes at physical address `phys` to `virt`
+unsafe fn map<F: FrameAllocator>(mapper: &mut PageMapper<RmmA, F>, virt: usize, phys: usize, size: usize, flags: PageFlags<RmmA>) -> Option<()> {
+    for offset in (0..size).step_by(PAGE_SIZE) {
+        let virt = VirtualAddress::new(virt + offset);
+        let phys = PhysicalAddress::new(phys + offset);
+        mapper.map_phys(virt, phys, flags)?.ignore(); // Not the active table
+    }
+    Some(())
+}
+
+/// Write the arguments of the kernel laid out in `layout`, whose entry point is `entry`, and build
+/// the page tables to jump to it with, in frames of `allocator`, which needs at most
+/// `table_frames` of them
+pub unsafe fn prepare<F: FrameAllocator>(layout: &Layout, entry: usize, mut allocator: F) -> Option<Jump> {
+    let args = KernelArgs {
+        kernel_base: layout.kernel.0 as u64,
+        kernel_size: layout.kernel.1 as u64,
+        stack_base: layout.stack.0 as u64,
+        stack_size: layout.stack.1 as u64,
+        env_base: layout.env.0 as u64,
+        env_size: layout.env.1 as u64,
+        acpi_rsdps_base: layout.rsdps.0 as u64,
+        acpi_rsdps_size: layout.rsdps.1 as u64,
+        // Read before the kernel maps memory itself, so through the mapping of the region
+        areas_base: (crate::PHYS_OFFSET + layout.areas.0) as u64,
+        areas_size: layout.areas.1 as u64,
+        bootstrap_base: layout.initfs.0 as u64,
+        bootstrap_size: layout.initfs.1 as u64,
+        bootstrap_entry: layout.bootstrap_entry as u64,
+    };
+    ptr::write_unaligned(RmmA::phys_to_virt(PhysicalAddress::new(layout.args)).data() as *mut KernelArgs, args);
+
+    let trampoline = allocator.allocate_one()?.data();
+    ptr::copy_nonoverlapping(
+        TRAMPOLINE_DATA.as_ptr(),
+        RmmA::phys_to_virt(PhysicalAddress::new(trampoline)).data() as *mut u8,
+        TRAMPOLINE_DATA.len(),
+    );
+    let trampoline_flags = PageFlags::new().execute(true);
+
+    let table = {
+        let mut mapper = PageMapper::<RmmA, _>::create(TableKind::Kernel, &mut allocator)?;
+        map(&mut mapper, crate::KERNEL_OFFSET, layout.kernel.0, layout.kernel.1, PageFlags::new().write(true).execute(true))?;
+        map(&mut mapper, crate::PHYS_OFFSET + layout.region.0, layout.region.0, layout.region.1, PageFlags::new().write(true))?;
+        map(&mut mapper, trampoline, trampoline, PAGE_SIZE, trampoline_flags)?;
+
+        // Where the bootloader mapped it, for the new kernel to keep printing on it
+        #[cfg(feature = "graphical_debug")]
+        {
+            let (phys, virt, size) = *crate::devices::graphical_debug::FRAMEBUFFER.lock();
+            if phys != 0 && virt != 0 {
+                map(&mut mapper, virt, phys, size.next_multiple_of(PAGE_SIZE), PageFlags::new().write(true))?;
+            }
+        }
+
+        mapper.table().phys().data()
+    };
+
+    let transition_table = {
+        // Only the code jumping to the trampoline runs on it, without touching the stack
+        let mut mapper = PageMapper::<RmmA, _>::create(TableKind::Kernel, &mut allocator)?;
+        let kernel_base = KERNEL_BASE.load(Ordering::Relaxed);
+        let kernel_size = KERNEL_SIZE.load(Ordering::Relaxed).next_multiple_of(PAGE_SIZE);
+        map(&mut mapper, crate::KERNEL_OFFSET, kernel_base, kernel_size, PageFlags::new().execute(true))?;
+        map(&mut mapper, trampoline, trampoline, PAGE_SIZE, trampoline_flags)?;
+        mapper.table().phys().data()
+    };
+
+    Some(Jump {
+        transition_table,
+        trampoline,
+        table,
+        stack: crate::PHYS_OFFSET + layout.stack.0 + layout.stack.1,
+        args: crate::PHYS_OFFSET + layout.args,
+        entry,
+    })
+}
+
+/// Frames needed by `prepare`, at most, for a kernel of `kernel_size` bytes in a region of
+/// `region_size` bytes
+pub fn table_frames(kernel_size: usize, region_size: usize) -> usize {
+    // A page table for each 2 MiB mapped, a directory for each 1 GiB, and the levels above
+    let tables = |size: usize| size.div_ceil(512 * PAGE_SIZE) + size.div_ceil(512 * 512 * PAGE_SIZE) + 2;
+    #[cfg(feature = "graphical_debug")]
+    let framebuffer = tables(crate::devices::graphical_debug::FRAMEBUFFER.lock().2);
+    #[cfg(not(feature = "graphical_debug"))]
+    let framebuffer = 0;
+    // The trampoline page, then both kernels, the region and the trampoline in either table
+    1 + tables(kernel_size) + tables(KERNEL_SIZE.load(Ordering::Relaxed)) + tables(region_size)
+        + 2 * tables(PAGE_SIZE) + framebuffer
+}
+
+/// Stop every other CPU with an NMI, waiting for them for a while. Called on panic, as they may be
+/// holding locks or be stuck with interrupts disabled.
+pub fn stop_other_cpus() {
+    STOPPING.store(true, Ordering::SeqCst);
+
+    // NMI to all excluding self
+    #[cfg(feature = "multi_core")]
+    unsafe {
+        super::device::local_apic::LOCAL_APIC.set_icr(3 << 18 | 1 << 14 | 0b100 << 8);
+    }
+
+    let others = crate::cpu_count().saturating_sub(1);
+    let mut spins = 0;
+    while STOPPED.load(Ordering::SeqCst) < others && spins < STOP_SPINS {
+        core::hint::spin_loop();
+        spins += 1;
+    }
+}
+
+/// Stop the current CPU if another one is jumping into a new kernel. Called by the NMI handler.
+pub fn nmi() {
+    if !STOPPING.load(Ordering::SeqCst) {
+        return;
+    }
+    STOPPED.fetch_add(1, Ordering::SeqCst);
+    // NMIs stay blocked, as this one never returns
+    loop {
+        unsafe { core::arch::asm!("cli; hlt") };
+    }
+}
+
+/// Jump into the kernel prepared as `jump`. Other CPUs must be stopped.
+pub unsafe fn jump(jump: &Jump) -> ! {
+    // The performance counter would raise NMIs before the new kernel can handle them
+    super::nmi::stop();
+
+    core::arch::asm!(
+        "
+        cli
+        mov cr3, {transition_table}
+        jmp {trampoline}
+        ",
+        transition_table = in(reg) jump.transition_table,
+        trampoline = in(reg) jump.trampoline,
+        in("rdi") jump.table,
+        in("rsi") jump.stack,
+        in("rdx") jump.args,
+        in("rcx") jump.entry,
+        options(noreturn),
+    );
+}
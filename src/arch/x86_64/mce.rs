@@ -0,0 +1,336 @@
+//! # Machine check architecture
+//! Hardware errors are reported in per-CPU banks of MSRs. Errors the hardware corrected, or that
+//! need no immediate action, are found by polling the banks from the timer interrupt, while the
+//! others raise a machine check exception (`#MC`). Both are logged, and can be read from
+//! `sys:mce`.
+//!
+//! An uncorrected error that corrupted the processor context is fatal. Otherwise, if the error
+//! requires action and interrupted userspace, only the interrupted context is killed, with
+//! `SIGBUS` if it can be resumed to handle it, and `SIGKILL` if not. Uncorrected errors in the
+//! kernel are fatal.
+//!
+//! The machine check handler can interrupt any code, including code holding the log lock, so it
+//! never waits for it: records that cannot be logged right away are counted as lost.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::sync::Mutex;
+use crate::syscall::error::Result;
+
+use super::cpuid::cpuid;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MCG_CTL: u32 = 0x17B;
+const IA32_MC0_CTL: u32 = 0x400;
+
+const MCG_CAP_COUNT: u64 = 0xFF;
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+
+/// The interrupted instruction can be restarted
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+
+const MCI_STATUS_VAL: u64 = 1 << 63;
+const MCI_STATUS_OVER: u64 = 1 << 62;
+const MCI_STATUS_UC: u64 = 1 << 61;
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+const MCI_STATUS_PCC: u64 = 1 << 57;
+const MCI_STATUS_S: u64 = 1 << 56;
+const MCI_STATUS_AR: u64 = 1 << 55;
+
+/// Number of records kept, older ones are overwritten
+const LOG_SIZE: usize = 64;
+
+/// Timer ticks between polls of the banks, about 2.25 s
+const POLL_TICKS: usize = 1000;
+
+fn mci_ctl(bank: u8) -> u32 {
+    IA32_MC0_CTL + 4 * u32::from(bank)
+}
+fn mci_status(bank: u8) -> u32 {
+    IA32_MC0_CTL + 4 * u32::from(bank) + 1
+}
+fn mci_addr(bank: u8) -> u32 {
+    IA32_MC0_CTL + 4 * u32::from(bank) + 2
+}
+fn mci_misc(bank: u8) -> u32 {
+    IA32_MC0_CTL + 4 * u32::from(bank) + 3
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Severity {
+    /// Corrected by the hardware
+    Corrected,
+    /// Uncorrected, but no action is required, or not signaled by `#MC`
+    Deferred,
+    /// Uncorrected, and the interrupted context consumed the corrupt data
+    ActionRequired,
+    /// Uncorrected, and the processor context is corrupt
+    Fatal,
+}
+
+impl Severity {
+    fn from_status(status: u64) -> Self {
+        if status & MCI_STATUS_UC == 0 {
+            Severity::Corrected
+        } else if status & MCI_STATUS_PCC != 0 {
+            Severity::Fatal
+        } else if status & MCI_STATUS_S != 0 && status & MCI_STATUS_AR != 0 {
+            Severity::ActionRequired
+        } else {
+            Severity::Deferred
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Corrected => "corrected",
+            Severity::Deferred => "deferred",
+            Severity::ActionRequired => "action_required",
+            Severity::Fatal => "fatal",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Record {
+    pub cpu: usize,
+    pub bank: u8,
+    pub status: u64,
+    pub addr: Option<u64>,
+    pub misc: Option<u64>,
+}
+
+impl Record {
+    pub fn severity(&self) -> Severity {
+        Severity::from_status(self.status)
+    }
+
+    /// The unit reporting the error, from the compound MCA error code
+    pub fn unit(&self) -> &'static str {
+        let code = self.status & 0xFFFF;
+        // Bit 12 only filters corrected errors, and is ignored
+        let code = code & !(1 << 12);
+        if code & 0xF800 == 0x0800 {
+            "bus"
+        } else if code & 0xFF00 == 0x0100 {
+            "cache"
+        } else if code & 0xFF80 == 0x0080 {
+            "memory"
+        } else if code & 0xFFF0 == 0x0010 {
+            "tlb"
+        } else if code & 0xFFFC == 0x000C {
+            "cache"
+        } else if code & 0xFC00 == 0x0400 || code == 0x0005 {
+            "internal"
+        } else {
+            "other"
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cpu={} bank={} {} {} status={:#018x}", self.cpu, self.bank, self.severity().name(), self.unit(), self.status)?;
+        if let Some(addr) = self.addr {
+            write!(f, " addr={:#x}", addr)?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, " misc={:#x}", misc)?;
+        }
+        if self.status & MCI_STATUS_OVER != 0 {
+            write!(f, " overflow")?;
+        }
+        Ok(())
+    }
+}
+
+struct Log {
+    records: [Option<Record>; LOG_SIZE],
+    /// Number of records logged so far, the next one going at `next % LOG_SIZE`
+    next: usize,
+}
+
+static LOG: Mutex<Log> = Mutex::new(Log {
+    records: [None; LOG_SIZE],
+    next: 0,
+});
+
+/// Records that could not be logged, as the log was locked
+static LOST: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of banks of the current CPU, 0 if machine checks are not supported
+#[thread_local]
+static BANKS: Cell<u8> = Cell::new(0);
+
+#[thread_local]
+static POLL_COUNTDOWN: Cell<usize> = Cell::new(POLL_TICKS);
+
+fn read_bank(bank: u8) -> Option<Record> {
+    let status = unsafe { rdmsr(mci_status(bank)) };
+    if status & MCI_STATUS_VAL == 0 {
+        return None;
+    }
+    Some(Record {
+        cpu: crate::cpu_id(),
+        bank,
+        status,
+        addr: (status & MCI_STATUS_ADDRV != 0).then(|| unsafe { rdmsr(mci_addr(bank)) }),
+        misc: (status & MCI_STATUS_MISCV != 0).then(|| unsafe { rdmsr(mci_misc(bank)) }),
+    })
+}
+
+fn clear_bank(bank: u8) {
+    unsafe { wrmsr(mci_status(bank), 0) };
+}
+
+fn log(log: &mut Log, record: Record) {
+    log.records[log.next % LOG_SIZE] = Some(record);
+    log.next += 1;
+}
+
+/// Enable machine checks on the current CPU, after logging what the banks hold from before boot
+pub unsafe fn init() {
+    let supported = cpuid().and_then(|cpuid| cpuid.get_feature_info()).map_or(false, |info| {
+        info.has_mce() && info.has_mca()
+    });
+    if !supported {
+        return;
+    }
+
+    let cap = rdmsr(IA32_MCG_CAP);
+    let banks = (cap & MCG_CAP_COUNT) as u8;
+    BANKS.set(banks);
+
+    {
+        let mut log_guard = LOG.lock();
+        for bank in 0..banks {
+            if let Some(record) = read_bank(bank) {
+                log(&mut log_guard, record);
+            }
+            clear_bank(bank);
+        }
+    }
+
+    if cap & MCG_CAP_CTL_P != 0 {
+        wrmsr(IA32_MCG_CTL, !0);
+    }
+    for bank in 0..banks {
+        wrmsr(mci_ctl(bank), !0);
+    }
+
+    x86::controlregs::cr4_write(x86::controlregs::cr4() | x86::controlregs::Cr4::CR4_ENABLE_MACHINE_CHECK);
+}
+
+/// Log the errors not signaled by `#MC` from the banks of the current CPU, every `POLL_TICKS`
+/// calls. Called from the timer interrupt.
+pub fn poll() {
+    let countdown = POLL_COUNTDOWN.get();
+    if countdown > 1 {
+        POLL_COUNTDOWN.set(countdown - 1);
+        return;
+    }
+    POLL_COUNTDOWN.set(POLL_TICKS);
+
+    // Retried on the next poll, the banks keep their records until then
+    let Some(mut log_guard) = LOG.try_lock() else {
+        return;
+    };
+    for bank in 0..BANKS.get() {
+        let Some(record) = read_bank(bank) else {
+            continue;
+        };
+        // Those are left for the machine check handler
+        if matches!(record.severity(), Severity::ActionRequired | Severity::Fatal) {
+            continue;
+        }
+        log(&mut log_guard, record);
+        clear_bank(bank);
+    }
+}
+
+/// What the machine check handler has to do about the interrupted context
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Resume it
+    Resume,
+    /// Send it `SIGBUS`, with the physical address of the error if known
+    Signal(Option<u64>),
+    /// Kill it, as it cannot be resumed
+    Kill,
+    /// Panic, the error cannot be contained
+    Panic,
+}
+
+/// Log the errors reported by the banks of the current CPU, and decide what to do about the
+/// interrupted context. Called from the machine check handler.
+pub fn handle(user: bool) -> Action {
+    let mcg_status = unsafe { rdmsr(IA32_MCG_STATUS) };
+
+    let mut log_guard = LOG.try_lock();
+    let mut worst = None;
+    let mut addr = None;
+    for bank in 0..BANKS.get() {
+        let Some(record) = read_bank(bank) else {
+            continue;
+        };
+        println!("Machine check: {}", record);
+
+        let severity = record.severity();
+        if Some(severity) > worst {
+            worst = Some(severity);
+            addr = record.addr;
+        }
+
+        match log_guard {
+            Some(ref mut log_guard) => log(log_guard, record),
+            None => {
+                LOST.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        clear_bank(bank);
+    }
+    drop(log_guard);
+
+    // Signal that the exception was handled, so that another one does not shut down the CPU
+    unsafe { wrmsr(IA32_MCG_STATUS, 0) };
+
+    let restartable = mcg_status & MCG_STATUS_RIPV != 0;
+    match worst {
+        Some(Severity::Fatal) => Action::Panic,
+        _ if !restartable => if user { Action::Kill } else { Action::Panic },
+        Some(Severity::ActionRequired) if user => Action::Signal(addr),
+        Some(Severity::ActionRequired) => Action::Panic,
+        // Nothing that needs action, or nothing in the banks at all, as machine checks can be
+        // broadcast to every CPU while only one of them reports the error
+        _ => Action::Resume,
+    }
+}
+
+/// The logged machine check records, oldest first
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+    let (records, next) = {
+        let log = LOG.lock();
+        (log.records, log.next)
+    };
+
+    let lost = LOST.load(Ordering::Relaxed) + next.saturating_sub(LOG_SIZE);
+    if lost > 0 {
+        let _ = writeln!(string, "lost {}", lost);
+    }
+    for i in next.saturating_sub(LOG_SIZE)..next {
+        if let Some(record) = records[i % LOG_SIZE] {
+            let _ = writeln!(string, "{}", record);
+        }
+    }
+
+    Ok(string.into_bytes())
+}
@@ -1,13 +1,19 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use x86::controlregs::Cr4;
 use x86::cpuid::ExtendedFeatures;
 
+/// Whether SMAP is enabled. STAC and CLAC are invalid instructions on CPUs without SMAP, so the
+/// usercopy functions and interrupt entries check this before using them.
+pub static SMAP: AtomicBool = AtomicBool::new(false);
+
 pub unsafe fn init() {
     let has_ext_feat = |feat: fn(ExtendedFeatures) -> bool| crate::cpuid::cpuid_always().get_extended_feature_info().map_or(false, feat);
 
     if has_ext_feat(|feat| feat.has_umip()) {
         // UMIP (UserMode Instruction Prevention) forbids userspace from calling SGDT, SIDT, SLDT,
-        // SMSW and STR. KASLR is currently not implemented, but this protects against leaking
-        // addresses.
+        // SMSW and STR. KASLR only randomizes the kernel heap, not the kernel image, but this
+        // protects against leaking addresses.
         x86::controlregs::cr4_write(x86::controlregs::cr4() | Cr4::CR4_ENABLE_UMIP);
     }
     if has_ext_feat(|feat| feat.has_smep()) {
@@ -24,5 +30,23 @@ pub unsafe fn init() {
         x86::controlregs::cr4_write(x86::controlregs::cr4() | Cr4::CR4_ENABLE_SMAP);
         // Clear CLAC in (the probably unlikely) case the bootloader set it earlier.
         x86::bits64::rflags::clac();
+        SMAP.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Allow the kernel to access user pages, if SMAP is enabled. Only for code that cannot go through
+/// `UserSlice`, like the debugger.
+pub unsafe fn stac() {
+    if SMAP.load(Ordering::Relaxed) {
+        x86::bits64::rflags::stac();
+    }
+}
+
+/// Forbid the kernel from accessing user pages again, if SMAP is enabled. Called when entering an
+/// interrupt or exception handler, as the CPU does not clear RFLAGS.AC when interrupting a
+/// usercopy function, and restores it when returning.
+pub unsafe fn clac() {
+    if SMAP.load(Ordering::Relaxed) {
+        x86::bits64::rflags::clac();
     }
 }
@@ -0,0 +1,307 @@
+//! # Speculative execution mitigations
+//! Which mitigations each CPU supports, and which are active. Some can be switched on and off at
+//! runtime through `mitigations:`. A CPU applies a change at its next context switch, as that is
+//! also where most of the mitigations take effect.
+//!
+//! The mitigations enabled at boot can be chosen with `MITIGATIONS` in the environment: a comma
+//! separated list, applied in order to the defaults, of `off` (disable all that can be disabled),
+//! `auto` (the defaults), `<name>` (enable) and `-<name>` (disable). For example,
+//! `MITIGATIONS=off,ibpb` enables only IBPB.
+
+use core::arch::x86_64::__cpuid_count;
+use core::str;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use x86::msr;
+
+/// Number of CPUs whose state is tracked
+pub const CPUS: usize = 32;
+
+// CPUID.(EAX=7, ECX=0):EDX bits
+const CPUID_MD_CLEAR: u32 = 1 << 10;
+const CPUID_SPEC_CTRL: u32 = 1 << 26;
+const CPUID_STIBP: u32 = 1 << 27;
+const CPUID_ARCH_CAPABILITIES: u32 = 1 << 29;
+const CPUID_SSBD: u32 = 1 << 31;
+
+const IA32_SPEC_CTRL: u32 = 0x48;
+const IA32_PRED_CMD: u32 = 0x49;
+const IA32_ARCH_CAPABILITIES: u32 = 0x10A;
+
+const SPEC_CTRL_IBRS: u64 = 1 << 0;
+const SPEC_CTRL_STIBP: u64 = 1 << 1;
+const SPEC_CTRL_SSBD: u64 = 1 << 2;
+const PRED_CMD_IBPB: u64 = 1 << 0;
+const ARCH_CAPABILITIES_RDCL_NO: u64 = 1 << 0;
+const ARCH_CAPABILITIES_IBRS_ALL: u64 = 1 << 1;
+const ARCH_CAPABILITIES_SSB_NO: u64 = 1 << 4;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mitigation {
+    /// Page table isolation against Meltdown, chosen at build time with the `pti` feature
+    Pti,
+    /// Indirect branch prediction barrier when switching to the address space of another user,
+    /// against Spectre v2
+    Ibpb,
+    /// Speculative store bypass disable, against Spectre v4
+    Ssbd,
+    /// Clearing CPU buffers, against MDS. Reported only, nothing clears them yet.
+    MdClear,
+    /// Enhanced indirect branch restricted speculation, against Spectre v2 and retbleed in the
+    /// kernel. Legacy IBRS would have to be set on every kernel entry, and is not used.
+    Ibrs,
+    /// Single thread indirect branch predictors, against Spectre v2 from sibling hyperthreads.
+    /// Costly, and off by default.
+    Stibp,
+}
+
+impl Mitigation {
+    pub const ALL: [Mitigation; 6] = [
+        Mitigation::Pti,
+        Mitigation::Ibpb,
+        Mitigation::Ssbd,
+        Mitigation::MdClear,
+        Mitigation::Ibrs,
+        Mitigation::Stibp,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mitigation::Pti => "pti",
+            Mitigation::Ibpb => "ibpb",
+            Mitigation::Ssbd => "ssbd",
+            Mitigation::MdClear => "md_clear",
+            Mitigation::Ibrs => "ibrs",
+            Mitigation::Stibp => "stibp",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|mitigation| mitigation.name() == name)
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+
+    /// Whether it can be switched at runtime
+    pub fn toggleable(self) -> bool {
+        matches!(self, Mitigation::Ibpb | Mitigation::Ssbd | Mitigation::Ibrs | Mitigation::Stibp)
+    }
+
+    /// The bit of `IA32_SPEC_CTRL` that enables it, if any
+    fn spec_ctrl(self) -> u64 {
+        match self {
+            Mitigation::Ibrs => SPEC_CTRL_IBRS,
+            Mitigation::Stibp => SPEC_CTRL_STIBP,
+            Mitigation::Ssbd => SPEC_CTRL_SSBD,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    /// The CPU cannot do it
+    Unsupported,
+    /// The CPU reports that it is not vulnerable
+    NotVulnerable,
+    On,
+    Off,
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Unsupported => "unsupported",
+            Status::NotVulnerable => "not vulnerable",
+            Status::On => "on",
+            Status::Off => "off",
+        }
+    }
+}
+
+struct CpuState {
+    /// Whether the CPU has started and detected its mitigations
+    present: AtomicBool,
+    /// Mitigation bits the CPU supports
+    supported: AtomicU8,
+    /// Mitigation bits the CPU is not vulnerable to
+    immune: AtomicU8,
+    /// Mitigation bits currently applied
+    active: AtomicU8,
+}
+
+static CPU_STATE: [CpuState; CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const STATE: CpuState = CpuState {
+        present: AtomicBool::new(false),
+        supported: AtomicU8::new(0),
+        immune: AtomicU8::new(0),
+        active: AtomicU8::new(0),
+    };
+    [STATE; CPUS]
+};
+
+/// Mitigation bits that should be active on every CPU that supports them
+static ENABLED: AtomicU8 = AtomicU8::new(DEFAULT);
+
+const DEFAULT: u8 = 1 << Mitigation::Pti as u8 | 1 << Mitigation::Ibpb as u8 | 1 << Mitigation::Ibrs as u8;
+
+fn toggleable_bits() -> u8 {
+    Mitigation::ALL.iter()
+        .filter(|mitigation| mitigation.toggleable())
+        .fold(0, |bits, mitigation| bits | mitigation.bit())
+}
+
+/// Choose the mitigations enabled at boot from `MITIGATIONS` in the environment. Called on the BSP
+/// before `init`.
+pub fn configure(env: &[u8]) {
+    let value = match str::from_utf8(env).unwrap_or("").lines().find_map(|line| line.strip_prefix("MITIGATIONS=")) {
+        Some(value) => value,
+        None => return,
+    };
+
+    let toggleable = toggleable_bits();
+    let mut enabled = DEFAULT;
+    for item in value.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        let (name, enable) = match item.strip_prefix('-') {
+            Some(name) => (name, false),
+            None => (item, true),
+        };
+        match (name, Mitigation::from_name(name)) {
+            ("off", _) => enabled &= !toggleable,
+            ("auto", _) => enabled = DEFAULT,
+            (_, Some(mitigation)) if mitigation.toggleable() => if enable {
+                enabled |= mitigation.bit();
+            } else {
+                enabled &= !mitigation.bit();
+            },
+            _ => log::warn!("mitigations: ignoring unknown or fixed mitigation {:?}", item),
+        }
+    }
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Detect the mitigations of the current CPU. Called on each CPU while starting.
+pub unsafe fn init(cpu_id: usize) {
+    let state = match CPU_STATE.get(cpu_id) {
+        Some(state) => state,
+        None => return,
+    };
+
+    let max_leaf = __cpuid_count(0, 0).eax;
+    let edx = if max_leaf >= 7 { __cpuid_count(7, 0).edx } else { 0 };
+    let capabilities = if edx & CPUID_ARCH_CAPABILITIES != 0 { msr::rdmsr(IA32_ARCH_CAPABILITIES) } else { 0 };
+
+    let mut supported = Mitigation::Pti.bit();
+    if edx & CPUID_SPEC_CTRL != 0 {
+        supported |= Mitigation::Ibpb.bit();
+    }
+    if edx & CPUID_SSBD != 0 && edx & CPUID_SPEC_CTRL != 0 {
+        supported |= Mitigation::Ssbd.bit();
+    }
+    if edx & CPUID_MD_CLEAR != 0 {
+        supported |= Mitigation::MdClear.bit();
+    }
+    if edx & CPUID_SPEC_CTRL != 0 && capabilities & ARCH_CAPABILITIES_IBRS_ALL != 0 {
+        supported |= Mitigation::Ibrs.bit();
+    }
+    if edx & CPUID_STIBP != 0 {
+        supported |= Mitigation::Stibp.bit();
+    }
+
+    let mut immune = 0;
+    if capabilities & ARCH_CAPABILITIES_RDCL_NO != 0 {
+        immune |= Mitigation::Pti.bit();
+    }
+    if capabilities & ARCH_CAPABILITIES_SSB_NO != 0 {
+        immune |= Mitigation::Ssbd.bit();
+    }
+
+    state.supported.store(supported, Ordering::Relaxed);
+    state.immune.store(immune, Ordering::Relaxed);
+    state.active.store(if cfg!(feature = "pti") { Mitigation::Pti.bit() } else { 0 }, Ordering::Relaxed);
+    apply(state);
+    state.present.store(true, Ordering::Release);
+}
+
+/// The IDs of the CPUs that have started, which are local APIC IDs and need not be contiguous
+pub fn cpus() -> impl Iterator<Item = usize> {
+    (0..CPUS).filter(|&cpu_id| CPU_STATE[cpu_id].present.load(Ordering::Acquire))
+}
+
+/// Apply changes to the enabled mitigations on the current CPU
+fn apply(state: &CpuState) {
+    let wanted = ENABLED.load(Ordering::Relaxed) & state.supported.load(Ordering::Relaxed);
+    let active = state.active.load(Ordering::Relaxed);
+
+    let (mut set, mut clear) = (0, 0);
+    for mitigation in Mitigation::ALL {
+        if (wanted ^ active) & mitigation.bit() != 0 {
+            if wanted & mitigation.bit() != 0 {
+                set |= mitigation.spec_ctrl();
+            } else {
+                clear |= mitigation.spec_ctrl();
+            }
+        }
+    }
+    if set | clear != 0 {
+        unsafe {
+            let spec_ctrl = msr::rdmsr(IA32_SPEC_CTRL);
+            msr::wrmsr(IA32_SPEC_CTRL, spec_ctrl & !clear | set);
+        }
+    }
+
+    // PTI and MD_CLEAR are not switched at runtime
+    let runtime = toggleable_bits();
+    state.active.store(active & !runtime | wanted & runtime, Ordering::Relaxed);
+}
+
+/// Called on each context switch, before switching to the next context. The branch predictors
+/// are only flushed when switching to the address space of another user, as processes of the same
+/// user can already read each other's memory.
+pub fn on_switch(address_space_changed: bool, user_changed: bool) {
+    let state = match CPU_STATE.get(crate::cpu_id()) {
+        Some(state) => state,
+        None => return,
+    };
+    apply(state);
+
+    if address_space_changed && user_changed && state.active.load(Ordering::Relaxed) & Mitigation::Ibpb.bit() != 0 {
+        unsafe { msr::wrmsr(IA32_PRED_CMD, PRED_CMD_IBPB) };
+    }
+}
+
+/// Whether `mitigation` should be used on CPUs that support it
+pub fn enabled(mitigation: Mitigation) -> bool {
+    ENABLED.load(Ordering::Relaxed) & mitigation.bit() != 0
+}
+
+/// Switch `mitigation` on or off on all CPUs, which fails if it cannot be switched at runtime
+pub fn set_enabled(mitigation: Mitigation, enabled: bool) -> bool {
+    if !mitigation.toggleable() {
+        return false;
+    }
+    if enabled {
+        ENABLED.fetch_or(mitigation.bit(), Ordering::Relaxed);
+    } else {
+        ENABLED.fetch_and(!mitigation.bit(), Ordering::Relaxed);
+    }
+    true
+}
+
+/// The status of `mitigation` on `cpu_id`
+pub fn status(cpu_id: usize, mitigation: Mitigation) -> Option<Status> {
+    let state = CPU_STATE.get(cpu_id).filter(|state| state.present.load(Ordering::Acquire))?;
+    let bit = mitigation.bit();
+    Some(if state.active.load(Ordering::Relaxed) & bit != 0 {
+        Status::On
+    } else if state.immune.load(Ordering::Relaxed) & bit != 0 {
+        Status::NotVulnerable
+    } else if state.supported.load(Ordering::Relaxed) & bit == 0 {
+        Status::Unsupported
+    } else {
+        Status::Off
+    })
+}
@@ -4,6 +4,9 @@ pub mod macros;
 /// Constants like memory locations
 pub mod consts;
 
+/// CPU frequency scaling drivers
+pub mod cpufreq;
+
 /// CPUID wrapper
 pub mod cpuid;
 
@@ -16,6 +19,9 @@ pub mod device;
 /// Global descriptor table
 pub mod gdt;
 
+/// Idle states
+pub mod idle;
+
 /// Interrupt instructions
 #[macro_use]
 pub mod interrupt;
@@ -26,25 +32,56 @@ pub mod idt;
 /// Inter-processor interrupts
 pub mod ipi;
 
+/// Booting into another kernel
+pub mod kexec;
+
+/// Machine check architecture
+pub mod mce;
+
 /// Miscellaneous processor features
 pub mod misc;
 
+/// Speculative execution mitigations
+pub mod mitigations;
+
+/// NMI watchdog
+pub mod nmi;
+
 /// Paging
 pub mod paging;
 
+/// Paravirtualized guest support
+pub mod paravirt;
+
+/// Performance counters
+pub mod pmu;
+
 /// Page table isolation
 pub mod pti;
 
 pub mod rmm;
 
+/// TLB shootdown
+pub mod tlb;
+
 /// Initialization and start function
 pub mod start;
 
 /// Stop function
 pub mod stop;
 
+/// Suspend to RAM
+#[cfg(feature = "acpi")]
+pub mod suspend;
+
+/// Digital thermal sensors
+pub mod thermal;
+
 pub mod time;
 
+/// Extended processor state
+pub mod xsave;
+
 pub use ::rmm::X8664Arch as CurrentRmmArch;
 
 // Flags
@@ -67,14 +104,20 @@ pub unsafe extern "C" fn arch_copy_to_user(dst: usize, src: usize, len: usize) -
         ret
     ", options(noreturn));
 
+    // STAC and CLAC are only valid if the CPU supports SMAP, which is checked at runtime
     #[cfg(feature = "x86_smap")]
     core::arch::asm!("
         xor eax, eax
         mov rcx, rdx
+        cmp byte ptr [rip + {smap}], 0
+        je 2f
         stac
         rep movsb
         clac
         ret
-    ", options(noreturn));
+    2:
+        rep movsb
+        ret
+    ", smap = sym misc::SMAP, options(noreturn));
 }
 pub use arch_copy_to_user as arch_copy_from_user;
@@ -0,0 +1,140 @@
+//! # NMI watchdog
+//! The first general-purpose performance counter of each CPU counts unhalted core cycles, and
+//! raises an NMI through the local APIC each time it overflows, about every `PERIOD` nanoseconds,
+//! even while interrupts are disabled. The NMI is passed on to `lockup::nmi`, and on the BSP to
+//! `watchdog::nmi`.
+//!
+//! Only architectural performance monitoring is supported, and the period is limited to 2^31
+//! cycles, as writes to the counter are sign-extended from 32 bits.
+
+use core::arch::x86_64::__cpuid;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86::msr::{rdmsr, wrmsr};
+
+use super::device::{local_apic::LOCAL_APIC, tsc};
+use super::interrupt::InterruptStack;
+
+/// Nanoseconds between NMIs, at the TSC frequency
+const PERIOD: u64 = 1_000_000_000;
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+const EVENT_UNHALTED_CORE_CYCLES: u64 = 0x3C;
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+// CPUID.0AH:EBX bits, set if the event is not available
+const CPUID_NO_CORE_CYCLES: u32 = 1 << 0;
+
+/// Delivery mode of the performance counter LVT entry
+const LVT_NMI: u32 = 0b100 << 8;
+
+/// Cycles counted between NMIs, zero if the counter cannot be used
+static CYCLES: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds between NMIs
+static PERIOD_NS: AtomicU64 = AtomicU64::new(0);
+/// Bits of the counter
+static WIDTH: AtomicU64 = AtomicU64::new(0);
+static VERSION: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the counter runs on this CPU
+#[thread_local]
+static RUNNING: Cell<bool> = Cell::new(false);
+
+/// Load the counter to overflow after `CYCLES`, and unmask the LVT entry, which is masked when
+/// the NMI is delivered
+unsafe fn reload() {
+    let width = WIDTH.load(Ordering::Relaxed);
+    let cycles = CYCLES.load(Ordering::Relaxed);
+    wrmsr(IA32_PMC0, cycles.wrapping_neg() & ((1 << width) - 1));
+    if VERSION.load(Ordering::Relaxed) >= 2 {
+        wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 1);
+    }
+    LOCAL_APIC.set_lvt_pmi(LVT_NMI);
+}
+
+/// Start the counter on the current CPU, if `init` found it usable
+pub unsafe fn start() {
+    if CYCLES.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    wrmsr(IA32_PERFEVTSEL0, 0);
+    reload();
+    wrmsr(IA32_PERFEVTSEL0, EVENT_UNHALTED_CORE_CYCLES | EVTSEL_USR | EVTSEL_OS | EVTSEL_INT | EVTSEL_EN);
+    if VERSION.load(Ordering::Relaxed) >= 2 {
+        wrmsr(IA32_PERF_GLOBAL_CTRL, rdmsr(IA32_PERF_GLOBAL_CTRL) | 1);
+    }
+    RUNNING.set(true);
+}
+
+/// Stop the counter on the current CPU
+pub unsafe fn stop() {
+    if !RUNNING.get() {
+        return;
+    }
+    wrmsr(IA32_PERFEVTSEL0, 0);
+    RUNNING.set(false);
+}
+
+/// Start the watchdog on the BSP, once the TSC frequency is known. The APs, started before, start
+/// it on their next timer interrupt.
+pub unsafe fn init() {
+    if __cpuid(0).eax < 0xA {
+        return;
+    }
+    let leaf = __cpuid(0xA);
+    let version = leaf.eax & 0xFF;
+    let counters = (leaf.eax >> 8) & 0xFF;
+    let width = (leaf.eax >> 16) & 0xFF;
+    let events = (leaf.eax >> 24) & 0xFF;
+    if version == 0 || counters == 0 || width < 32 || events == 0 || leaf.ebx & CPUID_NO_CORE_CYCLES != 0 {
+        return;
+    }
+    let Some(khz) = tsc::khz() else {
+        return;
+    };
+
+    let cycles = (khz * PERIOD / 1_000_000).min((1 << 31) - 1);
+    PERIOD_NS.store(cycles * 1_000_000 / khz, Ordering::Relaxed);
+    WIDTH.store(u64::from(width), Ordering::Relaxed);
+    VERSION.store(u64::from(version), Ordering::Relaxed);
+    CYCLES.store(cycles, Ordering::Relaxed);
+
+    start();
+    crate::watchdog::nmi_started();
+    log::info!("NMI watchdog: every {} ms", PERIOD_NS.load(Ordering::Relaxed) / 1_000_000);
+}
+
+/// Start the counter on the current CPU if it does not run yet. Called on each timer tick.
+pub fn poll() {
+    if !RUNNING.get() {
+        unsafe { start() };
+    }
+}
+
+/// Handle an NMI raised by the counter, returning false if it was not
+pub unsafe fn handle(stack: &InterruptStack) -> bool {
+    if !RUNNING.get() {
+        return false;
+    }
+    // The counter is loaded negative, so its top bit is clear once it overflowed
+    let width = WIDTH.load(Ordering::Relaxed);
+    if rdmsr(IA32_PMC0) & (1 << (width - 1)) != 0 {
+        return false;
+    }
+    reload();
+
+    let period = PERIOD_NS.load(Ordering::Relaxed);
+    if crate::cpu_id() == 0 {
+        crate::watchdog::nmi(period);
+    }
+    crate::lockup::nmi(period, || stack.dump());
+    true
+}
@@ -0,0 +1,106 @@
+//! # Huge pages
+//! 2 MiB pages, mapped directly by page directory entries with the page size bit set. The generic
+//! mapper only knows about 4 KiB pages, and would interpret a huge page as a page table, so huge
+//! mappings must only be modified through these functions. Translation through `translate` falls
+//! back to the generic mapper for regular pages.
+
+use rmm::{Arch, FrameAllocator, PageEntry, PageFlags, PageFlush, PageMapper, PageTable, PhysicalAddress, VirtualAddress};
+
+/// Whether huge pages can be mapped
+pub const SUPPORTED: bool = true;
+
+/// Size of a huge page
+pub const HUGE_PAGE_SIZE: usize = 2 * rmm::MEGABYTE;
+
+/// Page size bit of page directory entries
+const ENTRY_FLAG_HUGE: usize = 1 << 7;
+
+/// Level of the page directory, whose entries map huge pages
+const HUGE_LEVEL: usize = 1;
+
+/// Walk to the page directory containing `virt`. Fails if `virt` is covered by a larger mapping.
+unsafe fn page_directory<A: Arch, F: FrameAllocator>(mapper: &PageMapper<A, F>, virt: VirtualAddress) -> Option<PageTable<A>> {
+    let mut table = mapper.table();
+    while table.level() > HUGE_LEVEL {
+        let i = table.index_of(virt)?;
+        if table.entry(i)?.data() & ENTRY_FLAG_HUGE != 0 {
+            return None;
+        }
+        table = table.next(i)?;
+    }
+    Some(table)
+}
+
+/// Walk to the page directory containing `virt`, allocating any missing tables
+unsafe fn create_page_directory<A: Arch, F: FrameAllocator>(mapper: &mut PageMapper<A, F>, virt: VirtualAddress) -> Option<PageTable<A>> {
+    let mut table = mapper.table();
+    while table.level() > HUGE_LEVEL {
+        let i = table.index_of(virt)?;
+        let entry = table.entry(i)?;
+        if !entry.present() {
+            let phys = mapper.allocator_mut().allocate_one()?;
+            table.set_entry(i, PageEntry::new(phys.data() | A::ENTRY_FLAG_READWRITE | A::ENTRY_FLAG_DEFAULT_TABLE))?;
+        } else if entry.data() & ENTRY_FLAG_HUGE != 0 {
+            return None;
+        }
+        table = table.next(i)?;
+    }
+    Some(table)
+}
+
+/// Map the huge page at `virt` to `phys`, both of which must be aligned to `HUGE_PAGE_SIZE`.
+/// Fails if any part of it is already mapped.
+pub unsafe fn map_phys<A: Arch, F: FrameAllocator>(mapper: &mut PageMapper<A, F>, virt: VirtualAddress, phys: PhysicalAddress, flags: PageFlags<A>) -> Option<PageFlush<A>> {
+    assert_eq!(virt.data() % HUGE_PAGE_SIZE, 0, "huge page virtual address not aligned");
+    assert_eq!(phys.data() % HUGE_PAGE_SIZE, 0, "huge page physical address not aligned");
+
+    let mut table = create_page_directory(mapper, virt)?;
+    let i = table.index_of(virt)?;
+    if table.entry(i)?.present() {
+        return None;
+    }
+    table.set_entry(i, PageEntry::new(phys.data() | flags.data() | ENTRY_FLAG_HUGE))?;
+    Some(PageFlush::new(virt))
+}
+
+/// Change the flags of the huge page at `virt`
+pub unsafe fn remap<A: Arch, F: FrameAllocator>(mapper: &mut PageMapper<A, F>, virt: VirtualAddress, flags: PageFlags<A>) -> Option<PageFlush<A>> {
+    let mut table = page_directory(mapper, virt)?;
+    let i = table.index_of(virt)?;
+    let entry = table.entry(i)?;
+    if !entry.present() || entry.data() & ENTRY_FLAG_HUGE == 0 {
+        return None;
+    }
+    let phys = entry.address().ok()?;
+    table.set_entry(i, PageEntry::new(phys.data() | flags.data() | ENTRY_FLAG_HUGE))?;
+    Some(PageFlush::new(virt))
+}
+
+/// Unmap the huge page at `virt`, returning the physical address and flags it was mapped with
+pub unsafe fn unmap_phys<A: Arch, F: FrameAllocator>(mapper: &mut PageMapper<A, F>, virt: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<A>, PageFlush<A>)> {
+    let mut table = page_directory(mapper, virt)?;
+    let i = table.index_of(virt)?;
+    let entry = table.entry(i)?;
+    if !entry.present() || entry.data() & ENTRY_FLAG_HUGE == 0 {
+        return None;
+    }
+    let phys = entry.address().ok()?;
+    let flags = PageFlags::from_data(entry.flags().data() & !ENTRY_FLAG_HUGE);
+    table.set_entry(i, PageEntry::new(0))?;
+    Some((phys, flags, PageFlush::new(virt)))
+}
+
+/// Translate the page containing `virt`, which may be part of either a huge or a regular page.
+/// Like the generic mapper, this returns the address of the 4 KiB frame containing `virt`.
+pub fn translate<A: Arch, F: FrameAllocator>(mapper: &PageMapper<A, F>, virt: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<A>)> {
+    unsafe {
+        if let Some(table) = page_directory(mapper, virt) {
+            let entry = table.entry(table.index_of(virt)?)?;
+            if entry.present() && entry.data() & ENTRY_FLAG_HUGE != 0 {
+                let phys = entry.address().ok()?.add(virt.data() % HUGE_PAGE_SIZE / A::PAGE_SIZE * A::PAGE_SIZE);
+                return Some((phys, PageFlags::from_data(entry.flags().data() & !ENTRY_FLAG_HUGE)));
+            }
+        }
+    }
+    mapper.translate(virt)
+}
@@ -1,6 +1,6 @@
 use crate::ipi::{ipi, IpiKind, IpiTarget};
 
-use super::RmmA;
+use super::{PageMapper, PhysicalAddress, RmmA, VirtualAddress};
 
 pub use rmm::{Flusher, PageFlush, PageFlushAll};
 
@@ -21,3 +21,44 @@ impl Drop for InactiveFlusher {
         ipi(IpiKind::Tlb, IpiTarget::Other);
     }
 }
+
+/// Flushes changes to a user page table from the TLB of every CPU running it, see `tlb`
+pub struct TlbShootdown {
+    table: PhysicalAddress,
+    local: bool,
+    range: Option<(VirtualAddress, usize)>,
+    dirty: bool,
+}
+impl TlbShootdown {
+    pub fn new(mapper: &PageMapper) -> Self {
+        Self {
+            table: mapper.table().phys(),
+            local: mapper.is_current(),
+            range: None,
+            dirty: false,
+        }
+    }
+    /// Only invalidate `page_count` pages from `start` on other CPUs, which must include every
+    /// page flushed
+    pub fn with_range(mut self, start: VirtualAddress, page_count: usize) -> Self {
+        self.range = Some((start, page_count));
+        self
+    }
+}
+impl Flusher<RmmA> for TlbShootdown {
+    fn consume(&mut self, flush: PageFlush<RmmA>) {
+        self.dirty = true;
+        if self.local {
+            flush.flush();
+        } else {
+            unsafe { flush.ignore(); }
+        }
+    }
+}
+impl Drop for TlbShootdown {
+    fn drop(&mut self) {
+        if self.dirty {
+            crate::arch::tlb::shootdown(self.table, self.range);
+        }
+    }
+}
@@ -0,0 +1,441 @@
+//! # Paravirtualization
+//! A hypervisor is detected from the CPUID leaves at `0x4000_0000`, and its paravirtualized
+//! facilities are used when running as its guest:
+//!
+//! - kvmclock, and the reference TSC page of Hyper-V, registered as clocksources (see `time`),
+//!   which stay correct when the vCPUs are migrated or the TSC is not stable
+//! - PV IPIs of KVM, sending an IPI to many CPUs in a single hypercall (see `ipi::ipi_mask`)
+//! - crash notification, through the crash MSRs of Hyper-V, or the pvpanic device of QEMU
+//!
+//! `sys:paravirt` reads as the hypervisor, and the facilities used.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::fmt::Write;
+use core::ptr::{self, addr_of};
+use core::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering};
+
+use spin::Once;
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::paging::{RmmA, RmmArch, PAGE_SIZE};
+use crate::syscall::error::Result;
+use crate::syscall::io::{Io, Pio};
+use crate::time::{self, Clocksource};
+
+/// Number of CPUs with a kvmclock area, others read that of the BSP
+pub const CPUS: usize = 32;
+
+// CPUID.01H:ECX bits
+const CPUID_HYPERVISOR: u32 = 1 << 31;
+
+const KVM_SIGNATURE: &[u8; 12] = b"KVMKVMKVM\0\0\0";
+const HYPERV_SIGNATURE: &[u8; 12] = b"Microsoft Hv";
+
+// KVM features, in EAX of the leaf after its signature
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const KVM_FEATURE_PV_SEND_IPI: u32 = 1 << 11;
+const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u32 = 1 << 24;
+
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+const KVM_HC_SEND_IPI: u64 = 10;
+
+/// Whether the kvmclock of every vCPU agrees, in `PvclockVcpuTimeInfo::flags`
+const PVCLOCK_TSC_STABLE_BIT: u8 = 1 << 0;
+
+// Hyper-V features, in CPUID.40000003H
+const HV_MSR_TIME_REF_COUNT_AVAILABLE: u32 = 1 << 1;
+const HV_MSR_REFERENCE_TSC_AVAILABLE: u32 = 1 << 9;
+const HV_FEATURE_GUEST_CRASH_MSR_AVAILABLE: u32 = 1 << 10;
+
+const HV_X64_MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+const HV_X64_MSR_TIME_REF_COUNT: u32 = 0x4000_0020;
+const HV_X64_MSR_REFERENCE_TSC: u32 = 0x4000_0021;
+const HV_X64_MSR_CRASH_P0: u32 = 0x4000_0100;
+const HV_X64_MSR_CRASH_CTL: u32 = 0x4000_0105;
+
+/// An open source guest, of no type Hyper-V knows
+const HV_GUEST_OS_ID_OPEN_SOURCE: u64 = 1 << 63;
+const HV_CRASH_CTL_CRASH_NOTIFY: u64 = 1 << 63;
+
+/// The pvpanic device of QEMU, and its event telling the guest panicked
+const PVPANIC_PORT: u16 = 0x505;
+const PVPANIC_PANICKED: u8 = 1 << 0;
+
+// Facilities used
+const KVMCLOCK: u32 = 1 << 0;
+const HV_TSC_PAGE: u32 = 1 << 1;
+const PV_IPI: u32 = 1 << 2;
+const HV_CRASH: u32 = 1 << 3;
+const PVPANIC: u32 = 1 << 4;
+
+const FACILITY_NAMES: [(u32, &str); 5] = [
+    (KVMCLOCK, "kvmclock"),
+    (HV_TSC_PAGE, "hv_tsc_page"),
+    (PV_IPI, "pv_ipi"),
+    (HV_CRASH, "hv_crash"),
+    (PVPANIC, "pvpanic"),
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Hypervisor {
+    Kvm,
+    HyperV,
+    Other,
+}
+
+impl Hypervisor {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hypervisor::Kvm => "kvm",
+            Hypervisor::HyperV => "hyperv",
+            Hypervisor::Other => "other",
+        }
+    }
+}
+
+struct Detected {
+    hypervisor: Option<Hypervisor>,
+    /// Features of KVM, which may also be found after those of Hyper-V it emulates
+    kvm_features: Option<u32>,
+    /// Features of Hyper-V, in EAX and EDX
+    hyperv_features: Option<(u32, u32)>,
+    /// Whether the hypercall instruction is `vmmcall`, on AMD
+    vmmcall: bool,
+}
+
+/// Time of a vCPU, updated by KVM
+#[repr(C)]
+struct PvclockVcpuTimeInfo {
+    /// Odd while being updated
+    version: u32,
+    _pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    _pad: [u8; 2],
+}
+
+/// Reference TSC page, updated by Hyper-V
+#[repr(C)]
+struct HvReferenceTscPage {
+    /// Zero while the page is not valid, then the time reference counter MSR is read instead
+    sequence: u32,
+    _reserved: u32,
+    scale: u64,
+    offset: i64,
+}
+
+static DETECTED: Once<Detected> = Once::new();
+
+static FACILITIES: AtomicU32 = AtomicU32::new(0);
+
+/// The kvmclock areas, one per CPU, and the reference TSC page, with their physical addresses
+static KVMCLOCK_AREAS: AtomicPtr<PvclockVcpuTimeInfo> = AtomicPtr::new(ptr::null_mut());
+static KVMCLOCK_PHYS: AtomicU64 = AtomicU64::new(0);
+static HV_TSC: AtomicPtr<HvReferenceTscPage> = AtomicPtr::new(ptr::null_mut());
+static HV_TSC_PHYS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether kvmclock agrees on each vCPU, and otherwise the latest time it read, so that it never
+/// goes backwards
+static KVMCLOCK_STABLE: AtomicBool = AtomicBool::new(false);
+static KVMCLOCK_LAST: AtomicU64 = AtomicU64::new(0);
+
+fn signature(leaf: u32) -> [u8; 12] {
+    let result = unsafe { __cpuid(leaf) };
+    let mut signature = [0; 12];
+    signature[..4].copy_from_slice(&result.ebx.to_le_bytes());
+    signature[4..8].copy_from_slice(&result.ecx.to_le_bytes());
+    signature[8..].copy_from_slice(&result.edx.to_le_bytes());
+    signature
+}
+
+fn detect() -> Detected {
+    let mut detected = Detected {
+        hypervisor: None,
+        kvm_features: None,
+        hyperv_features: None,
+        vmmcall: &signature(0) == b"AuthenticAMD" || &signature(0) == b"HygonGenuine",
+    };
+    if unsafe { __cpuid(1) }.ecx & CPUID_HYPERVISOR == 0 {
+        return detected;
+    }
+
+    detected.hypervisor = Some(match &signature(0x4000_0000) {
+        KVM_SIGNATURE => Hypervisor::Kvm,
+        HYPERV_SIGNATURE => Hypervisor::HyperV,
+        _ => Hypervisor::Other,
+    });
+    // KVM may come after the leaves of the Hyper-V it emulates
+    for base in [0x4000_0000, 0x4000_0100] {
+        if &signature(base) == KVM_SIGNATURE {
+            detected.kvm_features = Some(unsafe { __cpuid(base + 1) }.eax);
+            break;
+        }
+    }
+    if detected.hypervisor == Some(Hypervisor::HyperV) && unsafe { __cpuid(0x4000_0000) }.eax >= 0x4000_0003 {
+        let leaf = unsafe { __cpuid(0x4000_0003) };
+        detected.hyperv_features = Some((leaf.eax, leaf.edx));
+    }
+    detected
+}
+
+/// Allocate a zeroed page, returning its physical and virtual addresses
+unsafe fn allocate_page() -> Option<(usize, usize)> {
+    let frame = crate::memory::allocate_frames(1)?;
+    let virt = RmmA::phys_to_virt(frame.start_address()).data();
+    ptr::write_bytes(virt as *mut u8, 0, PAGE_SIZE);
+    Some((frame.start_address().data(), virt))
+}
+
+fn kvmclock_nanoseconds() -> u128 {
+    let areas = KVMCLOCK_AREAS.load(Ordering::Relaxed);
+    let cpu_id = crate::cpu_id();
+    let info = unsafe { areas.add(if cpu_id < CPUS { cpu_id } else { 0 }) };
+
+    let nanoseconds = loop {
+        let version = unsafe { ptr::read_volatile(addr_of!((*info).version)) };
+        if version & 1 == 1 {
+            core::hint::spin_loop();
+            continue;
+        }
+        fence(Ordering::Acquire);
+        let (tsc_timestamp, system_time, mul, shift) = unsafe {
+            (
+                ptr::read_volatile(addr_of!((*info).tsc_timestamp)),
+                ptr::read_volatile(addr_of!((*info).system_time)),
+                ptr::read_volatile(addr_of!((*info).tsc_to_system_mul)),
+                ptr::read_volatile(addr_of!((*info).tsc_shift)),
+            )
+        };
+        let tsc = unsafe { _rdtsc() };
+        fence(Ordering::Acquire);
+        if unsafe { ptr::read_volatile(addr_of!((*info).version)) } != version {
+            continue;
+        }
+
+        let delta = tsc.wrapping_sub(tsc_timestamp);
+        let delta = if shift < 0 { delta >> u32::from(shift.unsigned_abs()) } else { delta << shift as u32 };
+        break system_time.wrapping_add(((u128::from(delta) * u128::from(mul)) >> 32) as u64);
+    };
+
+    if KVMCLOCK_STABLE.load(Ordering::Relaxed) {
+        u128::from(nanoseconds)
+    } else {
+        u128::from(KVMCLOCK_LAST.fetch_max(nanoseconds, Ordering::Relaxed).max(nanoseconds))
+    }
+}
+
+fn hv_tsc_page_nanoseconds() -> u128 {
+    let page = HV_TSC.load(Ordering::Relaxed);
+    loop {
+        let sequence = unsafe { ptr::read_volatile(addr_of!((*page).sequence)) };
+        if sequence == 0 {
+            // Counts in units of 100 ns
+            return u128::from(unsafe { rdmsr(HV_X64_MSR_TIME_REF_COUNT) }) * 100;
+        }
+        fence(Ordering::Acquire);
+        let (scale, offset) = unsafe {
+            (ptr::read_volatile(addr_of!((*page).scale)), ptr::read_volatile(addr_of!((*page).offset)))
+        };
+        let tsc = unsafe { _rdtsc() };
+        fence(Ordering::Acquire);
+        if unsafe { ptr::read_volatile(addr_of!((*page).sequence)) } != sequence {
+            continue;
+        }
+
+        let count = ((u128::from(tsc) * u128::from(scale)) >> 64) as u64;
+        return u128::from(count.wrapping_add(offset as u64)) * 100;
+    }
+}
+
+/// Set up the kvmclock of KVM on `cpu_id`, the BSP allocating the areas and registering it
+unsafe fn init_kvmclock(cpu_id: usize, features: u32) {
+    if features & KVM_FEATURE_CLOCKSOURCE2 == 0 || cpu_id >= CPUS {
+        return;
+    }
+    if cpu_id == 0 && KVMCLOCK_AREAS.load(Ordering::SeqCst).is_null() {
+        let Some((phys, virt)) = allocate_page() else {
+            log::warn!("kvmclock: failed to allocate its areas");
+            return;
+        };
+        KVMCLOCK_AREAS.store(virt as *mut PvclockVcpuTimeInfo, Ordering::SeqCst);
+        KVMCLOCK_PHYS.store(phys as u64, Ordering::SeqCst);
+    }
+    let phys = KVMCLOCK_PHYS.load(Ordering::SeqCst);
+    if phys == 0 {
+        return;
+    }
+    let area = phys + (cpu_id * core::mem::size_of::<PvclockVcpuTimeInfo>()) as u64;
+    wrmsr(MSR_KVM_SYSTEM_TIME_NEW, area | 1);
+
+    if cpu_id == 0 {
+        let info = KVMCLOCK_AREAS.load(Ordering::SeqCst);
+        let flags = ptr::read_volatile(addr_of!((*info).flags));
+        let stable = features & KVM_FEATURE_CLOCKSOURCE_STABLE_BIT != 0 && flags & PVCLOCK_TSC_STABLE_BIT != 0;
+        KVMCLOCK_STABLE.store(stable, Ordering::SeqCst);
+        FACILITIES.fetch_or(KVMCLOCK, Ordering::SeqCst);
+        time::register(Clocksource {
+            name: "kvmclock",
+            rating: 400,
+            read: kvmclock_nanoseconds,
+            verify: false,
+        });
+    }
+}
+
+/// Set up the reference TSC page of Hyper-V, which is shared by every CPU
+unsafe fn init_hv_tsc_page(eax: u32) {
+    let needed = HV_MSR_REFERENCE_TSC_AVAILABLE | HV_MSR_TIME_REF_COUNT_AVAILABLE;
+    if eax & needed != needed {
+        return;
+    }
+    if HV_TSC.load(Ordering::SeqCst).is_null() {
+        let Some((phys, virt)) = allocate_page() else {
+            log::warn!("Hyper-V: failed to allocate the reference TSC page");
+            return;
+        };
+        HV_TSC.store(virt as *mut HvReferenceTscPage, Ordering::SeqCst);
+        HV_TSC_PHYS.store(phys as u64, Ordering::SeqCst);
+    }
+    wrmsr(HV_X64_MSR_REFERENCE_TSC, HV_TSC_PHYS.load(Ordering::SeqCst) | 1);
+
+    FACILITIES.fetch_or(HV_TSC_PAGE, Ordering::SeqCst);
+    time::register(Clocksource {
+        name: "hv_tsc_page",
+        rating: 400,
+        read: hv_tsc_page_nanoseconds,
+        verify: false,
+    });
+}
+
+/// Detect the hypervisor on the BSP, and set up its facilities on `cpu_id`. Called again after
+/// resuming from S3, which lost the MSRs.
+pub unsafe fn init(cpu_id: usize) {
+    let detected = DETECTED.call_once(detect);
+    let Some(hypervisor) = detected.hypervisor else {
+        return;
+    };
+
+    if let Some(features) = detected.kvm_features {
+        init_kvmclock(cpu_id, features);
+        if cpu_id == 0 && features & KVM_FEATURE_PV_SEND_IPI != 0 {
+            FACILITIES.fetch_or(PV_IPI, Ordering::SeqCst);
+        }
+    }
+
+    if cpu_id != 0 {
+        return;
+    }
+
+    if let Some((eax, edx)) = detected.hyperv_features {
+        // Nothing else may be used before the guest identifies itself
+        wrmsr(HV_X64_MSR_GUEST_OS_ID, HV_GUEST_OS_ID_OPEN_SOURCE);
+        if detected.kvm_features.is_none() {
+            init_hv_tsc_page(eax);
+        }
+        if edx & HV_FEATURE_GUEST_CRASH_MSR_AVAILABLE != 0 {
+            FACILITIES.fetch_or(HV_CRASH, Ordering::SeqCst);
+        }
+    }
+
+    // The port reads as the events supported, or all ones without the device
+    let events = Pio::<u8>::new(PVPANIC_PORT).read();
+    if events != 0xFF && events & PVPANIC_PANICKED != 0 {
+        FACILITIES.fetch_or(PVPANIC, Ordering::SeqCst);
+    }
+
+    log::info!("Hypervisor: {} ({})", hypervisor.name(), facilities().join(" "));
+}
+
+fn facilities() -> Vec<&'static str> {
+    let facilities = FACILITIES.load(Ordering::Relaxed);
+    FACILITY_NAMES.iter().filter(|&&(bit, _)| facilities & bit != 0).map(|&(_, name)| name).collect()
+}
+
+/// The hypervisor the kernel runs on, if any
+pub fn hypervisor() -> Option<Hypervisor> {
+    DETECTED.get().and_then(|detected| detected.hypervisor)
+}
+
+/// Send the IPI `vector` to each CPU whose bit is set in `mask` in a single hypercall, as CPU IDs
+/// are local APIC IDs. Returns false if PV IPIs are not available.
+pub fn send_ipi_mask(vector: u8, mask: u64) -> bool {
+    if FACILITIES.load(Ordering::Relaxed) & PV_IPI == 0 {
+        return false;
+    }
+    let Some(detected) = DETECTED.get() else {
+        return false;
+    };
+
+    // The bitmaps of APIC IDs from the minimum one, and the ICR, with fixed delivery
+    let (low, high, min, icr) = (mask, 0_u64, 0_u64, u64::from(vector));
+    let ret: i64;
+    unsafe {
+        // RBX is reserved by LLVM, so the first argument is swapped into it
+        if detected.vmmcall {
+            asm!(
+                "xchg {low}, rbx",
+                "vmmcall",
+                "xchg {low}, rbx",
+                low = inout(reg) low => _,
+                inlateout("rax") KVM_HC_SEND_IPI => ret,
+                in("rcx") high,
+                in("rdx") min,
+                in("rsi") icr,
+                options(nostack),
+            );
+        } else {
+            asm!(
+                "xchg {low}, rbx",
+                "vmcall",
+                "xchg {low}, rbx",
+                low = inout(reg) low => _,
+                inlateout("rax") KVM_HC_SEND_IPI => ret,
+                in("rcx") high,
+                in("rdx") min,
+                in("rsi") icr,
+                options(nostack),
+            );
+        }
+    }
+    ret >= 0
+}
+
+/// Tell the hypervisor the kernel panicked on `cpu_id`, for it to report or restart the guest
+pub fn crash(cpu_id: usize) {
+    let facilities = FACILITIES.load(Ordering::Relaxed);
+    if facilities & HV_CRASH != 0 {
+        unsafe {
+            for i in 0..5 {
+                wrmsr(HV_X64_MSR_CRASH_P0 + i, if i == 0 { cpu_id as u64 } else { 0 });
+            }
+            wrmsr(HV_X64_MSR_CRASH_CTL, HV_CRASH_CTL_CRASH_NOTIFY);
+        }
+    }
+    if facilities & PVPANIC != 0 {
+        Pio::<u8>::new(PVPANIC_PORT).write(PVPANIC_PANICKED);
+    }
+}
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+    match hypervisor() {
+        Some(hypervisor) => {
+            let _ = writeln!(string, "hypervisor: {}", hypervisor.name());
+        }
+        None => string.push_str("hypervisor: none\n"),
+    }
+    for name in facilities() {
+        let _ = write!(string, "{}", name);
+        if name == "kvmclock" && !KVMCLOCK_STABLE.load(Ordering::Relaxed) {
+            string.push_str(" unstable");
+        }
+        string.push('\n');
+    }
+    Ok(string.into_bytes())
+}
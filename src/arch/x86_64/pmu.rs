@@ -0,0 +1,145 @@
+//! # Performance counters
+//! The general-purpose counters of architectural performance monitoring, programmed for `perf`.
+//! The first counter is left to the NMI watchdog (see `nmi`), so counter `n` of `perf` is
+//! `IA32_PMC<n + 1>`. Counters raising an interrupt on overflow share the NMI of the watchdog.
+//!
+//! Only the architectural events are supported, and periods are limited to 2^31 events, as writes
+//! to the counters are sign-extended from 32 bits.
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use x86::msr::{rdmsr, wrmsr};
+
+use super::device::local_apic::LOCAL_APIC;
+use crate::perf::Event;
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38E;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+/// Delivery mode of the performance counter LVT entry
+const LVT_NMI: u32 = 0b100 << 8;
+
+/// Longest period of a counter raising interrupts
+pub const PERIOD_MAX: u64 = (1 << 31) - 1;
+
+/// Counters usable by `perf`, zero until `init`
+static COUNTERS: AtomicU32 = AtomicU32::new(0);
+/// Bits of the counters
+static WIDTH: AtomicU64 = AtomicU64::new(0);
+static VERSION: AtomicU32 = AtomicU32::new(0);
+/// CPUID.0AH:EBX, with a bit set for each architectural event that is not available
+static UNAVAILABLE: AtomicU32 = AtomicU32::new(!0);
+
+/// The event select and unit mask of `event`, and its bit in CPUID.0AH:EBX
+fn encoding(event: Event) -> (u64, u32) {
+    match event {
+        Event::Cycles => (0x003C, 0),
+        Event::Instructions => (0x00C0, 1),
+        Event::CacheReferences => (0x4F2E, 3),
+        Event::CacheMisses => (0x412E, 4),
+        Event::Branches => (0x00C4, 5),
+        Event::BranchMisses => (0x00C5, 6),
+    }
+}
+
+/// Find the counters, on the BSP
+pub unsafe fn init() {
+    if __cpuid(0).eax < 0xA {
+        return;
+    }
+    let leaf = __cpuid(0xA);
+    let version = leaf.eax & 0xFF;
+    let counters = (leaf.eax >> 8) & 0xFF;
+    let width = (leaf.eax >> 16) & 0xFF;
+    let events = (leaf.eax >> 24) & 0xFF;
+    if version == 0 || counters < 2 || width < 32 {
+        return;
+    }
+
+    // Events past the length of EBX are not available either
+    let unavailable = leaf.ebx | !((1_u32 << events.min(31)) - 1);
+    UNAVAILABLE.store(unavailable, Ordering::Relaxed);
+    WIDTH.store(u64::from(width), Ordering::Relaxed);
+    VERSION.store(version, Ordering::Relaxed);
+    COUNTERS.store(counters - 1, Ordering::Relaxed);
+    log::info!("PMU: {} counters of {} bits, version {}", counters - 1, width, version);
+}
+
+/// Number of counters usable by `perf`
+pub fn counters() -> usize {
+    COUNTERS.load(Ordering::Relaxed) as usize
+}
+
+/// Returns true if `event` can be counted
+pub fn supported(event: Event) -> bool {
+    UNAVAILABLE.load(Ordering::Relaxed) & (1 << encoding(event).1) == 0
+}
+
+/// Mask of the bits of a counter
+pub fn mask() -> u64 {
+    (1 << WIDTH.load(Ordering::Relaxed)) - 1
+}
+
+/// Start counter `index` at `value`, counting `event`, and raising an NMI when it overflows if
+/// `interrupt` is set
+pub unsafe fn start(index: usize, event: Event, value: u64, interrupt: bool) {
+    let hw = index as u32 + 1;
+    wrmsr(IA32_PERFEVTSEL0 + hw, 0);
+    wrmsr(IA32_PMC0 + hw, value & mask());
+    let mut select = encoding(event).0 | EVTSEL_USR | EVTSEL_OS | EVTSEL_EN;
+    if interrupt {
+        select |= EVTSEL_INT;
+        LOCAL_APIC.set_lvt_pmi(LVT_NMI);
+    }
+    wrmsr(IA32_PERFEVTSEL0 + hw, select);
+    if VERSION.load(Ordering::Relaxed) >= 2 {
+        wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 1 << hw);
+        wrmsr(IA32_PERF_GLOBAL_CTRL, rdmsr(IA32_PERF_GLOBAL_CTRL) | 1 << hw);
+    }
+}
+
+/// Stop counter `index`, returning its value
+pub unsafe fn stop(index: usize) -> u64 {
+    let hw = index as u32 + 1;
+    wrmsr(IA32_PERFEVTSEL0 + hw, 0);
+    if VERSION.load(Ordering::Relaxed) >= 2 {
+        wrmsr(IA32_PERF_GLOBAL_CTRL, rdmsr(IA32_PERF_GLOBAL_CTRL) & !(1 << hw));
+    }
+    read(index)
+}
+
+/// The value of counter `index`
+pub unsafe fn read(index: usize) -> u64 {
+    rdmsr(IA32_PMC0 + index as u32 + 1)
+}
+
+/// Returns true if counter `index`, started at a negative value, overflowed
+pub unsafe fn overflowed(index: usize) -> bool {
+    let hw = index as u32 + 1;
+    if VERSION.load(Ordering::Relaxed) >= 2 {
+        rdmsr(IA32_PERF_GLOBAL_STATUS) & 1 << hw != 0
+    } else {
+        // The top bit is clear once it overflowed
+        read(index) & 1 << (WIDTH.load(Ordering::Relaxed) - 1) == 0
+    }
+}
+
+/// Restart counter `index` at `value` after it overflowed, and unmask the LVT entry, which is
+/// masked when the NMI is delivered
+pub unsafe fn reload(index: usize, value: u64) {
+    let hw = index as u32 + 1;
+    wrmsr(IA32_PMC0 + hw, value & mask());
+    if VERSION.load(Ordering::Relaxed) >= 2 {
+        wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 1 << hw);
+    }
+    LOCAL_APIC.set_lvt_pmi(LVT_NMI);
+}
@@ -1,86 +1,164 @@
+//! # Kernel page-table isolation
+//! Mitigates Meltdown-class leaks of kernel memory to userspace, by not mapping the kernel while
+//! userspace runs. With the `pti` feature, each address space has a second, user PML4. Its lower
+//! half is the same as that of the regular PML4, but its higher half only maps the entry area: the
+//! code of the interrupt and syscall entries, the IDTs, and the PCR, entry stack and IST stacks of
+//! each CPU. The user PML4 is loaded right before returning to userspace, and the entry code
+//! switches back to the regular PML4 before anything else.
+//!
+//! Interrupts from userspace arrive on the entry stack of the CPU, set in its TSS. Once the page
+//! tables have been switched, the entry code moves the interrupt frame to the kernel stack of the
+//! context, and the return to userspace moves it back. The syscall instruction does not use a
+//! stack, and switches page tables right away. Paranoid interrupts can interrupt the kernel while
+//! it still runs on the user PML4, and save and restore CR3 instead.
+//!
+//! rmm only knows of the regular PML4, so the entries of the user PML4 are synchronized before
+//! each return to userspace. Without PCIDs, every switch flushes the TLB, which makes syscalls and
+//! interrupts considerably slower.
+
 #[cfg(feature = "pti")]
 use core::ptr;
+#[cfg(feature = "pti")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(feature = "pti")]
 
 #[cfg(feature = "pti")]
-use crate::memory::Frame;
+use crate::gdt::{self, ProcessorControlRegion};
 #[cfg(feature = "pti")]
-use crate::paging::ActivePageTable;
+use crate::memory::{allocate_frames, PAGE_SIZE};
 #[cfg(feature = "pti")]
-use crate::paging::entry::EntryFlags;
+use crate::paging::{huge, KernelMapper, PageFlags, PageMapper, PhysicalAddress, RmmA, RmmArch, TableKind, VirtualAddress, ENTRY_COUNT};
+use crate::sync::Mutex;
 
+/// Size of the entry stack of each CPU, which paranoid interrupts of the entry code also run on
 #[cfg(feature = "pti")]
-#[thread_local]
-pub static mut PTI_CPU_STACK: [u8; 256] = [0; 256];
+pub const ENTRY_STACK_PAGES: usize = 4;
 
+/// The page tables mapping the entry area, created by the BSP
+#[cfg(feature = "pti")]
+static ENTRY_TABLE: Mutex<Option<PageMapper>> = Mutex::new(None);
+/// Physical address of the PML4 of `ENTRY_TABLE`, whose higher half is copied into the user PML4s
 #[cfg(feature = "pti")]
-#[thread_local]
-pub static mut PTI_CONTEXT_STACK: usize = 0;
+static ENTRY_PML4: AtomicUsize = AtomicUsize::new(0);
 
+/// Map the kernel pages in `start..end` into the entry area, at the same addresses
 #[cfg(feature = "pti")]
-#[inline(always)]
-unsafe fn switch_stack(old: usize, new: usize) {
-    let old_rsp: usize;
-    asm!("", out("rsp") old_rsp);
+unsafe fn map_entry_area(start: usize, end: usize, flags: PageFlags<RmmA>) {
+    let kernel_mapper = KernelMapper::lock();
+    let mut entry_table = ENTRY_TABLE.lock();
+    let mapper = entry_table.get_or_insert_with(|| {
+        let mapper = PageMapper::create(TableKind::Kernel, crate::rmm::FRAME_ALLOCATOR).expect("failed to allocate PTI entry table");
+        ENTRY_PML4.store(mapper.table().phys().data(), Ordering::SeqCst);
+        mapper
+    });
+
+    for page in (start / PAGE_SIZE * PAGE_SIZE..end).step_by(PAGE_SIZE) {
+        let virt = VirtualAddress::new(page);
+        if mapper.translate(virt).is_some() {
+            continue;
+        }
+        let (phys, _) = huge::translate(&kernel_mapper, virt).expect("PTI entry area is not mapped in the kernel");
+        // The entry table is never active, so there is nothing to flush
+        mapper.map_phys(virt, phys, flags).expect("failed to map PTI entry area").ignore();
+    }
+}
+
+/// Map the entry code, and the PCR, IST stacks and a new entry stack of the current CPU, into the
+/// entry area. Called on each CPU once its PCR is set up and its IST stacks are allocated.
+#[cfg(feature = "pti")]
+pub unsafe fn init(pcr: &mut ProcessorControlRegion) {
+    extern "C" {
+        static __entry_start: u8;
+        static __entry_end: u8;
+    }
+    let entry_code = (&__entry_start as *const u8 as usize)..(&__entry_end as *const u8 as usize);
+    map_entry_area(entry_code.start, entry_code.end, PageFlags::new().execute(true));
+
+    let pcr_start = pcr as *mut ProcessorControlRegion as usize;
+    map_entry_area(pcr_start, pcr_start + core::mem::size_of::<ProcessorControlRegion>(), PageFlags::new().write(true));
+
+    let stack_frame = allocate_frames(ENTRY_STACK_PAGES).expect("failed to allocate PTI entry stack");
+    let stack_start = RmmA::phys_to_virt(stack_frame.start_address()).data();
+    let stack_end = stack_start + ENTRY_STACK_PAGES * PAGE_SIZE;
+    map_entry_area(stack_start, stack_end, PageFlags::new().write(true));
 
-    let offset_rsp = old - old_rsp;
+    // Interrupts from userspace arrive on the entry stack, the kernel stack is in `pti_rsp0`
+    ptr::addr_of_mut!(pcr.tss.rsp[0]).write(stack_end as u64);
 
-    let new_rsp = new - offset_rsp;
+    // Interrupts with a stack of their own, such as NMIs, may also arrive while userspace runs
+    let ist = ptr::addr_of!(pcr.tss.ist).read_unaligned();
+    for stack_end in ist.into_iter().filter(|&stack_end| stack_end != 0) {
+        let stack_end = stack_end as usize;
+        map_entry_area(stack_end - gdt::IST_STACK_SIZE, stack_end, PageFlags::new().write(true));
+    }
 
-    ptr::copy_nonoverlapping(
-        old_rsp as *const u8,
-        new_rsp as *mut u8,
-        offset_rsp
-    );
+    let table = RmmA::table(TableKind::Kernel).data();
+    pcr.pti_kernel_cr3 = table;
+    pcr.pti_user_cr3 = table;
+}
+
+/// Map an IDT into the entry area, as the CPU reads it while userspace runs
+#[cfg(feature = "pti")]
+pub unsafe fn map_idt(idt: *const crate::idt::Idt) {
+    map_entry_area(idt as usize, idt as usize + core::mem::size_of::<crate::idt::Idt>(), PageFlags::new());
+}
 
-    asm!("", out("rsp") new_rsp);
+/// Allocate the user PML4 of a new address space
+#[cfg(feature = "pti")]
+pub fn new_user_table() -> Option<PhysicalAddress> {
+    let frame = allocate_frames(1)?;
+    unsafe { ptr::write_bytes(RmmA::phys_to_virt(frame.start_address()).data() as *mut u8, 0, PAGE_SIZE) };
+    Some(frame.start_address())
 }
 
+/// Set the page tables to switch between when entering and leaving the kernel on this CPU. Called
+/// when switching address spaces.
 #[cfg(feature = "pti")]
-#[inline(always)]
-pub unsafe fn map() {
-    // {
-    //     let mut active_table = unsafe { ActivePageTable::new() };
-    //
-    //     // Map kernel heap
-    //     let address = active_table.p4()[::KERNEL_HEAP_PML4].address();
-    //     let frame = Frame::containing_address(address);
-    //     let mut flags = active_table.p4()[::KERNEL_HEAP_PML4].flags();
-    //     flags.remove(EntryFlags::PRESENT);
-    //     active_table.p4_mut()[::KERNEL_HEAP_PML4].set(frame, flags);
-    //
-    //     // Reload page tables
-    //     active_table.flush_all();
-    // }
-
-    // Switch to per-context stack
-    switch_stack(PTI_CPU_STACK.as_ptr() as usize + PTI_CPU_STACK.len(), PTI_CONTEXT_STACK);
+pub unsafe fn set_tables(kernel_table: PhysicalAddress, user_table: PhysicalAddress) {
+    let pcr = gdt::pcr();
+    ptr::addr_of_mut!((*pcr).pti_kernel_cr3).write(kernel_table.data());
+    ptr::addr_of_mut!((*pcr).pti_user_cr3).write(user_table.data());
 }
 
+/// Update the user PML4 of the current address space from the regular one, and the entry area.
+/// Called before returning to userspace.
+#[cfg(feature = "pti")]
+pub unsafe extern "C" fn sync() {
+    let pcr = gdt::pcr();
+    let kernel_table = ptr::addr_of!((*pcr).pti_kernel_cr3).read();
+    let user_table = ptr::addr_of!((*pcr).pti_user_cr3).read();
+    let entry_table = ENTRY_PML4.load(Ordering::Relaxed);
+    // Kernel contexts never return to userspace
+    if user_table == kernel_table || entry_table == 0 {
+        return;
+    }
+
+    let entries = |table: usize| RmmA::phys_to_virt(PhysicalAddress::new(table)).data() as *mut u64;
+    let (kernel, user, entry) = (entries(kernel_table), entries(user_table), entries(entry_table));
+    for i in 0..ENTRY_COUNT {
+        let source = if i < ENTRY_COUNT / 2 { kernel } else { entry };
+        let value = source.add(i).read_volatile();
+        if user.add(i).read_volatile() != value {
+            user.add(i).write_volatile(value);
+        }
+    }
+}
+
+/// Synchronizes the user PML4 when dropped, at the end of an interrupt or syscall handler, however
+/// it returns
+#[cfg(feature = "pti")]
+pub struct SyncGuard;
+
 #[cfg(feature = "pti")]
-#[inline(always)]
-pub unsafe extern "C" fn unmap() {
-    // Switch to per-CPU stack
-    switch_stack(PTI_CONTEXT_STACK, PTI_CPU_STACK.as_ptr() as usize + PTI_CPU_STACK.len());
-
-    // {
-    //     let mut active_table = unsafe { ActivePageTable::new() };
-    //
-    //     // Unmap kernel heap
-    //     let address = active_table.p4()[::KERNEL_HEAP_PML4].address();
-    //     let frame = Frame::containing_address(address);
-    //     let mut flags = active_table.p4()[::KERNEL_HEAP_PML4].flags();
-    //     flags.insert(EntryFlags::PRESENT);
-    //     active_table.p4_mut()[::KERNEL_HEAP_PML4].set(frame, flags);
-    //
-    //     // Reload page tables
-    //     active_table.flush_all();
-    // }
+impl Drop for SyncGuard {
+    fn drop(&mut self) {
+        unsafe { sync() };
+    }
 }
 
 #[cfg(not(feature = "pti"))]
-#[inline(always)]
-pub unsafe fn map() {}
+pub unsafe extern "C" fn sync() {}
 
 #[cfg(not(feature = "pti"))]
-#[inline(always)]
-pub unsafe extern "C" fn unmap() {}
+pub struct SyncGuard;
@@ -8,7 +8,6 @@ use rmm::{
     KILOBYTE,
     MEGABYTE,
     Arch,
-    BuddyAllocator,
     BumpAllocator,
     FrameAllocator,
     FrameCount,
@@ -20,9 +19,13 @@ use rmm::{
     TableKind,
     VirtualAddress,
 };
-use spin::Mutex;
+
+use crate::memory::buddy::BuddyAllocator;
+use crate::memory::{firmware, numa, zone};
+use crate::sync::Mutex;
 
 use super::CurrentRmmArch as RmmA;
+use super::paging::huge::{self, HUGE_PAGE_SIZE};
 
 // Keep synced with OsMemoryKind in bootloader
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -59,6 +62,48 @@ unsafe fn page_flags<A: Arch>(virt: VirtualAddress) -> PageFlags<A> {
     }
 }
 
+/// Map `area` at PHYS_OFFSET, using huge pages for the parts of it that cover entire huge pages,
+/// to reduce TLB pressure
+unsafe fn map_area<A: Arch, F: FrameAllocator>(mapper: &mut PageMapper<A, F>, area: &MemoryArea) -> Option<()> {
+    let mut i = 0;
+    while i < area.size / A::PAGE_SIZE {
+        let phys = area.base.add(i * A::PAGE_SIZE);
+        let virt = A::phys_to_virt(phys);
+        let flags = page_flags::<A>(virt);
+        if phys.data() % HUGE_PAGE_SIZE == 0 && area.size - i * A::PAGE_SIZE >= HUGE_PAGE_SIZE {
+            huge::map_phys(mapper, virt, phys, flags)?.ignore(); // Not the active table
+            i += HUGE_PAGE_SIZE / A::PAGE_SIZE;
+            continue;
+        }
+        mapper.map_phys(virt, phys, flags)?.ignore(); // Not the active table
+        i += 1;
+    }
+    Some(())
+}
+
+/// Map the kernel at KERNEL_OFFSET, and at PHYS_OFFSET too
+unsafe fn map_kernel<A: Arch, F: FrameAllocator>(mapper: &mut PageMapper<A, F>, kernel_base: usize, kernel_size_aligned: usize) -> Option<()> {
+    for i in 0..kernel_size_aligned / A::PAGE_SIZE {
+        let phys = PhysicalAddress::new(kernel_base + i * A::PAGE_SIZE);
+        let virt = VirtualAddress::new(crate::KERNEL_OFFSET + i * A::PAGE_SIZE);
+        mapper.map_phys(virt, phys, page_flags::<A>(virt))?.ignore(); // Not the active table
+
+        let virt = A::phys_to_virt(phys);
+        mapper.map_phys(virt, phys, page_flags::<A>(virt))?.ignore(); // Not the active table
+    }
+    Some(())
+}
+
+/// Map `size_aligned` bytes of memory at `base` at PHYS_OFFSET
+unsafe fn map_linear<A: Arch, F: FrameAllocator>(mapper: &mut PageMapper<A, F>, base: usize, size_aligned: usize) -> Option<()> {
+    for i in 0..size_aligned / A::PAGE_SIZE {
+        let phys = PhysicalAddress::new(base + i * A::PAGE_SIZE);
+        let virt = A::phys_to_virt(phys);
+        mapper.map_phys(virt, phys, page_flags::<A>(virt))?.ignore(); // Not the active table
+    }
+    Some(())
+}
+
 unsafe fn inner<A: Arch>(
     areas: &'static [MemoryArea],
     kernel_base: usize, kernel_size_aligned: usize,
@@ -89,59 +134,27 @@ unsafe fn inner<A: Arch>(
 
         // Map all physical areas at PHYS_OFFSET
         for area in areas.iter() {
-            for i in 0..area.size / A::PAGE_SIZE {
-                let phys = area.base.add(i * A::PAGE_SIZE);
-                let virt = A::phys_to_virt(phys);
-                let flags = page_flags::<A>(virt);
-                let flush = mapper.map_phys(
-                    virt,
-                    phys,
-                    flags
-                ).expect("failed to map frame");
-                flush.ignore(); // Not the active table
-            }
+            map_area(&mut mapper, area).expect("failed to map area");
         }
 
         // Map kernel at KERNEL_OFFSET and identity map too
-        for i in 0..kernel_size_aligned / A::PAGE_SIZE {
-            let phys = PhysicalAddress::new(kernel_base + i * A::PAGE_SIZE);
-            let virt = VirtualAddress::new(crate::KERNEL_OFFSET + i * A::PAGE_SIZE);
-            let flags = page_flags::<A>(virt);
-            let flush = mapper.map_phys(
-                virt,
-                phys,
-                flags
-            ).expect("failed to map frame");
-            flush.ignore(); // Not the active table
+        map_kernel(&mut mapper, kernel_base, kernel_size_aligned).expect("failed to map kernel");
+
+        map_linear(&mut mapper, stack_base, stack_size_aligned).expect("failed to map stack");
+        map_linear(&mut mapper, env_base, env_size_aligned).expect("failed to map env");
+        map_linear(&mut mapper, acpi_base, acpi_size_aligned).expect("failed to map acpi");
+        map_linear(&mut mapper, initfs_base, initfs_size_aligned).expect("failed to map initfs");
 
+        // Map the memory kept out of the allocator for zones, see `memory::zone`
+        zone::for_each_free_frame(|phys| {
             let virt = A::phys_to_virt(phys);
             let flush = mapper.map_phys(
                 virt,
                 phys,
-                flags
+                page_flags::<A>(virt)
             ).expect("failed to map frame");
             flush.ignore(); // Not the active table
-        }
-
-        let mut identity_map = |base, size_aligned| {
-            // Map with identity mapping
-            for i in 0..size_aligned / A::PAGE_SIZE {
-                let phys = PhysicalAddress::new(base + i * A::PAGE_SIZE);
-                let virt = A::phys_to_virt(phys);
-                let flags = page_flags::<A>(virt);
-                let flush = mapper.map_phys(
-                    virt,
-                    phys,
-                    flags
-                ).expect("failed to map frame");
-                flush.ignore(); // Not the active table
-            }
-        };
-
-        identity_map(stack_base, stack_size_aligned);
-        identity_map(env_base, env_size_aligned);
-        identity_map(acpi_base, acpi_size_aligned);
-        identity_map(initfs_base, initfs_size_aligned);
+        });
 
         // Ensure graphical debug region remains paged
         #[cfg(feature = "graphical_debug")]
@@ -184,7 +197,7 @@ unsafe fn inner<A: Arch>(
     let offset = bump_allocator.offset();
     log::info!("Permanently used: {} KB", (offset + (KILOBYTE - 1)) / KILOBYTE);
 
-    BuddyAllocator::<A>::new(bump_allocator).expect("failed to create BuddyAllocator")
+    BuddyAllocator::<A>::new(areas, offset).expect("failed to create BuddyAllocator")
 }
 
 // There can only be one allocator (at the moment), so making this a ZST is great!
@@ -195,14 +208,33 @@ static INNER_ALLOCATOR: Mutex<Option<BuddyAllocator<RmmA>>> = Mutex::new(None);
 
 impl FrameAllocator for LockedAllocator {
     unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
-        if let Some(ref mut allocator) = *INNER_ALLOCATOR.lock() {
-            allocator.allocate(count)
+        // Single frames come from the free list of the current CPU's node first
+        if count.data() == 1 {
+            if let Some(address) = numa::allocate_local() {
+                return Some(address);
+            }
+        }
+        let address = if let Some(ref mut allocator) = *INNER_ALLOCATOR.lock() {
+            match allocator.allocate(count) {
+                // The missing frames may be on the node free lists
+                None if count.data() > 1 && numa::drain(|address| allocator.free(address, FrameCount::new(1))) > 0 => {
+                    allocator.allocate(count)
+                }
+                address => address,
+            }
         } else {
             None
+        };
+        match address {
+            None if count.data() == 1 => numa::allocate_remote(),
+            address => address,
         }
     }
 
     unsafe fn free(&mut self, address: PhysicalAddress, count: FrameCount) {
+        if count.data() == 1 && numa::free(address) {
+            return;
+        }
         if let Some(ref mut allocator) = *INNER_ALLOCATOR.lock() {
             allocator.free(address, count)
         }
@@ -210,7 +242,9 @@ impl FrameAllocator for LockedAllocator {
 
     unsafe fn usage(&self) -> FrameUsage {
         if let Some(ref allocator) = *INNER_ALLOCATOR.lock() {
-            allocator.usage()
+            // Frames on the node free lists are free, although the allocator does not know
+            let usage = allocator.usage();
+            FrameUsage::new(FrameCount::new(usage.used().data() - numa::free_frames()), usage.total())
         } else {
             FrameUsage::new(FrameCount::new(0), FrameCount::new(0))
         }
@@ -309,6 +343,84 @@ impl Drop for KernelMapper {
     }
 }
 
+/// The memory handed over by the bootloader that is kept in use, outside of the areas: the
+/// kernel, stack, env, ACPI tables and initfs, as physical start and end addresses
+static mut BOOT_DATA: [(usize, usize); 5] = [(0, 0); 5];
+
+/// Every range of memory the kernel may use, as physical start and end addresses, other than the
+/// zones: the memory handed over by the bootloader, then the areas
+pub fn for_each_ram_range(mut f: impl FnMut(usize, usize)) {
+    unsafe {
+        for &(start, end) in BOOT_DATA.iter() {
+            f(start, end);
+        }
+        for area in AREAS.iter().filter(|area| area.size > 0) {
+            f(area.base.data(), area.base.data() + area.size);
+        }
+    }
+}
+
+/// Returns true if the frame at `address` is memory the kernel may use, including the zones
+pub fn is_ram(address: PhysicalAddress) -> bool {
+    let mut ram = false;
+    for_each_ram_range(|start, end| ram |= (start..end).contains(&address.data()));
+    if !ram {
+        zone::for_each_frame(|frame, _| ram |= frame == address);
+    }
+    ram
+}
+
+/// Every frame that may be in use: the memory handed over by the bootloader, the frames of the
+/// areas that are not free in the frame allocator, and the used frames of the zones. The first
+/// frame of each free block is included too, as it holds the links of the free lists. `f` must
+/// not allocate or free frames.
+pub fn for_each_used_frame(mut f: impl FnMut(PhysicalAddress)) {
+    type A = RmmA;
+
+    unsafe {
+        for &(start, end) in BOOT_DATA.iter() {
+            for frame in (start..end).step_by(A::PAGE_SIZE) {
+                f(PhysicalAddress::new(frame));
+            }
+        }
+
+        let allocator = INNER_ALLOCATOR.lock();
+        for area in AREAS.iter() {
+            for i in 0..area.size / A::PAGE_SIZE {
+                let frame = area.base.add(i * A::PAGE_SIZE);
+                match allocator.as_ref().and_then(|allocator| allocator.containing_free_block(frame)) {
+                    Some(block) if block != frame => (),
+                    _ => f(frame),
+                }
+            }
+        }
+    }
+
+    zone::for_each_frame(|frame, used| if used {
+        f(frame)
+    });
+}
+
+/// Map all memory the kernel may use like the kernel page table does, in a new page table
+pub unsafe fn map_ram<F: FrameAllocator>(mapper: &mut PageMapper<RmmA, F>) -> Option<()> {
+    type A = RmmA;
+
+    for area in AREAS.iter() {
+        map_area(mapper, area)?;
+    }
+    let (kernel_base, kernel_end) = BOOT_DATA[0];
+    map_kernel(mapper, kernel_base, kernel_end - kernel_base)?;
+    for &(start, end) in BOOT_DATA[1..].iter() {
+        map_linear(mapper, start, end - start)?;
+    }
+
+    let mut result = Some(());
+    zone::for_each_frame(|frame, _| if result.is_some() {
+        result = map_linear(mapper, frame.data(), A::PAGE_SIZE);
+    });
+    result
+}
+
 pub unsafe fn init(
     kernel_base: usize, kernel_size: usize,
     stack_base: usize, stack_size: usize,
@@ -343,12 +455,38 @@ pub unsafe fn init(
         areas_size / mem::size_of::<BootloaderMemoryEntry>()
     );
 
+    // Memory that was in use while booting, and must not be given to the low zone
+    let boot_data = [
+        (kernel_base, kernel_end),
+        (stack_base, stack_end),
+        (env_base, env_end),
+        (acpi_base, acpi_end),
+        (initfs_base, initfs_end),
+    ];
+    BOOT_DATA = boot_data;
+
+    // Devices that only address 32 bits need a pool of memory below 4 GiB, if there is memory above
+    let high_memory = bootloader_areas.iter().any(|area| {
+        { area.kind } == BootloaderMemoryKind::Free && area.base + area.size > zone::DMA32_END
+    });
+    let mut dma32_pool = !high_memory;
+
     // Copy memory map from bootloader location, and page align it
     let mut area_i = 0;
     for bootloader_area in bootloader_areas.iter() {
-        if { bootloader_area.kind } != BootloaderMemoryKind::Free {
+        match { bootloader_area.kind } {
+            BootloaderMemoryKind::Free => (),
+            // Kept for reading through `physmem:`
+            BootloaderMemoryKind::Reclaim => {
+                firmware::add(bootloader_area.base, bootloader_area.size, firmware::Kind::Reclaim);
+                continue;
+            }
+            BootloaderMemoryKind::Reserved => {
+                firmware::add(bootloader_area.base, bootloader_area.size, firmware::Kind::Reserved);
+                continue;
+            }
             // Not a free area
-            continue;
+            BootloaderMemoryKind::Null => continue,
         }
 
         let mut base = bootloader_area.base as usize;
@@ -371,6 +509,13 @@ pub unsafe fn init(
 
         let mut new_base = base;
 
+        // Keep free real-mode memory for the low zone
+        for frame in (base..cmp::min(base + size, real_end)).step_by(A::PAGE_SIZE) {
+            if !boot_data.iter().any(|&(start, end)| frame < end && frame + A::PAGE_SIZE > start) {
+                zone::add_low(PhysicalAddress::new(frame));
+            }
+        }
+
         // Ensure real-mode areas are not used
         if base < real_end && base + size > real_base {
             log::warn!("{:X}:{:X} overlaps with real mode {:X}:{:X}", base, size, real_base, real_size);
@@ -415,6 +560,15 @@ pub unsafe fn init(
             size = new_size;
         }
 
+        // Take the DMA32 pool from the end of the first large enough area below 4 GiB
+        let pool_size = zone::POOL_FRAMES * A::PAGE_SIZE;
+        if !dma32_pool && (base + size) as u64 <= zone::DMA32_END && size >= 2 * pool_size {
+            size -= pool_size;
+            log::info!("DMA32 pool {:X}:{:X}", base + size, pool_size);
+            zone::add_dma32(PhysicalAddress::new(base + size), zone::POOL_FRAMES);
+            dma32_pool = true;
+        }
+
         if size == 0 {
             // Area is zero sized, skip
             continue;
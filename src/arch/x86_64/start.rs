@@ -10,7 +10,17 @@ use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use crate::allocator;
 #[cfg(feature = "acpi")]
 use crate::acpi;
+use crate::arch::cpufreq;
+use crate::arch::idle;
+use crate::arch::mce;
+use crate::arch::mitigations;
+use crate::arch::nmi;
+use crate::arch::paravirt;
+use crate::arch::pmu;
+use crate::arch::xsave;
 use crate::arch::pti;
+use crate::arch::thermal;
+use crate::arch::tlb;
 use crate::arch::flags::*;
 use crate::device;
 #[cfg(feature = "graphical_debug")]
@@ -41,12 +51,12 @@ static BSP_READY: AtomicBool = AtomicBool::new(false);
 
 #[repr(packed)]
 pub struct KernelArgs {
-    kernel_base: u64,
-    kernel_size: u64,
-    stack_base: u64,
-    stack_size: u64,
-    env_base: u64,
-    env_size: u64,
+    pub kernel_base: u64,
+    pub kernel_size: u64,
+    pub stack_base: u64,
+    pub stack_size: u64,
+    pub env_base: u64,
+    pub env_size: u64,
 
     /// The base 64-bit pointer to an array of saved RSDPs. It's up to the kernel (and possibly
     /// userspace), to decide which RSDP to use. The buffer will be a linked list containing a
@@ -55,19 +65,19 @@ pub struct KernelArgs {
     /// This field can be NULL, and if so, the system has not booted with UEFI or in some other way
     /// retrieved the RSDPs. The kernel or a userspace driver will thus try searching the BIOS
     /// memory instead. On UEFI systems, BIOS-like searching is not guaranteed to actually work though.
-    acpi_rsdps_base: u64,
+    pub acpi_rsdps_base: u64,
     /// The size of the RSDPs region.
-    acpi_rsdps_size: u64,
+    pub acpi_rsdps_size: u64,
 
-    areas_base: u64,
-    areas_size: u64,
+    pub areas_base: u64,
+    pub areas_size: u64,
 
     /// The physical base 64-bit pointer to the contiguous bootstrap/initfs.
-    bootstrap_base: u64,
+    pub bootstrap_base: u64,
     /// Size of contiguous bootstrap/initfs physical region, not necessarily page aligned.
-    bootstrap_size: u64,
+    pub bootstrap_size: u64,
     /// Entry point the kernel will jump to.
-    bootstrap_entry: u64,
+    pub bootstrap_entry: u64,
 }
 
 /// The entry to Rust, all things must be initialized
@@ -88,6 +98,13 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
         // Convert env to slice
         let env = slice::from_raw_parts((args.env_base as usize + crate::PHYS_OFFSET) as *const u8, args.env_size as usize);
 
+        // Set up the debug sinks that need no memory management, all of which receive early output
+        #[cfg(feature = "qemu_debug")]
+        log::set_sinks_ready(log::Sinks::QEMU, true);
+
+        #[cfg(feature = "serial_debug")]
+        device::serial::init_early();
+
         // Set up graphical debug
         #[cfg(feature = "graphical_debug")]
         graphical_debug::init(env);
@@ -99,13 +116,14 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
         log::init_logger(|r| {
             use core::fmt::Write;
             let _ = writeln!(
-                super::debug::Writer::new(),
+                super::debug::Writer::with(log::syslog_level(r.level()), log::FACILITY_KERNEL),
                 "{}:{} -- {}",
                 r.target(),
                 r.level(),
                 r.args()
             );
         });
+        log::configure(env);
 
         info!("Redox OS starting...");
         info!("Kernel: {:X}:{:X}", { args.kernel_base }, { args.kernel_base } + { args.kernel_size });
@@ -162,21 +180,53 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
         // Setup kernel heap
         allocator::init();
 
+        // Reserve memory for the crash kernel, and keep the RSDPs to hand them over to it
+        crate::kexec::reserve(env);
+        super::kexec::set_rsdps(args.acpi_rsdps_base as usize, args.acpi_rsdps_size as usize);
+
         // Set up double buffer for grpahical debug now that heap is available
         #[cfg(feature = "graphical_debug")]
         graphical_debug::init_heap();
 
         idt::init_paging_post_heap(true, 0);
 
-        // Activate memory logging
-        log::init();
-
         // Initialize miscellaneous processor features
         misc::init();
 
+        // Enable the extended registers saved on context switches
+        xsave::init(true);
+
+        // Detect speculative execution mitigations
+        mitigations::configure(env);
+        mitigations::init(0);
+
+        // Detect performance states
+        cpufreq::init(0);
+
+        // Detect thermal sensors
+        thermal::init(0);
+
+        // Detect idle states
+        idle::init();
+
+        // Receive TLB shootdowns
+        tlb::init(0);
+
+        // Detect the hypervisor, and use its paravirtualized clocks
+        paravirt::init(0);
+
+        // Enable machine checks
+        mce::init();
+
         // Initialize devices
         device::init();
 
+        // Listen for GDB on its serial port, if asked to
+        crate::debugger::gdbstub::configure(env);
+
+        // Drive the virtio console and entropy device until userspace drivers start
+        crate::devices::virtio::init();
+
         // Read ACPI tables, starts APs
         #[cfg(feature = "acpi")]
         {
@@ -186,11 +236,20 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
                 None
             });
             device::init_after_acpi();
+
+            // Load the ACPI namespace, once the timers it may wait on are running
+            acpi::aml::init();
         }
 
         // Initialize all of the non-core devices not otherwise needed to complete initialization
         device::init_noncore();
 
+        // Raise NMIs for the watchdog and lockup detection, once the TSC is calibrated
+        nmi::init();
+
+        // Find the performance counters left to perf
+        pmu::init();
+
         // Stop graphical debug
         #[cfg(feature = "graphical_debug")]
         graphical_debug::fini();
@@ -200,6 +259,7 @@ pub unsafe extern fn kstart(args_ptr: *const KernelArgs) -> ! {
         crate::Bootstrap {
             base: crate::memory::Frame::containing_address(crate::paging::PhysicalAddress::new(args.bootstrap_base as usize)),
             page_count: (args.bootstrap_size as usize) / crate::memory::PAGE_SIZE,
+            size: args.bootstrap_size as usize,
             entry: args.bootstrap_entry as usize,
             env,
         }
@@ -250,6 +310,27 @@ pub unsafe extern fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
         // Initialize miscellaneous processor features
         misc::init();
 
+        // Enable the extended registers saved on context switches
+        xsave::init(false);
+
+        // Detect speculative execution mitigations
+        mitigations::init(cpu_id);
+
+        // Detect performance states
+        cpufreq::init(cpu_id);
+
+        // Detect thermal sensors
+        thermal::init(cpu_id);
+
+        // Receive TLB shootdowns
+        tlb::init(cpu_id);
+
+        // Use the paravirtualized clocks of the hypervisor
+        paravirt::init(cpu_id);
+
+        // Enable machine checks
+        mce::init();
+
         // Test tdata and tbss
         {
             assert_eq!(TBSS_TEST_ZERO.get(), 0);
@@ -275,16 +356,16 @@ pub unsafe extern fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
     crate::kmain_ap(cpu_id);
 }
 
-#[cfg(not(feature = "pit"))]
-macro_rules! inner_pit_unmap(
+#[cfg(not(feature = "pti"))]
+macro_rules! usermode_pti_sync(
     () => {
         "
-            // unused: {pti_unmap}
+            // unused: {pti_sync} {PTI_USER_CR3}
         "
     }
 );
-#[cfg(feature = "pit")]
-macro_rules! inner_pit_unmap(
+#[cfg(feature = "pti")]
+macro_rules! usermode_pti_sync(
     () => {
         "
             push rdi
@@ -293,13 +374,28 @@ macro_rules! inner_pit_unmap(
             push rcx
             sub rsp, 8
 
-            call {pti_unmap}
+            call {pti_sync}
 
             add rsp, 8
             pop rcx
             pop rdx
             pop rsi
             pop rdi
+
+            // Loaded into CR3 once the kernel stack is no longer used
+            mov rbp, gs:[{PTI_USER_CR3}]
+        "
+    }
+);
+#[cfg(not(feature = "pti"))]
+macro_rules! usermode_pti_switch(
+    () => { "" }
+);
+#[cfg(feature = "pti")]
+macro_rules! usermode_pti_switch(
+    () => {
+        "
+            mov cr3, rbp
         "
     }
 );
@@ -363,6 +459,7 @@ macro_rules! restore_fsgsbase(
 );
 
 #[naked]
+#[link_section = ".entry-text"]
 // TODO: AbiCompatBool
 pub unsafe extern "C" fn usermode(_ip: usize, _sp: usize, _arg: usize, _is_singlestep: usize) -> ! {
     // rdi, rsi, rdx, rcx
@@ -371,7 +468,7 @@ pub unsafe extern "C" fn usermode(_ip: usize, _sp: usize, _arg: usize, _is_singl
             shl rcx, {shift_singlestep}
             or rcx, {flag_interrupts}
 
-            ", inner_pit_unmap!(), "
+            ", usermode_pti_sync!(), "
 
             // Save rdx for later
             mov r12, rdx
@@ -399,6 +496,7 @@ pub unsafe extern "C" fn usermode(_ip: usize, _sp: usize, _arg: usize, _is_singl
             mov rcx, rdi
             // Target stack pointer
             mov rsp, rsi
+            ", usermode_pti_switch!(), "
             // Target argument
             mov rdi, r12
 
@@ -432,7 +530,8 @@ pub unsafe extern "C" fn usermode(_ip: usize, _sp: usize, _arg: usize, _is_singl
 
         flag_interrupts = const(FLAG_INTERRUPTS),
         shift_singlestep = const(SHIFT_SINGLESTEP),
-        pti_unmap = sym pti::unmap,
+        pti_sync = sym pti::sync,
+        PTI_USER_CR3 = const(memoffset::offset_of!(gdt::ProcessorControlRegion, pti_user_cr3)),
         user_data_seg_selector = const(gdt::GDT_USER_DATA << 3 | 3),
 
         MSR_FSBASE = const(x86::msr::IA32_FS_BASE),
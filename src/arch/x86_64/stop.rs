@@ -1,6 +1,8 @@
 #[cfg(feature = "acpi")]
 use crate::{
+    acpi::fadt::FADT,
     context,
+    power,
     scheme::acpi,
     time,
 };
@@ -11,6 +13,13 @@ use crate::syscall::io::{Io, Pio};
 pub unsafe extern fn kreset() -> ! {
     println!("kreset");
 
+    // Reset register of the FADT
+    #[cfg(feature = "acpi")]
+    if let Some(fadt) = FADT.get().filter(|fadt| fadt.has_reset_reg()) {
+        println!("Reset with ACPI reset register");
+        fadt.reset();
+    }
+
     // 8042 reset
     {
         println!("Reset with 8042");
@@ -64,6 +73,10 @@ fn userspace_acpi_shutdown() {
 pub unsafe extern fn kstop() -> ! {
     log::info!("Running kstop()");
 
+    // ACPI S5, if the ACPI driver set its sleep types
+    #[cfg(feature = "acpi")]
+    power::enter_s5();
+
     #[cfg(feature = "acpi")]
     userspace_acpi_shutdown();
 
@@ -0,0 +1,380 @@
+//! # Suspend to RAM
+//! Entering S3 loses the state of every CPU, but not the memory. Each CPU saves its control
+//! registers, descriptor tables and callee-saved registers with `save`, which returns false, and
+//! returns true a second time once the state was restored after resuming.
+//!
+//! The firmware resumes the BSP in real mode at the waking vector of the FACS, which is the AP
+//! trampoline. It enters long mode with the kernel page table and jumps to `resume_entry`, which
+//! restores the saved state. The BSP then restores the devices, and restarts each AP through the
+//! trampoline the same way.
+//!
+//! Hibernation saves the state the same way, and takes its image right after (see `hibernate`).
+//! Restoring the image ends by jumping to `resume_entry` directly.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use rmm::{FrameAllocator, PageMapper, TableKind};
+use x86::controlregs;
+use x86::msr;
+
+use crate::acpi::fadt::FADT;
+use crate::acpi::madt;
+use crate::device;
+use crate::gdt;
+use crate::hibernate::RestorePage;
+use crate::hotplug::CPUS;
+use crate::interrupt;
+use crate::memory::PAGE_SIZE;
+use crate::paging::{PhysicalAddress, RmmA, RmmArch};
+use crate::syscall::error::{Error, Result, EIO, ENODEV, ENOMEM};
+
+/// A descriptor table register, as stored by SGDT and SIDT
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct TablePointer {
+    limit: u16,
+    base: u64,
+}
+
+/// The state of a CPU that does not survive S3, other than what the per-CPU initialization sets up
+/// again (see `restore`)
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct CpuState {
+    // Saved and restored by `save_registers` and `resume_entry`, the stack pointing to the return
+    // address of `save_registers`
+    rsp: usize,
+    rbx: usize,
+    rbp: usize,
+    r12: usize,
+    r13: usize,
+    r14: usize,
+    r15: usize,
+    gdtr: TablePointer,
+    idtr: TablePointer,
+
+    cr0: usize,
+    cr3: usize,
+    cr4: usize,
+    efer: u64,
+    fs_base: u64,
+    gs_base: u64,
+    kernel_gs_base: u64,
+}
+
+impl CpuState {
+    const fn new() -> Self {
+        const TABLE: TablePointer = TablePointer { limit: 0, base: 0 };
+        Self {
+            rsp: 0, rbx: 0, rbp: 0, r12: 0, r13: 0, r14: 0, r15: 0,
+            gdtr: TABLE, idtr: TABLE,
+            cr0: 0, cr3: 0, cr4: 0, efer: 0, fs_base: 0, gs_base: 0, kernel_gs_base: 0,
+        }
+    }
+}
+
+/// The saved state of each CPU, by CPU ID
+static mut STATES: [CpuState; CPUS] = {
+    const STATE: CpuState = CpuState::new();
+    [STATE; CPUS]
+};
+
+/// CPUs that restored their state, cleared before resuming them
+static RESUMED: AtomicU64 = AtomicU64::new(0);
+
+/// Returns true if the ACPI tables allow entering S3
+pub fn supported() -> bool {
+    FADT.get().map_or(false, |fadt| fadt.pm1a_control_block != 0 && fadt.facs().is_some())
+}
+
+/// Save the state of the current CPU, with interrupts disabled. Returns false after saving, and
+/// true once the CPU resumed and its state was restored.
+pub unsafe fn save(cpu_id: usize) -> bool {
+    let state = match STATES.get_mut(cpu_id) {
+        Some(state) => state as *mut CpuState,
+        None => return false,
+    };
+
+    (*state).cr0 = controlregs::cr0().bits();
+    (*state).cr3 = controlregs::cr3() as usize;
+    (*state).cr4 = controlregs::cr4().bits();
+    (*state).efer = msr::rdmsr(msr::IA32_EFER);
+    (*state).fs_base = msr::rdmsr(msr::IA32_FS_BASE);
+    (*state).gs_base = msr::rdmsr(msr::IA32_GS_BASE);
+    (*state).kernel_gs_base = msr::rdmsr(msr::IA32_KERNEL_GSBASE);
+
+    if save_registers(state) == 0 {
+        return false;
+    }
+
+    restore(cpu_id);
+    RESUMED.fetch_or(1 << cpu_id, Ordering::SeqCst);
+    true
+}
+
+/// Set up again what the per-CPU initialization in `start` sets up on the current CPU, other than
+/// what `resume_entry` restored
+unsafe fn restore(cpu_id: usize) {
+    // The TSS descriptor was left busy, which LTR refuses
+    gdt::reload_tss();
+
+    super::paging::init();
+    interrupt::syscall::init();
+    super::misc::init();
+    super::xsave::init(false);
+    super::mitigations::init(cpu_id);
+    super::cpufreq::init(cpu_id);
+    super::mce::init();
+    super::paravirt::init(cpu_id);
+    device::local_apic::init_ap();
+    super::nmi::start();
+}
+
+/// Enter S3 on the BSP, after every AP was stopped with its state saved. `slp_typa` and
+/// `slp_typb` are the sleep types of the `\_S3` object. Returns once resumed, or if S3 could not
+/// be entered, with the APs in `aps` restarted.
+pub unsafe fn enter(slp_typa: u8, slp_typb: u8, aps: u64) -> Result<()> {
+    let fadt = FADT.get().ok_or(Error::new(ENODEV))?;
+    if !fadt.set_waking_vector(madt::TRAMPOLINE as u32) {
+        return Err(Error::new(ENODEV));
+    }
+
+    match saved(aps, || fadt.enter_sleep_state(slp_typa, slp_typb)) {
+        None => Ok(()),
+        Some(()) => {
+            // Still running, the firmware did not enter S3
+            log::error!("Failed to enter S3");
+            Err(Error::new(EIO))
+        }
+    }
+}
+
+/// Save the state of the BSP, after every AP was stopped with its state saved, and run `f` with the
+/// devices suspended. Returns the result of `f`, or `None` once the saved state was resumed, by the
+/// firmware or from a hibernation image, with the APs in `aps` restarted either way.
+pub unsafe fn saved<T>(aps: u64, f: impl FnOnce() -> T) -> Option<T> {
+    // The trampoline is the waking vector, and the stack is not used, as `resume_entry` switches to
+    // the saved one right away
+    let page_table = madt::map_trampoline();
+    madt::set_trampoline_args(STATES.as_ptr() as u64, page_table, 0, 0, resume_entry as usize);
+
+    device::suspend();
+
+    let result = if save(0) {
+        None
+    } else {
+        Some(f())
+    };
+
+    device::resume();
+
+    start_aps(aps, page_table);
+    madt::unmap_trampoline();
+    result
+}
+
+/// Restart the APs in `aps` from their saved state, through the mapped trampoline
+unsafe fn start_aps(aps: u64, page_table: usize) {
+    // CPU IDs are local APIC IDs
+    for cpu_id in (1..CPUS).filter(|cpu_id| aps & (1 << cpu_id) != 0) {
+        print!("AP {}:", cpu_id);
+        madt::start_ap(cpu_id as u32, STATES.as_ptr().add(cpu_id) as u64, page_table, 0, 0, resume_entry as usize);
+        while RESUMED.load(Ordering::SeqCst) & (1 << cpu_id) == 0 {
+            interrupt::pause();
+        }
+        println!(" Resumed");
+    }
+    RESUMED.store(0, Ordering::SeqCst);
+}
+
+/// Restore a hibernation image on the BSP, after every AP in `aps` was stopped, by copying the pages
+/// of the restore list starting at `list` (see `hibernate::RestorePage`) to their frames and
+/// resuming the state saved in the image. `allocator` must only hand out frames that are not part
+/// of the image, like those of the list. Only returns if the page table to copy with could not be
+/// created, with the APs restarted.
+pub unsafe fn restore_image<F: FrameAllocator>(list: PhysicalAddress, mut allocator: F, aps: u64) -> Error {
+    // Frames of the running kernel are overwritten while copying, so switch to a page table and
+    // stack in frames that are not. Its code and read-only data are identical to those restored.
+    let prepared = allocator.allocate_one().and_then(|stack| {
+        let mut mapper = PageMapper::<RmmA, _>::create(TableKind::Kernel, &mut allocator)?;
+        super::rmm::map_ram(&mut mapper)?;
+        Some((stack, mapper.table().phys()))
+    });
+    let (stack, table) = match prepared {
+        Some(prepared) => prepared,
+        None => {
+            let page_table = madt::map_trampoline();
+            madt::set_trampoline_args(STATES.as_ptr() as u64, page_table, 0, 0, resume_entry as usize);
+            start_aps(aps, page_table);
+            madt::unmap_trampoline();
+            return Error::new(ENOMEM);
+        }
+    };
+
+    // The address of the BSP state, for `resume_entry`, at the bottom of the stack
+    let state = RmmA::phys_to_virt(stack).data() as *mut usize;
+    state.write(STATES.as_ptr() as usize);
+
+    // Clearing the global pages flag flushes the global pages of the running kernel too, which the
+    // restored one maps differently
+    core::arch::asm!(
+        "
+        mov rax, cr4
+        btr rax, 7
+        mov cr4, rax
+        mov cr3, rdx
+        mov rsp, rcx
+        call {copy_image}
+        ",
+        copy_image = sym copy_image,
+        in("rdi") list.data(),
+        in("rsi") state,
+        in("rdx") table.data(),
+        in("rcx") state as usize + PAGE_SIZE,
+        options(noreturn),
+    );
+}
+
+/// Copy the pages of the restore list `list`, then resume the BSP state whose address is at
+/// `state`. Runs on the page table and stack set up by `restore_image`.
+unsafe extern "C" fn copy_image(list: usize, state: usize) -> ! {
+    let mut next = list;
+    while next != 0 {
+        let page = &*(RmmA::phys_to_virt(PhysicalAddress::new(next)).data() as *const RestorePage);
+        for &[frame, contents] in &page.entries[..page.count] {
+            ptr::copy_nonoverlapping(
+                RmmA::phys_to_virt(PhysicalAddress::new(contents)).data() as *const u8,
+                RmmA::phys_to_virt(PhysicalAddress::new(frame)).data() as *mut u8,
+                PAGE_SIZE,
+            );
+        }
+        next = page.next;
+    }
+
+    core::arch::asm!(
+        "jmp {resume_entry}",
+        resume_entry = sym resume_entry,
+        in("rdi") state,
+        options(noreturn),
+    );
+}
+
+/// Save the callee-saved registers and descriptor tables to `state`, returning zero. Returns
+/// nonzero when `resume_entry` restored them.
+#[naked]
+unsafe extern "C" fn save_registers(_state: *mut CpuState) -> usize {
+    core::arch::asm!(
+        "
+        mov [rdi + {rsp}], rsp
+        mov [rdi + {rbx}], rbx
+        mov [rdi + {rbp}], rbp
+        mov [rdi + {r12}], r12
+        mov [rdi + {r13}], r13
+        mov [rdi + {r14}], r14
+        mov [rdi + {r15}], r15
+        sgdt [rdi + {gdtr}]
+        sidt [rdi + {idtr}]
+        xor eax, eax
+        ret
+        ",
+        rsp = const memoffset::offset_of!(CpuState, rsp),
+        rbx = const memoffset::offset_of!(CpuState, rbx),
+        rbp = const memoffset::offset_of!(CpuState, rbp),
+        r12 = const memoffset::offset_of!(CpuState, r12),
+        r13 = const memoffset::offset_of!(CpuState, r13),
+        r14 = const memoffset::offset_of!(CpuState, r14),
+        r15 = const memoffset::offset_of!(CpuState, r15),
+        gdtr = const memoffset::offset_of!(CpuState, gdtr),
+        idtr = const memoffset::offset_of!(CpuState, idtr),
+        options(noreturn),
+    );
+}
+
+/// Entered from the trampoline in long mode with the kernel page table, and the address of a
+/// pointer to the `CpuState` to restore in rdi. Returns from `save_registers` a second time.
+#[naked]
+unsafe extern "C" fn resume_entry() {
+    core::arch::asm!(
+        "
+        mov rdi, [rdi]
+
+        mov rax, [rdi + {cr4}]
+        mov cr4, rax
+        mov rax, [rdi + {cr0}]
+        mov cr0, rax
+        mov ecx, {efer_msr}
+        mov eax, [rdi + {efer}]
+        mov edx, [rdi + {efer} + 4]
+        wrmsr
+        mov rax, [rdi + {cr3}]
+        mov cr3, rax
+
+        // The stack below the saved one is free, and the trampoline did not set up any
+        mov rsp, [rdi + {rsp}]
+
+        lgdt [rdi + {gdtr}]
+        lidt [rdi + {idtr}]
+
+        // Reload CS from the GDT of the PCR, then the other segments as in gdt::init
+        push {kernel_cs}
+        lea rax, [rip + 1f]
+        push rax
+        retfq
+    1:
+        mov ax, {kernel_ds}
+        mov ss, ax
+        xor eax, eax
+        mov ds, ax
+        mov es, ax
+        mov fs, ax
+        mov gs, ax
+
+        // Loading the null selectors may have reset the bases
+        mov ecx, {fs_base_msr}
+        mov eax, [rdi + {fs_base}]
+        mov edx, [rdi + {fs_base} + 4]
+        wrmsr
+        mov ecx, {gs_base_msr}
+        mov eax, [rdi + {gs_base}]
+        mov edx, [rdi + {gs_base} + 4]
+        wrmsr
+        mov ecx, {kernel_gs_base_msr}
+        mov eax, [rdi + {kernel_gs_base}]
+        mov edx, [rdi + {kernel_gs_base} + 4]
+        wrmsr
+
+        mov rbx, [rdi + {rbx}]
+        mov rbp, [rdi + {rbp}]
+        mov r12, [rdi + {r12}]
+        mov r13, [rdi + {r13}]
+        mov r14, [rdi + {r14}]
+        mov r15, [rdi + {r15}]
+        mov eax, 1
+        ret
+        ",
+        cr0 = const memoffset::offset_of!(CpuState, cr0),
+        cr3 = const memoffset::offset_of!(CpuState, cr3),
+        cr4 = const memoffset::offset_of!(CpuState, cr4),
+        efer = const memoffset::offset_of!(CpuState, efer),
+        fs_base = const memoffset::offset_of!(CpuState, fs_base),
+        gs_base = const memoffset::offset_of!(CpuState, gs_base),
+        kernel_gs_base = const memoffset::offset_of!(CpuState, kernel_gs_base),
+        rsp = const memoffset::offset_of!(CpuState, rsp),
+        rbx = const memoffset::offset_of!(CpuState, rbx),
+        rbp = const memoffset::offset_of!(CpuState, rbp),
+        r12 = const memoffset::offset_of!(CpuState, r12),
+        r13 = const memoffset::offset_of!(CpuState, r13),
+        r14 = const memoffset::offset_of!(CpuState, r14),
+        r15 = const memoffset::offset_of!(CpuState, r15),
+        gdtr = const memoffset::offset_of!(CpuState, gdtr),
+        idtr = const memoffset::offset_of!(CpuState, idtr),
+        efer_msr = const msr::IA32_EFER,
+        fs_base_msr = const msr::IA32_FS_BASE,
+        gs_base_msr = const msr::IA32_GS_BASE,
+        kernel_gs_base_msr = const msr::IA32_KERNEL_GSBASE,
+        kernel_cs = const gdt::GDT_KERNEL_CODE << 3,
+        kernel_ds = const gdt::GDT_KERNEL_DATA << 3,
+        options(noreturn),
+    );
+}
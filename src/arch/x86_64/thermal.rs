@@ -0,0 +1,119 @@
+//! # Digital thermal sensors
+//! Temperatures of each core and of the package, from the digital thermal sensors of Intel CPUs.
+//! They report how far below TjMax they are, the temperature at which the CPU throttles itself.
+//! Each CPU reads its own core sensor, and the BSP the package sensor (see `thermal`).
+
+use core::arch::x86_64::__cpuid_count;
+use core::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use x86::msr;
+
+/// Number of CPUs whose sensor is tracked
+pub const CPUS: usize = 32;
+
+// CPUID.0:EBX
+const VENDOR_INTEL: u32 = 0x756E_6547; // "Genu"
+
+// CPUID.6:EAX bits
+const CPUID_DTS: u32 = 1 << 0;
+const CPUID_PTM: u32 = 1 << 6;
+
+const IA32_THERM_STATUS: u32 = 0x19C;
+const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+const IA32_PACKAGE_THERM_STATUS: u32 = 0x1B1;
+
+const THERM_STATUS_VALID: u64 = 1 << 31;
+
+/// TjMax when it cannot be read, in degrees Celsius
+const DEFAULT_TJ_MAX: i32 = 100;
+/// First Intel model with `MSR_TEMPERATURE_TARGET`, Nehalem
+const INTEL_MODEL_NEHALEM: u32 = 0x1A;
+
+/// No sensor, or no reading yet
+const NONE: i32 = i32::MIN;
+
+/// TjMax of each CPU in degrees Celsius, and the last reading of its core sensor in millidegrees
+static TJ_MAX: [AtomicI32; CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const TJ_MAX: AtomicI32 = AtomicI32::new(NONE);
+    [TJ_MAX; CPUS]
+};
+static CORE: [AtomicI32; CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const CORE: AtomicI32 = AtomicI32::new(NONE);
+    [CORE; CPUS]
+};
+
+static PACKAGE_SENSOR: AtomicBool = AtomicBool::new(false);
+static PACKAGE: AtomicI32 = AtomicI32::new(NONE);
+
+/// Detect the sensors of the current CPU. Called on each CPU while starting.
+pub unsafe fn init(cpu_id: usize) {
+    let Some(tj_max) = TJ_MAX.get(cpu_id) else {
+        return;
+    };
+    if __cpuid_count(0, 0).ebx != VENDOR_INTEL || __cpuid_count(0, 0).eax < 6 {
+        return;
+    }
+    let features = __cpuid_count(6, 0).eax;
+    if features & CPUID_DTS == 0 {
+        return;
+    }
+
+    let signature = __cpuid_count(1, 0).eax;
+    let family = (signature >> 8) & 0xF;
+    let model = (signature >> 4) & 0xF | (signature >> 12) & 0xF0;
+    let value = if family == 6 && model >= INTEL_MODEL_NEHALEM {
+        ((msr::rdmsr(MSR_TEMPERATURE_TARGET) >> 16) & 0xFF) as i32
+    } else {
+        0
+    };
+    tj_max.store(if value == 0 { DEFAULT_TJ_MAX } else { value }, Ordering::Relaxed);
+
+    if cpu_id == 0 && features & CPUID_PTM != 0 {
+        PACKAGE_SENSOR.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Millidegrees Celsius from a thermal status register, if its reading is valid
+fn reading(status: u64, tj_max: i32) -> Option<i32> {
+    if status & THERM_STATUS_VALID == 0 {
+        return None;
+    }
+    Some((tj_max - ((status >> 16) & 0x7F) as i32) * 1000)
+}
+
+/// Read the sensors of the current CPU
+pub unsafe fn poll(cpu_id: usize) {
+    let Some(tj_max) = self::tj_max(cpu_id) else {
+        return;
+    };
+    if let Some(temp) = reading(msr::rdmsr(IA32_THERM_STATUS), tj_max) {
+        CORE[cpu_id].store(temp, Ordering::Relaxed);
+    }
+    if cpu_id == 0 && PACKAGE_SENSOR.load(Ordering::Relaxed) {
+        if let Some(temp) = reading(msr::rdmsr(IA32_PACKAGE_THERM_STATUS), tj_max) {
+            PACKAGE.store(temp, Ordering::Relaxed);
+        }
+    }
+}
+
+/// TjMax of `cpu_id` in degrees Celsius, if it has a sensor
+pub fn tj_max(cpu_id: usize) -> Option<i32> {
+    Some(TJ_MAX.get(cpu_id)?.load(Ordering::Relaxed)).filter(|&tj_max| tj_max != NONE)
+}
+
+/// The last reading of the core sensor of `cpu_id`, in millidegrees Celsius
+pub fn core(cpu_id: usize) -> Option<i32> {
+    Some(CORE.get(cpu_id)?.load(Ordering::Relaxed)).filter(|&temp| temp != NONE)
+}
+
+/// Whether the package has a sensor
+pub fn has_package() -> bool {
+    PACKAGE_SENSOR.load(Ordering::Relaxed)
+}
+
+/// The last reading of the package sensor, in millidegrees Celsius
+pub fn package() -> Option<i32> {
+    Some(PACKAGE.load(Ordering::Relaxed)).filter(|&temp| temp != NONE)
+}
@@ -1,36 +1,81 @@
+//! # Clocksources
+//! The invariant TSC, calibrated against the HPET, and the HPET main counter if it is 64 bits wide
+//! and so does not wrap around, are registered as clocksources (see `time`). The ticks of the
+//! system timer are interpolated with the count of the HPET or PIT since the last.
+
 #[cfg(feature = "acpi")]
 use super::device::hpet;
-use super::device::pit;
+use super::device::{pit, tsc};
+use crate::time::{self, Clocksource};
+
+#[cfg(feature = "acpi")]
+fn hpet_nanoseconds() -> u128 {
+    match *crate::acpi::ACPI_TABLE.hpet.read() {
+        Some(ref hpet) => unsafe { hpet::nanoseconds(hpet) },
+        None => 0,
+    }
+}
+
+/// Register the clocksources, once the system timer runs
+pub unsafe fn init() {
+    #[cfg(feature = "acpi")]
+    let reference = match *crate::acpi::ACPI_TABLE.hpet.read() {
+        Some(ref hpet) if hpet::is_64bit(hpet) => Some(hpet_nanoseconds as fn() -> u128),
+        _ => None,
+    };
+    #[cfg(not(feature = "acpi"))]
+    let reference = None;
+
+    if let Some(read) = reference {
+        time::register(Clocksource {
+            name: "hpet",
+            rating: 250,
+            read,
+            verify: false,
+        });
+    }
+    if tsc::init(reference) {
+        time::register(Clocksource {
+            name: "tsc",
+            rating: 300,
+            read: tsc::nanoseconds,
+            verify: true,
+        });
+    }
+    log::info!("Clocksource: {}", time::clocksource());
+}
+
+/// Count a tick of the system timer
+pub fn tick() {
+    *time::OFFSET.lock() += pit::RATE;
+}
 
+/// Nanoseconds since the last tick of the system timer
 pub fn counter() -> u128 {
     #[cfg(feature = "acpi")]
-    if let Some(ref hpet) = *crate::acpi::ACPI_TABLE.hpet.read() {
-        //TODO: handle rollover?
-        //TODO: improve performance
-
-        // Current count
-        let counter = unsafe { hpet.base_address.read_u64(hpet::MAIN_COUNTER_OFFSET) };
-        // Comparator holds next interrupt count
-        let comparator = unsafe { hpet.base_address.read_u64(hpet::T0_COMPARATOR_OFFSET) };
-        // Get period in femtoseconds
-        let capability = unsafe { hpet.base_address.read_u64(hpet::CAPABILITY_OFFSET) };
-
-        // There seems to be a bug in qemu on macos that causes the calculation to produce 0 for
-        // period_fs and hence a divide by zero calculating the divisor - workaround it while we
-        // try and get a fix from qemu: https://gitlab.com/qemu-project/qemu/-/issues/1570
-        let mut period_fs = capability >> 32;
-        if period_fs == 0 {
-            period_fs = 10_000_000;
-        }
+    {
+        // The HPET only ticks when it is the system timer, its main counter may run regardless
+        let hpet = crate::acpi::ACPI_TABLE.hpet.read();
+        if let Some(hpet) = hpet.as_ref().filter(|hpet| unsafe { hpet::is_timer(hpet) }) {
+            //TODO: handle rollover?
+            //TODO: improve performance
 
-        // Calculate divisor
-        let divisor = (pit::RATE as u64 * 1_000_000) / period_fs;
-        // Calculate last interrupt
-        let last_interrupt = comparator.saturating_sub(divisor);
-        // Calculate ticks since last interrupt
-        let elapsed = counter.saturating_sub(last_interrupt);
-        // Calculate nanoseconds since last interrupt
-        return (elapsed as u128 * period_fs as u128) / 1_000_000;
+            // Current count
+            let counter = unsafe { hpet.base_address.read_u64(hpet::MAIN_COUNTER_OFFSET) };
+            // Comparator holds next interrupt count
+            let comparator = unsafe { hpet.base_address.read_u64(hpet::T0_COMPARATOR_OFFSET) };
+            // Get period in femtoseconds
+            let period_fs = unsafe { hpet::period_fs(hpet) };
+
+            // Calculate divisor
+            let divisor = (pit::RATE as u64 * 1_000_000) / period_fs;
+            // Calculate last interrupt
+            let last_interrupt = comparator.saturating_sub(divisor);
+            // Calculate ticks since last interrupt
+            let elapsed = counter.saturating_sub(last_interrupt);
+            // Calculate nanoseconds since last interrupt
+            return (elapsed as u128 * period_fs as u128) / 1_000_000;
+        }
     }
 
     // Read ticks since last interrupt
@@ -0,0 +1,181 @@
+//! # TLB shootdown
+//! After changing the page tables of an address space, the stale TLB entries of other CPUs running
+//! that address space have to be invalidated. Each CPU has a mailbox in its PCR, recording which
+//! user page table it runs. The changed range is posted to the mailboxes of the CPUs running the
+//! changed page table only, followed by an IPI, and the sender waits until they are done.
+//!
+//! CPUs running other address spaces are skipped: without PCIDs, loading CR3 when they switch to
+//! the changed address space invalidates its TLB entries anyway. Switching takes the address
+//! space lock, which is held while changing the page tables and sending the shootdown, so a CPU
+//! cannot load the old tables after the mailboxes were checked.
+//!
+//! As the sender waits with the address space lock held, a CPU waiting for that lock with
+//! interrupts disabled, such as while switching contexts or handling a page fault, would never
+//! take the IPI. Those paths wait with `spin_handling`, which handles the request meanwhile.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use core::ptr;
+
+use x86::tlb;
+
+use crate::device::local_apic::LOCAL_APIC;
+use crate::ipi::IpiKind;
+use crate::memory::PAGE_SIZE;
+use crate::paging::{PhysicalAddress, VirtualAddress};
+use crate::syscall::error::Result;
+
+/// Number of CPUs with mailboxes
+pub const CPUS: usize = 32;
+
+/// Largest range invalidated page by page, larger ones flush the entire TLB
+pub const BATCH_PAGES: usize = 32;
+
+/// `Mailbox::count` requesting a flush of the entire TLB
+const FULL: usize = usize::MAX;
+
+#[repr(C, align(128))]
+pub struct Mailbox {
+    /// Physical address of the user page table this CPU runs, or 0
+    active: AtomicUsize,
+    /// Held by the CPU posting a request, until it has been handled
+    lock: AtomicBool,
+    /// Set by the sender, and cleared by this CPU when it has handled the request
+    pending: AtomicBool,
+    /// First page to invalidate, and the number of pages, or `FULL`
+    start: AtomicUsize,
+    count: AtomicUsize,
+}
+
+impl Mailbox {
+    pub const fn new() -> Self {
+        Self {
+            active: AtomicUsize::new(0),
+            lock: AtomicBool::new(false),
+            pending: AtomicBool::new(false),
+            start: AtomicUsize::new(0),
+            count: AtomicUsize::new(0),
+        }
+    }
+}
+
+static MAILBOXES: [AtomicPtr<Mailbox>; CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const MAILBOX: AtomicPtr<Mailbox> = AtomicPtr::new(ptr::null_mut());
+    [MAILBOX; CPUS]
+};
+
+/// Shootdowns requested, IPIs sent for them, and CPUs skipped because they ran another address
+/// space
+static SHOOTDOWNS: AtomicU64 = AtomicU64::new(0);
+static IPIS: AtomicU64 = AtomicU64::new(0);
+static SKIPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Register the mailbox in the PCR of the current CPU. Called on each CPU after the PCR is set up.
+pub unsafe fn init(cpu_id: usize) {
+    if let Some(slot) = MAILBOXES.get(cpu_id) {
+        slot.store(ptr::addr_of_mut!((*crate::gdt::pcr()).tlb), Ordering::SeqCst);
+    }
+}
+
+fn mailbox(cpu_id: usize) -> Option<&'static Mailbox> {
+    let mailbox = MAILBOXES.get(cpu_id)?.load(Ordering::Acquire);
+    unsafe { mailbox.as_ref() }
+}
+
+/// Record that the current CPU runs the user page table at `table`, or none if zero. Called while
+/// switching contexts, before loading the table.
+pub fn set_active(table: PhysicalAddress) {
+    if let Some(mailbox) = mailbox(crate::cpu_id()) {
+        mailbox.active.store(table.data(), Ordering::SeqCst);
+    }
+}
+
+/// Handle the request in the mailbox of the current CPU, if any
+pub fn handle_pending() {
+    let mailbox = match mailbox(crate::cpu_id()) {
+        Some(mailbox) => mailbox,
+        None => return,
+    };
+    if !mailbox.pending.load(Ordering::Acquire) {
+        return;
+    }
+
+    let start = mailbox.start.load(Ordering::Relaxed);
+    match mailbox.count.load(Ordering::Relaxed) {
+        FULL => unsafe { tlb::flush_all() },
+        count => for page in 0..count {
+            unsafe { tlb::flush(start + page * PAGE_SIZE) };
+        },
+    }
+    mailbox.pending.store(false, Ordering::Release);
+}
+
+/// Invalidate `page_count` pages from `start`, or everything if `None`, on the other CPUs running
+/// the user page table at `table`, and wait until they have
+pub fn shootdown(table: PhysicalAddress, range: Option<(VirtualAddress, usize)>) {
+    SHOOTDOWNS.fetch_add(1, Ordering::Relaxed);
+
+    let (start, count) = match range {
+        Some((start, count)) if count <= BATCH_PAGES => (start.data(), count),
+        _ => (0, FULL),
+    };
+
+    let current = crate::cpu_id();
+    let mut targets = [false; CPUS];
+    for cpu_id in (0..CPUS).filter(|&cpu_id| cpu_id != current) {
+        let mailbox = match mailbox(cpu_id) {
+            Some(mailbox) => mailbox,
+            None => continue,
+        };
+        if mailbox.active.load(Ordering::SeqCst) != table.data() {
+            SKIPPED.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+
+        // Another CPU may be waiting for this one while holding the lock, so keep handling our own
+        // requests
+        while mailbox.lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            handle_pending();
+            core::hint::spin_loop();
+        }
+        mailbox.start.store(start, Ordering::Relaxed);
+        mailbox.count.store(count, Ordering::Relaxed);
+        mailbox.pending.store(true, Ordering::Release);
+
+        // CPU IDs are local APIC IDs
+        unsafe { LOCAL_APIC.ipi_vector(cpu_id as u32, IpiKind::TlbShootdown as u8) };
+        IPIS.fetch_add(1, Ordering::Relaxed);
+        targets[cpu_id] = true;
+    }
+
+    for cpu_id in (0..CPUS).filter(|&cpu_id| targets[cpu_id]) {
+        let mailbox = mailbox(cpu_id).expect("mailbox of shootdown target disappeared");
+        while mailbox.pending.load(Ordering::Acquire) {
+            handle_pending();
+            core::hint::spin_loop();
+        }
+        mailbox.lock.store(false, Ordering::Release);
+    }
+}
+
+/// Spin until `try_lock` succeeds, handling the request in the mailbox of the current CPU
+/// meanwhile. Used to wait for an address space lock with interrupts disabled, as its holder may be
+/// waiting for this CPU.
+pub fn spin_handling<T>(mut try_lock: impl FnMut() -> Option<T>) -> T {
+    loop {
+        if let Some(guard) = try_lock() {
+            return guard;
+        }
+        handle_pending();
+        core::hint::spin_loop();
+    }
+}
+
+/// The number of shootdowns, IPIs sent for them, and CPUs skipped, for `sys:tlb`
+pub fn stats_resource() -> Result<Vec<u8>> {
+    Ok(format!("shootdowns: {}\nipis: {}\nskipped: {}\n",
+        SHOOTDOWNS.load(Ordering::Relaxed),
+        IPIS.load(Ordering::Relaxed),
+        SKIPPED.load(Ordering::Relaxed)).into_bytes())
+}
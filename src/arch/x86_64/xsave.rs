@@ -0,0 +1,313 @@
+//! # Extended processor state
+//! The FPU, SSE, AVX, AVX-512 and AMX registers of a context are saved and restored on context
+//! switches, into an area whose size depends on which of these the CPU supports. They are chosen
+//! once on the BSP, as are the instructions used: XSAVES with the compacted format where
+//! available, otherwise XSAVEOPT or XSAVE, and FXSAVE on CPUs without XSAVE. XSAVEOPT and XSAVES
+//! skip registers in their initial state, and those not modified since the last restore.
+//!
+//! Components the CPU can disable with extended feature disable (XFD), in practice the AMX tile
+//! data of 8 KiB, are allocated lazily. Until a context first uses them, its area does not include
+//! them and they are disabled while it runs, so that using them raises #NM. The handler grows the
+//! area of the context, and enables them (see `Kfx::grow`).
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid_count;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use x86::controlregs::{self, Cr4};
+use x86::msr;
+
+const XSTATE_X87: u64 = 1 << 0;
+const XSTATE_SSE: u64 = 1 << 1;
+const XSTATE_AVX: u64 = 1 << 2;
+const XSTATE_AVX512: u64 = 0b111 << 5;
+const XSTATE_AMX: u64 = 0b11 << 17;
+
+/// The components used if supported. AVX-512 and AMX are only usable if all of their components
+/// are enabled.
+const WANTED: u64 = XSTATE_X87 | XSTATE_SSE | XSTATE_AVX | XSTATE_AVX512 | XSTATE_AMX;
+
+const IA32_XSS: u32 = 0xDA0;
+const IA32_XFD: u32 = 0x1C4;
+const IA32_XFD_ERR: u32 = 0x1C5;
+
+/// Offset of the XSAVE header, after the legacy FXSAVE region
+pub const HEADER_OFFSET: usize = 512;
+/// Size of the legacy region and the XSAVE header, the smallest XSAVE area
+const LEGACY_SIZE: usize = 576;
+/// Alignment of the save area, required by XSAVE
+pub const ALIGN: usize = 64;
+
+/// The instructions used to save and restore the extended state
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Method {
+    Fxsave,
+    Xsave,
+    Xsaveopt,
+    Xsaves,
+}
+
+static METHOD: AtomicU8 = AtomicU8::new(Method::Fxsave as u8);
+/// Components enabled in XCR0
+static FEATURES: AtomicU64 = AtomicU64::new(0);
+/// Components only saved for contexts that have used them, disabled with XFD for the others
+static LAZY: AtomicU64 = AtomicU64::new(0);
+/// Size of the save area without and with the lazily allocated components
+static SIZE: AtomicUsize = AtomicUsize::new(HEADER_OFFSET);
+static FULL_SIZE: AtomicUsize = AtomicUsize::new(HEADER_OFFSET);
+
+pub fn method() -> Method {
+    match METHOD.load(Ordering::Relaxed) {
+        1 => Method::Xsave,
+        2 => Method::Xsaveopt,
+        3 => Method::Xsaves,
+        _ => Method::Fxsave,
+    }
+}
+
+/// The components saved for a context, with or without the lazily allocated ones
+pub fn features(full: bool) -> u64 {
+    let features = FEATURES.load(Ordering::Relaxed);
+    if full { features } else { features & !LAZY.load(Ordering::Relaxed) }
+}
+
+/// Size of the save area of a context, with or without the lazily allocated components
+pub fn area_size(full: bool) -> usize {
+    if full { FULL_SIZE.load(Ordering::Relaxed) } else { SIZE.load(Ordering::Relaxed) }
+}
+
+/// Whether some components are allocated lazily
+pub fn has_lazy() -> bool {
+    LAZY.load(Ordering::Relaxed) != 0
+}
+
+/// Size of an area holding `features`, in the standard or compacted format
+fn size_of(features: u64, compacted: bool) -> usize {
+    let mut size = LEGACY_SIZE;
+    for i in 2..64 {
+        if features & (1 << i) == 0 {
+            continue;
+        }
+        let leaf = unsafe { __cpuid_count(0xD, i) };
+        if compacted {
+            // ECX bit 1: the component is 64-byte aligned in the compacted format
+            if leaf.ecx & (1 << 1) != 0 {
+                size = size.next_multiple_of(64);
+            }
+            size += leaf.eax as usize;
+        } else {
+            size = size.max(leaf.ebx as usize + leaf.eax as usize);
+        }
+    }
+    size
+}
+
+/// Choose the components and instructions to use. Called on the BSP.
+unsafe fn negotiate() {
+    let cpuid = crate::cpuid::cpuid_always();
+    if !cpuid.get_feature_info().map_or(false, |info| info.has_xsave()) {
+        return;
+    }
+
+    let leaf = __cpuid_count(0xD, 0);
+    let supported = u64::from(leaf.edx) << 32 | u64::from(leaf.eax);
+    let mut features = supported & WANTED;
+    if features & XSTATE_AVX == 0 || features & XSTATE_AVX512 != XSTATE_AVX512 {
+        features &= !XSTATE_AVX512;
+    }
+    if features & XSTATE_AMX != XSTATE_AMX {
+        features &= !XSTATE_AMX;
+    }
+
+    let leaf = __cpuid_count(0xD, 1);
+    let (xsaveopt, xsaves, xfd) = (leaf.eax & (1 << 0) != 0, leaf.eax & (1 << 3) != 0, leaf.eax & (1 << 4) != 0);
+
+    let mut lazy = 0;
+    if xfd {
+        for i in 2..64 {
+            // ECX bit 2: the component can be disabled with XFD
+            if features & (1 << i) != 0 && __cpuid_count(0xD, i).ecx & (1 << 2) != 0 {
+                lazy |= 1 << i;
+            }
+        }
+    }
+
+    let method = if xsaves { Method::Xsaves } else if xsaveopt { Method::Xsaveopt } else { Method::Xsave };
+    let compacted = method == Method::Xsaves;
+
+    FEATURES.store(features, Ordering::Relaxed);
+    LAZY.store(lazy, Ordering::Relaxed);
+    SIZE.store(size_of(features & !lazy, compacted), Ordering::Relaxed);
+    FULL_SIZE.store(size_of(features, compacted), Ordering::Relaxed);
+    METHOD.store(method as u8, Ordering::Relaxed);
+}
+
+/// Enable the extended state on the current CPU. Called on each CPU while starting, before any
+/// context runs on it.
+pub unsafe fn init(bsp: bool) {
+    if bsp {
+        negotiate();
+    }
+
+    let method = method();
+    if method == Method::Fxsave {
+        return;
+    }
+
+    controlregs::cr4_write(controlregs::cr4() | Cr4::CR4_ENABLE_OS_XSAVE);
+    let features = FEATURES.load(Ordering::Relaxed);
+    asm!("xsetbv", in("ecx") 0, in("eax") features as u32, in("edx") (features >> 32) as u32);
+    if method == Method::Xsaves {
+        // Only user components are saved
+        msr::wrmsr(IA32_XSS, 0);
+    }
+    if has_lazy() {
+        msr::wrmsr(IA32_XFD, LAZY.load(Ordering::Relaxed));
+        msr::wrmsr(IA32_XFD_ERR, 0);
+    }
+}
+
+/// Prepare a zeroed save area holding `features`. XRSTORS requires the compacted format to be set
+/// in the header.
+pub unsafe fn init_area(area: *mut u8, features: u64) {
+    if method() == Method::Xsaves {
+        area.add(HEADER_OFFSET + 8).cast::<u64>().write(1 << 63 | features);
+    }
+}
+
+/// Mark the legacy FPU and SSE registers of a save area as in use, so that restoring it loads them
+/// from the area after they were written, instead of resetting them
+pub unsafe fn set_legacy_in_use(area: *mut u8) {
+    if method() != Method::Fxsave {
+        let xstate_bv = area.add(HEADER_OFFSET).cast::<u64>();
+        xstate_bv.write(xstate_bv.read() | XSTATE_X87 | XSTATE_SSE);
+    }
+}
+
+/// Save the extended state of the current CPU into `area`, which holds `features`
+pub unsafe fn save(area: *mut u8, features: u64) {
+    let (low, high) = (features as u32, (features >> 32) as u32);
+    match method() {
+        Method::Fxsave => asm!("fxsave64 [{}]", in(reg) area),
+        Method::Xsave => asm!("xsave64 [{}]", in(reg) area, in("eax") low, in("edx") high),
+        Method::Xsaveopt => asm!("xsaveopt64 [{}]", in(reg) area, in("eax") low, in("edx") high),
+        Method::Xsaves => asm!("xsaves64 [{}]", in(reg) area, in("eax") low, in("edx") high),
+    }
+}
+
+/// Restore the extended state of the current CPU from `area`, which holds `features`. Components
+/// allocated lazily that `area` does not hold are disabled with XFD.
+pub unsafe fn restore(area: *const u8, features: u64) {
+    let lazy = LAZY.load(Ordering::Relaxed);
+    if lazy != 0 {
+        let xfd = lazy & !features;
+        if msr::rdmsr(IA32_XFD) != xfd {
+            msr::wrmsr(IA32_XFD, xfd);
+        }
+    }
+
+    let (low, high) = (features as u32, (features >> 32) as u32);
+    match method() {
+        Method::Fxsave => asm!("fxrstor64 [{}]", in(reg) area),
+        Method::Xsave | Method::Xsaveopt => asm!("xrstor64 [{}]", in(reg) area, in("eax") low, in("edx") high),
+        Method::Xsaves => asm!("xrstors64 [{}]", in(reg) area, in("eax") low, in("edx") high),
+    }
+}
+
+/// Handle #NM raised by a lazily allocated component, by growing the save area of the current
+/// context. Returns false if the fault had another cause, or the area could not be grown.
+pub unsafe fn handle_xfd_fault() -> bool {
+    if !has_lazy() || msr::rdmsr(IA32_XFD_ERR) == 0 {
+        return false;
+    }
+    msr::wrmsr(IA32_XFD_ERR, 0);
+
+    let Ok(context_lock) = crate::context::current() else {
+        return false;
+    };
+    let mut context = context_lock.write();
+    context.kfx.grow().is_ok()
+}
+
+/// Size of an area holding `features` in the standard format, as exposed to debuggers
+pub fn standard_size(features: u64) -> usize {
+    if method() == Method::Fxsave { HEADER_OFFSET } else { size_of(features, false) }
+}
+
+/// Offsets of the components in `features` above SSE, in the standard and the compacted format,
+/// and their sizes
+fn layout(features: u64) -> impl Iterator<Item = (usize, usize, usize, u64)> {
+    let mut compacted = LEGACY_SIZE;
+    (2..64).filter(move |i| features & (1 << i) != 0).map(move |i| {
+        let leaf = unsafe { __cpuid_count(0xD, i) };
+        if leaf.ecx & (1 << 1) != 0 {
+            compacted = compacted.next_multiple_of(64);
+        }
+        let offsets = (leaf.ebx as usize, compacted, leaf.eax as usize, 1 << i);
+        compacted += leaf.eax as usize;
+        offsets
+    })
+}
+
+/// Copy `area`, which holds `features`, into `out` in the standard format. The bytes of the legacy
+/// region reserved for software hold `features`, like on Linux, so that debuggers know the layout.
+pub unsafe fn to_standard(area: *const u8, features: u64, out: &mut [u8]) {
+    let area = core::slice::from_raw_parts(area, if method() == Method::Xsaves { size_of(features, true) } else { out.len() });
+    out[..HEADER_OFFSET].copy_from_slice(&area[..HEADER_OFFSET]);
+    out[464..472].copy_from_slice(&features.to_ne_bytes());
+    if method() == Method::Fxsave {
+        return;
+    }
+
+    // XSTATE_BV, with XCOMP_BV and the rest of the header left zero
+    out[HEADER_OFFSET..HEADER_OFFSET + 8].copy_from_slice(&area[HEADER_OFFSET..HEADER_OFFSET + 8]);
+    let xstate_bv = u64::from_ne_bytes(area[HEADER_OFFSET..HEADER_OFFSET + 8].try_into().unwrap());
+    for (standard, compacted, size, bit) in layout(features) {
+        // Components in their initial state are not saved, and read as zero
+        if xstate_bv & bit == 0 {
+            continue;
+        }
+        let from = if method() == Method::Xsaves { compacted } else { standard };
+        out[standard..standard + size].copy_from_slice(&area[from..from + size]);
+    }
+}
+
+/// Copy `data`, in the standard format, into `area`, which holds `features`. Components of `data`
+/// the area does not hold are ignored. Returns false without changing `area` if `data` could not
+/// be restored.
+pub unsafe fn from_standard(data: &[u8], area: *mut u8, features: u64) -> bool {
+    if data.len() != standard_size(features) {
+        return false;
+    }
+    // MXCSR must not set reserved bits, MXCSR_MASK is kept
+    let mxcsr = u32::from_ne_bytes(data[24..28].try_into().unwrap());
+    let mut mxcsr_mask = area.add(28).cast::<u32>().read();
+    if mxcsr_mask == 0 {
+        mxcsr_mask = 0xFFBF;
+    }
+    if mxcsr & !mxcsr_mask != 0 {
+        return false;
+    }
+
+    let area = core::slice::from_raw_parts_mut(area, if method() == Method::Xsaves { size_of(features, true) } else { data.len() });
+    // The reserved bytes of the legacy region are kept too
+    area[..28].copy_from_slice(&data[..28]);
+    area[32..464].copy_from_slice(&data[32..464]);
+    if method() == Method::Fxsave {
+        return true;
+    }
+
+    let xstate_bv = u64::from_ne_bytes(data[HEADER_OFFSET..HEADER_OFFSET + 8].try_into().unwrap()) & features;
+    area[HEADER_OFFSET..LEGACY_SIZE].fill(0);
+    area[HEADER_OFFSET..HEADER_OFFSET + 8].copy_from_slice(&xstate_bv.to_ne_bytes());
+    init_area(area.as_mut_ptr(), features);
+    for (standard, compacted, size, bit) in layout(features) {
+        if xstate_bv & bit == 0 {
+            continue;
+        }
+        let to = if method() == Method::Xsaves { compacted } else { standard };
+        area[to..to + size].copy_from_slice(&data[standard..standard + size]);
+    }
+    true
+}
@@ -0,0 +1,32 @@
+; trampoline for jumping into a kernel loaded by kexec
+; compiled with nasm by build.rs, and included in src/arch/x86_64/kexec.rs
+;
+; Copied to a page identity mapped in both the page table it is entered with and that of the new
+; kernel, so it needs no fixed address. Entered with interrupts disabled, and:
+;   rdi: physical address of the page table of the new kernel
+;   rsi: top of the stack of the new kernel
+;   rdx: address of the arguments of the new kernel
+;   rcx: entry point of the new kernel
+
+SECTION .text
+USE64
+
+kexec:
+    ; Clearing the global pages flag flushes the global pages of the running kernel, which the new
+    ; one maps differently, and clearing PCIDE lets the new kernel load CR3 as it pleases
+    mov rax, cr4
+    btr rax, 7
+    btr rax, 17
+    mov cr4, rax
+
+    mov cr3, rdi
+
+    bts rax, 7
+    mov cr4, rax
+
+    ; Enter like a call would, with a null return address
+    mov rsp, rsi
+    push qword 0
+    mov rdi, rdx
+    xor rbp, rbp
+    jmp rcx
@@ -0,0 +1,233 @@
+//! # Audit
+//! Security-relevant events are recorded into a ring for intrusion detection: changes of user and
+//! group IDs, changes of scheme and PID namespaces, opens failing with `EACCES`, and ptrace
+//! attaches. Each record holds the subject context and its credentials, and is read as a line of
+//! text from `audit:log`, see `scheme::audit`.
+//!
+//! Which events are recorded is chosen by an ordered list of rules, the first matching rule
+//! deciding. An event no rule matches is not recorded. Rules are lines of the form
+//! `record|ignore <kind>|all [uid=<uid>] [pid=<pid>]`, where `uid` is the real user ID of the
+//! subject. By default, everything is recorded.
+
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use crate::context::{self, ContextId};
+use crate::sync::Mutex;
+use crate::syscall::error::{Error, Result, EINVAL};
+use crate::time;
+
+/// Number of records kept, older ones are overwritten
+pub const MAX_RECORDS: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    /// The real or effective user ID changed
+    SetUid,
+    /// The real or effective group ID changed
+    SetGid,
+    /// A scheme or PID namespace was created or entered
+    Namespace,
+    /// An open failed with `EACCES`
+    OpenDenied,
+    /// A tracer attached to a context
+    PtraceAttach,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 5] = [Kind::SetUid, Kind::SetGid, Kind::Namespace, Kind::OpenDenied, Kind::PtraceAttach];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::SetUid => "setuid",
+            Kind::SetGid => "setgid",
+            Kind::Namespace => "ns",
+            Kind::OpenDenied => "open_denied",
+            Kind::PtraceAttach => "ptrace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|kind| kind.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rule {
+    /// Whether matching events are recorded or ignored
+    pub record: bool,
+    /// The kind of events matched, or all of them
+    pub kind: Option<Kind>,
+    pub uid: Option<u32>,
+    pub pid: Option<ContextId>,
+}
+
+impl Rule {
+    fn matches(&self, kind: Kind, pid: ContextId, uid: u32) -> bool {
+        self.kind.map_or(true, |k| k == kind)
+            && self.uid.map_or(true, |u| u == uid)
+            && self.pid.map_or(true, |p| p == pid)
+    }
+
+    pub fn parse(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+        let record = match words.next() {
+            Some("record") => true,
+            Some("ignore") => false,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        let kind = match words.next().ok_or(Error::new(EINVAL))? {
+            "all" => None,
+            name => Some(Kind::from_name(name).ok_or(Error::new(EINVAL))?),
+        };
+        let mut rule = Rule { record, kind, uid: None, pid: None };
+        for word in words {
+            let (key, value) = word.split_once('=').ok_or(Error::new(EINVAL))?;
+            let value = value.parse::<usize>().map_err(|_| Error::new(EINVAL))?;
+            match key {
+                "uid" => rule.uid = Some(u32::try_from(value).map_err(|_| Error::new(EINVAL))?),
+                "pid" => rule.pid = Some(ContextId::from(value)),
+                _ => return Err(Error::new(EINVAL)),
+            }
+        }
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", if self.record { "record" } else { "ignore" }, self.kind.map_or("all", Kind::name))?;
+        if let Some(uid) = self.uid {
+            write!(f, " uid={}", uid)?;
+        }
+        if let Some(pid) = self.pid {
+            write!(f, " pid={}", pid.into())?;
+        }
+        Ok(())
+    }
+}
+
+/// The rules, or `None` until set, in which case everything is recorded
+static RULES: Mutex<Option<Vec<Rule>>> = Mutex::new(None);
+
+struct Record {
+    seq: u64,
+    time: u128,
+    pid: ContextId,
+    name: String,
+    ruid: u32,
+    euid: u32,
+    kind: Kind,
+    detail: String,
+}
+
+struct Ring {
+    records: VecDeque<Record>,
+    /// Sequence number of the next record
+    next_seq: u64,
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring {
+    records: VecDeque::new(),
+    next_seq: 0,
+});
+
+pub fn rules() -> Vec<Rule> {
+    RULES.lock().clone().unwrap_or_else(|| vec![Rule { record: true, kind: None, uid: None, pid: None }])
+}
+
+pub fn set_rules(rules: Vec<Rule>) {
+    *RULES.lock() = Some(rules);
+}
+
+/// Record an event of the current context, if the rules ask for it. The current context must not
+/// be locked.
+pub fn record(kind: Kind, detail: fmt::Arguments) {
+    let Ok(context_lock) = context::current() else {
+        return;
+    };
+    let (pid, name, ruid, euid) = {
+        let context = context_lock.read();
+        (context.tgid(), context.name.to_string(), context.ruid, context.euid)
+    };
+
+    let recorded = match *RULES.lock() {
+        Some(ref rules) => rules.iter().find(|rule| rule.matches(kind, pid, ruid)).map_or(false, |rule| rule.record),
+        None => true,
+    };
+    if !recorded {
+        return;
+    }
+
+    let mut detail_string = String::new();
+    let _ = detail_string.write_fmt(detail);
+
+    {
+        let mut ring = RING.lock();
+        let seq = ring.next_seq;
+        ring.next_seq += 1;
+        if ring.records.len() >= MAX_RECORDS {
+            ring.records.pop_front();
+        }
+        ring.records.push_back(Record {
+            seq,
+            time: time::monotonic(),
+            pid,
+            name,
+            ruid,
+            euid,
+            kind,
+            detail: detail_string,
+        });
+    }
+
+    crate::scheme::audit::notify();
+}
+
+/// Format the whole records from sequence number `seq` on that fit in `max` bytes, and return the
+/// sequence number to continue from. If records before the oldest one kept were asked for, the
+/// number of them lost is reported first. Fails with `EINVAL` if not even one record fits.
+pub fn read(seq: u64, max: usize) -> Result<(String, u64)> {
+    let ring = RING.lock();
+    let mut string = String::new();
+    let mut next = seq;
+
+    let oldest = ring.records.front().map_or(ring.next_seq, |record| record.seq);
+    if next < oldest {
+        let _ = writeln!(string, "lost {}", oldest - next);
+        next = oldest;
+    }
+
+    let start = (next - oldest) as usize;
+    for record in ring.records.iter().skip(start) {
+        let mut line = String::new();
+        let _ = writeln!(line, "{} {} pid={} name={:?} uid={} euid={} {} {}",
+            record.seq, record.time, record.pid.into(), record.name, record.ruid, record.euid,
+            record.kind.name(), record.detail);
+        if string.len() + line.len() > max {
+            break;
+        }
+        string.push_str(&line);
+        next = record.seq + 1;
+    }
+
+    // Not even one record fits
+    if string.is_empty() && start < ring.records.len() {
+        return Err(Error::new(EINVAL));
+    }
+
+    Ok((string, next))
+}
+
+/// Sequence number of the oldest record kept
+pub fn oldest_seq() -> u64 {
+    let ring = RING.lock();
+    ring.records.front().map_or(ring.next_seq, |record| record.seq)
+}
+
+/// Sequence number of the next record
+pub fn next_seq() -> u64 {
+    RING.lock().next_seq
+}
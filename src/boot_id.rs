@@ -0,0 +1,50 @@
+//! # Boot ID
+//! A random identifier of the current boot, formatted as a version 4 UUID, so that logs and dumps
+//! from different boots can be told apart.
+
+use core::fmt;
+
+use spin::Once;
+
+use crate::log::info;
+
+pub struct BootId([u8; 16]);
+
+impl BootId {
+    fn generate() -> Self {
+        let mut bytes = [0; 16];
+        crate::entropy::fill(&mut bytes);
+        // Version 4 (random), variant 1
+        bytes[6] = (bytes[6] & 0x0F) | 0x40;
+        bytes[8] = (bytes[8] & 0x3F) | 0x80;
+        BootId(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl fmt::Display for BootId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+static BOOT_ID: Once<BootId> = Once::new();
+
+/// Generate the boot ID, and record it in the kernel log
+pub fn init() {
+    info!("Boot ID: {}", BOOT_ID.call_once(BootId::generate));
+}
+
+/// The boot ID, if it has been generated yet
+pub fn get() -> Option<&'static BootId> {
+    BOOT_ID.get()
+}
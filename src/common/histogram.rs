@@ -0,0 +1,84 @@
+//! Lock-free histogram with power-of-two buckets, suitable for recording latencies from any
+//! context, including interrupt handlers.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Bucket 0 holds zero, and bucket `i` holds values in `2^(i - 1)..2^i`
+const BUCKETS: usize = 65;
+
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            buckets: [ZERO; BUCKETS],
+            count: ZERO,
+            sum: ZERO,
+            max: ZERO,
+        }
+    }
+
+    pub fn record(&self, value: u64) {
+        let bucket = (u64::BITS - value.leading_zeros()) as usize;
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn mean(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed).checked_div(self.count()).unwrap_or(0)
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    /// Upper bound of the value below which `percent` percent of the recorded values fall
+    pub fn percentile(&self, percent: u64) -> u64 {
+        let target = (self.count() * percent).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count.load(Ordering::Relaxed);
+            if seen >= target {
+                return match bucket {
+                    0 => 0,
+                    64 => u64::MAX,
+                    _ => (1 << bucket) - 1,
+                };
+            }
+        }
+        self.max()
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test() {
+    let histogram = Histogram::new();
+    assert_eq!(histogram.percentile(50), 0);
+    for value in 1..=100 {
+        histogram.record(value);
+    }
+    assert_eq!(histogram.count(), 100);
+    assert_eq!(histogram.mean(), 50);
+    assert_eq!(histogram.max(), 100);
+    assert_eq!(histogram.percentile(50), 63);
+    assert_eq!(histogram.percentile(99), 127);
+}
@@ -1,6 +1,8 @@
 pub mod aligned_box;
+pub mod histogram;
 #[macro_use]
 pub mod int_like;
+pub mod sha256;
 pub mod unique;
 
 /// Debug macro, lifted from the std
@@ -1,12 +1,14 @@
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::arch::asm;
-use core::mem;
+use core::{mem, slice};
 use core::ptr;
 use core::sync::atomic::{AtomicBool, Ordering};
 use memoffset::offset_of;
 use spin::Once;
 
 use crate::{push_scratch, pop_scratch};
+use crate::common::aligned_box::AlignedBox;
 use crate::interrupt::handler::ScratchRegisters;
 use crate::device::cpu::registers::{control_regs, tlb};
 use crate::paging::{RmmA, RmmArch, TableKind};
@@ -22,6 +24,9 @@ pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
 pub const KFX_SIZE: usize = 1024;
 pub const KFX_ALIGN: usize = 16;
 
+/// Kernel FX - the save area of the FPU and SIMD registers of a context
+pub type Kfx = AlignedBox<[u8; KFX_SIZE], KFX_ALIGN>;
+
 #[derive(Clone, Debug)]
 pub struct Context {
     elr_el1: usize,
@@ -163,6 +168,21 @@ impl super::Context {
             ptr::write(self.kfx.as_mut_ptr() as *mut FloatRegisters, new);
         }
     }
+
+    /// The floating point state, the same as `get_fx_regs`, as SVE state is not saved
+    pub fn get_xstate(&self) -> Vec<u8> {
+        let regs = self.get_fx_regs();
+        unsafe { slice::from_raw_parts(&regs as *const FloatRegisters as *const u8, mem::size_of::<FloatRegisters>()) }.to_vec()
+    }
+
+    /// Set the floating point state from the format of `get_xstate`
+    pub fn set_xstate(&mut self, xstate: &[u8]) -> bool {
+        if xstate.len() != mem::size_of::<FloatRegisters>() {
+            return false;
+        }
+        self.set_fx_regs(unsafe { xstate.as_ptr().cast::<FloatRegisters>().read_unaligned() });
+        true
+    }
 }
 
 pub static EMPTY_CR3: Once<rmm::PhysicalAddress> = Once::new();
@@ -0,0 +1,421 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::{mem, slice};
+use core::ptr;
+use core::sync::atomic::AtomicBool;
+use memoffset::offset_of;
+use spin::Once;
+
+use crate::common::aligned_box::AlignedBox;
+use crate::interrupt::handler::ScratchRegisters;
+use crate::interrupt::trap;
+use crate::paging::{RmmA, RmmArch, TableKind};
+use crate::syscall::FloatRegisters;
+
+/// This must be used by the kernel to ensure that context switches are done atomically
+/// Compare and exchange this to true when beginning a context switch on any CPU
+/// The `Context::switch_to` function will set it back to false, allowing other CPU's to switch
+/// This must be done, as no locks can be held on the stack during switch
+pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
+
+// 256 bytes for registers, extra bytes for fcsr
+pub const KFX_SIZE: usize = 272;
+pub const KFX_ALIGN: usize = 16;
+
+const _: () = assert!(mem::size_of::<FloatRegisters>() <= KFX_SIZE);
+
+/// Kernel FX - the save area of the FPU registers of a context
+pub type Kfx = AlignedBox<[u8; KFX_SIZE], KFX_ALIGN>;
+
+#[derive(Clone, Debug)]
+pub struct Context {
+    pub(crate) tp: usize, /* Thread pointer of userspace, restored when returning to it */
+    fx_loadable: bool,
+    sp: usize,          /* Stack Pointer (x2)                                   */
+    ra: usize,          /* Return Address (x1)                                  */
+    s0: usize,          /* Frame pointer, callee saved Register                 */
+    s1: usize,          /* Callee saved Register                                */
+    s2: usize,          /* Callee saved Register                                */
+    s3: usize,          /* Callee saved Register                                */
+    s4: usize,          /* Callee saved Register                                */
+    s5: usize,          /* Callee saved Register                                */
+    s6: usize,          /* Callee saved Register                                */
+    s7: usize,          /* Callee saved Register                                */
+    s8: usize,          /* Callee saved Register                                */
+    s9: usize,          /* Callee saved Register                                */
+    s10: usize,         /* Callee saved Register                                */
+    s11: usize,         /* Callee saved Register                                */
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context {
+            tp: 0,
+            fx_loadable: false,
+            sp: 0,
+            ra: 0,
+            s0: 0,
+            s1: 0,
+            s2: 0,
+            s3: 0,
+            s4: 0,
+            s5: 0,
+            s6: 0,
+            s7: 0,
+            s8: 0,
+            s9: 0,
+            s10: 0,
+            s11: 0,
+        }
+    }
+
+    pub fn set_stack(&mut self, address: usize) {
+        self.sp = address;
+    }
+
+    pub fn set_lr(&mut self, address: usize) {
+        self.ra = address;
+    }
+
+    pub fn set_fp(&mut self, address: usize) {
+        self.s0 = address;
+    }
+
+    pub unsafe fn signal_stack(&mut self, handler: extern fn(usize), sig: u8) {
+        // Keep the stack 16-byte aligned, see `SignalHandlerStack`
+        self.push(0);
+        self.push(self.ra);
+        self.push(sig as usize);
+        self.push(handler as usize);
+        self.set_lr(signal_handler_wrapper as usize);
+    }
+
+    pub unsafe fn push(&mut self, value: usize) {
+        self.sp -= mem::size_of::<usize>();
+        *(self.sp as *mut usize) = value;
+    }
+
+    pub unsafe fn pop(&mut self) -> usize {
+        let value = *(self.sp as *const usize);
+        self.sp += mem::size_of::<usize>();
+        value
+    }
+
+    pub fn dump(&self) {
+        println!("tp: 0x{:016x}", self.tp);
+        println!("sp: 0x{:016x}", self.sp);
+        println!("ra: 0x{:016x}", self.ra);
+        println!("s0: 0x{:016x}", self.s0);
+        println!("s1: 0x{:016x}", self.s1);
+        println!("s2: 0x{:016x}", self.s2);
+        println!("s3: 0x{:016x}", self.s3);
+        println!("s4: 0x{:016x}", self.s4);
+        println!("s5: 0x{:016x}", self.s5);
+        println!("s6: 0x{:016x}", self.s6);
+        println!("s7: 0x{:016x}", self.s7);
+        println!("s8: 0x{:016x}", self.s8);
+        println!("s9: 0x{:016x}", self.s9);
+        println!("s10: 0x{:016x}", self.s10);
+        println!("s11: 0x{:016x}", self.s11);
+    }
+}
+
+impl super::Context {
+    pub fn get_fx_regs(&self) -> FloatRegisters {
+        if !self.arch.fx_loadable {
+            panic!("TODO: make get_fx_regs always work");
+        }
+
+        unsafe {
+            ptr::read(self.kfx.as_ptr() as *const FloatRegisters)
+        }
+    }
+
+    pub fn set_fx_regs(&mut self, new: FloatRegisters) {
+        if !self.arch.fx_loadable {
+            panic!("TODO: make set_fx_regs always work");
+        }
+
+        unsafe {
+            ptr::write(self.kfx.as_mut_ptr() as *mut FloatRegisters, new);
+        }
+    }
+
+    /// The floating point state, the same as `get_fx_regs`, as vector state is not saved
+    pub fn get_xstate(&self) -> Vec<u8> {
+        let regs = self.get_fx_regs();
+        unsafe { slice::from_raw_parts(&regs as *const FloatRegisters as *const u8, mem::size_of::<FloatRegisters>()) }.to_vec()
+    }
+
+    /// Set the floating point state from the format of `get_xstate`
+    pub fn set_xstate(&mut self, xstate: &[u8]) -> bool {
+        if xstate.len() != mem::size_of::<FloatRegisters>() {
+            return false;
+        }
+        self.set_fx_regs(unsafe { xstate.as_ptr().cast::<FloatRegisters>().read_unaligned() });
+        true
+    }
+}
+
+pub static EMPTY_CR3: Once<rmm::PhysicalAddress> = Once::new();
+
+// SAFETY: EMPTY_CR3 must be initialized.
+pub unsafe fn empty_cr3() -> rmm::PhysicalAddress {
+    debug_assert!(EMPTY_CR3.poll().is_some());
+    *EMPTY_CR3.get_unchecked()
+}
+
+pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
+    // The kernel is built without the F and D extensions, so that it never uses the FPU itself
+    let float_regs = &mut *(prev.kfx.as_mut_ptr() as *mut FloatRegisters);
+    asm!(
+        ".option push",
+        ".option arch, +d",
+        "fsd f0, 8 * 0({0})",
+        "fsd f1, 8 * 1({0})",
+        "fsd f2, 8 * 2({0})",
+        "fsd f3, 8 * 3({0})",
+        "fsd f4, 8 * 4({0})",
+        "fsd f5, 8 * 5({0})",
+        "fsd f6, 8 * 6({0})",
+        "fsd f7, 8 * 7({0})",
+        "fsd f8, 8 * 8({0})",
+        "fsd f9, 8 * 9({0})",
+        "fsd f10, 8 * 10({0})",
+        "fsd f11, 8 * 11({0})",
+        "fsd f12, 8 * 12({0})",
+        "fsd f13, 8 * 13({0})",
+        "fsd f14, 8 * 14({0})",
+        "fsd f15, 8 * 15({0})",
+        "fsd f16, 8 * 16({0})",
+        "fsd f17, 8 * 17({0})",
+        "fsd f18, 8 * 18({0})",
+        "fsd f19, 8 * 19({0})",
+        "fsd f20, 8 * 20({0})",
+        "fsd f21, 8 * 21({0})",
+        "fsd f22, 8 * 22({0})",
+        "fsd f23, 8 * 23({0})",
+        "fsd f24, 8 * 24({0})",
+        "fsd f25, 8 * 25({0})",
+        "fsd f26, 8 * 26({0})",
+        "fsd f27, 8 * 27({0})",
+        "fsd f28, 8 * 28({0})",
+        "fsd f29, 8 * 29({0})",
+        "fsd f30, 8 * 30({0})",
+        "fsd f31, 8 * 31({0})",
+        "csrr {1}, fcsr",
+        ".option pop",
+        in(reg) core::ptr::addr_of_mut!(float_regs.fregs),
+        out(reg) float_regs.fcsr,
+    );
+
+    prev.arch.fx_loadable = true;
+
+    if next.arch.fx_loadable {
+        let float_regs = &*(next.kfx.as_ptr() as *const FloatRegisters);
+        asm!(
+            ".option push",
+            ".option arch, +d",
+            "fld f0, 8 * 0({0})",
+            "fld f1, 8 * 1({0})",
+            "fld f2, 8 * 2({0})",
+            "fld f3, 8 * 3({0})",
+            "fld f4, 8 * 4({0})",
+            "fld f5, 8 * 5({0})",
+            "fld f6, 8 * 6({0})",
+            "fld f7, 8 * 7({0})",
+            "fld f8, 8 * 8({0})",
+            "fld f9, 8 * 9({0})",
+            "fld f10, 8 * 10({0})",
+            "fld f11, 8 * 11({0})",
+            "fld f12, 8 * 12({0})",
+            "fld f13, 8 * 13({0})",
+            "fld f14, 8 * 14({0})",
+            "fld f15, 8 * 15({0})",
+            "fld f16, 8 * 16({0})",
+            "fld f17, 8 * 17({0})",
+            "fld f18, 8 * 18({0})",
+            "fld f19, 8 * 19({0})",
+            "fld f20, 8 * 20({0})",
+            "fld f21, 8 * 21({0})",
+            "fld f22, 8 * 22({0})",
+            "fld f23, 8 * 23({0})",
+            "fld f24, 8 * 24({0})",
+            "fld f25, 8 * 25({0})",
+            "fld f26, 8 * 26({0})",
+            "fld f27, 8 * 27({0})",
+            "fld f28, 8 * 28({0})",
+            "fld f29, 8 * 29({0})",
+            "fld f30, 8 * 30({0})",
+            "fld f31, 8 * 31({0})",
+            "csrw fcsr, {1}",
+            ".option pop",
+            in(reg) core::ptr::addr_of!(float_regs.fregs),
+            in(reg) float_regs.fcsr,
+        );
+    }
+
+    // The user thread pointer lives in the trap data of the CPU while the context runs
+    prev.arch.tp = trap::user_tp();
+    trap::set_user_tp(next.arch.tp);
+
+    match next.addr_space {
+        // Since Arc is essentially just wraps a pointer, in this case a regular pointer (as
+        // opposed to dyn or slice fat pointers), and NonNull optimization exists, map_or will
+        // hopefully be optimized down to checking prev and next pointers, as next cannot be null.
+        Some(ref next_space) => if prev.addr_space.as_ref().map_or(true, |prev_space| !Arc::ptr_eq(&prev_space, &next_space)) {
+            // Suppose we have two sibling threads A and B. A runs on CPU 0 and B on CPU 1. A
+            // recently called yield and is now here about to switch back. Meanwhile, B is
+            // currently creating a new mapping in their shared address space, for example a
+            // message on a channel.
+            //
+            // Unless we acquire this lock, it may be possible that the TLB will not contain new
+            // entries. While this can be caught and corrected in a page fault handler, this is not
+            // true when entries are removed from a page table!
+            next_space.read().table.utable.make_current();
+        }
+        None => {
+            RmmA::set_table(TableKind::User, empty_cr3());
+        }
+    }
+
+    switch_to_inner(&mut prev.arch, &mut next.arch)
+}
+
+#[naked]
+unsafe extern "C" fn switch_to_inner(_prev: &mut Context, _next: &mut Context) {
+    core::arch::asm!(
+        "
+        sd s0, {off_s0}(a0)
+        ld s0, {off_s0}(a1)
+
+        sd s1, {off_s1}(a0)
+        ld s1, {off_s1}(a1)
+
+        sd s2, {off_s2}(a0)
+        ld s2, {off_s2}(a1)
+
+        sd s3, {off_s3}(a0)
+        ld s3, {off_s3}(a1)
+
+        sd s4, {off_s4}(a0)
+        ld s4, {off_s4}(a1)
+
+        sd s5, {off_s5}(a0)
+        ld s5, {off_s5}(a1)
+
+        sd s6, {off_s6}(a0)
+        ld s6, {off_s6}(a1)
+
+        sd s7, {off_s7}(a0)
+        ld s7, {off_s7}(a1)
+
+        sd s8, {off_s8}(a0)
+        ld s8, {off_s8}(a1)
+
+        sd s9, {off_s9}(a0)
+        ld s9, {off_s9}(a1)
+
+        sd s10, {off_s10}(a0)
+        ld s10, {off_s10}(a1)
+
+        sd s11, {off_s11}(a0)
+        ld s11, {off_s11}(a1)
+
+        sd ra, {off_ra}(a0)
+        ld ra, {off_ra}(a1)
+
+        sd sp, {off_sp}(a0)
+        ld sp, {off_sp}(a1)
+
+        tail {switch_hook}
+        ",
+        off_s0 = const(offset_of!(Context, s0)),
+        off_s1 = const(offset_of!(Context, s1)),
+        off_s2 = const(offset_of!(Context, s2)),
+        off_s3 = const(offset_of!(Context, s3)),
+        off_s4 = const(offset_of!(Context, s4)),
+        off_s5 = const(offset_of!(Context, s5)),
+        off_s6 = const(offset_of!(Context, s6)),
+        off_s7 = const(offset_of!(Context, s7)),
+        off_s8 = const(offset_of!(Context, s8)),
+        off_s9 = const(offset_of!(Context, s9)),
+        off_s10 = const(offset_of!(Context, s10)),
+        off_s11 = const(offset_of!(Context, s11)),
+        off_ra = const(offset_of!(Context, ra)),
+        off_sp = const(offset_of!(Context, sp)),
+
+        switch_hook = sym crate::context::switch_finish_hook,
+        options(noreturn),
+    );
+}
+
+/// The stack of `signal_handler_wrapper`, below what `Context::signal_stack` pushed
+#[allow(dead_code)]
+#[repr(C)]
+pub struct SignalHandlerStack {
+    scratch: ScratchRegisters,
+    handler: extern fn(usize),
+    sig: usize,
+    ra: usize,
+    padding: usize,
+}
+
+#[naked]
+unsafe extern fn signal_handler_wrapper() {
+    #[inline(never)]
+    unsafe extern "C" fn inner(stack: &SignalHandlerStack) {
+        (stack.handler)(stack.sig);
+    }
+
+    // Save scratch registers, in the order of `ScratchRegisters`
+    core::arch::asm!(
+        "
+        addi sp, sp, -{scratch}
+        sd ra, 0*8(sp)
+        sd t0, 1*8(sp)
+        sd t1, 2*8(sp)
+        sd t2, 3*8(sp)
+        sd a0, 4*8(sp)
+        sd a1, 5*8(sp)
+        sd a2, 6*8(sp)
+        sd a3, 7*8(sp)
+        sd a4, 8*8(sp)
+        sd a5, 9*8(sp)
+        sd a6, 10*8(sp)
+        sd a7, 11*8(sp)
+        sd t3, 12*8(sp)
+        sd t4, 13*8(sp)
+        sd t5, 14*8(sp)
+        sd t6, 15*8(sp)
+
+        mv a0, sp
+        call {inner}
+
+        ld t0, 1*8(sp)
+        ld t1, 2*8(sp)
+        ld t2, 3*8(sp)
+        ld a0, 4*8(sp)
+        ld a1, 5*8(sp)
+        ld a2, 6*8(sp)
+        ld a3, 7*8(sp)
+        ld a4, 8*8(sp)
+        ld a5, 9*8(sp)
+        ld a6, 10*8(sp)
+        ld a7, 11*8(sp)
+        ld t3, 12*8(sp)
+        ld t4, 13*8(sp)
+        ld t5, 14*8(sp)
+        ld t6, 15*8(sp)
+        addi sp, sp, {scratch}
+
+        ld ra, 2*8(sp)
+        addi sp, sp, 4*8
+        ret
+        ",
+        scratch = const mem::size_of::<ScratchRegisters>(),
+        inner = sym inner,
+        options(noreturn),
+    );
+}
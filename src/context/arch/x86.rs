@@ -1,9 +1,11 @@
-use core::mem;
+use core::{mem, slice};
 use core::sync::atomic::AtomicBool;
 
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use crate::{push_scratch, pop_scratch};
+use crate::common::aligned_box::AlignedBox;
 use crate::gdt::{pcr, GDT_USER_FS, GDT_USER_GS};
 use crate::interrupt::handler::ScratchRegisters;
 use crate::paging::{RmmA, RmmArch, TableKind};
@@ -23,6 +25,9 @@ const ST_RESERVED: u128 = 0xFFFF_FFFF_FFFF_0000_0000_0000_0000_0000;
 pub const KFX_SIZE: usize = 512;
 pub const KFX_ALIGN: usize = 16;
 
+/// Kernel FX - the save area of the FPU and SIMD registers of a context
+pub type Kfx = AlignedBox<[u8; KFX_SIZE], KFX_ALIGN>;
+
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct Context {
@@ -117,6 +122,21 @@ impl super::Context {
             self.kfx.as_mut_ptr().cast::<FloatRegisters>().write(new);
         }
     }
+
+    /// The floating point state, the same as `get_fx_regs`, as AVX state is not saved
+    pub fn get_xstate(&self) -> Vec<u8> {
+        let regs = self.get_fx_regs();
+        unsafe { slice::from_raw_parts(&regs as *const FloatRegisters as *const u8, mem::size_of::<FloatRegisters>()) }.to_vec()
+    }
+
+    /// Set the floating point state from the format of `get_xstate`
+    pub fn set_xstate(&mut self, xstate: &[u8]) -> bool {
+        if xstate.len() != mem::size_of::<FloatRegisters>() {
+            return false;
+        }
+        self.set_fx_regs(unsafe { xstate.as_ptr().cast::<FloatRegisters>().read_unaligned() });
+        true
+    }
 }
 
 pub static EMPTY_CR3: Once<rmm::PhysicalAddress> = Once::new();
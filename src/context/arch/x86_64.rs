@@ -1,12 +1,17 @@
+use core::alloc::{GlobalAlloc, Layout};
 use core::mem;
+use core::ptr::NonNull;
 use core::sync::atomic::AtomicBool;
 
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use crate::{push_scratch, pop_scratch};
+use crate::arch::x86_64::xsave;
 use crate::interrupt::handler::ScratchRegisters;
-use crate::paging::{RmmA, RmmArch, TableKind};
-use crate::syscall::FloatRegisters;
+use crate::memory::Enomem;
+use crate::paging::{PhysicalAddress, RmmA, RmmArch, TableKind};
+use crate::syscall::{FloatRegisters, IntRegisters};
 
 use memoffset::offset_of;
 use spin::Once;
@@ -19,8 +24,96 @@ pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
 
 const ST_RESERVED: u128 = 0xFFFF_FFFF_FFFF_0000_0000_0000_0000_0000;
 
-pub const KFX_SIZE: usize = 512;
-pub const KFX_ALIGN: usize = 16;
+/// Kernel FX - the save area of the FPU, SIMD and other extended registers of a context, sized for
+/// the components the CPU supports (see `arch::x86_64::xsave`)
+pub struct Kfx {
+    area: NonNull<u8>,
+    /// Whether the area holds the lazily allocated components
+    full: bool,
+}
+
+// The area is owned, like a Box
+unsafe impl Send for Kfx {}
+unsafe impl Sync for Kfx {}
+
+#[allow(clippy::len_without_is_empty)]
+impl Kfx {
+    fn layout(full: bool) -> Layout {
+        Layout::from_size_align(xsave::area_size(full), xsave::ALIGN).expect("invalid XSAVE area layout")
+    }
+
+    fn alloc(full: bool) -> Result<Self, Enomem> {
+        unsafe {
+            let area = NonNull::new(crate::ALLOCATOR.alloc_zeroed(Self::layout(full))).ok_or(Enomem)?;
+            xsave::init_area(area.as_ptr(), xsave::features(full));
+            Ok(Self { area, full })
+        }
+    }
+
+    /// An area in the initial state, without the lazily allocated components
+    pub fn try_zeroed() -> Result<Self, Enomem> {
+        Self::alloc(false)
+    }
+
+    /// The components the area holds
+    pub fn features(&self) -> u64 {
+        xsave::features(self.full)
+    }
+
+    pub fn len(&self) -> usize {
+        xsave::area_size(self.full)
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.area.as_ptr()
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.area.as_ptr()
+    }
+
+    /// Add the lazily allocated components, for the context currently running, once it has used
+    /// them. Its registers are saved into the new area, and restored from it with the new
+    /// components enabled. They are reset in the process, as they may still hold values of the
+    /// context that last used them.
+    pub unsafe fn grow(&mut self) -> Result<(), Enomem> {
+        if self.full {
+            return Ok(());
+        }
+        let mut new = Self::alloc(true)?;
+
+        xsave::save(self.as_mut_ptr(), self.features());
+        // Components are ordered by number in either format, and the lazily allocated ones come
+        // last, so the others have the same offsets. The header is kept.
+        let header = xsave::HEADER_OFFSET;
+        core::ptr::copy_nonoverlapping(self.as_ptr(), new.as_mut_ptr(), header + 8);
+        core::ptr::copy_nonoverlapping(self.as_ptr().add(header + 16), new.as_mut_ptr().add(header + 16), self.len() - header - 16);
+
+        xsave::restore(new.as_ptr(), new.features());
+        *self = new;
+        Ok(())
+    }
+}
+
+impl Clone for Kfx {
+    fn clone(&self) -> Self {
+        let mut new = Self::alloc(self.full).unwrap_or_else(|_| alloc::alloc::handle_alloc_error(Self::layout(self.full)));
+        unsafe { core::ptr::copy_nonoverlapping(self.as_ptr(), new.as_mut_ptr(), self.len()) };
+        new
+    }
+}
+
+impl Drop for Kfx {
+    fn drop(&mut self) {
+        unsafe { crate::ALLOCATOR.dealloc(self.area.as_ptr(), Self::layout(self.full)) };
+    }
+}
+
+impl core::fmt::Debug for Kfx {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "[kfx at {:p}, size {} features {:#x}]", self.area.as_ptr(), self.len(), self.features())
+    }
+}
 
 #[derive(Clone, Debug)]
 #[repr(C)]
@@ -89,6 +182,29 @@ impl Context {
         self.rsp += mem::size_of::<usize>();
         value
     }
+
+    /// The registers of the kernel code that switched away from this context, as they are once
+    /// `switch_to` returns to it, for debuggers. Only the callee-saved registers are known.
+    ///
+    /// # Safety
+    /// The context must not be running, for its stack to hold the return address.
+    pub unsafe fn switched_registers(&self) -> Option<IntRegisters> {
+        if self.rsp == 0 {
+            return None;
+        }
+        Some(IntRegisters {
+            rflags: self.rflags,
+            rbx: self.rbx,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rbp: self.rbp,
+            rip: *(self.rsp as *const usize),
+            rsp: self.rsp + mem::size_of::<usize>(),
+            ..IntRegisters::default()
+        })
+    }
 }
 impl super::Context {
     pub fn get_fx_regs(&self) -> FloatRegisters {
@@ -120,8 +236,71 @@ impl super::Context {
 
         unsafe {
             self.kfx.as_mut_ptr().cast::<FloatRegisters>().write(new);
+            xsave::set_legacy_in_use(self.kfx.as_mut_ptr());
         }
     }
+
+    /// The extended register state, as an XSAVE area in the standard format, like `NT_X86_XSTATE`
+    /// on Linux (see `xsave::to_standard`)
+    pub fn get_xstate(&self) -> Vec<u8> {
+        let mut xstate = vec![0; xsave::standard_size(self.kfx.features())];
+        unsafe { xsave::to_standard(self.kfx.as_ptr(), self.kfx.features(), &mut xstate) };
+        xstate
+    }
+
+    /// Set the extended register state from an XSAVE area in the standard format, of the size
+    /// `get_xstate` returns. Fails if restoring it would fault.
+    pub fn set_xstate(&mut self, xstate: &[u8]) -> bool {
+        let features = self.kfx.features();
+        unsafe { xsave::from_standard(xstate, self.kfx.as_mut_ptr(), features) }
+    }
+}
+
+/// FSBASE and GSBASE of the running context, as seen by userspace
+pub unsafe fn read_fsgsbase() -> (usize, usize) {
+    #[cfg(not(feature = "x86_fsgsbase"))]
+    {
+        // The user GSBASE is in KERNEL_GSBASE while the kernel runs, after SWAPGS
+        (x86::msr::rdmsr(x86::msr::IA32_FS_BASE) as usize, x86::msr::rdmsr(x86::msr::IA32_KERNEL_GSBASE) as usize)
+    }
+    #[cfg(feature = "x86_fsgsbase")]
+    {
+        use x86::bits64::segmentation::*;
+
+        let fsbase = rdfsbase();
+        swapgs();
+        let gsbase = rdgsbase();
+        swapgs();
+        (fsbase as usize, gsbase as usize)
+    }
+}
+
+/// Set FSBASE and GSBASE of the running context, whose architecture specific context is `arch`.
+/// The addresses must be canonical.
+pub unsafe fn write_fsgsbase(arch: &mut Context, fsbase: usize, gsbase: usize) {
+    #[cfg(not(feature = "x86_fsgsbase"))]
+    {
+        x86::msr::wrmsr(x86::msr::IA32_FS_BASE, fsbase as u64);
+        // We have to write to KERNEL_GSBASE, because when the kernel returns to userspace, it
+        // will have executed SWAPGS first.
+        x86::msr::wrmsr(x86::msr::IA32_KERNEL_GSBASE, gsbase as u64);
+
+        arch.fsbase = fsbase;
+        arch.gsbase = gsbase;
+    }
+    #[cfg(feature = "x86_fsgsbase")]
+    {
+        use x86::bits64::segmentation::*;
+
+        wrfsbase(fsbase as u64);
+        swapgs();
+        wrgsbase(gsbase as u64);
+        swapgs();
+
+        // No need to update the context; with fsgsbase enabled, these registers are automatically
+        // saved and restored.
+        let _ = arch;
+    }
 }
 
 pub static EMPTY_CR3: Once<rmm::PhysicalAddress> = Once::new();
@@ -134,12 +313,8 @@ pub unsafe fn empty_cr3() -> rmm::PhysicalAddress {
 
 /// Switch to the next context by restoring its stack and registers
 pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
-    core::arch::asm!("
-        fxsave64 [{prev_fx}]
-        fxrstor64 [{next_fx}]
-        ", prev_fx = in(reg) prev.kfx.as_mut_ptr(),
-        next_fx = in(reg) next.kfx.as_ptr(),
-    );
+    xsave::save(prev.kfx.as_mut_ptr(), prev.kfx.features());
+    xsave::restore(next.kfx.as_ptr(), next.kfx.features());
 
     {
         use x86::{bits64::segmentation::*, msr};
@@ -159,6 +334,11 @@ pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
         }
     }
 
+    // Branch predictions made in one user's address space must not be used in another's
+    let address_space_changed = next.addr_space.as_ref().map_or(false, |next_space| prev.addr_space.as_ref().map_or(true, |prev_space| !Arc::ptr_eq(prev_space, next_space)));
+    let user_changed = prev.ruid != next.ruid || prev.euid != next.euid;
+    crate::arch::mitigations::on_switch(address_space_changed, user_changed);
+
     match next.addr_space {
         // Since Arc essentially just wraps a pointer, in this case a regular pointer (as opposed
         // to dyn or slice fat pointers), and NonNull optimization exists, map_or will hopefully be
@@ -172,9 +352,18 @@ pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
             // Unless we acquire this lock, it may be possible that the TLB will not contain new
             // entries. While this can be caught and corrected in a page fault handler, this is not
             // true when entries are removed from a page table!
-            next_space.read().table.utable.make_current();
+            //
+            // The holder of the lock may be waiting for this CPU to handle a TLB shootdown
+            let next_space = crate::arch::tlb::spin_handling(|| next_space.try_read());
+            crate::arch::tlb::set_active(next_space.table.utable.table().phys());
+            #[cfg(feature = "pti")]
+            crate::arch::pti::set_tables(next_space.table.utable.table().phys(), next_space.table.pti_table);
+            next_space.table.utable.make_current();
         }
         None => {
+            crate::arch::tlb::set_active(PhysicalAddress::new(0));
+            #[cfg(feature = "pti")]
+            crate::arch::pti::set_tables(empty_cr3(), empty_cr3());
             RmmA::set_table(TableKind::User, empty_cr3());
         }
     }
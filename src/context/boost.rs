@@ -0,0 +1,47 @@
+//! # Wakeup boost
+//! Contexts woken by input are scheduled before any other runnable context, so that terminal echo
+//! and GUI updates do not have to wait for every busy context to use up its time slice. The
+//! sources of wakeups that count as input are configurable (see `BOOST_*` and `sched:boost`).
+//!
+//! A boost is used up when the context is next switched to, and expires after `duration`, so a
+//! context cannot starve others by waking itself repeatedly any faster than input arrives.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::syscall::abi::{SchedBoost, BOOST_DEBUG, BOOST_SERIO};
+use crate::time;
+
+/// Which wakeups boost the woken context
+static CLASSES: AtomicUsize = AtomicUsize::new(BOOST_SERIO | BOOST_DEBUG);
+/// How long a boost lasts, in nanoseconds
+static DURATION: AtomicU64 = AtomicU64::new(10_000_000);
+/// The latest time any boost lasts until, so that the scheduler only looks for boosted contexts
+/// when there may be some
+static ACTIVE_UNTIL: AtomicU64 = AtomicU64::new(0);
+
+/// The time until which a context woken by `class` is boosted, if `class` is boosted at all
+pub fn deadline(class: usize) -> Option<u128> {
+    if CLASSES.load(Ordering::Relaxed) & class == 0 {
+        return None;
+    }
+    let deadline = time::monotonic() + DURATION.load(Ordering::Relaxed) as u128;
+    ACTIVE_UNTIL.fetch_max(deadline as u64, Ordering::Relaxed);
+    Some(deadline)
+}
+
+/// Whether any context may still be boosted at `now`
+pub fn active(now: u128) -> bool {
+    now < ACTIVE_UNTIL.load(Ordering::Relaxed) as u128
+}
+
+pub fn policy() -> SchedBoost {
+    SchedBoost {
+        classes: CLASSES.load(Ordering::Relaxed),
+        duration: (DURATION.load(Ordering::Relaxed) / 1000) as usize,
+    }
+}
+
+pub fn set_policy(policy: SchedBoost) {
+    CLASSES.store(policy.classes, Ordering::Relaxed);
+    DURATION.store(policy.duration as u64 * 1000, Ordering::Relaxed);
+}
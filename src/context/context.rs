@@ -1,25 +1,34 @@
 use alloc::{
     boxed::Box,
-    collections::VecDeque,
     sync::Arc,
-    vec::Vec, borrow::Cow,
+    vec::Vec,
 };
 use core::{
     cmp::Ordering,
     mem,
 };
-use spin::RwLock;
 
 use crate::arch::{interrupt::InterruptStack, paging::PAGE_SIZE};
 use crate::common::aligned_box::AlignedBox;
 use crate::common::unique::Unique;
 use crate::context::{self, arch};
 use crate::context::file::{FileDescriptor, FileDescription};
+use crate::context::kstack::KernelStack;
+use crate::context::label::Label;
+use crate::context::latency::Latency;
+use crate::context::sigqueue::SigQueue;
+use crate::context::syscalls::SyscallStats;
 use crate::context::memory::AddrSpace;
-use crate::ipi::{ipi, IpiKind, IpiTarget};
+use crate::context::pid_ns::PidNamespace;
+use crate::context::thread_group::ThreadGroup;
+use crate::context::wakeups::Wakeups;
+use crate::ipi::{ipi_single, IpiKind};
+use crate::perf::Counter;
 use crate::scheme::{SchemeNamespace, FileHandle};
-use crate::sync::WaitMap;
+use crate::sync::{RwLock, WaitMap};
+use crate::time;
 
+use crate::syscall::abi::{Rusage, SigInfo};
 use crate::syscall::data::SigAction;
 use crate::syscall::error::{Result, Error, EAGAIN, EINVAL, ESRCH};
 use crate::syscall::flag::{SIG_DFL, SigActionFlags};
@@ -126,7 +135,7 @@ pub struct ContextSnapshot {
 impl ContextSnapshot {
     //TODO: Should this accept &mut Context to ensure name/files will not change?
     pub fn new(context: &Context) -> Self {
-        let name = context.name.clone().into_owned().into_boxed_str();
+        let name = Box::from(context.name.as_str());
         let mut files = Vec::new();
         for descriptor_opt in context.files.read().iter() {
             let description = if let Some(descriptor) = descriptor_opt {
@@ -208,8 +217,29 @@ pub struct Context {
     /// this value.
     // TODO: bitmask (selection of multiple allowed CPUs)?
     pub sched_affinity: Option<usize>,
+    /// Adjustment of the badness of this context when choosing what to kill when out of memory,
+    /// see `oom`
+    pub oom_score_adj: i16,
+    /// Time until which this context is scheduled ahead of others, after being woken by input,
+    /// see `boost`
+    pub boost: Option<u128>,
+    /// Membership in the low-latency class, see `latency`
+    pub latency: Option<Latency>,
+    /// Timer and IRQ wakeups armed by this context, see `wakeups`
+    pub wakeups: Arc<Wakeups>,
+    /// Performance counters counting this context, see `perf`
+    pub perf: Vec<Arc<Counter>>,
+    /// Page faults handled without and with waiting for I/O, see `Rusage`
+    pub minor_faults: u64,
+    pub major_faults: u64,
+    /// Resource usage of reaped children, and their reaped children, kept by the group leader
+    pub children_usage: Rusage,
+    /// Resource usage of the reaped threads of the group, kept by the group leader
+    pub threads_usage: Rusage,
     /// Current system call
     pub syscall: Option<(usize, usize, usize, usize, usize, usize)>,
+    /// Counts and latencies of the system calls made, see `syscalls`
+    pub syscall_stats: SyscallStats,
     /// Head buffer to use when system call buffers are not page aligned
     // TODO: Store in user memory?
     pub syscall_head: Option<AlignedBox<[u8; PAGE_SIZE], PAGE_SIZE>>,
@@ -221,17 +251,25 @@ pub struct Context {
     /// Context is being waited on
     pub waitpid: Arc<WaitMap<WaitpidKey, (ContextId, usize)>>,
     /// Context should handle pending signals
-    pub pending: VecDeque<u8>,
+    pub pending: SigQueue,
+    /// Information about the signal most recently delivered to this context
+    pub siginfo: Option<SigInfo>,
+    /// Thread group, shared by all threads of a process
+    pub thread_group: Arc<ThreadGroup>,
+    /// The PID namespace of the context, `None` for the root namespace
+    pub pid_ns: Option<Arc<PidNamespace>>,
+    /// The PID namespace processes created by the context are placed in
+    pub pid_ns_for_children: Option<Arc<PidNamespace>>,
     /// Context should wake up at specified time
     pub wake: Option<u128>,
     /// The architecture specific context
     pub arch: arch::Context,
     /// Kernel FX - used to store SIMD and FPU registers on context switch
-    pub kfx: AlignedBox<[u8; arch::KFX_SIZE], {arch::KFX_ALIGN}>,
+    pub kfx: arch::Kfx,
     /// Kernel stack
-    pub kstack: Option<Box<[u8]>>,
+    pub kstack: Option<KernelStack>,
     /// Kernel signal backup: Registers, Kernel FX, Kernel Stack, Signal number
-    pub ksig: Option<(arch::Context, AlignedBox<[u8; arch::KFX_SIZE], {arch::KFX_ALIGN}>, Option<Box<[u8]>>, u8)>,
+    pub ksig: Option<(arch::Context, arch::Kfx, Option<Box<[u8]>>, u8)>,
     /// Restore ksig context on next switch
     pub ksig_restore: bool,
     /// Address space containing a page table lock, and grants. Normally this will have a value,
@@ -239,9 +277,8 @@ pub struct Context {
     /// not yet had its address space changed. Note that these are only for user mappings; kernel
     /// mappings are universal and independent on address spaces or contexts.
     pub addr_space: Option<Arc<RwLock<AddrSpace>>>,
-    /// The name of the context
-    // TODO: fixed size ArrayString?
-    pub name: Cow<'static, str>,
+    /// The hierarchical debug label of the context, see `Label`
+    pub name: Label,
     /// The open files in the scheme
     pub files: Arc<RwLock<Vec<Option<FileDescriptor>>>>,
     /// Signal actions
@@ -287,20 +324,34 @@ impl Context {
             switch_time: 0,
             cpu_time: 0,
             sched_affinity: None,
+            oom_score_adj: 0,
+            boost: None,
+            latency: None,
+            wakeups: Arc::new(Wakeups::new()),
+            perf: Vec::new(),
+            minor_faults: 0,
+            major_faults: 0,
+            children_usage: Rusage::default(),
+            threads_usage: Rusage::default(),
             syscall: None,
+            syscall_stats: SyscallStats::new(),
             syscall_head: Some(AlignedBox::try_zeroed()?),
             syscall_tail: Some(AlignedBox::try_zeroed()?),
             vfork: false,
             waitpid: Arc::new(WaitMap::new()),
-            pending: VecDeque::new(),
+            pending: SigQueue::new(),
+            siginfo: None,
+            thread_group: Arc::new(ThreadGroup::new(id)),
+            pid_ns: None,
+            pid_ns_for_children: None,
             wake: None,
             arch: arch::Context::new(),
-            kfx: AlignedBox::<[u8; arch::KFX_SIZE], {arch::KFX_ALIGN}>::try_zeroed()?,
+            kfx: arch::Kfx::try_zeroed()?,
             kstack: None,
             ksig: None,
             ksig_restore: false,
             addr_space: None,
-            name: Cow::Borrowed(""),
+            name: Label::default(),
             files: Arc::new(RwLock::new(Vec::new())),
             actions: Self::empty_actions(),
             regs: None,
@@ -311,6 +362,41 @@ impl Context {
         Ok(this)
     }
 
+    /// The thread group ID, which is also the process ID seen by userspace
+    pub fn tgid(&self) -> ContextId {
+        self.thread_group.tgid
+    }
+
+    /// Resource usage of this context alone
+    pub fn usage(&self) -> Rusage {
+        Rusage {
+            cpu_time: self.cpu_time as u64,
+            minor_faults: self.minor_faults,
+            major_faults: self.major_faults,
+        }
+    }
+
+    /// Returns true if this context is the leader of its thread group
+    pub fn is_group_leader(&self) -> bool {
+        self.id == self.thread_group.tgid
+    }
+
+    /// Returns true if this context or its thread group has signals pending
+    pub fn has_pending_signals(&self) -> bool {
+        !self.pending.is_empty() || !self.thread_group.pending.lock().is_empty()
+    }
+
+    /// Take the lowest-numbered pending signal, preferring signals directed at this specific
+    /// thread if both it and its thread group have it pending
+    pub fn pop_pending_signal(&mut self) -> Option<SigInfo> {
+        let mut group = self.thread_group.pending.lock();
+        match (self.pending.next(), group.next()) {
+            (Some(own), Some(shared)) if shared < own => group.pop(),
+            (Some(_), _) => self.pending.pop(),
+            (None, _) => group.pop(),
+        }
+    }
+
     /// Block the context, and return true if it was runnable before being blocked
     pub fn block(&mut self, reason: &'static str) -> bool {
         if self.status == Status::Runnable {
@@ -327,11 +413,21 @@ impl Context {
         if self.status == Status::Blocked {
             self.status = Status::Runnable;
             self.status_reason = "";
+            crate::tracepoint!(SchedWakeup, self.id.into(), 0);
+
+            // Low-latency contexts are switched to right away, instead of at the next tick
+            let kind = match self.latency {
+                Some(ref mut latency) => {
+                    latency.wake(time::monotonic());
+                    IpiKind::Switch
+                }
+                None => IpiKind::Wakeup,
+            };
 
             if let Some(cpu_id) = self.cpu_id {
                if cpu_id != crate::cpu_id() {
                     // Send IPI if not on current CPU
-                    ipi(IpiKind::Wakeup, IpiTarget::Other);
+                    ipi_single(kind, cpu_id);
                }
             }
 
@@ -0,0 +1,304 @@
+//! # Core dumps
+//! When a context is killed by a signal whose default action is to dump core, such as `SIGSEGV`,
+//! an ELF core of it is written to the path made from the core pattern, which root sets through
+//! `dump:pattern` (see `scheme::dump`). Nothing is written while the pattern is empty, as it is by
+//! default. In the pattern, `%p` stands for the PID, `%e` for the last component of the name of
+//! the context, `%s` for the signal, `%t` for the seconds since the epoch, and `%%` for `%`.
+//!
+//! The dying context opens the path itself, with its credentials and namespace, so that the core
+//! can go to any scheme. It has a segment for each grant the context owns, with the pages not
+//! mapped yet written as zeroes, while borrowed grants such as physmaps are left out. Its notes,
+//! named `REDOX`, hold the registers in the formats of `proc:` (`regs/int` and `regs/xstate`), and
+//! the signal. Schemes provided by userspace read what is written from the memory of the writer,
+//! so everything is staged through a grant mapped in the dying context for the purpose.
+//!
+//! The exit status of a context that dumped core has `CORE_FLAG` set, like `WCOREDUMP`.
+
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::{mem, slice};
+
+use crate::context::{self, memory::AddrSpace};
+use crate::elf::{self, program_header, CoreWriter};
+use crate::log::{info, warn};
+use crate::memory::PAGE_SIZE;
+use crate::paging::VirtualAddress;
+use crate::ptrace;
+use crate::scheme::memory::MemoryScheme;
+use crate::scheme::FileHandle;
+use crate::sync::RwLock;
+use crate::syscall::data::Map;
+use crate::syscall::error::*;
+use crate::syscall::flag::{
+    MapFlags, O_CLOEXEC, O_CREAT, O_TRUNC, O_WRONLY, SIGABRT, SIGBUS, SIGFPE, SIGILL, SIGQUIT,
+    SIGSEGV, SIGSYS, SIGTRAP, SIGXCPU, SIGXFSZ,
+};
+use crate::syscall::usercopy::UserSlice;
+use crate::syscall::{self, IntRegisters};
+use crate::time;
+
+/// Name of the notes of a core
+pub const NOTE_NAME: &str = "REDOX";
+/// The integer registers, like `proc:regs/int`
+pub const NOTE_INT: u32 = 1;
+/// The extended register state, like `proc:regs/xstate`
+pub const NOTE_XSTATE: u32 = 2;
+/// The signal, as a native word
+pub const NOTE_SIGNAL: u32 = 3;
+
+/// Set in the exit status of contexts that dumped core
+pub const CORE_FLAG: usize = 0x80;
+
+/// Longest core pattern
+pub const PATTERN_MAX: usize = 256;
+
+/// Size of the grant the core is staged through
+const STAGING_SIZE: usize = 64 * 1024;
+
+static PATTERN: RwLock<String> = RwLock::new(String::new());
+
+/// The core pattern
+pub fn pattern() -> String {
+    PATTERN.read().clone()
+}
+
+/// Replace the core pattern, disabling core dumps if empty
+pub fn set_pattern(pattern: &str) -> Result<()> {
+    if pattern.len() > PATTERN_MAX || pattern.contains('\n') {
+        return Err(Error::new(EINVAL));
+    }
+    *PATTERN.write() = String::from(pattern);
+    Ok(())
+}
+
+/// Returns true if the default action of `sig` is to dump core
+pub fn dumps_core(sig: usize) -> bool {
+    matches!(sig, SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGSYS | SIGXCPU | SIGXFSZ)
+}
+
+fn expand(pattern: &str, pid: usize, name: &str, sig: usize) -> String {
+    let name = name.rsplit('/').next().unwrap_or(name);
+    let mut path = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue;
+        }
+        let _ = match chars.next() {
+            Some('p') => write!(path, "{}", pid),
+            Some('e') => write!(path, "{}", name),
+            Some('s') => write!(path, "{}", sig),
+            Some('t') => write!(path, "{}", time::realtime() / time::NANOS_PER_SEC),
+            Some('%') => write!(path, "%"),
+            Some(other) => write!(path, "%{}", other),
+            None => write!(path, "%"),
+        };
+    }
+    path
+}
+
+/// A grant written as a segment
+struct Segment {
+    start: usize,
+    size: usize,
+    flags: u32,
+}
+
+/// A grant mapped in the dying context, to stage what is written through
+struct Staging {
+    base: usize,
+}
+
+impl Staging {
+    fn new() -> Result<Staging> {
+        let map = Map {
+            offset: 0,
+            size: STAGING_SIZE,
+            flags: MapFlags::PROT_READ | MapFlags::PROT_WRITE | MapFlags::MAP_PRIVATE,
+            address: 0,
+        };
+        let base = MemoryScheme::fmap_anonymous(&AddrSpace::current()?, &map)?;
+        Ok(Staging { base })
+    }
+
+    fn open(&self, path: &str) -> Result<FileHandle> {
+        let buf = UserSlice::wo(self.base, path.len())?;
+        buf.copy_from_slice(path.as_bytes())?;
+        syscall::open(UserSlice::ro(self.base, path.len())?, O_WRONLY | O_CREAT | O_TRUNC | O_CLOEXEC | 0o600)
+    }
+
+    fn write(&self, fd: FileHandle, data: &[u8]) -> Result<()> {
+        for chunk in data.chunks(STAGING_SIZE) {
+            UserSlice::wo(self.base, chunk.len())?.copy_from_slice(chunk)?;
+            let mut written = 0;
+            while written < chunk.len() {
+                let buf = UserSlice::ro(self.base + written, chunk.len() - written)?;
+                match syscall::file_op_generic(fd, |scheme, _, number| scheme.kwrite(number, buf))? {
+                    0 => return Err(Error::new(EIO)),
+                    count => written += count,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = syscall::funmap(self.base, STAGING_SIZE);
+    }
+}
+
+/// The headers and notes of the core, with the segments starting at the page boundary after them
+fn core_headers(segments: &[Segment], notes: &[(u32, &[u8])]) -> Result<Vec<u8>> {
+    let notes_size: usize = notes.iter().map(|(_, desc)| elf::note_size(NOTE_NAME, desc.len())).sum();
+    let phnum = 1 + segments.len();
+    let mut buf = vec![0; elf::header::SIZEOF_EHDR + phnum * program_header::SIZEOF_PHDR + notes_size];
+    let mut writer = CoreWriter::new(&mut buf, phnum).ok_or(Error::new(E2BIG))?;
+
+    let notes_start = writer.len();
+    for &(kind, desc) in notes {
+        writer.note(NOTE_NAME, kind, desc);
+    }
+    writer.segment(program_header::ProgramHeader {
+        p_type: program_header::PT_NOTE,
+        p_flags: program_header::PF_R,
+        p_offset: notes_start as _,
+        p_vaddr: 0,
+        p_paddr: 0,
+        p_filesz: notes_size as _,
+        p_memsz: 0,
+        p_align: 4,
+    });
+
+    let mut offset = writer.len().next_multiple_of(PAGE_SIZE);
+    for segment in segments {
+        writer.segment(program_header::ProgramHeader {
+            p_type: program_header::PT_LOAD,
+            p_flags: segment.flags,
+            p_offset: offset as _,
+            p_vaddr: segment.start as _,
+            p_paddr: 0,
+            p_filesz: segment.size as _,
+            p_memsz: segment.size as _,
+            p_align: PAGE_SIZE as _,
+        });
+        offset += segment.size;
+    }
+    writer.finish();
+    Ok(buf)
+}
+
+/// Write `headers`, then the contents of `segments`, returning the size of the core
+fn write_contents(staging: &Staging, fd: FileHandle, headers: &[u8], segments: &[Segment], addr_space: &RwLock<AddrSpace>) -> Result<usize> {
+    staging.write(fd, headers)?;
+    let padding = headers.len().next_multiple_of(PAGE_SIZE) - headers.len();
+    staging.write(fd, &[0; PAGE_SIZE][..padding])?;
+
+    let mut size = headers.len() + padding;
+    let mut chunk = vec![0; STAGING_SIZE];
+    for segment in segments {
+        for offset in (0..segment.size).step_by(STAGING_SIZE) {
+            let address = segment.start + offset;
+            let len = (segment.size - offset).min(STAGING_SIZE);
+            let chunk = &mut chunk[..len];
+            {
+                // Released before writing, which may need it to read the staging grant
+                let mut addr_space = addr_space.write();
+                let mut pos = 0;
+                for page in ptrace::context_memory(&mut addr_space, VirtualAddress::new(address), len) {
+                    let page_len = match page {
+                        Some((page, _)) => {
+                            let page = unsafe { &*page };
+                            chunk[pos..pos + page.len()].copy_from_slice(page);
+                            page.len()
+                        }
+                        // Not mapped yet
+                        None => {
+                            let page_len = (PAGE_SIZE - (address + pos) % PAGE_SIZE).min(len - pos);
+                            chunk[pos..pos + page_len].fill(0);
+                            page_len
+                        }
+                    };
+                    pos += page_len;
+                }
+            }
+            staging.write(fd, chunk)?;
+            size += len;
+        }
+    }
+    Ok(size)
+}
+
+/// Write the core of the current context, killed by `sig`, returning its path and size
+fn write_core(pattern: &str, sig: usize) -> Result<(String, usize)> {
+    let pid: usize = syscall::getpid()?.into();
+    let (name, regs, xstate, addr_space) = {
+        let context_lock = context::current()?;
+        let context = context_lock.read();
+        let regs = unsafe { ptrace::regs_for(&context) }.map(|stack| {
+            let mut regs = IntRegisters::default();
+            stack.save(&mut regs);
+            regs
+        });
+        (context.name.to_string(), regs, context.get_xstate(), Arc::clone(context.addr_space()?))
+    };
+
+    let segments: Vec<Segment> = addr_space
+        .read()
+        .grants
+        .iter()
+        .filter(|grant| grant.is_owned())
+        .map(|grant| {
+            let flags = grant.flags();
+            let mut p_flags = program_header::PF_R;
+            if flags.has_write() {
+                p_flags |= program_header::PF_W;
+            }
+            if flags.has_execute() {
+                p_flags |= program_header::PF_X;
+            }
+            Segment {
+                start: grant.start_address().data(),
+                size: grant.size(),
+                flags: p_flags,
+            }
+        })
+        .collect();
+
+    let regs_bytes = regs.as_ref().map_or(&[][..], |regs| unsafe {
+        slice::from_raw_parts(regs as *const IntRegisters as *const u8, mem::size_of::<IntRegisters>())
+    });
+    let sig_bytes = sig.to_ne_bytes();
+    let headers = core_headers(&segments, &[(NOTE_INT, regs_bytes), (NOTE_XSTATE, &xstate), (NOTE_SIGNAL, &sig_bytes)])?;
+
+    // Mapped after listing the grants, to be left out
+    let staging = Staging::new()?;
+    let path = expand(pattern, pid, &name, sig);
+    let fd = staging.open(&path)?;
+    let result = write_contents(&staging, fd, &headers, &segments, &addr_space);
+    let _ = syscall::close(fd);
+    result.map(|size| (path, size))
+}
+
+/// Write a core of the current context, killed by `sig`, if the core pattern is set. Returns true
+/// if it was written.
+pub fn write(sig: usize) -> bool {
+    let pattern = pattern();
+    if pattern.is_empty() {
+        return false;
+    }
+    match write_core(&pattern, sig) {
+        Ok((path, size)) => {
+            info!("Core of {} bytes written to {}", size, path);
+            true
+        }
+        Err(err) => {
+            warn!("Failed to write core: {}", err);
+            false
+        }
+    }
+}
@@ -2,8 +2,8 @@
 
 use alloc::sync::Arc;
 use crate::event;
-use spin::RwLock;
 use crate::scheme::{self, SchemeNamespace, SchemeId};
+use crate::sync::RwLock;
 use crate::syscall::error::{Result, Error, EBADF};
 
 /// A file description
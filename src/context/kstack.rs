@@ -0,0 +1,280 @@
+//! # Kernel stacks
+//! Kernel stacks end in a canary, a word chosen at boot and written at their lowest address, which
+//! is checked when switching away from a context and when returning from a syscall. A corrupt
+//! canary means the stack overflowed into whatever lies below, and is fatal.
+//!
+//! On x86_64, kernel stacks are also not allocated from the heap, but in their own PML4, with
+//! unmapped space below each of them. An overflow past the canary then faults on this guard area
+//! instead of corrupting adjacent allocations. Pushing onto the guard area cannot deliver the page
+//! fault on the same stack, and turns into a double fault, which runs on its own stack (see
+//! `gdt::IST_DOUBLE_FAULT`) and reports the overflow using `guard_hit`.
+
+use core::fmt;
+
+use spin::Once;
+
+use crate::context::ContextId;
+use crate::syscall::error::Result;
+
+static CANARY: Once<usize> = Once::new();
+
+fn canary() -> usize {
+    *CANARY.call_once(|| crate::entropy::next_u64() as usize)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub use self::guarded::{guard_hit, init, set_current, KernelStack};
+
+#[cfg(not(target_arch = "x86_64"))]
+pub use self::heap::{init, set_current, KernelStack};
+
+impl KernelStack {
+    /// Allocate a zeroed kernel stack of `size` bytes, a multiple of the page size, with the
+    /// canary in place
+    pub fn new(size: usize) -> Result<Self> {
+        let mut stack = Self::allocate(size)?;
+        stack.fill(0);
+        stack.write_canary();
+        Ok(stack)
+    }
+
+    fn write_canary(&mut self) {
+        let canary = canary();
+        self[..core::mem::size_of::<usize>()].copy_from_slice(&canary.to_ne_bytes());
+    }
+
+    /// Returns true if the canary is intact
+    pub fn canary_intact(&self) -> bool {
+        let mut bytes = [0_u8; core::mem::size_of::<usize>()];
+        bytes.copy_from_slice(&self[..core::mem::size_of::<usize>()]);
+        usize::from_ne_bytes(bytes) == canary()
+    }
+
+    /// Panic if the canary of the stack of context `id` was overwritten
+    pub fn check_canary(&self, id: ContextId) {
+        if !self.canary_intact() {
+            panic!("kernel stack overflow: canary of context {} at {:p} overwritten", id.into(), self.as_ptr());
+        }
+    }
+}
+
+impl fmt::Debug for KernelStack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KernelStack({:p}, {:#x})", self.as_ptr(), self.len())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod guarded {
+    use alloc::vec::Vec;
+    use core::ops::{Deref, DerefMut};
+    use core::slice;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::context::{KSTACK_SIZE_DEFAULT, KSTACK_SIZE_MAX};
+    use crate::ipi::{ipi, IpiKind, IpiTarget};
+    use crate::memory::{deallocate_frames, Frame, PAGE_SIZE};
+    use crate::paging::mapper::PageFlushAll;
+    use crate::paging::{KernelMapper, Page, PageFlags, PageMapper, VirtualAddress};
+    use crate::sync::Mutex;
+    use crate::syscall::error::{Error, Result, ENOMEM};
+
+    /// Each stack is at the top of a slot, the rest of which is left unmapped, at least one page
+    const SLOT_SIZE: usize = KSTACK_SIZE_MAX + PAGE_SIZE;
+    const SLOT_COUNT: usize = crate::PML4_SIZE / SLOT_SIZE;
+    /// Number of unused stacks of the default size kept mapped for reuse, which saves unmapping
+    /// them and flushing the TLBs of all CPUs
+    const CACHE_MAX: usize = 64;
+
+    struct Slots {
+        /// The lowest slot never used
+        next: usize,
+        /// Slots used before, and now unmapped
+        free: Vec<usize>,
+        /// Slots with a stack of the default size still mapped
+        cached: Vec<usize>,
+    }
+
+    static SLOTS: Mutex<Slots> = Mutex::new(Slots {
+        next: 0,
+        free: Vec::new(),
+        cached: Vec::new(),
+    });
+
+    /// The lowest address of the kernel stack of the current context, or 0
+    #[thread_local]
+    static CURRENT_BASE: AtomicUsize = AtomicUsize::new(0);
+
+    fn slot_start(slot: usize) -> usize {
+        crate::KERNEL_STACK_OFFSET + slot * SLOT_SIZE
+    }
+
+    pub struct KernelStack {
+        slot: usize,
+        len: usize,
+    }
+
+    impl KernelStack {
+        pub(super) fn allocate(size: usize) -> Result<Self> {
+            assert!(size % PAGE_SIZE == 0 && size <= KSTACK_SIZE_MAX, "invalid kernel stack size {:#x}", size);
+
+            let slot = {
+                let mut slots = SLOTS.lock();
+                if size == KSTACK_SIZE_DEFAULT {
+                    if let Some(slot) = slots.cached.pop() {
+                        return Ok(Self { slot, len: size });
+                    }
+                }
+                match slots.free.pop() {
+                    Some(slot) => slot,
+                    None if slots.next < SLOT_COUNT => {
+                        slots.next += 1;
+                        slots.next - 1
+                    }
+                    None => return Err(Error::new(ENOMEM)),
+                }
+            };
+            let stack = Self { slot, len: size };
+
+            let mut mapper = KernelMapper::lock();
+            let mapper = mapper.get_mut().expect("failed to obtain exclusive access to KernelMapper to allocate kernel stack");
+            let mut flush_all = PageFlushAll::new();
+            for (i, page) in stack.pages().enumerate() {
+                // The pages are not global, so that the TLB shootdown when unmapping reaches them
+                match unsafe { mapper.map(page.start_address(), PageFlags::new().write(true)) } {
+                    Some(flush) => flush_all.consume(flush),
+                    None => {
+                        unsafe { unmap_pages(mapper, stack.pages().take(i)) };
+                        SLOTS.lock().free.push(slot);
+                        // Nothing left to unmap on drop
+                        core::mem::forget(stack);
+                        return Err(Error::new(ENOMEM));
+                    }
+                }
+            }
+            flush_all.flush();
+            Ok(stack)
+        }
+
+        fn base(&self) -> usize {
+            slot_start(self.slot) + SLOT_SIZE - self.len
+        }
+
+        fn pages(&self) -> impl Iterator<Item = Page> {
+            let base = self.base();
+            (0..self.len / PAGE_SIZE).map(move |i| Page::containing_address(VirtualAddress::new(base + i * PAGE_SIZE)))
+        }
+    }
+
+    impl Deref for KernelStack {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.base() as *const u8, self.len) }
+        }
+    }
+
+    impl DerefMut for KernelStack {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            unsafe { slice::from_raw_parts_mut(self.base() as *mut u8, self.len) }
+        }
+    }
+
+    impl Drop for KernelStack {
+        fn drop(&mut self) {
+            if self.len == KSTACK_SIZE_DEFAULT {
+                let mut slots = SLOTS.lock();
+                if slots.cached.len() < CACHE_MAX {
+                    slots.cached.push(self.slot);
+                    return;
+                }
+            }
+
+            {
+                let mut mapper = KernelMapper::lock();
+                let mapper = mapper.get_mut().expect("failed to obtain exclusive access to KernelMapper to free kernel stack");
+                unsafe { unmap_pages(mapper, self.pages()) };
+            }
+            // Other CPUs that ran the context may still have the stack in their TLB
+            ipi(IpiKind::Tlb, IpiTarget::Other);
+
+            SLOTS.lock().free.push(self.slot);
+        }
+    }
+
+    /// Unmap `pages` and free their frames, flushing only the local TLB
+    unsafe fn unmap_pages(mapper: &mut PageMapper, pages: impl Iterator<Item = Page>) {
+        let mut flush_all = PageFlushAll::new();
+        for page in pages {
+            if let Some((phys, _, flush)) = mapper.unmap_phys(page.start_address(), false) {
+                deallocate_frames(Frame::containing_address(phys), 1);
+                flush_all.consume(flush);
+            }
+        }
+        flush_all.flush();
+    }
+
+    /// Create the page table of the kernel stack area, so that it is part of the kernel mappings
+    /// every address space copies. Called on the BSP before the first address space is created.
+    pub fn init() {
+        let mut mapper = KernelMapper::lock();
+        let mapper = mapper.get_mut().expect("failed to obtain exclusive access to KernelMapper for kernel stacks");
+        let virt = VirtualAddress::new(crate::KERNEL_STACK_OFFSET);
+        unsafe {
+            mapper.map(virt, PageFlags::new()).expect("failed to map kernel stack area").flush();
+            unmap_pages(mapper, core::iter::once(Page::containing_address(virt)));
+        }
+    }
+
+    /// Record the kernel stack of the context switched to, for `guard_hit`
+    pub fn set_current(stack: Option<&KernelStack>) {
+        CURRENT_BASE.store(stack.map_or(0, KernelStack::base), Ordering::Relaxed);
+    }
+
+    /// Returns true if `address` is in the guard area below the kernel stack of the current
+    /// context. Does not take any locks, so that it can be used by the double fault handler.
+    pub fn guard_hit(address: usize) -> bool {
+        let base = CURRENT_BASE.load(Ordering::Relaxed);
+        if base == 0 {
+            return false;
+        }
+        let slot_start = (base - crate::KERNEL_STACK_OFFSET) / SLOT_SIZE * SLOT_SIZE + crate::KERNEL_STACK_OFFSET;
+        (slot_start..base).contains(&address)
+    }
+
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod heap {
+    use alloc::boxed::Box;
+    use core::ops::{Deref, DerefMut};
+
+    use crate::syscall::error::Result;
+
+    /// Kernel stacks are allocated from the heap, without a guard area
+    pub struct KernelStack(Box<[u8]>);
+
+    impl KernelStack {
+        pub(super) fn allocate(size: usize) -> Result<Self> {
+            Ok(Self(vec![0; size].into_boxed_slice()))
+        }
+    }
+
+    impl Deref for KernelStack {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    impl DerefMut for KernelStack {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            &mut self.0
+        }
+    }
+
+    pub fn init() {}
+
+    pub fn set_current(_stack: Option<&KernelStack>) {}
+}
@@ -0,0 +1,53 @@
+use alloc::borrow::Cow;
+use core::fmt;
+use core::ops::Deref;
+
+use crate::syscall::error::{Error, EINVAL, Result};
+
+/// Maximum length of a label in bytes
+pub const LABEL_MAX: usize = 256;
+
+/// Debug label of a context, made of `/` separated components from the most general to the most
+/// specific, e.g. `netstack/worker-3`. This lets the threads of a daemon share a common prefix
+/// while still being told apart in panics, the debugger and the `proc:` and `sys:` schemes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Label(Cow<'static, str>);
+
+impl Label {
+    pub const fn new(label: &'static str) -> Self {
+        Label(Cow::Borrowed(label))
+    }
+
+    /// Parse a label set by userspace, rejecting labels that are too long or contain control
+    /// characters
+    pub fn parse(label: &str) -> Result<Self> {
+        if label.len() > LABEL_MAX || label.chars().any(char::is_control) {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(Label(Cow::Owned(label.into())))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Label {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<&'static str> for Label {
+    fn from(label: &'static str) -> Self {
+        Label::new(label)
+    }
+}
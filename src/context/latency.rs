@@ -0,0 +1,118 @@
+//! # Low-latency wakeups
+//! Contexts with hard timing requirements, like audio drivers refilling a DMA buffer, can join the
+//! low-latency class through `sched:latency`, giving the longest acceptable delay between being
+//! woken and running. Members of the class are scheduled before any other context, including
+//! boosted ones (see `boost`), their wakeups by IRQs and other CPUs interrupt the CPU owning them
+//! instead of waiting for its next tick, and their sleeps end on the first tick after the
+//! deadline rather than at the next regular context switch.
+//!
+//! Every wakeup of a member is measured, from the time it was woken, or the end of its sleep,
+//! until it runs. Wakeups taking longer than requested are counted as misses, and the time of the
+//! last one is kept, so that a driver can tell whether an underrun was caused by the kernel
+//! running it late.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::syscall::abi::SchedLatency;
+use crate::time;
+
+/// Number of contexts in the class, so that the scheduler only looks for them when there are any
+static MEMBERS: AtomicUsize = AtomicUsize::new(0);
+/// A member was woken and may not have run yet
+static PENDING: AtomicBool = AtomicBool::new(false);
+/// The earliest time a sleeping member has to be woken, found by the scheduler
+static NEXT_WAKE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Membership of a context in the class, and its measurements
+#[derive(Debug)]
+pub struct Latency {
+    /// Longest acceptable wakeup latency, in nanoseconds
+    max: u128,
+    /// Time the context was woken, if it has not run since
+    woken: Option<u128>,
+    wakeups: u64,
+    misses: u64,
+    /// Worst and most recent latency, in nanoseconds
+    worst: u128,
+    last: u128,
+    /// Time of the most recent miss, in nanoseconds since boot
+    last_miss: u128,
+}
+
+impl Latency {
+    /// Join the class, with a maximum latency in nanoseconds
+    pub fn new(max: u128) -> Self {
+        MEMBERS.fetch_add(1, Ordering::Relaxed);
+        Self {
+            max,
+            woken: None,
+            wakeups: 0,
+            misses: 0,
+            worst: 0,
+            last: 0,
+            last_miss: 0,
+        }
+    }
+
+    /// Record that the context was woken at `time`. Waking an already woken context keeps the
+    /// earlier time.
+    pub fn wake(&mut self, time: u128) {
+        self.woken.get_or_insert(time);
+        PENDING.store(true, Ordering::Release);
+    }
+
+    /// Record that the context runs at `now`
+    pub fn run(&mut self, now: u128) {
+        let woken = match self.woken.take() {
+            Some(woken) => woken,
+            None => return,
+        };
+        let latency = now.saturating_sub(woken);
+        self.wakeups += 1;
+        self.last = latency;
+        self.worst = self.worst.max(latency);
+        if latency > self.max {
+            self.misses += 1;
+            self.last_miss = now;
+        }
+    }
+
+    /// The measurements, as read from `sched:latency`
+    pub fn stats(&self) -> SchedLatency {
+        SchedLatency {
+            max_latency: (self.max / 1000) as usize,
+            wakeups: self.wakeups as usize,
+            misses: self.misses as usize,
+            worst: (self.worst / 1000) as usize,
+            last: (self.last / 1000) as usize,
+            last_miss: (self.last_miss / 1000) as usize,
+        }
+    }
+}
+
+impl Drop for Latency {
+    fn drop(&mut self) {
+        MEMBERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether any context is in the class
+pub fn any() -> bool {
+    MEMBERS.load(Ordering::Relaxed) != 0
+}
+
+/// Whether a member may be waiting to run, in which case the timer tick switches contexts right
+/// away
+pub fn due() -> bool {
+    any() && (PENDING.load(Ordering::Acquire) || time::monotonic() >= NEXT_WAKE.load(Ordering::Relaxed) as u128)
+}
+
+/// Called by the scheduler before looking for a member to run
+pub fn begin_search() {
+    PENDING.store(false, Ordering::Release);
+}
+
+/// Called by the scheduler with the earliest sleep deadline of the members it looked at
+pub fn end_search(next_wake: Option<u128>) {
+    NEXT_WAKE.store(next_wake.map_or(u64::MAX, |wake| wake as u64), Ordering::Relaxed);
+}
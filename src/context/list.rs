@@ -1,31 +1,51 @@
 use alloc::sync::Arc;
-use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use core::{iter, mem};
+use core::ops::{Bound, RangeBounds};
 use core::sync::atomic::Ordering;
 
-use spin::RwLock;
-
-use crate::syscall::error::{Result, Error, EAGAIN};
+use crate::memory::Enomem;
+use crate::sync::{RwLock, TryClone};
+use crate::syscall::error::{Result, Error, EAGAIN, ENOMEM};
 use super::context::{Context, ContextId};
+use super::kstack::KernelStack;
 
 /// Context list type
 pub struct ContextList {
-    map: BTreeMap<ContextId, Arc<RwLock<Context>>>,
+    /// The contexts, sorted by ID. A vector rather than a map, as every change copies the whole
+    /// list anyway, and a vector can be copied without aborting if memory runs out.
+    map: Vec<(ContextId, Arc<RwLock<Context>>)>,
     next_id: usize
 }
 
+impl TryClone for ContextList {
+    fn try_clone(&self) -> core::result::Result<Self, Enomem> {
+        let mut map = Vec::new();
+        map.try_reserve_exact(self.map.len()).map_err(|_| Enomem)?;
+        map.extend(self.map.iter().cloned());
+        Ok(ContextList {
+            map,
+            next_id: self.next_id
+        })
+    }
+}
+
 impl ContextList {
     /// Create a new context list.
     pub const fn new() -> Self {
         ContextList {
-            map: BTreeMap::new(),
+            map: Vec::new(),
             next_id: 1
         }
     }
 
+    fn search(&self, id: ContextId) -> core::result::Result<usize, usize> {
+        self.map.binary_search_by_key(&id, |&(id, _)| id)
+    }
+
     /// Get the nth context.
     pub fn get(&self, id: ContextId) -> Option<&Arc<RwLock<Context>>> {
-        self.map.get(&id)
+        self.search(id).ok().map(|index| &self.map[index].1)
     }
 
     /// Get an iterator of all parents
@@ -39,21 +59,33 @@ impl ContextList {
 
     /// Get the current context.
     pub fn current(&self) -> Option<&Arc<RwLock<Context>>> {
-        self.map.get(&super::CONTEXT_ID.load(Ordering::SeqCst))
+        self.get(super::CONTEXT_ID.load(Ordering::SeqCst))
     }
 
-    pub fn iter(&self) -> ::alloc::collections::btree_map::Iter<ContextId, Arc<RwLock<Context>>> {
-        self.map.iter()
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&ContextId, &Arc<RwLock<Context>>)> + '_ {
+        self.map.iter().map(|(id, context)| (id, context))
     }
 
-    pub fn range(&self, range: impl core::ops::RangeBounds<ContextId>) -> ::alloc::collections::btree_map::Range<'_, ContextId, Arc<RwLock<Context>>> {
-        self.map.range(range)
+    pub fn range(&self, range: impl RangeBounds<ContextId>) -> impl DoubleEndedIterator<Item = (&ContextId, &Arc<RwLock<Context>>)> + '_ {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => self.map.partition_point(|&(id, _)| id < start),
+            Bound::Excluded(&start) => self.map.partition_point(|&(id, _)| id <= start),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => self.map.partition_point(|&(id, _)| id <= end),
+            Bound::Excluded(&end) => self.map.partition_point(|&(id, _)| id < end),
+            Bound::Unbounded => self.map.len(),
+        };
+        self.map[start..core::cmp::max(start, end)].iter().map(|(id, context)| (id, context))
     }
 
     pub(crate) fn insert_context_raw(&mut self, id: ContextId) -> Result<&Arc<RwLock<Context>>> {
-        assert!(self.map.insert(id, Arc::new(RwLock::new(Context::new(id)?))).is_none());
+        let index = self.search(id).expect_err("Failed to insert new context. ID is already in use.");
+        self.map.try_reserve(1).map_err(|_| Error::new(ENOMEM))?;
+        self.map.insert(index, (id, Arc::new(RwLock::new(Context::new(id)?))));
 
-        Ok(self.map.get(&id).expect("Failed to insert new context. ID is out of bounds."))
+        Ok(&self.map[index].1)
     }
 
     /// Create a new context.
@@ -69,7 +101,7 @@ impl ContextList {
             self.next_id = min;
         }
 
-        while self.map.contains_key(&ContextId::from(self.next_id)) {
+        while self.search(ContextId::from(self.next_id)).is_ok() {
             self.next_id += 1;
         }
 
@@ -85,12 +117,17 @@ impl ContextList {
 
     /// Spawn a context from a function.
     pub fn spawn(&mut self, func: extern fn()) -> Result<&Arc<RwLock<Context>>> {
+        self.spawn_with_kstack(func, super::KSTACK_SIZE_DEFAULT)
+    }
+
+    /// Spawn a context from a function, with a kernel stack of `kstack_size` bytes.
+    pub fn spawn_with_kstack(&mut self, func: extern fn(), kstack_size: usize) -> Result<&Arc<RwLock<Context>>> {
+        let mut stack = KernelStack::new(kstack_size)?;
         let context_lock = self.new_context()?;
         {
             let mut context = context_lock.write();
             let _ = context.set_addr_space(super::memory::new_addrspace()?);
 
-            let mut stack = vec![0; 65_536].into_boxed_slice();
             let mut offset = stack.len();
 
             #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -110,6 +147,13 @@ impl ContextList {
                 offset -= (stack.as_ptr() as usize + offset) % 16;
             }
 
+            #[cfg(target_arch = "riscv64")]
+            {
+                context.arch.set_lr(func as usize);
+                // Stack should be 16 byte aligned
+                offset -= (stack.as_ptr() as usize + offset) % 16;
+            }
+
             context.arch.set_stack(stack.as_ptr() as usize + offset);
             context.kstack = Some(stack);
         }
@@ -117,6 +161,6 @@ impl ContextList {
     }
 
     pub fn remove(&mut self, id: ContextId) -> Option<Arc<RwLock<Context>>> {
-        self.map.remove(&id)
+        self.search(id).ok().map(|index| self.map.remove(index).1)
     }
 }
@@ -0,0 +1,119 @@
+//! # Load accounting
+//! Classic 1, 5 and 15 minute load averages, which are exponentially decaying averages of the
+//! number of runnable contexts, sampled every `SAMPLE_INTERVAL`. Also tracks how much time each
+//! CPU spends running contexts other than its idle context (`kmain`), for per-CPU utilization.
+//!
+//! Both are updated by the scheduler, so they cost nothing between context switches.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::context::{contexts, wakeups, ContextId, Status};
+use crate::time;
+
+/// Number of CPUs whose utilization is tracked
+pub const CPUS: usize = 32;
+
+/// Nanoseconds between load average samples
+const SAMPLE_INTERVAL: u128 = 5_000_000_000;
+
+/// Load averages are stored in fixed point, with this many fractional bits
+pub const FSHIFT: u32 = 11;
+pub const FIXED_1: usize = 1 << FSHIFT;
+/// `FIXED_1 / exp(SAMPLE_INTERVAL / period)` for periods of 1, 5 and 15 minutes
+const EXP: [usize; 3] = [1884, 2014, 2037];
+
+static LOADAVG: [AtomicUsize; 3] = [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
+static NEXT_SAMPLE: AtomicU64 = AtomicU64::new(0);
+/// Set while a CPU is sampling, so that others do not wait for it
+static SAMPLING: AtomicBool = AtomicBool::new(false);
+
+struct CpuLoad {
+    /// Nanoseconds spent running contexts, and idle
+    busy: AtomicU64,
+    idle: AtomicU64,
+    /// The totals at the previous sample
+    last_busy: AtomicU64,
+    last_idle: AtomicU64,
+    /// Utilization during the previous sample interval, in thousandths
+    utilization: AtomicUsize,
+}
+
+static CPU_LOAD: [CpuLoad; CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const CPU_LOAD: CpuLoad = CpuLoad {
+        busy: AtomicU64::new(0),
+        idle: AtomicU64::new(0),
+        last_busy: AtomicU64::new(0),
+        last_idle: AtomicU64::new(0),
+        utilization: AtomicUsize::new(0),
+    };
+    [CPU_LOAD; CPUS]
+};
+
+/// The first contexts are the idle contexts of each CPU, see `context::init`
+fn is_idle(id: ContextId) -> bool {
+    (1..=crate::cpu_count()).contains(&id.into())
+}
+
+/// Account `time` nanoseconds on `cpu_id` to the context `id`, which is being switched away from
+pub fn account(cpu_id: usize, id: ContextId, time: u128) {
+    let cpu = match CPU_LOAD.get(cpu_id) {
+        Some(cpu) => cpu,
+        None => return,
+    };
+    let counter = if is_idle(id) { &cpu.idle } else { &cpu.busy };
+    counter.fetch_add(time as u64, Ordering::Relaxed);
+}
+
+/// Take a sample if the interval has passed. Called by the scheduler before it locks anything.
+pub fn tick() {
+    let now = time::monotonic();
+    if now < NEXT_SAMPLE.load(Ordering::Relaxed) as u128 || SAMPLING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    // Check again, another CPU may have just finished sampling
+    if now >= NEXT_SAMPLE.load(Ordering::Relaxed) as u128 {
+        NEXT_SAMPLE.store((now + SAMPLE_INTERVAL) as u64, Ordering::Relaxed);
+        sample();
+    }
+    SAMPLING.store(false, Ordering::Release);
+}
+
+fn sample() {
+    // Contexts currently being switched are locked, and skipped
+    let runnable = contexts().iter()
+        .filter(|(&id, _)| !is_idle(id))
+        .filter_map(|(_, context_lock)| context_lock.try_read())
+        .filter(|context| context.status == Status::Runnable)
+        .count();
+
+    for (average, exp) in LOADAVG.iter().zip(EXP) {
+        let old = average.load(Ordering::Relaxed);
+        average.store((old * exp + runnable * FIXED_1 * (FIXED_1 - exp)) >> FSHIFT, Ordering::Relaxed);
+    }
+
+    for cpu in CPU_LOAD.iter().take(crate::cpu_count()) {
+        let busy = cpu.busy.load(Ordering::Relaxed);
+        let idle = cpu.idle.load(Ordering::Relaxed);
+        let busy_delta = busy - cpu.last_busy.swap(busy, Ordering::Relaxed);
+        let idle_delta = idle - cpu.last_idle.swap(idle, Ordering::Relaxed);
+        let total = busy_delta + idle_delta;
+        if total > 0 {
+            cpu.utilization.store((busy_delta * 1000 / total) as usize, Ordering::Relaxed);
+        }
+    }
+
+    wakeups::sample(SAMPLE_INTERVAL);
+}
+
+/// The 1, 5 and 15 minute load averages, in fixed point with `FSHIFT` fractional bits
+pub fn loadavg() -> [usize; 3] {
+    [0, 1, 2].map(|i| LOADAVG[i].load(Ordering::Relaxed))
+}
+
+/// Busy and idle nanoseconds of `cpu_id`, and its utilization in thousandths during the last
+/// sample interval
+pub fn cpu_load(cpu_id: usize) -> Option<(u64, u64, usize)> {
+    let cpu = CPU_LOAD.get(cpu_id)?;
+    Some((cpu.busy.load(Ordering::Relaxed), cpu.idle.load(Ordering::Relaxed), cpu.utilization.load(Ordering::Relaxed)))
+}
@@ -1,24 +1,47 @@
 use alloc::collections::{BTreeMap, BTreeSet};
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{sync::{Arc, Weak}, vec::Vec};
 use core::borrow::Borrow;
 use core::cmp::{self, Eq, Ordering, PartialEq, PartialOrd};
 use core::fmt::{self, Debug};
 use core::ops::Deref;
-use spin::{RwLock, RwLockWriteGuard};
 use syscall::{
     flag::MapFlags,
     error::*,
 };
 use rmm::Arch as _;
 
+use crate::sync::{RwLock, RwLockWriteGuard};
+use crate::syscall::abi::{MADV_DONTNEED, MADV_FREE, MADV_NORMAL, MADV_RANDOM, MADV_SEQUENTIAL, MADV_WILLNEED, MAP_GROWSDOWN, MREMAP_FIXED, MREMAP_MAYMOVE};
+
 use crate::arch::paging::PAGE_SIZE;
 use crate::context::file::FileDescriptor;
-use crate::memory::{Enomem, Frame};
-use crate::paging::mapper::{Flusher, InactiveFlusher, PageFlushAll};
-use crate::paging::{KernelMapper, Page, PageFlags, PageIter, PageMapper, RmmA, round_up_pages, TableKind, VirtualAddress};
+use crate::context::oom;
+use crate::context::swap::{self, SwapIn, SwapSlot};
+use crate::memory::{free_frames, Enomem, Frame};
+use crate::scheme::mempressure;
+use crate::paging::mapper::{Flusher, TlbShootdown};
+use crate::paging::huge::{self, HUGE_PAGE_SIZE};
+use crate::paging::{KernelMapper, Page, PageFlags, PageIter, PageMapper, PhysicalAddress, RmmA, round_up_pages, TableKind, VirtualAddress};
 
 pub const MMAP_MIN_DEFAULT: usize = PAGE_SIZE;
 
+/// Default of how far below a grows-down grant a fault may be, for the grant to be grown
+pub const STACK_GROWTH_DEFAULT: usize = 256 * PAGE_SIZE;
+/// Number of unmapped pages that must remain between a grown stack and the mapping below it, so
+/// that a stack overflow faults rather than silently running into other memory
+pub const STACK_GUARD_PAGES: usize = 16;
+
+/// Number of pages over which the bases of mappings and stacks are randomized (ASLR), a sixteenth
+/// of userspace so that it scales with the address space of each architecture
+pub const ASLR_PAGES: usize = crate::USER_END_OFFSET / 16 / PAGE_SIZE;
+
+// Both randomized bases must stay below the end of userspace, and mappings below stacks
+const _: () = assert!(MMAP_MIN_DEFAULT + ASLR_PAGES * PAGE_SIZE <= crate::USER_END_OFFSET / 2);
+const _: () = assert!(crate::USER_END_OFFSET / 2 + ASLR_PAGES * PAGE_SIZE < crate::USER_END_OFFSET);
+
+/// Number of regular pages per huge page
+const HUGE_PAGE_FRAMES: usize = HUGE_PAGE_SIZE / PAGE_SIZE;
+
 pub fn page_flags(flags: MapFlags) -> PageFlags<RmmA> {
     PageFlags::new()
         .user(true)
@@ -37,19 +60,164 @@ pub fn map_flags(page_flags: PageFlags<RmmA>) -> MapFlags {
 
 pub struct UnmapResult {
     pub file_desc: Option<GrantFileRef>,
+    pub lender: Option<(Weak<RwLock<AddrSpace>>, Region)>,
 }
 impl Drop for UnmapResult {
     fn drop(&mut self) {
         if let Some(fd) = self.file_desc.take() {
             let _ = fd.desc.close();
         }
+        if let Some((lender, region)) = self.lender.take() {
+            unlend(lender, region);
+        }
+    }
+}
+
+/// End the borrow of `region` from `lender`, if it still exists. The borrowing address space must
+/// not be locked, as the lender may be borrowing from it too.
+pub fn unlend(lender: Weak<RwLock<AddrSpace>>, region: Region) {
+    if let Some(lender) = lender.upgrade() {
+        lender.write().unlend(region);
     }
 }
 
+/// Translate a user address, which may be part of a huge page
+pub fn translate(mapper: &PageMapper, address: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<RmmA>)> {
+    huge::translate(mapper, address)
+}
+
 pub fn new_addrspace() -> Result<Arc<RwLock<AddrSpace>>> {
     Arc::try_new(RwLock::new(AddrSpace::new()?)).map_err(|_| Error::new(ENOMEM))
 }
 
+/// Called by the page fault handler, to allocate the page containing `address` if it belongs to a
+/// lazily allocated grant of the current address space. Returns true if the access can be retried.
+///
+/// Faults from kernel mode (i.e. usercopy) only try to take the locks, as this CPU may already be
+/// holding them, in which case the access fails as if the page was not mapped. For the same reason,
+/// only faults from user mode wait for memory to be freed when it runs out.
+pub fn try_demand_page(address: VirtualAddress, from_user: bool) -> bool {
+    // Leave the last frames to the kernel, whose allocations cannot wait for a victim to exit
+    if from_user && free_frames() < oom::KERNEL_RESERVE {
+        oom::out_of_memory(1);
+    }
+
+    let addr_space_lock = if from_user {
+        match AddrSpace::current() {
+            Ok(addr_space) => addr_space,
+            Err(_) => return false,
+        }
+    } else {
+        match AddrSpace::try_current() {
+            Some(addr_space) => addr_space,
+            None => return false,
+        }
+    };
+    let major = match fault_in(&addr_space_lock, address, from_user) {
+        Ok(major) => major,
+        // Signalled, user mode retries the access once the signal is handled
+        Err(error) if error.errno == EINTR => return from_user,
+        Err(error) if error.errno == ENOMEM => return from_user && oom::out_of_memory(1),
+        Err(_) => return false,
+    };
+
+    swap::track(&addr_space_lock, Page::containing_address(address));
+    if from_user {
+        mempressure::update();
+    }
+    count_fault(from_user, major);
+    true
+}
+
+/// Allocate the page containing `address` if it belongs to a lazily allocated grant of the current
+/// address space, for usercopy, which must not hold the address space lock. Fails with `EFAULT` if
+/// there is no such grant, `EINTR` if a signal interrupts reading it back from swap, or `ENOMEM`.
+pub fn demand_page(address: VirtualAddress) -> Result<()> {
+    let addr_space_lock = AddrSpace::current()?;
+    let major = fault_in(&addr_space_lock, address, true)?;
+    swap::track(&addr_space_lock, Page::containing_address(address));
+    count_fault(false, major);
+    Ok(())
+}
+
+/// Populate the page containing `address` of a lazily allocated grant, or of a stack grown to
+/// cover it, waiting for the address space lock if `wait`. Returns true if the page had to be read
+/// back from swap.
+fn fault_in(addr_space_lock: &Arc<RwLock<AddrSpace>>, address: VirtualAddress, wait: bool) -> Result<bool> {
+    let mut major = false;
+    loop {
+        let mut addr_space = if wait {
+            write_faulting(addr_space_lock)
+        } else {
+            addr_space_lock.try_write().ok_or(Error::new(EFAULT))?
+        };
+
+        match addr_space.grants.contains(address) {
+            Some(grant) if grant.lazy => (),
+            Some(_) => return Err(Error::new(EFAULT)),
+            None => if !addr_space.grow_stack(address) {
+                return Err(Error::new(EFAULT));
+            },
+        }
+        major |= addr_space.swapped.contains_key(&Page::containing_address(address));
+        // Wait for the page to be read back with the address space unlocked, and retry
+        match addr_space.try_populate(Region::new(address, 1))? {
+            None => return Ok(major),
+            Some(read) => {
+                drop(addr_space);
+                read.wait()?;
+            }
+        }
+    }
+}
+
+/// Write lock the address space of a fault, which may be handled with interrupts disabled, while
+/// the holder of the lock waits for this CPU to handle a TLB shootdown
+fn write_faulting(addr_space_lock: &RwLock<AddrSpace>) -> RwLockWriteGuard<AddrSpace> {
+    #[cfg(target_arch = "x86_64")]
+    return crate::arch::tlb::spin_handling(|| addr_space_lock.try_write());
+    #[cfg(not(target_arch = "x86_64"))]
+    addr_space_lock.write()
+}
+
+/// Count a page fault of the current context, if it can be locked
+fn count_fault(from_user: bool, major: bool) {
+    let context_lock = match super::current() {
+        Ok(context_lock) => context_lock,
+        Err(_) => return,
+    };
+    let mut context = if from_user {
+        context_lock.write()
+    } else {
+        match context_lock.try_write() {
+            Some(context) => context,
+            None => return,
+        }
+    };
+    if major {
+        context.major_faults += 1;
+    } else {
+        context.minor_faults += 1;
+    }
+}
+
+/// Usage statistics of an address space, in pages, listed by `proc:<pid>/memstat`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AddrSpaceStats {
+    /// Number of grants, i.e. separate mappings
+    pub grants: usize,
+    /// Pages covered by any grant
+    pub mapped: usize,
+    /// Pages of anonymous memory owned by this address space, whether allocated yet or not
+    pub committed: usize,
+    /// Pages currently backed by a frame
+    pub resident: usize,
+    /// Pages borrowed from other address spaces, schemes or physical memory
+    pub borrowed: usize,
+    /// Pages swapped out to the backing store
+    pub swapped: usize,
+}
+
 #[derive(Debug)]
 pub struct AddrSpace {
     pub table: Table,
@@ -59,11 +227,43 @@ pub struct AddrSpace {
     /// the exception that we have a memory safe kernel which doesn't have to protect itself
     /// against null pointers, so fixed mmaps to address zero are still allowed.
     pub mmap_min: usize,
+    /// How far below a grows-down grant (i.e. a stack) a page fault may be, for the grant to be
+    /// extended to cover it
+    pub stack_growth: usize,
+    /// Pages of lazily allocated grants which have been swapped out
+    pub swapped: BTreeMap<Page, SwapSlot>,
+    /// Whether pages may be swapped out, which is not the case for address spaces that swap
+    /// depends on
+    pub swappable: bool,
+    /// Regions whose frames are also mapped by other address spaces, and therefore cannot be
+    /// swapped out. A region is listed once per borrow, until the borrowing grant (see
+    /// `Grant::lender`) is unmapped.
+    pub lent: Vec<Region>,
+    /// Maximum number of pages that may be mapped, cf. `RLIMIT_AS`
+    pub max_mapped: usize,
+    /// Maximum number of pages of anonymous memory that may be committed, cf. `RLIMIT_DATA`
+    pub max_committed: usize,
+    /// Whether mappings may be both writable and executable, which they can only be when the
+    /// process opted out of W^X, such as for a JIT compiler
+    pub allow_wx: bool,
+    /// Whether `mmap_base` and `stack_base` are randomized, see `randomize`
+    pub aslr: bool,
+    /// Where mappings without an address, and `MAP_GROWSDOWN` mappings such as stacks, are placed
+    /// from, if there is room above
+    pub mmap_base: usize,
+    pub stack_base: usize,
 }
 impl AddrSpace {
     pub fn current() -> Result<Arc<RwLock<Self>>> {
         Ok(Arc::clone(super::current()?.read().addr_space()?))
     }
+    /// Like `current`, but without waiting for the current context, which this CPU may already
+    /// have locked
+    pub fn try_current() -> Option<Arc<RwLock<Self>>> {
+        let contexts = super::contexts();
+        let context = contexts.current()?.try_read()?;
+        context.addr_space().ok().map(Arc::clone)
+    }
 
     /// Attempt to clone an existing address space so that all mappings are copied (CoW).
     pub fn try_clone(&mut self) -> Result<Arc<RwLock<Self>>> {
@@ -79,10 +279,42 @@ impl AddrSpace {
         for grant in self.grants.iter() {
             if grant.desc_opt.is_some() { continue; }
 
-            let new_grant;
+            let mut new_grant;
 
             // TODO: Replace this with CoW
-            if grant.owned {
+            if grant.huge {
+                new_grant = Grant::zeroed_huge(Page::containing_address(grant.start_address()), grant.size() / PAGE_SIZE, grant.flags(), new_mapper, ())?;
+
+                for page in new_grant.pages().map(Page::start_address) {
+                    let current_frame = unsafe { RmmA::phys_to_virt(translate(this_mapper, page).expect("grant containing unmapped pages").0) }.data() as *const u8;
+                    let new_frame = unsafe { RmmA::phys_to_virt(translate(new_mapper, page).expect("grant containing unmapped pages").0) }.data() as *mut u8;
+
+                    unsafe {
+                        new_frame.copy_from_nonoverlapping(current_frame, PAGE_SIZE);
+                    }
+                }
+            } else if grant.lazy {
+                // Only copy the pages that have been accessed, the rest stay lazily zeroed
+                new_grant = Grant::zeroed_lazy(Page::containing_address(grant.start_address()), grant.size() / PAGE_SIZE, grant.flags());
+
+                // Swapped out pages are shared until either copy is swapped in
+                for (page, slot) in self.swapped.range(Page::containing_address(grant.start_address())..Page::containing_address(grant.end_address())) {
+                    new_guard.swapped.insert(*page, slot.share());
+                }
+
+                for page in grant.pages() {
+                    let current_frame = match this_mapper.translate(page.start_address()) {
+                        Some((frame, _)) => unsafe { RmmA::phys_to_virt(frame) }.data() as *const u8,
+                        None => continue,
+                    };
+                    unsafe { new_mapper.map(page.start_address(), grant.flags()) }.ok_or(Error::new(ENOMEM))?.ignore();
+                    let new_frame = unsafe { RmmA::phys_to_virt(new_mapper.translate(page.start_address()).expect("page was just mapped").0) }.data() as *mut u8;
+
+                    unsafe {
+                        new_frame.copy_from_nonoverlapping(current_frame, PAGE_SIZE);
+                    }
+                }
+            } else if grant.owned {
                 new_grant = Grant::zeroed(Page::containing_address(grant.start_address()), grant.size() / PAGE_SIZE, grant.flags(), new_mapper, ())?;
 
                 for page in new_grant.pages().map(Page::start_address) {
@@ -100,33 +332,84 @@ impl AddrSpace {
                 new_grant = Grant::reborrow(grant, Page::containing_address(grant.start_address()), this_mapper, new_mapper, ())?;
             }
 
+            new_grant.grows_down = grant.grows_down;
             new_guard.grants.insert(new_grant);
         }
+        new_guard.stack_growth = self.stack_growth;
+        new_guard.swappable = self.swappable;
+        new_guard.max_mapped = self.max_mapped;
+        new_guard.max_committed = self.max_committed;
+        new_guard.allow_wx = self.allow_wx;
+        // The copy keeps the layout, and places new mappings like the original would
+        new_guard.aslr = self.aslr;
+        new_guard.mmap_base = self.mmap_base;
+        new_guard.stack_base = self.stack_base;
         Ok(new)
     }
     pub fn new() -> Result<Self> {
-        Ok(Self {
+        let mut this = Self {
             grants: UserGrants::new(),
             table: setup_new_utable()?,
             mmap_min: MMAP_MIN_DEFAULT,
-        })
+            stack_growth: STACK_GROWTH_DEFAULT,
+            swapped: BTreeMap::new(),
+            swappable: true,
+            lent: Vec::new(),
+            max_mapped: usize::MAX,
+            max_committed: usize::MAX,
+            allow_wx: false,
+            aslr: true,
+            mmap_base: 0,
+            stack_base: 0,
+        };
+        this.randomize();
+        Ok(this)
+    }
+    /// Choose new random bases for mappings and stacks, or the lowest possible ones if `aslr` is
+    /// disabled. Executables and interpreters loaded at no fixed address, i.e. position
+    /// independent ones, are mapped above `mmap_base` as well, so this randomizes the whole layout
+    /// of new address spaces.
+    pub fn randomize(&mut self) {
+        let random_offset = || if self.aslr {
+            (crate::entropy::next_u64() as usize % ASLR_PAGES) * PAGE_SIZE
+        } else {
+            0
+        };
+        self.mmap_base = MMAP_MIN_DEFAULT + random_offset();
+        // Stacks are kept away from other mappings, in the upper half
+        self.stack_base = crate::USER_END_OFFSET / 2 + random_offset();
+    }
+    /// Find a free region for a mapping without an address, above the base for its kind if
+    /// possible, and otherwise anywhere above `mmap_min`
+    pub fn find_free(&self, size: usize, align: usize, flags: MapFlags) -> Option<Region> {
+        let base = if flags.bits() & MAP_GROWSDOWN == MAP_GROWSDOWN { self.stack_base } else { self.mmap_base };
+        self.grants.find_free_aligned(cmp::max(base, self.mmap_min), size, align)
+            .or_else(|| self.grants.find_free_aligned(self.mmap_min, size, align))
     }
     pub fn is_current(&self) -> bool {
         self.table.utable.is_current()
     }
+    /// Fail with `EACCES` if `flags` are both writable and executable, unless allowed (W^X)
+    pub fn check_wx(&self, flags: MapFlags) -> Result<()> {
+        if flags.contains(MapFlags::PROT_WRITE | MapFlags::PROT_EXEC) && !self.allow_wx {
+            return Err(Error::new(EACCES));
+        }
+        Ok(())
+    }
     pub fn mprotect(&mut self, base: Page, page_count: usize, flags: MapFlags) -> Result<()> {
-        let (mut active, mut inactive);
-        let mut flusher = if self.is_current() {
-            active = PageFlushAll::new();
-            &mut active as &mut dyn Flusher<RmmA>
-        } else {
-            inactive = InactiveFlusher::new();
-            &mut inactive as &mut dyn Flusher<RmmA>
-        };
+        self.check_wx(flags)?;
+
+        let mut flusher = TlbShootdown::new(&self.table.utable).with_range(base.start_address(), page_count);
         let mapper = &mut self.table.utable;
 
         let region = Region::new(base.start_address(), page_count * PAGE_SIZE);
 
+        // Huge grants cannot be split within a huge page
+        let is_huge_aligned = |region: Region| region.start_address().data() % HUGE_PAGE_SIZE == 0 && region.size() % HUGE_PAGE_SIZE == 0;
+        if self.grants.conflicts(region).any(|grant| grant.huge && !is_huge_aligned(grant.intersect(region))) {
+            return Err(Error::new(EINVAL));
+        }
+
         // TODO: Remove allocation
         let regions = self.grants.conflicts(region).map(|g| *g.region()).collect::<Vec<_>>();
 
@@ -158,17 +441,116 @@ impl AddrSpace {
         }
         Ok(())
     }
+    pub fn madvise(&mut self, base: Page, page_count: usize, advice: usize) -> Result<()> {
+        let region = Region::new(base.start_address(), page_count * PAGE_SIZE);
+
+        // Like on Linux, advice about unmapped memory fails with ENOMEM
+        let covered: usize = self.grants.conflicts(region).map(|grant| grant.intersect(region).size()).sum();
+        if covered != region.size() {
+            return Err(Error::new(ENOMEM));
+        }
+
+        match advice {
+            MADV_NORMAL | MADV_RANDOM | MADV_SEQUENTIAL => Ok(()),
+            // Swap-ins are only queued, like readahead
+            MADV_WILLNEED => self.try_populate(region).map(drop),
+            MADV_DONTNEED | MADV_FREE => self.discard(region),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+    /// Free the frames of all anonymous memory within `region`, which will read as zero when next
+    /// accessed.
+    fn discard(&mut self, region: Region) -> Result<()> {
+        // Borrowers still map the frames of lent memory
+        if self.is_lent(region) {
+            return Err(Error::new(EBUSY));
+        }
+        if self.grants.conflicts(region).any(|grant| !grant.is_committed() || grant.huge || grant.desc_opt.is_some()) {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut flusher = TlbShootdown::new(&self.table.utable).with_range(region.start_address(), region.size().div_ceil(PAGE_SIZE));
+
+        // TODO: Remove allocation
+        let regions = self.grants.conflicts(region).map(|g| *g.region()).collect::<Vec<_>>();
+
+        for grant_region in regions {
+            let grant = self.grants.take(&grant_region).expect("grant cannot magically disappear while we hold the lock!");
+            let (before, mut grant, after) = grant.extract(grant_region.intersect(region)).expect("failed to extract grant");
+
+            if let Some(before) = before { self.grants.insert(before); }
+            if let Some(after) = after { self.grants.insert(after); }
+
+            grant.discard(&mut self.table.utable, &mut flusher);
+            drop(self.take_swapped(*grant.region()));
+            self.grants.insert(grant);
+        }
+        Ok(())
+    }
+    /// Remove the swapped out pages within `region`
+    fn take_swapped(&mut self, region: Region) -> BTreeMap<Page, SwapSlot> {
+        let mut taken = self.swapped.split_off(&Page::containing_address(region.start_address()));
+        let mut after = taken.split_off(&Page::containing_address(region.round().end_address()));
+        self.swapped.append(&mut after);
+        taken
+    }
+    /// Whether `page` is resident, and can be swapped out
+    pub fn can_swap_out(&self, page: Page) -> bool {
+        let byte = Region::byte(page.start_address());
+        let swappable_grant = self.grants.contains(page.start_address())
+            .map_or(false, |grant| grant.lazy && !grant.huge && grant.desc_opt.is_none());
+
+        self.swappable
+            && swappable_grant
+            && !self.lent.iter().any(|region| region.occupies(byte))
+            && self.table.utable.translate(page.start_address()).is_some()
+    }
+    /// Unmap `page`, which must have been checked with `can_swap_out`, and return its frame. The
+    /// caller records where the contents went in `swapped`.
+    pub fn swap_out(&mut self, page: Page) -> Frame {
+        let mut flusher = TlbShootdown::new(&self.table.utable).with_range(page.start_address(), 1);
+
+        let (phys, _, flush) = unsafe { self.table.utable.unmap_phys(page.start_address(), true) }
+            .expect("swapping out an unmapped page");
+        flusher.consume(flush);
+        Frame::containing_address(phys)
+    }
+    /// Whether any part of `region` is borrowed by another address space, in which case its frames
+    /// must be neither freed nor moved
+    pub fn is_lent(&self, region: Region) -> bool {
+        overlaps_lent(&self.lent, region)
+    }
+    /// Mark `region` as borrowed by another address space, until `unlend` is called
+    pub fn lend(&mut self, region: Region) {
+        self.lent.push(region);
+    }
+    /// End a borrow of `region`, which may be only part of a lent region if the borrower unmapped
+    /// the rest separately
+    pub fn unlend(&mut self, region: Region) {
+        if let Some(index) = self.lent.iter().position(|lent| lent.start_address() <= region.start_address() && region.end_address() <= lent.end_address()) {
+            let lent = self.lent.swap_remove(index);
+            self.lent.extend(lent.before(region));
+            self.lent.extend(lent.after(region));
+        }
+    }
     pub fn munmap(mut self: RwLockWriteGuard<'_, Self>, page: Page, page_count: usize) {
         let mut notify_files = Vec::new();
+        let mut lenders = Vec::new();
 
         let requested = Region::new(page.start_address(), page_count * PAGE_SIZE);
-        let mut flusher = PageFlushAll::new();
+        let mut flusher = TlbShootdown::new(&self.table.utable).with_range(page.start_address(), page_count);
 
         let conflicting: Vec<Region> = self.grants.conflicts(requested).map(Region::from).collect();
 
         for conflict in conflicting {
             let grant = self.grants.take(&conflict).expect("conflicting region didn't exist");
-            let intersection = grant.intersect(requested);
+            let mut intersection = grant.intersect(requested);
+            if grant.huge {
+                // Like hugetlb mappings on Linux, partially unmapped huge pages are unmapped entirely
+                let start = intersection.start_address().data() / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+                let end = intersection.end_address().data().next_multiple_of(HUGE_PAGE_SIZE);
+                intersection = Region::new(VirtualAddress::new(start), end - start);
+            }
             let (before, mut grant, after) = grant.extract(intersection.round()).expect("conflicting region shared no common parts");
 
             // Notify scheme that holds grant
@@ -184,11 +566,23 @@ impl AddrSpace {
                 self.grants.insert(after);
             }
 
+            // The lender is locked once this address space is not
+            if let Some(lender) = grant.lender.take() {
+                lenders.push(lender);
+            }
+
             // Remove irrelevant region
+            drop(self.take_swapped(*grant.region()));
             grant.unmap(&mut self.table.utable, &mut flusher);
         }
+        // Lending ends when the memory is gone
+        self.lent.retain(|region| region.intersect(requested).is_empty());
         drop(self);
 
+        for (lender, region) in lenders {
+            unlend(lender, region);
+        }
+
         for (file_ref, intersection) in notify_files {
             let scheme_id = { file_ref.desc.description.read().scheme };
 
@@ -206,28 +600,251 @@ impl AddrSpace {
             let _ = file_ref.desc.close();
         }
     }
+    /// Resize the anonymous memory at `old`, which must be part of a single grant. The memory is
+    /// resized in place if possible, and otherwise moved to `new` or a free region if allowed by
+    /// `flags`. Any new pages are allocated lazily. Returns the new location of the memory.
+    pub fn mremap(&mut self, old: Page, old_page_count: usize, new_page_count: usize, flags: usize, new: Option<Page>) -> Result<Page> {
+        let old_region = Region::new(old.start_address(), old_page_count * PAGE_SIZE);
+        let new_size = new_page_count.checked_mul(PAGE_SIZE).filter(|&size| size <= crate::USER_END_OFFSET).ok_or(Error::new(EINVAL))?;
+
+        // Borrowers map the frames where they are, and all of them
+        if self.is_lent(old_region) {
+            return Err(Error::new(EBUSY));
+        }
+
+        let grant_region = match self.grants.contains(old.start_address()) {
+            Some(grant) if grant.end_address() >= old_region.end_address() => {
+                if !grant.is_committed() || grant.huge || grant.desc_opt.is_some() {
+                    return Err(Error::new(EINVAL));
+                }
+                *grant.region()
+            }
+            _ => return Err(Error::new(EFAULT)),
+        };
+
+        let in_place = flags & MREMAP_FIXED == 0 && (new_page_count <= old_page_count || {
+            let extension = Region::new(old_region.end_address(), new_size - old_region.size());
+            grant_region.end_address() == old_region.end_address()
+                && old_region.end_address().data().checked_add(extension.size()).map_or(false, |end| end <= crate::USER_END_OFFSET)
+                && self.grants.conflicts(extension).next().is_none()
+        });
+
+        let destination = if in_place {
+            old
+        } else if flags & MREMAP_MAYMOVE == 0 {
+            return Err(Error::new(ENOMEM));
+        } else if let Some(new) = new {
+            let fits = new.start_address().data().checked_add(new_size).map_or(false, |end| end <= crate::USER_END_OFFSET);
+            if !fits {
+                return Err(Error::new(EINVAL));
+            }
+            let requested = Region::new(new.start_address(), new_size);
+            if requested.intersect(old_region).size() != 0 {
+                return Err(Error::new(EINVAL));
+            }
+            if self.grants.conflicts(requested).next().is_some() {
+                return Err(Error::new(EOPNOTSUPP));
+            }
+            new
+        } else {
+            Page::containing_address(self.find_free(new_size, PAGE_SIZE, MapFlags::empty()).ok_or(Error::new(ENOMEM))?.start_address())
+        };
+        if new_page_count > old_page_count {
+            self.check_limits(new_page_count - old_page_count, true)?;
+        }
+
+        let mut flusher = TlbShootdown::new(&self.table.utable);
+
+        let grant = self.grants.take(&grant_region).expect("grant cannot magically disappear while we hold the lock!");
+        let (before, mut grant, after) = grant.extract(old_region).expect("failed to extract grant");
+
+        if let Some(before) = before { self.grants.insert(before); }
+        if let Some(after) = after { self.grants.insert(after); }
+
+        if destination != old {
+            if let Err(err) = grant.relocate(destination, &mut self.table.utable, &mut flusher) {
+                self.grants.insert(grant);
+                return Err(err);
+            }
+            let swapped = self.take_swapped(old_region);
+            self.swapped.extend(swapped.into_iter().map(|(page, slot)| (destination.next_by((page.start_address().data() - old.start_address().data()) / PAGE_SIZE), slot)));
+        }
+        if new_page_count < old_page_count {
+            let (_, kept, tail) = grant.extract(Region::new(destination.start_address(), new_size)).expect("failed to extract grant");
+            if let Some(tail) = tail {
+                drop(self.take_swapped(*tail.region()));
+                tail.unmap(&mut self.table.utable, &mut flusher);
+            }
+            grant = kept;
+        }
+        if new_page_count > old_page_count {
+            unsafe { grant.region_mut().set_size(new_size); }
+            grant.lazy = true;
+        }
+        self.grants.insert(grant);
+
+        Ok(destination)
+    }
+    /// Fail with `ENOMEM` if mapping `page_count` more pages, which are anonymous memory if
+    /// `committed`, would exceed the limits of this address space
+    pub fn check_limits(&self, page_count: usize, committed: bool) -> Result<()> {
+        if self.grants.mapped_pages() + page_count > self.max_mapped
+            || (committed && self.grants.committed_pages() + page_count > self.max_committed)
+        {
+            return Err(Error::new(ENOMEM));
+        }
+        Ok(())
+    }
+    pub fn stats(&self) -> AddrSpaceStats {
+        let mut stats = AddrSpaceStats {
+            mapped: self.grants.mapped_pages(),
+            committed: self.grants.committed_pages(),
+            swapped: self.swapped.len(),
+            ..AddrSpaceStats::default()
+        };
+        for grant in self.grants.iter() {
+            let page_count = grant.size() / PAGE_SIZE;
+
+            stats.grants += 1;
+            if !grant.is_owned() {
+                stats.borrowed += page_count;
+            }
+            // TODO: Skip unmapped page tables instead of translating every page
+            stats.resident += if grant.lazy {
+                grant.pages().filter(|page| self.table.utable.translate(page.start_address()).is_some()).count()
+            } else {
+                page_count
+            };
+        }
+        stats
+    }
+    /// Allocate the frames of any lazily allocated pages within `region`, so that the region can
+    /// be translated or borrowed without faulting. Swapped out pages are read back by the swap
+    /// daemon if their contents are not in memory, which must not be waited for with the address
+    /// space locked, so this then fails with `EAGAIN`. Use `write_populated` to wait for them.
+    pub fn populate(&mut self, region: Region) -> Result<()> {
+        match self.try_populate(region)? {
+            Some(_) => Err(Error::new(EAGAIN)),
+            None => Ok(()),
+        }
+    }
+    /// Like `populate`, but returns the first swap-in to wait for, after queueing all of them
+    fn try_populate(&mut self, region: Region) -> Result<Option<swap::PendingRead>> {
+        let mut flusher = TlbShootdown::new(&self.table.utable).with_range(region.start_address(), region.size().div_ceil(PAGE_SIZE));
+        let mapper = &mut self.table.utable;
+        let mut pending = None;
+
+        for grant in self.grants.conflicts(region).filter(|grant| grant.lazy) {
+            for page in grant.intersect(region).round().pages() {
+                if mapper.translate(page.start_address()).is_some() {
+                    continue;
+                }
+                let swap_in = match self.swapped.get(&page) {
+                    Some(slot) => Some(swap::swap_in(slot)?),
+                    None => None,
+                };
+                let flush = match swap_in {
+                    Some(SwapIn::Ready(frame)) => {
+                        drop(self.swapped.remove(&page));
+                        match unsafe { mapper.map_phys(page.start_address(), frame.start_address(), grant.flags()) } {
+                            Some(flush) => flush,
+                            None => {
+                                crate::memory::deallocate_frames(frame, 1);
+                                return Err(Error::new(ENOMEM));
+                            }
+                        }
+                    }
+                    Some(SwapIn::Reading(read)) => {
+                        pending.get_or_insert(read);
+                        continue;
+                    }
+                    None => unsafe { mapper.map(page.start_address(), grant.flags()) }.ok_or(Error::new(ENOMEM))?,
+                };
+                flusher.consume(flush);
+            }
+        }
+        Ok(pending)
+    }
+    /// Lock the address space for writing, with the lazily allocated pages within `region`
+    /// populated, waiting for swapped out pages to be read back with it unlocked
+    pub fn write_populated(lock: &RwLock<Self>, region: Region) -> Result<RwLockWriteGuard<'_, Self>> {
+        loop {
+            let mut addr_space = lock.write();
+            match addr_space.try_populate(region)? {
+                Some(read) => {
+                    drop(addr_space);
+                    read.wait()?;
+                }
+                None => return Ok(addr_space),
+            }
+        }
+    }
+    /// Extend the grows-down grant above `address` down to the page containing it, if `address`
+    /// is close enough to the grant, and the guard pages below it would remain unmapped.
+    fn grow_stack(&mut self, address: VirtualAddress) -> bool {
+        let page = Page::containing_address(address);
+        let grant_region = match self.grants.above(address) {
+            Some(grant) if grant.grows_down => *grant.region(),
+            _ => return false,
+        };
+        if grant_region.start_address().data() - page.start_address().data() > self.stack_growth {
+            return false;
+        }
+        let guard_start = match page.start_address().data().checked_sub(STACK_GUARD_PAGES * PAGE_SIZE) {
+            Some(guard_start) => VirtualAddress::new(guard_start),
+            None => return false,
+        };
+        if self.grants.conflicts(Region::between(guard_start, grant_region.start_address())).next().is_some() {
+            return false;
+        }
+
+        let growth = (grant_region.start_address().data() - page.start_address().data()) / PAGE_SIZE;
+        let committed = self.grants.contains(grant_region.start_address()).map_or(false, Grant::is_committed);
+        if self.check_limits(growth, committed).is_err() {
+            return false;
+        }
+
+        let mut grant = self.grants.take(&grant_region).expect("grant cannot magically disappear while we hold the lock!");
+        unsafe {
+            *grant.region_mut() = Region::between(page.start_address(), grant_region.end_address());
+        }
+        grant.lazy = true;
+        self.grants.insert(grant);
+
+        true
+    }
     pub fn mmap(&mut self, page: Option<Page>, page_count: usize, flags: MapFlags, map: impl FnOnce(Page, PageFlags<RmmA>, &mut PageMapper, &mut dyn Flusher<RmmA>) -> Result<Grant>) -> Result<Page> {
+        self.mmap_aligned(page, page_count, PAGE_SIZE, flags, map)
+    }
+    /// Like `mmap`, but the grant will start at a multiple of `align` bytes
+    pub fn mmap_aligned(&mut self, page: Option<Page>, page_count: usize, align: usize, flags: MapFlags, map: impl FnOnce(Page, PageFlags<RmmA>, &mut PageMapper, &mut dyn Flusher<RmmA>) -> Result<Grant>) -> Result<Page> {
         // Finally, the end of all "T0DO: Abstract with other grant creation"!
         if page_count == 0 {
             return Err(Error::new(EINVAL));
         }
+        self.check_wx(flags)?;
 
         let region = match page {
             Some(page) => self.grants.find_free_at(self.mmap_min, page.start_address(), page_count * PAGE_SIZE, flags)?,
-            None => self.grants.find_free(self.mmap_min, page_count * PAGE_SIZE).ok_or(Error::new(ENOMEM))?,
+            None => self.find_free(page_count * PAGE_SIZE, align, flags).ok_or(Error::new(ENOMEM))?,
         };
+        if region.start_address().data() % align != 0 {
+            return Err(Error::new(EINVAL));
+        }
         let page = Page::containing_address(region.start_address());
+        self.check_limits(page_count, false)?;
 
-        let (mut active, mut inactive);
-        let flusher = if self.is_current() {
-            active = PageFlushAll::new();
-            &mut active as &mut dyn Flusher<RmmA>
-        } else {
-            inactive = InactiveFlusher::new();
-            &mut inactive as &mut dyn Flusher<RmmA>
-        };
+        let mut flusher = TlbShootdown::new(&self.table.utable);
 
-        self.grants.insert(map(page, page_flags(flags), &mut self.table.utable, flusher)?);
+        let grant = map(page, page_flags(flags), &mut self.table.utable, &mut flusher)?;
+        // Whether the grant is anonymous memory is only known once it has been created
+        if grant.is_committed() {
+            if let Err(error) = self.check_limits(grant.size() / PAGE_SIZE, true) {
+                grant.unmap(&mut self.table.utable, &mut flusher);
+                return Err(error);
+            }
+        }
+        self.grants.insert(grant);
         Ok(page)
     }
 }
@@ -241,6 +858,11 @@ pub struct UserGrants {
 
     //TODO: technically VirtualAddress is from a scheme's context!
     pub funmap: BTreeMap<Region, VirtualAddress>,
+    /// Number of pages of anonymous memory committed to this address space, whether or not they
+    /// have been touched yet
+    committed_pages: usize,
+    /// Number of pages covered by grants
+    mapped_pages: usize,
 }
 
 impl Default for UserGrants {
@@ -255,8 +877,16 @@ impl UserGrants {
             inner: BTreeSet::new(),
             holes: core::iter::once((VirtualAddress::new(0), crate::USER_END_OFFSET)).collect::<BTreeMap<_, _>>(),
             funmap: BTreeMap::new(),
+            committed_pages: 0,
+            mapped_pages: 0,
         }
     }
+    pub fn committed_pages(&self) -> usize {
+        self.committed_pages
+    }
+    pub fn mapped_pages(&self) -> usize {
+        self.mapped_pages
+    }
     /// Returns the grant, if any, which occupies the specified address
     pub fn contains(&self, address: VirtualAddress) -> Option<&Grant> {
         let byte = Region::byte(address);
@@ -265,6 +895,12 @@ impl UserGrants {
             .next_back()
             .filter(|existing| existing.occupies(byte))
     }
+    /// Returns the first grant starting above the specified address
+    pub fn above(&self, address: VirtualAddress) -> Option<&Grant> {
+        self.inner
+            .range(Region::byte(address)..)
+            .find(|existing| existing.start_address() > address)
+    }
     /// Returns an iterator over all grants that occupy some part of the
     /// requested region
     pub fn conflicts<'a>(&'a self, requested: Region) -> impl Iterator<Item = &'a Grant> + 'a {
@@ -276,26 +912,29 @@ impl UserGrants {
             .take_while(move |region| !region.intersect(requested).is_empty())
     }
     /// Return a free region with the specified size
-    // TODO: Alignment (x86_64: 4 KiB, 2 MiB, or 1 GiB).
     pub fn find_free(&self, min: usize, size: usize) -> Option<Region> {
+        self.find_free_aligned(min, size, PAGE_SIZE)
+    }
+    /// Return a free region with the specified size, starting at a multiple of `align`
+    // TODO: 1 GiB alignment on x86_64
+    pub fn find_free_aligned(&self, min: usize, size: usize, align: usize) -> Option<Region> {
         // Get first available hole, but do reserve the page starting from zero as most compiled
         // languages cannot handle null pointers safely even if they point to valid memory. If an
         // application absolutely needs to map the 0th page, they will have to do so explicitly via
         // MAP_FIXED/MAP_FIXED_NOREPLACE.
         // TODO: Allow explicitly allocating guard pages?
 
+        let aligned_start = |hole_offset: &VirtualAddress| cmp::max(hole_offset.data(), min).checked_next_multiple_of(align);
+
         let (hole_start, _hole_size) = self.holes.iter()
             .skip_while(|(hole_offset, hole_size)| hole_offset.data() + **hole_size <= min)
             .find(|(hole_offset, hole_size)| {
-                let avail_size = if hole_offset.data() <= min && min <= hole_offset.data() + **hole_size {
-                    **hole_size - (min - hole_offset.data())
-                } else {
-                    **hole_size
-                };
-                size <= avail_size
+                aligned_start(hole_offset)
+                    .and_then(|start| start.checked_add(size))
+                    .map_or(false, |end| end <= hole_offset.data() + **hole_size)
             })?;
         // Create new region
-        Some(Region::new(VirtualAddress::new(cmp::max(hole_start.data(), min)), size))
+        Some(Region::new(VirtualAddress::new(aligned_start(hole_start)?), size))
     }
     /// Return a free region, respecting the user's hinted address and flags. Address may be null.
     pub fn find_free_at(&mut self, min: usize, address: VirtualAddress, size: usize, flags: MapFlags) -> Result<Region> {
@@ -408,6 +1047,10 @@ impl UserGrants {
         }
         */
 
+        self.mapped_pages += grant.size() / PAGE_SIZE;
+        if grant.is_committed() {
+            self.committed_pages += grant.size() / PAGE_SIZE;
+        }
         self.inner.insert(grant);
     }
     pub fn remove(&mut self, region: &Region) -> bool {
@@ -416,6 +1059,10 @@ impl UserGrants {
     pub fn take(&mut self, region: &Region) -> Option<Grant> {
         let grant = self.inner.take(region)?;
         Self::unreserve(&mut self.holes, grant.region());
+        self.mapped_pages -= grant.size() / PAGE_SIZE;
+        if grant.is_committed() {
+            self.committed_pages -= grant.size() / PAGE_SIZE;
+        }
         Some(grant)
     }
     pub fn iter(&self) -> impl Iterator<Item = &Grant> + '_ {
@@ -599,8 +1246,18 @@ pub struct Grant {
     mapped: bool,
     pub(crate) owned: bool,
     pub(crate) allocator_owned: bool,
+    /// Frames are only allocated (zeroed) when a page is first accessed, so pages of this grant
+    /// may not be mapped yet
+    pub(crate) lazy: bool,
+    /// Mapped using huge pages, so that the grant must be aligned to `HUGE_PAGE_SIZE`
+    pub(crate) huge: bool,
+    /// A stack, which is extended downwards by the page fault handler when accessed just below
+    pub(crate) grows_down: bool,
     //TODO: This is probably a very heavy way to keep track of fmap'd files, perhaps move to the context?
     pub desc_opt: Option<GrantFileRef>,
+    /// The address space the frames of this grant are borrowed from, and the region they are at
+    /// there, which is lent (see `AddrSpace::lend`) until this grant is unmapped
+    pub(crate) lender: Option<(Weak<RwLock<AddrSpace>>, Region)>,
 }
 #[derive(Clone, Debug)]
 pub struct GrantFileRef {
@@ -616,6 +1273,16 @@ impl Grant {
         self.owned
     }
 
+    pub fn is_lazy(&self) -> bool {
+        self.lazy
+    }
+
+    /// Whether this grant is anonymous memory that counts towards the committed pages of its
+    /// address space
+    fn is_committed(&self) -> bool {
+        self.owned && self.allocator_owned
+    }
+
     pub fn region(&self) -> &Region {
         &self.region
     }
@@ -645,16 +1312,73 @@ impl Grant {
             mapped: true,
             owned: false,
             allocator_owned: false,
+            lazy: false,
+            huge: false,
+            grows_down: false,
             desc_opt: None,
+            lender: None,
         })
     }
+    /// Map frames owned by a scheme, such as a shared memory object, which are kept alive by
+    /// `desc` for as long as the grant exists. The descriptor is closed if mapping fails.
+    pub fn shared(frames: &[Frame], dst: Page, flags: PageFlags<RmmA>, desc: GrantFileRef, mapper: &mut PageMapper, mut flusher: impl Flusher<RmmA>) -> Result<Grant, Enomem> {
+        for (index, frame) in frames.iter().enumerate() {
+            let flush = match unsafe { mapper.map_phys(dst.next_by(index).start_address(), frame.start_address(), flags) } {
+                Some(flush) => flush,
+                None => {
+                    for index in 0..index {
+                        if let Some((_, _, flush)) = unsafe { mapper.unmap_phys(dst.next_by(index).start_address(), true) } {
+                            flusher.consume(flush);
+                        }
+                    }
+                    let _ = desc.desc.close();
+                    return Err(Enomem);
+                }
+            };
+            flusher.consume(flush);
+        }
+        Ok(Grant { region: Region { start: dst.start_address(), size: frames.len() * PAGE_SIZE }, flags, mapped: true, owned: false, allocator_owned: false, lazy: false, huge: false, grows_down: false, desc_opt: Some(desc), lender: None })
+    }
     pub fn zeroed(dst: Page, page_count: usize, flags: PageFlags<RmmA>, mapper: &mut PageMapper, mut flusher: impl Flusher<RmmA>) -> Result<Grant, Enomem> {
         // TODO: Unmap partially in case of ENOMEM
         for page in Page::range_exclusive(dst, dst.next_by(page_count)) {
             let flush = unsafe { mapper.map(page.start_address(), flags) }.ok_or(Enomem)?;
             flusher.consume(flush);
         }
-        Ok(Grant { region: Region { start: dst.start_address(), size: page_count * PAGE_SIZE }, flags, mapped: true, owned: true, allocator_owned: true, desc_opt: None })
+        Ok(Grant { region: Region { start: dst.start_address(), size: page_count * PAGE_SIZE }, flags, mapped: true, owned: true, allocator_owned: true, lazy: false, huge: false, grows_down: false, desc_opt: None, lender: None })
+    }
+    /// Reserve zeroed memory without allocating any frames, which are instead allocated by the
+    /// page fault handler when each page is first accessed
+    pub fn zeroed_lazy(dst: Page, page_count: usize, flags: PageFlags<RmmA>) -> Grant {
+        Grant { region: Region { start: dst.start_address(), size: page_count * PAGE_SIZE }, flags, mapped: true, owned: true, allocator_owned: true, lazy: true, huge: false, grows_down: false, desc_opt: None, lender: None }
+    }
+    /// Allocate zeroed memory backed by huge pages. Both `dst` and the size must be aligned to
+    /// `HUGE_PAGE_SIZE`.
+    pub fn zeroed_huge(dst: Page, page_count: usize, flags: PageFlags<RmmA>, mapper: &mut PageMapper, mut flusher: impl Flusher<RmmA>) -> Result<Grant, Enomem> {
+        for page in Page::range_exclusive(dst, dst.next_by(page_count)).step_by(HUGE_PAGE_FRAMES) {
+            let flush = crate::memory::allocate_aligned_frames(HUGE_PAGE_FRAMES, HUGE_PAGE_FRAMES).and_then(|frame| {
+                unsafe {
+                    (RmmA::phys_to_virt(frame.start_address()).data() as *mut u8).write_bytes(0, HUGE_PAGE_SIZE);
+                }
+                let flush = unsafe { huge::map_phys(mapper, page.start_address(), frame.start_address(), flags) };
+                if flush.is_none() {
+                    crate::memory::deallocate_frames(frame, HUGE_PAGE_FRAMES);
+                }
+                flush
+            });
+            let Some(flush) = flush else {
+                // Unmap and free the huge pages mapped so far
+                for page in Page::range_exclusive(dst, page).step_by(HUGE_PAGE_FRAMES) {
+                    if let Some((phys, _, flush)) = unsafe { huge::unmap_phys(mapper, page.start_address()) } {
+                        crate::memory::deallocate_frames(Frame::containing_address(phys), HUGE_PAGE_FRAMES);
+                        flusher.consume(flush);
+                    }
+                }
+                return Err(Enomem);
+            };
+            flusher.consume(flush);
+        }
+        Ok(Grant { region: Region { start: dst.start_address(), size: page_count * PAGE_SIZE }, flags, mapped: true, owned: true, allocator_owned: true, lazy: false, huge: true, grows_down: false, desc_opt: None, lender: None })
     }
     pub fn borrow(src_base: Page, dst_base: Page, page_count: usize, flags: PageFlags<RmmA>, desc_opt: Option<GrantFileRef>, src_mapper: &mut PageMapper, dst_mapper: &mut PageMapper, dst_flusher: impl Flusher<RmmA>) -> Result<Grant, Enomem> {
         Self::copy_inner(src_base, dst_base, page_count, flags, desc_opt, src_mapper, dst_mapper, (), dst_flusher, false, false, false)
@@ -662,11 +1386,14 @@ impl Grant {
     pub fn reborrow(src_grant: &Grant, dst_base: Page, src_mapper: &mut PageMapper, dst_mapper: &mut PageMapper, dst_flusher: impl Flusher<RmmA>) -> Result<Grant> {
         Self::borrow(Page::containing_address(src_grant.start_address()), dst_base, src_grant.size() / PAGE_SIZE, src_grant.flags(), src_grant.desc_opt.clone(), src_mapper, dst_mapper, dst_flusher).map_err(Into::into)
     }
+    /// Move a grant to another address space. Lazy grants must have been populated first.
     pub fn transfer(mut src_grant: Grant, dst_base: Page, src_mapper: &mut PageMapper, dst_mapper: &mut PageMapper, src_flusher: impl Flusher<RmmA>, dst_flusher: impl Flusher<RmmA>) -> Result<Grant> {
         assert!(core::mem::replace(&mut src_grant.mapped, false));
         let desc_opt = src_grant.desc_opt.take();
 
-        Self::copy_inner(Page::containing_address(src_grant.start_address()), dst_base, src_grant.size() / PAGE_SIZE, src_grant.flags(), desc_opt, src_mapper, dst_mapper, src_flusher, dst_flusher, src_grant.owned, src_grant.allocator_owned, true).map_err(Into::into)
+        let mut grant = Self::copy_inner(Page::containing_address(src_grant.start_address()), dst_base, src_grant.size() / PAGE_SIZE, src_grant.flags(), desc_opt, src_mapper, dst_mapper, src_flusher, dst_flusher, src_grant.owned, src_grant.allocator_owned, true)?;
+        grant.lender = src_grant.lender.take();
+        Ok(grant)
     }
 
     fn copy_inner(
@@ -693,7 +1420,7 @@ impl Grant {
 
                 (entry, entry_flags)
             } else {
-                src_mapper.translate(src_page.start_address()).unwrap_or_else(|| panic!("grant at {:p} references unmapped memory", src_page.start_address().data() as *const u8))
+                translate(src_mapper, src_page.start_address()).unwrap_or_else(|| panic!("grant at {:p} references unmapped memory", src_page.start_address().data() as *const u8))
             };
 
             let flush = match unsafe { dst_mapper.map_phys(dst_base.next_by(index).start_address(), address, flags) } {
@@ -732,7 +1459,11 @@ impl Grant {
             mapped: true,
             owned,
             allocator_owned,
+            lazy: false,
+            huge: false,
+            grows_down: false,
             desc_opt,
+            lender: None,
         })
     }
 
@@ -743,9 +1474,23 @@ impl Grant {
     pub fn remap(&mut self, mapper: &mut PageMapper, mut flusher: impl Flusher<RmmA>, flags: PageFlags<RmmA>) {
         assert!(self.mapped);
 
+        if self.huge {
+            for page in self.pages().step_by(HUGE_PAGE_FRAMES) {
+                let result = unsafe { huge::remap(mapper, page.start_address(), flags) }.expect("grant contained unmapped huge page");
+                flusher.consume(result);
+            }
+            self.flags = flags;
+            return;
+        }
+
         for page in self.pages() {
             unsafe {
-                let result = mapper.remap(page.start_address(), flags).expect("grant contained unmap address");
+                let result = match mapper.remap(page.start_address(), flags) {
+                    Some(result) => result,
+                    // Not yet accessed, will be mapped with the new flags
+                    None if self.lazy => continue,
+                    None => panic!("grant contained unmap address"),
+                };
                 flusher.consume(result);
             }
         }
@@ -759,9 +1504,24 @@ impl Grant {
     pub fn unmap(mut self, mapper: &mut PageMapper, mut flusher: impl Flusher<RmmA>) -> UnmapResult {
         assert!(self.mapped);
 
+        if self.huge {
+            for page in self.pages().step_by(HUGE_PAGE_FRAMES) {
+                let (phys, _, flush) = unsafe { huge::unmap_phys(mapper, page.start_address()) }
+                    .unwrap_or_else(|| panic!("missing huge page at {:#0x} for grant {:?}", page.start_address().data(), self));
+                // Huge grants are always allocator owned
+                crate::memory::deallocate_frames(Frame::containing_address(phys), HUGE_PAGE_FRAMES);
+                flusher.consume(flush);
+            }
+            self.mapped = false;
+            return UnmapResult { file_desc: self.desc_opt.take(), lender: self.lender.take() };
+        }
+
         for page in self.pages() {
-            let (entry, _, flush) = unsafe { mapper.unmap_phys(page.start_address(), true) }
-                .unwrap_or_else(|| panic!("missing page at {:#0x} for grant {:?}", page.start_address().data(), self));
+            let (entry, _, flush) = match unsafe { mapper.unmap_phys(page.start_address(), true) } {
+                Some(result) => result,
+                None if self.lazy => continue,
+                None => panic!("missing page at {:#0x} for grant {:?}", page.start_address().data(), self),
+            };
 
             if self.owned && self.allocator_owned {
                 // TODO: make sure this frame can be safely freed, physical use counter.
@@ -786,7 +1546,67 @@ impl Grant {
         self.mapped = false;
 
         // TODO: This imposes a large cost on unmapping, but that cost cannot be avoided without modifying fmap and funmap
-        UnmapResult { file_desc: self.desc_opt.take() }
+        UnmapResult { file_desc: self.desc_opt.take(), lender: self.lender.take() }
+    }
+
+    /// Free all frames of an anonymous grant, turning it into a lazily allocated one
+    pub fn discard(&mut self, mapper: &mut PageMapper, mut flusher: impl Flusher<RmmA>) {
+        assert!(self.mapped && self.is_committed());
+
+        for page in self.pages() {
+            let (entry, _, flush) = match unsafe { mapper.unmap_phys(page.start_address(), true) } {
+                Some(result) => result,
+                None if self.lazy => continue,
+                None => panic!("missing page at {:#0x} for grant {:?}", page.start_address().data(), self),
+            };
+            crate::memory::deallocate_frames(Frame::containing_address(entry), 1);
+            flusher.consume(flush);
+        }
+
+        self.lazy = true;
+    }
+
+    /// Move the pages of this grant to `dst`, without copying them. The pages are mapped at `dst`
+    /// before they are unmapped from the grant, so that if the page tables of `dst` cannot be
+    /// allocated, the grant is left where it was, and this fails with `ENOMEM`.
+    pub fn relocate(&mut self, dst: Page, mapper: &mut PageMapper, mut flusher: impl Flusher<RmmA>) -> Result<()> {
+        assert!(self.mapped && !self.huge);
+
+        for (index, page) in self.pages().enumerate() {
+            let entry = match mapper.translate(page.start_address()) {
+                Some((entry, _)) => entry,
+                None if self.lazy => continue,
+                None => panic!("missing page at {:#0x} for grant {:?}", page.start_address().data(), self),
+            };
+            match unsafe { mapper.map_phys(dst.next_by(index).start_address(), entry, self.flags) } {
+                Some(flush) => flusher.consume(flush),
+                None => {
+                    for index in 0..index {
+                        if let Some((_, _, flush)) = unsafe { mapper.unmap_phys(dst.next_by(index).start_address(), true) } {
+                            flusher.consume(flush);
+                        }
+                    }
+                    return Err(Error::new(ENOMEM));
+                }
+            }
+        }
+
+        for page in self.pages() {
+            if let Some((_, _, flush)) = unsafe { mapper.unmap_phys(page.start_address(), true) } {
+                flusher.consume(flush);
+            }
+        }
+
+        unsafe {
+            self.region_mut().set_start_address(dst.start_address());
+        }
+        Ok(())
+    }
+
+    /// The part of the lent region backing `region`, a part of this grant
+    fn lent_part(&self, region: Region) -> Option<(Weak<RwLock<AddrSpace>>, Region)> {
+        let (lender, lent) = self.lender.as_ref()?;
+        Some((Weak::clone(lender), Region::new(self.region.rebase(*lent, region.start_address()), region.size())))
     }
 
     /// Extract out a region into a separate grant. The return value is as
@@ -805,6 +1625,9 @@ impl Grant {
     pub fn extract(mut self, region: Region) -> Option<(Option<Grant>, Grant, Option<Grant>)> {
         assert_eq!(region.start_address().data() % PAGE_SIZE, 0, "split_out must be called on page-size aligned start address");
         assert_eq!(region.size() % PAGE_SIZE, 0, "split_out must be called on page-size aligned end address");
+        if self.huge {
+            assert!(region.start_address().data() % HUGE_PAGE_SIZE == 0 && region.size() % HUGE_PAGE_SIZE == 0, "huge grants can only be split at huge page boundaries");
+        }
 
         let before_grant = self.before(region).map(|region| Grant {
             region,
@@ -812,7 +1635,11 @@ impl Grant {
             mapped: self.mapped,
             owned: self.owned,
             allocator_owned: self.allocator_owned,
+            lazy: self.lazy,
+            huge: self.huge,
+            grows_down: self.grows_down,
             desc_opt: self.desc_opt.clone(),
+            lender: self.lent_part(region),
         });
         let after_grant = self.after(region).map(|region| Grant {
             region,
@@ -820,9 +1647,14 @@ impl Grant {
             mapped: self.mapped,
             owned: self.owned,
             allocator_owned: self.allocator_owned,
+            lazy: self.lazy,
+            huge: self.huge,
+            grows_down: self.grows_down,
             desc_opt: self.desc_opt.clone(),
+            lender: self.lent_part(region),
         });
 
+        self.lender = self.lent_part(region);
         unsafe {
             *self.region_mut() = region;
         }
@@ -836,7 +1668,7 @@ impl Grant {
 
             _ => return false,
         }
-        self.owned == with.owned && self.mapped == with.mapped && self.flags.data() == with.flags.data()
+        self.owned == with.owned && self.mapped == with.mapped && self.lazy == with.lazy && self.huge == with.huge && self.grows_down == with.grows_down && self.flags.data() == with.flags.data()
     }
 }
 
@@ -881,6 +1713,9 @@ pub const DANGLING: usize = 1 << (usize::BITS - 2);
 #[derive(Debug)]
 pub struct Table {
     pub utable: PageMapper,
+    /// The PML4 loaded while userspace runs, with page-table isolation (see `arch::pti`)
+    #[cfg(all(target_arch = "x86_64", feature = "pti"))]
+    pub pti_table: PhysicalAddress,
 }
 
 impl Drop for Table {
@@ -895,6 +1730,8 @@ impl Drop for Table {
             }
         }
         crate::memory::deallocate_frames(Frame::containing_address(self.utable.table().phys()), 1);
+        #[cfg(all(target_arch = "x86_64", feature = "pti"))]
+        crate::memory::deallocate_frames(Frame::containing_address(self.pti_table), 1);
     }
 }
 
@@ -908,6 +1745,28 @@ pub fn setup_new_utable() -> Result<Table> {
     })
 }
 
+/// Allocates a new utable sharing the kernel mappings, as there is a single page table on riscv64
+#[cfg(target_arch = "riscv64")]
+pub fn setup_new_utable() -> Result<Table> {
+    let utable = unsafe { PageMapper::create(TableKind::User, crate::rmm::FRAME_ALLOCATOR).ok_or(Error::new(ENOMEM))? };
+
+    {
+        let active_ktable = KernelMapper::lock();
+
+        // Copy higher half (kernel) mappings. Every top level kernel entry is created at boot, so
+        // there is nothing to keep in sync later.
+        for i in crate::paging::ENTRY_COUNT / 2..crate::paging::ENTRY_COUNT {
+            if let Some(entry) = unsafe { active_ktable.table().entry(i) } {
+                unsafe { utable.table().set_entry(i, entry) };
+            }
+        }
+    }
+
+    Ok(Table {
+        utable,
+    })
+}
+
 /// Allocates a new identically mapped ktable and empty utable (same memory on x86)
 #[cfg(target_arch = "x86")]
 pub fn setup_new_utable() -> Result<Table> {
@@ -924,7 +1783,7 @@ pub fn setup_new_utable() -> Result<Table> {
         };
 
         // Copy higher half (kernel) mappings
-        for i in 512..1024 {
+        for i in crate::paging::kernel_entries() {
             copy_mapping(i);
         }
     }
@@ -958,15 +1817,39 @@ pub fn setup_new_utable() -> Result<Table> {
         // Copy kernel heap mapping
         copy_mapping(crate::KERNEL_HEAP_PML4);
 
+        // Copy kernel stack mapping
+        copy_mapping(crate::KERNEL_STACK_PML4);
+
         // Copy physmap mapping
         copy_mapping(crate::PHYS_PML4);
     }
 
     Ok(Table {
         utable,
+        #[cfg(feature = "pti")]
+        pti_table: crate::arch::pti::new_user_table().ok_or(Error::new(ENOMEM))?,
     })
 }
 
+/// Whether `region` overlaps any of the `lent` regions
+fn overlaps_lent(lent: &[Region], region: Region) -> bool {
+    lent.iter().any(|lent| !lent.intersect(region).is_empty())
+}
+
+#[test]
+fn lent_regions_cannot_be_discarded() {
+    let region = |start: usize, pages: usize| Region::new(VirtualAddress::new(start * PAGE_SIZE), pages * PAGE_SIZE);
+    let lent = [region(4, 2), region(10, 1)];
+
+    assert!(overlaps_lent(&lent, region(4, 2)));
+    assert!(overlaps_lent(&lent, region(5, 1)));
+    assert!(overlaps_lent(&lent, region(0, 5)));
+    assert!(overlaps_lent(&lent, region(0, 16)));
+    assert!(!overlaps_lent(&lent, region(0, 4)));
+    assert!(!overlaps_lent(&lent, region(6, 4)));
+    assert!(!overlaps_lent(&[], region(0, 16)));
+}
+
 #[cfg(tests)]
 mod tests {
     // TODO: Get these tests working
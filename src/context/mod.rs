@@ -3,15 +3,14 @@
 //! For resources on contexts, please consult [wikipedia](https://en.wikipedia.org/wiki/Context_switch) and  [osdev](https://wiki.osdev.org/Context_Switching)
 use core::sync::atomic::Ordering;
 
-use alloc::borrow::Cow;
 use alloc::sync::Arc;
 
-use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
-
 use crate::paging::{RmmA, RmmArch, TableKind};
+use crate::sync::{Rcu, RcuReadGuard, RcuWriteGuard, RwLock};
 use crate::syscall::error::{Error, ESRCH, Result};
 
 pub use self::context::{BorrowedHtBuf, Context, ContextId, ContextSnapshot, Status, WaitpidKey};
+pub use self::label::Label;
 pub use self::list::ContextList;
 pub use self::switch::switch;
 
@@ -19,6 +18,10 @@ pub use self::switch::switch;
 #[path = "arch/aarch64.rs"]
 mod arch;
 
+#[cfg(target_arch = "riscv64")]
+#[path = "arch/riscv64.rs"]
+mod arch;
+
 #[cfg(target_arch = "x86")]
 #[path = "arch/x86.rs"]
 mod arch;
@@ -36,18 +39,57 @@ mod list;
 /// Context switch function
 mod switch;
 
+/// Scheduling boost for contexts woken by input
+pub mod boost;
+
+/// Core dumps of contexts killed by signals
+pub mod coredump;
+
 /// File struct - defines a scheme and a file number
 pub mod file;
 
+/// Kernel stacks with guard pages and canaries
+pub mod kstack;
+
+/// Hierarchical context debug labels
+pub mod label;
+
+/// Low-latency wakeups for contexts with hard timing requirements
+pub mod latency;
+
+/// Load averages and CPU utilization
+pub mod load;
+
 /// Memory struct - contains a set of pages for a context
 pub mod memory;
 
+/// Out of memory handling
+pub mod oom;
+
+/// PID namespaces
+pub mod pid_ns;
+
 /// Signal handling
 pub mod signal;
 
+/// Pending signal queues
+pub mod sigqueue;
+
+/// Swapping anonymous memory out to a backing store
+pub mod swap;
+
+/// Syscall counts and latencies per context
+pub mod syscalls;
+
+/// Thread groups
+pub mod thread_group;
+
 /// Timeout handling
 pub mod timeout;
 
+/// Timer and IRQ wakeups per context
+pub mod wakeups;
+
 pub use self::switch::switch_finish_hook;
 
 /// Limit on number of contexts
@@ -56,21 +98,33 @@ pub const CONTEXT_MAX_CONTEXTS: usize = (isize::max_value() as usize) - 1;
 /// Maximum context files
 pub const CONTEXT_MAX_FILES: usize = 65_536;
 
-/// Contexts list
-static CONTEXTS: RwLock<ContextList> = RwLock::new(ContextList::new());
+/// Default size of kernel stacks
+pub const KSTACK_SIZE_DEFAULT: usize = 65_536;
+/// Smallest kernel stack that can be requested for a new context
+pub const KSTACK_SIZE_MIN: usize = 16_384;
+/// Largest kernel stack that can be requested for a new context
+pub const KSTACK_SIZE_MAX: usize = 1_048_576;
+
+/// Contexts list, read without locking, as by every pass of the scheduler. Writing copies it.
+static CONTEXTS: Rcu<ContextList> = Rcu::new(ContextList::new());
 
 #[thread_local]
 static CONTEXT_ID: context::AtomicContextId = context::AtomicContextId::default();
 
+/// The context of init, the first userspace process, spawned by `kmain`
+pub static INIT_ID: context::AtomicContextId = context::AtomicContextId::default();
+
 pub use self::arch::empty_cr3;
+#[cfg(target_arch = "x86_64")]
+pub use self::arch::{read_fsgsbase, write_fsgsbase};
 
 pub fn init() {
-    let mut contexts = contexts_mut();
+    let mut contexts = contexts_mut().expect("could not copy contexts list");
     let id = ContextId::from(crate::cpu_id() + 1);
     let context_lock = contexts.insert_context_raw(id).expect("could not initialize first context");
     let mut context = context_lock.write();
     context.sched_affinity = Some(crate::cpu_id());
-    context.name = Cow::Borrowed("kmain");
+    context.name = Label::new("kmain");
 
     self::arch::EMPTY_CR3.call_once(|| unsafe { RmmA::table(TableKind::User) });
 
@@ -80,16 +134,23 @@ pub fn init() {
     CONTEXT_ID.store(context.id, Ordering::SeqCst);
 }
 
-/// Get the global schemes list, const
-pub fn contexts() -> RwLockReadGuard<'static, ContextList> {
+/// Get the global schemes list, const. Never waits, even for a writer on this CPU.
+pub fn contexts() -> RcuReadGuard<'static, ContextList> {
     CONTEXTS.read()
 }
 
-/// Get the global schemes list, mutable
-pub fn contexts_mut() -> RwLockWriteGuard<'static, ContextList> {
+/// Get the global schemes list, mutable. Changes are seen by readers once the guard is dropped.
+/// Fails with `ENOMEM` if the list cannot be copied.
+pub fn contexts_mut() -> Result<RcuWriteGuard<'static, ContextList>> {
     CONTEXTS.write()
 }
 
+/// Free the contexts removed from the list once no reader can see them anymore. Called from the
+/// idle loop, as removed contexts are otherwise only freed by the next change to the list.
+pub fn reclaim() {
+    CONTEXTS.reclaim();
+}
+
 pub fn context_id() -> ContextId {
     // Thread local variables can and should only be modified using Relaxed. This is to prevent a
     // hardware thread from racing with itself, for example if there is an interrupt. Orderings
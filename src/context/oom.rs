@@ -0,0 +1,135 @@
+//! # Out of memory handling
+//! When a page fault cannot be served because memory is (nearly) exhausted, swap is asked to
+//! reclaim pages first. If there is nothing to reclaim, the process with the highest badness is
+//! killed, and the faulting context waits for it to release its memory before retrying.
+//!
+//! The badness of a process is the number of pages it has resident or swapped out, adjusted by its
+//! `oom_score_adj` (settable through `proc:<pid>/oom-score-adj`) in thousandths of all memory.
+//! Processes with `OOM_SCORE_ADJ_MIN` are never killed. The last `KERNEL_RESERVE` frames are left
+//! to the kernel, whose allocations cannot wait for a victim, so that only the kernel running out
+//! of memory itself panics.
+
+use alloc::sync::{Arc, Weak};
+use core::sync::atomic::Ordering;
+
+use crate::context::{self, ContextId};
+use crate::memory::{free_frames, used_frames, PAGE_SIZE};
+use crate::sync::{Mutex, RwLock, WaitCondition};
+use crate::syscall::abi::SigInfo;
+use crate::syscall::flag::SIGKILL;
+
+use super::memory::AddrSpace;
+use super::swap;
+
+pub const OOM_SCORE_ADJ_MIN: i16 = -1000;
+pub const OOM_SCORE_ADJ_MAX: i16 = 1000;
+
+/// Free frames below which userspace faults start reclaiming memory, or killing
+pub const KERNEL_RESERVE: usize = crate::KERNEL_HEAP_SIZE / PAGE_SIZE;
+
+/// The address space of the process most recently killed, until it has been released
+static VICTIM: Mutex<Option<Weak<RwLock<AddrSpace>>>> = Mutex::new(None);
+/// Signalled when a context releases its address space
+static RELEASED: WaitCondition = WaitCondition::new();
+
+/// Find the context with the highest badness, and its address space
+fn select_victim() -> Option<(ContextId, usize, Arc<RwLock<AddrSpace>>)> {
+    let total = (used_frames() + free_frames()) as isize;
+    let mut victim: Option<(ContextId, usize, Arc<RwLock<AddrSpace>>)> = None;
+
+    let init = context::INIT_ID.load(Ordering::SeqCst);
+
+    let contexts = context::contexts();
+    for (&id, context_lock) in contexts.iter() {
+        let context = match context_lock.try_read() {
+            Some(context) => context,
+            None => continue,
+        };
+        // Init is never killed, nor are any of its threads, which would take it down with them
+        if context.thread_group.tgid == init || context.oom_score_adj == OOM_SCORE_ADJ_MIN {
+            continue;
+        }
+        // Kernel contexts have no address space, or one without user memory, which is skipped
+        // below as it has no pages
+        let addr_space_lock = match context.addr_space() {
+            Ok(addr_space) => addr_space,
+            Err(_) => continue,
+        };
+        // Threads share their address space, which only has to be counted once
+        if victim.as_ref().map_or(false, |(_, _, other)| Arc::ptr_eq(other, addr_space_lock)) {
+            continue;
+        }
+        // The address space may be locked by this CPU, and is skipped in that case
+        let stats = match addr_space_lock.try_read() {
+            Some(addr_space) => addr_space.stats(),
+            None => continue,
+        };
+        let pages = stats.resident.saturating_sub(stats.borrowed) + stats.swapped;
+        if pages == 0 {
+            continue;
+        }
+        let points = (pages as isize + context.oom_score_adj as isize * total / 1000).max(1) as usize;
+
+        if victim.as_ref().map_or(true, |&(_, best, _)| points > best) {
+            victim = Some((id, points, Arc::clone(addr_space_lock)));
+        }
+    }
+    victim
+}
+
+/// Kill every context using `addr_space`
+fn kill(addr_space: &Arc<RwLock<AddrSpace>>) {
+    let contexts = context::contexts();
+    for (_id, context_lock) in contexts.iter() {
+        let mut context = context_lock.write();
+        if context.addr_space.as_ref().map_or(false, |other| Arc::ptr_eq(other, addr_space)) {
+            context.pending.push(SigInfo::kernel(SIGKILL));
+        }
+    }
+}
+
+/// Try to free `pages` frames for the current context, which is about to fault them in. Blocks
+/// until the memory of a victim has been released, and returns false if retrying is pointless,
+/// such as when the current context is the victim itself.
+pub fn out_of_memory(pages: usize) -> bool {
+    // Frames are freed once the daemon has read them, so give it a chance to run
+    if swap::reclaim(pages) > 0 {
+        unsafe { context::switch(); }
+        return true;
+    }
+
+    let mut victim = VICTIM.lock();
+    let victim_addr_space = match victim.as_ref().and_then(Weak::upgrade) {
+        Some(addr_space) => addr_space,
+        None => {
+            let (id, points, addr_space) = match select_victim() {
+                Some(victim) => victim,
+                None => return false,
+            };
+            log::warn!("Out of memory: killing context {} with badness {}", id.into(), points);
+            kill(&addr_space);
+            *victim = Some(Arc::downgrade(&addr_space));
+            addr_space
+        }
+    };
+    if AddrSpace::current().map_or(true, |current| Arc::ptr_eq(&current, &victim_addr_space)) {
+        return false;
+    }
+    drop(victim_addr_space);
+
+    loop {
+        if victim.as_ref().map_or(true, |weak| weak.strong_count() == 0) {
+            *victim = None;
+            return true;
+        }
+        if !RELEASED.wait(victim, "out_of_memory") {
+            return false;
+        }
+        victim = VICTIM.lock();
+    }
+}
+
+/// Called when a context has released its address space, which may have been the victim's
+pub fn released() {
+    RELEASED.notify();
+}
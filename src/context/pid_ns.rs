@@ -0,0 +1,157 @@
+//! # PID namespaces
+//! A PID namespace gives the contexts created inside it a private PID space starting at 1, so that
+//! a container can run its own init. Namespaces nest: a context is visible, under a PID of its
+//! own, in the namespace it was created in and in every ancestor of that namespace. The root
+//! namespace is represented by `None`, where PIDs are the context IDs themselves.
+//!
+//! A context creates a namespace with `SYS_MKPIDNS`, after which the processes it creates are
+//! placed in the new namespace, the first one becoming its init. Threads stay in the namespace of
+//! the thread creating them. When the init of a namespace exits, every context remaining in the
+//! namespace or one nested within it is killed, and no further contexts can be created in it.
+//!
+//! Namespaces only translate IDs at the syscall boundary; within the kernel, contexts are always
+//! referred to by their context ID.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::iter;
+
+use crate::context::{self, ContextId};
+use crate::sync::Mutex;
+use crate::syscall::error::{Error, Result, ENOSPC, ESRCH};
+
+/// Maximum nesting depth of namespaces, the root namespace being at level 0
+pub const MAX_LEVEL: usize = 32;
+
+#[derive(Debug)]
+struct Ids {
+    /// Context ID to PID in this namespace
+    local: BTreeMap<ContextId, ContextId>,
+    /// PID in this namespace to context ID
+    global: BTreeMap<ContextId, ContextId>,
+    next: usize,
+    /// Set once the init of the namespace has exited
+    dead: bool,
+}
+
+#[derive(Debug)]
+pub struct PidNamespace {
+    /// The namespace this one was created in, `None` for a child of the root namespace
+    pub parent: Option<Arc<PidNamespace>>,
+    /// Nesting depth, 1 for a child of the root namespace
+    pub level: usize,
+    ids: Mutex<Ids>,
+}
+
+impl PidNamespace {
+    /// Create a namespace nested within `parent`
+    pub fn new(parent: Option<Arc<PidNamespace>>) -> Result<Arc<Self>> {
+        let level = parent.as_ref().map_or(0, |parent| parent.level) + 1;
+        if level > MAX_LEVEL {
+            return Err(Error::new(ENOSPC));
+        }
+        Ok(Arc::new(Self {
+            parent,
+            level,
+            ids: Mutex::new(Ids {
+                local: BTreeMap::new(),
+                global: BTreeMap::new(),
+                next: 1,
+                dead: false,
+            }),
+        }))
+    }
+
+    /// This namespace and its ancestors, excluding the root namespace
+    fn ancestors(&self) -> impl Iterator<Item = &PidNamespace> {
+        iter::successors(Some(self), |ns| ns.parent.as_deref())
+    }
+
+    /// Allocate the lowest free PID at or after the last one allocated, like context IDs
+    fn allocate(&self, id: ContextId) -> Result<ContextId> {
+        let mut ids = self.ids.lock();
+        if ids.dead {
+            return Err(Error::new(ESRCH));
+        }
+        if ids.next >= context::CONTEXT_MAX_CONTEXTS {
+            ids.next = 1;
+        }
+        while ids.global.contains_key(&ContextId::from(ids.next)) {
+            ids.next += 1;
+        }
+        let pid = ContextId::from(ids.next);
+        ids.next += 1;
+        ids.local.insert(id, pid);
+        ids.global.insert(pid, id);
+        Ok(pid)
+    }
+
+    fn release(&self, id: ContextId) {
+        let mut ids = self.ids.lock();
+        if let Some(pid) = ids.local.remove(&id) {
+            ids.global.remove(&pid);
+        }
+    }
+}
+
+/// The PID namespace of the current context
+pub fn current() -> Result<Option<Arc<PidNamespace>>> {
+    Ok(context::current()?.read().pid_ns.clone())
+}
+
+/// Give the context `id` a PID in `ns` and each of its ancestors. Fails if one of them is dead.
+pub fn register(ns: Option<&PidNamespace>, id: ContextId) -> Result<()> {
+    let Some(ns) = ns else {
+        return Ok(());
+    };
+    for (i, level) in ns.ancestors().enumerate() {
+        if let Err(err) = level.allocate(id) {
+            ns.ancestors().take(i).for_each(|level| level.release(id));
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Release the PIDs of the context `id`, once it has been reaped
+pub fn unregister(ns: Option<&PidNamespace>, id: ContextId) {
+    if let Some(ns) = ns {
+        ns.ancestors().for_each(|level| level.release(id));
+    }
+}
+
+/// The PID of the context `id` as seen from `ns`, if it is visible there
+pub fn to_local(ns: Option<&PidNamespace>, id: ContextId) -> Option<ContextId> {
+    match ns {
+        Some(ns) => ns.ids.lock().local.get(&id).copied(),
+        None => Some(id),
+    }
+}
+
+/// The context ID of the PID `pid` of `ns`
+pub fn to_global(ns: Option<&PidNamespace>, pid: ContextId) -> Option<ContextId> {
+    match ns {
+        Some(ns) => ns.ids.lock().global.get(&pid).copied(),
+        None => Some(pid),
+    }
+}
+
+/// Like [`to_local`], but with 0 for contexts outside of `ns`, as when reporting the parent of the
+/// init of a namespace
+pub fn to_local_or_zero(ns: Option<&PidNamespace>, id: ContextId) -> ContextId {
+    to_local(ns, id).unwrap_or(ContextId::from(0))
+}
+
+/// Returns true if the context `id` is the init of `ns`
+pub fn is_init(ns: &PidNamespace, id: ContextId) -> bool {
+    ns.ids.lock().local.get(&id) == Some(&ContextId::from(1))
+}
+
+/// Mark `ns` dead after its init exited, and return the contexts in it and its descendants, all
+/// of which are to be killed
+pub fn kill(ns: &PidNamespace) -> Vec<ContextId> {
+    let mut ids = ns.ids.lock();
+    ids.dead = true;
+    ids.local.keys().copied().collect()
+}
@@ -3,7 +3,7 @@ use core::mem;
 use syscall::flag::{PTRACE_FLAG_IGNORE, PTRACE_STOP_SIGNAL, SIG_DFL, SIG_IGN, SIGCHLD, SIGCONT, SIGKILL, SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU};
 use syscall::ptrace_event;
 
-use crate::context::{contexts, switch, Status, WaitpidKey};
+use crate::context::{contexts, coredump, switch, Status, WaitpidKey};
 use crate::start::usermode;
 use crate::ptrace;
 use crate::syscall::usercopy::UserSlice;
@@ -98,7 +98,8 @@ pub extern "C" fn signal_handler(sig: usize) {
             },
             _ => {
                 // println!("Exit {}", sig);
-                crate::syscall::exit(sig);
+                let core = coredump::dumps_core(sig) && coredump::write(sig);
+                crate::syscall::exit(if core { sig | coredump::CORE_FLAG } else { sig });
             }
         }
     } else if handler == SIG_IGN {
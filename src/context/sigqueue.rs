@@ -0,0 +1,74 @@
+use alloc::collections::VecDeque;
+
+use crate::syscall::abi::{SigInfo, SIGRTMIN};
+
+/// Maximum number of instances of a single realtime signal that can be queued at once
+pub const RTSIG_QUEUE_MAX: usize = 32;
+
+/// Queue of pending signals, each carrying its `SigInfo`. Standard signals are pending at most
+/// once, so sending one that is already pending has no effect. Realtime signals are queued once
+/// per send, up to `RTSIG_QUEUE_MAX` instances of each. Signals are taken lowest-numbered first,
+/// and instances of the same signal in the order they were sent.
+#[derive(Debug, Default)]
+pub struct SigQueue {
+    entries: VecDeque<SigInfo>,
+}
+
+impl SigQueue {
+    pub const fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Number of queued instances of `sig`
+    pub fn count(&self, sig: usize) -> usize {
+        self.entries.iter().filter(|info| info.si_signo as usize == sig).count()
+    }
+
+    /// Queue a signal, returning false if the queue limit of that signal has been reached. A
+    /// standard signal that is already pending is silently merged.
+    pub fn push(&mut self, info: SigInfo) -> bool {
+        let sig = info.si_signo as usize;
+        let count = self.count(sig);
+        if sig < SIGRTMIN {
+            if count == 0 {
+                self.entries.push_back(info);
+            }
+            true
+        } else if count < RTSIG_QUEUE_MAX {
+            self.entries.push_back(info);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The signal `pop` takes next
+    pub fn next(&self) -> Option<usize> {
+        self.entries.iter().map(|info| info.si_signo as usize).min()
+    }
+
+    /// Take the lowest-numbered pending signal, as POSIX requires for realtime signals, and the
+    /// oldest instance of it
+    pub fn pop(&mut self) -> Option<SigInfo> {
+        let index = self.entries.iter().enumerate().min_by_key(|(_, info)| info.si_signo)?.0;
+        self.entries.remove(index)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SigInfo> + '_ {
+        self.entries.iter()
+    }
+}
@@ -0,0 +1,479 @@
+//! # Swap
+//! Reclaims anonymous memory by writing pages out to a backing store, provided by a userspace
+//! daemon through the `swap:` scheme.
+//!
+//! Pages of lazily allocated grants are tracked in the order they were faulted in, which
+//! approximates least recently used. When free memory falls below `LOW_WATERMARK`, the daemon
+//! reclaims the oldest pages: each is unmapped, assigned a slot of the backing store, and queued
+//! for the daemon to read and write out. Its frame is freed once the daemon has read it. A later
+//! fault on the page queues a request to read the slot back, unlocks the address space, and blocks
+//! until the daemon has written the data (including faults by the kernel itself, during usercopy),
+//! before retrying.
+//!
+//! Address spaces which must keep running for swap to make progress, such as the daemon itself
+//! and the drivers it depends on, are marked unswappable. Pages lent to other address spaces (for
+//! scheme calls and `fmap`) are never swapped out, as their frames are mapped twice.
+
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use rmm::Arch as _;
+
+use crate::memory::{allocate_frames, deallocate_frames, free_frames, used_frames, Frame, PAGE_SIZE};
+use crate::paging::{Page, RmmA};
+use crate::sync::{Mutex, RwLock, WaitCondition};
+use crate::syscall::error::*;
+
+use super::memory::AddrSpace;
+
+/// Number of free frames below which pages are swapped out
+pub const LOW_WATERMARK: usize = 1024;
+/// Number of pages reclaimed at once, when memory is low
+pub const RECLAIM_BATCH: usize = 64;
+
+/// A slot of the backing store, holding the contents of a swapped out page. The slot is freed
+/// when the last reference to it is dropped.
+pub struct SwapSlot {
+    generation: usize,
+    slot: usize,
+}
+
+impl SwapSlot {
+    /// Another reference to the same slot, for an address space cloned from the owner
+    pub fn share(&self) -> SwapSlot {
+        if let Some(backing) = SWAP.lock().as_mut().filter(|backing| backing.generation == self.generation) {
+            *backing.uses.get_mut(&self.slot).expect("swap slot in use without a use count") += 1;
+        }
+        SwapSlot { generation: self.generation, slot: self.slot }
+    }
+}
+
+impl fmt::Debug for SwapSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SwapSlot({})", self.slot)
+    }
+}
+
+impl Drop for SwapSlot {
+    fn drop(&mut self) {
+        let mut swap = SWAP.lock();
+        let backing = match swap.as_mut().filter(|backing| backing.generation == self.generation) {
+            Some(backing) => backing,
+            None => return,
+        };
+        let uses = backing.uses.get_mut(&self.slot).expect("swap slot in use without a use count");
+        *uses -= 1;
+        if *uses > 0 {
+            return;
+        }
+        backing.uses.remove(&self.slot);
+        if let Some(id) = backing.reads.remove(&self.slot) {
+            backing.cancel_read(id);
+        }
+        // Nothing needs to be written if the daemon has not picked up the page yet
+        if let Some(index) = backing.queued_out(self.slot) {
+            if let Some(Request::Out { frame, .. }) = backing.queue.remove(index) {
+                deallocate_frames(frame, 1);
+            }
+        }
+        backing.free_slots.push(self.slot);
+    }
+}
+
+enum Request {
+    /// Write the page to the slot
+    Out { slot: usize, frame: Frame },
+    /// Read the slot into the frame
+    In { id: usize, slot: usize, frame: Frame },
+}
+
+/// A request as handed to the daemon
+pub enum Task {
+    /// Write the contents of `frame` to `slot`. The frame is freed once the daemon has copied it.
+    Out { slot: usize, frame: Frame },
+    /// Read `slot`, and complete the swap-in `id` with its contents
+    In { id: usize, slot: usize },
+}
+
+struct Backing {
+    generation: usize,
+    slot_count: usize,
+    /// Slots above this have never been used
+    next_slot: usize,
+    free_slots: Vec<usize>,
+    /// Number of references to each slot in use
+    uses: BTreeMap<usize, usize>,
+    /// Requests not yet read by the daemon
+    queue: VecDeque<Request>,
+    /// Swap-ins read by the daemon, waiting for their data, by request ID
+    reading: BTreeMap<usize, Frame>,
+    /// Results of swap-ins, waiting for the faulting context to pick them up
+    completed: BTreeMap<usize, Result<Frame>>,
+    /// Swap-ins not yet picked up, by slot
+    reads: BTreeMap<usize, usize>,
+    /// Swap-ins read by the daemon whose slot has been freed meanwhile, so that their frame is
+    /// freed once they complete
+    abandoned: BTreeSet<usize>,
+    next_request: usize,
+    /// Address space of the daemon
+    daemon: Weak<RwLock<AddrSpace>>,
+}
+
+impl Backing {
+    fn queued_out(&self, slot: usize) -> Option<usize> {
+        self.queue.iter().position(|request| matches!(request, Request::Out { slot: queued, .. } if *queued == slot))
+    }
+    /// Drop the swap-in `id`, whose slot has been freed
+    fn cancel_read(&mut self, id: usize) {
+        if let Some(index) = self.queue.iter().position(|request| matches!(request, Request::In { id: queued, .. } if *queued == id)) {
+            if let Some(Request::In { frame, .. }) = self.queue.remove(index) {
+                deallocate_frames(frame, 1);
+            }
+            return;
+        }
+        match self.completed.remove(&id) {
+            Some(Ok(frame)) => deallocate_frames(frame, 1),
+            Some(Err(_)) => (),
+            // Being read by the daemon
+            None => { self.abandoned.insert(id); }
+        }
+    }
+    fn allocate_slot(&mut self) -> Option<SwapSlot> {
+        let slot = match self.free_slots.pop() {
+            Some(slot) => slot,
+            None if self.next_slot < self.slot_count => {
+                self.next_slot += 1;
+                self.next_slot - 1
+            }
+            None => return None,
+        };
+        self.uses.insert(slot, 1);
+        Some(SwapSlot { generation: self.generation, slot })
+    }
+}
+
+impl Drop for Backing {
+    fn drop(&mut self) {
+        let frames = self.queue.drain(..).map(|request| match request {
+            Request::Out { frame, .. } | Request::In { frame, .. } => frame,
+        });
+        let frames = frames.chain(core::mem::take(&mut self.reading).into_values());
+        let frames = frames.chain(core::mem::take(&mut self.completed).into_values().filter_map(Result::ok));
+        for frame in frames {
+            deallocate_frames(frame, 1);
+        }
+    }
+}
+
+static SWAP: Mutex<Option<Backing>> = Mutex::new(None);
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+/// Whether a backing store is registered, so that pages should be tracked
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Set while a hibernation image is held, as the slots it refers to must keep their contents
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+/// Signalled when the daemon has something to do
+static REQUESTS: WaitCondition = WaitCondition::new();
+/// Signalled when swap-ins complete
+static COMPLETIONS: WaitCondition = WaitCondition::new();
+
+/// Pages which may be swapped out, oldest first. Entries are checked when they are reclaimed, so
+/// they may refer to pages which have been unmapped since.
+static LRU: Mutex<VecDeque<(Weak<RwLock<AddrSpace>>, Page)>> = Mutex::new(VecDeque::new());
+
+/// Register the backing store of the daemon running in `daemon`, with room for `slot_count` pages
+pub fn register(slot_count: usize, daemon: &Arc<RwLock<AddrSpace>>) -> Result<()> {
+    if SWAP.lock().is_some() {
+        return Err(Error::new(EBUSY));
+    }
+    // Address spaces are always locked before the backing store
+    daemon.write().swappable = false;
+
+    let mut swap = SWAP.lock();
+    if swap.is_some() {
+        drop(swap);
+        daemon.write().swappable = true;
+        return Err(Error::new(EBUSY));
+    }
+    *swap = Some(Backing {
+        generation: GENERATION.fetch_add(1, Ordering::SeqCst),
+        slot_count,
+        next_slot: 0,
+        free_slots: Vec::new(),
+        uses: BTreeMap::new(),
+        queue: VecDeque::new(),
+        reading: BTreeMap::new(),
+        completed: BTreeMap::new(),
+        reads: BTreeMap::new(),
+        abandoned: BTreeSet::new(),
+        next_request: 0,
+        daemon: Arc::downgrade(daemon),
+    });
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Remove the backing store. The contents of swapped out pages are lost, so faults on them fail.
+pub fn unregister() {
+    ENABLED.store(false, Ordering::SeqCst);
+    let backing = SWAP.lock().take();
+    if let Some(daemon) = backing.as_ref().and_then(|backing| backing.daemon.upgrade()) {
+        daemon.write().swappable = true;
+    }
+    drop(backing);
+    LRU.lock().clear();
+    COMPLETIONS.notify();
+}
+
+/// Track a page which has just been faulted in, making it a candidate for swapping out
+pub fn track(addr_space: &Arc<RwLock<AddrSpace>>, page: Page) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    {
+        let mut lru = LRU.lock();
+        lru.push_back((Arc::downgrade(addr_space), page));
+        // Drop stale entries, there can never be more pages to swap out than frames in use
+        while lru.len() > used_frames() {
+            lru.pop_front();
+        }
+    }
+    if free_frames() < LOW_WATERMARK {
+        REQUESTS.notify();
+    }
+}
+
+/// Stop or resume swapping out pages, while a hibernation image is held (see `hibernate`). Pages
+/// can still be swapped in.
+pub fn freeze(frozen: bool) {
+    FROZEN.store(frozen, Ordering::SeqCst);
+}
+
+/// Number of pages queued to be swapped out, that the daemon has not read yet
+pub fn pending_out() -> usize {
+    SWAP.lock().as_ref().map_or(0, |backing| {
+        backing.queue.iter().filter(|request| matches!(request, Request::Out { .. })).count()
+    })
+}
+
+/// Swap out up to `count` of the least recently faulted in pages. The frames are freed once the
+/// daemon has read them. Returns the number of pages queued.
+pub fn reclaim(count: usize) -> usize {
+    if FROZEN.load(Ordering::SeqCst) {
+        return 0;
+    }
+
+    let mut reclaimed = 0;
+    let mut remaining = LRU.lock().len();
+
+    while reclaimed < count && remaining > 0 {
+        remaining -= 1;
+        let (weak, page) = match LRU.lock().pop_front() {
+            Some(entry) => entry,
+            None => break,
+        };
+        let addr_space_lock = match weak.upgrade() {
+            Some(addr_space) => addr_space,
+            None => continue,
+        };
+        // The address space may be in use, possibly by this CPU, so never wait for it
+        let mut addr_space = match addr_space_lock.try_write() {
+            Some(addr_space) => addr_space,
+            None => {
+                LRU.lock().push_back((weak, page));
+                continue;
+            }
+        };
+        if !addr_space.can_swap_out(page) {
+            continue;
+        }
+        let slot = match SWAP.lock().as_mut().and_then(Backing::allocate_slot) {
+            Some(slot) => slot,
+            None => {
+                LRU.lock().push_front((weak, page));
+                break;
+            }
+        };
+        let frame = addr_space.swap_out(page);
+        match SWAP.lock().as_mut() {
+            Some(backing) => backing.queue.push_back(Request::Out { slot: slot.slot, frame }),
+            // Unregistered in the meantime, so the contents are lost like those of other pages
+            None => deallocate_frames(frame, 1),
+        }
+        addr_space.swapped.insert(page, slot);
+        reclaimed += 1;
+    }
+
+    if reclaimed > 0 {
+        REQUESTS.notify();
+    }
+    reclaimed
+}
+
+/// A swap-in queued for the daemon, which the faulting context waits for with its address space
+/// unlocked, before retrying the fault
+pub struct PendingRead {
+    generation: usize,
+    id: usize,
+}
+
+impl PendingRead {
+    /// Block until the daemon has completed the read, or the backing store is removed. Fails with
+    /// `EINTR` if a signal woke the current context first, in which case the read stays queued.
+    pub fn wait(self) -> Result<()> {
+        let mut swap = SWAP.lock();
+        loop {
+            match swap.as_ref().filter(|backing| backing.generation == self.generation) {
+                Some(backing) if !backing.completed.contains_key(&self.id) && backing.reads.values().any(|&id| id == self.id) => (),
+                _ => return Ok(()),
+            }
+            if !COMPLETIONS.wait(swap, "swap_in") {
+                return Err(Error::new(EINTR));
+            }
+            swap = SWAP.lock();
+        }
+    }
+}
+
+pub enum SwapIn {
+    /// A frame with the contents of the slot, after which the slot can be dropped
+    Ready(Frame),
+    /// The daemon has to read the slot first
+    Reading(PendingRead),
+}
+
+/// Get a frame with the contents of `slot` if they are in memory, or else queue a request for the
+/// daemon to read them, unless one is queued already. The caller must not wait for the read with
+/// the address space locked, and retries once it completes.
+pub fn swap_in(slot: &SwapSlot) -> Result<SwapIn> {
+    let mut swap = SWAP.lock();
+    let backing = swap.as_mut().filter(|backing| backing.generation == slot.generation).ok_or(Error::new(EIO))?;
+
+    if let Some(&id) = backing.reads.get(&slot.slot) {
+        return match backing.completed.remove(&id) {
+            Some(result) => {
+                backing.reads.remove(&slot.slot);
+                result.map(SwapIn::Ready)
+            }
+            None => Ok(SwapIn::Reading(PendingRead { generation: backing.generation, id })),
+        };
+    }
+
+    // The page may not have been written out yet, in which case it is still in memory
+    if let Some(index) = backing.queued_out(slot.slot) {
+        if backing.uses[&slot.slot] == 1 {
+            let Some(Request::Out { frame, .. }) = backing.queue.remove(index) else {
+                unreachable!("queued_out returned the index of a swap-out request");
+            };
+            return Ok(SwapIn::Ready(frame));
+        }
+        let Request::Out { frame: ref src, .. } = backing.queue[index] else {
+            unreachable!("queued_out returned the index of a swap-out request");
+        };
+        let frame = allocate_frames(1).ok_or(Error::new(ENOMEM))?;
+        unsafe {
+            (RmmA::phys_to_virt(frame.start_address()).data() as *mut u8).copy_from_nonoverlapping(RmmA::phys_to_virt(src.start_address()).data() as *const u8, PAGE_SIZE);
+        }
+        return Ok(SwapIn::Ready(frame));
+    }
+
+    let frame = allocate_frames(1).ok_or(Error::new(ENOMEM))?;
+    let id = backing.next_request;
+    backing.next_request += 1;
+    backing.queue.push_back(Request::In { id, slot: slot.slot, frame });
+    backing.reads.insert(slot.slot, id);
+    let generation = backing.generation;
+    drop(swap);
+    REQUESTS.notify();
+
+    Ok(SwapIn::Reading(PendingRead { generation, id }))
+}
+
+/// Take the next request for the daemon, reclaiming memory first if it is low. Blocks until there
+/// is a request, unless `block` is false.
+pub fn next_task(block: bool) -> Result<Task> {
+    loop {
+        let mut swap = SWAP.lock();
+        let backing = swap.as_mut().ok_or(Error::new(ENODEV))?;
+        match backing.queue.pop_front() {
+            Some(Request::Out { slot, frame }) => return Ok(Task::Out { slot, frame }),
+            Some(Request::In { id, slot, frame }) => {
+                backing.reading.insert(id, frame);
+                return Ok(Task::In { id, slot });
+            }
+            None => drop(swap),
+        }
+
+        if free_frames() < LOW_WATERMARK && reclaim(RECLAIM_BATCH) > 0 {
+            continue;
+        }
+        if !block {
+            return Err(Error::new(EAGAIN));
+        }
+
+        let swap = SWAP.lock();
+        if swap.as_ref().map_or(false, |backing| backing.queue.is_empty()) && !REQUESTS.wait(swap, "swap::next_task") {
+            return Err(Error::new(EINTR));
+        }
+    }
+}
+
+/// Return a task which could not be handed to the daemon, to the front of the queue
+pub fn requeue(task: Task) {
+    let mut swap = SWAP.lock();
+    let backing = match swap.as_mut() {
+        Some(backing) => backing,
+        None => {
+            if let Task::Out { frame, .. } = task {
+                deallocate_frames(frame, 1);
+            }
+            return;
+        }
+    };
+    match task {
+        Task::Out { slot, frame } => backing.queue.push_front(Request::Out { slot, frame }),
+        Task::In { id, slot } => if let Some(frame) = backing.reading.remove(&id) {
+            backing.queue.push_front(Request::In { id, slot, frame });
+        },
+    }
+}
+
+fn take_reading(id: usize) -> Result<Frame> {
+    SWAP.lock().as_mut().ok_or(Error::new(ENODEV))?.reading.remove(&id).ok_or(Error::new(ENOENT))
+}
+
+fn finish(id: usize, result: Result<Frame>) {
+    match SWAP.lock().as_mut() {
+        Some(backing) if !backing.abandoned.remove(&id) => { backing.completed.insert(id, result); }
+        _ => if let Ok(frame) = result { deallocate_frames(frame, 1); },
+    }
+    COMPLETIONS.notify();
+}
+
+/// Complete the swap-in `id`, with `fill` copying the contents of the slot into the page. If
+/// `fill` fails, the swap-in remains pending.
+pub fn complete(id: usize, fill: impl FnOnce(&mut [u8]) -> Result<()>) -> Result<()> {
+    let frame = take_reading(id)?;
+
+    let data = unsafe { core::slice::from_raw_parts_mut(RmmA::phys_to_virt(frame.start_address()).data() as *mut u8, PAGE_SIZE) };
+    if let Err(error) = fill(data) {
+        match SWAP.lock().as_mut() {
+            Some(backing) => { backing.reading.insert(id, frame); }
+            None => deallocate_frames(frame, 1),
+        }
+        return Err(error);
+    }
+
+    finish(id, Ok(frame));
+    Ok(())
+}
+
+/// Fail the swap-in `id`, for which the daemon could not read the slot
+pub fn fail(id: usize) -> Result<()> {
+    deallocate_frames(take_reading(id)?, 1);
+    finish(id, Err(Error::new(EIO)));
+    Ok(())
+}
@@ -2,18 +2,21 @@ use core::cell::Cell;
 use core::ops::Bound;
 use core::sync::atomic::Ordering;
 
+use alloc::boxed::Box;
 use alloc::sync::Arc;
 
-use spin::{RwLock, RwLockWriteGuard};
-
 use crate::context::signal::signal_handler;
-use crate::context::{arch, contexts, Context, Status, CONTEXT_ID};
+use crate::context::{arch, boost, contexts, kstack, latency, load, wakeups, Context, Status, CONTEXT_ID};
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use crate::gdt;
+use crate::hotplug;
 use crate::interrupt::irq::PIT_TICKS;
 use crate::interrupt;
+use crate::journal::{self, EventKind};
 use crate::ptrace;
+use crate::sync::{RwLock, RwLockWriteGuard};
 use crate::time;
+use crate::trace::SwitchState;
 
 unsafe fn update_runnable(context: &mut Context, cpu_id: usize) -> bool {
     // Ignore already running contexts
@@ -26,6 +29,11 @@ unsafe fn update_runnable(context: &mut Context, cpu_id: usize) -> bool {
         return false;
     }
 
+    // An offline CPU only runs its idle context
+    if hotplug::is_offline(cpu_id) && context.id != hotplug::idle_context(cpu_id) {
+        return false;
+    }
+
     // Take ownership if not already owned
     // TODO: Support unclaiming context, while still respecting the CPU affinity.
     if context.cpu_id == None && context.sched_affinity.map_or(true, |id| id == crate::cpu_id()) {
@@ -45,7 +53,7 @@ unsafe fn update_runnable(context: &mut Context, cpu_id: usize) -> bool {
         let ksig = context.ksig.take().expect("context::switch: ksig not set with ksig_restore");
         context.arch = ksig.0;
 
-        context.kfx.copy_from_slice(&*ksig.1);
+        context.kfx = ksig.1;
 
         if let Some(ref mut kstack) = context.kstack {
             kstack.copy_from_slice(&ksig.2.expect("context::switch: ksig kstack not set with ksig_restore"));
@@ -64,7 +72,7 @@ unsafe fn update_runnable(context: &mut Context, cpu_id: usize) -> bool {
     }
 
     // Unblock when there are pending signals
-    if context.status == Status::Blocked && !context.pending.is_empty() {
+    if context.status == Status::Blocked && context.has_pending_signals() {
         context.unblock();
     }
 
@@ -75,6 +83,11 @@ unsafe fn update_runnable(context: &mut Context, cpu_id: usize) -> bool {
         let current = time::monotonic();
         if current >= wake {
             context.wake = None;
+            context.wakeups.record(wakeups::Kind::Timer);
+            // The latency of a sleep is measured from its end
+            if let Some(ref mut latency) = context.latency {
+                latency.wake(wake);
+            }
             context.unblock();
         }
     }
@@ -83,6 +96,23 @@ unsafe fn update_runnable(context: &mut Context, cpu_id: usize) -> bool {
     context.status == Status::Runnable
 }
 
+/// The contexts a pass of the search for the next context considers
+#[derive(Clone, Copy, PartialEq)]
+enum Pass {
+    /// Contexts in the low-latency class, see `latency`
+    Latency,
+    /// Contexts recently woken by input, see `boost`
+    Boosted,
+    All,
+}
+
+fn earliest(a: Option<u128>, b: Option<u128>) -> Option<u128> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
 struct SwitchResult {
     prev_lock: Arc<RwLock<Context>>,
     next_lock: Arc<RwLock<Context>>,
@@ -112,10 +142,16 @@ pub unsafe fn switch() -> bool {
     //set PIT Interrupt counter to 0, giving each process same amount of PIT ticks
     let _ticks = PIT_TICKS.swap(0, Ordering::SeqCst);
 
+    load::tick();
+    #[cfg(target_arch = "x86_64")]
+    crate::cpufreq::tick();
+
     // Set the global lock to avoid the unsafe operations below from causing issues
+    let lock_start = journal::timestamp();
     while arch::CONTEXT_SWITCH_LOCK.compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed).is_err() {
         interrupt::pause();
     }
+    journal::contended(journal::Lock::ContextSwitch, lock_start);
 
     let cpu_id = crate::cpu_id();
     let switch_time = crate::time::monotonic();
@@ -128,33 +164,84 @@ pub unsafe fn switch() -> bool {
         let prev_context_lock = contexts.current().expect("context::switch: not inside of context");
         let prev_context_guard = prev_context_lock.write();
 
+        // Low-latency contexts are looked for first, then contexts recently woken by input, before
+        // any other context
+        let mut passes = [Pass::All; 3];
+        let mut pass_count = 0;
+        if latency::any() {
+            latency::begin_search();
+            passes[pass_count] = Pass::Latency;
+            pass_count += 1;
+        }
+        if boost::active(switch_time) {
+            passes[pass_count] = Pass::Boosted;
+            pass_count += 1;
+        }
+        passes[pass_count] = Pass::All;
+        pass_count += 1;
+
+        // The earliest end of a sleep of the low-latency contexts, including the previous one
+        let mut next_wake = prev_context_guard.latency.as_ref().and(prev_context_guard.wake);
+        // The low-latency context to run, once the other members have been looked at
+        let mut latency_choice = None;
+
         // Locate next context
-        for (_pid, next_context_lock) in contexts
-            // Include all contexts with IDs greater than the current...
-            .range(
-                (Bound::Excluded(prev_context_guard.id), Bound::Unbounded)
-            )
-            .chain(contexts
-                // ... and all contexts with IDs less than the current...
-                .range((Bound::Unbounded, Bound::Excluded(prev_context_guard.id)))
-            )
-            // ... but not the current context, which is already locked
-        {
-            // Lock next context
-            let mut next_context_guard = next_context_lock.write();
-
-            // Update state of next context and check if runnable
-            if update_runnable(&mut *next_context_guard, cpu_id) {
-                // Store locks for previous and next context
-                switch_context_opt = Some((
-                    Arc::clone(prev_context_lock),
-                    RwLockWriteGuard::leak(prev_context_guard) as *mut Context,
-                    Arc::clone(next_context_lock),
-                    RwLockWriteGuard::leak(next_context_guard) as *mut Context,
-                ));
-                break;
-            } else {
-                continue;
+        'search: for &pass in &passes[..pass_count] {
+            for (_pid, next_context_lock) in contexts
+                // Include all contexts with IDs greater than the current...
+                .range(
+                    (Bound::Excluded(prev_context_guard.id), Bound::Unbounded)
+                )
+                .chain(contexts
+                    // ... and all contexts with IDs less than the current...
+                    .range((Bound::Unbounded, Bound::Excluded(prev_context_guard.id)))
+                )
+                // ... but not the current context, which is already locked
+            {
+                // Lock next context
+                let mut next_context_guard = next_context_lock.write();
+
+                match pass {
+                    Pass::Latency if next_context_guard.latency.is_none() => continue,
+                    // Keep looking at the sleeping members after finding one to run
+                    Pass::Latency if latency_choice.is_some() => {
+                        next_wake = earliest(next_wake, next_context_guard.wake);
+                        continue;
+                    }
+                    Pass::Boosted if next_context_guard.boost.map_or(true, |until| until <= switch_time) => continue,
+                    _ => (),
+                }
+
+                // Update state of next context and check if runnable
+                if update_runnable(&mut *next_context_guard, cpu_id) {
+                    if pass == Pass::Latency {
+                        latency_choice = Some((next_context_lock, next_context_guard));
+                        continue;
+                    }
+                    // Store locks for previous and next context
+                    switch_context_opt = Some((
+                        Arc::clone(prev_context_lock),
+                        RwLockWriteGuard::leak(prev_context_guard) as *mut Context,
+                        Arc::clone(next_context_lock),
+                        RwLockWriteGuard::leak(next_context_guard) as *mut Context,
+                    ));
+                    break 'search;
+                } else if pass == Pass::Latency {
+                    next_wake = earliest(next_wake, next_context_guard.wake);
+                }
+            }
+
+            if pass == Pass::Latency {
+                latency::end_search(next_wake);
+                if let Some((next_context_lock, next_context_guard)) = latency_choice.take() {
+                    switch_context_opt = Some((
+                        Arc::clone(prev_context_lock),
+                        RwLockWriteGuard::leak(prev_context_guard) as *mut Context,
+                        Arc::clone(next_context_lock),
+                        RwLockWriteGuard::leak(next_context_guard) as *mut Context,
+                    ));
+                    break 'search;
+                }
             }
         }
     };
@@ -165,11 +252,20 @@ pub unsafe fn switch() -> bool {
         let prev_context = &mut *prev_context_ptr;
         prev_context.running = false;
         prev_context.cpu_time += switch_time.saturating_sub(prev_context.switch_time);
+        load::account(cpu_id, prev_context.id, switch_time.saturating_sub(prev_context.switch_time));
+        if let Some(ref stack) = prev_context.kstack {
+            stack.check_canary(prev_context.id);
+        }
 
         // Set new context as running and set switch time
         let next_context = &mut *next_context_ptr;
         next_context.running = true;
         next_context.switch_time = switch_time;
+        // A boost only lasts until the context gets to run
+        next_context.boost = None;
+        if let Some(ref mut latency) = next_context.latency {
+            latency.run(switch_time);
+        }
 
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
         {
@@ -177,16 +273,22 @@ pub unsafe fn switch() -> bool {
                 gdt::set_tss_stack(stack.as_ptr() as usize + stack.len());
             }
         }
+        kstack::set_current(next_context.kstack.as_ref());
         CONTEXT_ID.store(next_context.id, Ordering::SeqCst);
+        journal::record(EventKind::ContextSwitch, prev_context.id.into(), next_context.id.into());
+        crate::tracepoint!(SchedSwitch, prev_context.id.into(), next_context.id.into(), SwitchState::of(&prev_context.status));
+        crate::perf::switch(&next_context.perf);
 
         if next_context.ksig.is_none() {
             //TODO: Allow nested signals
-            if let Some(sig) = next_context.pending.pop_front() {
+            if let Some(info) = next_context.pop_pending_signal() {
                 // Signal was found, run signal handler
+                let sig = info.si_signo as u8;
                 let arch = next_context.arch.clone();
                 let kfx = next_context.kfx.clone();
-                let kstack = next_context.kstack.clone();
+                let kstack = next_context.kstack.as_deref().map(Box::from);
                 next_context.ksig = Some((arch, kfx, kstack, sig));
+                next_context.siginfo = Some(info);
                 next_context.arch.signal_stack(signal_handler, sig);
             }
         }
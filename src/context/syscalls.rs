@@ -0,0 +1,56 @@
+//! # Syscall statistics
+//! While enabled through `syscalls:ctl` (see `scheme::syscalls`), every syscall that returns is
+//! counted, and the nanoseconds it took are recorded in a histogram of the calling context, by
+//! syscall number. Time spent blocked inside the syscall is included, so that the latency seen by
+//! the caller, such as that of schemes and relibc, can be measured. Disabled, they cost one load
+//! per syscall.
+
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::common::histogram::Histogram;
+use crate::context::contexts;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true if syscalls are being recorded
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// The latencies of the syscalls made by a context, by syscall number
+#[derive(Default)]
+pub struct SyscallStats {
+    latencies: BTreeMap<usize, Histogram>,
+}
+
+impl SyscallStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that syscall `number` took `nanos`
+    pub fn record(&mut self, number: usize, nanos: u64) {
+        self.latencies.entry(number).or_default().record(nanos);
+    }
+
+    /// The syscall numbers that were recorded, in order, with their latencies
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Histogram)> + '_ {
+        self.latencies.iter().map(|(&number, histogram)| (number, histogram))
+    }
+
+    pub fn clear(&mut self) {
+        self.latencies.clear();
+    }
+}
+
+/// Discard the statistics of all contexts
+pub fn reset() {
+    for (_, context_lock) in contexts().iter() {
+        context_lock.write().syscall_stats.clear();
+    }
+}
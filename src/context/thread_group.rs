@@ -0,0 +1,30 @@
+
+use crate::context::ContextId;
+use crate::context::sigqueue::SigQueue;
+use crate::sync::{Mutex, WaitCondition};
+
+/// State shared between all contexts of a thread group. A context that was not created as a thread
+/// is the leader of its own group, whose ID is the ID of that context.
+#[derive(Debug)]
+pub struct ThreadGroup {
+    /// The thread group ID, i.e. the context ID of the group leader
+    pub tgid: ContextId,
+    /// Signals sent to the group as a whole, handled by whichever thread gets to them first
+    pub pending: Mutex<SigQueue>,
+    /// Exit status set by `exit_group`, overriding the status of every thread exiting after it
+    pub exit_status: Mutex<Option<usize>>,
+    /// Notified with `exit_status` held when a thread exits, for the leader to report the status of
+    /// the group once the other threads are gone
+    pub exited: WaitCondition,
+}
+
+impl ThreadGroup {
+    pub fn new(tgid: ContextId) -> Self {
+        Self {
+            tgid,
+            pending: Mutex::new(SigQueue::new()),
+            exit_status: Mutex::new(None),
+            exited: WaitCondition::new(),
+        }
+    }
+}
@@ -1,11 +1,16 @@
 use alloc::collections::VecDeque;
-use spin::{Once, Mutex, MutexGuard};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
 
+use crate::context::wakeups::{Kind, Wakeups};
 use crate::event;
 use crate::scheme::SchemeId;
+use crate::sync::{Mutex, MutexGuard};
 use crate::syscall::data::TimeSpec;
 use crate::syscall::flag::{CLOCK_MONOTONIC, CLOCK_REALTIME, EVENT_READ};
 use crate::time;
+use crate::trace::Work;
 
 #[derive(Debug)]
 struct Timeout {
@@ -13,12 +18,18 @@ struct Timeout {
     pub event_id: usize,
     pub clock: usize,
     pub time: u128,
+    /// The wakeups of the context that registered the timeout
+    pub wakeups: Option<Arc<Wakeups>>,
 }
 
 type Registry = VecDeque<Timeout>;
 
 static REGISTRY: Once<Mutex<Registry>> = Once::new();
 
+/// Monotonic time of the last tick, and nanoseconds between the last two
+static LAST_TICK: AtomicU64 = AtomicU64::new(0);
+static TICK_PERIOD: AtomicU64 = AtomicU64::new(0);
+
 /// Initialize registry, called if needed
 fn init_registry() -> Mutex<Registry> {
     Mutex::new(Registry::new())
@@ -30,19 +41,38 @@ fn registry() -> MutexGuard<'static, Registry> {
 }
 
 pub fn register(scheme_id: SchemeId, event_id: usize, clock: usize, time: TimeSpec) {
+    let wakeups = super::current().ok().map(|context| Arc::clone(&context.read().wakeups));
+
     let mut registry = registry();
     registry.push_back(Timeout {
         scheme_id,
         event_id,
         clock,
-        time: (time.tv_sec as u128 * time::NANOS_PER_SEC) + (time.tv_nsec as u128)
+        time: (time.tv_sec as u128 * time::NANOS_PER_SEC) + (time.tv_nsec as u128),
+        wakeups,
     });
 }
 
+/// Monotonic time of the next tick, as far as it can be predicted. The timer is periodic, and
+/// timeouts are only checked on ticks, so no timer wakes CPUs up any later.
+pub fn next_tick() -> u128 {
+    let last = LAST_TICK.load(Ordering::Relaxed);
+    u128::from(last) + u128::from(TICK_PERIOD.load(Ordering::Relaxed))
+}
+
+/// Trigger the timeouts that expired. Called on each tick of the timer.
 pub fn trigger() {
+    let mono = time::monotonic();
+    let last = LAST_TICK.swap(mono as u64, Ordering::Relaxed);
+    if last != 0 {
+        TICK_PERIOD.store((mono as u64).saturating_sub(last), Ordering::Relaxed);
+    }
+
+    crate::tracepoint!(WorkBegin, Work::Timeouts, 0);
+    let mut expired = 0;
+
     let mut registry = registry();
 
-    let mono = time::monotonic();
     let real = time::realtime();
 
     let mut i = 0;
@@ -64,9 +94,16 @@ pub fn trigger() {
 
         if trigger {
             let timeout = registry.remove(i).unwrap();
+            if let Some(wakeups) = timeout.wakeups {
+                wakeups.record(Kind::Timer);
+            }
             event::trigger(timeout.scheme_id, timeout.event_id, EVENT_READ);
+            expired += 1;
         } else {
             i += 1;
         }
     }
+    drop(registry);
+
+    crate::tracepoint!(WorkEnd, Work::Timeouts, expired);
 }
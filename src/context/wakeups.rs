@@ -0,0 +1,62 @@
+//! # Wakeup accounting
+//! Counts the timer and IRQ wakeups of each context, attributed to the context that armed them:
+//! sleeps and futex timeouts to the sleeping context, timeouts of `time:` to the context that
+//! wrote them, and IRQs to the contexts that opened the IRQ in `irq:`. The rate over the last load
+//! sample interval is kept as well, so that `sys:wakeups` can show which contexts keep the CPUs
+//! from staying idle, such as daemons polling with short timeouts.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::context::contexts;
+use crate::time;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Kind {
+    Timer,
+    Irq,
+}
+
+#[derive(Debug, Default)]
+pub struct Wakeups {
+    timer: AtomicU64,
+    irq: AtomicU64,
+    /// The total at the previous sample
+    last: AtomicU64,
+    /// Wakeups per second during the previous sample interval, in hundredths
+    rate: AtomicU64,
+}
+
+impl Wakeups {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, kind: Kind) {
+        let counter = match kind {
+            Kind::Timer => &self.timer,
+            Kind::Irq => &self.irq,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn total(&self) -> u64 {
+        self.timer.load(Ordering::Relaxed) + self.irq.load(Ordering::Relaxed)
+    }
+
+    /// Timer and IRQ wakeups, and wakeups per second in hundredths
+    pub fn counts(&self) -> (u64, u64, u64) {
+        (self.timer.load(Ordering::Relaxed), self.irq.load(Ordering::Relaxed), self.rate.load(Ordering::Relaxed))
+    }
+}
+
+/// Update the rates of all contexts, `interval` nanoseconds after the previous sample. Called
+/// when sampling the load averages.
+pub fn sample(interval: u128) {
+    // Contexts currently being switched are locked, and skipped
+    for context in contexts().iter().filter_map(|(_, context_lock)| context_lock.try_read()) {
+        let wakeups = &context.wakeups;
+        let total = wakeups.total();
+        let delta = total - wakeups.last.swap(total, Ordering::Relaxed);
+        wakeups.rate.store((delta as u128 * 100 * time::NANOS_PER_SEC / interval) as u64, Ordering::Relaxed);
+    }
+}
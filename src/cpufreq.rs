@@ -0,0 +1,159 @@
+//! # CPU frequency scaling
+//! Each CPU runs at the performance state chosen by its governor, among those of its driver (see
+//! `arch::cpufreq`). `performance` chooses the fastest state and `powersave` the slowest.
+//! `ondemand` goes to the fastest state once the CPU is busy for more than `UP_THRESHOLD` of a
+//! sample interval, and otherwise to the slowest state at which the work of the last interval
+//! would have kept it busy for at most `UP_THRESHOLD`. Busy time is that accounted by the
+//! scheduler (see `context::load`).
+//!
+//! Governors are chosen per CPU through `cpufreq:`, and each CPU applies its own on context
+//! switches, at most once per `SAMPLE_INTERVAL`, as states can only be set on the CPU itself.
+//! Whatever the governor, no CPU goes faster than the state limit set by thermal throttling (see
+//! `thermal`).
+
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use crate::arch::cpufreq;
+use crate::context::load;
+use crate::time;
+
+/// Number of CPUs with a governor, those whose utilization is tracked
+pub const CPUS: usize = load::CPUS;
+
+/// Nanoseconds between samples of the utilization
+const SAMPLE_INTERVAL: u128 = 50_000_000;
+
+/// Utilization above which `ondemand` goes to the fastest state, in thousandths
+const UP_THRESHOLD: u64 = 800;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Governor {
+    Performance,
+    Powersave,
+    Ondemand,
+}
+
+impl Governor {
+    pub const ALL: [Governor; 3] = [Governor::Performance, Governor::Powersave, Governor::Ondemand];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Governor::Performance => "performance",
+            Governor::Powersave => "powersave",
+            Governor::Ondemand => "ondemand",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|governor| governor.name() == name)
+    }
+}
+
+struct Policy {
+    governor: AtomicU8,
+    next_sample: AtomicU64,
+    /// Busy and idle nanoseconds at the previous sample
+    last_busy: AtomicU64,
+    last_idle: AtomicU64,
+}
+
+static POLICY: [Policy; CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const POLICY: Policy = Policy {
+        governor: AtomicU8::new(Governor::Ondemand as u8),
+        next_sample: AtomicU64::new(0),
+        last_busy: AtomicU64::new(0),
+        last_idle: AtomicU64::new(0),
+    };
+    [POLICY; CPUS]
+};
+
+/// The fastest state allowed
+static LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+/// The fastest state allowed, zero if not throttled
+pub fn limit() -> usize {
+    LIMIT.load(Ordering::Relaxed)
+}
+
+/// Set the fastest state allowed, up to the slowest state of any CPU
+pub fn set_limit(limit: usize) {
+    let slowest = (0..crate::cpu_count()).map(cpufreq::count).max().unwrap_or(0).saturating_sub(1);
+    LIMIT.store(limit.min(slowest), Ordering::Relaxed);
+}
+
+/// The governor of `cpu_id`
+pub fn governor(cpu_id: usize) -> Option<Governor> {
+    let governor = POLICY.get(cpu_id)?.governor.load(Ordering::Relaxed);
+    Governor::ALL.get(usize::from(governor)).copied()
+}
+
+/// Choose the governor of `cpu_id`, which applies it at its next sample
+pub fn set_governor(cpu_id: usize, governor: Governor) -> bool {
+    match POLICY.get(cpu_id) {
+        Some(policy) => {
+            policy.governor.store(governor as u8, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The frequency `cpu_id` was set to, in MHz
+pub fn frequency(cpu_id: usize) -> Option<u32> {
+    cpufreq::frequency(cpu_id, cpufreq::state(cpu_id)?)
+}
+
+/// Apply the governor of the current CPU if the sample interval has passed. Called by the
+/// scheduler before it locks anything.
+pub fn tick() {
+    let cpu_id = crate::cpu_id();
+    let policy = match POLICY.get(cpu_id) {
+        Some(policy) => policy,
+        None => return,
+    };
+    let now = time::monotonic();
+    if now < policy.next_sample.load(Ordering::Relaxed) as u128 {
+        return;
+    }
+    policy.next_sample.store((now + SAMPLE_INTERVAL) as u64, Ordering::Relaxed);
+
+    let count = cpufreq::count(cpu_id);
+    let (busy, idle, _) = match load::cpu_load(cpu_id) {
+        Some(load) if count > 0 => load,
+        _ => return,
+    };
+    let busy_delta = busy - policy.last_busy.swap(busy, Ordering::Relaxed);
+    let idle_delta = idle - policy.last_idle.swap(idle, Ordering::Relaxed);
+
+    let current = cpufreq::state(cpu_id);
+    let target = match governor(cpu_id) {
+        Some(Governor::Performance) | None => 0,
+        Some(Governor::Powersave) => count - 1,
+        Some(Governor::Ondemand) => ondemand(cpu_id, count, current, busy_delta, idle_delta),
+    }.max(limit()).min(count - 1);
+    if current != Some(target) {
+        unsafe { cpufreq::set_state(target) };
+    }
+}
+
+fn ondemand(cpu_id: usize, count: usize, current: Option<usize>, busy: u64, idle: u64) -> usize {
+    let total = busy + idle;
+    let (current, current_mhz) = match current.and_then(|current| Some((current, cpufreq::frequency(cpu_id, current)?))) {
+        Some(current) if total > 0 => current,
+        // Until a sample was taken at a known state, start from the fastest one
+        _ => return current.unwrap_or(0),
+    };
+    let utilization = busy * 1000 / total;
+    if utilization > UP_THRESHOLD {
+        return 0;
+    }
+
+    // The slowest state fast enough for the work done at the current frequency
+    let needed = u64::from(current_mhz) * utilization / UP_THRESHOLD;
+    (0..count)
+        .rev()
+        .find(|&index| cpufreq::frequency(cpu_id, index).map_or(false, |mhz| u64::from(mhz) >= needed))
+        .unwrap_or(current)
+}
@@ -0,0 +1,872 @@
+//! # GDB stub
+//! A stub speaking the GDB remote serial protocol on COM2, to debug the kernel from another
+//! machine over a serial cable, without an external probe. It is enabled with `GDB=com2` in the
+//! environment, and `GDB=com2,wait` also breaks into it once devices are initialized, for GDB to
+//! attach before the kernel goes on:
+//!
+//! ```text
+//! (gdb) file kernel
+//! (gdb) target remote /dev/ttyS1
+//! ```
+//!
+//! The kernel stops on breakpoints and single-steps in kernel mode, and when GDB interrupts it.
+//! The CPU that stopped runs the stub, polling the port with interrupts disabled, while the others
+//! are stopped with an NMI, until GDB resumes them all.
+//!
+//! Threads are CPUs, with the registers they were stopped with, and contexts that are not running,
+//! with the registers of the kernel code that switched away from them, of which only the
+//! callee-saved ones are known. Memory is read and written in the address space of the selected
+//! thread, through the mapping of physical memory, so that software breakpoints can be written to
+//! the read-only kernel code. Only memory handed over by the bootloader can be accessed.
+//!
+//! Breakpoints are taken through the regular breakpoint handler, which records the registers in
+//! the current context, so breakpoints in code holding the current context locked for writing
+//! hang. Lockup detection is paused while stopped, while hardware watchdogs are stopped, but the
+//! deadline of userspace keeps running.
+
+use core::fmt::{self, Write};
+use core::hint::spin_loop;
+use core::ptr;
+use core::str;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use rmm::{PageMapper, TableKind};
+
+use crate::context::{self, ContextId};
+use crate::devices::uart_16550::SerialPort;
+use crate::interrupt::InterruptStack;
+use crate::log::{info, warn};
+use crate::memory::PAGE_SIZE;
+use crate::paging::{huge, PhysicalAddress, RmmA, RmmArch, VirtualAddress};
+use crate::rmm::FRAME_ALLOCATOR;
+use crate::sync::Mutex;
+use crate::syscall::io::Pio;
+use crate::syscall::IntRegisters;
+use crate::USER_END_OFFSET;
+
+/// Number of CPUs that can be stopped
+const CPUS: usize = 32;
+
+/// Base of the I/O ports of COM2
+const PORT_BASE: u16 = 0x2F8;
+
+/// Largest packet, in either direction
+const PACKET_SIZE: usize = 4096;
+
+/// Number of software breakpoints
+const BREAKPOINTS: usize = 32;
+
+/// Thread IDs of contexts start here, those below standing for CPUs, plus one
+const CONTEXT_TIDS: usize = 0x1_0000;
+
+/// Iterations of the wait for the other CPUs to stop, well over a second
+const STOP_SPINS: usize = 100_000_000;
+
+const INT3: u8 = 0xCC;
+
+/// Sent by GDB to interrupt the kernel
+const INTERRUPT: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const NO_CPU: usize = usize::MAX;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_STACK: AtomicPtr<InterruptStack> = AtomicPtr::new(ptr::null_mut());
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TABLE: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const FALSE: AtomicBool = AtomicBool::new(false);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Whether GDB is attached, and waits for a stop reply
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+/// Set when GDB interrupted the kernel, until the stub is entered
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The CPU running the stub, if any
+static OWNER: AtomicUsize = AtomicUsize::new(NO_CPU);
+/// Counting the CPUs stopped by the owner, and the times it resumed them
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+static RESUMES: AtomicUsize = AtomicUsize::new(0);
+
+/// The registers each CPU was stopped with, while stopped
+static STACKS: [AtomicPtr<InterruptStack>; CPUS] = [NO_STACK; CPUS];
+/// The user page table of each CPU, while stopped
+static TABLES: [AtomicUsize; CPUS] = [NO_TABLE; CPUS];
+/// Set for the CPU that is single-stepping
+static STEPPING: [AtomicBool; CPUS] = [FALSE; CPUS];
+
+/// The software breakpoints, as addresses and the bytes replaced
+static BREAKPOINT_LIST: Mutex<[Option<(usize, u8)>; BREAKPOINTS]> = Mutex::new([None; BREAKPOINTS]);
+
+/// The packets received and sent, only used by the owner
+static PACKETS: Mutex<(Packet, Packet)> = Mutex::new((Packet::new(), Packet::new()));
+
+/// Enable the stub if `GDB` in the environment names its port, and break into it if asked to.
+/// Called on the BSP once devices are initialized.
+pub fn configure(env: &[u8]) {
+    let Some(value) = str::from_utf8(env).unwrap_or("").lines().find_map(|line| line.strip_prefix("GDB=")) else {
+        return;
+    };
+    let wait = match value.trim() {
+        "com2" => false,
+        "com2,wait" => true,
+        _ => {
+            warn!("gdbstub: ignoring unsupported GDB={:?}", value);
+            return;
+        }
+    };
+    ENABLED.store(true, Ordering::SeqCst);
+    info!("gdbstub: listening on COM2");
+    if wait {
+        info!("gdbstub: waiting for GDB");
+        breakpoint();
+    }
+}
+
+/// Whether the stub is enabled, and owns COM2
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Break into the stub, if enabled
+#[inline(never)]
+pub fn breakpoint() {
+    if enabled() {
+        unsafe { core::arch::asm!("int3") };
+    }
+}
+
+/// Handle a byte received on COM2 by its interrupt, breaking into the stub if GDB interrupts.
+/// Returns false if the stub is disabled, and the byte is input for `debug:`.
+pub fn input(byte: u8) -> bool {
+    if !enabled() {
+        return false;
+    }
+    if byte == INTERRUPT {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        breakpoint();
+    }
+    true
+}
+
+/// The current CPU, if it can be stopped
+fn cpu() -> Option<usize> {
+    Some(crate::cpu_id()).filter(|&cpu| cpu < CPUS)
+}
+
+/// Handle a breakpoint exception taken with `stack`, whose RIP was moved back to the `int3`.
+/// Returns false if it is not for the stub.
+pub fn breakpoint_trap(stack: &mut InterruptStack) -> bool {
+    if !enabled() || stack.iret.cs & 0b11 != 0 {
+        return false;
+    }
+    // A breakpoint in the stub itself
+    let Some(cpu) = cpu().filter(|&cpu| OWNER.load(Ordering::SeqCst) != cpu) else {
+        return false;
+    };
+    if !claim(cpu, stack) {
+        // Taken again once resumed, if still there
+        return true;
+    }
+
+    let rip = stack.iret.rip;
+    if !BREAKPOINT_LIST.lock().iter().flatten().any(|&(address, _)| address == rip) {
+        // Compiled in, as by `breakpoint`, unless removed since
+        if unsafe { *(rip as *const u8) } != INT3 {
+            release();
+            return true;
+        }
+        stack.iret.rip += 1;
+    }
+
+    let signal = if INTERRUPTED.swap(false, Ordering::SeqCst) { SIGINT } else { SIGTRAP };
+    stop(cpu, stack, signal);
+    true
+}
+
+/// Handle a debug exception taken with `stack`. Returns false if it is not for the stub.
+pub fn debug_trap(stack: &mut InterruptStack) -> bool {
+    let Some(cpu) = cpu() else {
+        return false;
+    };
+    if !STEPPING[cpu].swap(false, Ordering::SeqCst) {
+        return false;
+    }
+    stack.set_singlestep(false);
+    if claim(cpu, stack) {
+        stop(cpu, stack, SIGTRAP);
+    }
+    true
+}
+
+/// Stop the current CPU if another one runs the stub, until it resumes. Called by the NMI handler,
+/// returning true if the NMI was sent to stop.
+pub fn nmi(stack: &mut InterruptStack) -> bool {
+    let Some(cpu) = cpu() else {
+        return false;
+    };
+    let resumes = RESUMES.load(Ordering::SeqCst);
+    let owner = OWNER.load(Ordering::SeqCst);
+    if owner == NO_CPU || owner == cpu {
+        return false;
+    }
+    // Already stopped, by a trap taken while another CPU ran the stub
+    if !STACKS[cpu].load(Ordering::SeqCst).is_null() {
+        return true;
+    }
+    park(cpu, stack, resumes);
+    true
+}
+
+/// Become the CPU running the stub, returning true, or wait for the one that is to resume the
+/// others, returning false
+fn claim(cpu: usize, stack: &mut InterruptStack) -> bool {
+    let resumes = RESUMES.load(Ordering::SeqCst);
+    if OWNER.compare_exchange(NO_CPU, cpu, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+        return true;
+    }
+    park(cpu, stack, resumes);
+    false
+}
+
+/// Wait with the registers in `stack` until the owner resumes, after `resumes` resumes
+fn park(cpu: usize, stack: &mut InterruptStack, resumes: usize) {
+    TABLES[cpu].store(RmmA::table(TableKind::User).data(), Ordering::SeqCst);
+    STACKS[cpu].store(stack, Ordering::SeqCst);
+    STOPPED.fetch_add(1, Ordering::SeqCst);
+    while RESUMES.load(Ordering::SeqCst) == resumes && OWNER.load(Ordering::SeqCst) != NO_CPU {
+        spin_loop();
+    }
+    STACKS[cpu].store(ptr::null_mut(), Ordering::SeqCst);
+}
+
+/// Resume the CPUs waiting in `park`, and let another one run the stub
+fn release() {
+    STOPPED.store(0, Ordering::SeqCst);
+    RESUMES.fetch_add(1, Ordering::SeqCst);
+    OWNER.store(NO_CPU, Ordering::SeqCst);
+}
+
+/// Stop every other CPU, run the stub on this one until GDB resumes, and resume them all
+fn stop(cpu: usize, stack: &mut InterruptStack, signal: u8) {
+    TABLES[cpu].store(RmmA::table(TableKind::User).data(), Ordering::SeqCst);
+    STACKS[cpu].store(stack, Ordering::SeqCst);
+
+    // NMI to all excluding self
+    #[cfg(feature = "multi_core")]
+    unsafe {
+        crate::device::local_apic::LOCAL_APIC.set_icr(3 << 18 | 1 << 14 | 0b100 << 8);
+    }
+    let others = crate::cpu_count().min(CPUS).saturating_sub(1);
+    let mut spins = 0;
+    while STOPPED.load(Ordering::SeqCst) < others && spins < STOP_SPINS {
+        spin_loop();
+        spins += 1;
+    }
+
+    // Restarted by the next timer interrupt
+    unsafe { crate::arch::nmi::stop() };
+    crate::lockup::pause();
+    crate::watchdog::suspend();
+
+    let step = Session::new(cpu).run(signal);
+    if let Some(step) = step {
+        if let Some(stack) = unsafe { STACKS[step].load(Ordering::SeqCst).as_mut() } {
+            STEPPING[step].store(true, Ordering::SeqCst);
+            stack.set_singlestep(true);
+        }
+    }
+
+    crate::watchdog::resume();
+    crate::lockup::resume();
+    STACKS[cpu].store(ptr::null_mut(), Ordering::SeqCst);
+    release();
+}
+
+/// A CPU or a context that is not running, as seen by GDB
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Thread {
+    Cpu(usize),
+    Context(ContextId),
+}
+
+impl Thread {
+    fn from_tid(tid: usize) -> Option<Thread> {
+        let thread = match tid {
+            0 => return None,
+            1..=CPUS => Thread::Cpu(tid - 1),
+            _ => Thread::Context(ContextId::from(tid.checked_sub(CONTEXT_TIDS)?)),
+        };
+        Some(thread).filter(|thread| thread.alive())
+    }
+
+    fn tid(self) -> usize {
+        match self {
+            Thread::Cpu(cpu) => cpu + 1,
+            Thread::Context(id) => CONTEXT_TIDS + id.into(),
+        }
+    }
+
+    fn alive(self) -> bool {
+        match self {
+            Thread::Cpu(cpu) => cpu < CPUS && !STACKS[cpu].load(Ordering::SeqCst).is_null(),
+            // The context may be locked by a stopped CPU
+            Thread::Context(id) => context::contexts()
+                .get(id)
+                .map_or(false, |context| context.try_read().map_or(false, |context| !context.running)),
+        }
+    }
+
+    fn registers(self) -> Option<IntRegisters> {
+        match self {
+            Thread::Cpu(cpu) => {
+                let stack = unsafe { STACKS[cpu].load(Ordering::SeqCst).as_ref()? };
+                let mut regs = IntRegisters::default();
+                stack.save(&mut regs);
+                // Pushed in long mode even without a privilege change
+                regs.rsp = stack.iret.rsp;
+                regs.ss = stack.iret.ss;
+                Some(regs)
+            }
+            Thread::Context(id) => {
+                let contexts = context::contexts();
+                let context = contexts.get(id)?.try_read()?;
+                if context.running {
+                    return None;
+                }
+                unsafe { context.arch.switched_registers() }
+            }
+        }
+    }
+
+    fn set_registers(self, regs: &IntRegisters) -> bool {
+        match self {
+            Thread::Cpu(cpu) => match unsafe { STACKS[cpu].load(Ordering::SeqCst).as_mut() } {
+                Some(stack) => {
+                    stack.load(regs);
+                    true
+                }
+                None => false,
+            },
+            Thread::Context(_) => false,
+        }
+    }
+
+    /// The page table with the user memory of the thread
+    fn user_table(self) -> Option<PhysicalAddress> {
+        match self {
+            Thread::Cpu(cpu) => Some(TABLES[cpu].load(Ordering::SeqCst)).filter(|&table| table != 0).map(PhysicalAddress::new),
+            Thread::Context(id) => {
+                let contexts = context::contexts();
+                let context = contexts.get(id)?.try_read()?;
+                let addr_space = context.addr_space.as_ref()?.try_read()?;
+                Some(addr_space.table.utable.table().phys())
+            }
+        }
+    }
+
+    /// The bytes from `address` to the end of its page, through the mapping of physical memory
+    fn page(self, address: usize) -> Option<&'static mut [u8]> {
+        let virt = VirtualAddress::new(address);
+        let (phys, _) = if address >= USER_END_OFFSET {
+            let mapper = unsafe { PageMapper::<RmmA, _>::current(TableKind::Kernel, FRAME_ALLOCATOR) };
+            huge::translate(&mapper, virt)?
+        } else {
+            let mapper = unsafe { PageMapper::<RmmA, _>::new(TableKind::User, self.user_table()?, FRAME_ALLOCATOR) };
+            huge::translate(&mapper, virt)?
+        };
+        let mut ram = false;
+        crate::rmm::for_each_ram_range(|start, end| ram |= (start..end).contains(&phys.data()));
+        if !ram {
+            return None;
+        }
+        let offset = address % PAGE_SIZE;
+        let base = RmmA::phys_to_virt(phys).data() + offset;
+        Some(unsafe { core::slice::from_raw_parts_mut(base as *mut u8, PAGE_SIZE - offset) })
+    }
+
+    /// Read memory at `address` into `buf`, returning how much could be
+    fn read(self, mut address: usize, buf: &mut [u8]) -> usize {
+        let mut done = 0;
+        while done < buf.len() {
+            let Some(page) = self.page(address) else {
+                break;
+            };
+            let count = page.len().min(buf.len() - done);
+            buf[done..done + count].copy_from_slice(&page[..count]);
+            done += count;
+            address += count;
+        }
+        done
+    }
+
+    /// Write `data` to memory at `address`, returning false if not all of it could be
+    fn write(self, mut address: usize, mut data: &[u8]) -> bool {
+        while !data.is_empty() {
+            let Some(page) = self.page(address) else {
+                return false;
+            };
+            let count = page.len().min(data.len());
+            page[..count].copy_from_slice(&data[..count]);
+            data = &data[count..];
+            address += count;
+        }
+        true
+    }
+}
+
+/// Insert a software breakpoint at `address` in kernel code
+fn insert_breakpoint(cpu: usize, address: usize) -> bool {
+    let mut list = BREAKPOINT_LIST.lock();
+    if list.iter().flatten().any(|&(bp, _)| bp == address) {
+        return true;
+    }
+    let Some(slot) = list.iter_mut().find(|slot| slot.is_none()) else {
+        return false;
+    };
+    let thread = Thread::Cpu(cpu);
+    let mut original = [0];
+    if address < USER_END_OFFSET || thread.read(address, &mut original) != 1 || !thread.write(address, &[INT3]) {
+        return false;
+    }
+    *slot = Some((address, original[0]));
+    true
+}
+
+/// Remove the software breakpoint at `address`
+fn remove_breakpoint(cpu: usize, address: usize) -> bool {
+    let mut list = BREAKPOINT_LIST.lock();
+    let Some(slot) = list.iter_mut().find(|slot| slot.map_or(false, |(bp, _)| bp == address)) else {
+        return false;
+    };
+    if let Some((address, original)) = slot.take() {
+        Thread::Cpu(cpu).write(address, &[original]);
+    }
+    true
+}
+
+/// Remove every software breakpoint, as GDB detaches
+fn remove_breakpoints(cpu: usize) {
+    for (address, original) in BREAKPOINT_LIST.lock().iter_mut().filter_map(Option::take) {
+        Thread::Cpu(cpu).write(address, &[original]);
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn parse_hex(bytes: &[u8]) -> Option<usize> {
+    if bytes.is_empty() || bytes.len() > 2 * core::mem::size_of::<usize>() {
+        return None;
+    }
+    bytes.iter().try_fold(0, |value, &c| Some(value << 4 | usize::from(hex_digit(c)?)))
+}
+
+/// Parse `<address>,<length>` as in memory packets
+fn parse_range(bytes: &[u8]) -> Option<(usize, usize)> {
+    let comma = bytes.iter().position(|&c| c == b',')?;
+    Some((parse_hex(&bytes[..comma])?, parse_hex(&bytes[comma + 1..])?))
+}
+
+/// Parse hexadecimal digits into `buf`, which must be the size of the bytes they encode
+fn decode_hex(bytes: &[u8], buf: &mut [u8]) -> bool {
+    if bytes.len() != 2 * buf.len() {
+        return false;
+    }
+    for (byte, pair) in buf.iter_mut().zip(bytes.chunks(2)) {
+        match (hex_digit(pair[0]), hex_digit(pair[1])) {
+            (Some(high), Some(low)) => *byte = high << 4 | low,
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// The registers in the order of the `g` packet of x86_64, up to RIP, after which come EFLAGS and
+/// the segment registers, of 4 bytes each
+fn gdb_order(regs: &mut IntRegisters) -> [&mut usize; 17] {
+    [
+        &mut regs.rax, &mut regs.rbx, &mut regs.rcx, &mut regs.rdx, &mut regs.rsi, &mut regs.rdi,
+        &mut regs.rbp, &mut regs.rsp, &mut regs.r8, &mut regs.r9, &mut regs.r10, &mut regs.r11,
+        &mut regs.r12, &mut regs.r13, &mut regs.r14, &mut regs.r15, &mut regs.rip,
+    ]
+}
+
+/// A packet, without its framing
+struct Packet {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    const fn new() -> Packet {
+        Packet {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < self.data.len() {
+            self.data[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let _ = write!(self, "{:02x}", byte);
+        }
+    }
+}
+
+impl Write for Packet {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+/// COM2, used without its lock, which a stopped CPU may hold
+struct Port(SerialPort<Pio<u8>>);
+
+impl Port {
+    fn read(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.0.receive() {
+                return byte;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Receive a packet into `packet`, acknowledging it
+    fn receive(&mut self, packet: &mut Packet) {
+        loop {
+            // Acknowledgements and interrupts are meaningless while stopped
+            while self.read() != b'$' {}
+
+            packet.clear();
+            let mut sum = 0_u8;
+            let mut escaped = false;
+            loop {
+                let byte = self.read();
+                if byte == b'#' {
+                    break;
+                }
+                sum = sum.wrapping_add(byte);
+                if escaped {
+                    packet.push(byte ^ 0x20);
+                    escaped = false;
+                } else if byte == b'}' {
+                    escaped = true;
+                } else {
+                    packet.push(byte);
+                }
+            }
+            let (high, low) = (self.read(), self.read());
+            if hex_digit(high).zip(hex_digit(low)).map(|(high, low)| high << 4 | low) == Some(sum) {
+                self.0.send(b'+');
+                return;
+            }
+            self.0.send(b'-');
+        }
+    }
+
+    /// Send `data` as a packet, until acknowledged
+    fn send(&mut self, data: &[u8]) {
+        loop {
+            self.0.send(b'$');
+            let mut sum = 0_u8;
+            for &byte in data {
+                if matches!(byte, b'#' | b'$' | b'}' | b'*') {
+                    self.0.send(b'}');
+                    self.0.send(byte ^ 0x20);
+                    sum = sum.wrapping_add(b'}').wrapping_add(byte ^ 0x20);
+                } else {
+                    self.0.send(byte);
+                    sum = sum.wrapping_add(byte);
+                }
+            }
+            self.0.send(b'#');
+            for digit in [sum >> 4, sum & 0xF] {
+                self.0.send(b"0123456789abcdef"[usize::from(digit)]);
+            }
+            // GDB may also be sending a packet of its own, which it sends again
+            loop {
+                match self.read() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+/// What GDB asked for when resuming
+enum Resume {
+    Continue,
+    /// Single-step the CPU
+    Step(usize),
+}
+
+/// The conversation with GDB on the CPU running the stub
+struct Session {
+    cpu: usize,
+    port: Port,
+    /// Threads for registers and memory, and for resuming
+    general: Thread,
+    resume: Thread,
+    /// The last thread listed by `qfThreadInfo` and `qsThreadInfo`
+    listed: usize,
+}
+
+impl Session {
+    fn new(cpu: usize) -> Session {
+        Session {
+            cpu,
+            port: Port(SerialPort::<Pio<u8>>::new(PORT_BASE)),
+            general: Thread::Cpu(cpu),
+            resume: Thread::Cpu(cpu),
+            listed: 0,
+        }
+    }
+
+    /// Talk to GDB until it resumes, returning the CPU to single-step, if any
+    fn run(mut self, signal: u8) -> Option<usize> {
+        let mut packets = PACKETS.lock();
+        let (request, reply) = &mut *packets;
+
+        if ATTACHED.load(Ordering::SeqCst) {
+            reply.clear();
+            self.stop_reply(reply, signal);
+            self.port.send(reply.as_bytes());
+        }
+
+        loop {
+            self.port.receive(request);
+            ATTACHED.store(true, Ordering::SeqCst);
+            reply.clear();
+            let resume = self.handle(request.as_bytes(), reply, signal);
+            match resume {
+                Some(Resume::Continue) => return None,
+                Some(Resume::Step(cpu)) => return Some(cpu),
+                None => self.port.send(reply.as_bytes()),
+            }
+        }
+    }
+
+    fn stop_reply(&self, reply: &mut Packet, signal: u8) {
+        let _ = write!(reply, "T{:02x}thread:{:x};", signal, Thread::Cpu(self.cpu).tid());
+    }
+
+    /// The thread for `tid`, with 0 and -1 standing for the CPU running the stub
+    fn thread(&self, tid: &[u8]) -> Option<Thread> {
+        match tid {
+            b"0" | b"-1" => Some(Thread::Cpu(self.cpu)),
+            _ => Thread::from_tid(parse_hex(tid)?),
+        }
+    }
+
+    /// Handle `request`, writing the reply, unless it resumes
+    fn handle(&mut self, request: &[u8], reply: &mut Packet, signal: u8) -> Option<Resume> {
+        let Some((&command, args)) = request.split_first() else {
+            return None;
+        };
+        match command {
+            b'?' => self.stop_reply(reply, signal),
+            b'q' => self.query(args, reply),
+            b'H' => match args.split_first().map(|(&kind, tid)| (kind, self.thread(tid))) {
+                Some((b'g', Some(thread))) => {
+                    self.general = thread;
+                    let _ = reply.write_str("OK");
+                }
+                Some((b'c', Some(thread))) => {
+                    self.resume = thread;
+                    let _ = reply.write_str("OK");
+                }
+                _ => {
+                    let _ = reply.write_str("E01");
+                }
+            },
+            b'T' => {
+                let _ = reply.write_str(if self.thread(args).is_some() { "OK" } else { "E01" });
+            }
+            b'g' => match self.general.registers() {
+                Some(mut regs) => {
+                    let (rflags, cs, ss) = (regs.rflags, regs.cs, regs.ss);
+                    for reg in gdb_order(&mut regs) {
+                        reply.push_hex(&reg.to_le_bytes());
+                    }
+                    for reg in [rflags, cs, ss, 0, 0, 0, 0] {
+                        reply.push_hex(&(reg as u32).to_le_bytes());
+                    }
+                }
+                None => {
+                    let _ = reply.write_str("E01");
+                }
+            },
+            b'G' => {
+                let mut regs = self.general.registers().unwrap_or_default();
+                let mut ok = args.len() >= 17 * 16;
+                if ok {
+                    for (reg, hex) in gdb_order(&mut regs).into_iter().zip(args.chunks(16)) {
+                        let mut bytes = [0; 8];
+                        ok &= decode_hex(hex, &mut bytes);
+                        *reg = usize::from_le_bytes(bytes);
+                    }
+                }
+                let _ = reply.write_str(if ok && self.general.set_registers(&regs) { "OK" } else { "E01" });
+            }
+            b'm' => match parse_range(args) {
+                Some((address, len)) => {
+                    let mut buf = [0; PACKET_SIZE / 2 - 1];
+                    let len = len.min(buf.len());
+                    match self.general.read(address, &mut buf[..len]) {
+                        0 if len > 0 => {
+                            let _ = reply.write_str("E14");
+                        }
+                        count => reply.push_hex(&buf[..count]),
+                    }
+                }
+                None => {
+                    let _ = reply.write_str("E01");
+                }
+            },
+            b'M' => {
+                let colon = args.iter().position(|&c| c == b':').unwrap_or(args.len());
+                let mut buf = [0; PACKET_SIZE / 2];
+                let ok = match parse_range(&args[..colon]) {
+                    Some((address, len)) if len <= buf.len() && colon < args.len() => {
+                        decode_hex(&args[colon + 1..], &mut buf[..len]) && self.general.write(address, &buf[..len])
+                    }
+                    _ => false,
+                };
+                let _ = reply.write_str(if ok { "OK" } else { "E14" });
+            }
+            b'Z' | b'z' => {
+                // Only software breakpoints, GDB using its own watchpoints otherwise
+                let mut fields = args.split(|&c| c == b',');
+                if let (Some(b"0"), Some(address)) = (fields.next(), fields.next().and_then(parse_hex)) {
+                    let ok = if command == b'Z' {
+                        insert_breakpoint(self.cpu, address)
+                    } else {
+                        remove_breakpoint(self.cpu, address)
+                    };
+                    let _ = reply.write_str(if ok { "OK" } else { "E01" });
+                }
+            }
+            b'c' | b's' => {
+                if let Some(address) = parse_hex(args) {
+                    if let Some(mut regs) = Thread::Cpu(self.cpu).registers() {
+                        regs.rip = address;
+                        Thread::Cpu(self.cpu).set_registers(&regs);
+                    }
+                }
+                return Some(match (command, self.resume) {
+                    (b's', Thread::Cpu(cpu)) => Resume::Step(cpu),
+                    (b's', Thread::Context(_)) => Resume::Step(self.cpu),
+                    _ => Resume::Continue,
+                });
+            }
+            b'D' | b'k' => {
+                remove_breakpoints(self.cpu);
+                ATTACHED.store(false, Ordering::SeqCst);
+                if command == b'D' {
+                    self.port.send(b"OK");
+                }
+                return Some(Resume::Continue);
+            }
+            // Unsupported, as told by an empty reply
+            _ => (),
+        }
+        None
+    }
+
+    fn query(&mut self, args: &[u8], reply: &mut Packet) {
+        if args.starts_with(b"Supported") {
+            let _ = write!(reply, "PacketSize={:x}", PACKET_SIZE);
+        } else if args == b"Attached" {
+            let _ = reply.write_str("1");
+        } else if args == b"C" {
+            let _ = write!(reply, "QC{:x}", self.general.tid());
+        } else if args == b"fThreadInfo" {
+            self.listed = 0;
+            self.list_threads(reply);
+        } else if args == b"sThreadInfo" {
+            self.list_threads(reply);
+        } else if let Some(tid) = args.strip_prefix(b"ThreadExtraInfo,") {
+            let mut info = Packet::new();
+            match self.thread(tid) {
+                Some(Thread::Cpu(cpu)) => {
+                    let _ = write!(info, "CPU {}", cpu);
+                    for (id, context_lock) in context::contexts().iter() {
+                        if let Some(context) = context_lock.try_read().filter(|context| context.running && context.cpu_id == Some(cpu)) {
+                            let _ = write!(info, " running {} ({})", (*id).into(), context.name);
+                        }
+                    }
+                }
+                Some(Thread::Context(id)) => {
+                    if let Some(context) = context::contexts().get(id).and_then(|context_lock| context_lock.try_read()) {
+                        let _ = write!(info, "{} ({:?})", context.name, context.status);
+                    }
+                }
+                None => (),
+            }
+            // Hexadecimal, taking twice the room
+            info.len = info.len.min(PACKET_SIZE / 2);
+            reply.push_hex(info.as_bytes());
+        }
+    }
+
+    /// List the threads after the last listed, as many as fit
+    fn list_threads(&mut self, reply: &mut Packet) {
+        // Room for a thread ID and a comma
+        const ROOM: usize = 2 * core::mem::size_of::<usize>() + 1;
+
+        let _ = reply.write_str("m");
+        let mut listed = 0;
+        let mut list = |reply: &mut Packet, tid: usize| {
+            if tid <= self.listed || reply.len + ROOM > PACKET_SIZE {
+                return;
+            }
+            if listed > 0 {
+                reply.push(b',');
+            }
+            let _ = write!(reply, "{:x}", tid);
+            listed += 1;
+            self.listed = tid;
+        };
+
+        for cpu in 0..CPUS {
+            if Thread::Cpu(cpu).alive() {
+                list(reply, Thread::Cpu(cpu).tid());
+            }
+        }
+        for (&id, context_lock) in context::contexts().iter() {
+            if context_lock.try_read().map_or(false, |context| !context.running) {
+                list(reply, Thread::Context(id).tid());
+            }
+        }
+
+        if listed == 0 {
+            reply.clear();
+            let _ = reply.write_str("l");
+        }
+    }
+}
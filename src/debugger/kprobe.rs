@@ -0,0 +1,268 @@
+//! # Kprobes
+//! Probes planted at runtime on kernel code through `kprobe:` (see `scheme::kprobe`), given as a
+//! function of the kernel (see `symbols`) with an offset. A probe replaces the first byte of an
+//! instruction with `int3`. When a CPU hits it, the breakpoint handler counts the hit and records
+//! the `kprobe` and `kprobe_args` trace events with the argument registers, if they are enabled
+//! (see `trace`). The CPU then puts the original byte back, single-steps the instruction with
+//! interrupts disabled, and plants the probe again.
+//!
+//! One CPU steps over a probe at a time, and others hitting a probe meanwhile wait for it, so hits
+//! on a probe being stepped over by another CPU are missed. A probe hit by an NMI taken while its
+//! CPU steps over a probe is disarmed, as it cannot be stepped over in turn. The offset must be
+//! that of the start of an instruction, such as given by a disassembly, which is not checked.
+//!
+//! The probe and trace paths and the interrupt entry code cannot be probed, as their probes would
+//! be hit recursively. Kernel code is read-only, so probes are written through the mapping of
+//! physical memory.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use rmm::{PageMapper, TableKind};
+
+use crate::interrupt::{self, InterruptStack};
+use crate::memory::PAGE_SIZE;
+use crate::paging::{huge, RmmA, RmmArch, VirtualAddress};
+use crate::rmm::FRAME_ALLOCATOR;
+use crate::symbols::{self, Location};
+use crate::sync::Mutex;
+use crate::syscall::error::*;
+
+/// Number of probes
+const PROBES: usize = 64;
+
+const INT3: u8 = 0xCC;
+
+const NO_CPU: usize = usize::MAX;
+
+/// RFLAGS.IF
+const FLAG_INTERRUPTS: usize = 1 << 9;
+
+/// Prefixes of the demangled names of the functions that cannot be probed
+const NOPROBE: [&str; 6] = [
+    "kernel::debugger::",
+    "kernel::trace::",
+    "kernel::journal::",
+    "kernel::arch::x86_64::interrupt::",
+    "kernel::cpu_id",
+    "kernel::context::context_id",
+];
+
+struct Probe {
+    /// Address of the probed instruction, zero if the slot is free
+    address: AtomicUsize,
+    /// The same byte, through the mapping of physical memory
+    alias: AtomicUsize,
+    original: AtomicU8,
+    armed: AtomicBool,
+    hits: AtomicU64,
+}
+
+static PROBE_LIST: [Probe; PROBES] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const PROBE: Probe = Probe {
+        address: AtomicUsize::new(0),
+        alias: AtomicUsize::new(0),
+        original: AtomicU8::new(0),
+        armed: AtomicBool::new(false),
+        hits: AtomicU64::new(0),
+    };
+    [PROBE; PROBES]
+};
+
+/// The names of the probes, by address. Held while adding and removing probes.
+static NAMES: Mutex<BTreeMap<usize, String>> = Mutex::new(BTreeMap::new());
+
+/// The CPU stepping over a probe, or removing probes
+static OWNER: AtomicUsize = AtomicUsize::new(NO_CPU);
+/// The slot of the probe being stepped over
+static STEPPED: AtomicUsize = AtomicUsize::new(0);
+/// Whether the stepping CPU had interrupts enabled
+static INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+/// Wait for the current CPU to own the probes, and return false, or return true if it already
+/// does
+fn acquire(cpu: usize) -> bool {
+    loop {
+        match OWNER.compare_exchange(NO_CPU, cpu, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => return false,
+            Err(owner) if owner == cpu => return true,
+            Err(_) => spin_loop(),
+        }
+    }
+}
+
+fn release() {
+    OWNER.store(NO_CPU, Ordering::Release);
+}
+
+fn write(probe: &Probe, byte: u8) {
+    unsafe { (probe.alias.load(Ordering::Relaxed) as *mut u8).write_volatile(byte) };
+}
+
+/// Put the original byte back, for good
+fn disarm(probe: &Probe) {
+    if probe.armed.swap(false, Ordering::Relaxed) {
+        write(probe, probe.original.load(Ordering::Relaxed));
+    }
+}
+
+/// The slot of the probe at `address`
+fn find(address: usize) -> Option<usize> {
+    PROBE_LIST.iter().position(|probe| probe.address.load(Ordering::Acquire) == address)
+}
+
+/// The address of the probe given by `spec`, `<function>[+<offset>]` or `0x<address>`, and its
+/// name
+fn resolve(spec: &str) -> Result<(usize, String)> {
+    let parse = |number: &str| match number.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => number.parse::<usize>(),
+    };
+
+    let location = if spec.starts_with("0x") {
+        let address = parse(spec).or(Err(Error::new(EINVAL)))?;
+        symbols::lookup(address).ok_or(Error::new(ENOENT))?
+    } else {
+        let (name, offset) = match spec.rsplit_once('+') {
+            Some((name, offset)) => (name, parse(offset).or(Err(Error::new(EINVAL)))?),
+            None => (spec, 0),
+        };
+        Location {
+            symbol: symbols::find(name).ok_or(Error::new(ENOENT))?,
+            offset,
+        }
+    };
+    let function = location.symbol.to_string();
+    if location.offset >= location.symbol.size || NOPROBE.iter().any(|prefix| function.starts_with(prefix)) {
+        return Err(Error::new(EINVAL));
+    }
+    Ok((location.symbol.address + location.offset, location.to_string()))
+}
+
+/// Plant a probe at `spec`, a function of the kernel with an optional offset, or an address
+pub fn add(spec: &str) -> Result<()> {
+    let (address, name) = resolve(spec)?;
+    let mut names = NAMES.lock();
+    if names.contains_key(&address) {
+        return Err(Error::new(EEXIST));
+    }
+    let probe = PROBE_LIST.iter().find(|probe| probe.address.load(Ordering::Relaxed) == 0).ok_or(Error::new(ENOSPC))?;
+
+    let mapper = unsafe { PageMapper::<RmmA, _>::current(TableKind::Kernel, FRAME_ALLOCATOR) };
+    let (phys, _) = huge::translate(&mapper, VirtualAddress::new(address)).ok_or(Error::new(EFAULT))?;
+    let alias = RmmA::phys_to_virt(phys).data() + address % PAGE_SIZE;
+
+    probe.alias.store(alias, Ordering::Relaxed);
+    probe.original.store(unsafe { (alias as *const u8).read_volatile() }, Ordering::Relaxed);
+    probe.hits.store(0, Ordering::Relaxed);
+    probe.armed.store(true, Ordering::Relaxed);
+    probe.address.store(address, Ordering::Release);
+    write(probe, INT3);
+    names.insert(address, name);
+    Ok(())
+}
+
+/// Remove the probes at the addresses in `addresses`, once no CPU steps over them
+fn remove_addresses(addresses: &[usize]) {
+    unsafe { interrupt::disable() };
+    acquire(crate::cpu_id());
+    for probe in PROBE_LIST.iter().filter(|probe| addresses.contains(&probe.address.load(Ordering::Relaxed))) {
+        disarm(probe);
+        probe.address.store(0, Ordering::Release);
+    }
+    release();
+    unsafe { interrupt::enable() };
+}
+
+/// Remove the probe planted at `spec`
+pub fn remove(spec: &str) -> Result<()> {
+    let (address, _) = resolve(spec)?;
+    let mut names = NAMES.lock();
+    names.remove(&address).ok_or(Error::new(ENOENT))?;
+    remove_addresses(&[address]);
+    Ok(())
+}
+
+/// Remove every probe
+pub fn clear() {
+    let mut names = NAMES.lock();
+    let addresses: Vec<usize> = names.keys().copied().collect();
+    remove_addresses(&addresses);
+    names.clear();
+}
+
+/// The probes, one per line, with their address and hits
+pub fn list() -> Vec<u8> {
+    let names = NAMES.lock();
+    let mut string = String::new();
+    for (&address, name) in names.iter() {
+        let Some(probe) = find(address).map(|slot| &PROBE_LIST[slot]) else {
+            continue;
+        };
+        let _ = writeln!(string, "{:#x} {} {}{}",
+            address,
+            probe.hits.load(Ordering::Relaxed),
+            name,
+            if probe.armed.load(Ordering::Relaxed) { "" } else { " disarmed" });
+    }
+    string.into_bytes()
+}
+
+/// Handle a breakpoint exception taken with `stack`, whose RIP was moved back to the `int3`.
+/// Returns false if it is not for a probe.
+pub fn breakpoint_trap(stack: &mut InterruptStack) -> bool {
+    if stack.iret.cs & 0b11 != 0 {
+        return false;
+    }
+    let rip = stack.iret.rip;
+    let Some(slot) = find(rip) else {
+        // A probe removed since it was hit is taken again as the original instruction
+        return unsafe { (rip as *const u8).read_volatile() } != INT3;
+    };
+    let probe = &PROBE_LIST[slot];
+
+    probe.hits.fetch_add(1, Ordering::Relaxed);
+    crate::tracepoint!(Kprobe, rip, stack.scratch.rdi, stack.scratch.rsi);
+    crate::tracepoint!(KprobeArgs, stack.scratch.rdx, stack.scratch.rcx, stack.scratch.r8);
+
+    if acquire(crate::cpu_id()) {
+        // An NMI taken while stepping over another probe, or removing probes
+        disarm(probe);
+        return true;
+    }
+    // Removed while waiting, the original byte is back
+    if probe.address.load(Ordering::Relaxed) != rip || !probe.armed.load(Ordering::Relaxed) {
+        release();
+        return true;
+    }
+
+    write(probe, probe.original.load(Ordering::Relaxed));
+    STEPPED.store(slot, Ordering::Relaxed);
+    INTERRUPTS.store(stack.iret.rflags & FLAG_INTERRUPTS != 0, Ordering::Relaxed);
+    stack.iret.rflags &= !FLAG_INTERRUPTS;
+    stack.set_singlestep(true);
+    true
+}
+
+/// Handle a debug exception taken with `stack`, after stepping over a probe. Returns false if it
+/// is not for a probe.
+pub fn debug_trap(stack: &mut InterruptStack) -> bool {
+    if OWNER.load(Ordering::Relaxed) != crate::cpu_id() || !stack.is_singlestep() {
+        return false;
+    }
+    let probe = &PROBE_LIST[STEPPED.load(Ordering::Relaxed)];
+    if probe.armed.load(Ordering::Relaxed) {
+        write(probe, INT3);
+    }
+    stack.set_singlestep(false);
+    if INTERRUPTS.load(Ordering::Relaxed) {
+        stack.iret.rflags |= FLAG_INTERRUPTS;
+    }
+    release();
+    true
+}
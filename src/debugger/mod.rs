@@ -1,9 +1,17 @@
 use crate::paging::{RmmA, RmmArch, TableKind};
 
+/// GDB remote serial protocol stub
+#[cfg(target_arch = "x86_64")]
+pub mod gdbstub;
+
+/// Probes on kernel code, logging to the trace
+#[cfg(target_arch = "x86_64")]
+pub mod kprobe;
+
 //TODO: combine arches into one function (aarch64 one is newest)
 
 // Super unsafe due to page table switching and raw pointers!
-#[cfg(target_arch = "aarch64")]
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
     println!("DEBUGGER START");
     println!();
@@ -47,7 +55,10 @@ pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
                 println!("regs:");
                 regs.dump();
 
+                #[cfg(target_arch = "aarch64")]
                 let mut sp = regs.iret.sp_el0;
+                #[cfg(target_arch = "riscv64")]
+                let mut sp = regs.preserved.sp;
                 println!("stack: {:>016x}", sp);
                 //Maximum 64 usizes
                 for _ in 0..64 {
@@ -74,6 +85,9 @@ pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
         println!();
     }
 
+    crate::journal::dump();
+    crate::dump::write(format_args!("debugger"));
+
     println!("DEBUGGER END");
 }
 
@@ -147,13 +161,16 @@ pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
         println!();
     }
 
+    crate::journal::dump();
+    crate::dump::write(format_args!("debugger"));
+
     println!("DEBUGGER END");
 }
 
 // Super unsafe due to page table switching and raw pointers!
 #[cfg(target_arch = "x86_64")]
 pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
-    unsafe { x86::bits64::rflags::stac(); }
+    unsafe { crate::arch::x86_64::misc::stac(); }
 
     println!("DEBUGGER START");
     println!();
@@ -222,8 +239,11 @@ pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
         println!();
     }
 
+    crate::journal::dump();
+    crate::dump::write(format_args!("debugger"));
+
     println!("DEBUGGER END");
-    unsafe { x86::bits64::rflags::clac(); }
+    unsafe { crate::arch::x86_64::misc::clac(); }
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -1,9 +1,10 @@
 use core::str;
-use spin::Mutex;
 
 pub use self::debug::DebugDisplay;
 use self::display::Display;
 
+use crate::sync::Mutex;
+
 pub mod debug;
 pub mod display;
 
@@ -63,6 +64,7 @@ pub fn init(env: &[u8]) {
         let debug_display = DebugDisplay::new(display);
         *DEBUG_DISPLAY.lock() = Some(debug_display);
     }
+    crate::log::set_sinks_ready(crate::log::Sinks::DISPLAY, true);
 }
 
 pub fn init_heap() {
@@ -75,6 +77,7 @@ pub fn init_heap() {
 
 pub fn fini() {
     DEBUG_DISPLAY.lock().take();
+    crate::log::set_sinks_ready(crate::log::Sinks::DISPLAY, false);
 
     println!("Finished graphical debug");
 }
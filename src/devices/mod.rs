@@ -1,3 +1,4 @@
 #[cfg(feature = "graphical_debug")]
 pub mod graphical_debug;
 pub mod uart_16550;
+pub mod virtio;
@@ -0,0 +1,37 @@
+//! Virtio console, written to as a log sink through the transmit queue of its first port
+
+use crate::log::{self, Sinks};
+use crate::sync::Mutex;
+
+use super::{Device, Transport};
+
+/// The transmit queue of port 0
+const TRANSMITQ: u16 = 1;
+
+pub static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+
+pub struct Console {
+    device: Device,
+}
+
+impl Console {
+    pub fn write(&mut self, buf: &[u8]) {
+        for chunk in buf.chunks(crate::memory::PAGE_SIZE) {
+            if !self.device.send(chunk) {
+                // Stop writing to a device that stopped responding, rather than waiting each time
+                log::set_sinks_ready(Sinks::VIRTIO, false);
+                return;
+            }
+        }
+    }
+}
+
+/// Use the console at `transport` as a log sink. Returns false if it could not be set up.
+pub(super) unsafe fn init(transport: Transport) -> bool {
+    let Some(device) = Device::new(transport, TRANSMITQ) else {
+        return false;
+    };
+    *CONSOLE.lock() = Some(Console { device });
+    log::set_sinks_ready(Sinks::VIRTIO, true);
+    true
+}
@@ -0,0 +1,430 @@
+//! # Virtio
+//! Minimal drivers for the virtio devices the kernel uses itself before userspace drivers start:
+//! the console, as a log sink, and the entropy device, seeding the entropy pool. Devices are
+//! found on PCI, through their legacy I/O port interface, on x86, and as MMIO devices listed in
+//! the device tree elsewhere.
+//!
+//! Each device is driven through a single virtqueue with one request in flight, copied through a
+//! bounce page, the kernel waiting for the device to use it. The devices are reset once userspace
+//! drivers take them over, which then find them as usual.
+
+use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use crate::memory::{allocate_frames, Frame, PAGE_SIZE};
+use crate::paging::{Page, PageFlags, PhysicalAddress, RmmA, RmmArch, VirtualAddress};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::syscall::io::{Io, Pio};
+
+pub mod console;
+pub mod rng;
+
+const DEVICE_CONSOLE: u32 = 3;
+const DEVICE_RNG: u32 = 4;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+
+/// Required of non-legacy devices, in the second word of features
+const FEATURE_VERSION_1: u32 = 1 << 0;
+
+// Descriptor flags
+const DESC_F_WRITE: u16 = 2;
+
+/// Largest queue used, where the device lets the driver choose
+const QUEUE_SIZE: u16 = 16;
+
+/// Iterations waiting for the device to use a request, as the timer may not run yet
+const TIMEOUT: usize = 10_000_000;
+
+/// Most MMIO devices taken from the device tree
+const MAX_MMIO: usize = 16;
+
+// MMIO registers
+const MMIO_MAGIC: usize = 0x000;
+const MMIO_VERSION: usize = 0x004;
+const MMIO_DEVICE_ID: usize = 0x008;
+const MMIO_DEVICE_FEATURES: usize = 0x010;
+const MMIO_DEVICE_FEATURES_SEL: usize = 0x014;
+const MMIO_DRIVER_FEATURES: usize = 0x020;
+const MMIO_DRIVER_FEATURES_SEL: usize = 0x024;
+const MMIO_GUEST_PAGE_SIZE: usize = 0x028;
+const MMIO_QUEUE_SEL: usize = 0x030;
+const MMIO_QUEUE_NUM_MAX: usize = 0x034;
+const MMIO_QUEUE_NUM: usize = 0x038;
+const MMIO_QUEUE_ALIGN: usize = 0x03c;
+const MMIO_QUEUE_PFN: usize = 0x040;
+const MMIO_QUEUE_READY: usize = 0x044;
+const MMIO_QUEUE_NOTIFY: usize = 0x050;
+const MMIO_STATUS: usize = 0x070;
+const MMIO_QUEUE_DESC: usize = 0x080;
+const MMIO_QUEUE_DRIVER: usize = 0x090;
+const MMIO_QUEUE_DEVICE: usize = 0x0a0;
+
+/// "virt", little-endian
+const MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
+
+/// Configuration space access, and the legacy registers in the I/O BAR
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod pci {
+    use alloc::vec::Vec;
+
+    use crate::syscall::io::{Io, Pio};
+
+    pub const VENDOR: u16 = 0x1AF4;
+    /// Transitional devices, with the legacy interface, have the device ID `0x1000 + ID - 1`
+    pub const TRANSITIONAL_DEVICES: core::ops::RangeInclusive<u16> = 0x1000..=0x103F;
+
+    pub const DEVICE_FEATURES: u16 = 0x00;
+    pub const GUEST_FEATURES: u16 = 0x04;
+    pub const QUEUE_ADDRESS: u16 = 0x08;
+    pub const QUEUE_SIZE: u16 = 0x0C;
+    pub const QUEUE_SELECT: u16 = 0x0E;
+    pub const QUEUE_NOTIFY: u16 = 0x10;
+    pub const DEVICE_STATUS: u16 = 0x12;
+
+    const CONFIG_ADDRESS: u16 = 0xCF8;
+    const CONFIG_DATA: u16 = 0xCFC;
+
+    /// In the header type byte of the configuration space
+    const HEADER_MULTI_FUNCTION: u32 = 1 << 23;
+
+    const COMMAND_IO: u32 = 1 << 0;
+    const COMMAND_BUS_MASTER: u32 = 1 << 2;
+
+    pub fn read(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+        let address = 1 << 31 | u32::from(bus) << 16 | u32::from(device) << 11 | u32::from(function) << 8 | u32::from(offset & 0xFC);
+        Pio::<u32>::new(CONFIG_ADDRESS).write(address);
+        Pio::<u32>::new(CONFIG_DATA).read()
+    }
+
+    pub fn write(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+        let address = 1 << 31 | u32::from(bus) << 16 | u32::from(device) << 11 | u32::from(function) << 8 | u32::from(offset & 0xFC);
+        Pio::<u32>::new(CONFIG_ADDRESS).write(address);
+        Pio::<u32>::new(CONFIG_DATA).write(value);
+    }
+
+    /// The virtio device ID and the I/O port base of each transitional virtio device
+    pub fn devices() -> Vec<(u32, u16)> {
+        let mut devices = Vec::new();
+        for bus in 0..=255 {
+            for device in 0..32 {
+                // Only multi-function devices have functions other than 0
+                let functions = if read(bus, device, 0, 0x0C) & HEADER_MULTI_FUNCTION != 0 { 8 } else { 1 };
+                for function in 0..functions {
+                    let id = read(bus, device, function, 0x00);
+                    if id & 0xFFFF != u32::from(VENDOR) || !TRANSITIONAL_DEVICES.contains(&((id >> 16) as u16)) {
+                        continue;
+                    }
+                    let bar0 = read(bus, device, function, 0x10);
+                    if bar0 & 1 == 0 {
+                        continue;
+                    }
+                    // Decoding of the I/O BAR, and DMA by the device, leaving the status as is
+                    let command = read(bus, device, function, 0x04) & 0xFFFF;
+                    write(bus, device, function, 0x04, command | COMMAND_IO | COMMAND_BUS_MASTER);
+                    // The subsystem ID is the virtio device ID
+                    let subsystem = read(bus, device, function, 0x2C) >> 16;
+                    devices.push((subsystem, (bar0 & 0xFFFC) as u16));
+                }
+            }
+        }
+        devices
+    }
+}
+
+/// Physical address and size of the MMIO devices in the device tree
+static MMIO: [(AtomicUsize, AtomicUsize); MAX_MMIO] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: (AtomicUsize, AtomicUsize) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    [NONE; MAX_MMIO]
+};
+
+/// Add an MMIO device at physical `address`, from a `virtio,mmio` node of the device tree
+pub fn add_mmio(address: usize, size: usize) {
+    for (slot, slot_size) in MMIO.iter() {
+        if slot.compare_exchange(0, address, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            slot_size.store(size, Ordering::SeqCst);
+            return;
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Transport {
+    /// Legacy interface at an I/O port base
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Pci(u16),
+    /// Registers at a virtual address, and their version: 1 for legacy
+    Mmio(usize, u32),
+}
+
+impl Transport {
+    unsafe fn mmio_read(base: usize, offset: usize) -> u32 {
+        ptr::read_volatile((base + offset) as *const u32)
+    }
+
+    unsafe fn mmio_write(base: usize, offset: usize, value: u32) {
+        ptr::write_volatile((base + offset) as *mut u32, value);
+    }
+
+    unsafe fn set_status(self, status: u8) {
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Transport::Pci(port) => {
+                Pio::<u8>::new(port + pci::DEVICE_STATUS).write(status);
+            }
+            Transport::Mmio(base, _) => Self::mmio_write(base, MMIO_STATUS, u32::from(status)),
+        }
+    }
+
+    unsafe fn status(self) -> u8 {
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Transport::Pci(port) => {
+                Pio::<u8>::new(port + pci::DEVICE_STATUS).read()
+            }
+            Transport::Mmio(base, _) => Self::mmio_read(base, MMIO_STATUS) as u8,
+        }
+    }
+
+    /// Accept no optional feature, other than those required of non-legacy devices. Returns false
+    /// if the device refused.
+    unsafe fn negotiate(self) -> bool {
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Transport::Pci(port) => {
+                let _ = Pio::<u32>::new(port + pci::DEVICE_FEATURES).read();
+                Pio::<u32>::new(port + pci::GUEST_FEATURES).write(0);
+                true
+            }
+            Transport::Mmio(base, 1) => {
+                Self::mmio_write(base, MMIO_DRIVER_FEATURES_SEL, 0);
+                Self::mmio_write(base, MMIO_DRIVER_FEATURES, 0);
+                Self::mmio_write(base, MMIO_GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+                true
+            }
+            Transport::Mmio(base, _) => {
+                Self::mmio_write(base, MMIO_DEVICE_FEATURES_SEL, 1);
+                let version_1 = Self::mmio_read(base, MMIO_DEVICE_FEATURES) & FEATURE_VERSION_1;
+                for (sel, features) in [(0, 0), (1, version_1)] {
+                    Self::mmio_write(base, MMIO_DRIVER_FEATURES_SEL, sel);
+                    Self::mmio_write(base, MMIO_DRIVER_FEATURES, features);
+                }
+                self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+                self.status() & STATUS_FEATURES_OK != 0
+            }
+        }
+    }
+
+    /// Set up the queue at `index`, returning it
+    unsafe fn setup_queue(self, index: u16) -> Option<Virtqueue> {
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Transport::Pci(port) => {
+                Pio::<u16>::new(port + pci::QUEUE_SELECT).write(index);
+                // The legacy interface has a queue size of its own choosing
+                let size = Pio::<u16>::new(port + pci::QUEUE_SIZE).read();
+                let queue = Virtqueue::new(size)?;
+                Pio::<u32>::new(port + pci::QUEUE_ADDRESS).write((queue.phys / PAGE_SIZE) as u32);
+                Some(queue)
+            }
+            Transport::Mmio(base, version) => {
+                Self::mmio_write(base, MMIO_QUEUE_SEL, u32::from(index));
+                let max = Self::mmio_read(base, MMIO_QUEUE_NUM_MAX);
+                if max == 0 {
+                    return None;
+                }
+                let queue = Virtqueue::new(QUEUE_SIZE.min(max as u16))?;
+                Self::mmio_write(base, MMIO_QUEUE_NUM, u32::from(queue.size));
+                if version == 1 {
+                    Self::mmio_write(base, MMIO_QUEUE_ALIGN, PAGE_SIZE as u32);
+                    Self::mmio_write(base, MMIO_QUEUE_PFN, (queue.phys / PAGE_SIZE) as u32);
+                } else {
+                    for (offset, address) in [(MMIO_QUEUE_DESC, queue.phys), (MMIO_QUEUE_DRIVER, queue.phys + queue.avail), (MMIO_QUEUE_DEVICE, queue.phys + queue.used)] {
+                        Self::mmio_write(base, offset, address as u32);
+                        Self::mmio_write(base, offset + 4, (address as u64 >> 32) as u32);
+                    }
+                    Self::mmio_write(base, MMIO_QUEUE_READY, 1);
+                }
+                Some(queue)
+            }
+        }
+    }
+
+    unsafe fn notify(self, index: u16) {
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Transport::Pci(port) => {
+                Pio::<u16>::new(port + pci::QUEUE_NOTIFY).write(index);
+            }
+            Transport::Mmio(base, _) => Self::mmio_write(base, MMIO_QUEUE_NOTIFY, u32::from(index)),
+        }
+    }
+}
+
+/// A split virtqueue, in the legacy layout: the descriptors, the available ring, and the used
+/// ring on the next page boundary
+struct Virtqueue {
+    size: u16,
+    phys: usize,
+    virt: usize,
+    /// Offsets of the available and used rings
+    avail: usize,
+    used: usize,
+    /// Next index of the available ring, and the index of the used ring last seen
+    next_avail: u16,
+    last_used: u16,
+}
+
+impl Virtqueue {
+    fn new(size: u16) -> Option<Self> {
+        if size == 0 {
+            return None;
+        }
+        let avail = 16 * usize::from(size);
+        let used = (avail + 6 + 2 * usize::from(size)).next_multiple_of(PAGE_SIZE);
+        let pages = (used + 6 + 8 * usize::from(size)).div_ceil(PAGE_SIZE);
+
+        let frame = allocate_frames(pages)?;
+        let phys = frame.start_address().data();
+        let virt = unsafe { RmmA::phys_to_virt(frame.start_address()).data() };
+        unsafe { ptr::write_bytes(virt as *mut u8, 0, pages * PAGE_SIZE) };
+        Some(Self { size, phys, virt, avail, used, next_avail: 0, last_used: 0 })
+    }
+
+    /// Make the single descriptor of `len` bytes at `buffer` available, and wait for the device to
+    /// use it. Returns the number of bytes it wrote, or `None` if it timed out.
+    unsafe fn transfer(&mut self, transport: Transport, index: u16, buffer: usize, len: usize, writable: bool) -> Option<usize> {
+        // Descriptor 0: address, length, flags and next
+        let desc = self.virt as *mut u8;
+        ptr::write_volatile(desc as *mut u64, buffer as u64);
+        ptr::write_volatile(desc.add(8) as *mut u32, len as u32);
+        ptr::write_volatile(desc.add(12) as *mut u16, if writable { DESC_F_WRITE } else { 0 });
+        ptr::write_volatile(desc.add(14) as *mut u16, 0);
+
+        // The ring entry, then its index
+        let avail = (self.virt + self.avail) as *mut u16;
+        ptr::write_volatile(avail.add(2 + usize::from(self.next_avail % self.size)), 0);
+        fence(Ordering::SeqCst);
+        self.next_avail = self.next_avail.wrapping_add(1);
+        ptr::write_volatile(avail.add(1), self.next_avail);
+        fence(Ordering::SeqCst);
+
+        transport.notify(index);
+
+        let used = (self.virt + self.used) as *const u16;
+        for _ in 0..TIMEOUT {
+            if ptr::read_volatile(used.add(1)) != self.last_used {
+                fence(Ordering::SeqCst);
+                let element = (self.virt + self.used + 4 + 8 * usize::from(self.last_used % self.size)) as *const u32;
+                self.last_used = self.last_used.wrapping_add(1);
+                return Some(ptr::read_volatile(element.add(1)) as usize);
+            }
+            core::hint::spin_loop();
+        }
+        None
+    }
+}
+
+/// A device driven through one of its queues
+pub struct Device {
+    transport: Transport,
+    index: u16,
+    queue: Virtqueue,
+    /// Bounce page the requests are copied through
+    buffer_phys: usize,
+    buffer: &'static mut [u8],
+}
+
+impl Device {
+    /// Reset the device, and set up its queue at `index`
+    unsafe fn new(transport: Transport, index: u16) -> Option<Self> {
+        transport.set_status(0);
+        transport.set_status(STATUS_ACKNOWLEDGE);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        if !transport.negotiate() {
+            return None;
+        }
+        let queue = transport.setup_queue(index)?;
+        let frame = allocate_frames(1)?;
+        let buffer = core::slice::from_raw_parts_mut(RmmA::phys_to_virt(frame.start_address()).data() as *mut u8, PAGE_SIZE);
+
+        let features_ok = transport.status() & STATUS_FEATURES_OK;
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | features_ok | STATUS_DRIVER_OK);
+        Some(Self {
+            transport,
+            index,
+            queue,
+            buffer_phys: frame.start_address().data(),
+            buffer,
+        })
+    }
+
+    /// Send `data`, at most a page. Returns false if the device did not take it.
+    fn send(&mut self, data: &[u8]) -> bool {
+        let len = data.len().min(PAGE_SIZE);
+        self.buffer[..len].copy_from_slice(&data[..len]);
+        unsafe { self.queue.transfer(self.transport, self.index, self.buffer_phys, len, false) }.is_some()
+    }
+
+    /// Receive up to `len` bytes, at most a page
+    fn receive(&mut self, len: usize) -> Option<&[u8]> {
+        let len = len.min(PAGE_SIZE);
+        let received = unsafe { self.queue.transfer(self.transport, self.index, self.buffer_phys, len, true) }?;
+        Some(&self.buffer[..received.min(len)])
+    }
+}
+
+/// Map the registers of an MMIO device, returning their virtual address
+unsafe fn map_mmio(address: usize, size: usize) -> Option<usize> {
+    let mut mapper = crate::paging::KernelMapper::lock();
+    let mapper = mapper.get_mut()?;
+    let start = Frame::containing_address(PhysicalAddress::new(address));
+    let end = Frame::containing_address(PhysicalAddress::new(address + size.max(1) - 1));
+    for frame in Frame::range_inclusive(start, end) {
+        let page = Page::containing_address(VirtualAddress::new(frame.start_address().data() + crate::PHYS_OFFSET));
+        // Fails if already mapped, as when devices share a page
+        if let Some(flush) = mapper.map_phys(page.start_address(), frame.start_address(), PageFlags::new().write(true)) {
+            flush.flush();
+        }
+    }
+    Some(address + crate::PHYS_OFFSET)
+}
+
+/// Each device found, and its virtio device ID
+unsafe fn devices() -> impl Iterator<Item = (u32, Transport)> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let pci = pci::devices().into_iter().map(|(id, port)| (id, Transport::Pci(port)));
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let pci = core::iter::empty();
+
+    let mmio = MMIO.iter().filter_map(|(address, size)| {
+        let address = address.load(Ordering::SeqCst);
+        if address == 0 {
+            return None;
+        }
+        let base = map_mmio(address, size.load(Ordering::SeqCst))?;
+        if Transport::mmio_read(base, MMIO_MAGIC) != MMIO_MAGIC_VALUE {
+            return None;
+        }
+        let version = Transport::mmio_read(base, MMIO_VERSION);
+        Some((Transport::mmio_read(base, MMIO_DEVICE_ID), Transport::Mmio(base, version)))
+    });
+    pci.chain(mmio)
+}
+
+/// Find the console, if it is a log sink, and the entropy device, using the first of each
+pub unsafe fn init() {
+    let (mut console, mut rng) = (false, false);
+    for (id, transport) in devices() {
+        match id {
+            DEVICE_CONSOLE if cfg!(feature = "virtio_debug") && !console => console = console::init(transport),
+            DEVICE_RNG if !rng => rng = rng::init(transport),
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,28 @@
+//! Virtio entropy device, read once to seed the entropy pool
+
+use crate::entropy;
+
+use super::{Device, Transport};
+
+/// The request queue
+const REQUESTQ: u16 = 0;
+
+/// Bytes read from the device
+const SEED_SIZE: usize = 64;
+
+/// Seed the entropy pool from the device at `transport`. Returns false if it could not be read.
+pub(super) unsafe fn init(transport: Transport) -> bool {
+    let Some(mut device) = Device::new(transport, REQUESTQ) else {
+        return false;
+    };
+    let Some(seed) = device.receive(SEED_SIZE) else {
+        return false;
+    };
+    for chunk in seed.chunks(8) {
+        let mut bytes = [0; 8];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        entropy::add(u64::from_ne_bytes(bytes));
+    }
+    crate::log::info!("virtio-rng: seeded the entropy pool with {} bytes", seed.len());
+    !seed.is_empty()
+}
@@ -0,0 +1,358 @@
+//! # Kernel dumps
+//! ELF cores of the kernel, written when panicking, for post-mortem analysis with standard tooling
+//! such as `gdb` and the kernel executable.
+//!
+//! Root arms dumps through `dump:ctl` (see `scheme::dump`), which sets aside a region of memory
+//! for them, so that panicking does not need to allocate. There are two kinds:
+//!
+//! - A minidump copies the essential memory into the region: the log ring, then each context and
+//!   its kernel stack, leaving out those that do not fit.
+//! - A full dump, on x86_64, only writes the headers into the region, with a segment for each range
+//!   of memory the kernel may use, at its address in the linear mapping, and one for the kernel at
+//!   `KERNEL_OFFSET`. The contents of the segments follow the headers in the core, from the first
+//!   page boundary after them, in the order of the segments.
+//!
+//! Both have notes named `REDOX`: `NOTE_REASON` with the panic message, and `NOTE_CONTEXT` for
+//! each context, as text. Contexts locked while panicking are listed without being copied. The
+//! other CPUs still run while the dump is written, so it may not be consistent.
+//!
+//! A dump written when panicking is read from the crash kernel (see `kexec`), which is told where
+//! the region is as `CRASH_DUMP=<base>:<size>` in its environment, in hexadecimal, and finds the
+//! memory of the crashed kernel reserved. The debugger writes one too, which can be read back
+//! through `dump:core`.
+
+use alloc::sync::Arc;
+use core::fmt::{self, Write};
+use core::mem;
+use core::slice;
+
+use crate::context;
+use crate::elf::{program_header, CoreWriter};
+use crate::log::{info, Line, KMSG};
+use crate::memory::{allocate_frames, deallocate_frames, Frame, PAGE_SIZE};
+use crate::paging::{PhysicalAddress, RmmA, RmmArch};
+use crate::sync::Mutex;
+use crate::syscall::error::*;
+use crate::syscall::usercopy::UserSliceWo;
+
+/// Name of the notes of a dump
+pub const NOTE_NAME: &str = "REDOX";
+/// The reason the dump was written, such as the panic message
+pub const NOTE_REASON: u32 = 1;
+/// A context, as `<id> <name> <status> kstack=<base>:<size>`, in hexadecimal
+pub const NOTE_CONTEXT: u32 = 2;
+
+/// Size of the region of a minidump, unless chosen when arming
+pub const MINI_DEFAULT: usize = 16 * 1024 * 1024;
+/// Size of the region of a full dump, unless chosen when arming
+pub const FULL_DEFAULT: usize = 256 * 1024;
+
+/// Program headers the region of a full dump has room for
+const FULL_PHNUM: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    Mini,
+    Full,
+}
+
+struct Region {
+    frame: Frame,
+    size: usize,
+    kind: Kind,
+}
+
+impl Region {
+    fn base(&self) -> usize {
+        self.frame.start_address().data()
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(RmmA::phys_to_virt(self.frame.start_address()).data() as *const u8, self.size) }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(RmmA::phys_to_virt(self.frame.start_address()).data() as *mut u8, self.size) }
+    }
+}
+
+/// A dump written to the region
+#[derive(Clone, Copy, Debug)]
+pub struct Written {
+    /// Size of the part of the core in the region
+    pub headers: usize,
+    /// Size of the core
+    pub size: usize,
+}
+
+struct State {
+    region: Option<Region>,
+    written: Option<Written>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    region: None,
+    written: None,
+});
+
+/// Set aside `size` bytes, rounded up to pages, to write dumps of `kind` to, replacing the region
+/// armed before
+pub fn arm(kind: Kind, size: usize) -> Result<()> {
+    if kind == Kind::Full && cfg!(not(target_arch = "x86_64")) {
+        return Err(Error::new(EOPNOTSUPP));
+    }
+    #[cfg(target_arch = "x86_64")]
+    if crate::kexec::loaded().map_or(false, |(_, crash)| crash) {
+        // Its environment points at the region
+        return Err(Error::new(EBUSY));
+    }
+    let size = size.next_multiple_of(PAGE_SIZE);
+    if size == 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let frame = allocate_frames(size / PAGE_SIZE).ok_or(Error::new(ENOMEM))?;
+    info!("Dump region: {:X}:{:X}", frame.start_address().data(), frame.start_address().data() + size);
+
+    let mut state = STATE.lock();
+    if let Some(old) = state.region.replace(Region { frame, size, kind }) {
+        deallocate_frames(old.frame, old.size / PAGE_SIZE);
+    }
+    state.written = None;
+    Ok(())
+}
+
+/// Free the region
+pub fn disarm() -> Result<()> {
+    #[cfg(target_arch = "x86_64")]
+    if crate::kexec::loaded().map_or(false, |(_, crash)| crash) {
+        return Err(Error::new(EBUSY));
+    }
+    let mut state = STATE.lock();
+    let region = state.region.take().ok_or(Error::new(ENOENT))?;
+    deallocate_frames(region.frame, region.size / PAGE_SIZE);
+    state.written = None;
+    Ok(())
+}
+
+/// The kind of dumps armed, and the physical base and size of their region
+pub fn armed() -> Option<(Kind, usize, usize)> {
+    STATE.lock().region.as_ref().map(|region| (region.kind, region.base(), region.size))
+}
+
+/// The dump written last, if any
+pub fn written() -> Option<Written> {
+    STATE.lock().written
+}
+
+/// The line telling the crash kernel where the region is, if armed
+pub fn crash_env() -> Option<Line> {
+    let state = STATE.lock();
+    let region = state.region.as_ref()?;
+    let mut line = Line::new();
+    let _ = writeln!(line, "CRASH_DUMP={:X}:{:X}", region.base(), region.size);
+    Some(line)
+}
+
+fn segment(vaddr: usize, paddr: usize, offset: usize, size: usize, align: usize) -> program_header::ProgramHeader {
+    program_header::ProgramHeader {
+        p_type: program_header::PT_LOAD,
+        p_flags: program_header::PF_R | program_header::PF_W,
+        p_offset: offset as _,
+        p_vaddr: vaddr as _,
+        p_paddr: paddr as _,
+        p_filesz: size as _,
+        p_memsz: size as _,
+        p_align: align as _,
+    }
+}
+
+/// Append the notes, returning their offset and size
+fn notes(writer: &mut CoreWriter, reason: fmt::Arguments) -> (usize, usize) {
+    let start = writer.len();
+    let mut line = Line::new();
+    let _ = line.write_fmt(reason);
+    writer.note(NOTE_NAME, NOTE_REASON, line.as_bytes());
+
+    // The contexts may be locked by this CPU
+    for (id, context_lock) in context::contexts().iter() {
+        let mut line = Line::new();
+        match context_lock.try_read() {
+            Some(context) => {
+                let (base, size) = context.kstack.as_ref().map_or((0, 0), |stack| (stack.as_ptr() as usize, stack.len()));
+                let _ = write!(line, "{} {} {:?} kstack={:X}:{:X}", (*id).into(), context.name, context.status, base, size);
+            }
+            None => {
+                let _ = write!(line, "{} locked", (*id).into());
+            }
+        }
+        if !writer.note(NOTE_NAME, NOTE_CONTEXT, line.as_bytes()) {
+            break;
+        }
+    }
+    (start, writer.len() - start)
+}
+
+fn note_segment(offset: usize, size: usize) -> program_header::ProgramHeader {
+    program_header::ProgramHeader {
+        p_type: program_header::PT_NOTE,
+        p_flags: program_header::PF_R,
+        p_offset: offset as _,
+        p_vaddr: 0,
+        p_paddr: 0,
+        p_filesz: size as _,
+        p_memsz: 0,
+        p_align: 4,
+    }
+}
+
+/// Append a copy of `size` bytes at `address` with a segment for it, returning false if it does
+/// not fit
+unsafe fn copy_segment(writer: &mut CoreWriter, address: usize, size: usize) -> bool {
+    if writer.remaining() < size {
+        return false;
+    }
+    let data = slice::from_raw_parts(address as *const u8, size);
+    let Some(offset) = writer.append(data) else {
+        return false;
+    };
+    writer.segment(segment(address, 0, offset, size, 1))
+}
+
+fn write_mini(buf: &mut [u8], reason: fmt::Arguments) -> Option<Written> {
+    // A segment for the log ring, and one for each context and its stack
+    let contexts = context::contexts();
+    let phnum = 2 + 2 * contexts.iter().count();
+    let mut writer = CoreWriter::new(buf, phnum)?;
+    let (offset, size) = notes(&mut writer, reason);
+    writer.segment(note_segment(offset, size));
+
+    unsafe {
+        copy_segment(&mut writer, &KMSG as *const _ as usize, mem::size_of_val(&KMSG));
+        for (_, context_lock) in contexts.iter() {
+            let Some(context) = context_lock.try_read() else {
+                continue;
+            };
+            if !copy_segment(&mut writer, Arc::as_ptr(context_lock) as usize, mem::size_of_val(&**context_lock)) {
+                break;
+            }
+            if let Some(stack) = context.kstack.as_ref() {
+                if !copy_segment(&mut writer, stack.as_ptr() as usize, stack.len()) {
+                    break;
+                }
+            }
+        }
+    }
+
+    let headers = writer.finish();
+    Some(Written { headers, size: headers })
+}
+
+/// Call `f` with each range of memory the kernel may use, as physical start and end addresses,
+/// merging adjacent frames of the zones
+#[cfg(target_arch = "x86_64")]
+fn for_each_ram_range(mut f: impl FnMut(usize, usize)) {
+    use crate::memory::zone;
+
+    crate::arch::rmm::for_each_ram_range(|start, end| if end > start {
+        f(start, end)
+    });
+    let mut pending: Option<(usize, usize)> = None;
+    zone::for_each_frame(|frame, _| match pending {
+        Some((start, end)) if end == frame.data() => pending = Some((start, end + PAGE_SIZE)),
+        _ => {
+            if let Some((start, end)) = pending {
+                f(start, end);
+            }
+            pending = Some((frame.data(), frame.data() + PAGE_SIZE));
+        }
+    });
+    if let Some((start, end)) = pending {
+        f(start, end);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn write_full(buf: &mut [u8], reason: fmt::Arguments) -> Option<Written> {
+    use core::sync::atomic::Ordering;
+    use crate::start::{KERNEL_BASE, KERNEL_SIZE};
+
+    let mut writer = CoreWriter::new(buf, FULL_PHNUM)?;
+    let (offset, size) = notes(&mut writer, reason);
+    writer.segment(note_segment(offset, size));
+
+    let kernel_base = KERNEL_BASE.load(Ordering::Relaxed);
+    let kernel_size = KERNEL_SIZE.load(Ordering::Relaxed);
+    let mut kernel_offset = None;
+    let mut next = writer.len().next_multiple_of(PAGE_SIZE);
+    for_each_ram_range(|start, end| {
+        let vaddr = RmmA::phys_to_virt(PhysicalAddress::new(start)).data();
+        if writer.segment(segment(vaddr, start, next, end - start, PAGE_SIZE)) {
+            if (start..end).contains(&kernel_base) {
+                kernel_offset = Some(next + kernel_base - start);
+            }
+            next += end - start;
+        }
+    });
+    // The same contents, where the kernel executable is linked
+    if let Some(offset) = kernel_offset {
+        writer.segment(segment(crate::KERNEL_OFFSET, kernel_base, offset, kernel_size, PAGE_SIZE));
+    }
+
+    let headers = writer.finish();
+    Some(Written { headers, size: next })
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn write_full(_buf: &mut [u8], _reason: fmt::Arguments) -> Option<Written> {
+    None
+}
+
+/// Write a dump to the region, if armed, with `reason` as note. Called when panicking, and by the
+/// debugger.
+pub fn write(reason: fmt::Arguments) -> Option<Written> {
+    // This CPU may have panicked while holding it
+    let mut state = STATE.try_lock()?;
+    let state = &mut *state;
+    let region = state.region.as_mut()?;
+    let base = region.base();
+    let written = match region.kind {
+        Kind::Mini => write_mini(region.data_mut(), reason),
+        Kind::Full => write_full(region.data_mut(), reason),
+    };
+    match written {
+        Some(written) => println!("Dump of {} bytes written to {:X}", written.size, base),
+        None => println!("Failed to write dump to {:X}", base),
+    }
+    state.written = written;
+    written
+}
+
+/// Read the dump written last at `offset`, returning zero past its end or if there is none
+pub fn read(offset: usize, buf: UserSliceWo) -> Result<usize> {
+    let state = STATE.lock();
+    let (Some(region), Some(written)) = (state.region.as_ref(), state.written) else {
+        return Ok(0);
+    };
+    let data = region.data();
+    if offset < written.headers {
+        return buf.copy_common_bytes_from_slice(&data[offset..written.headers]);
+    }
+
+    // The contents of the segments of a full dump, in the memory of this kernel
+    let elf = crate::elf::Elf::from(&data[..written.headers]).map_err(|_| Error::new(EIO))?;
+    for segment in elf.segments().filter(|segment| segment.p_type == program_header::PT_LOAD) {
+        let (start, size) = (segment.p_offset as usize, segment.p_filesz as usize);
+        if (start..start + size).contains(&offset) {
+            let phys = PhysicalAddress::new(segment.p_paddr as usize + offset - start);
+            let memory = unsafe { slice::from_raw_parts(RmmA::phys_to_virt(phys).data() as *const u8, start + size - offset) };
+            return buf.copy_common_bytes_from_slice(memory);
+        }
+    }
+    if offset < written.size {
+        // Padding before the first segment
+        static ZEROES: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+        let len = (written.size - offset).min(PAGE_SIZE - offset % PAGE_SIZE);
+        return buf.copy_common_bytes_from_slice(&ZEROES[..len]);
+    }
+    Ok(0)
+}
@@ -73,6 +73,15 @@ impl<'a> Elf<'a> {
         }
     }
 
+    /// The name of `symbol`, from the string table linked to the symbol table
+    pub fn symbol_name(&self, symbol: &sym::Sym) -> Option<&'a str> {
+        let symtab = self.sections().find(|section| section.sh_type == SHT_SYMTAB)?;
+        let strtab = self.sections().nth(symtab.sh_link as usize)?;
+        let start = strtab.sh_offset as usize + symbol.st_name as usize;
+        let len = self.data.get(start..)?.iter().position(|&byte| byte == 0)?;
+        core::str::from_utf8(&self.data[start..start + len]).ok()
+    }
+
     /// Get the entry field of the header
     pub fn entry(&self) -> usize {
         self.header.e_entry as usize
@@ -164,3 +173,124 @@ impl<'a> Iterator for ElfSymbols<'a> {
         }
     }
 }
+
+/// Machine of the cores written by the kernel
+#[cfg(target_arch = "x86")]
+pub const CORE_MACHINE: u16 = header::EM_386;
+#[cfg(target_arch = "x86_64")]
+pub const CORE_MACHINE: u16 = header::EM_X86_64;
+#[cfg(target_arch = "aarch64")]
+pub const CORE_MACHINE: u16 = header::EM_AARCH64;
+#[cfg(target_arch = "riscv64")]
+pub const CORE_MACHINE: u16 = header::EM_RISCV;
+
+/// Size of a note of a core, with its name and description padded to 4 bytes
+pub fn note_size(name: &str, desc_len: usize) -> usize {
+    12 + (name.len() + 1).next_multiple_of(4) + desc_len.next_multiple_of(4)
+}
+
+/// Writes an ELF core into a buffer, without allocating: the header, room for the program
+/// headers, then notes and data appended in order
+pub struct CoreWriter<'a> {
+    buf: &'a mut [u8],
+    /// Program headers there is room for, and written
+    phnum: usize,
+    phdrs: usize,
+    /// End of the data appended
+    len: usize,
+}
+
+impl<'a> CoreWriter<'a> {
+    /// Start a core in `buf` with room for `phnum` program headers. Returns `None` if they do not
+    /// fit.
+    pub fn new(buf: &'a mut [u8], phnum: usize) -> Option<CoreWriter<'a>> {
+        let len = header::SIZEOF_EHDR + phnum * program_header::SIZEOF_PHDR;
+        if buf.len() < len || phnum > usize::from(u16::MAX) {
+            return None;
+        }
+        let mut e_ident = [0; header::SIZEOF_IDENT];
+        e_ident[..header::SELFMAG].copy_from_slice(header::ELFMAG);
+        e_ident[header::EI_CLASS] = header::ELFCLASS;
+        e_ident[header::EI_DATA] = header::ELFDATA2LSB;
+        e_ident[header::EI_VERSION] = header::EV_CURRENT;
+        let ehdr = header::Header {
+            e_ident,
+            e_type: header::ET_CORE,
+            e_machine: CORE_MACHINE,
+            e_version: u32::from(header::EV_CURRENT),
+            e_entry: 0,
+            e_phoff: header::SIZEOF_EHDR as _,
+            e_shoff: 0,
+            e_flags: 0,
+            e_ehsize: header::SIZEOF_EHDR as u16,
+            e_phentsize: program_header::SIZEOF_PHDR as u16,
+            e_phnum: 0,
+            e_shentsize: 0,
+            e_shnum: 0,
+            e_shstrndx: 0,
+        };
+        let mut writer = CoreWriter { buf, phnum, phdrs: 0, len };
+        writer.write_struct(0, &ehdr);
+        Some(writer)
+    }
+
+    fn write_struct<T>(&mut self, offset: usize, value: &T) {
+        let bytes = unsafe { core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>()) };
+        self.buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Size of what was written
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Room left for data
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.len
+    }
+
+    /// Append `data`, returning its offset, or `None` if it does not fit
+    pub fn append(&mut self, data: &[u8]) -> Option<usize> {
+        let offset = self.len;
+        self.buf.get_mut(offset..offset + data.len())?.copy_from_slice(data);
+        self.len += data.len();
+        Some(offset)
+    }
+
+    /// Append a note, padded to 4 bytes. Returns false if it does not fit.
+    pub fn note(&mut self, name: &str, kind: u32, desc: &[u8]) -> bool {
+        let name_size = name.len() + 1;
+        let size = note_size(name, desc.len());
+        if self.remaining() < size {
+            return false;
+        }
+        let start = self.len;
+        self.buf[start..start + size].fill(0);
+        self.buf[start..start + 4].copy_from_slice(&(name_size as u32).to_le_bytes());
+        self.buf[start + 4..start + 8].copy_from_slice(&(desc.len() as u32).to_le_bytes());
+        self.buf[start + 8..start + 12].copy_from_slice(&kind.to_le_bytes());
+        self.buf[start + 12..start + 12 + name.len()].copy_from_slice(name.as_bytes());
+        let desc_start = start + 12 + name_size.next_multiple_of(4);
+        self.buf[desc_start..desc_start + desc.len()].copy_from_slice(desc);
+        self.len += size;
+        true
+    }
+
+    /// Add a program header. Returns false if there is no room left for it.
+    pub fn segment(&mut self, phdr: program_header::ProgramHeader) -> bool {
+        if self.phdrs == self.phnum {
+            return false;
+        }
+        self.write_struct(header::SIZEOF_EHDR + self.phdrs * program_header::SIZEOF_PHDR, &phdr);
+        self.phdrs += 1;
+        true
+    }
+
+    /// Set the number of program headers to those added, returning the size of what was written
+    pub fn finish(mut self) -> usize {
+        let mut ehdr = unsafe { (self.buf.as_ptr() as *const header::Header).read_unaligned() };
+        ehdr.e_phnum = self.phdrs as u16;
+        self.write_struct(0, &ehdr);
+        self.len
+    }
+}
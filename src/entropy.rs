@@ -0,0 +1,115 @@
+//! # Entropy pool
+//! Mixes the output of hardware random number generators, where available, with timer jitter
+//! into a small pool. The output is suitable for identifiers and address randomization, but the
+//! pool is not a cryptographically secure generator.
+
+use spin::Once;
+
+use crate::sync::Mutex;
+
+struct Pool {
+    state: [u64; 4],
+}
+
+impl Pool {
+    /// Advance the pool state (xoshiro256**)
+    fn next(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+
+        result
+    }
+
+    fn mix(&mut self, value: u64) {
+        self.state[0] ^= value;
+        self.state[3] ^= value.rotate_left(32);
+        // Diffuse the input into the whole state
+        for _ in 0..4 {
+            self.next();
+        }
+    }
+}
+
+// Arbitrary nonzero initial state, the digits of pi
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    state: [0x243F_6A88_85A3_08D3, 0x1319_8A2E_0370_7344, 0xA409_3822_299F_31D0, 0x082E_FA98_EC4E_6C89],
+});
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn hardware_random() -> Option<u64> {
+    static HAS_RDRAND: Once<bool> = Once::new();
+
+    let has_rdrand = *HAS_RDRAND.call_once(|| {
+        crate::arch::cpuid::cpuid()
+            .and_then(|cpuid| cpuid.get_feature_info())
+            .map_or(false, |info| info.has_rdrand())
+    });
+    if !has_rdrand {
+        return None;
+    }
+
+    // RDRAND can fail transiently when its entropy source is exhausted
+    for _ in 0..10 {
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut value = 0;
+            if unsafe { core::arch::x86_64::_rdrand64_step(&mut value) } == 1 {
+                return Some(value);
+            }
+        }
+        #[cfg(target_arch = "x86")]
+        {
+            let (mut low, mut high) = (0, 0);
+            if unsafe { core::arch::x86::_rdrand32_step(&mut low) == 1 && core::arch::x86::_rdrand32_step(&mut high) == 1 } {
+                return Some(u64::from(high) << 32 | u64::from(low));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn hardware_random() -> Option<u64> {
+    None
+}
+
+/// Timer jitter, which is the only source of entropy if there is no hardware generator
+fn jitter() -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    let cycles = unsafe { core::arch::x86_64::_rdtsc() };
+    #[cfg(target_arch = "x86")]
+    let cycles = unsafe { core::arch::x86::_rdtsc() };
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let cycles = 0;
+
+    cycles ^ (crate::time::monotonic() as u64).rotate_left(32)
+}
+
+/// Add a value which is hard to predict, such as the time an interrupt arrived, to the pool
+pub fn add(value: u64) {
+    POOL.lock().mix(value);
+}
+
+pub fn next_u64() -> u64 {
+    let mut pool = POOL.lock();
+    pool.mix(jitter());
+    if let Some(value) = hardware_random() {
+        pool.mix(value);
+    }
+    pool.next()
+}
+
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let len = chunk.len();
+        chunk.copy_from_slice(&next_u64().to_ne_bytes()[..len]);
+    }
+}
@@ -1,11 +1,11 @@
 use alloc::sync::Arc;
 use alloc::collections::BTreeMap;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use spin::Once;
 
 use crate::context;
 use crate::scheme::{self, SchemeId};
-use crate::sync::WaitQueue;
+use crate::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard, WaitQueue};
 use crate::syscall::data::Event;
 use crate::syscall::error::{Error, Result, EBADF, ESRCH};
 use crate::syscall::flag::EventFlags;
@@ -50,13 +50,19 @@ impl EventQueue {
 
             register(
                 RegKey { scheme, number },
-                QueueKey { queue: self.id, id: event.id, data: event.data },
-                event.flags
+                QueueKey { queue: self.id, id: event.id },
+                Subscription { flags: event.flags, data: event.data }
             );
 
-            let flags = sync(RegKey { scheme, number })?;
+            // Events that are already pending are only reported to this queue, other subscribers
+            // have either seen them already or will be told by the scheme
+            let flags = sync(RegKey { scheme, number })? & event.flags;
             if !flags.is_empty() {
-                trigger(scheme, number, flags);
+                self.queue.send(Event {
+                    id: event.id,
+                    flags,
+                    data: event.data,
+                });
             }
         }
 
@@ -98,14 +104,21 @@ pub struct RegKey {
     pub number: usize,
 }
 
+/// A single subscription to the events of a file, one per event queue and file descriptor. Any
+/// number of queues can subscribe to the same file, each with its own flags and data.
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct QueueKey {
     pub queue: EventQueueId,
     pub id: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Subscription {
+    pub flags: EventFlags,
     pub data: usize,
 }
 
-type Registry = BTreeMap<RegKey, BTreeMap<QueueKey, EventFlags>>;
+type Registry = BTreeMap<RegKey, BTreeMap<QueueKey, Subscription>>;
 
 static REGISTRY: Once<RwLock<Registry>> = Once::new();
 
@@ -124,17 +137,21 @@ pub fn registry_mut() -> RwLockWriteGuard<'static, Registry> {
     REGISTRY.call_once(init_registry).write()
 }
 
-pub fn register(reg_key: RegKey, queue_key: QueueKey, flags: EventFlags) {
+/// Add, replace or (with empty flags) remove the subscription of `queue_key` to `reg_key`
+pub fn register(reg_key: RegKey, queue_key: QueueKey, subscription: Subscription) {
     let mut registry = registry_mut();
 
-    let entry = registry.entry(reg_key).or_insert_with(|| {
-        BTreeMap::new()
-    });
-
-    if flags.is_empty() {
-        entry.remove(&queue_key);
+    if subscription.flags.is_empty() {
+        if let Some(entry) = registry.get_mut(&reg_key) {
+            entry.remove(&queue_key);
+            if entry.is_empty() {
+                registry.remove(&reg_key);
+            }
+        }
     } else {
-        entry.insert(queue_key, flags);
+        registry.entry(reg_key).or_insert_with(|| {
+            BTreeMap::new()
+        }).insert(queue_key, subscription);
     }
 }
 
@@ -145,8 +162,8 @@ pub fn sync(reg_key: RegKey) -> Result<EventFlags> {
         let registry = registry();
 
         if let Some(queue_list) = registry.get(&reg_key) {
-            for (_queue_key, &queue_flags) in queue_list.iter() {
-                flags |= queue_flags;
+            for (_queue_key, subscription) in queue_list.iter() {
+                flags |= subscription.flags;
             }
         }
     }
@@ -166,25 +183,48 @@ pub fn unregister_file(scheme: SchemeId, number: usize) {
     registry.remove(&RegKey { scheme, number });
 }
 
-//TODO: Implement unregister_queue
-// pub fn unregister_queue(scheme: SchemeId, number: usize) {
-//
-// }
+/// Remove every subscription of a queue that is being closed, and let the schemes of the files it
+/// was subscribed to know which events are still wanted by the remaining subscribers
+pub fn unregister_queue(queue: EventQueueId) {
+    let mut changed = alloc::vec::Vec::new();
+
+    {
+        let mut registry = registry_mut();
+        registry.retain(|reg_key, queue_list| {
+            let len = queue_list.len();
+            queue_list.retain(|queue_key, _subscription| queue_key.queue != queue);
+            if queue_list.len() != len {
+                changed.push(RegKey { scheme: reg_key.scheme, number: reg_key.number });
+            }
+            !queue_list.is_empty()
+        });
+    }
+
+    for reg_key in changed {
+        let _ = sync(reg_key);
+    }
+}
 
 pub fn trigger(scheme: SchemeId, number: usize, flags: EventFlags) {
+    trigger_boosted(scheme, number, flags, 0)
+}
+
+/// Like `trigger`, but boost the contexts reading the event queues, if wakeups of `class` are
+/// boosted (see `context::boost`)
+pub fn trigger_boosted(scheme: SchemeId, number: usize, flags: EventFlags, class: usize) {
     let registry = registry();
 
     if let Some(queue_list) = registry.get(&RegKey { scheme, number }) {
-        for (queue_key, &queue_flags) in queue_list.iter() {
-            let common_flags = flags & queue_flags;
+        for (queue_key, subscription) in queue_list.iter() {
+            let common_flags = flags & subscription.flags;
             if !common_flags.is_empty() {
                 let queues = queues();
                 if let Some(queue) = queues.get(&queue_key.queue) {
-                    queue.queue.send(Event {
+                    queue.queue.send_boosted(Event {
                         id: queue_key.id,
                         flags: common_flags,
-                        data: queue_key.data
-                    });
+                        data: subscription.data
+                    }, class);
                 }
             }
         }
@@ -0,0 +1,454 @@
+//! # Hibernation
+//! Suspend to disk, by handing an image of memory to a userspace daemon that stores it, and
+//! restoring it when booting again.
+//!
+//! With the other CPUs stopped (see `power`), the BSP saves its state like for suspend to RAM, and
+//! copies every frame that may be in use (see `rmm::for_each_used_frame`) into frames allocated
+//! beforehand. The system then runs again, so that the daemon can read the image from
+//! `power:image`, store it and power off. Pages are swapped out first, for the copies to fit in
+//! free memory, and no more while the image is held, so that the swap slots it refers to keep
+//! their contents.
+//!
+//! When booting again, the daemon writes the image back to `power:image`, before anything uses
+//! swap or writes to the file systems. Each page is kept in a frame that is not part of the image,
+//! and writing the last one restores it: with the other CPUs stopped again, the BSP switches to a
+//! page table and stack in such frames, copies each page to its frame, and resumes the state saved
+//! in the image (see `arch::suspend::restore_image`). This needs the same kernel, at the same
+//! physical address, and the same memory map, which the image header records.
+
+use alloc::vec::Vec;
+use core::{mem, slice};
+
+use crate::arch::rmm::{for_each_ram_range, for_each_used_frame, is_ram};
+use crate::arch::suspend;
+use crate::common::sha256::{self, Digest, Sha256};
+use crate::context::{self, swap};
+use crate::kernel_executable_offsets::{__rodata_end, __text_start};
+use crate::log::{info, warn};
+use crate::memory::{allocate_frames, deallocate_frames, free_frames, Frame, PAGE_SIZE};
+use crate::paging::{PhysicalAddress, RmmA, RmmArch};
+use crate::sync::Mutex;
+use crate::syscall::abi::{HibernateHeader, HIBERNATE_MAGIC};
+use crate::syscall::error::*;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+use crate::time;
+
+/// Frame numbers in each page of the frame list
+const FRAMES_PER_PAGE: usize = PAGE_SIZE / mem::size_of::<usize>();
+
+/// Frames allocated for the copies on top of those counted in use, for the frames the allocations
+/// themselves put in use, as a fraction of the count
+const SLACK_DIVISOR: usize = 64;
+const SLACK_MIN: usize = 1024;
+
+/// How long to wait for the swap daemon to write pages out, when making room for the copies
+const SWAP_TIMEOUT: u128 = 10 * time::NANOS_PER_SEC;
+
+/// Entries in each page of a restore list
+pub const RESTORE_ENTRIES: usize = PAGE_SIZE / (2 * mem::size_of::<usize>()) - 1;
+
+/// A page of the list of pages to restore, each entry of which is the physical address of a frame
+/// of the image, and that of the frame holding its contents
+#[repr(C)]
+pub struct RestorePage {
+    /// Physical address of the next page of the list, or zero
+    pub next: usize,
+    pub count: usize,
+    pub entries: [[usize; 2]; RESTORE_ENTRIES],
+}
+
+/// An image taken by `snapshot`
+struct Image {
+    header: HibernateHeader,
+    /// Physical addresses of the frames, ascending
+    frames: Vec<usize>,
+    /// Copy of each frame
+    copies: Vec<Frame>,
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        for copy in self.copies.drain(..) {
+            deallocate_frames(copy, 1);
+        }
+    }
+}
+
+/// An image being written back to `power:image`
+struct Loader {
+    header: Option<HibernateHeader>,
+    /// Physical addresses of the frames, ascending
+    frames: Vec<usize>,
+    /// Number of pages loaded
+    loaded: usize,
+    /// Pages of the restore list, the first of which is its head
+    list: Vec<Frame>,
+    /// Other frames taken from the allocator, which are part of the image or hold the page table to
+    /// restore it with
+    held: Vec<Frame>,
+}
+
+impl Loader {
+    /// A frame that is not part of the image
+    fn safe_frame(&mut self) -> Result<Frame> {
+        loop {
+            let frame = allocate_frames(1).ok_or(Error::new(ENOMEM))?;
+            if self.frames.binary_search(&frame.start_address().data()).is_err() {
+                return Ok(frame);
+            }
+            // Holding it keeps the allocator from handing it out again
+            self.held.push(frame);
+        }
+    }
+
+    fn push(&mut self, frame: usize, contents: Frame) -> Result<()> {
+        let full = match self.list.last() {
+            Some(last) => unsafe { (*restore_page(last)).count == RESTORE_ENTRIES },
+            None => true,
+        };
+        if full {
+            let page = self.safe_frame()?;
+            if let Some(last) = self.list.last() {
+                unsafe { (*restore_page(last)).next = page.start_address().data(); }
+            }
+            // Allocated frames are zeroed, so the page is empty and last
+            self.list.push(page);
+        }
+        let page = unsafe { &mut *restore_page(self.list.last().unwrap()) };
+        page.entries[page.count] = [frame, contents.start_address().data()];
+        page.count += 1;
+        Ok(())
+    }
+
+    fn load_page(&mut self, buf: UserSliceRo) -> Result<()> {
+        let header = match self.header {
+            Some(header) => header,
+            None => {
+                let header = unsafe { buf.read_exact::<HibernateHeader>()? };
+                check(&header)?;
+                self.frames.try_reserve_exact(header.pages).map_err(|_| Error::new(ENOMEM))?;
+                self.header = Some(header);
+                return Ok(());
+            }
+        };
+
+        if self.frames.len() < header.pages {
+            let count = (header.pages - self.frames.len()).min(FRAMES_PER_PAGE);
+            for address in buf.usizes().take(count) {
+                let address = address?;
+                // Ascending, for `safe_frame`, and memory that can be mapped to restore it
+                let ascending = self.frames.last().map_or(true, |&last| last < address);
+                if !ascending || address % PAGE_SIZE != 0 || !is_ram(PhysicalAddress::new(address)) {
+                    return Err(Error::new(EINVAL));
+                }
+                self.frames.push(address);
+            }
+            return Ok(());
+        }
+
+        let frame = *self.frames.get(self.loaded).ok_or(Error::new(EINVAL))?;
+        let contents = self.safe_frame()?;
+        let data = unsafe { slice::from_raw_parts_mut(RmmA::phys_to_virt(contents.start_address()).data() as *mut u8, PAGE_SIZE) };
+        if let Err(err) = buf.copy_to_slice(data).and_then(|()| self.push(frame, contents.clone())) {
+            deallocate_frames(contents, 1);
+            return Err(err);
+        }
+        self.loaded += 1;
+        Ok(())
+    }
+
+    fn load_pages(&mut self, buf: UserSliceRo) -> Result<bool> {
+        for page in buf.in_exact_chunks(PAGE_SIZE) {
+            if self.complete() {
+                return Err(Error::new(EINVAL));
+            }
+            self.load_page(page)?;
+        }
+        Ok(self.complete())
+    }
+
+    fn complete(&self) -> bool {
+        self.header.map_or(false, |header| self.loaded == header.pages)
+    }
+}
+
+impl Drop for Loader {
+    fn drop(&mut self) {
+        for page in self.list.drain(..) {
+            let restore_page = unsafe { &*restore_page(&page) };
+            for &[_, contents] in &restore_page.entries[..restore_page.count] {
+                deallocate_frames(Frame::containing_address(PhysicalAddress::new(contents)), 1);
+            }
+            deallocate_frames(page, 1);
+        }
+        for frame in self.held.drain(..) {
+            deallocate_frames(frame, 1);
+        }
+    }
+}
+
+/// Hands out the frames of the page table that restores the image, which must not be part of it
+struct SafeAllocator<'a>(&'a mut Loader);
+
+impl rmm::FrameAllocator for SafeAllocator<'_> {
+    unsafe fn allocate(&mut self, count: rmm::FrameCount) -> Option<rmm::PhysicalAddress> {
+        if count.data() != 1 {
+            return None;
+        }
+        let frame = self.0.safe_frame().ok()?;
+        let address = frame.start_address();
+        self.0.held.push(frame);
+        Some(address)
+    }
+
+    unsafe fn free(&mut self, _address: rmm::PhysicalAddress, _count: rmm::FrameCount) {
+        // Freed with the loader
+    }
+
+    unsafe fn usage(&self) -> rmm::FrameUsage {
+        rmm::FrameUsage::new(rmm::FrameCount::new(0), rmm::FrameCount::new(0))
+    }
+}
+
+static IMAGE: Mutex<Option<Image>> = Mutex::new(None);
+static LOADER: Mutex<Option<Loader>> = Mutex::new(None);
+
+fn restore_page(frame: &Frame) -> *mut RestorePage {
+    RmmA::phys_to_virt(frame.start_address()).data() as *mut RestorePage
+}
+
+fn list_pages(pages: usize) -> usize {
+    pages.div_ceil(FRAMES_PER_PAGE)
+}
+
+/// SHA-256 of the code and read-only data of the kernel
+fn kernel_digest() -> Digest {
+    let start = __text_start();
+    sha256::digest(unsafe { slice::from_raw_parts(start as *const u8, __rodata_end() - start) })
+}
+
+/// SHA-256 of the memory the kernel may use
+fn memory_map_digest() -> Digest {
+    let mut hasher = Sha256::new();
+    for_each_ram_range(|start, end| {
+        hasher.update(&start.to_le_bytes());
+        hasher.update(&end.to_le_bytes());
+    });
+    hasher.finish()
+}
+
+fn header(pages: usize) -> HibernateHeader {
+    HibernateHeader {
+        magic: HIBERNATE_MAGIC,
+        pages,
+        size: (1 + list_pages(pages) + pages) * PAGE_SIZE,
+        kernel: kernel_digest(),
+        memory_map: memory_map_digest(),
+    }
+}
+
+/// Check that `header` belongs to an image this kernel can restore
+fn check(header: &HibernateHeader) -> Result<()> {
+    if header.magic != HIBERNATE_MAGIC || header.pages == 0 || header.size != (1 + list_pages(header.pages) + header.pages) * PAGE_SIZE {
+        return Err(Error::new(EINVAL));
+    }
+    if header.kernel != kernel_digest() || header.memory_map != memory_map_digest() {
+        warn!("Hibernation image of another kernel or memory map");
+        return Err(Error::new(EINVAL));
+    }
+    Ok(())
+}
+
+fn count_used_frames() -> usize {
+    let mut count = 0;
+    for_each_used_frame(|_| count += 1);
+    count
+}
+
+fn slack(count: usize) -> usize {
+    (count / SLACK_DIVISOR).max(SLACK_MIN)
+}
+
+/// Returns true if an image was taken and not yet discarded
+pub fn has_image() -> bool {
+    IMAGE.lock().is_some()
+}
+
+/// Discard the image, once stored or if it cannot be
+pub fn discard() {
+    if IMAGE.lock().take().is_some() {
+        swap::freeze(false);
+    }
+}
+
+/// Swap out pages until the copies of the frames in use fit in free memory, if there is a swap
+/// daemon to write them out
+pub fn make_room() -> Result<()> {
+    let count = count_used_frames();
+    let needed = count + slack(count);
+    let free = free_frames();
+    if free >= needed {
+        return Ok(());
+    }
+
+    // Each page swapped out is one less to copy, and one more free frame
+    let missing = (needed - free).div_ceil(2);
+    info!("Swapping out {} pages to make room for the hibernation image", missing);
+    swap::reclaim(missing);
+
+    let initial = time::monotonic();
+    while swap::pending_out() > 0 && time::monotonic() - initial < SWAP_TIMEOUT {
+        let _ = unsafe { context::switch() };
+    }
+
+    let count = count_used_frames();
+    if free_frames() < count + slack(count) {
+        return Err(Error::new(ENOMEM));
+    }
+    Ok(())
+}
+
+/// Take an image, on the BSP with the APs in `aps` stopped and interrupts disabled. Returns false
+/// once the image was taken, and true once resumed from it.
+pub unsafe fn snapshot(aps: u64) -> Result<bool> {
+    let header_base = header(0);
+
+    let count = count_used_frames();
+    let estimate = count + slack(count);
+    let mut frames = Vec::new();
+    let mut copies = Vec::new();
+    frames.try_reserve_exact(estimate).map_err(|_| Error::new(ENOMEM))?;
+    copies.try_reserve_exact(estimate).map_err(|_| Error::new(ENOMEM))?;
+    for _ in 0..estimate {
+        match allocate_frames(1) {
+            Some(copy) => copies.push(copy),
+            None => {
+                for copy in copies {
+                    deallocate_frames(copy, 1);
+                }
+                return Err(Error::new(ENOMEM));
+            }
+        }
+    }
+    copies.sort_unstable_by_key(|copy: &Frame| copy.start_address().data());
+
+    // The copies are not part of the image, and after resuming from it, those taken in the image
+    // are free again
+    let taken = suspend::saved(aps, || {
+        let mut overflow = false;
+        for_each_used_frame(|frame| {
+            if copies.binary_search_by_key(&frame.data(), |copy| copy.start_address().data()).is_ok() {
+                return;
+            }
+            if frames.len() == frames.capacity() {
+                overflow = true;
+                return;
+            }
+            frames.push(frame.data());
+        });
+        if overflow {
+            return false;
+        }
+        frames.sort_unstable();
+        frames.dedup();
+
+        for (&frame, copy) in frames.iter().zip(copies.iter()) {
+            (RmmA::phys_to_virt(copy.start_address()).data() as *mut u8).copy_from_nonoverlapping(
+                RmmA::phys_to_virt(PhysicalAddress::new(frame)).data() as *const u8,
+                PAGE_SIZE,
+            );
+        }
+        true
+    });
+
+    let spare = match taken {
+        Some(true) => copies.split_off(frames.len()),
+        Some(false) | None => mem::take(&mut copies),
+    };
+    for copy in spare {
+        deallocate_frames(copy, 1);
+    }
+
+    match taken {
+        Some(true) => {
+            let header = HibernateHeader { pages: frames.len(), size: (1 + list_pages(frames.len()) + frames.len()) * PAGE_SIZE, ..header_base };
+            swap::freeze(true);
+            *IMAGE.lock() = Some(Image { header, frames, copies });
+            Ok(false)
+        }
+        Some(false) => Err(Error::new(ENOMEM)),
+        None => Ok(true),
+    }
+}
+
+/// Read the image at `offset`, returning zero past its end or if there is none
+pub fn read_image(offset: usize, buf: UserSliceWo) -> Result<usize> {
+    static ZEROES: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+    let image = IMAGE.lock();
+    let image = match image.as_ref() {
+        Some(image) => image,
+        None => return Ok(0),
+    };
+
+    let (index, start) = (offset / PAGE_SIZE, offset % PAGE_SIZE);
+    let list_pages = list_pages(image.frames.len());
+    let page: &[u8] = if index == 0 {
+        &image.header[..]
+    } else if index <= list_pages {
+        let frames = &image.frames[(index - 1) * FRAMES_PER_PAGE..];
+        let frames = &frames[..frames.len().min(FRAMES_PER_PAGE)];
+        unsafe { slice::from_raw_parts(frames.as_ptr() as *const u8, frames.len() * mem::size_of::<usize>()) }
+    } else if let Some(copy) = image.copies.get(index - 1 - list_pages) {
+        unsafe { slice::from_raw_parts(RmmA::phys_to_virt(copy.start_address()).data() as *const u8, PAGE_SIZE) }
+    } else {
+        return Ok(0);
+    };
+
+    // The header and frame list are padded to a page
+    match page.get(start..).filter(|data| !data.is_empty()) {
+        Some(data) => buf.copy_common_bytes_from_slice(data),
+        None => buf.copy_common_bytes_from_slice(&ZEROES[start..]),
+    }
+}
+
+/// Load the next pages of an image, from a write of whole pages. Returns true once the image is
+/// complete, to be restored with `restore`. Loading is aborted on errors.
+pub fn load(buf: UserSliceRo) -> Result<bool> {
+    if buf.len() % PAGE_SIZE != 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let mut loader = LOADER.lock();
+    let result = loader
+        .get_or_insert_with(|| Loader {
+            header: None,
+            frames: Vec::new(),
+            loaded: 0,
+            list: Vec::new(),
+            held: Vec::new(),
+        })
+        .load_pages(buf);
+    if result.is_err() {
+        *loader = None;
+    }
+    result
+}
+
+/// Abort loading an image
+pub fn abort_load() {
+    *LOADER.lock() = None;
+}
+
+/// Restore the loaded image, on the BSP with the APs in `aps` stopped and interrupts disabled.
+/// Only returns if it could not be restored, with the APs restarted.
+pub unsafe fn restore(aps: u64) -> Error {
+    let mut loader = match LOADER.lock().take().filter(Loader::complete) {
+        Some(loader) => loader,
+        None => return Error::new(EINVAL),
+    };
+    let list = loader.list[0].start_address();
+
+    info!("Restoring hibernation image of {} pages", loader.loaded);
+    suspend::restore_image(list, SafeAllocator(&mut loader), aps)
+}
@@ -0,0 +1,134 @@
+//! # CPU hotplug
+//! A secondary CPU can be taken offline at runtime, and brought back later, through `cpu:`. An
+//! offline CPU only runs its idle context, which parks it: it halts with interrupts enabled, only
+//! woken by IPIs, and ignores the timer IPIs it still receives. Device IRQs are always routed to
+//! the BSP, which is why it cannot be taken offline.
+//!
+//! Before a CPU is parked, the contexts it owns are released, so that the other CPUs take them over
+//! at their next switch. Contexts pinned to the CPU with a scheduling affinity cannot migrate, and
+//! keep it from going offline.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::context::{self, ContextId};
+use crate::interrupt;
+use crate::ipi::{ipi, ipi_single, IpiKind, IpiTarget};
+use crate::log::info;
+use crate::syscall::error::{Error, Result, EBUSY, EINVAL, ENOENT};
+
+/// Number of CPUs that can be taken offline
+pub const CPUS: usize = 64;
+
+/// CPUs asked to go offline
+static OFFLINE: AtomicU64 = AtomicU64::new(0);
+/// CPUs parked in their idle context
+static PARKED: AtomicU64 = AtomicU64::new(0);
+
+fn bit(cpu_id: usize) -> u64 {
+    if cpu_id < CPUS { 1 << cpu_id } else { 0 }
+}
+
+/// The idle context of `cpu_id`, created by `context::init`
+pub fn idle_context(cpu_id: usize) -> ContextId {
+    ContextId::from(cpu_id + 1)
+}
+
+/// Returns true if `cpu_id` was asked to go offline, or is offline
+pub fn is_offline(cpu_id: usize) -> bool {
+    OFFLINE.load(Ordering::SeqCst) & bit(cpu_id) != 0
+}
+
+/// Returns true if `cpu_id` is parked
+pub fn is_parked(cpu_id: usize) -> bool {
+    PARKED.load(Ordering::SeqCst) & bit(cpu_id) != 0
+}
+
+/// Take `cpu_id` offline, waiting until it is parked
+pub fn offline(cpu_id: usize) -> Result<()> {
+    if cpu_id >= crate::cpu_count() || cpu_id >= CPUS {
+        return Err(Error::new(ENOENT));
+    }
+    // The BSP receives every device IRQ, and the current CPU could not wait for itself
+    if cpu_id == 0 || cpu_id == crate::cpu_id() {
+        return Err(Error::new(EBUSY));
+    }
+    if is_offline(cpu_id) {
+        return Ok(());
+    }
+
+    {
+        let contexts = context::contexts();
+        let pinned = contexts.iter().any(|(&id, context_lock)| {
+            id != idle_context(cpu_id) && !crate::lockup::is_watchdog(id) && context_lock.read().sched_affinity == Some(cpu_id)
+        });
+        if pinned {
+            return Err(Error::new(EBUSY));
+        }
+    }
+
+    OFFLINE.fetch_or(bit(cpu_id), Ordering::SeqCst);
+    // Switches to the idle context, the only one it runs from now on
+    ipi_single(IpiKind::Switch, cpu_id);
+    while !is_parked(cpu_id) {
+        core::hint::spin_loop();
+    }
+
+    // The CPU does not run any of its contexts anymore, so they can be handed to the others
+    {
+        let contexts = context::contexts();
+        for (&id, context_lock) in contexts.iter() {
+            if id == idle_context(cpu_id) {
+                continue;
+            }
+            let mut context = context_lock.write();
+            if context.cpu_id == Some(cpu_id) {
+                context.cpu_id = None;
+            }
+        }
+    }
+    ipi(IpiKind::Wakeup, IpiTarget::Other);
+
+    info!("CPU {} offline", cpu_id);
+    Ok(())
+}
+
+/// Bring `cpu_id` back online, waiting until it left its parking loop
+pub fn online(cpu_id: usize) -> Result<()> {
+    if cpu_id >= crate::cpu_count() || cpu_id >= CPUS {
+        return Err(Error::new(ENOENT));
+    }
+    if !is_offline(cpu_id) {
+        return Ok(());
+    }
+    // Still on its way to the parking loop, which it would leave right away
+    if !is_parked(cpu_id) {
+        return Err(Error::new(EINVAL));
+    }
+
+    OFFLINE.fetch_and(!bit(cpu_id), Ordering::SeqCst);
+    ipi_single(IpiKind::Wakeup, cpu_id);
+    while is_parked(cpu_id) {
+        core::hint::spin_loop();
+    }
+
+    info!("CPU {} online", cpu_id);
+    Ok(())
+}
+
+/// Park the current CPU until it is brought back online. Called by the idle loop of secondary
+/// CPUs, with interrupts disabled.
+pub unsafe fn park() {
+    let cpu_id = crate::cpu_id();
+    PARKED.fetch_or(bit(cpu_id), Ordering::SeqCst);
+
+    while is_offline(cpu_id) {
+        // Stop here if the system suspends, until resumed
+        #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+        crate::power::park(cpu_id);
+
+        interrupt::enable_and_halt();
+        interrupt::disable();
+    }
+
+    PARKED.fetch_and(!bit(cpu_id), Ordering::SeqCst);
+}
@@ -0,0 +1,111 @@
+//! # Idle
+//! What a CPU does when it has nothing to run. Deeper idle states save more power, but take longer
+//! to leave, and only pay off when the CPU stays in them long enough. Each architecture lists its
+//! states, shallowest first (see `arch::idle`), and the deepest one whose target residency fits in
+//! the predicted idle duration is entered.
+//!
+//! The idle duration is predicted as the time until the next tick (see `timeout::next_tick`),
+//! corrected by how long the CPU actually stayed idle, compared to that prediction, in the past:
+//! a CPU woken by interrupts well before each tick chooses shallower states. While low-latency
+//! contexts exist (see `context::latency`), only states that can be left within `LATENCY_LIMIT`
+//! are entered.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::arch::idle as driver;
+use crate::context::{latency, timeout};
+use crate::time;
+
+/// Number of CPUs whose idle states are tracked
+pub const CPUS: usize = 32;
+
+/// Most idle states of any architecture
+pub const MAX_STATES: usize = 8;
+
+/// Exit latency allowed while low-latency contexts exist, in microseconds
+const LATENCY_LIMIT: u32 = 20;
+
+/// The correction of predictions is in fixed point, with this many fractional bits
+const CORRECTION_SHIFT: u32 = 10;
+/// Weight of the last idle period in the correction, as a shift
+const CORRECTION_WEIGHT: u32 = 3;
+
+/// An idle state
+#[derive(Clone, Copy, Debug)]
+pub struct State {
+    pub name: &'static str,
+    /// Time to leave it, in microseconds
+    pub exit_latency: u32,
+    /// Time to stay in it for it to save power, in microseconds
+    pub target_residency: u32,
+}
+
+struct CpuIdle {
+    /// Ratio of the measured idle durations to the predicted ones
+    correction: AtomicU32,
+    /// Number of times each state was entered, and nanoseconds spent in it
+    usage: [AtomicU64; MAX_STATES],
+    time: [AtomicU64; MAX_STATES],
+}
+
+static CPU_IDLE: [CpuIdle; CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const COUNTER: AtomicU64 = AtomicU64::new(0);
+    #[allow(clippy::declare_interior_mutable_const)]
+    const IDLE: CpuIdle = CpuIdle {
+        correction: AtomicU32::new(1 << CORRECTION_SHIFT),
+        usage: [COUNTER; MAX_STATES],
+        time: [COUNTER; MAX_STATES],
+    };
+    [IDLE; CPUS]
+};
+
+/// The deepest state worth entering for `predicted` nanoseconds
+fn select(states: &[State], predicted: u128) -> usize {
+    let latency_limit = if latency::any() { LATENCY_LIMIT } else { u32::MAX };
+    states.iter()
+        .enumerate()
+        .skip(1)
+        .take_while(|(_, state)| u128::from(state.target_residency) * 1000 <= predicted && state.exit_latency <= latency_limit)
+        .last()
+        .map_or(0, |(index, _)| index)
+}
+
+/// Wait for the next interrupt in an idle state, with interrupts disabled. They are enabled once
+/// in the idle state, and stay enabled once it returns.
+pub unsafe fn idle() {
+    let cpu_id = crate::cpu_id();
+    let states = driver::states();
+    let cpu = match CPU_IDLE.get(cpu_id) {
+        Some(cpu) => cpu,
+        None => return driver::enter(0),
+    };
+
+    let start = time::monotonic();
+    let expected = timeout::next_tick().saturating_sub(start);
+    let correction = cpu.correction.load(Ordering::Relaxed);
+    let predicted = (expected * u128::from(correction)) >> CORRECTION_SHIFT;
+
+    let index = select(states, predicted).min(MAX_STATES - 1);
+    driver::enter(index);
+
+    let measured = time::monotonic().saturating_sub(start);
+    cpu.usage[index].fetch_add(1, Ordering::Relaxed);
+    cpu.time[index].fetch_add(measured as u64, Ordering::Relaxed);
+
+    // Exponentially decaying average of the ratio, up to one as the tick bounds the idle duration
+    if expected > 0 {
+        let ratio = ((measured << CORRECTION_SHIFT) / expected).min(1 << CORRECTION_SHIFT) as u32;
+        cpu.correction.store(correction - (correction >> CORRECTION_WEIGHT) + (ratio >> CORRECTION_WEIGHT), Ordering::Relaxed);
+    }
+}
+
+/// The idle states, and for each the number of times `cpu_id` entered it and the nanoseconds it
+/// spent in it
+pub fn usage(cpu_id: usize) -> impl Iterator<Item = (&'static State, u64, u64)> {
+    let cpu = CPU_IDLE.get(cpu_id);
+    driver::states().iter().enumerate().take(MAX_STATES).filter_map(move |(index, state)| {
+        let cpu = cpu?;
+        Some((state, cpu.usage[index].load(Ordering::Relaxed), cpu.time[index].load(Ordering::Relaxed)))
+    })
+}
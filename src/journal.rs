@@ -0,0 +1,197 @@
+//! # Event journal
+//! A fixed-size ring per CPU, recording significant kernel events with a cheap timestamp. The
+//! journals are dumped on panic and by the debugger, merged by timestamp, to reconstruct the order
+//! of events across CPUs when debugging SMP races.
+//!
+//! Recording reserves an entry by incrementing the head of the ring, so it is lock-free and safe
+//! from interrupt handlers, even if they interrupt the recording of another event. Entries are
+//! invalidated while they are written, and reading while other CPUs are still running skips those,
+//! but may observe an entry overwritten as it is read if the ring wrapped around meanwhile.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::ipi::IPI_TARGET_CPU;
+
+/// Number of CPUs with a journal, events on other CPUs are not recorded
+const CPUS: usize = 32;
+/// Number of entries per CPU
+const ENTRIES: usize = 128;
+
+/// Spins on a lock, in timestamp units, after which contention is recorded
+pub const CONTENTION_THRESHOLD: u64 = 100_000;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(usize)]
+pub enum EventKind {
+    /// Switched from context `a` to context `b`
+    ContextSwitch = 1,
+    /// IRQ `a` was delivered
+    Irq = 2,
+    /// IPI of vector `a` sent to target `b`, an `IpiTarget`, or `IPI_TARGET_CPU` plus the ID of
+    /// the CPU
+    Ipi = 3,
+    /// Waited `b` timestamp units for the lock `a`, a `Lock` value
+    LockContention = 4,
+}
+
+impl EventKind {
+    fn from_raw(raw: usize) -> Option<Self> {
+        Some(match raw {
+            1 => Self::ContextSwitch,
+            2 => Self::Irq,
+            3 => Self::Ipi,
+            4 => Self::LockContention,
+            _ => return None,
+        })
+    }
+}
+
+/// Locks whose contention is recorded
+#[derive(Clone, Copy, Debug)]
+#[repr(usize)]
+pub enum Lock {
+    ContextSwitch = 0,
+}
+
+struct Entry {
+    time: AtomicU64,
+    kind: AtomicUsize,
+    a: AtomicUsize,
+    b: AtomicUsize,
+}
+
+struct Journal {
+    /// Total number of entries reserved, the next entry is at `head % ENTRIES`
+    head: AtomicUsize,
+    entries: [Entry; ENTRIES],
+}
+
+impl Journal {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ENTRY: Entry = Entry {
+            time: AtomicU64::new(0),
+            kind: AtomicUsize::new(0),
+            a: AtomicUsize::new(0),
+            b: AtomicUsize::new(0),
+        };
+        Self {
+            head: AtomicUsize::new(0),
+            entries: [ENTRY; ENTRIES],
+        }
+    }
+}
+
+static JOURNALS: [Journal; CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const JOURNAL: Journal = Journal::new();
+    [JOURNAL; CPUS]
+};
+
+/// A timestamp which is cheap to read, and comparable across CPUs
+#[inline(always)]
+pub fn timestamp() -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    return unsafe { core::arch::x86_64::_rdtsc() };
+
+    #[cfg(target_arch = "x86")]
+    return unsafe { core::arch::x86::_rdtsc() };
+
+    #[cfg(target_arch = "aarch64")]
+    return {
+        let counter: u64;
+        unsafe { core::arch::asm!("mrs {}, cntvct_el0", out(reg) counter) };
+        counter
+    };
+
+    #[cfg(target_arch = "riscv64")]
+    return {
+        let counter: u64;
+        unsafe { core::arch::asm!("rdtime {}", out(reg) counter) };
+        counter
+    };
+}
+
+/// Timestamp units per second, if known
+pub fn frequency() -> Option<u64> {
+    #[cfg(target_arch = "x86_64")]
+    return crate::device::tsc::khz().map(|khz| khz * 1000);
+
+    #[cfg(target_arch = "x86")]
+    return None;
+
+    #[cfg(target_arch = "aarch64")]
+    return Some(u64::from(unsafe { crate::device::cpu::registers::control_regs::cntfreq_el0() })).filter(|&frequency| frequency != 0);
+
+    #[cfg(target_arch = "riscv64")]
+    return Some(crate::arch::device_tree::TIMEBASE_FREQUENCY.load(Ordering::Relaxed));
+}
+
+/// Record an event in the journal of the current CPU
+pub fn record(kind: EventKind, a: usize, b: usize) {
+    let journal = match JOURNALS.get(crate::cpu_id()) {
+        Some(journal) => journal,
+        None => return,
+    };
+
+    // Reserve the entry first, so that an interrupt recording an event meanwhile, or another CPU
+    // if the context was moved since reading its ID, writes another one
+    let head = journal.head.fetch_add(1, Ordering::AcqRel);
+    let entry = &journal.entries[head % ENTRIES];
+    // Invalidate the entry while it is being written
+    entry.kind.store(0, Ordering::Relaxed);
+    entry.time.store(timestamp(), Ordering::Relaxed);
+    entry.a.store(a, Ordering::Relaxed);
+    entry.b.store(b, Ordering::Relaxed);
+    entry.kind.store(kind as usize, Ordering::Release);
+}
+
+/// Record lock contention, if `since` (a timestamp) is long enough ago
+pub fn contended(lock: Lock, since: u64) {
+    let waited = timestamp().wrapping_sub(since);
+    if waited >= CONTENTION_THRESHOLD {
+        record(EventKind::LockContention, lock as usize, waited as usize);
+    }
+}
+
+fn lock_name(lock: usize) -> &'static str {
+    match lock {
+        0 => "context switch",
+        _ => "unknown",
+    }
+}
+
+/// Print the journals of all CPUs, merged in timestamp order
+pub fn dump() {
+    // Entries of each journal are visited from the oldest to the newest
+    let mut cursors = [(0, 0); CPUS];
+    for (cursor, journal) in cursors.iter_mut().zip(JOURNALS.iter()) {
+        let head = journal.head.load(Ordering::Acquire);
+        *cursor = (head.saturating_sub(ENTRIES), head);
+    }
+
+    println!("JOURNAL:");
+    loop {
+        let next = cursors.iter().enumerate()
+            .filter(|(_, (next, end))| next < end)
+            .map(|(cpu, &(next, _))| (cpu, JOURNALS[cpu].entries[next % ENTRIES].time.load(Ordering::Relaxed)))
+            .min_by_key(|&(_, time)| time);
+        let (cpu, time) = match next {
+            Some(next) => next,
+            None => break,
+        };
+
+        let entry = &JOURNALS[cpu].entries[cursors[cpu].0 % ENTRIES];
+        cursors[cpu].0 += 1;
+
+        let (a, b) = (entry.a.load(Ordering::Relaxed), entry.b.load(Ordering::Relaxed));
+        match EventKind::from_raw(entry.kind.load(Ordering::Acquire)) {
+            Some(EventKind::ContextSwitch) => println!("  {:>20} CPU {}: switch {} -> {}", time, cpu, a, b),
+            Some(EventKind::Irq) => println!("  {:>20} CPU {}: IRQ {}", time, cpu, a),
+            Some(EventKind::Ipi) if b >= IPI_TARGET_CPU => println!("  {:>20} CPU {}: IPI {:#x} to CPU {}", time, cpu, a, b - IPI_TARGET_CPU),
+            Some(EventKind::Ipi) => println!("  {:>20} CPU {}: IPI {:#x} to {}", time, cpu, a, b),
+            Some(EventKind::LockContention) => println!("  {:>20} CPU {}: waited {} for {} lock", time, cpu, b, lock_name(a)),
+            None => (),
+        }
+    }
+}
@@ -0,0 +1,525 @@
+//! # Kexec
+//! Booting into another kernel without going through the firmware, on demand or when panicking.
+//!
+//! Userspace writes the kernel, the initfs and the environment to `kexec:` (see `scheme::kexec`),
+//! then loads them, which copies them into a region of physical memory laid out the way the
+//! bootloader lays out memory: the kernel, its stack, the environment, the RSDPs, the arguments,
+//! the memory map and the initfs. After them come the page tables to jump with (see
+//! `arch::kexec`), then memory the new kernel can use freely. The staged files are dropped once
+//! loaded.
+//!
+//! A kernel loaded on demand goes into memory allocated for it. It is given all memory, and booted
+//! into like rebooting (see `power::kexec`).
+//!
+//! A crash kernel goes into the region reserved while booting with `CRASHKERNEL=<MiB>`, which
+//! nothing else uses. It is booted into when panicking, after the other CPUs are stopped. It is
+//! only given that region and the memory below 1 MiB, which it needs to start the other CPUs. The
+//! rest of the memory of the crashed kernel is marked reserved, and listed in its environment, in
+//! hexadecimal, as `CRASH_MEMORY=<base>:<size>,...` for a capture environment to save it, along
+//! with the region of the kernel dump, if armed (see `dump`).
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{mem, ptr, str};
+
+use spin::Once;
+
+use crate::arch::kexec::{self as arch, Jump};
+use crate::arch::rmm::{for_each_ram_range, BootloaderMemoryEntry, BootloaderMemoryKind};
+use crate::elf::{program_header, Elf};
+use crate::log::{info, warn};
+use crate::memory::{allocate_frames, deallocate_frames, firmware, zone, Frame, PAGE_SIZE};
+use crate::paging::{PhysicalAddress, RmmA, RmmArch};
+use crate::sync::{Mutex, SleepMutex};
+use crate::syscall::error::*;
+
+/// Size of the stack of the new kernel
+const STACK_SIZE: usize = 128 * 1024;
+/// Memory the new kernel is given besides what it is loaded into, at least
+const MIN_FREE: usize = 16 * 1024 * 1024;
+
+/// The files written to `kexec:`, to load
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Blob {
+    Kernel,
+    Initfs,
+    Env,
+}
+
+struct Staging {
+    kernel: Vec<u8>,
+    initfs: Vec<u8>,
+    env: Vec<u8>,
+}
+
+impl Staging {
+    fn blob(&mut self, blob: Blob) -> &mut Vec<u8> {
+        match blob {
+            Blob::Kernel => &mut self.kernel,
+            Blob::Initfs => &mut self.initfs,
+            Blob::Env => &mut self.env,
+        }
+    }
+}
+
+/// A loaded kernel
+struct Image {
+    /// Physical base and size of the region it is laid out in
+    region: (usize, usize),
+    /// The frames of the region, if allocated rather than reserved
+    frames: Option<Frame>,
+    crash: bool,
+    jump: Jump,
+}
+
+/// The kernel to boot into
+enum Slot {
+    Empty,
+    /// Reserved by `load`, which lays out the kernel without holding the lock
+    Loading,
+    Loaded(Image),
+}
+
+impl Slot {
+    fn image(&self) -> Option<&Image> {
+        match self {
+            Slot::Loaded(image) => Some(image),
+            _ => None,
+        }
+    }
+}
+
+/// Where each part of a loaded kernel is, as physical base and size, the size of the kernel and
+/// the areas being page aligned, and the others not necessarily
+#[derive(Clone, Copy, Debug)]
+pub struct Layout {
+    pub region: (usize, usize),
+    pub kernel: (usize, usize),
+    pub stack: (usize, usize),
+    pub env: (usize, usize),
+    pub rsdps: (usize, usize),
+    /// A page, holding the arguments
+    pub args: usize,
+    pub areas: (usize, usize),
+    pub initfs: (usize, usize),
+    pub tables: (usize, usize),
+    pub free: (usize, usize),
+    pub bootstrap_entry: usize,
+}
+
+/// The region reserved for the crash kernel, as physical base and size
+static RESERVED: Once<(usize, usize)> = Once::new();
+/// Slept on, as appending and loading copy whole files
+static STAGING: SleepMutex<Staging> = SleepMutex::new(Staging {
+    kernel: Vec::new(),
+    initfs: Vec::new(),
+    env: Vec::new(),
+});
+static IMAGE: Mutex<Slot> = Mutex::new(Slot::Empty);
+/// Set once panicking, so that panicking again does not boot into the crash kernel twice
+static CRASHING: AtomicBool = AtomicBool::new(false);
+
+/// Hands out the frames of the page tables, from those set aside for them in the region
+struct RegionAllocator {
+    next: usize,
+    end: usize,
+}
+
+impl rmm::FrameAllocator for RegionAllocator {
+    unsafe fn allocate(&mut self, count: rmm::FrameCount) -> Option<rmm::PhysicalAddress> {
+        let size = count.data() * PAGE_SIZE;
+        if self.end - self.next < size {
+            return None;
+        }
+        // Zeroed when loading
+        let address = self.next;
+        self.next += size;
+        Some(rmm::PhysicalAddress::new(address))
+    }
+
+    unsafe fn free(&mut self, _address: rmm::PhysicalAddress, _count: rmm::FrameCount) {
+        // Freed with the region
+    }
+
+    unsafe fn usage(&self) -> rmm::FrameUsage {
+        rmm::FrameUsage::new(rmm::FrameCount::new(0), rmm::FrameCount::new(0))
+    }
+}
+
+fn page_align(size: usize) -> usize {
+    size.next_multiple_of(PAGE_SIZE)
+}
+
+/// Reserve the region of the crash kernel, if `CRASHKERNEL=<MiB>` is set. Called while booting,
+/// once frames can be allocated.
+pub fn reserve(env: &[u8]) {
+    let Some(mib) = str::from_utf8(env).unwrap_or("").lines().find_map(|line| line.strip_prefix("CRASHKERNEL=")) else {
+        return;
+    };
+    let Ok(mib) = mib.trim().parse::<usize>() else {
+        warn!("Invalid CRASHKERNEL={}", mib);
+        return;
+    };
+    let size = mib * 1024 * 1024;
+    match allocate_frames(size / PAGE_SIZE) {
+        Some(frame) => {
+            let base = frame.start_address().data();
+            info!("Crash kernel region: {:X}:{:X}", base, base + size);
+            RESERVED.call_once(|| (base, size));
+        }
+        None => warn!("Failed to reserve {} MiB for the crash kernel", mib),
+    }
+}
+
+/// The region reserved for the crash kernel, as physical base and size
+pub fn reserved() -> Option<(usize, usize)> {
+    RESERVED.get().copied()
+}
+
+/// Append `data` to the staged `blob`
+pub fn append(blob: Blob, data: &[u8]) -> Result<()> {
+    let mut staging = STAGING.lock("kexec::append")?;
+    let blob = staging.blob(blob);
+    blob.try_reserve(data.len()).map_err(|_| Error::new(ENOMEM))?;
+    blob.extend_from_slice(data);
+    Ok(())
+}
+
+/// Drop the staged `blob`
+pub fn clear(blob: Blob) -> Result<()> {
+    *STAGING.lock("kexec::clear")?.blob(blob) = Vec::new();
+    Ok(())
+}
+
+/// Size of the staged `blob`
+pub fn staged(blob: Blob) -> Result<usize> {
+    Ok(STAGING.lock("kexec::staged")?.blob(blob).len())
+}
+
+/// The physical region of the loaded kernel, and whether it is a crash kernel, if one is loaded
+pub fn loaded() -> Option<((usize, usize), bool)> {
+    IMAGE.lock().image().map(|image| (image.region, image.crash))
+}
+
+/// Check that `data` is a kernel that can be loaded like the bootloader does, as its file mapped
+/// at `KERNEL_OFFSET`, returning its entry point and the page aligned size it needs
+fn check_kernel(data: &[u8]) -> Result<(usize, usize)> {
+    let elf = Elf::from(data).map_err(|_| Error::new(ENOEXEC))?;
+    let headers_end = elf
+        .program_header_count()
+        .checked_mul(elf.program_headers_size())
+        .and_then(|size| size.checked_add(elf.program_headers()));
+    if elf.program_headers() % mem::align_of::<program_header::ProgramHeader>() != 0
+        || elf.program_headers_size() < mem::size_of::<program_header::ProgramHeader>()
+        || headers_end.map_or(true, |end| end > data.len())
+    {
+        return Err(Error::new(ENOEXEC));
+    }
+
+    let mut size = data.len();
+    for segment in elf.segments().filter(|segment| segment.p_type == program_header::PT_LOAD) {
+        let offset = (segment.p_vaddr as usize).checked_sub(crate::KERNEL_OFFSET);
+        let file_end = (segment.p_offset as usize).checked_add(segment.p_filesz as usize);
+        if offset != Some(segment.p_offset as usize)
+            || segment.p_filesz > segment.p_memsz
+            || file_end.map_or(true, |end| end > data.len())
+        {
+            return Err(Error::new(ENOEXEC));
+        }
+        size = size.max(segment.p_offset as usize + segment.p_memsz as usize);
+    }
+
+    let entry = elf.entry();
+    if !(crate::KERNEL_OFFSET..crate::KERNEL_OFFSET + size).contains(&entry) {
+        return Err(Error::new(ENOEXEC));
+    }
+    Ok((entry, page_align(size)))
+}
+
+/// The memory of the running kernel, as sorted ranges that do not touch each other
+fn ram_ranges() -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    for_each_ram_range(|start, end| ranges.push((start, end)));
+    zone::for_each_frame(|frame, _| ranges.push((frame.data(), frame.data() + PAGE_SIZE)));
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// `ranges` without `start..end`
+fn subtract(ranges: &[(usize, usize)], (start, end): (usize, usize)) -> Vec<(usize, usize)> {
+    let mut result = Vec::with_capacity(ranges.len() + 1);
+    for &(range_start, range_end) in ranges {
+        if range_start < start {
+            result.push((range_start, range_end.min(start)));
+        }
+        if range_end > end {
+            result.push((range_start.max(end), range_end));
+        }
+    }
+    result
+}
+
+/// Lay the parts of a kernel out from `base`, with `tables` frames for the page tables, returning
+/// where they end
+fn lay_out(base: usize, kernel: usize, env: usize, rsdps: usize, areas: usize, initfs: usize, tables: usize) -> (Layout, usize) {
+    let mut next = base;
+    let mut part = |size: usize| {
+        let start = next;
+        next += page_align(size);
+        (start, size)
+    };
+    let kernel = part(kernel);
+    let stack = part(STACK_SIZE);
+    let env = part(env);
+    let rsdps = part(rsdps);
+    let args = part(PAGE_SIZE).0;
+    let areas = part(areas);
+    let initfs = part(initfs);
+    let tables = part(tables * PAGE_SIZE);
+    let layout = Layout {
+        region: (base, 0),
+        kernel,
+        stack,
+        env,
+        rsdps,
+        args,
+        areas,
+        initfs,
+        tables,
+        free: (next, 0),
+        bootstrap_entry: 0,
+    };
+    (layout, next)
+}
+
+/// The areas given to the new kernel: `old`, the memory of the running kernel outside the region,
+/// the firmware regions, and the free memory of the region
+fn areas(old: &[(usize, usize)], crash: bool, free: (usize, usize)) -> Vec<BootloaderMemoryEntry> {
+    let entry = |base: usize, size: usize, kind| BootloaderMemoryEntry {
+        base: base as u64,
+        size: size as u64,
+        kind,
+    };
+
+    let mut areas = Vec::new();
+    for &(start, end) in old {
+        if !crash {
+            areas.push(entry(start, end - start, BootloaderMemoryKind::Free));
+            continue;
+        }
+        // Kept for the crash kernel to start the other CPUs with, as the bootloader does
+        if start < zone::LOW_END {
+            areas.push(entry(start, end.min(zone::LOW_END) - start, BootloaderMemoryKind::Free));
+        }
+        if end > zone::LOW_END {
+            let start = start.max(zone::LOW_END);
+            areas.push(entry(start, end - start, BootloaderMemoryKind::Reserved));
+        }
+    }
+    for region in firmware::regions() {
+        let kind = match region.kind {
+            firmware::Kind::Reclaim => BootloaderMemoryKind::Reclaim,
+            firmware::Kind::Reserved => BootloaderMemoryKind::Reserved,
+        };
+        areas.push(BootloaderMemoryEntry {
+            base: region.base,
+            size: region.size,
+            kind,
+        });
+    }
+    areas.push(entry(free.0, free.1, BootloaderMemoryKind::Free));
+    areas.sort_unstable_by_key(|area| area.base);
+    areas
+}
+
+/// The line listing the memory of the crashed kernel, for the environment of the crash kernel
+fn crash_memory(old: &[(usize, usize)]) -> String {
+    let mut line = String::from("CRASH_MEMORY=");
+    for (i, &(start, end)) in old.iter().enumerate() {
+        let _ = write!(line, "{}{:X}:{:X}", if i == 0 { "" } else { "," }, start, end - start);
+    }
+    line.push('\n');
+    line
+}
+
+unsafe fn copy_to(address: usize, data: &[u8]) {
+    let dst = RmmA::phys_to_virt(PhysicalAddress::new(address)).data() as *mut u8;
+    ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len());
+}
+
+/// Load the staged kernel, initfs and environment, as a crash kernel if `crash`, with
+/// `bootstrap_entry` as the entry point of the initfs
+pub fn load(bootstrap_entry: usize, crash: bool) -> Result<()> {
+    let mut staging = STAGING.lock("kexec::load")?;
+    {
+        let mut slot = IMAGE.lock();
+        if !matches!(*slot, Slot::Empty) {
+            return Err(Error::new(EBUSY));
+        }
+        *slot = Slot::Loading;
+    }
+
+    let result = load_staged(&mut staging, bootstrap_entry, crash);
+    let mut slot = IMAGE.lock();
+    match result {
+        Ok(image) => {
+            *slot = Slot::Loaded(image);
+            Ok(())
+        }
+        Err(err) => {
+            *slot = Slot::Empty;
+            Err(err)
+        }
+    }
+}
+
+/// Lay out the staged files in a new region, or the reserved one if `crash`
+fn load_staged(staging: &mut Staging, bootstrap_entry: usize, crash: bool) -> Result<Image> {
+    let (entry, kernel_size) = check_kernel(&staging.kernel)?;
+    if staging.initfs.is_empty() {
+        return Err(Error::new(EINVAL));
+    }
+
+    let ram = ram_ranges();
+    let (rsdps_base, rsdps_size) = arch::rsdps();
+    // Each range of memory may be split around the region and at 1 MiB
+    let areas_max = 2 * ram.len() + firmware::MAX_REGIONS + 2;
+    let areas_size = areas_max * mem::size_of::<BootloaderMemoryEntry>();
+
+    let mut env = staging.env.clone();
+    if !env.is_empty() && !env.ends_with(b"\n") {
+        env.push(b'\n');
+    }
+
+    let (region, frames) = if crash {
+        (reserved().ok_or(Error::new(ENOMEM))?, None)
+    } else {
+        let (_, end) = lay_out(0, kernel_size, env.len(), rsdps_size, areas_size, staging.initfs.len(), 0);
+        let tables = arch::table_frames(kernel_size, 2 * (end + MIN_FREE));
+        let size = end + tables * PAGE_SIZE + MIN_FREE;
+        let frame = allocate_frames(size / PAGE_SIZE).ok_or(Error::new(ENOMEM))?;
+        ((frame.start_address().data(), size), Some(frame))
+    };
+    let free_region = || {
+        if let Some(frame) = &frames {
+            deallocate_frames(frame.clone(), region.1 / PAGE_SIZE);
+        }
+    };
+
+    let old = subtract(&ram, (region.0, region.0 + region.1));
+    if crash {
+        env.extend_from_slice(crash_memory(&old).as_bytes());
+        if let Some(line) = crate::dump::crash_env() {
+            env.extend_from_slice(line.as_bytes());
+        }
+    }
+    let tables = arch::table_frames(kernel_size, region.1);
+    let (mut layout, end) = lay_out(region.0, kernel_size, env.len(), rsdps_size, areas_size, staging.initfs.len(), tables);
+    if end + MIN_FREE > region.0 + region.1 {
+        free_region();
+        return Err(Error::new(ENOMEM));
+    }
+    layout.region = region;
+    layout.free = (end, region.0 + region.1 - end);
+    layout.bootstrap_entry = bootstrap_entry;
+
+    let areas = areas(&old, crash, layout.free);
+    layout.areas.1 = areas.len() * mem::size_of::<BootloaderMemoryEntry>();
+
+    let jump = unsafe {
+        // Zeroes the bss of the kernel, and the memory the page tables are built in
+        ptr::write_bytes(RmmA::phys_to_virt(PhysicalAddress::new(region.0)).data() as *mut u8, 0, end - region.0);
+        copy_to(layout.kernel.0, &staging.kernel);
+        copy_to(layout.env.0, &env);
+        if rsdps_size > 0 {
+            let rsdps = RmmA::phys_to_virt(PhysicalAddress::new(rsdps_base)).data() as *const u8;
+            ptr::copy_nonoverlapping(rsdps, RmmA::phys_to_virt(PhysicalAddress::new(layout.rsdps.0)).data() as *mut u8, rsdps_size);
+        } else {
+            layout.rsdps = (0, 0);
+        }
+        ptr::copy_nonoverlapping(
+            areas.as_ptr() as *const u8,
+            RmmA::phys_to_virt(PhysicalAddress::new(layout.areas.0)).data() as *mut u8,
+            layout.areas.1,
+        );
+        copy_to(layout.initfs.0, &staging.initfs);
+
+        let allocator = RegionAllocator {
+            next: layout.tables.0,
+            end: layout.tables.0 + layout.tables.1,
+        };
+        arch::prepare(&layout, entry, allocator)
+    };
+    let Some(jump) = jump else {
+        free_region();
+        return Err(Error::new(ENOMEM));
+    };
+
+    info!(
+        "Loaded {} at {:X}:{:X}, entry {:X}",
+        if crash { "crash kernel" } else { "kernel" },
+        region.0,
+        region.0 + region.1,
+        entry
+    );
+    staging.kernel = Vec::new();
+    staging.initfs = Vec::new();
+    staging.env = Vec::new();
+    Ok(Image { region, frames, crash, jump })
+}
+
+/// Unload the loaded kernel, freeing its region unless reserved
+pub fn unload() -> Result<()> {
+    let image = {
+        let mut slot = IMAGE.lock();
+        match mem::replace(&mut *slot, Slot::Empty) {
+            Slot::Loaded(image) => image,
+            Slot::Loading => {
+                *slot = Slot::Loading;
+                return Err(Error::new(EBUSY));
+            }
+            Slot::Empty => return Err(Error::new(ENOENT)),
+        }
+    };
+    if let Some(frames) = image.frames {
+        deallocate_frames(frames, image.region.1 / PAGE_SIZE);
+    }
+    Ok(())
+}
+
+/// Boot into the crash kernel, stopping the other CPUs first. Called when panicking, and only
+/// returns if no crash kernel is loaded.
+pub fn crash() {
+    if CRASHING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    // Loading may have been interrupted
+    let Some(jump) = IMAGE.try_lock().and_then(|slot| {
+        slot.image().filter(|image| image.crash).map(|image| image.jump)
+    }) else {
+        return;
+    };
+
+    println!("Booting into the crash kernel");
+    arch::stop_other_cpus();
+    unsafe { arch::jump(&jump) }
+}
+
+/// Boot into the kernel loaded on demand, with the other CPUs stopped. Only returns if none is
+/// loaded.
+pub unsafe fn exec() -> Error {
+    let Some(jump) = IMAGE.lock().image().filter(|image| !image.crash).map(|image| image.jump) else {
+        return Error::new(ENOENT);
+    };
+    arch::jump(&jump)
+}
@@ -57,6 +57,7 @@
 #![feature(slice_ptr_get, slice_ptr_len)]
 #![feature(sync_unsafe_cell)]
 #![feature(thread_local)]
+#![cfg_attr(feature = "lockdep", feature(const_caller_location))]
 #![no_std]
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -75,6 +76,7 @@ extern crate spin;
 #[cfg(feature = "slab")]
 extern crate slab_allocator;
 
+use alloc::sync::Arc;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::scheme::SchemeNamespace;
@@ -99,25 +101,61 @@ pub mod allocator;
 #[cfg(all(feature = "acpi", any(target_arch = "x86", target_arch = "x86_64")))]
 mod acpi;
 
+/// Security audit records
+pub mod audit;
+
+/// Boot ID
+pub mod boot_id;
+
 /// Context management
 pub mod context;
 
+/// CPU frequency scaling
+#[cfg(target_arch = "x86_64")]
+pub mod cpufreq;
+
 /// Debugger
 pub mod debugger;
 
 /// Architecture-independent devices
 pub mod devices;
 
+/// Kernel dumps
+pub mod dump;
+
 /// ELF file parsing
 #[cfg(not(feature="doc"))]
 pub mod elf;
 
+/// Entropy pool
+pub mod entropy;
+
 /// Event handling
 pub mod event;
 
 /// External functions
 pub mod externs;
 
+/// Hibernation
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+pub mod hibernate;
+
+/// Idle states
+pub mod idle;
+
+/// CPU hotplug
+pub mod hotplug;
+
+/// Booting into another kernel
+#[cfg(target_arch = "x86_64")]
+pub mod kexec;
+
+/// Lockup detection
+pub mod lockup;
+
+/// Per-CPU event journal
+pub mod journal;
+
 /// Logging
 pub mod log;
 
@@ -128,25 +166,49 @@ pub mod memory;
 #[cfg(not(any(feature="doc", test)))]
 pub mod panic;
 
+/// Performance counters
+pub mod perf;
+
+/// Power management
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+pub mod power;
+
+/// Sampling profiler
+#[cfg(target_arch = "x86_64")]
+pub mod profile;
+
 /// Process tracing
 pub mod ptrace;
 
 /// Schemes, filesystem handlers
 pub mod scheme;
 
+/// Kernel symbols
+pub mod symbols;
+
 /// Synchronization primitives
 pub mod sync;
 
 /// Syscall handlers
 pub mod syscall;
 
+/// Thermal zones
+#[cfg(target_arch = "x86_64")]
+pub mod thermal;
+
 /// Time
 pub mod time;
 
+/// Tracepoints and function tracing
+pub mod trace;
+
 /// Tests
 #[cfg(test)]
 pub mod tests;
 
+/// Watchdog
+pub mod watchdog;
+
 #[global_allocator]
 static ALLOCATOR: allocator::Allocator = allocator::Allocator;
 
@@ -181,6 +243,8 @@ pub extern "C" fn userspace_init() {
 pub struct Bootstrap {
     pub base: crate::memory::Frame,
     pub page_count: usize,
+    /// Exact size of the bootstrap image in bytes, which need not be page aligned
+    pub size: usize,
     pub entry: usize,
     pub env: &'static [u8],
 }
@@ -191,6 +255,15 @@ pub fn kmain(cpus: usize, bootstrap: Bootstrap) -> ! {
     CPU_ID.store(0, Ordering::SeqCst);
     CPU_COUNT.store(cpus, Ordering::SeqCst);
 
+    boot_id::init();
+
+    // Every CPU has its thread-local storage by now
+    #[cfg(feature = "lockdep")]
+    sync::lockdep::enable();
+
+    // Must happen before the first address space is created, which copies the kernel mappings
+    context::kstack::init();
+
     //Initialize the first context, stored in kernel/src/context/mod.rs
     context::init();
 
@@ -200,27 +273,32 @@ pub fn kmain(cpus: usize, bootstrap: Bootstrap) -> ! {
 
     BOOTSTRAP.call_once(|| bootstrap);
 
-    match context::contexts_mut().spawn(userspace_init) {
+    match context::contexts_mut().and_then(|mut contexts| contexts.spawn(userspace_init).map(Arc::clone)) {
         Ok(context_lock) => {
             let mut context = context_lock.write();
             context.rns = SchemeNamespace::from(1);
             context.ens = SchemeNamespace::from(1);
             context.status = context::Status::Runnable;
             context.name = "bootstrap".into();
+            context::INIT_ID.store(context.id, Ordering::SeqCst);
         },
         Err(err) => {
             panic!("failed to spawn userspace_init: {:?}", err);
         }
     }
 
+    // Detect CPUs that stop switching contexts
+    lockup::init();
+
     loop {
+        context::reclaim();
         unsafe {
             interrupt::disable();
             if context::switch() {
                 interrupt::enable_and_nop();
             } else {
-                // Enable interrupts, then halt CPU (to save power) until the next interrupt is actually fired.
-                interrupt::enable_and_halt();
+                // Wait for the next interrupt in an idle state, with interrupts enabled
+                idle::idle();
             }
         }
     }
@@ -238,13 +316,16 @@ pub fn kmain_ap(id: usize) -> ! {
         info!("AP {}: {:?}", id, pid);
 
         loop {
+            context::reclaim();
             unsafe {
                 interrupt::disable();
-                if context::switch() {
+                if hotplug::is_offline(id) {
+                    hotplug::park();
+                } else if context::switch() {
                     interrupt::enable_and_nop();
                 } else {
-                    // Enable interrupts, then halt CPU (to save power) until the next interrupt is actually fired.
-                    interrupt::enable_and_halt();
+                    // Wait for the next interrupt in an idle state, with interrupts enabled
+                    idle::idle();
                 }
             }
         }
@@ -263,6 +344,21 @@ pub fn kmain_ap(id: usize) -> ! {
 /// Allow exception handlers to send signal to arch-independant kernel
 #[no_mangle]
 pub extern fn ksignal(signal: usize) {
+    ksignal_info(syscall::abi::SigInfo::kernel(signal));
+}
+
+/// Like `ksignal`, for faults that have a code and faulting address to report
+#[no_mangle]
+pub extern fn ksignal_fault(signal: usize, code: i32, addr: usize) {
+    ksignal_info(syscall::abi::SigInfo {
+        si_code: code,
+        si_addr: addr,
+        ..syscall::abi::SigInfo::kernel(signal)
+    });
+}
+
+fn ksignal_info(info: syscall::abi::SigInfo) {
+    let signal = info.si_signo as usize;
     info!("SIGNAL {}, CPU {}, PID {:?}", signal, cpu_id(), context::context_id());
     {
         let contexts = context::contexts();
@@ -272,12 +368,17 @@ pub extern fn ksignal(signal: usize) {
         }
     }
 
-    // Try running kill(getpid(), signal), but fallback to exiting
-    syscall::getpid()
-        .and_then(|pid| syscall::kill(pid, signal).map(|_| ()))
-        .unwrap_or_else(|_| {
-            syscall::exit(signal & 0x7F);
-        });
+    // Queue the signal for the faulting thread itself (not its whole thread group), but fallback
+    // to exiting
+    match context::current() {
+        Ok(context_lock) if signal < 0x7F => {
+            context_lock.write().pending.push(info);
+
+            // Switch to ensure delivery to self
+            unsafe { context::switch(); }
+        },
+        _ => syscall::exit(signal & 0x7F),
+    }
 }
 
 // TODO: Use this macro on aarch64 too.
@@ -0,0 +1,183 @@
+//! # Lockup detection
+//! A CPU is in a hard lockup when it no longer takes timer interrupts, as when it spins with
+//! interrupts disabled, and in a soft lockup when it takes them but no longer switches to other
+//! contexts.
+//!
+//! Each CPU has a watchdog context pinned to it, which records the time every `SOFT_PERIOD`, and
+//! the timer interrupt checks that it did within `SOFT_THRESHOLD`. The NMI watchdog of the
+//! architecture, if any, calls `nmi` periodically on each CPU, which checks that the timer
+//! interrupt ran within `HARD_THRESHOLD`. A lockup is reported once, with the backtrace of the
+//! stuck CPU, and again if the CPU recovers and locks up later. Offline CPUs are not checked, and
+//! no CPU is while paused, as when stopped by a debugger.
+
+use alloc::format;
+use alloc::sync::Arc;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::context::{self, ContextId, Label};
+use crate::hotplug;
+use crate::log::{error, warn};
+use crate::time::{self, NANOS_PER_SEC};
+
+/// Number of CPUs checked
+const CPUS: usize = 32;
+
+/// Nanoseconds between runs of the watchdog contexts
+const SOFT_PERIOD: u128 = 4 * NANOS_PER_SEC;
+/// Nanoseconds without the watchdog context running that make a soft lockup
+const SOFT_THRESHOLD: u64 = 20 * NANOS_PER_SEC as u64;
+/// Nanoseconds without a timer interrupt that make a hard lockup
+const HARD_THRESHOLD: u64 = 10 * NANOS_PER_SEC as u64;
+
+/// Timer ticks between checks for soft lockups, about half a second
+const CHECK_TICKS: usize = 125;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NONE: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const FALSE: AtomicBool = AtomicBool::new(false);
+
+/// The watchdog context of each CPU, zero if none
+static WATCHDOGS: [AtomicUsize; CPUS] = [NONE; CPUS];
+/// The monotonic clock when the watchdog context of each CPU last ran
+static TOUCHED: [AtomicU64; CPUS] = [ZERO; CPUS];
+static SOFT_REPORTED: [AtomicBool; CPUS] = [FALSE; CPUS];
+
+/// Timer interrupts taken by each CPU, those seen by its last NMI, and for how long they have not
+/// changed, in nanoseconds
+static TICKS: [AtomicUsize; CPUS] = [NONE; CPUS];
+static NMI_TICKS: [AtomicUsize; CPUS] = [NONE; CPUS];
+static STALLED: [AtomicU64; CPUS] = [ZERO; CPUS];
+static HARD_REPORTED: [AtomicBool; CPUS] = [FALSE; CPUS];
+
+/// Set while CPUs are stopped on purpose
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+#[thread_local]
+static CHECK_COUNTDOWN: Cell<usize> = Cell::new(CHECK_TICKS);
+
+fn now() -> u64 {
+    time::monotonic() as u64
+}
+
+/// Record that the watchdog context of the current CPU ran, every `SOFT_PERIOD`
+extern "C" fn watchdog() {
+    let cpu_id = crate::cpu_id();
+    loop {
+        if let Some(touched) = TOUCHED.get(cpu_id) {
+            touched.store(now(), Ordering::Relaxed);
+        }
+
+        let Ok(context_lock) = context::current() else {
+            return;
+        };
+        {
+            let mut context = context_lock.write();
+            context.wake = Some(time::monotonic() + SOFT_PERIOD);
+            context.block("lockup watchdog");
+        }
+        unsafe { context::switch() };
+    }
+}
+
+/// Spawn the watchdog context of each CPU
+pub fn init() {
+    for cpu_id in 0..crate::cpu_count().min(CPUS) {
+        let label = Label::parse(&format!("kernel/watchdog/{}", cpu_id)).unwrap_or_default();
+        TOUCHED[cpu_id].store(now(), Ordering::Relaxed);
+        match context::contexts_mut().and_then(|mut contexts| contexts.spawn(watchdog).map(Arc::clone)) {
+            Ok(context_lock) => {
+                let mut context = context_lock.write();
+                context.sched_affinity = Some(cpu_id);
+                context.status = context::Status::Runnable;
+                context.name = label;
+                WATCHDOGS[cpu_id].store(context.id.into(), Ordering::Relaxed);
+            }
+            Err(err) => warn!("Failed to spawn the watchdog context of CPU {}: {:?}", cpu_id, err),
+        }
+    }
+}
+
+/// Whether `id` is the watchdog context of a CPU, which may stay pinned to it while offline
+pub fn is_watchdog(id: ContextId) -> bool {
+    let id = id.into();
+    WATCHDOGS.iter().any(|watchdog| watchdog.load(Ordering::Relaxed) == id)
+}
+
+/// Stop checking for lockups, while CPUs are stopped on purpose
+pub fn pause() {
+    PAUSED.store(true, Ordering::SeqCst);
+}
+
+/// Check for lockups again, counting from now
+pub fn resume() {
+    let now = now();
+    for (touched, stalled) in TOUCHED.iter().zip(&STALLED) {
+        touched.store(now, Ordering::Relaxed);
+        stalled.store(0, Ordering::Relaxed);
+    }
+    PAUSED.store(false, Ordering::SeqCst);
+}
+
+/// Count a timer interrupt on the current CPU, and check for a soft lockup every `CHECK_TICKS`
+pub fn tick() {
+    let cpu_id = crate::cpu_id();
+    let Some(ticks) = TICKS.get(cpu_id) else {
+        return;
+    };
+    ticks.fetch_add(1, Ordering::Relaxed);
+
+    let countdown = CHECK_COUNTDOWN.get();
+    if countdown > 1 {
+        CHECK_COUNTDOWN.set(countdown - 1);
+        return;
+    }
+    CHECK_COUNTDOWN.set(CHECK_TICKS);
+    if WATCHDOGS[cpu_id].load(Ordering::Relaxed) == 0 || hotplug::is_offline(cpu_id) || PAUSED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let stuck = now().saturating_sub(TOUCHED[cpu_id].load(Ordering::Relaxed));
+    if stuck < SOFT_THRESHOLD {
+        SOFT_REPORTED[cpu_id].store(false, Ordering::Relaxed);
+    } else if !SOFT_REPORTED[cpu_id].swap(true, Ordering::Relaxed) {
+        error!(
+            "Soft lockup on CPU {}: stuck for {} s in context {}",
+            cpu_id,
+            stuck / NANOS_PER_SEC as u64,
+            context::context_id().into()
+        );
+        unsafe { crate::interrupt::stack_trace() };
+    }
+}
+
+/// Check for a hard lockup of the current CPU, called by the NMI watchdog every `period`
+/// nanoseconds. If found, `dump` is called to print the interrupted registers before the backtrace.
+pub fn nmi(period: u64, dump: impl FnOnce()) {
+    let cpu_id = crate::cpu_id();
+    let Some(ticks) = TICKS.get(cpu_id) else {
+        return;
+    };
+    let ticks = ticks.load(Ordering::Relaxed);
+    if NMI_TICKS[cpu_id].swap(ticks, Ordering::Relaxed) != ticks || hotplug::is_offline(cpu_id) || PAUSED.load(Ordering::Relaxed) {
+        STALLED[cpu_id].store(0, Ordering::Relaxed);
+        HARD_REPORTED[cpu_id].store(false, Ordering::Relaxed);
+        return;
+    }
+    let stalled = STALLED[cpu_id].fetch_add(period, Ordering::Relaxed) + period;
+    if stalled < HARD_THRESHOLD || HARD_REPORTED[cpu_id].swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    error!(
+        "Hard lockup on CPU {}: no timer interrupt for {} s in context {}",
+        cpu_id,
+        stalled / NANOS_PER_SEC as u64,
+        context::context_id().into()
+    );
+    dump();
+    unsafe { crate::interrupt::stack_trace() };
+}
@@ -1,37 +1,517 @@
-use alloc::collections::VecDeque;
-use core::sync::atomic::{AtomicBool, Ordering};
-use spin::Mutex;
+//! # Kernel log
+//! Kernel and `debug:` output is kept in `KMSG`, a fixed size ring of records, each a line with a
+//! sequence number, the monotonic time it started at, a syslog level and a facility. The oldest
+//! records are dropped to make room for new ones, so `sys:log` and `kmsg:` can read back the
+//! output of early boot as long as it was not overwritten. Consoles print the records from the
+//! ring as they are completed, with the time they were logged at.
+//!
+//! Records of the `log` crate are filtered by their target, the module they come from, with the
+//! level of the longest matching filter, or the default one. Filters are written like
+//! `info,acpi=debug,scheme::irq=off`, where the `kernel::` prefix of targets can be left out, and
+//! are set at boot by `LOG` in the environment, and later through `kmsg:filter`.
 
-pub static LOG: Mutex<Option<Log>> = Mutex::new(None);
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::str;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use log::LevelFilter;
 
-pub fn init() {
-    *LOG.lock() = Some(Log::new(1024 * 1024));
+use crate::sync::{Mutex, RwLock};
+
+pub static KMSG: Mutex<Kmsg> = Mutex::new(Kmsg::new());
+
+bitflags! {
+    /// Kernel output sinks. Output is written to every sink that is ready at the time, so that
+    /// early messages show up regardless of which one is being watched.
+    pub struct Sinks: usize {
+        const MEMORY = 1 << 0;
+        const DISPLAY = 1 << 1;
+        const SERIAL = 1 << 2;
+        const LPSS = 1 << 3;
+        const QEMU = 1 << 4;
+        const SYSTEM76_EC = 1 << 5;
+        const VIRTIO = 1 << 6;
+    }
+}
+
+static READY_SINKS: AtomicUsize = AtomicUsize::new(Sinks::MEMORY.bits());
+
+/// Mark `sinks` as ready (or no longer ready) to receive output
+pub fn set_sinks_ready(sinks: Sinks, ready: bool) {
+    if ready {
+        READY_SINKS.fetch_or(sinks.bits(), Ordering::SeqCst);
+    } else {
+        READY_SINKS.fetch_and(!sinks.bits(), Ordering::SeqCst);
+    }
+}
+
+pub fn ready_sinks() -> Sinks {
+    Sinks::from_bits_truncate(READY_SINKS.load(Ordering::SeqCst))
+}
+
+// Syslog levels
+pub const LEVEL_EMERG: u8 = 0;
+pub const LEVEL_ALERT: u8 = 1;
+pub const LEVEL_CRIT: u8 = 2;
+pub const LEVEL_ERR: u8 = 3;
+pub const LEVEL_WARNING: u8 = 4;
+pub const LEVEL_NOTICE: u8 = 5;
+pub const LEVEL_INFO: u8 = 6;
+pub const LEVEL_DEBUG: u8 = 7;
+
+// Syslog facilities
+pub const FACILITY_KERNEL: u8 = 0;
+pub const FACILITY_USER: u8 = 1;
+
+/// The syslog level of a record of the `log` crate
+pub fn syslog_level(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => LEVEL_ERR,
+        log::Level::Warn => LEVEL_WARNING,
+        log::Level::Info => LEVEL_INFO,
+        log::Level::Debug | log::Level::Trace => LEVEL_DEBUG,
+    }
+}
+
+/// Size of the ring, in bytes
+const KMSG_SIZE: usize = 256 * 1024;
+
+/// Longest text of a record, longer lines are split
+pub const LINE_MAX: usize = 1024;
+
+/// Size of the header of a record: the sequence number, time, level, facility and length
+const HEADER_SIZE: usize = 20;
+
+/// Longest prefix of a line printed on the consoles
+const PREFIX_MAX: usize = 32;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub seq: u64,
+    /// The monotonic clock when the record was started, in nanoseconds
+    pub time: u64,
+    pub level: u8,
+    pub facility: u8,
+    /// Length of the text
+    pub len: usize,
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.time.to_le_bytes());
+        bytes[16] = self.level;
+        bytes[17] = self.facility;
+        bytes[18..20].copy_from_slice(&(self.len as u16).to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; HEADER_SIZE]) -> Header {
+        let u64_at = |offset: usize| {
+            let mut value = [0; 8];
+            value.copy_from_slice(&bytes[offset..offset + 8]);
+            u64::from_le_bytes(value)
+        };
+        Header {
+            seq: u64_at(0),
+            time: u64_at(8),
+            level: bytes[16],
+            facility: bytes[17],
+            len: usize::from(u16::from_le_bytes([bytes[18], bytes[19]])),
+        }
+    }
+}
+
+/// Where a reader of the ring is, as the next record to read and its position
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cursor {
+    pub seq: u64,
+    pos: usize,
+}
+
+/// A line formatted for the consoles, or for `sys:log`
+pub struct Line {
+    data: [u8; PREFIX_MAX + LINE_MAX + 1],
+    len: usize,
 }
 
-pub struct Log {
-    data: VecDeque<u8>,
-    size: usize,
+impl Line {
+    pub const fn new() -> Line {
+        Line {
+            data: [0; PREFIX_MAX + LINE_MAX + 1],
+            len: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let count = bytes.len().min(self.data.len() - self.len);
+        self.data[self.len..self.len + count].copy_from_slice(&bytes[..count]);
+        self.len += count;
+    }
 }
 
-impl Log {
-    pub fn new(size: usize) -> Log {
-        Log {
-            data: VecDeque::with_capacity(size),
-            size
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+pub struct Kmsg {
+    data: [u8; KMSG_SIZE],
+    /// Positions of the oldest record, and after the newest. They only grow, and are taken modulo
+    /// the size of the ring to index it.
+    head: usize,
+    tail: usize,
+    /// Sequence numbers of the oldest record, and of the next one
+    first_seq: u64,
+    next_seq: u64,
+    /// The line being written, until its end
+    line: [u8; LINE_MAX],
+    line_len: usize,
+    line_time: u64,
+    line_level: u8,
+    line_facility: u8,
+    /// Time of the last record, used when the clock cannot be read
+    last_time: u64,
+    /// The next record to print on the consoles
+    console: Cursor,
+    /// Bytes of the line being written already printed on the consoles, and its sequence number
+    console_partial: (u64, usize),
+}
+
+impl Kmsg {
+    const fn new() -> Kmsg {
+        Kmsg {
+            data: [0; KMSG_SIZE],
+            head: 0,
+            tail: 0,
+            first_seq: 0,
+            next_seq: 0,
+            line: [0; LINE_MAX],
+            line_len: 0,
+            line_time: 0,
+            line_level: LEVEL_INFO,
+            line_facility: FACILITY_KERNEL,
+            last_time: 0,
+            console: Cursor { seq: 0, pos: 0 },
+            console_partial: (0, 0),
+        }
+    }
+
+    pub fn first_seq(&self) -> u64 {
+        self.first_seq
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    fn copy_in(&mut self, pos: usize, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.data[(pos + i) % KMSG_SIZE] = byte;
+        }
+    }
+
+    fn copy_out(&self, pos: usize, bytes: &mut [u8]) {
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.data[(pos + i) % KMSG_SIZE];
+        }
+    }
+
+    fn header(&self, pos: usize) -> Header {
+        let mut bytes = [0; HEADER_SIZE];
+        self.copy_out(pos, &mut bytes);
+        Header::from_bytes(&bytes)
+    }
+
+    /// Append the line being written as a record, dropping the oldest ones to make room
+    fn commit(&mut self) {
+        let size = HEADER_SIZE + self.line_len;
+        while self.tail + size - self.head > KMSG_SIZE {
+            self.head += HEADER_SIZE + self.header(self.head).len;
+            self.first_seq += 1;
+        }
+        let header = Header {
+            seq: self.next_seq,
+            time: self.line_time,
+            level: self.line_level,
+            facility: self.line_facility,
+            len: self.line_len,
+        };
+        self.copy_in(self.tail, &header.to_bytes());
+        let line = self.line;
+        self.copy_in(self.tail + HEADER_SIZE, &line[..self.line_len]);
+        self.tail += size;
+        self.next_seq += 1;
+        self.line_len = 0;
+    }
+
+    /// Write `buf` with `level` and `facility`, which apply to the lines it starts. Each line is
+    /// added to the ring once it ends.
+    pub fn write(&mut self, level: u8, facility: u8, buf: &[u8]) {
+        // Output of another kind ends the line
+        if self.line_len > 0 && (self.line_level, self.line_facility) != (level, facility) {
+            self.commit();
+        }
+        for &byte in buf {
+            if self.line_len == 0 {
+                // The clock may be locked by the CPU logging, as when switching sources
+                if let Some(time) = crate::time::try_monotonic() {
+                    self.last_time = time as u64;
+                }
+                self.line_time = self.last_time;
+                self.line_level = level;
+                self.line_facility = facility;
+            }
+            if byte == b'\n' {
+                self.commit();
+                continue;
+            }
+            self.line[self.line_len] = byte;
+            self.line_len += 1;
+            if self.line_len == LINE_MAX {
+                self.commit();
+            }
+        }
+    }
+
+    /// A cursor at the record `seq`, or the closest one still in the ring
+    pub fn cursor(&self, seq: u64) -> Cursor {
+        let seq = seq.clamp(self.first_seq, self.next_seq);
+        let mut cursor = Cursor {
+            seq: self.first_seq,
+            pos: self.head,
+        };
+        while cursor.seq < seq {
+            cursor.pos += HEADER_SIZE + self.header(cursor.pos).len;
+            cursor.seq += 1;
+        }
+        cursor
+    }
+
+    /// Read the record at `cursor` into `text`, and advance it. Returns `Ok(None)` if there is no
+    /// new record, or `Err` with the number of records lost if the ones at `cursor` were
+    /// overwritten, after moving it to the oldest one.
+    pub fn read(&self, cursor: &mut Cursor, text: &mut [u8; LINE_MAX]) -> Result<Option<Header>, u64> {
+        if cursor.seq < self.first_seq || cursor.pos < self.head {
+            let lost = self.first_seq.saturating_sub(cursor.seq);
+            *cursor = self.cursor(self.first_seq);
+            return Err(lost);
+        }
+        if cursor.seq >= self.next_seq {
+            return Ok(None);
+        }
+        let header = self.header(cursor.pos);
+        self.copy_out(cursor.pos + HEADER_SIZE, &mut text[..header.len]);
+        cursor.pos += HEADER_SIZE + header.len;
+        cursor.seq += 1;
+        Ok(Some(header))
+    }
+
+    /// Format the next output not yet printed on the consoles into `line`: a record, or the part
+    /// of the line being written not printed yet, so that prompts show up. Returns false if there
+    /// is none.
+    pub fn next_console(&mut self, line: &mut Line) -> bool {
+        let mut cursor = self.console;
+        let mut text = [0; LINE_MAX];
+        let (partial_seq, partial) = self.console_partial;
+        line.len = 0;
+        loop {
+            match self.read(&mut cursor, &mut text) {
+                Ok(Some(header)) => {
+                    self.console = cursor;
+                    if header.seq == partial_seq && partial > 0 {
+                        line.push(&text[partial.min(header.len)..header.len]);
+                        line.push(b"\n");
+                    } else {
+                        format_line(line, &header, &text[..header.len]);
+                    }
+                    return true;
+                }
+                Ok(None) => break,
+                Err(_) => continue,
+            }
+        }
+        self.console = cursor;
+
+        let printed = if partial_seq == self.next_seq { partial } else { 0 };
+        if self.line_len <= printed {
+            return false;
+        }
+        if printed == 0 {
+            format_prefix(line, self.line_facility, self.line_time);
+        }
+        line.push(&self.line[printed..self.line_len]);
+        self.console_partial = (self.next_seq, self.line_len);
+        true
+    }
+
+    /// The text of all records, as printed on the consoles
+    pub fn text(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut cursor = self.cursor(self.first_seq);
+        let mut text = [0; LINE_MAX];
+        let mut line = Line::new();
+        while let Ok(Some(header)) = self.read(&mut cursor, &mut text) {
+            line.len = 0;
+            format_line(&mut line, &header, &text[..header.len]);
+            data.extend_from_slice(line.as_bytes());
         }
+        data
+    }
+}
+
+/// Kernel output is prefixed with the time it was logged at. Userspace output, which may be that
+/// of a terminal on `debug:`, is printed as is.
+fn format_prefix(line: &mut Line, facility: u8, time: u64) {
+    if facility == FACILITY_KERNEL {
+        let micros = time / 1000;
+        let _ = write!(line, "[{:5}.{:06}] ", micros / 1_000_000, micros % 1_000_000);
+    }
+}
+
+fn format_line(line: &mut Line, header: &Header, text: &[u8]) {
+    format_prefix(line, header.facility, header.time);
+    line.push(text);
+    line.push(b"\n");
+}
+
+/// Most filters besides the default level
+const FILTERS_MAX: usize = 16;
+
+/// Longest target of a filter
+const TARGET_MAX: usize = 64;
+
+/// Prefix of the targets of the kernel, which filters can leave out
+const TARGET_PREFIX: &str = "kernel::";
+
+static FILTERS: RwLock<Filters> = RwLock::new(Filters::new());
+
+#[derive(Clone, Copy)]
+struct Filter {
+    target: [u8; TARGET_MAX],
+    len: usize,
+    level: LevelFilter,
+}
+
+impl Filter {
+    fn target(&self) -> &str {
+        str::from_utf8(&self.target[..self.len]).unwrap_or("")
     }
 
-    pub fn read(&self) -> (&[u8], &[u8]) {
-        self.data.as_slices()
+    /// Whether `target` is the module of the filter, or one of its submodules
+    fn matches(&self, target: &str) -> bool {
+        let filter = self.target();
+        let target = target.strip_prefix(TARGET_PREFIX).unwrap_or(target);
+        target.strip_prefix(filter).map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
     }
+}
+
+/// Level filters by target. Kept in fixed size storage, as they are set before the heap exists.
+#[derive(Clone, Copy)]
+pub struct Filters {
+    default: LevelFilter,
+    filters: [Filter; FILTERS_MAX],
+    count: usize,
+}
 
-    pub fn write(&mut self, buf: &[u8]) {
-        for &b in buf {
-            while self.data.len() + 1 >= self.size {
-                self.data.pop_front();
+impl Filters {
+    const fn new() -> Filters {
+        Filters {
+            default: LevelFilter::Info,
+            filters: [Filter {
+                target: [0; TARGET_MAX],
+                len: 0,
+                level: LevelFilter::Off,
+            }; FILTERS_MAX],
+            count: 0,
+        }
+    }
+
+    /// Parse filters written like `info,acpi=debug`. Returns `None` if they are invalid or too
+    /// many.
+    pub fn parse(spec: &str) -> Option<Filters> {
+        let mut filters = Filters::new();
+        for item in spec.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let Some((target, level)) = item.split_once('=') else {
+                filters.default = item.parse().ok()?;
+                continue;
+            };
+            let target = target.trim();
+            let target = target.strip_prefix(TARGET_PREFIX).unwrap_or(target);
+            if target.is_empty() || target.len() > TARGET_MAX {
+                return None;
             }
-            self.data.push_back(b);
+            let filter = filters.filters.get_mut(filters.count)?;
+            filter.target[..target.len()].copy_from_slice(target.as_bytes());
+            filter.len = target.len();
+            filter.level = level.trim().parse().ok()?;
+            filters.count += 1;
+        }
+        Some(filters)
+    }
+
+    /// The level of records of `target` that are logged
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.filters[..self.count]
+            .iter()
+            .filter(|filter| filter.matches(target))
+            .max_by_key(|filter| filter.len)
+            .map_or(self.default, |filter| filter.level)
+    }
+
+    /// The most verbose level of any target
+    fn max_level(&self) -> LevelFilter {
+        self.filters[..self.count].iter().map(|filter| filter.level).fold(self.default, Ord::max)
+    }
+}
+
+fn level_name(level: LevelFilter) -> &'static str {
+    match level {
+        LevelFilter::Off => "off",
+        LevelFilter::Error => "error",
+        LevelFilter::Warn => "warn",
+        LevelFilter::Info => "info",
+        LevelFilter::Debug => "debug",
+        LevelFilter::Trace => "trace",
+    }
+}
+
+impl fmt::Display for Filters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", level_name(self.default))?;
+        for filter in &self.filters[..self.count] {
+            write!(f, ",{}={}", filter.target(), level_name(filter.level))?;
         }
+        Ok(())
+    }
+}
+
+/// The filters in use
+pub fn filters() -> Filters {
+    *FILTERS.read()
+}
+
+/// Use `filters` from now on
+pub fn set_filters(filters: Filters) {
+    *FILTERS.write() = filters;
+    ::log::set_max_level(filters.max_level());
+}
+
+/// Set the filters from `LOG` in the environment, if any. Called on the BSP after `init_logger`.
+pub fn configure(env: &[u8]) {
+    let Some(spec) = str::from_utf8(env).unwrap_or("").lines().find_map(|line| line.strip_prefix("LOG=")) else {
+        return;
+    };
+    match Filters::parse(spec) {
+        Some(filters) => set_filters(filters),
+        None => warn!("log: ignoring invalid filters {:?}", spec),
     }
 }
 
@@ -41,11 +521,14 @@ struct RedoxLogger {
 }
 
 impl ::log::Log for RedoxLogger {
-    fn enabled(&self, _: &log::Metadata<'_>) -> bool {
-        false
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        // The filters may be being set by this CPU, if logging from an NMI
+        FILTERS.try_read().map_or(true, |filters| metadata.level() <= filters.level(metadata.target()))
     }
     fn log(&self, record: &log::Record<'_>) {
-        (self.log_func)(record)
+        if self.enabled(record.metadata()) {
+            (self.log_func)(record)
+        }
     }
     fn flush(&self) {}
 }
@@ -54,7 +537,7 @@ pub fn init_logger(func: fn(&log::Record)) {
     unsafe {
         match LOGGER.initialized.load(Ordering::SeqCst) {
             false => {
-                ::log::set_max_level(::log::LevelFilter::Info);
+                ::log::set_max_level(FILTERS.read().max_level());
                     LOGGER.log_func = func;
                     match ::log::set_logger(&LOGGER) {
                         Ok(_) => ::log::info!("Logger initialized."),
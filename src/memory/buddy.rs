@@ -0,0 +1,328 @@
+//! # Buddy allocator
+//! The frame allocator. Free memory is kept in blocks of 2^order frames, aligned to their size, on
+//! a free list per order. Allocating splits the smallest large enough block, and freeing merges a
+//! block with its buddy (the other half of the block of the next order) for as long as the buddy
+//! is free, so contiguous allocations keep succeeding after memory has been fragmented by uptime.
+//! Both are O(log n).
+//!
+//! The free lists are threaded through the free blocks themselves, and the order of each free
+//! block is kept in a byte per frame, taken from the end of the largest memory area.
+
+use core::cmp;
+use core::marker::PhantomData;
+
+use rmm::{Arch, FrameAllocator, FrameCount, FrameUsage, MemoryArea, PhysicalAddress, VirtualAddress};
+
+/// Order of the largest blocks, of 1 GiB with 4 KiB pages
+pub const MAX_ORDER: usize = 18;
+
+/// Set in the state byte of the first frame of a free block, whose order is in the other bits
+const FREE: u8 = 0x80;
+
+/// No block, at the end of a free list
+const NONE: usize = usize::MAX;
+
+/// The links of a free block, stored in its first frame
+#[repr(C)]
+struct Links {
+    next: usize,
+    prev: usize,
+}
+
+/// The page size and the mapping of physical memory, which is all the allocator needs of an `Arch`
+pub trait FrameMemory {
+    const PAGE_SIZE: usize;
+    unsafe fn phys_to_virt(phys: PhysicalAddress) -> VirtualAddress;
+}
+
+impl<A: Arch> FrameMemory for A {
+    const PAGE_SIZE: usize = A::PAGE_SIZE;
+    unsafe fn phys_to_virt(phys: PhysicalAddress) -> VirtualAddress {
+        A::phys_to_virt(phys)
+    }
+}
+
+pub struct BuddyAllocator<A> {
+    /// First free block of each order, as frame numbers
+    heads: [usize; MAX_ORDER + 1],
+    /// Virtual address of the state bytes, one per frame starting at frame `base`
+    states: usize,
+    base: usize,
+    frames: usize,
+    free: usize,
+    total: usize,
+    phantom: PhantomData<A>,
+}
+
+/// The ranges of `areas` that are still free after the first `offset` bytes were used by the
+/// bump allocator, as physical start and end addresses
+fn usable(areas: &[MemoryArea], mut offset: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+    areas.iter().filter_map(move |area| {
+        let skip = cmp::min(offset, area.size);
+        offset -= skip;
+        (area.size > skip).then(|| (area.base.data() + skip, area.base.data() + area.size))
+    })
+}
+
+impl<A: FrameMemory> BuddyAllocator<A> {
+    /// Take over the memory of `areas`, except the first `offset` bytes which the bump allocator
+    /// has handed out while booting
+    pub unsafe fn new(areas: &[MemoryArea], offset: usize) -> Option<Self> {
+        let first = usable(areas, offset).map(|(start, _)| start).min()? / A::PAGE_SIZE;
+        let last = usable(areas, offset).map(|(_, end)| end).max()? / A::PAGE_SIZE;
+        let frames = last - first;
+
+        // The state bytes go at the end of the largest area
+        let states_size = frames.div_ceil(A::PAGE_SIZE) * A::PAGE_SIZE;
+        let (largest_start, largest_end) = usable(areas, offset).max_by_key(|&(start, end)| end - start)?;
+        if largest_end - largest_start < states_size {
+            return None;
+        }
+        let states_base = largest_end - states_size;
+        let states = A::phys_to_virt(PhysicalAddress::new(states_base)).data();
+        (states as *mut u8).write_bytes(0, frames);
+
+        let mut allocator = Self {
+            heads: [NONE; MAX_ORDER + 1],
+            states,
+            base: first,
+            frames,
+            free: 0,
+            total: 0,
+            phantom: PhantomData,
+        };
+        for (start, end) in usable(areas, offset) {
+            let end = if end == largest_end { states_base } else { end };
+            allocator.free_range(start / A::PAGE_SIZE, end / A::PAGE_SIZE);
+        }
+        allocator.total = allocator.free;
+
+        log::info!("Buddy allocator: {} frames from {:#X} to {:#X}", allocator.total, first * A::PAGE_SIZE, last * A::PAGE_SIZE);
+        Some(allocator)
+    }
+
+    fn state(&self, frame: usize) -> Option<u8> {
+        let i = frame.checked_sub(self.base).filter(|&i| i < self.frames)?;
+        Some(unsafe { (self.states as *const u8).add(i).read() })
+    }
+    fn set_state(&mut self, frame: usize, state: u8) {
+        let i = frame - self.base;
+        assert!(i < self.frames);
+        unsafe { (self.states as *mut u8).add(i).write(state) }
+    }
+
+    /// The first frame of the free block containing the frame at `address`, if it is free
+    pub fn containing_free_block(&self, address: PhysicalAddress) -> Option<PhysicalAddress> {
+        let frame = address.data() / A::PAGE_SIZE;
+        (0..=MAX_ORDER)
+            .map(|order| (frame & !((1 << order) - 1), order))
+            .find(|&(block, order)| self.state(block) == Some(FREE | order as u8))
+            .map(|(block, _)| PhysicalAddress::new(block * A::PAGE_SIZE))
+    }
+
+    fn links(frame: usize) -> *mut Links {
+        unsafe { A::phys_to_virt(PhysicalAddress::new(frame * A::PAGE_SIZE)).data() as *mut Links }
+    }
+
+    unsafe fn push(&mut self, frame: usize, order: usize) {
+        let head = self.heads[order];
+        Self::links(frame).write(Links { next: head, prev: NONE });
+        if head != NONE {
+            (*Self::links(head)).prev = frame;
+        }
+        self.heads[order] = frame;
+        self.set_state(frame, FREE | order as u8);
+        self.free += 1 << order;
+    }
+
+    unsafe fn remove(&mut self, frame: usize, order: usize) {
+        let Links { next, prev } = Self::links(frame).read();
+        if prev == NONE {
+            self.heads[order] = next;
+        } else {
+            (*Self::links(prev)).next = next;
+        }
+        if next != NONE {
+            (*Self::links(next)).prev = prev;
+        }
+        self.set_state(frame, 0);
+        self.free -= 1 << order;
+    }
+
+    /// Free the block of 2^`order` frames at `frame`, merging it with its buddy while possible
+    unsafe fn free_block(&mut self, mut frame: usize, mut order: usize) {
+        debug_assert_eq!(self.state(frame).map(|state| state & FREE), Some(0), "double free of frame {:#X}", frame * A::PAGE_SIZE);
+
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if self.state(buddy) != Some(FREE | order as u8) {
+                break;
+            }
+            self.remove(buddy, order);
+            frame = cmp::min(frame, buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    /// Free the frames from `frame` to `end`, as the largest aligned blocks that fit
+    unsafe fn free_range(&mut self, mut frame: usize, end: usize) {
+        while frame < end {
+            let mut order = cmp::min(frame.trailing_zeros() as usize, MAX_ORDER);
+            while frame + (1 << order) > end {
+                order -= 1;
+            }
+            self.free_block(frame, order);
+            frame += 1 << order;
+        }
+    }
+}
+
+impl<A: FrameMemory> FrameAllocator for BuddyAllocator<A> {
+    unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
+        let count = count.data();
+        if count == 0 || count > 1 << MAX_ORDER {
+            return None;
+        }
+        let order = count.next_power_of_two().trailing_zeros() as usize;
+        let found = (order..=MAX_ORDER).find(|&order| self.heads[order] != NONE)?;
+        let frame = self.heads[found];
+        self.remove(frame, found);
+
+        // Split off the upper halves, and return the frames past `count`
+        for order in (order..found).rev() {
+            self.push(frame + (1 << order), order);
+        }
+        self.free_range(frame + count, frame + (1 << order));
+
+        let address = PhysicalAddress::new(frame * A::PAGE_SIZE);
+        (A::phys_to_virt(address).data() as *mut u8).write_bytes(0, count * A::PAGE_SIZE);
+        Some(address)
+    }
+
+    unsafe fn free(&mut self, address: PhysicalAddress, count: FrameCount) {
+        let frame = address.data() / A::PAGE_SIZE;
+        self.free_range(frame, frame + count.data());
+    }
+
+    unsafe fn usage(&self) -> FrameUsage {
+        FrameUsage::new(FrameCount::new(self.total - self.free), FrameCount::new(self.total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Where the memory of each test starts, so that tests running in parallel do not share it
+    static BASES: [AtomicUsize; 4] = [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
+
+    /// Memory of test `N`, with small pages so that blocks of `MAX_ORDER` fit
+    struct TestMemory<const N: usize>;
+
+    impl<const N: usize> FrameMemory for TestMemory<N> {
+        const PAGE_SIZE: usize = 64;
+        unsafe fn phys_to_virt(phys: PhysicalAddress) -> VirtualAddress {
+            VirtualAddress::new(BASES[N].load(Ordering::SeqCst) + phys.data())
+        }
+    }
+
+    /// An allocator of `frames` frames, all in use, along with the memory it manages and its
+    /// state bytes
+    fn allocator<const N: usize>(frames: usize) -> (BuddyAllocator<TestMemory<N>>, Vec<u64>, Vec<u8>) {
+        let memory = vec![0_u64; frames * TestMemory::<N>::PAGE_SIZE / 8];
+        let mut states = vec![0_u8; frames];
+        BASES[N].store(memory.as_ptr() as usize, Ordering::SeqCst);
+        let allocator = BuddyAllocator {
+            heads: [NONE; MAX_ORDER + 1],
+            states: states.as_mut_ptr() as usize,
+            base: 0,
+            frames,
+            free: 0,
+            total: frames,
+            phantom: PhantomData,
+        };
+        (allocator, memory, states)
+    }
+
+    /// The number of free blocks of `order`
+    fn blocks<A: FrameMemory>(allocator: &BuddyAllocator<A>, order: usize) -> usize {
+        let mut count = 0;
+        let mut frame = allocator.heads[order];
+        while frame != NONE {
+            count += 1;
+            frame = unsafe { BuddyAllocator::<A>::links(frame).read().next };
+        }
+        count
+    }
+
+    fn address<A: FrameMemory>(frame: usize) -> PhysicalAddress {
+        PhysicalAddress::new(frame * A::PAGE_SIZE)
+    }
+
+    #[test]
+    fn split() {
+        let (mut allocator, _memory, _states) = allocator::<0>(16);
+        unsafe { allocator.free_range(0, 16) };
+        assert_eq!(blocks(&allocator, 4), 1);
+
+        // The upper halves are split off, and the frame past the 3 allocated ones is returned
+        let frame = unsafe { allocator.allocate(FrameCount::new(3)) };
+        assert_eq!(frame, Some(address::<TestMemory<0>>(0)));
+        assert_eq!((blocks(&allocator, 0), blocks(&allocator, 1), blocks(&allocator, 2), blocks(&allocator, 3), blocks(&allocator, 4)), (1, 0, 1, 1, 0));
+        assert_eq!(allocator.free, 13);
+        assert_eq!(allocator.containing_free_block(address::<TestMemory<0>>(3)), Some(address::<TestMemory<0>>(3)));
+        assert_eq!(allocator.containing_free_block(address::<TestMemory<0>>(2)), None);
+        assert_eq!(allocator.containing_free_block(address::<TestMemory<0>>(13)), Some(address::<TestMemory<0>>(8)));
+    }
+
+    #[test]
+    fn merge() {
+        let (mut allocator, _memory, _states) = allocator::<1>(16);
+        unsafe { allocator.free_range(0, 16) };
+        let frames: Vec<PhysicalAddress> = (0..16).map(|_| unsafe { allocator.allocate(FrameCount::new(1)) }.unwrap()).collect();
+        assert_eq!(allocator.free, 0);
+        assert_eq!(unsafe { allocator.allocate(FrameCount::new(1)) }, None);
+
+        // Freeing every frame, in an order where buddies are not freed one after the other,
+        // merges them back into a single block
+        for &i in &[5, 0, 9, 14, 3, 7, 1, 12, 6, 10, 2, 15, 8, 4, 11, 13] {
+            unsafe { allocator.free(frames[i], FrameCount::new(1)) };
+        }
+        assert_eq!(allocator.free, 16);
+        assert_eq!(blocks(&allocator, 4), 1);
+        assert!((0..4).all(|order| blocks(&allocator, order) == 0));
+        assert_eq!(unsafe { allocator.allocate(FrameCount::new(16)) }, Some(address::<TestMemory<1>>(0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free() {
+        let (mut allocator, _memory, _states) = allocator::<2>(16);
+        unsafe {
+            allocator.free_range(0, 16);
+            let frame = allocator.allocate(FrameCount::new(1)).unwrap();
+            allocator.free(frame, FrameCount::new(1));
+            allocator.free(frame, FrameCount::new(1));
+        }
+    }
+
+    #[test]
+    fn max_order() {
+        let (mut allocator, _memory, _states) = allocator::<3>(2 << MAX_ORDER);
+        unsafe { allocator.free_range(0, 2 << MAX_ORDER) };
+        // Blocks are never merged beyond `MAX_ORDER`
+        assert_eq!(blocks(&allocator, MAX_ORDER), 2);
+
+        assert_eq!(unsafe { allocator.allocate(FrameCount::new((1 << MAX_ORDER) + 1)) }, None);
+        let frame = unsafe { allocator.allocate(FrameCount::new(1 << MAX_ORDER)) }.unwrap();
+        assert_eq!(blocks(&allocator, MAX_ORDER), 1);
+        unsafe { allocator.free(frame, FrameCount::new(1 << MAX_ORDER)) };
+        assert_eq!(blocks(&allocator, MAX_ORDER), 2);
+        assert_eq!(allocator.free, 2 << MAX_ORDER);
+    }
+}
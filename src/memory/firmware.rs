@@ -0,0 +1,69 @@
+//! # Firmware memory map
+//! The regions the firmware reported as reserved or reclaimable, such as those holding ACPI
+//! tables, are not given to the frame allocator and would otherwise be forgotten after boot. They
+//! are kept here, so that `physmem:` can check that reads stay within them.
+
+use alloc::vec::Vec;
+
+use crate::sync::Mutex;
+
+/// Maximum number of regions kept, further ones are ignored
+pub const MAX_REGIONS: usize = 128;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    /// Usable once the OS no longer needs its contents, e.g. ACPI tables
+    Reclaim,
+    /// Never usable, e.g. firmware data and memory-mapped devices
+    Reserved,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Reclaim => "reclaim",
+            Kind::Reserved => "reserved",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub base: u64,
+    pub size: u64,
+    pub kind: Kind,
+}
+
+struct Map {
+    regions: [Region; MAX_REGIONS],
+    len: usize,
+}
+
+static MAP: Mutex<Map> = Mutex::new(Map {
+    regions: [Region { base: 0, size: 0, kind: Kind::Reserved }; MAX_REGIONS],
+    len: 0,
+});
+
+/// Record a region of the firmware memory map. Called while booting, before the heap exists.
+pub fn add(base: u64, size: u64, kind: Kind) {
+    let mut map = MAP.lock();
+    if size == 0 || map.len == MAX_REGIONS {
+        return;
+    }
+    let len = map.len;
+    map.regions[len] = Region { base, size, kind };
+    map.len += 1;
+}
+
+/// The region containing all of `start..end`, if any
+pub fn region_containing(start: u64, end: u64) -> Option<Region> {
+    let map = MAP.lock();
+    map.regions[..map.len].iter()
+        .find(|region| start >= region.base && end <= region.base.saturating_add(region.size))
+        .copied()
+}
+
+pub fn regions() -> Vec<Region> {
+    let map = MAP.lock();
+    map.regions[..map.len].to_vec()
+}
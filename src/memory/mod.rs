@@ -10,9 +10,23 @@ use rmm::{
     FrameAllocator,
     FrameCount,
 };
-use crate::syscall::flag::{PartialAllocStrategy, PhysallocFlags};
+use crate::syscall::flag::PartialAllocStrategy;
 use crate::syscall::error::{ENOMEM, Error};
 
+use self::zone::Zone;
+
+/// The frame allocator
+pub mod buddy;
+
+/// Reserved regions of the firmware memory map
+pub mod firmware;
+
+/// Memory nodes, and keeping frames local to them
+pub mod numa;
+
+/// Physically low memory, for devices that cannot address all of it
+pub mod zone;
+
 /// A memory map area
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(packed)]
@@ -39,23 +53,44 @@ pub fn used_frames() -> usize {
 
 /// Allocate a range of frames
 pub fn allocate_frames(count: usize) -> Option<Frame> {
-    unsafe {
+    let frame = unsafe {
         LockedAllocator.allocate(FrameCount::new(count)).map(|phys| {
             Frame::containing_address(PhysicalAddress::new(phys.data()))
         })
+    };
+    if let Some(ref frame) = frame {
+        crate::tracepoint!(FrameAlloc, frame.start_address().data(), count);
     }
+    frame
 }
-pub fn allocate_frames_complex(count: usize, flags: PhysallocFlags, strategy: Option<PartialAllocStrategy>, min: usize) -> Option<(Frame, usize)> {
+/// Allocate a range of frames whose start is aligned to `align` frames, by over-allocating and
+/// freeing the frames before and after the aligned range
+pub fn allocate_aligned_frames(count: usize, align: usize) -> Option<Frame> {
+    let total = count.checked_add(align - 1)?;
+    let base = allocate_frames(total)?;
+    let base_number = base.start_address().data() / PAGE_SIZE;
+    let before = base_number.next_multiple_of(align) - base_number;
+    let after = total - before - count;
+
+    if before > 0 {
+        deallocate_frames(base.clone(), before);
+    }
+    if after > 0 {
+        deallocate_frames(base.next_by(before + count), after);
+    }
+    Some(base.next_by(before))
+}
+pub fn allocate_frames_complex(count: usize, zone: Zone, strategy: Option<PartialAllocStrategy>, min: usize) -> Option<(Frame, usize)> {
     //TODO: support partial allocation
-    if flags == PhysallocFlags::SPACE_64 && strategy.is_none() {
+    if strategy.is_none() {
         let actual = cmp::max(count, min);
-        return allocate_frames(actual).map(|frame| (frame, actual));
+        return zone::allocate(zone, actual).map(|frame| (frame, actual));
     }
 
     println!(
-        "!!!! allocate_frames_complex not implemented for count {}, flags {:?}, strategy {:?}, min {}",
+        "!!!! allocate_frames_complex not implemented for count {}, zone {:?}, strategy {:?}, min {}",
         count,
-        flags,
+        zone,
         strategy,
         min
     );
@@ -64,6 +99,10 @@ pub fn allocate_frames_complex(count: usize, flags: PhysallocFlags, strategy: Op
 
 /// Deallocate a range of frames frame
 pub fn deallocate_frames(frame: Frame, count: usize) {
+    crate::tracepoint!(FrameFree, frame.start_address().data(), count);
+    if zone::free(&frame, count) {
+        return;
+    }
     unsafe {
         LockedAllocator.free(
             rmm::PhysicalAddress::new(frame.start_address().data()),
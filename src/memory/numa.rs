@@ -0,0 +1,224 @@
+//! # NUMA
+//! Memory nodes and the distances between them, as described by firmware (the SRAT and SLIT ACPI
+//! tables on x86). Without a description of more than one node, none of this has any effect.
+//!
+//! The frame allocator itself knows nothing about nodes, so single frames that are freed are kept
+//! on a free list of the node they belong to, and allocations of single frames are served from the
+//! list of the allocating CPU's node first. Frames from other nodes are only used when the frame
+//! allocator is out of memory, nearest node first. When the frame allocator cannot find contiguous
+//! frames, all free lists are returned to it, as the frames on them may be what it is missing.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Once;
+
+use crate::paging::{PhysicalAddress, RmmA, RmmArch};
+use crate::sync::Mutex;
+
+use super::{Frame, PAGE_SIZE};
+
+/// Maximum number of nodes, any further nodes are treated as having no memory
+pub const MAX_NODES: usize = 8;
+
+/// Distance between a node and itself, as defined by ACPI
+pub const LOCAL_DISTANCE: u8 = 10;
+/// Distance assumed between different nodes, when firmware does not say
+pub const REMOTE_DISTANCE: u8 = 20;
+
+/// Number of frames each node keeps on its free list, the rest are returned to the allocator
+const NODE_FREE_FRAMES: usize = 16384;
+
+/// A range of frame numbers belonging to a node
+#[derive(Clone, Copy, Debug)]
+pub struct NodeRange {
+    pub start: usize,
+    pub end: usize,
+    pub node: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Topology {
+    pub node_count: usize,
+    /// Memory of each node, sorted by start
+    pub ranges: Vec<NodeRange>,
+    /// The node of each CPU, by CPU ID
+    pub cpus: BTreeMap<usize, usize>,
+    /// `node_count` by `node_count` matrix of relative distances, or empty if unknown
+    pub distances: Vec<u8>,
+}
+
+static TOPOLOGY: Once<Topology> = Once::new();
+
+/// Set the topology, once, while booting
+pub fn init(mut topology: Topology) {
+    if topology.node_count <= 1 {
+        return;
+    }
+    topology.ranges.sort_unstable_by_key(|range| range.start);
+    log::info!("NUMA: {} nodes, {} memory ranges, {} CPUs", topology.node_count, topology.ranges.len(), topology.cpus.len());
+    TOPOLOGY.call_once(|| topology);
+}
+
+pub fn topology() -> Option<&'static Topology> {
+    TOPOLOGY.get()
+}
+
+pub fn node_count() -> usize {
+    topology().map_or(1, |topology| topology.node_count)
+}
+
+/// The node of the frame at `address`, if known
+pub fn node_of(address: PhysicalAddress) -> Option<usize> {
+    let number = address.data() / PAGE_SIZE;
+    let ranges = &topology()?.ranges;
+    let index = ranges.partition_point(|range| range.start <= number).checked_sub(1)?;
+    let range = ranges[index];
+    (number < range.end).then_some(range.node)
+}
+
+/// The node of `cpu_id`, or node 0 if unknown
+pub fn cpu_node(cpu_id: usize) -> usize {
+    topology().and_then(|topology| topology.cpus.get(&cpu_id).copied()).unwrap_or(0)
+}
+
+pub fn distance(from: usize, to: usize) -> u8 {
+    match topology() {
+        Some(topology) if !topology.distances.is_empty() => topology.distances[from * topology.node_count + to],
+        _ if from == to => LOCAL_DISTANCE,
+        _ => REMOTE_DISTANCE,
+    }
+}
+
+/// A free list threaded through the free frames themselves, so that freeing never allocates
+struct FreeList {
+    /// Physical address of the first frame, or zero if empty
+    head: usize,
+    len: usize,
+}
+
+static FREE: [Mutex<FreeList>; MAX_NODES] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Mutex<FreeList> = Mutex::new(FreeList { head: 0, len: 0 });
+    [EMPTY; MAX_NODES]
+};
+
+/// Frames on all free lists, which the frame allocator counts as used
+static FREE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn free_frames() -> usize {
+    FREE_FRAMES.load(Ordering::Relaxed)
+}
+
+/// Frames on the free list of `node`
+pub fn node_free_frames(node: usize) -> usize {
+    FREE.get(node).map_or(0, |list| list.lock().len)
+}
+
+fn pop(node: usize) -> Option<PhysicalAddress> {
+    let address = {
+        let mut list = FREE.get(node)?.lock();
+        if list.head == 0 {
+            return None;
+        }
+        let address = PhysicalAddress::new(list.head);
+        list.head = unsafe { (RmmA::phys_to_virt(address).data() as *const usize).read() };
+        list.len -= 1;
+        address
+    };
+    FREE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+
+    // Frames are expected to be zeroed when allocated
+    unsafe {
+        (RmmA::phys_to_virt(address).data() as *mut u8).write_bytes(0, PAGE_SIZE);
+    }
+    Some(address)
+}
+
+/// Keep the freed frame at `address` on the free list of its node. Returns false if it should be
+/// returned to the frame allocator instead.
+pub fn free(address: PhysicalAddress) -> bool {
+    let node = match node_of(address) {
+        Some(node) if node < MAX_NODES => node,
+        _ => return false,
+    };
+    {
+        let mut list = FREE[node].lock();
+        if list.len >= NODE_FREE_FRAMES {
+            return false;
+        }
+        unsafe {
+            (RmmA::phys_to_virt(address).data() as *mut usize).write(list.head);
+        }
+        list.head = address.data();
+        list.len += 1;
+    }
+    FREE_FRAMES.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Allocate a frame from the node of the current CPU
+pub fn allocate_local() -> Option<PhysicalAddress> {
+    topology()?;
+    pop(cpu_node(crate::cpu_id()))
+}
+
+/// Allocate a frame from any other node, nearest first, when the frame allocator has run out
+pub fn allocate_remote() -> Option<PhysicalAddress> {
+    let local = cpu_node(crate::cpu_id());
+    // No heap allocations, this may be called to grow the heap
+    let mut nodes: [usize; MAX_NODES] = core::array::from_fn(|node| node);
+    let nodes = &mut nodes[..node_count().min(MAX_NODES)];
+    nodes.sort_unstable_by_key(|&node| distance(local, node));
+    nodes.iter().filter(|&&node| node != local).find_map(|&node| pop(node))
+}
+
+/// Return the frames of every free list to the frame allocator with `free`, for when it is out of
+/// contiguous frames. Returns the number of frames returned.
+pub fn drain(mut free: impl FnMut(PhysicalAddress)) -> usize {
+    let mut drained = 0;
+    for list in FREE.iter() {
+        let (mut head, len) = {
+            let mut list = list.lock();
+            let taken = (list.head, list.len);
+            list.head = 0;
+            list.len = 0;
+            taken
+        };
+        FREE_FRAMES.fetch_sub(len, Ordering::Relaxed);
+        drained += len;
+
+        while head != 0 {
+            let address = PhysicalAddress::new(head);
+            head = unsafe { (RmmA::phys_to_virt(address).data() as *const usize).read() };
+            free(address);
+        }
+    }
+    drained
+}
+
+/// Allocate `count` contiguous frames on `node`, for userspace that needs memory close to a
+/// device. The frame allocator has no notion of nodes, so this fails if the frames it returns are
+/// on another node.
+pub fn allocate_on(node: usize, count: usize) -> Option<Frame> {
+    if node >= node_count() {
+        return None;
+    }
+    if count == 1 {
+        if let Some(address) = pop(node) {
+            return Some(Frame::containing_address(address));
+        }
+    }
+
+    let frame = super::allocate_frames(count)?;
+    if topology().is_none() {
+        return Some(frame);
+    }
+    let last = frame.next_by(count - 1);
+    if node_of(frame.start_address()) == Some(node) && node_of(last.start_address()) == Some(node) {
+        return Some(frame);
+    }
+    super::deallocate_frames(frame, count);
+    None
+}
@@ -0,0 +1,208 @@
+//! # Zones
+//! Devices that can only address part of physical memory, like legacy DMA below 1 MiB or PCI
+//! devices limited to 32-bit addresses, need memory from that part. The frame allocator returns
+//! frames from anywhere, so while booting, free memory below 1 MiB (which is otherwise left unused)
+//! and a pool of memory below 4 GiB are kept out of it, and handed out only when such a zone is
+//! requested, for example with `physalloc3`.
+
+use crate::paging::{PhysicalAddress, RmmA, RmmArch};
+use crate::sync::Mutex;
+
+use super::{Frame, PAGE_SIZE};
+
+/// Maximum number of frames in a pool
+pub const POOL_FRAMES: usize = 4096;
+
+/// End of the low zone, memory reachable from real mode
+pub const LOW_END: usize = 0x10_0000;
+/// End of the DMA32 zone
+pub const DMA32_END: u64 = 0x1_0000_0000;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Zone {
+    /// Below 1 MiB
+    Low,
+    /// Below 4 GiB
+    Dma32,
+    /// Anywhere
+    Normal,
+}
+
+/// A range of frames, each of which is either free or used
+struct Pool {
+    /// First frame number
+    base: usize,
+    frames: usize,
+    used: [u64; POOL_FRAMES / 64],
+    /// Frames that were free memory when added, as opposed to firmware or boot data in the low zone
+    usable: [u64; POOL_FRAMES / 64],
+}
+
+impl Pool {
+    const fn empty() -> Self {
+        Self {
+            base: 0,
+            frames: 0,
+            used: [!0; POOL_FRAMES / 64],
+            usable: [0; POOL_FRAMES / 64],
+        }
+    }
+
+    fn is_used(&self, i: usize) -> bool {
+        self.used[i / 64] & (1 << (i % 64)) != 0
+    }
+    fn set_used(&mut self, i: usize, used: bool) {
+        if used {
+            self.used[i / 64] |= 1 << (i % 64);
+        } else {
+            self.used[i / 64] &= !(1 << (i % 64));
+        }
+    }
+
+    fn is_usable(&self, i: usize) -> bool {
+        self.usable[i / 64] & (1 << (i % 64)) != 0
+    }
+    fn add(&mut self, i: usize) {
+        self.usable[i / 64] |= 1 << (i % 64);
+        self.set_used(i, false);
+    }
+
+    /// Index of the frame `number`, if it is in this pool
+    fn index_of(&self, number: usize) -> Option<usize> {
+        number.checked_sub(self.base).filter(|&i| i < self.frames)
+    }
+
+    /// Find `count` contiguous free frames, first fit
+    fn allocate(&mut self, count: usize) -> Option<usize> {
+        let mut start = 0;
+        while start + count <= self.frames {
+            match (start..start + count).find(|&i| self.is_used(i)) {
+                Some(used) => start = used + 1,
+                None => {
+                    for i in start..start + count {
+                        self.set_used(i, true);
+                    }
+                    return Some(self.base + start);
+                }
+            }
+        }
+        None
+    }
+}
+
+static LOW: Mutex<Pool> = Mutex::new(Pool {
+    frames: LOW_END / PAGE_SIZE,
+    ..Pool::empty()
+});
+static DMA32: Mutex<Pool> = Mutex::new(Pool::empty());
+
+fn pool(zone: Zone) -> Option<&'static Mutex<Pool>> {
+    match zone {
+        Zone::Low => Some(&LOW),
+        Zone::Dma32 => Some(&DMA32),
+        Zone::Normal => None,
+    }
+}
+
+/// Add the free frame at `address`, below `LOW_END`, to the low zone. Called while booting.
+pub fn add_low(address: PhysicalAddress) {
+    // The first frame is never handed out, so that a physical address of zero stays invalid
+    let mut pool = LOW.lock();
+    if let Some(i) = pool.index_of(address.data() / PAGE_SIZE).filter(|&i| i > 0) {
+        pool.add(i);
+    }
+}
+
+/// Use the `frames` free frames at `base`, below `DMA32_END`, as the DMA32 pool. Called while
+/// booting.
+pub fn add_dma32(base: PhysicalAddress, frames: usize) {
+    let mut pool = DMA32.lock();
+    assert_eq!(pool.frames, 0, "DMA32 pool added twice");
+    pool.base = base.data() / PAGE_SIZE;
+    pool.frames = frames.min(POOL_FRAMES);
+    for i in 0..pool.frames {
+        pool.add(i);
+    }
+}
+
+/// The free frames of all pools, which must be mapped like the memory given to the frame allocator
+pub fn for_each_free_frame(mut f: impl FnMut(PhysicalAddress)) {
+    for pool in [&LOW, &DMA32] {
+        let pool = pool.lock();
+        for i in (0..pool.frames).filter(|&i| !pool.is_used(i)) {
+            f(PhysicalAddress::new((pool.base + i) * PAGE_SIZE));
+        }
+    }
+}
+
+/// Every frame of the pools that was added as free memory, and whether it is in use
+pub fn for_each_frame(mut f: impl FnMut(PhysicalAddress, bool)) {
+    for pool in [&LOW, &DMA32] {
+        let pool = pool.lock();
+        for i in (0..pool.frames).filter(|&i| pool.is_usable(i)) {
+            f(PhysicalAddress::new((pool.base + i) * PAGE_SIZE), pool.is_used(i));
+        }
+    }
+}
+
+/// Allocate `count` contiguous, zeroed frames in `zone`
+pub fn allocate(zone: Zone, count: usize) -> Option<Frame> {
+    if count == 0 {
+        return None;
+    }
+    let pool = match pool(zone) {
+        Some(pool) => pool,
+        None => return super::allocate_frames(count),
+    };
+
+    if let Some(number) = pool.lock().allocate(count) {
+        let address = PhysicalAddress::new(number * PAGE_SIZE);
+        unsafe {
+            (RmmA::phys_to_virt(address).data() as *mut u8).write_bytes(0, count * PAGE_SIZE);
+        }
+        return Some(Frame::containing_address(address));
+    }
+
+    // Once the pool is exhausted, the frame allocator may still happen to have low enough memory
+    if zone == Zone::Dma32 {
+        let frame = super::allocate_frames(count)?;
+        if (frame.next_by(count).start_address().data() as u64) <= DMA32_END {
+            return Some(frame);
+        }
+        super::deallocate_frames(frame, count);
+    }
+    None
+}
+
+/// Return frames to the pool they were allocated from. Returns false if they are not part of a pool.
+pub fn free(frame: &Frame, count: usize) -> bool {
+    let number = frame.start_address().data() / PAGE_SIZE;
+    for pool in [&LOW, &DMA32] {
+        let mut pool = pool.lock();
+        if let Some(first) = pool.index_of(number) {
+            for i in first..(first + count).min(pool.frames) {
+                debug_assert!(pool.is_used(i), "double free of zone frame {:#X}", (pool.base + i) * PAGE_SIZE);
+                pool.set_used(i, false);
+            }
+            return true;
+        }
+    }
+    false
+}
+
+/// Take the frame at `address` out of its pool, for memory that has to be at a fixed address, like
+/// the AP trampoline. Returns false if it is not free memory of a pool.
+pub fn claim(address: PhysicalAddress) -> bool {
+    let number = address.data() / PAGE_SIZE;
+    for pool in [&LOW, &DMA32] {
+        let mut pool = pool.lock();
+        if let Some(i) = pool.index_of(number) {
+            if pool.is_used(i) {
+                return false;
+            }
+            pool.set_used(i, true);
+            return true;
+        }
+    }
+    false
+}
@@ -3,7 +3,7 @@
 use core::alloc::Layout;
 use core::panic::PanicInfo;
 
-use crate::{cpu_id, context, interrupt, syscall};
+use crate::{boot_id, cpu_id, context, dump, interrupt, journal, syscall};
 
 /// Required to handle panics
 #[panic_handler]
@@ -14,6 +14,11 @@ pub extern "C" fn rust_begin_unwind(info: &PanicInfo) -> ! {
     unsafe { interrupt::stack_trace(); }
 
     println!("CPU {}, PID {:?}", cpu_id(), context::context_id());
+    if let Some(boot_id) = boot_id::get() {
+        println!("BOOT ID: {}", boot_id);
+    }
+
+    journal::dump();
 
     // This could deadlock, but at this point we are going to halt anyways
     {
@@ -28,6 +33,14 @@ pub extern "C" fn rust_begin_unwind(info: &PanicInfo) -> ! {
         }
     }
 
+    dump::write(format_args!("{}", info));
+
+    #[cfg(target_arch = "x86_64")]
+    crate::kexec::crash();
+
+    #[cfg(target_arch = "x86_64")]
+    crate::arch::paravirt::crash(cpu_id());
+
     println!("HALT");
     loop {
         unsafe { interrupt::halt(); }
@@ -37,6 +50,12 @@ pub extern "C" fn rust_begin_unwind(info: &PanicInfo) -> ! {
 #[alloc_error_handler]
 #[no_mangle]
 #[allow(improper_ctypes_definitions)] // Layout is not repr(C)
-pub extern fn rust_oom(_layout: Layout) -> ! {
-    panic!("kernel memory allocation failed");
+pub extern fn rust_oom(layout: Layout) -> ! {
+    // Userspace is kept out of the last frames by the OOM killer (see `context::oom`), so the
+    // kernel itself cannot make progress
+    panic!(
+        "kernel memory allocation of {} bytes failed, {} frames free",
+        layout.size(),
+        crate::memory::free_frames(),
+    );
 }
@@ -0,0 +1,426 @@
+//! # Performance counters
+//! Counters of hardware events, such as cycles and cache misses, programmed into the counters of
+//! the PMU (see `arch::pmu`) for a context or a CPU, and opened through `perf:` (see
+//! `scheme::perf`).
+//!
+//! The counters of a context are started on the CPU it is switched to, in the hardware counters
+//! not taken by the counters of that CPU, and stopped when it is switched away. A context with
+//! more counters than there are free hardware counters is not counted by the extra ones. The
+//! counters of a CPU are started and stopped by that CPU on its next timer tick, and so are the
+//! counters of a context which are closed while it runs.
+//!
+//! A counter with a period raises an NMI each time it has counted that many events, which writes
+//! a `PerfSample` to its ring, mapped by the reader. Readers are woken on the next timer tick of
+//! the BSP, as locks cannot be taken from an NMI. Otherwise, counters are read on every timer
+//! tick, so their count is at most a tick old.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::{mem, ptr};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+use crate::context::ContextId;
+use crate::memory::{allocate_frames, deallocate_frames, Frame, PAGE_SIZE};
+use crate::paging::{RmmA, RmmArch};
+use crate::sync::{Mutex, RwLock};
+use crate::syscall::abi::{PerfRingHeader, PerfSample, PERF_SAMPLE_USER};
+use crate::syscall::error::*;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use crate::arch::pmu;
+
+/// Other architectures have no counters
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod pmu {
+    use super::Event;
+
+    pub const PERIOD_MAX: u64 = 0;
+
+    pub fn counters() -> usize {
+        0
+    }
+    pub fn supported(_event: Event) -> bool {
+        false
+    }
+    pub fn mask() -> u64 {
+        0
+    }
+    pub unsafe fn start(_index: usize, _event: Event, _value: u64, _interrupt: bool) {}
+    pub unsafe fn stop(_index: usize) -> u64 {
+        0
+    }
+    pub unsafe fn read(_index: usize) -> u64 {
+        0
+    }
+    pub unsafe fn overflowed(_index: usize) -> bool {
+        false
+    }
+    pub unsafe fn reload(_index: usize, _value: u64) {}
+}
+
+/// Number of CPUs whose counters are used, the counters of other CPUs are not
+const CPUS: usize = 32;
+/// Most hardware counters used on each CPU
+const SLOTS: usize = 8;
+/// Pages of samples in a ring, after its header
+pub const RING_PAGES: usize = 16;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    Cycles,
+    Instructions,
+    CacheReferences,
+    CacheMisses,
+    Branches,
+    BranchMisses,
+}
+
+impl Event {
+    pub const ALL: [Event; 6] = [
+        Event::Cycles,
+        Event::Instructions,
+        Event::CacheReferences,
+        Event::CacheMisses,
+        Event::Branches,
+        Event::BranchMisses,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Cycles => "cycles",
+            Event::Instructions => "instructions",
+            Event::CacheReferences => "cache-references",
+            Event::CacheMisses => "cache-misses",
+            Event::Branches => "branches",
+            Event::BranchMisses => "branch-misses",
+        }
+    }
+
+    /// Returns true if the PMU of this machine can count the event
+    pub fn supported(self) -> bool {
+        pmu::counters() > 0 && pmu::supported(self)
+    }
+}
+
+/// What a counter counts
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Target {
+    Context(ContextId),
+    Cpu(usize),
+}
+
+/// The samples of a counter, in a header page followed by `RING_PAGES` pages of samples
+pub struct Ring {
+    frames: Vec<Frame>,
+    /// Kept apart from the header, which the reader can write
+    capacity: u64,
+}
+
+impl Ring {
+    fn new() -> Result<Self> {
+        let count = RING_PAGES + 1;
+        let capacity = (RING_PAGES * PAGE_SIZE / mem::size_of::<PerfSample>()) as u64;
+        let base = allocate_frames(count).ok_or(Error::new(ENOMEM))?;
+        unsafe {
+            let virt = RmmA::phys_to_virt(base.start_address()).data();
+            (virt as *mut u8).write_bytes(0, count * PAGE_SIZE);
+            (*(virt as *mut PerfRingHeader)).capacity = capacity;
+        }
+        Ok(Self {
+            frames: (0..count).map(|i| base.next_by(i)).collect(),
+            capacity,
+        })
+    }
+
+    /// The frames to map, the header first
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    fn header(&self) -> &PerfRingHeader {
+        unsafe { &*(RmmA::phys_to_virt(self.frames[0].start_address()).data() as *const PerfRingHeader) }
+    }
+
+    /// Write `sample`, or count it as lost if the ring is full. Only called by the CPU the counter
+    /// runs on, from an NMI.
+    fn push(&self, sample: PerfSample) {
+        let header = self.header();
+        let head = header.head.load(Ordering::Relaxed);
+        // The tail is written by the reader, who may have made it anything
+        if head.wrapping_sub(header.tail.load(Ordering::Acquire)) >= self.capacity {
+            header.lost.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let data = RmmA::phys_to_virt(self.frames[1].start_address()).data() as *mut PerfSample;
+        unsafe { data.add((head % self.capacity) as usize).write_volatile(sample) };
+        header.head.store(head.wrapping_add(1), Ordering::Release);
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        deallocate_frames(self.frames[0].clone(), self.frames.len());
+    }
+}
+
+pub struct Counter {
+    pub event: Event,
+    pub target: Target,
+    /// Events between samples, or zero if the counter only counts
+    pub period: u64,
+    /// Events counted up to when the hardware counter was at `start`
+    count: AtomicU64,
+    start: AtomicU64,
+    ring: Option<Ring>,
+    /// Set when samples were written since the readers were last woken
+    pending: AtomicBool,
+    /// Set when closed, for the CPU it runs on to stop it
+    closed: AtomicBool,
+}
+
+impl Counter {
+    pub fn new(event: Event, target: Target, period: u64) -> Result<Arc<Self>> {
+        if !event.supported() {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        let ring = if period > 0 {
+            if pmu::PERIOD_MAX == 0 {
+                return Err(Error::new(EOPNOTSUPP));
+            }
+            if period > pmu::PERIOD_MAX {
+                return Err(Error::new(EINVAL));
+            }
+            Some(Ring::new()?)
+        } else {
+            None
+        };
+        Ok(Arc::new(Self {
+            event,
+            target,
+            period,
+            count: AtomicU64::new(0),
+            start: AtomicU64::new(0),
+            ring,
+            pending: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        }))
+    }
+
+    /// Events counted, as of the last timer tick or context switch
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn ring(&self) -> Option<&Ring> {
+        self.ring.as_ref()
+    }
+
+    /// Returns true if samples were written since the last call
+    pub fn take_pending(&self) -> bool {
+        self.pending.swap(false, Ordering::Relaxed)
+    }
+
+    /// Stop counting, on the next context switch or timer tick of the CPU it runs on
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Target::Cpu(_) = self.target {
+            CPU_COUNTERS.write().retain(|counter| !ptr::eq(&**counter, self));
+        }
+    }
+
+    fn closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// The value the hardware counter starts at, which overflows after `period` events
+    fn initial(&self) -> u64 {
+        self.period.wrapping_neg() & pmu::mask()
+    }
+
+    /// Add the events counted since the hardware counter was at `start`, now that it is at `value`
+    fn fold(&self, value: u64) {
+        let start = self.start.swap(value, Ordering::Relaxed);
+        self.count.fetch_add(value.wrapping_sub(start) & pmu::mask(), Ordering::Relaxed);
+    }
+}
+
+/// The counters started on a CPU, by hardware counter
+struct Local {
+    slots: [Option<Arc<Counter>>; SLOTS],
+    /// Number of those counting a context
+    contexts: usize,
+}
+
+static LOCALS: [Mutex<Local>; CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: Option<Arc<Counter>> = None;
+    #[allow(clippy::declare_interior_mutable_const)]
+    const LOCAL: Mutex<Local> = Mutex::new(Local {
+        slots: [NONE; SLOTS],
+        contexts: 0,
+    });
+    [LOCAL; CPUS]
+};
+
+/// The counters in `LOCALS`, for the NMI handler, which cannot lock them
+static RUNNING: [[AtomicPtr<Counter>; SLOTS]; CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicPtr<Counter> = AtomicPtr::new(ptr::null_mut());
+    #[allow(clippy::declare_interior_mutable_const)]
+    const CPU: [AtomicPtr<Counter>; SLOTS] = [NONE; SLOTS];
+    [CPU; CPUS]
+};
+
+/// The counters of CPUs, started by their CPU on its next timer tick
+static CPU_COUNTERS: RwLock<Vec<Arc<Counter>>> = RwLock::new(Vec::new());
+
+fn slots() -> usize {
+    pmu::counters().min(SLOTS)
+}
+
+/// Start `counter`, of a CPU, on the next timer tick of that CPU
+pub fn add_cpu_counter(counter: Arc<Counter>) {
+    CPU_COUNTERS.write().push(counter);
+}
+
+unsafe fn start(cpu: usize, local: &mut Local, index: usize, counter: Arc<Counter>) {
+    let initial = counter.initial();
+    counter.start.store(initial, Ordering::Relaxed);
+    pmu::start(index, counter.event, initial, counter.period > 0);
+    RUNNING[cpu][index].store(Arc::as_ptr(&counter) as *mut Counter, Ordering::Release);
+    if let Target::Context(_) = counter.target {
+        local.contexts += 1;
+    }
+    local.slots[index] = Some(counter);
+}
+
+unsafe fn stop(cpu: usize, local: &mut Local, index: usize) {
+    let Some(counter) = local.slots[index].take() else {
+        return;
+    };
+    RUNNING[cpu][index].store(ptr::null_mut(), Ordering::Release);
+    counter.fold(pmu::stop(index));
+    if let Target::Context(_) = counter.target {
+        local.contexts -= 1;
+    }
+}
+
+fn free_slot(local: &Local) -> Option<usize> {
+    (0..slots()).find(|&index| local.slots[index].is_none())
+}
+
+/// Stop the counters of the previous context on the current CPU, and start those of `next`.
+/// Called when switching contexts.
+pub fn switch(next: &[Arc<Counter>]) {
+    let cpu = crate::cpu_id();
+    let Some(local) = LOCALS.get(cpu) else {
+        return;
+    };
+    let mut local = local.lock();
+    if local.contexts == 0 && next.is_empty() {
+        return;
+    }
+
+    unsafe {
+        for index in 0..slots() {
+            if matches!(local.slots[index], Some(ref counter) if matches!(counter.target, Target::Context(_))) {
+                stop(cpu, &mut local, index);
+            }
+        }
+        for counter in next.iter().filter(|counter| !counter.closed()) {
+            let Some(index) = free_slot(&local) else {
+                break;
+            };
+            start(cpu, &mut local, index, Arc::clone(counter));
+        }
+    }
+}
+
+/// Read the counters of the current CPU, stop those that were closed, and start the counters
+/// added to the CPU. Called on each timer tick.
+pub fn tick() {
+    let cpu = crate::cpu_id();
+    if slots() == 0 {
+        return;
+    }
+    let Some(local) = LOCALS.get(cpu) else {
+        return;
+    };
+    let mut local = local.lock();
+
+    unsafe {
+        for index in 0..slots() {
+            let (closed, counting) = match local.slots[index] {
+                Some(ref counter) => (counter.closed(), counter.period == 0),
+                None => continue,
+            };
+            if closed {
+                stop(cpu, &mut local, index);
+            } else if counting {
+                // Those with a period are read when they overflow, by the NMI handler
+                local.slots[index].as_ref().unwrap().fold(pmu::read(index));
+            }
+        }
+
+        if let Some(counters) = CPU_COUNTERS.try_read() {
+            for counter in counters.iter().filter(|counter| counter.target == Target::Cpu(cpu) && !counter.closed()) {
+                if local.slots.iter().flatten().any(|running| Arc::ptr_eq(running, counter)) {
+                    continue;
+                }
+                let Some(index) = free_slot(&local) else {
+                    break;
+                };
+                start(cpu, &mut local, index, Arc::clone(counter));
+            }
+        }
+    }
+    drop(local);
+
+    if cpu == 0 {
+        crate::scheme::perf::wake();
+    }
+}
+
+/// Handle an NMI raised by counters that overflowed, writing a sample for each. Returns false if
+/// none did.
+pub fn nmi(ip: usize, user: bool) -> bool {
+    let cpu = crate::cpu_id();
+    let Some(running) = RUNNING.get(cpu) else {
+        return false;
+    };
+
+    let mut handled = false;
+    for (index, counter) in running.iter().enumerate().take(slots()) {
+        let counter = counter.load(Ordering::Acquire);
+        // Not freed while running, as the CPU holds a reference in `LOCALS`
+        let Some(counter) = (unsafe { counter.as_ref() }) else {
+            continue;
+        };
+        // Counters without a period do not raise NMIs, and may look like they overflowed
+        if counter.period == 0 || !unsafe { pmu::overflowed(index) } {
+            continue;
+        }
+        handled = true;
+
+        counter.fold(unsafe { pmu::read(index) });
+        let initial = counter.initial();
+        counter.start.store(initial, Ordering::Relaxed);
+        unsafe { pmu::reload(index, initial) };
+
+        if let Some(ref ring) = counter.ring {
+            ring.push(PerfSample {
+                time: crate::time::try_monotonic().unwrap_or(0) as u64,
+                ip: ip as u64,
+                pid: crate::context::context_id().into() as u64,
+                cpu: cpu as u32,
+                flags: if user { PERF_SAMPLE_USER } else { 0 },
+            });
+            counter.pending.store(true, Ordering::Relaxed);
+        }
+    }
+    handled
+}
@@ -0,0 +1,292 @@
+//! # Power management
+//! Suspend to RAM (ACPI S3), requested through `power:state`. Userspace drivers are told through
+//! `power:notify` first, and given a second to quiesce their devices. The suspending context then
+//! moves to the BSP, which the firmware resumes on, and takes the other CPUs offline (see
+//! `hotplug`). Each parked CPU saves its state and stops, and the BSP saves its own and enters S3
+//! (see `arch::suspend`), restarting the others once resumed. The CPUs that were online are
+//! brought back online, and drivers are told the system resumed.
+//!
+//! Suspend to disk goes the same way, except that the BSP takes an image of memory instead of
+//! entering S3 and the system runs again for it to be stored (see `hibernate`), and restoring an
+//! image stops the CPUs the same way before overwriting memory with it.
+//!
+//! Powering off and rebooting, also requested through `power:state`, tell drivers through
+//! `power:notify` as well, and give them `SHUTDOWN_TIMEOUT` to be ready, for filesystems to sync.
+//! The system then powers off through ACPI S5, or resets through the reset register of the FADT,
+//! falling back to other ways if it cannot (see `stop`). Booting into a kernel loaded with `kexec`
+//! goes like rebooting, with the other CPUs stopped like for suspend.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::acpi::fadt::FADT;
+use crate::arch::suspend;
+use crate::context::{self, Context};
+use crate::hibernate;
+use crate::hotplug::{self, CPUS};
+use crate::interrupt;
+use crate::ipi::{ipi, ipi_single, IpiKind, IpiTarget};
+use crate::kexec;
+use crate::log::{error, info, warn};
+use crate::scheme::power::{self as scheme, Transition};
+use crate::sync::{Mutex, RwLock};
+use crate::syscall::error::{Error, Result, EBUSY, ENOENT, EOPNOTSUPP};
+use crate::time;
+
+/// Time drivers are given to be ready to power off or reboot, in nanoseconds
+const SHUTDOWN_TIMEOUT: u128 = 5 * time::NANOS_PER_SEC;
+
+/// SLP_TYPa and SLP_TYPb of the `\_S3` and `\_S5` objects, set by the ACPI driver
+static S3_SLEEP_TYPES: Mutex<Option<(u8, u8)>> = Mutex::new(None);
+static S5_SLEEP_TYPES: Mutex<Option<(u8, u8)>> = Mutex::new(None);
+
+/// Held while suspending
+static SUSPEND_LOCK: Mutex<()> = Mutex::new(());
+/// Set while the BSP suspends, so that parked CPUs stop
+static SUSPENDING: AtomicBool = AtomicBool::new(false);
+/// Parked CPUs that saved their state and stopped
+static STOPPED: AtomicU64 = AtomicU64::new(0);
+
+fn bit(cpu_id: usize) -> u64 {
+    if cpu_id < CPUS { 1 << cpu_id } else { 0 }
+}
+
+pub fn s3_sleep_types() -> Option<(u8, u8)> {
+    *S3_SLEEP_TYPES.lock()
+}
+
+pub fn set_s3_sleep_types(sleep_types: Option<(u8, u8)>) {
+    *S3_SLEEP_TYPES.lock() = sleep_types;
+}
+
+pub fn s5_sleep_types() -> Option<(u8, u8)> {
+    *S5_SLEEP_TYPES.lock()
+}
+
+pub fn set_s5_sleep_types(sleep_types: Option<(u8, u8)>) {
+    *S5_SLEEP_TYPES.lock() = sleep_types;
+}
+
+/// Returns true if the system can be suspended to RAM
+pub fn can_suspend() -> bool {
+    suspend::supported() && s3_sleep_types().is_some()
+}
+
+/// Returns true if the system can be suspended to disk
+pub fn can_hibernate() -> bool {
+    suspend::supported()
+}
+
+/// Suspend to RAM, returning once resumed
+pub fn suspend() -> Result<()> {
+    let _guard = SUSPEND_LOCK.try_lock().ok_or(Error::new(EBUSY))?;
+    let (slp_typa, slp_typb) = match s3_sleep_types() {
+        Some(sleep_types) if suspend::supported() => sleep_types,
+        _ => return Err(Error::new(EOPNOTSUPP)),
+    };
+
+    info!("Suspending to RAM");
+    quiesce(Transition::Suspend, time::NANOS_PER_SEC);
+
+    let result = on_bsp(|| with_cpus_stopped(|aps| unsafe { suspend::enter(slp_typa, slp_typb, aps) }));
+
+    scheme::notify(Transition::Resume);
+    match result {
+        Ok(()) => info!("Resumed from suspend to RAM"),
+        Err(err) => error!("Failed to suspend to RAM: {}", err),
+    }
+    result
+}
+
+/// Take a hibernation image, returning once it was taken, with false, or once resumed from it,
+/// with true
+pub fn hibernate() -> Result<bool> {
+    let _guard = SUSPEND_LOCK.try_lock().ok_or(Error::new(EBUSY))?;
+    if !can_hibernate() {
+        return Err(Error::new(EOPNOTSUPP));
+    }
+    if hibernate::has_image() {
+        return Err(Error::new(EBUSY));
+    }
+
+    info!("Suspending to disk");
+    hibernate::make_room()?;
+    quiesce(Transition::Suspend, time::NANOS_PER_SEC);
+
+    let result = on_bsp(|| with_cpus_stopped(|aps| unsafe { hibernate::snapshot(aps) }));
+
+    scheme::notify(Transition::Resume);
+    match result {
+        Ok(false) => info!("Hibernation image taken"),
+        Ok(true) => info!("Resumed from hibernation"),
+        Err(err) => error!("Failed to suspend to disk: {}", err),
+    }
+    result
+}
+
+/// Restore the loaded hibernation image, only returning if it could not be
+pub fn restore() -> Result<()> {
+    let _guard = SUSPEND_LOCK.try_lock().ok_or(Error::new(EBUSY))?;
+    if !can_hibernate() {
+        return Err(Error::new(EOPNOTSUPP));
+    }
+
+    quiesce(Transition::Suspend, time::NANOS_PER_SEC);
+
+    let result = on_bsp(|| with_cpus_stopped(|aps| Err(unsafe { hibernate::restore(aps) })));
+
+    scheme::notify(Transition::Resume);
+    if let Err(err) = result {
+        error!("Failed to restore hibernation image: {}", err);
+    }
+    result
+}
+
+/// Tell drivers that the system powers off, and power off once they are ready
+pub fn power_off() -> Result<()> {
+    let _guard = SUSPEND_LOCK.try_lock().ok_or(Error::new(EBUSY))?;
+
+    info!("Powering off");
+    quiesce(Transition::PowerOff, SHUTDOWN_TIMEOUT);
+    unsafe { crate::stop::kstop() }
+}
+
+/// Tell drivers that the system reboots, and reset once they are ready
+pub fn reboot() -> Result<()> {
+    let _guard = SUSPEND_LOCK.try_lock().ok_or(Error::new(EBUSY))?;
+
+    info!("Rebooting");
+    quiesce(Transition::Reboot, SHUTDOWN_TIMEOUT);
+    unsafe { crate::stop::kreset() }
+}
+
+/// Tell drivers that the system reboots, and boot into the kernel loaded on demand (see `kexec`)
+/// once they are ready. Only returns if none is loaded.
+pub fn kexec() -> Result<()> {
+    if !matches!(kexec::loaded(), Some((_, false))) {
+        return Err(Error::new(ENOENT));
+    }
+    let _guard = SUSPEND_LOCK.try_lock().ok_or(Error::new(EBUSY))?;
+
+    info!("Booting into the loaded kernel");
+    quiesce(Transition::Reboot, SHUTDOWN_TIMEOUT);
+    on_bsp(|| with_cpus_stopped(|_| Err(unsafe { kexec::exec() })))
+}
+
+/// Enter ACPI S5, only returning if the sleep types are unknown or the system did not power off
+pub unsafe fn enter_s5() {
+    let (Some(fadt), Some((slp_typa, slp_typb))) = (FADT.get(), s5_sleep_types()) else {
+        return;
+    };
+    if fadt.pm1a_control_block == 0 {
+        return;
+    }
+    info!("Powering off through ACPI S5");
+    fadt.enter_sleep_state(slp_typa, slp_typb);
+}
+
+/// Run `f` with the current context on the BSP, restoring its affinity afterwards
+fn on_bsp<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let context_lock = context::current()?;
+    let affinity = move_to_bsp(&context_lock);
+
+    let result = f();
+
+    context_lock.write().sched_affinity = affinity;
+    result
+}
+
+/// Tell drivers of `transition`, and wait for them to be ready, for at most `timeout` nanoseconds
+fn quiesce(transition: Transition, timeout: u128) {
+    scheme::notify(transition);
+
+    let initial = time::monotonic();
+    while !scheme::all_ready() {
+        let _ = unsafe { context::switch() };
+
+        if time::monotonic() - initial > timeout {
+            warn!("Going on without every driver ready");
+            return;
+        }
+    }
+}
+
+/// Move the current context to the BSP, returning its previous affinity
+fn move_to_bsp(context_lock: &Arc<RwLock<Context>>) -> Option<usize> {
+    let affinity = {
+        let mut context = context_lock.write();
+        let affinity = context.sched_affinity;
+        context.sched_affinity = Some(0);
+        if crate::cpu_id() != 0 {
+            // Released, to be taken over by the BSP once switched away from
+            context.cpu_id = None;
+        }
+        affinity
+    };
+
+    while crate::cpu_id() != 0 {
+        ipi_single(IpiKind::Wakeup, 0);
+        unsafe { context::switch(); }
+    }
+
+    affinity
+}
+
+/// Take the other CPUs offline and stop them, then call `f` on the BSP with the stopped CPUs. It
+/// restarts them before returning.
+fn with_cpus_stopped<T>(f: impl FnOnce(u64) -> Result<T>) -> Result<T> {
+    let aps = (1..crate::cpu_count().min(CPUS)).fold(0, |aps, cpu_id| aps | bit(cpu_id));
+
+    let mut online = 0;
+    for cpu_id in (1..CPUS).filter(|&cpu_id| aps & bit(cpu_id) != 0) {
+        if hotplug::is_offline(cpu_id) {
+            continue;
+        }
+        if let Err(err) = hotplug::offline(cpu_id) {
+            bring_online(online);
+            return Err(err);
+        }
+        online |= bit(cpu_id);
+    }
+
+    // Wake the parked CPUs, to stop them
+    SUSPENDING.store(true, Ordering::SeqCst);
+    ipi(IpiKind::Wakeup, IpiTarget::Other);
+    while STOPPED.load(Ordering::SeqCst) != aps {
+        interrupt::pause();
+    }
+
+    let result = f(aps);
+
+    SUSPENDING.store(false, Ordering::SeqCst);
+    STOPPED.store(0, Ordering::SeqCst);
+
+    bring_online(online);
+    result
+}
+
+fn bring_online(cpus: u64) {
+    for cpu_id in (1..CPUS).filter(|&cpu_id| cpus & bit(cpu_id) != 0) {
+        if let Err(err) = hotplug::online(cpu_id) {
+            error!("Failed to bring CPU {} back online: {}", cpu_id, err);
+        }
+    }
+}
+
+/// Stop the current CPU while the BSP suspends, or takes or restores an image. Called by the parking loop of secondary CPUs,
+/// with interrupts disabled, and returns right away otherwise, or once resumed.
+pub unsafe fn park(cpu_id: usize) {
+    if !SUSPENDING.load(Ordering::SeqCst) || STOPPED.load(Ordering::SeqCst) & bit(cpu_id) != 0 {
+        return;
+    }
+
+    if suspend::save(cpu_id) {
+        return;
+    }
+    STOPPED.fetch_or(bit(cpu_id), Ordering::SeqCst);
+
+    // Only the INIT IPI restarting the CPU wakes it up
+    loop {
+        interrupt::halt();
+    }
+}
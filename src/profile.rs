@@ -0,0 +1,207 @@
+//! # Profiler
+//! A sampling profiler of the whole system, which does not need the PMU. While enabled through
+//! `profile:ctl` (see `scheme::profile`), each timer tick records a `ProfileSample` of the code it
+//! interrupted: the running context, whether it was in userspace, and its call stack, found by
+//! following frame pointers from the interrupted instruction. Code running with interrupts
+//! disabled is not seen by timer ticks, so it is sampled by NMIs instead, such as those of the NMI
+//! watchdog and of `perf`.
+//!
+//! Samples are written to a ring per CPU, allocated the first time profiling is enabled, and
+//! consumed by the readers of `profile:`, which are woken on the timer ticks of the BSP. A ring is
+//! only written by its own CPU, so recording is lock-free, and a sample taken by an NMI which
+//! interrupted the recording of another on the same CPU is dropped.
+//!
+//! Frames are only read from memory mapped by the page tables of the CPU, through the mapping of
+//! physical memory, so a bad frame pointer ends the call stack instead of faulting. Userspace built
+//! without frame pointers gives call stacks of the instruction pointer alone, or bogus ones.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use rmm::{PageMapper, TableKind};
+use spin::Once;
+
+use crate::memory::PAGE_SIZE;
+use crate::paging::{huge, RmmA, RmmArch, VirtualAddress};
+use crate::rmm::FRAME_ALLOCATOR;
+use crate::sync::Mutex;
+use crate::syscall::abi::{ProfileSample, PROFILE_DEPTH, PROFILE_NMI, PROFILE_USER};
+use crate::USER_END_OFFSET;
+
+/// Number of CPUs with a ring, samples on other CPUs are not recorded
+const CPUS: usize = 32;
+/// Number of samples per CPU
+const SAMPLES: usize = 1024;
+
+struct Ring {
+    /// Total number of samples recorded, the next one is at `head % SAMPLES`
+    head: AtomicUsize,
+    /// Total number of samples read
+    tail: AtomicUsize,
+    /// Samples dropped because the ring was full
+    lost: AtomicU64,
+    samples: Box<[UnsafeCell<ProfileSample>]>,
+    /// Held by the reader advancing `tail`
+    reader: Mutex<()>,
+}
+
+// Samples between `tail` and `head` are only read, and the others only written by the CPU
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    fn new() -> Self {
+        Self {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            lost: AtomicU64::new(0),
+            samples: (0..SAMPLES).map(|_| UnsafeCell::new(ProfileSample::default())).collect(),
+            reader: Mutex::new(()),
+        }
+    }
+
+    fn push(&self, sample: &ProfileSample) {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Acquire)) >= SAMPLES {
+            self.lost.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        unsafe { *self.samples[head % SAMPLES].get() = *sample };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Move up to `max` samples to `samples`
+    fn take(&self, samples: &mut Vec<ProfileSample>, max: usize) {
+        let _reader = self.reader.lock();
+        let head = self.head.load(Ordering::Acquire);
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        while tail != head && samples.len() < max {
+            samples.push(unsafe { *self.samples[tail % SAMPLES].get() });
+            tail = tail.wrapping_add(1);
+        }
+        self.tail.store(tail, Ordering::Release);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Relaxed)
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static RINGS: Once<Vec<Ring>> = Once::new();
+
+/// Set while the current CPU is recording a sample
+#[thread_local]
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+/// Returns true if samples are being recorded
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    if enabled {
+        RINGS.call_once(|| (0..CPUS).map(|_| Ring::new()).collect());
+    }
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Samples dropped on all CPUs because their ring was full
+pub fn lost() -> u64 {
+    RINGS.get().map_or(0, |rings| rings.iter().map(|ring| ring.lost.load(Ordering::Relaxed)).sum())
+}
+
+/// Read the word at `address`, if it is mapped in the current page tables. Userspace must not
+/// read kernel memory through its frame pointers, nor the kernel userspace memory.
+fn read_word(address: usize, user: bool) -> Option<usize> {
+    if address % mem::size_of::<usize>() != 0 || (address < USER_END_OFFSET) != user {
+        return None;
+    }
+    let kind = if user { TableKind::User } else { TableKind::Kernel };
+    let mapper = unsafe { PageMapper::<RmmA, _>::current(kind, FRAME_ALLOCATOR) };
+    let (phys, _) = huge::translate(&mapper, VirtualAddress::new(address))?;
+    let mut ram = false;
+    crate::rmm::for_each_ram_range(|start, end| ram |= (start..end).contains(&phys.data()));
+    if !ram {
+        return None;
+    }
+    let virt = RmmA::phys_to_virt(phys).data() + address % PAGE_SIZE;
+    Some(unsafe { (virt as *const usize).read_volatile() })
+}
+
+fn record(ip: usize, fp: usize, user: bool, flags: u32) {
+    let cpu = crate::cpu_id();
+    let Some(ring) = RINGS.get().and_then(|rings| rings.get(cpu)) else {
+        return;
+    };
+    if RECORDING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let mut sample = ProfileSample {
+        time: crate::time::try_monotonic().unwrap_or(0) as u64,
+        pid: crate::context::context_id().into() as u64,
+        cpu: cpu as u32,
+        flags: if user { flags | PROFILE_USER } else { flags },
+        depth: 1,
+        stack: [0; PROFILE_DEPTH],
+    };
+    sample.stack[0] = ip as u64;
+
+    // Each frame starts with the frame pointer of its caller, followed by the return address
+    let mut fp = fp;
+    while (sample.depth as usize) < PROFILE_DEPTH {
+        let Some(next) = read_word(fp, user) else {
+            break;
+        };
+        let Some(ret) = read_word(fp.wrapping_add(mem::size_of::<usize>()), user).filter(|&ret| ret != 0) else {
+            break;
+        };
+        sample.stack[sample.depth as usize] = ret as u64;
+        sample.depth += 1;
+        // Callers are further up the stack, which ends loops
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+
+    ring.push(&sample);
+    RECORDING.store(false, Ordering::Relaxed);
+}
+
+/// Sample the code interrupted by a timer tick, at `ip` with the frame pointer `fp`
+pub fn tick(ip: usize, fp: usize, user: bool) {
+    if enabled() {
+        record(ip, fp, user, 0);
+    }
+
+    if crate::cpu_id() == 0 && RINGS.get().map_or(false, |rings| rings.iter().any(|ring| !ring.is_empty())) {
+        crate::scheme::profile::wake();
+    }
+}
+
+/// Sample the code interrupted by an NMI, if timer ticks could not have, as it had interrupts
+/// disabled
+pub fn nmi(ip: usize, fp: usize, user: bool, interrupts: bool) {
+    if enabled() && !interrupts {
+        record(ip, fp, user, PROFILE_NMI);
+    }
+}
+
+/// Move up to `max` samples recorded on `cpu`, or on all CPUs, to a new vector
+pub fn take(cpu: Option<usize>, max: usize) -> Vec<ProfileSample> {
+    let mut samples = Vec::new();
+    let Some(rings) = RINGS.get() else {
+        return samples;
+    };
+    for (id, ring) in rings.iter().enumerate() {
+        if cpu.map_or(true, |cpu| cpu == id) {
+            ring.take(&mut samples, max);
+        }
+    }
+    samples
+}
@@ -10,10 +10,10 @@ use crate::{
         paging::{PAGE_SIZE, VirtualAddress},
     },
     common::unique::Unique,
-    context::{self, signal, Context, ContextId, memory::AddrSpace},
+    context::{self, signal, Context, ContextId, ContextList, memory::{self, AddrSpace}},
     event,
     scheme::proc,
-    sync::WaitCondition,
+    sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, WaitCondition},
     syscall::{
         data::PtraceEvent,
         error::*,
@@ -33,7 +33,7 @@ use alloc::{
     sync::Arc,
 };
 use core::cmp;
-use spin::{Mutex, Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use spin::Once;
 
 //  ____                _
 // / ___|  ___  ___ ___(_) ___  _ __  ___
@@ -176,6 +176,33 @@ pub fn close_tracee(pid: ContextId) {
     }
 }
 
+/// Check that `current`, running as `uid` and `gid`, may trace `target`: either itself, or a
+/// descendant it owns. Root may trace any context, which callers check first.
+pub fn check_tracer(contexts: &ContextList, current: &Context, target: &Context, uid: u32, gid: u32) -> Result<()> {
+    // Are we the process?
+    if target.id == current.id {
+        return Ok(());
+    }
+
+    // Do we own the process?
+    if uid != target.euid && gid != target.egid {
+        return Err(Error::new(EPERM));
+    }
+
+    // Is it a subprocess of us? In the future, a capability could
+    // bypass this check.
+    match contexts.ancestors(target.ppid).find(|&(id, _context)| id == current.id) {
+        Some((id, context)) => {
+            // Paranoid sanity check, as ptrace security holes
+            // wouldn't be fun
+            assert_eq!(id, current.id);
+            assert_eq!(id, context.read().id);
+            Ok(())
+        },
+        None => Err(Error::new(EPERM)),
+    }
+}
+
 /// Returns true if a session is attached to this process
 pub fn is_traced(pid: ContextId) -> bool {
     sessions().contains_key(&pid)
@@ -477,7 +504,7 @@ pub fn context_memory(addrspace: &mut AddrSpace, offset: VirtualAddress, len: us
         // [addr,addr+len) is a continuous page starting and/or ending at page boundaries, with the
         // possible exception of an unaligned head/tail.
 
-        let (address, flags) = addrspace.table.utable.translate(VirtualAddress::new(addr))?;
+        let (address, flags) = memory::translate(&addrspace.table.utable, VirtualAddress::new(addr))?;
 
         let start = RmmA::phys_to_virt(address).data() + addr % crate::memory::PAGE_SIZE;
         Some((core::ptr::slice_from_raw_parts_mut(start as *mut u8, len), flags.has_write()))
@@ -4,16 +4,20 @@ use core::sync::atomic::{self, AtomicUsize};
 
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
 
-use spin::{Mutex, Once, RwLock};
+use spin::Once;
 
+use crate::acpi::aml::{self, AmlValue};
+use crate::acpi::sci;
 use crate::acpi::{RXSDT_ENUM, RxsdtEnum};
 use crate::event;
 use crate::scheme::SchemeId;
-use crate::sync::WaitCondition;
+use crate::sync::{Mutex, RwLock, WaitCondition};
 
 use crate::syscall::data::Stat;
-use crate::syscall::error::{EACCES, EBADF, EBADFD, EINTR, EINVAL, EISDIR, ENOENT, ENOTDIR, EROFS};
+use crate::syscall::error::{EACCES, EBADF, EBADFD, EINTR, EINVAL, EISDIR, ENODEV, ENOENT, ENOTDIR, EROFS, ESPIPE};
 use crate::syscall::flag::{
     EventFlags, EVENT_READ,
     MODE_CHR, MODE_DIR, MODE_FILE,
@@ -25,6 +29,16 @@ use crate::syscall::error::{Error, Result};
 use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
 
 /// A scheme used to access the RSDT or XSDT, which is needed for e.g. `acpid` to function.
+///
+/// `acpi:aml` lists the objects of the ACPI namespace (see `acpi::aml`), and reading
+/// `acpi:aml/<path>` evaluates one, such as `acpi:aml/\_SB.PCI0._PRT`, as ASL-like text. Writing
+/// arguments to it, integers or strings separated by spaces, invokes the method with them instead,
+/// and the result is read from the start.
+///
+/// Opening `acpi:events` has the kernel handle the SCI (see `acpi::sci`). Reading it returns the
+/// events raised since, one per line, `power_button`, `sleep_button`, `lid open`, `lid closed` or
+/// `gpe <number>`, and it is readable with `EVENT_READ` once one is. `acpi:lid` reads as `open` or
+/// `closed`.
 pub struct AcpiScheme;
 
 struct Handle {
@@ -37,6 +51,15 @@ enum HandleKind {
     TopLevel,
     Rxsdt,
     ShutdownPipe,
+    AmlList(Vec<u8>),
+    /// The events not yet read
+    Events(Vec<u8>),
+    Lid,
+    Aml {
+        path: String,
+        /// The value it evaluated to, once read or written
+        value: Option<Vec<u8>>,
+    },
 }
 
 static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
@@ -44,13 +67,60 @@ static NEXT_FD: AtomicUsize = AtomicUsize::new(0);
 
 static DATA: Once<Box<[u8]>> = Once::new();
 
-const TOPLEVEL_CONTENTS: &[u8] = b"rxsdt\nkstop\n";
+const TOPLEVEL_CONTENTS: &[u8] = b"rxsdt\nkstop\naml\nevents\nlid\n";
 
 static KSTOP_WAITCOND: WaitCondition = WaitCondition::new();
 static KSTOP_FLAG: Mutex<bool> = Mutex::new(false);
 
 static SCHEME_ID: Once<SchemeId> = Once::new();
 
+/// Evaluate the object at `path` with `args`, as text
+fn evaluate(path: &str, args: Vec<AmlValue>) -> Result<Vec<u8>> {
+    let value = aml::evaluate(path, args)?;
+    let mut string = String::new();
+    aml::render(&mut string, &value);
+    string.push('\n');
+    Ok(string.into_bytes())
+}
+
+/// Parse arguments, as integers or strings separated by spaces
+fn parse_args(string: &str) -> Vec<AmlValue> {
+    string.split_whitespace().map(|arg| {
+        let integer = match arg.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => arg.parse().ok(),
+        };
+        integer.map_or_else(|| AmlValue::String(String::from(arg)), AmlValue::Integer)
+    }).collect()
+}
+
+/// Tell the readers of `events` that the SCI raised events. Called from the interrupt, as syscalls
+/// run with interrupts disabled the handles are not held on this CPU.
+pub fn sci_notify() {
+    let Some(&acpi_scheme) = SCHEME_ID.get() else {
+        return;
+    };
+    for (&fd, _) in HANDLES.read().iter().filter(|(_, handle)| matches!(handle.kind, HandleKind::Events(_))) {
+        event::trigger(acpi_scheme, fd, EVENT_READ);
+    }
+}
+
+/// Handle the pending events of the SCI, and queue them for every reader of `events`
+fn process_events() {
+    let events = sci::process();
+    if events.is_empty() {
+        return;
+    }
+    for handle in HANDLES.write().values_mut() {
+        if let HandleKind::Events(ref mut pending) = handle.kind {
+            for event in events.iter() {
+                pending.extend_from_slice(event.name().as_bytes());
+                pending.push(b'\n');
+            }
+        }
+    }
+}
+
 pub fn register_kstop() -> bool {
     *KSTOP_FLAG.lock() = true;
     let mut waiters_awoken = KSTOP_WAITCOND.notify();
@@ -126,7 +196,7 @@ impl Scheme for AcpiScheme {
         if flags & O_EXCL == O_EXCL || flags & O_SYMLINK == O_SYMLINK {
             return Err(Error::new(EINVAL));
         }
-        if flags & O_ACCMODE != O_RDONLY && flags & O_STAT != O_STAT {
+        if flags & O_ACCMODE != O_RDONLY && flags & O_STAT != O_STAT && !path.starts_with("aml/") {
             return Err(Error::new(EROFS));
         }
         let handle_kind = match path {
@@ -149,7 +219,42 @@ impl Scheme for AcpiScheme {
                 }
                 HandleKind::ShutdownPipe
             }
-            _ => return Err(Error::new(ENOENT)),
+            "events" => {
+                if flags & O_DIRECTORY == O_DIRECTORY && flags & O_STAT != O_STAT {
+                    return Err(Error::new(ENOTDIR));
+                }
+                if flags & O_STAT != O_STAT && !sci::enable() {
+                    return Err(Error::new(ENODEV));
+                }
+                HandleKind::Events(Vec::new())
+            }
+            "lid" => {
+                if flags & O_DIRECTORY == O_DIRECTORY && flags & O_STAT != O_STAT {
+                    return Err(Error::new(ENOTDIR));
+                }
+                HandleKind::Lid
+            }
+            "aml" => {
+                if flags & O_DIRECTORY != O_DIRECTORY && flags & O_STAT != O_STAT {
+                    return Err(Error::new(EISDIR));
+                }
+                let mut contents = String::new();
+                for object in aml::objects() {
+                    contents.push_str(&object);
+                    contents.push('\n');
+                }
+                HandleKind::AmlList(contents.into_bytes())
+            }
+            _ => {
+                let path = path.strip_prefix("aml/").ok_or(Error::new(ENOENT))?.trim_start_matches('\\');
+                if path.is_empty() {
+                    return Err(Error::new(ENOENT));
+                }
+                if flags & O_DIRECTORY == O_DIRECTORY && flags & O_STAT != O_STAT {
+                    return Err(Error::new(ENOTDIR));
+                }
+                HandleKind::Aml { path: format!("\\{}", path.replace('/', ".")), value: None }
+            }
         };
 
         let fd = NEXT_FD.fetch_add(1, atomic::Ordering::Relaxed);
@@ -175,6 +280,9 @@ impl Scheme for AcpiScheme {
             HandleKind::Rxsdt => DATA.get().ok_or(Error::new(EBADFD))?.len(),
             HandleKind::ShutdownPipe => 1,
             HandleKind::TopLevel => TOPLEVEL_CONTENTS.len(),
+            HandleKind::AmlList(ref contents) => contents.len(),
+            HandleKind::Aml { ref value, .. } => value.as_ref().map_or(0, Vec::len),
+            HandleKind::Events(_) | HandleKind::Lid => return Err(Error::new(ESPIPE)),
         };
 
         let new_offset = match whence {
@@ -205,7 +313,10 @@ impl Scheme for AcpiScheme {
             return Err(Error::new(EBADF));
         }
 
-        Ok(EventFlags::empty())
+        match handle.kind {
+            HandleKind::Events(ref pending) if !pending.is_empty() || sci::pending() => Ok(EVENT_READ),
+            _ => Ok(EventFlags::empty()),
+        }
     }
     fn close(&self, id: usize) -> Result<usize> {
         if HANDLES.write().remove(&id).is_none() {
@@ -215,10 +326,45 @@ impl Scheme for AcpiScheme {
     }
 }
 impl crate::scheme::KernelScheme for AcpiScheme {
-    fn kwrite(&self, _id: usize, _buf: UserSliceRo) -> Result<usize> {
-        Err(Error::new(EBADF))
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            Handle { kind: HandleKind::Aml { path, .. }, stat: false, .. } => path.clone(),
+            _ => return Err(Error::new(EBADF)),
+        };
+
+        let mut bytes = [0_u8; 256];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        let args = str::from_utf8(&bytes[..len]).map(parse_args).map_err(|_| Error::new(EINVAL))?;
+
+        // Evaluating may take long, the handles are not held meanwhile
+        let data = evaluate(&path, args)?;
+        if let Some(handle) = HANDLES.write().get_mut(&id) {
+            if let HandleKind::Aml { ref mut value, .. } = handle.kind {
+                *value = Some(data);
+                handle.offset = 0;
+            }
+        }
+        Ok(len)
     }
     fn kread(&self, id: usize, dst_buf: UserSliceWo) -> Result<usize> {
+        // Evaluating may take long, the handles are not held meanwhile
+        let (unevaluated, events, lid) = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            Handle { kind: HandleKind::Aml { path, value: None }, stat: false, .. } => (Some(path.clone()), false, false),
+            Handle { kind: HandleKind::Events(_), .. } => (None, true, false),
+            Handle { kind: HandleKind::Lid, .. } => (None, false, true),
+            _ => (None, false, false),
+        };
+        if events {
+            process_events();
+        }
+        let lid = if lid { sci::lid() } else { None };
+        if let Some(path) = unevaluated {
+            let data = evaluate(&path, Vec::new())?;
+            if let Some(Handle { kind: HandleKind::Aml { value, .. }, .. }) = HANDLES.write().get_mut(&id) {
+                value.get_or_insert(data);
+            }
+        }
+
         let mut handles = HANDLES.write();
         let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
 
@@ -248,6 +394,23 @@ impl crate::scheme::KernelScheme for AcpiScheme {
             }
             HandleKind::Rxsdt => DATA.get().ok_or(Error::new(EBADFD))?,
             HandleKind::TopLevel => TOPLEVEL_CONTENTS,
+            HandleKind::AmlList(ref contents) => contents,
+            HandleKind::Aml { ref value, .. } => value.as_deref().unwrap_or(&[]),
+            HandleKind::Events(ref mut pending) => {
+                let bytes_copied = dst_buf.copy_common_bytes_from_slice(pending)?;
+                pending.drain(..bytes_copied);
+                return Ok(bytes_copied);
+            }
+            HandleKind::Lid => {
+                let data: &[u8] = match lid {
+                    Some(true) => b"open\n",
+                    Some(false) => b"closed\n",
+                    None => return Err(Error::new(ENODEV)),
+                };
+                let bytes_copied = dst_buf.copy_common_bytes_from_slice(data.get(handle.offset..).unwrap_or(&[]))?;
+                handle.offset += bytes_copied;
+                return Ok(bytes_copied);
+            }
         };
 
         let src_offset = core::cmp::min(handle.offset, data.len());
@@ -284,6 +447,20 @@ impl crate::scheme::KernelScheme for AcpiScheme {
                 st_size: 1,
                 ..Default::default()
             },
+            HandleKind::Events(_) | HandleKind::Lid => Stat {
+                st_mode: MODE_CHR,
+                ..Default::default()
+            },
+            HandleKind::AmlList(ref contents) => Stat {
+                st_mode: MODE_DIR,
+                st_size: contents.len().try_into().unwrap_or(u64::max_value()),
+                ..Default::default()
+            },
+            HandleKind::Aml { ref value, .. } => Stat {
+                st_mode: MODE_FILE,
+                st_size: value.as_ref().map_or(0, Vec::len).try_into().unwrap_or(u64::max_value()),
+                ..Default::default()
+            },
         })?;
 
         Ok(0)
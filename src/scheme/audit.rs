@@ -0,0 +1,164 @@
+//! # Audit
+//! `audit:log` reads the audit records (see `crate::audit`) as lines of text, starting from the
+//! oldest one kept, each handle with its own position. Reads return whole records only, and return
+//! nothing once all have been read; `EVENT_READ` is triggered when a new record arrives.
+//! `audit:rules` reads the current rules, one per line, and writing replaces all of them at once.
+//! Only root can open the scheme.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::audit::{self, Rule};
+use crate::event;
+use crate::memory::PAGE_SIZE;
+use crate::sync::RwLock;
+use crate::syscall::error::*;
+use crate::syscall::flag::{EventFlags, EVENT_READ};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::{AtomicSchemeId, KernelScheme, SchemeId};
+
+/// Largest rule list that can be written
+const MAX_RULES_SIZE: usize = PAGE_SIZE;
+
+#[derive(Clone, Copy)]
+enum File {
+    Log,
+    Rules,
+}
+
+struct Handle {
+    file: File,
+    /// The sequence number of the next record for `Log`, the byte offset for `Rules`
+    position: u64,
+}
+
+static SCHEME_ID: AtomicSchemeId = AtomicSchemeId::default();
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn rules() -> Vec<u8> {
+    let mut string = String::new();
+    for rule in audit::rules() {
+        let _ = writeln!(string, "{}", rule);
+    }
+    string.into_bytes()
+}
+
+/// Trigger `EVENT_READ` on every log handle, after a record was added
+pub fn notify() {
+    let scheme_id = SCHEME_ID.load(Ordering::SeqCst);
+    for (&id, handle) in HANDLES.read().iter() {
+        if let File::Log = handle.file {
+            event::trigger(scheme_id, id, EVENT_READ);
+        }
+    }
+}
+
+pub struct AuditScheme;
+
+impl AuditScheme {
+    pub fn new(scheme_id: SchemeId) -> Self {
+        SCHEME_ID.store(scheme_id, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Scheme for AuditScheme {
+    fn open(&self, path: &str, _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        let (file, position) = match path.trim_matches('/') {
+            "log" => (File::Log, audit::oldest_seq()),
+            "rules" => (File::Rules, 0),
+            _ => return Err(Error::new(ENOENT)),
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { file, position });
+        Ok(id)
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        let handles = HANDLES.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+        // Records may have arrived before the handle was registered for events
+        match handle.file {
+            File::Log if handle.position < audit::next_seq() => Ok(EVENT_READ),
+            _ => Ok(EventFlags::empty()),
+        }
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+}
+impl KernelScheme for AuditScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let (file, position) = {
+            let handles = HANDLES.read();
+            let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+            (handle.file, handle.position)
+        };
+
+        let (bytes_read, position) = match file {
+            File::Log => {
+                let (data, next) = audit::read(position, buf.len())?;
+                (buf.copy_common_bytes_from_slice(data.as_bytes())?, next)
+            }
+            File::Rules => {
+                let data = rules();
+                let bytes_read = buf.copy_common_bytes_from_slice(data.get(position as usize..).unwrap_or(&[]))?;
+                (bytes_read, position + bytes_read as u64)
+            }
+        };
+
+        if let Some(handle) = HANDLES.write().get_mut(&id) {
+            handle.position = position;
+        }
+        Ok(bytes_read)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        if let File::Log = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            return Err(Error::new(EBADF));
+        }
+        if buf.len() > MAX_RULES_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut bytes = vec![0_u8; buf.len()];
+        buf.copy_to_slice(&mut bytes)?;
+        let text = str::from_utf8(&bytes).map_err(|_| Error::new(EINVAL))?;
+        let rules = text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Rule::parse)
+            .collect::<Result<Vec<Rule>>>()?;
+        audit::set_rules(rules);
+
+        Ok(bytes.len())
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Log => "audit:log",
+            File::Rules => "audit:rules",
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
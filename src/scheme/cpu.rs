@@ -0,0 +1,128 @@
+//! # CPU
+//! `cpu:status` lists whether each CPU is online or offline. `cpu:<id>` reads as `online` or
+//! `offline`, and root can write either to take a secondary CPU offline or bring it back online
+//! (see `hotplug`). Writes return once the CPU has changed state.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::hotplug;
+use crate::sync::RwLock;
+use crate::syscall::error::*;
+use crate::syscall::flag::{O_ACCMODE, O_RDONLY};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::KernelScheme;
+
+#[derive(Clone, Copy)]
+enum File {
+    Status,
+    Cpu(usize),
+}
+
+struct Handle {
+    file: File,
+    offset: usize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn state(cpu_id: usize) -> &'static str {
+    if hotplug::is_offline(cpu_id) { "offline" } else { "online" }
+}
+
+fn contents(file: File) -> Vec<u8> {
+    let mut string = String::new();
+    match file {
+        File::Status => {
+            for cpu_id in 0..crate::cpu_count() {
+                let _ = writeln!(string, "cpu{}: {}", cpu_id, state(cpu_id));
+            }
+        }
+        File::Cpu(cpu_id) => {
+            let _ = writeln!(string, "{}", state(cpu_id));
+        }
+    }
+    string.into_bytes()
+}
+
+pub struct CpuScheme;
+
+impl Scheme for CpuScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let file = match path.trim_matches('/') {
+            "status" => File::Status,
+            id => {
+                let cpu_id = id.parse::<usize>().map_err(|_| Error::new(ENOENT))?;
+                if cpu_id >= crate::cpu_count() {
+                    return Err(Error::new(ENOENT));
+                }
+                File::Cpu(cpu_id)
+            }
+        };
+        if flags & O_ACCMODE != O_RDONLY {
+            if uid != 0 {
+                return Err(Error::new(EACCES));
+            }
+            if let File::Status = file {
+                return Err(Error::new(EROFS));
+            }
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { file, offset: 0 });
+        Ok(id)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+}
+impl KernelScheme for CpuScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let data = contents(handle.file);
+        let bytes_read = buf.copy_common_bytes_from_slice(data.get(handle.offset..).unwrap_or(&[]))?;
+        handle.offset += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        let cpu_id = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Cpu(cpu_id) => cpu_id,
+            File::Status => return Err(Error::new(EBADF)),
+        };
+
+        let mut bytes = [0_u8; 8];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        match str::from_utf8(&bytes[..len]).map(str::trim) {
+            Ok("online" | "1") => hotplug::online(cpu_id)?,
+            Ok("offline" | "0") => hotplug::offline(cpu_id)?,
+            _ => return Err(Error::new(EINVAL)),
+        }
+        Ok(len)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Status => String::from("cpu:status"),
+            File::Cpu(cpu_id) => format!("cpu:{}", cpu_id),
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
@@ -0,0 +1,198 @@
+//! # CPU frequency
+//! `cpufreq:status` lists the driver, governor and frequency of each CPU. `cpufreq:<id>/governor`
+//! reads as the governor of a CPU, and root can write `performance`, `powersave` or `ondemand` to
+//! choose it (see `cpufreq`). `cpufreq:<id>/frequencies` lists the frequencies of its states, in
+//! MHz, fastest first.
+//!
+//! `cpufreq:pss` holds the states of the ACPI `_PSS` object, used by CPUs with no native driver,
+//! one per line as the frequency in MHz and the control value, fastest first. The kernel evaluates
+//! them if it could load the ACPI namespace (see `acpi::aml`), and the ACPI driver can write them
+//! otherwise.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::cpufreq::{self as driver, PssState};
+use crate::cpufreq::{self, Governor};
+use crate::sync::RwLock;
+use crate::syscall::error::*;
+use crate::syscall::flag::{O_ACCMODE, O_RDONLY};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::KernelScheme;
+
+/// Most bytes written to `pss` at once
+const PSS_SIZE: usize = 1024;
+
+#[derive(Clone, Copy)]
+enum File {
+    Status,
+    Governor(usize),
+    Frequencies(usize),
+    Pss,
+}
+
+struct Handle {
+    file: File,
+    offset: usize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn contents(file: File) -> Vec<u8> {
+    let mut string = String::new();
+    match file {
+        File::Status => {
+            for cpu_id in 0..crate::cpu_count() {
+                let _ = write!(string, "cpu{}: {}", cpu_id, driver::driver(cpu_id).unwrap_or("none"));
+                if let Some(governor) = cpufreq::governor(cpu_id).filter(|_| driver::count(cpu_id) > 0) {
+                    let _ = write!(string, " {}", governor.name());
+                }
+                if let Some(mhz) = cpufreq::frequency(cpu_id) {
+                    let _ = write!(string, " {} MHz", mhz);
+                }
+                string.push('\n');
+            }
+        }
+        File::Governor(cpu_id) => {
+            if let Some(governor) = cpufreq::governor(cpu_id) {
+                let _ = writeln!(string, "{}", governor.name());
+            }
+        }
+        File::Frequencies(cpu_id) => {
+            let frequencies = (0..driver::count(cpu_id)).filter_map(|index| driver::frequency(cpu_id, index));
+            for (i, mhz) in frequencies.enumerate() {
+                let _ = write!(string, "{}{}", if i == 0 { "" } else { " " }, mhz);
+            }
+            string.push('\n');
+        }
+        File::Pss => {
+            for state in driver::pss() {
+                let _ = writeln!(string, "{} {:#x}", state.mhz, state.control);
+            }
+        }
+    }
+    string.into_bytes()
+}
+
+fn parse_number(string: &str) -> Option<u64> {
+    match string.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => string.parse().ok(),
+    }
+}
+
+fn parse_pss(string: &str) -> Option<Vec<PssState>> {
+    string.lines().map(str::trim).filter(|line| !line.is_empty()).map(|line| {
+        let mut values = line.split_whitespace().map(parse_number);
+        match (values.next(), values.next(), values.next()) {
+            (Some(Some(mhz)), Some(Some(control)), None) => Some(PssState { mhz: u32::try_from(mhz).ok()?, control }),
+            _ => None,
+        }
+    }).collect()
+}
+
+pub struct CpuFreqScheme;
+
+impl Scheme for CpuFreqScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let file = match path.trim_matches('/') {
+            "status" => File::Status,
+            "pss" => File::Pss,
+            path => {
+                let (id, name) = path.split_once('/').ok_or(Error::new(ENOENT))?;
+                let cpu_id = id.parse::<usize>().map_err(|_| Error::new(ENOENT))?;
+                if cpu_id >= crate::cpu_count() || cpu_id >= cpufreq::CPUS {
+                    return Err(Error::new(ENOENT));
+                }
+                match name {
+                    "governor" => File::Governor(cpu_id),
+                    "frequencies" => File::Frequencies(cpu_id),
+                    _ => return Err(Error::new(ENOENT)),
+                }
+            }
+        };
+        if flags & O_ACCMODE != O_RDONLY {
+            if uid != 0 {
+                return Err(Error::new(EACCES));
+            }
+            if let File::Status | File::Frequencies(_) = file {
+                return Err(Error::new(EROFS));
+            }
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { file, offset: 0 });
+        Ok(id)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+}
+impl KernelScheme for CpuFreqScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let data = contents(handle.file);
+        let bytes_read = buf.copy_common_bytes_from_slice(data.get(handle.offset..).unwrap_or(&[]))?;
+        handle.offset += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        let file = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file;
+
+        match file {
+            File::Governor(cpu_id) => {
+                let mut bytes = [0_u8; 16];
+                let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+                let governor = str::from_utf8(&bytes[..len]).ok()
+                    .and_then(|name| Governor::from_name(name.trim()))
+                    .ok_or(Error::new(EINVAL))?;
+                if !cpufreq::set_governor(cpu_id, governor) {
+                    return Err(Error::new(EINVAL));
+                }
+                Ok(len)
+            }
+            File::Pss => {
+                // The whole table is written at once
+                if buf.len() > PSS_SIZE {
+                    return Err(Error::new(EINVAL));
+                }
+                let mut bytes = [0_u8; PSS_SIZE];
+                let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+                let states = str::from_utf8(&bytes[..len]).ok().and_then(parse_pss).ok_or(Error::new(EINVAL))?;
+                if !driver::set_pss(states) {
+                    return Err(Error::new(EINVAL));
+                }
+                Ok(len)
+            }
+            File::Status | File::Frequencies(_) => Err(Error::new(EBADF)),
+        }
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Status => String::from("cpufreq:status"),
+            File::Governor(cpu_id) => format!("cpufreq:{}/governor", cpu_id),
+            File::Frequencies(cpu_id) => format!("cpufreq:{}/frequencies", cpu_id),
+            File::Pss => String::from("cpufreq:pss"),
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
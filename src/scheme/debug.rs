@@ -1,10 +1,12 @@
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use spin::Once;
 
 use crate::arch::debug::Writer;
 use crate::event;
+use crate::log::{FACILITY_USER, LEVEL_INFO};
 use crate::scheme::*;
-use crate::sync::WaitQueue;
+use crate::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard, WaitQueue};
+use crate::syscall::abi::BOOST_DEBUG;
 use crate::syscall::flag::{EventFlags, EVENT_READ, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK};
 use crate::syscall::scheme::Scheme;
 use crate::syscall::usercopy::UserSliceRo;
@@ -43,13 +45,13 @@ fn handles_mut() -> RwLockWriteGuard<'static, BTreeMap<usize, Handle>> {
 
 /// Add to the input queue
 pub fn debug_input(data: u8) {
-    INPUT.call_once(init_input).send(data);
+    INPUT.call_once(init_input).send_boosted(data, BOOST_DEBUG);
 }
 
 // Notify readers of input updates
 pub fn debug_notify() {
     for (id, _handle) in handles().iter() {
-        event::trigger(SCHEME_ID.load(Ordering::SeqCst), *id, EVENT_READ);
+        event::trigger_boosted(SCHEME_ID.load(Ordering::SeqCst), *id, EVENT_READ, BOOST_DEBUG);
     }
 }
 
@@ -150,7 +152,7 @@ impl crate::scheme::KernelScheme for DebugScheme {
             // The reason why a new writer is created for each iteration, is because the page fault
             // handler in usercopy might use the same lock when printing for debug purposes, and
             // although it most likely won't, it would be dangerous to rely on that assumption.
-            Writer::new().write(tmp_bytes);
+            Writer::with(LEVEL_INFO, FACILITY_USER).write(tmp_bytes);
         }
 
         Ok(buf.len())
@@ -0,0 +1,127 @@
+//! # Directories
+//! Kernel schemes list directories as names, each followed by a newline. A `DirCursor` lists the
+//! current entries on every read, and remembers its position as the name of the last entry it
+//! returned rather than as a byte offset, so that a partially read directory resumes at the right
+//! entry even if entries before it were added or removed in the meantime. Entries are sorted, so
+//! that "after" is well defined.
+//!
+//! `seek(fd, 0, SEEK_CUR)` returns a cookie for the current position, which can be restored with
+//! `seek(fd, cookie, SEEK_SET)` on the same handle, like `telldir` and `seekdir`. Zero is always
+//! the start of the directory. A cookie taken in the middle of an entry resumes after that entry.
+//! Only the last `MAX_COOKIES` cookies of a handle are remembered, older ones fail with `EINVAL`.
+//!
+//! Unlike directories read as a flat buffer, a listing has no byte offsets that stay meaningful
+//! while it changes. Seeking to anything but zero or a cookie, including `SEEK_END` and relative
+//! seeks, fails with `EINVAL`.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::syscall::error::{Error, EINVAL, Result};
+use crate::syscall::flag::{SEEK_CUR, SEEK_SET};
+use crate::syscall::usercopy::UserSliceWo;
+
+/// Number of cookies remembered by each handle
+const MAX_COOKIES: usize = 64;
+
+#[derive(Debug, Default)]
+pub struct DirCursor {
+    /// The last entry returned, or None at the start
+    after: Option<Box<str>>,
+    /// The rest of an entry that did not fit in the previous read
+    pending: Vec<u8>,
+    /// The last positions handed out as cookies, oldest first
+    cookies: VecDeque<(isize, Box<str>)>,
+    /// The last cookie handed out
+    last_cookie: isize,
+}
+
+/// Size of the listing of `entries`, for `st_size`
+pub fn listing_size(entries: &[String]) -> u64 {
+    entries.iter().map(|entry| entry.len() as u64 + 1).sum()
+}
+
+impl DirCursor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the entries after the current position. Only whole entries are returned, unless the
+    /// next entry does not fit in `buf` at all.
+    pub fn read(&mut self, mut entries: Vec<String>, buf: UserSliceWo) -> Result<usize> {
+        let mut written = 0;
+        if !self.pending.is_empty() {
+            written = buf.copy_common_bytes_from_slice(&self.pending)?;
+            self.pending.drain(..written);
+            if !self.pending.is_empty() {
+                return Ok(written);
+            }
+        }
+
+        entries.sort_unstable();
+        entries.dedup();
+        let start = match self.after {
+            Some(ref after) => entries.partition_point(|entry| entry.as_str() <= &**after),
+            None => 0,
+        };
+
+        for entry in entries.into_iter().skip(start) {
+            let rest = match buf.advance(written) {
+                Some(rest) if !rest.is_empty() => rest,
+                _ => break,
+            };
+            let mut line = Vec::with_capacity(entry.len() + 1);
+            line.extend_from_slice(entry.as_bytes());
+            line.push(b'\n');
+            if line.len() > rest.len() && written > 0 {
+                break;
+            }
+
+            let copied = rest.copy_common_bytes_from_slice(&line)?;
+            written += copied;
+            self.pending = line.split_off(copied);
+            self.after = Some(entry.into_boxed_str());
+            if !self.pending.is_empty() {
+                break;
+            }
+        }
+        Ok(written)
+    }
+
+    pub fn seek(&mut self, pos: isize, whence: usize) -> Result<isize> {
+        match (whence, pos) {
+            (SEEK_SET, 0) => {
+                self.after = None;
+                self.pending.clear();
+                Ok(0)
+            }
+            (SEEK_SET, cookie) if cookie > 0 => {
+                let (_, position) = self.cookies.iter().find(|(known, _)| *known == cookie).ok_or(Error::new(EINVAL))?;
+                self.after = Some(position.clone());
+                self.pending.clear();
+                Ok(cookie)
+            }
+            (SEEK_CUR, 0) => Ok(self.cookie()),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    /// The cookie of the current position
+    fn cookie(&mut self) -> isize {
+        let after = match self.after {
+            Some(ref after) => after,
+            None => return 0,
+        };
+        if let Some(&(cookie, _)) = self.cookies.iter().find(|(_, position)| position == after) {
+            return cookie;
+        }
+        if self.cookies.len() >= MAX_COOKIES {
+            self.cookies.pop_front();
+        }
+        self.last_cookie += 1;
+        self.cookies.push_back((self.last_cookie, after.clone()));
+        self.last_cookie
+    }
+}
@@ -0,0 +1,208 @@
+//! # Dump
+//! Root arms kernel dumps (see `dump`) by writing to `dump:ctl`:
+//!
+//! - `arm mini [<KiB>]` sets aside a region for minidumps, of 16 MiB unless given
+//! - `arm full [<KiB>]` sets aside a region for the headers of full dumps, of 256 KiB unless
+//!   given, on x86_64
+//! - `disarm` frees the region
+//! - `capture` writes a dump now
+//!
+//! Arming again replaces the region. Neither is possible while a crash kernel is loaded, as it is
+//! told where the region is.
+//!
+//! `dump:status` lists the region and the dump written last, and `dump:core` reads that dump as
+//! an ELF core. In the crash kernel, the dump of the crashed kernel is read through `physmem:`
+//! instead, from the region given by `CRASH_DUMP=<base>:<size>`.
+//!
+//! `dump:pattern` holds the core pattern of processes (see `context::coredump`), which anyone can
+//! read and root can write.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::context::coredump;
+use crate::dump::{self, Kind};
+use crate::sync::RwLock;
+use crate::syscall::data::Stat;
+use crate::syscall::error::*;
+use crate::syscall::flag::{MODE_FILE, O_ACCMODE, O_RDONLY};
+use crate::syscall::scheme::{calc_seek_offset_usize, Scheme};
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::KernelScheme;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum File {
+    Ctl,
+    Status,
+    Core,
+    Pattern,
+}
+
+struct Handle {
+    file: File,
+    offset: usize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn status() -> Vec<u8> {
+    let mut string = String::new();
+    match dump::armed() {
+        Some((kind, base, size)) => {
+            let kind = match kind {
+                Kind::Mini => "mini",
+                Kind::Full => "full",
+            };
+            let _ = writeln!(string, "armed: {} at {:X}:{:X}", kind, base, base + size);
+        }
+        None => string.push_str("armed: none\n"),
+    }
+    match dump::written() {
+        Some(written) => {
+            let _ = writeln!(string, "written: {} bytes, {} in the region", written.size, written.headers);
+        }
+        None => string.push_str("written: none\n"),
+    }
+    string.into_bytes()
+}
+
+fn pattern() -> Vec<u8> {
+    let mut pattern = coredump::pattern().into_bytes();
+    pattern.push(b'\n');
+    pattern
+}
+
+fn size(file: File) -> usize {
+    match file {
+        File::Ctl => 0,
+        File::Status => status().len(),
+        File::Core => dump::written().map_or(0, |written| written.size),
+        File::Pattern => pattern().len(),
+    }
+}
+
+fn arm(kind: Kind, default: usize, kib: Option<&str>) -> Result<()> {
+    let size = match kib {
+        Some(kib) => kib.parse::<usize>().or(Err(Error::new(EINVAL)))?.checked_mul(1024).ok_or(Error::new(EINVAL))?,
+        None => default,
+    };
+    dump::arm(kind, size)
+}
+
+fn control(command: &str) -> Result<()> {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next(), words.next()) {
+        (Some("arm"), Some("mini"), kib, None) => arm(Kind::Mini, dump::MINI_DEFAULT, kib),
+        (Some("arm"), Some("full"), kib, None) => arm(Kind::Full, dump::FULL_DEFAULT, kib),
+        (Some("disarm"), None, None, None) => dump::disarm(),
+        (Some("capture"), None, None, None) => match dump::write(format_args!("captured through dump:ctl")) {
+            Some(_) => Ok(()),
+            None => Err(Error::new(ENOENT)),
+        },
+        _ => Err(Error::new(EINVAL)),
+    }
+}
+
+pub struct DumpScheme;
+
+impl Scheme for DumpScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let file = match path.trim_matches('/') {
+            "ctl" => File::Ctl,
+            "status" => File::Status,
+            "core" => File::Core,
+            "pattern" => File::Pattern,
+            _ => return Err(Error::new(ENOENT)),
+        };
+        let read_only = flags & O_ACCMODE == O_RDONLY;
+        if read_only && file == File::Pattern {
+            // Anyone may read the core pattern
+        } else if file != File::Status && uid != 0 {
+            // The core holds the memory of every process
+            return Err(Error::new(EACCES));
+        }
+        if !read_only && !matches!(file, File::Ctl | File::Pattern) {
+            return Err(Error::new(EROFS));
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { file, offset: 0 });
+        Ok(id)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<isize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let new_offset = calc_seek_offset_usize(handle.offset, pos, whence, size(handle.file))?;
+        handle.offset = new_offset as usize;
+        Ok(new_offset)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+impl KernelScheme for DumpScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let bytes_read = match handle.file {
+            File::Ctl => 0,
+            File::Status => buf.copy_common_bytes_from_slice(status().get(handle.offset..).unwrap_or(&[]))?,
+            File::Core => dump::read(handle.offset, buf)?,
+            File::Pattern => buf.copy_common_bytes_from_slice(pattern().get(handle.offset..).unwrap_or(&[]))?,
+        };
+        handle.offset += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        let file = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file;
+        let mut bytes = [0_u8; coredump::PATTERN_MAX + 1];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        let string = str::from_utf8(&bytes[..len]).or(Err(Error::new(EINVAL)))?;
+        match file {
+            File::Ctl => control(string.trim())?,
+            File::Pattern => coredump::set_pattern(string.trim_end_matches('\n'))?,
+            _ => return Err(Error::new(EBADF)),
+        }
+        Ok(len)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Ctl => "dump:ctl",
+            File::Status => "dump:status",
+            File::Core => "dump:core",
+            File::Pattern => "dump:pattern",
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let file = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file;
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FILE | match file {
+                File::Ctl => 0o200,
+                File::Pattern => 0o644,
+                File::Status | File::Core => 0o400,
+            },
+            st_size: size(file) as u64,
+            ..Default::default()
+        })?;
+        Ok(0)
+    }
+}
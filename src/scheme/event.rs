@@ -1,7 +1,7 @@
 use alloc::sync::Arc;
 use core::mem;
 
-use crate::event::{EventQueue, EventQueueId, next_queue_id, queues, queues_mut};
+use crate::event::{EventQueue, EventQueueId, next_queue_id, queues, queues_mut, unregister_queue};
 use crate::syscall::data::Event;
 use crate::syscall::error::*;
 use crate::syscall::scheme::Scheme;
@@ -34,7 +34,9 @@ impl Scheme for EventScheme {
 
     fn close(&self, id: usize) -> Result<usize> {
         let id = EventQueueId::from(id);
-        queues_mut().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+        queues_mut().remove(&id).ok_or(Error::new(EBADF))?;
+        unregister_queue(id);
+        Ok(0)
     }
 }
 impl crate::scheme::KernelScheme for EventScheme {
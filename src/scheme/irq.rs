@@ -3,21 +3,26 @@ use core::str::FromStr;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use alloc::string::String;
 
-use spin::{Mutex, RwLock};
-
 use crate::arch::interrupt::{available_irqs_iter, bsp_apic_id, is_reserved, set_reserved};
 
+use crate::context;
+use crate::context::wakeups::{Kind, Wakeups};
 use crate::event;
-use crate::interrupt::irq::acknowledge;
+use crate::interrupt::irq::{acknowledge, mask};
 use crate::scheme::{AtomicSchemeId, SchemeId};
+use crate::scheme::dir::{self, DirCursor};
+use crate::sync::{Mutex, RwLock};
+use crate::syscall::abi::{IRQ_ACK_AUTO, IRQ_ACK_MASK, IRQ_ACK_READ, IRQ_ACK_WRITE};
 use crate::syscall::data::Stat;
 use crate::syscall::error::*;
 use crate::syscall::flag::{EventFlags, EVENT_READ, O_DIRECTORY, O_CREAT, O_STAT, MODE_CHR, MODE_DIR};
-use crate::syscall::scheme::{calc_seek_offset_usize, Scheme};
+use crate::syscall::scheme::Scheme;
 use crate::syscall::usercopy::{UserSliceWo, UserSliceRo};
+use crate::time;
 
 pub static IRQ_SCHEME_ID: AtomicSchemeId = AtomicSchemeId::default();
 
@@ -27,7 +32,7 @@ static HANDLES: RwLock<Option<BTreeMap<usize, Handle>>> = RwLock::new(None);
 
 /// These are IRQs 0..=15 (corresponding to interrupt vectors 32..=47). They are opened without the
 /// O_CREAT flag.
-const BASE_IRQ_COUNT: u8 = 16;
+pub(super) const BASE_IRQ_COUNT: u8 = 16;
 
 /// These are the extended IRQs, 16..=223 (interrupt vectors 48..=255). Some of them are reserved
 /// for other devices, and some other interrupt vectors like 0x80 (software interrupts) and
@@ -35,40 +40,218 @@ const BASE_IRQ_COUNT: u8 = 16;
 ///
 /// Since these are non-sharable, they must be opened with O_CREAT, which then reserves them. They
 /// are only freed when the file descriptor is closed.
-const TOTAL_IRQ_COUNT: u8 = 224;
+pub(super) const TOTAL_IRQ_COUNT: u8 = 224;
 
 const INO_TOPLEVEL: u64 = 0x8002_0000_0000_0000;
 const INO_AVAIL: u64 = 0x8000_0000_0000_0000;
 const INO_BSP: u64 = 0x8001_0000_0000_0000;
+#[cfg(target_arch = "aarch64")]
+const INO_MSI: u64 = 0x8003_0000_0000_0000;
+
+/// An IRQ is considered to be storming if it fires more than `STORM_THRESHOLD` times without
+/// being acknowledged, within `STORM_WINDOW` nanoseconds.
+const STORM_THRESHOLD: usize = 10_000;
+const STORM_WINDOW: u128 = 100_000_000;
+/// How long a storming IRQ is kept masked, in nanoseconds
+const STORM_COOLDOWN: u128 = time::NANOS_PER_SEC;
+
+#[derive(Clone, Copy)]
+pub(super) struct Storm {
+    /// Start of the current window
+    window_start: u128,
+    /// Interrupts since the last acknowledgement, or since the start of the window
+    unacked: usize,
+    /// The time until which the IRQ is masked, if it is storming
+    pub masked_until: Option<u128>,
+    /// Number of storms detected
+    pub storms: usize,
+    /// Interrupts which arrived while masked, and were dropped
+    pub dropped: usize,
+}
+impl Storm {
+    const fn new() -> Self {
+        Self {
+            window_start: 0,
+            unacked: 0,
+            masked_until: None,
+            storms: 0,
+            dropped: 0,
+        }
+    }
+}
+
+pub(super) static STORMS: Mutex<[Storm; 224]> = Mutex::new([Storm::new(); 224]);
+/// Number of IRQs currently masked because of a storm, to avoid locking `STORMS` on every tick
+static STORMS_MASKED: AtomicUsize = AtomicUsize::new(0);
+
+/// Account for an interrupt, returning false if it should be dropped. If the IRQ starts storming,
+/// it is masked, but it is still delivered once more so that the driver is notified.
+fn storm_check(irq: u8) -> bool {
+    let now = time::monotonic();
+    let mut storms = STORMS.lock();
+    let storm = &mut storms[irq as usize];
+
+    if storm.masked_until.is_some() {
+        // Interrupts which `mask` cannot mask, such as MSIs on x86, which are not routed through
+        // the PIC or IOAPIC, keep arriving, so they are dropped instead.
+        storm.dropped += 1;
+        return false;
+    }
+
+    if now.saturating_sub(storm.window_start) >= STORM_WINDOW {
+        storm.window_start = now;
+        storm.unacked = 0;
+    }
+    storm.unacked += 1;
+
+    if storm.unacked > STORM_THRESHOLD {
+        storm.masked_until = Some(now + STORM_COOLDOWN);
+        storm.storms += 1;
+        STORMS_MASKED.fetch_add(1, Ordering::SeqCst);
+        unsafe { mask(irq as usize); }
+        log::warn!("IRQ {} fired {} times in {} ms without acknowledgement, masking it for {} ms", irq, storm.unacked, STORM_WINDOW / 1_000_000, STORM_COOLDOWN / 1_000_000);
+    }
+    true
+}
+
+/// Unmask IRQs whose storm cooldown has expired. Called from the timer interrupt.
+pub fn storm_tick() {
+    if STORMS_MASKED.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+
+    // This runs in the timer interrupt, so the locks may already be held on this CPU. If so, try
+    // again on the next tick.
+    let (counts, mut storms, guard) = match (COUNTS.try_lock(), STORMS.try_lock(), HANDLES.try_read()) {
+        (Some(counts), Some(storms), Some(guard)) => (counts, storms, guard),
+        _ => return,
+    };
+    let now = time::monotonic();
+    for (irq, storm) in storms.iter_mut().enumerate() {
+        if !storm.masked_until.map_or(false, |until| now >= until) {
+            continue;
+        }
+        storm.masked_until = None;
+        storm.window_start = now;
+        storm.unacked = 0;
+        STORMS_MASKED.fetch_sub(1, Ordering::SeqCst);
+
+        // Only unmask if the driver has caught up, or the IRQ is acknowledged automatically,
+        // otherwise the next acknowledgement unmasks it
+        let acked = guard.as_ref().map_or(false, |handles| handles.values()
+            .filter_map(Handle::as_irq_handle)
+            .any(|(ack, handle_irq, policy)| usize::from(handle_irq) == irq && (policy == AckPolicy::Auto || ack.load(Ordering::SeqCst) == counts[irq])));
+        if acked {
+            unsafe { acknowledge(irq); }
+        }
+        log::info!("IRQ {} unmasked after storm, {} interrupts dropped", irq, storm.dropped);
+    }
+}
 
 /// Add to the input queue
 #[no_mangle]
 pub extern fn irq_trigger(irq: u8) {
+    crate::journal::record(crate::journal::EventKind::Irq, irq.into(), 0);
+    crate::tracepoint!(Irq, irq, 0);
+    deliver(irq);
+    crate::tracepoint!(IrqExit, irq, 0);
+}
+
+/// Wake the handles of `irq`, unless it is storming
+fn deliver(irq: u8) {
+    if !storm_check(irq) {
+        return;
+    }
+
     COUNTS.lock()[irq as usize] += 1;
 
     let guard = HANDLES.read();
     if let Some(handles) = guard.as_ref() {
-        for (fd, _) in handles.iter().filter_map(|(fd, handle)| Some((fd, handle.as_irq_handle()?))).filter(|&(_, (_, handle_irq))| handle_irq == irq) {
-            event::trigger(IRQ_SCHEME_ID.load(Ordering::SeqCst), *fd, EVENT_READ);
+        let mut auto_ack = false;
+        for (fd, handle) in handles.iter() {
+            if let Handle::Irq { irq: handle_irq, policy, ref wakeups, .. } = *handle {
+                if handle_irq != irq {
+                    continue;
+                }
+                auto_ack |= policy == AckPolicy::Auto;
+                if let Some(wakeups) = wakeups {
+                    wakeups.record(Kind::Irq);
+                }
+                event::trigger(IRQ_SCHEME_ID.load(Ordering::SeqCst), *fd, EVENT_READ);
+            }
+        }
+        if auto_ack && STORMS.lock()[irq as usize].masked_until.is_none() {
+            unsafe { acknowledge(irq as usize); }
         }
     } else {
         println!("Calling IRQ without triggering");
     }
+    drop(guard);
+
+    super::uio::irq_trigger(irq);
+}
+
+/// Record that a handle has consumed the IRQ count `count`, and unmask the IRQ if requested
+pub(super) fn consume(irq: u8, handle_ack: &AtomicUsize, count: usize, unmask: bool) {
+    handle_ack.store(count, Ordering::SeqCst);
+
+    let mut storms = STORMS.lock();
+    let storm = &mut storms[irq as usize];
+    storm.unacked = 0;
+    // Storming IRQs stay masked until the cooldown expires
+    if unmask && storm.masked_until.is_none() {
+        unsafe { acknowledge(irq as usize); }
+    }
+}
+
+/// When the IRQ of a handle is acknowledged
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum AckPolicy {
+    /// When the count is written back
+    Write,
+    /// When a new count is read
+    Read,
+    /// Immediately, by the kernel
+    Auto,
+}
+impl AckPolicy {
+    fn from_flags(flags: usize) -> Result<Self> {
+        match flags & IRQ_ACK_MASK {
+            IRQ_ACK_WRITE => Ok(Self::Write),
+            IRQ_ACK_READ => Ok(Self::Read),
+            IRQ_ACK_AUTO => Ok(Self::Auto),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
 }
 
 enum Handle {
     Irq {
         ack: AtomicUsize,
         irq: u8,
+        policy: AckPolicy,
+        /// The wakeups of the context that opened the handle, which IRQs are attributed to
+        wakeups: Option<Arc<Wakeups>>,
     },
-    Avail(u8, Vec<u8>, AtomicUsize),    // CPU id, data, offset
-    TopLevel(Vec<u8>, AtomicUsize),     // data, offset
+    Avail(u8, Mutex<DirCursor>),    // CPU id, position
+    TopLevel(Mutex<DirCursor>),
     Bsp,
+    /// The addresses devices write MSIs to, on GICv3
+    #[cfg(target_arch = "aarch64")]
+    Msi,
 }
 impl Handle {
-    fn as_irq_handle<'a>(&'a self) -> Option<(&'a AtomicUsize, u8)> {
+    fn irq(irq: u8, policy: AckPolicy) -> Self {
+        Self::Irq {
+            ack: AtomicUsize::new(0),
+            irq,
+            policy,
+            wakeups: context::current().ok().map(|context| Arc::clone(&context.read().wakeups)),
+        }
+    }
+    fn as_irq_handle<'a>(&'a self) -> Option<(&'a AtomicUsize, u8, AckPolicy)> {
         match self {
-            &Self::Irq { ref ack, irq } => Some((ack, irq)),
+            &Self::Irq { ref ack, irq, policy, .. } => Some((ack, irq, policy)),
             _ => None,
         }
     }
@@ -110,18 +293,73 @@ impl IrqScheme {
             cpus,
         }
     }
+    /// Every logical CPU in the format of e.g. `cpu-1b`, and `bsp`
+    fn top_level_entries(&self) -> Vec<String> {
+        let mut entries: Vec<String> = self.cpus.iter().map(|cpu_id| format!("cpu-{:02x}", cpu_id)).collect();
+        if bsp_apic_id().is_some() {
+            entries.push("bsp".into());
+        }
+        #[cfg(target_arch = "aarch64")]
+        entries.push("msi".into());
+
+        // TODO: When signals are used for IRQs, there will probably also be a file
+        // `irq:signal` that maps IRQ numbers and their source APIC IDs to signal numbers.
+
+        entries
+    }
+    /// The IRQs of `cpu_id` that are not reserved yet
+    fn avail_entries(cpu_id: u8) -> Vec<String> {
+        available_irqs_iter(cpu_id.into())
+            .map(vector_to_irq)
+            .filter(|&irq| !(Some(u32::from(cpu_id)) == bsp_apic_id() && irq < BASE_IRQ_COUNT))
+            .map(|irq| format!("{}", irq))
+            .collect()
+    }
+    /// The doorbells of `irq:msi`, as `spi=<address>` for message based SPIs, and
+    /// `its=<address>` for LPIs through the ITS, if supported
+    #[cfg(target_arch = "aarch64")]
+    fn msi_contents() -> Vec<u8> {
+        let (spi, its) = crate::device::gicv3::msi_doorbells();
+        let mut string = String::new();
+        if let Some(address) = spi {
+            string.push_str(&format!("spi={:#x}\n", address));
+        }
+        if let Some(address) = its {
+            string.push_str(&format!("its={:#x}\n", address));
+        }
+        string.into_bytes()
+    }
+    #[cfg(target_arch = "aarch64")]
+    fn open_msi() -> Result<Handle> {
+        Ok(Handle::Msi)
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    fn open_msi() -> Result<Handle> {
+        Err(Error::new(ENOENT))
+    }
     fn open_ext_irq(flags: usize, cpu_id: u8, path_str: &str) -> Result<Handle> {
+        // `<irq>?its=<device id>` also maps the event `<irq>` of a PCIe device to an LPI
+        // delivered as `<irq>`, through the GICv3 ITS
+        #[cfg(target_arch = "aarch64")]
+        let (path_str, its_device) = match path_str.split_once("?its=") {
+            Some((irq, device_id)) => (irq, Some(u32::from_str(device_id).or(Err(Error::new(EINVAL)))?)),
+            None => (path_str, None),
+        };
+
         let irq_number = u8::from_str(path_str).or(Err(Error::new(ENOENT)))?;
+        let policy = AckPolicy::from_flags(flags)?;
+
+        #[cfg(target_arch = "aarch64")]
+        if its_device.is_some() && (irq_number < BASE_IRQ_COUNT || flags & O_CREAT == 0 || flags & O_STAT != 0) {
+            return Err(Error::new(EINVAL));
+        }
 
         Ok(if irq_number < BASE_IRQ_COUNT && Some(u32::from(cpu_id)) == bsp_apic_id() {
             // Give legacy IRQs only to `irq:{0..15}` and `irq:cpu-<BSP>/{0..15}` (same handles).
             //
             // The only CPUs don't have the legacy IRQs in their IDTs.
 
-            Handle::Irq {
-                ack: AtomicUsize::new(0),
-                irq: irq_number,
-            }
+            Handle::irq(irq_number, policy)
         } else if irq_number < TOTAL_IRQ_COUNT {
             if flags & O_CREAT == 0 && flags & O_STAT == 0 {
                 return Err(Error::new(EINVAL));
@@ -131,15 +369,23 @@ impl IrqScheme {
                     return Err(Error::new(EEXIST));
                 }
                 set_reserved(usize::from(cpu_id), irq_to_vector(irq_number), true);
+
+                #[cfg(target_arch = "aarch64")]
+                if let Some(device_id) = its_device {
+                    if let Err(err) = crate::device::gicv3::its_map(device_id, irq_number) {
+                        set_reserved(usize::from(cpu_id), irq_to_vector(irq_number), false);
+                        return Err(err);
+                    }
+                }
             }
-            Handle::Irq { ack: AtomicUsize::new(0), irq: irq_number }
+            Handle::irq(irq_number, policy)
         } else {
             return Err(Error::new(ENOENT));
         })
     }
 }
 
-const fn irq_to_vector(irq: u8) -> u8 {
+pub(super) const fn irq_to_vector(irq: u8) -> u8 {
     irq + 32
 }
 const fn vector_to_irq(vector: u8) -> u8 {
@@ -155,48 +401,22 @@ impl Scheme for IrqScheme {
         let handle: Handle = if path_str.is_empty() {
             if flags & O_DIRECTORY == 0 && flags & O_STAT == 0 { return Err(Error::new(EISDIR)) }
 
-            // list every logical CPU in the format of e.g. `cpu-1b`
-
-            let mut bytes = String::new();
-
-            use core::fmt::Write;
-
-            for cpu_id in &self.cpus {
-                writeln!(bytes, "cpu-{:02x}", cpu_id).unwrap();
-            }
-
-            if bsp_apic_id().is_some() {
-                writeln!(bytes, "bsp").unwrap();
-            }
-
-            // TODO: When signals are used for IRQs, there will probably also be a file
-            // `irq:signal` that maps IRQ numbers and their source APIC IDs to signal numbers.
-
-            Handle::TopLevel(bytes.into_bytes(), AtomicUsize::new(0))
+            Handle::TopLevel(Mutex::new(DirCursor::new()))
         } else {
             if path_str == "bsp" {
                 if bsp_apic_id().is_none() {
                     return Err(Error::new(ENOENT));
                 }
                 Handle::Bsp
+            } else if path_str == "msi" {
+                Self::open_msi()?
             } else if path_str.starts_with("cpu-") {
                 let path_str = &path_str[4..];
                 let cpu_id = u8::from_str_radix(&path_str[..2], 16).or(Err(Error::new(ENOENT)))?;
                 let path_str = path_str[2..].trim_end_matches('/');
 
                 if path_str.is_empty() {
-                    let mut data = String::new();
-                    use core::fmt::Write;
-
-                    for vector in available_irqs_iter(cpu_id.into()) {
-                        let irq = vector_to_irq(vector);
-                        if Some(u32::from(cpu_id)) == bsp_apic_id() && irq < BASE_IRQ_COUNT {
-                            continue;
-                        }
-                        writeln!(data, "{}", irq).unwrap();
-                    }
-
-                    Handle::Avail(cpu_id, data.into_bytes(), AtomicUsize::new(0))
+                    Handle::Avail(cpu_id, Mutex::new(DirCursor::new()))
                 } else if path_str.starts_with('/') {
                     let path_str = &path_str[1..];
                     Self::open_ext_irq(flags, cpu_id, path_str)?
@@ -205,7 +425,7 @@ impl Scheme for IrqScheme {
                 }
             } else if let Ok(plain_irq_number) = u8::from_str(path_str) {
                 if plain_irq_number < BASE_IRQ_COUNT {
-                    Handle::Irq { ack: AtomicUsize::new(0), irq: plain_irq_number }
+                    Handle::irq(plain_irq_number, AckPolicy::from_flags(flags)?)
                 } else {
                     return Err(Error::new(ENOENT));
                 }
@@ -223,12 +443,7 @@ impl Scheme for IrqScheme {
         let handle = handles_guard.as_ref().unwrap().get(&id).ok_or(Error::new(EBADF))?;
 
         match handle {
-            &Handle::Avail(_, ref buf, ref offset) | &Handle::TopLevel(ref buf, ref offset) => {
-                let cur_offset = offset.load(Ordering::SeqCst);
-                let new_offset = calc_seek_offset_usize(cur_offset, pos, whence, buf.len())?;
-                offset.store(new_offset as usize, Ordering::SeqCst);
-                Ok(new_offset)
-            }
+            &Handle::Avail(_, ref cursor) | &Handle::TopLevel(ref cursor) => cursor.lock().seek(pos, whence),
             _ => Err(Error::new(ESPIPE)),
         }
     }
@@ -265,13 +480,12 @@ impl crate::scheme::KernelScheme for IrqScheme {
         let handle = handles_guard.as_ref().unwrap().get(&file).ok_or(Error::new(EBADF))?;
 
         match handle {
-            &Handle::Irq { irq: handle_irq, ack: ref handle_ack } => if buffer.len() >= mem::size_of::<usize>() {
+            &Handle::Irq { irq: handle_irq, ack: ref handle_ack, .. } => if buffer.len() >= mem::size_of::<usize>() {
                 let ack = buffer.read_usize()?;
                 let current = COUNTS.lock()[handle_irq as usize];
 
                 if ack == current {
-                    handle_ack.store(ack, Ordering::SeqCst);
-                    unsafe { acknowledge(handle_irq as usize); }
+                    consume(handle_irq, handle_ack, ack, true);
                     Ok(mem::size_of::<usize>())
                 } else {
                     Ok(0)
@@ -306,16 +520,24 @@ impl crate::scheme::KernelScheme for IrqScheme {
                 st_nlink: 1,
                 ..Default::default()
             },
-            Handle::Avail(cpu_id, ref buf, _) => Stat {
+            #[cfg(target_arch = "aarch64")]
+            Handle::Msi => Stat {
+                st_mode: MODE_CHR | 0o400,
+                st_size: Self::msi_contents().len() as u64,
+                st_ino: INO_MSI,
+                st_nlink: 1,
+                ..Default::default()
+            },
+            Handle::Avail(cpu_id, _) => Stat {
                 st_mode: MODE_DIR | 0o700,
-                st_size: buf.len() as u64,
+                st_size: dir::listing_size(&Self::avail_entries(cpu_id)),
                 st_ino: INO_AVAIL | u64::from(cpu_id) << 32,
                 st_nlink: 2,
                 ..Default::default()
             },
-            Handle::TopLevel(ref buf, _) => Stat {
+            Handle::TopLevel(_) => Stat {
                 st_mode: MODE_DIR | 0o500,
-                st_size: buf.len() as u64,
+                st_size: dir::listing_size(&self.top_level_entries()),
                 st_ino: INO_TOPLEVEL,
                 st_nlink: 1,
                 ..Default::default()
@@ -330,8 +552,10 @@ impl crate::scheme::KernelScheme for IrqScheme {
         let scheme_path = match handle {
             Handle::Irq { irq, .. } => format!("irq:{}", irq),
             Handle::Bsp => format!("irq:bsp"),
-            Handle::Avail(cpu_id, _, _) => format!("irq:cpu-{:2x}", cpu_id),
-            Handle::TopLevel(_, _) => format!("irq:"),
+            #[cfg(target_arch = "aarch64")]
+            Handle::Msi => format!("irq:msi"),
+            Handle::Avail(cpu_id, _) => format!("irq:cpu-{:2x}", cpu_id),
+            Handle::TopLevel(_) => format!("irq:"),
         }.into_bytes();
 
         buf.copy_common_bytes_from_slice(&scheme_path)
@@ -342,10 +566,14 @@ impl crate::scheme::KernelScheme for IrqScheme {
 
         match *handle {
             // Ensures that the length of the buffer is larger than the size of a usize
-            Handle::Irq { irq: handle_irq, ack: ref handle_ack } => if buffer.len() >= mem::size_of::<usize>() {
+            Handle::Irq { irq: handle_irq, ack: ref handle_ack, policy } => if buffer.len() >= mem::size_of::<usize>() {
                 let current = COUNTS.lock()[handle_irq as usize];
                 if handle_ack.load(Ordering::SeqCst) != current {
                     buffer.write_usize(current)?;
+                    if policy != AckPolicy::Write {
+                        // Auto-acknowledged IRQs have already been unmasked
+                        consume(handle_irq, handle_ack, current, policy == AckPolicy::Read);
+                    }
                     Ok(mem::size_of::<usize>())
                 } else {
                     Ok(0)
@@ -364,13 +592,11 @@ impl crate::scheme::KernelScheme for IrqScheme {
                     Err(Error::new(EBADFD))
                 }
             }
-            Handle::Avail(_, ref buf, ref offset) | Handle::TopLevel(ref buf, ref offset) => {
-                let cur_offset = offset.load(Ordering::SeqCst);
-                let avail_buf = buf.get(cur_offset..).unwrap_or(&[]);
-                let bytes_read = buffer.copy_common_bytes_from_slice(avail_buf)?;
-                offset.fetch_add(bytes_read, Ordering::SeqCst);
-                Ok(bytes_read)
-            }
+            // The doorbells are read whole, with a single read
+            #[cfg(target_arch = "aarch64")]
+            Handle::Msi => buffer.copy_common_bytes_from_slice(&Self::msi_contents()),
+            Handle::Avail(cpu_id, ref cursor) => cursor.lock().read(Self::avail_entries(cpu_id), buffer),
+            Handle::TopLevel(ref cursor) => cursor.lock().read(self.top_level_entries(), buffer),
         }
     }
 
@@ -1,8 +1,8 @@
 use alloc::collections::BTreeMap;
 use core::{mem, str};
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::RwLock;
 
+use crate::sync::RwLock;
 use crate::syscall::data::ITimerSpec;
 use crate::syscall::error::*;
 use crate::syscall::flag::{CLOCK_REALTIME, CLOCK_MONOTONIC, EventFlags};
@@ -0,0 +1,176 @@
+//! # Kexec
+//! Root writes the kernel, the initfs and the environment to boot into to `kexec:kernel`,
+//! `kexec:initfs` and `kexec:env`, each write appending, and opening with `O_TRUNC` starting over.
+//! Writing to `kexec:ctl` then loads them (see `kexec`):
+//!
+//! - `load <entry>` loads them to boot into on demand, with `<entry>` the entry point of the
+//!   initfs, in hexadecimal
+//! - `crash <entry>` loads them to boot into when panicking, in the region reserved with
+//!   `CRASHKERNEL=<MiB>`
+//! - `unload` unloads them
+//! - `exec` boots into the kernel loaded on demand, like rebooting
+//!
+//! `kexec:status` lists the reserved region, the loaded kernel and the staged files.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::kexec::{self, Blob};
+use crate::sync::RwLock;
+use crate::syscall::error::*;
+use crate::syscall::flag::{O_ACCMODE, O_RDONLY, O_TRUNC};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::KernelScheme;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum File {
+    Blob(Blob),
+    Ctl,
+    Status,
+}
+
+struct Handle {
+    file: File,
+    offset: usize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn contents(file: File) -> Result<Vec<u8>> {
+    let mut string = String::new();
+    match file {
+        File::Blob(_) | File::Ctl => (),
+        File::Status => {
+            match kexec::reserved() {
+                Some((base, size)) => {
+                    let _ = writeln!(string, "reserved: {:X}:{:X}", base, base + size);
+                }
+                None => string.push_str("reserved: none\n"),
+            }
+            match kexec::loaded() {
+                Some(((base, size), crash)) => {
+                    let kind = if crash { "crash" } else { "on demand" };
+                    let _ = writeln!(string, "loaded: {} at {:X}:{:X}", kind, base, base + size);
+                }
+                None => string.push_str("loaded: none\n"),
+            }
+            for (name, blob) in [("kernel", Blob::Kernel), ("initfs", Blob::Initfs), ("env", Blob::Env)] {
+                let _ = writeln!(string, "staged {}: {} bytes", name, kexec::staged(blob)?);
+            }
+        }
+    }
+    Ok(string.into_bytes())
+}
+
+fn parse_entry(entry: &str) -> Result<usize> {
+    usize::from_str_radix(entry.trim_start_matches("0x"), 16).or(Err(Error::new(EINVAL)))
+}
+
+fn control(command: &str) -> Result<()> {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("load"), Some(entry), None) => kexec::load(parse_entry(entry)?, false),
+        (Some("crash"), Some(entry), None) => kexec::load(parse_entry(entry)?, true),
+        (Some("unload"), None, None) => kexec::unload(),
+        #[cfg(feature = "acpi")]
+        (Some("exec"), None, None) => crate::power::kexec(),
+        // Without ACPI, there is a single CPU to stop
+        #[cfg(not(feature = "acpi"))]
+        (Some("exec"), None, None) => Err(unsafe { kexec::exec() }),
+        _ => Err(Error::new(EINVAL)),
+    }
+}
+
+pub struct KexecScheme;
+
+impl Scheme for KexecScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let file = match path.trim_matches('/') {
+            "kernel" => File::Blob(Blob::Kernel),
+            "initfs" => File::Blob(Blob::Initfs),
+            "env" => File::Blob(Blob::Env),
+            "ctl" => File::Ctl,
+            "status" => File::Status,
+            _ => return Err(Error::new(ENOENT)),
+        };
+        if file != File::Status && uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        if flags & O_ACCMODE != O_RDONLY && file == File::Status {
+            return Err(Error::new(EROFS));
+        }
+        if let File::Blob(blob) = file {
+            if flags & O_TRUNC == O_TRUNC {
+                kexec::clear(blob)?;
+            }
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { file, offset: 0 });
+        Ok(id)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+impl KernelScheme for KexecScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let file = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file;
+        // Waits for the staged files, which must not be done holding the handles
+        let data = contents(file)?;
+
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let bytes_read = buf.copy_common_bytes_from_slice(data.get(handle.offset..).unwrap_or(&[]))?;
+        handle.offset += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        let file = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file;
+
+        match file {
+            File::Blob(blob) => {
+                let mut data = Vec::new();
+                data.try_reserve_exact(buf.len()).map_err(|_| Error::new(ENOMEM))?;
+                data.resize(buf.len(), 0);
+                buf.copy_to_slice(&mut data)?;
+                kexec::append(blob, &data)?;
+                Ok(data.len())
+            }
+            File::Ctl => {
+                let mut bytes = [0_u8; 64];
+                let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+                let command = str::from_utf8(&bytes[..len]).or(Err(Error::new(EINVAL)))?;
+                control(command.trim())?;
+                Ok(len)
+            }
+            File::Status => Err(Error::new(EBADF)),
+        }
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Blob(Blob::Kernel) => "kexec:kernel",
+            File::Blob(Blob::Initfs) => "kexec:initfs",
+            File::Blob(Blob::Env) => "kexec:env",
+            File::Ctl => "kexec:ctl",
+            File::Status => "kexec:status",
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
@@ -0,0 +1,191 @@
+//! # Kernel log
+//! Each read of `kmsg:` returns one record of the kernel log (see `log`), as
+//! `<syslog priority>,<sequence number>,<microseconds since boot>,-;<text>`, and fails with
+//! `EPIPE` once if the records it was at were overwritten, continuing from the oldest one. It
+//! returns 0 once all records were read. Seeking moves to a sequence number: from the start it
+//! is absolute, and from the end it is relative to the next record, so that seeking to 0 from the
+//! end skips the existing ones.
+//!
+//! Root can write lines to the log, each prefixed with `<level>` or logged as notices.
+//!
+//! `kmsg:filter` reads as the level filters of the log (see `log`), which root can replace by
+//! writing new ones.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::debug::Writer;
+use crate::log::{self, Cursor, Filters, FACILITY_USER, KMSG, LEVEL_NOTICE, LINE_MAX};
+use crate::sync::RwLock;
+use crate::syscall::error::*;
+use crate::syscall::flag::{O_ACCMODE, O_RDONLY, SEEK_CUR, SEEK_END, SEEK_SET};
+use crate::syscall::scheme::{calc_seek_offset_usize, Scheme};
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::KernelScheme;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum File {
+    Log,
+    Filter,
+}
+
+struct Handle {
+    file: File,
+    /// Next record to read, for `Log`
+    cursor: Cursor,
+    /// Offset read, for `Filter`
+    offset: usize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+/// Split the `<level>` prefix from a line written
+fn parse_level(line: &[u8]) -> (u8, &[u8]) {
+    if let [b'<', level @ b'0'..=b'7', b'>', rest @ ..] = line {
+        return (level - b'0', rest);
+    }
+    (LEVEL_NOTICE, line)
+}
+
+fn filter_contents() -> Vec<u8> {
+    let mut string = log::filters().to_string();
+    string.push('\n');
+    string.into_bytes()
+}
+
+pub struct KmsgScheme;
+
+impl Scheme for KmsgScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let file = match path.trim_matches('/') {
+            "" => File::Log,
+            "filter" => File::Filter,
+            _ => return Err(Error::new(ENOENT)),
+        };
+        if flags & O_ACCMODE != O_RDONLY && uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let cursor = {
+            let kmsg = KMSG.lock();
+            kmsg.cursor(kmsg.first_seq())
+        };
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { file, cursor, offset: 0 });
+        Ok(id)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<isize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        if handle.file == File::Filter {
+            let new_offset = calc_seek_offset_usize(handle.offset, pos, whence, filter_contents().len())?;
+            handle.offset = new_offset as usize;
+            return Ok(new_offset);
+        }
+
+        let kmsg = KMSG.lock();
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => handle.cursor.seq,
+            SEEK_END => kmsg.next_seq(),
+            _ => return Err(Error::new(EINVAL)),
+        };
+        let seq = base.checked_add_signed(pos as i64).ok_or(Error::new(EINVAL))?;
+        handle.cursor = kmsg.cursor(seq);
+
+        isize::try_from(handle.cursor.seq).or(Err(Error::new(EOVERFLOW)))
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+impl KernelScheme for KmsgScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let mut data = Vec::new();
+        {
+            let mut handles = HANDLES.write();
+            let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+            if handle.file == File::Filter {
+                let contents = filter_contents();
+                let bytes_read = buf.copy_common_bytes_from_slice(contents.get(handle.offset..).unwrap_or(&[]))?;
+                handle.offset += bytes_read;
+                return Ok(bytes_read);
+            }
+
+            let mut text = [0; LINE_MAX];
+            let kmsg = KMSG.lock();
+            let mut cursor = handle.cursor;
+            let header = match kmsg.read(&mut cursor, &mut text) {
+                Ok(Some(header)) => header,
+                Ok(None) => return Ok(0),
+                Err(_) => {
+                    handle.cursor = cursor;
+                    return Err(Error::new(EPIPE));
+                }
+            };
+
+            let mut prefix = String::new();
+            let _ = write!(
+                prefix,
+                "{},{},{},-;",
+                u32::from(header.facility) << 3 | u32::from(header.level),
+                header.seq,
+                header.time / 1000
+            );
+            data.extend_from_slice(prefix.as_bytes());
+            data.extend_from_slice(&text[..header.len]);
+            data.push(b'\n');
+
+            // The record is returned whole, or not at all
+            if data.len() > buf.len() {
+                return Err(Error::new(EINVAL));
+            }
+            handle.cursor = cursor;
+        }
+        buf.copy_common_bytes_from_slice(&data)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        let file = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file;
+
+        let mut bytes = [0_u8; LINE_MAX];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        if file == File::Filter {
+            let filters = str::from_utf8(&bytes[..len])
+                .ok()
+                .and_then(Filters::parse)
+                .ok_or(Error::new(EINVAL))?;
+            log::set_filters(filters);
+            return Ok(len);
+        }
+        for line in bytes[..len].split(|&byte| byte == b'\n').filter(|line| !line.is_empty()) {
+            let (level, text) = parse_level(line);
+            let mut writer = Writer::with(level, FACILITY_USER);
+            writer.write(text);
+            writer.write(b"\n");
+        }
+        Ok(len)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Log => "kmsg:",
+            File::Filter => "kmsg:filter",
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
@@ -0,0 +1,123 @@
+//! # Kprobes
+//! Reading `kprobe:` lists the probes planted on kernel code (see `debugger::kprobe`) as of when
+//! it was opened, one per line as `<address> <hits> <function>+<offset>`, followed by `disarmed`
+//! for those hit where they could not be stepped over. Root manages probes by writing to it:
+//!
+//! - `add <function>[+<offset>]` or `add 0x<address>` plants a probe
+//! - `remove <function>[+<offset>]` or `remove 0x<address>` removes it
+//! - `clear` removes every probe
+//!
+//! Functions are named as in the kernel symbol table, mangled or demangled without their hash.
+//! Hits are recorded in the trace as the `kprobe` and `kprobe_args` events, once enabled through
+//! `trace:ctl`.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::debugger::kprobe;
+use crate::sync::RwLock;
+use crate::syscall::data::Stat;
+use crate::syscall::error::*;
+use crate::syscall::flag::MODE_FILE;
+use crate::syscall::scheme::{calc_seek_offset_usize, Scheme};
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::KernelScheme;
+
+struct Handle {
+    /// The probes, listed when opened
+    data: Vec<u8>,
+    offset: usize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn control(command: &str) -> Result<()> {
+    let mut words = command.split_whitespace();
+    let result = match (words.next(), words.next()) {
+        (Some("add"), Some(spec)) => kprobe::add(spec),
+        (Some("remove"), Some(spec)) => kprobe::remove(spec),
+        (Some("clear"), None) => {
+            kprobe::clear();
+            Ok(())
+        }
+        _ => Err(Error::new(EINVAL)),
+    };
+    if words.next().is_some() {
+        return Err(Error::new(EINVAL));
+    }
+    result
+}
+
+pub struct KprobeScheme;
+
+impl Scheme for KprobeScheme {
+    fn open(&self, path: &str, _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if !path.trim_matches('/').is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+        // Probes patch kernel code, and are listed with kernel addresses
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { data: kprobe::list(), offset: 0 });
+        Ok(id)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<isize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let new_offset = calc_seek_offset_usize(handle.offset, pos, whence, handle.data.len())?;
+        handle.offset = new_offset as usize;
+        Ok(new_offset)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+impl KernelScheme for KprobeScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let bytes_read = buf.copy_common_bytes_from_slice(handle.data.get(handle.offset..).unwrap_or(&[]))?;
+        handle.offset += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        let mut bytes = [0_u8; 256];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        control(str::from_utf8(&bytes[..len]).or(Err(Error::new(EINVAL)))?)?;
+        Ok(len)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        buf.copy_common_bytes_from_slice(b"kprobe:")
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let handles = HANDLES.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FILE | 0o600,
+            st_size: handle.data.len() as u64,
+            ..Default::default()
+        })?;
+        Ok(0)
+    }
+}
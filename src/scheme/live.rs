@@ -4,7 +4,6 @@ use alloc::sync::Arc;
 use alloc::collections::BTreeMap;
 use core::{slice, str};
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::RwLock;
 use rmm::Flusher;
 
 use syscall::data::Stat;
@@ -13,8 +12,9 @@ use syscall::flag::{MODE_DIR, MODE_FILE};
 use syscall::scheme::{calc_seek_offset_usize, Scheme};
 
 use crate::memory::Frame;
-use crate::paging::{KernelMapper, Page, PageFlags, PhysicalAddress, VirtualAddress};
+use crate::paging::{huge, KernelMapper, Page, PageFlags, PhysicalAddress, VirtualAddress};
 use crate::paging::mapper::PageFlushAll;
+use crate::sync::RwLock;
 use crate::syscall::usercopy::{UserSliceWo, UserSliceRo};
 
 use super::OpenResult;
@@ -64,7 +64,8 @@ impl DiskScheme {
                 let start_page = Page::containing_address(VirtualAddress::new(virt));
                 let end_page = Page::containing_address(VirtualAddress::new(virt + size - 1));
                 for page in Page::range_inclusive(start_page, end_page) {
-                    if mapper.translate(page.start_address()).is_none() {
+                    // The physical memory map may use huge pages
+                    if huge::translate(&*mapper, page.start_address()).is_none() {
                         let frame = Frame::containing_address(PhysicalAddress::new(page.start_address().data() - crate::PHYS_OFFSET));
                         let flags = PageFlags::new().write(true);
                         let result = mapper.get_mut().expect("expected KernelMapper not to be in use while initializing live scheme").map_phys(page.start_address(), frame.start_address(), flags).expect("failed to map live page");
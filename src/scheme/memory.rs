@@ -1,12 +1,15 @@
 use alloc::sync::Arc;
 use rmm::PhysicalAddress;
-use spin::RwLock;
 use syscall::MapFlags;
 
 use crate::context::memory::{AddrSpace, Grant};
 use crate::memory::{free_frames, used_frames, PAGE_SIZE, Frame};
 
 use crate::paging::entry::EntryFlags;
+use crate::paging::huge::{self, HUGE_PAGE_SIZE};
+use crate::paging::{Page, PageFlags, RmmA};
+use crate::sync::RwLock;
+use crate::syscall::abi::{MAP_GROWSDOWN, MAP_HUGETLB};
 use crate::syscall::data::{Map, StatVfs};
 use crate::syscall::error::*;
 use crate::syscall::scheme::Scheme;
@@ -33,6 +36,25 @@ pub enum MemoryType {
     WriteCombining,
 }
 
+impl MemoryType {
+    /// Set the caching attributes of `page_flags` for this memory type
+    pub fn page_flags(self, page_flags: PageFlags<RmmA>) -> PageFlags<RmmA> {
+        match self {
+            // Default
+            MemoryType::Writeback => page_flags,
+
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] // TODO: AARCH64
+            MemoryType::WriteCombining => page_flags.custom_flag(EntryFlags::HUGE_PAGE.bits(), true),
+
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] // TODO: AARCH64
+            MemoryType::Uncacheable => page_flags.custom_flag(EntryFlags::NO_CACHE.bits(), true),
+
+            #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+            _ => page_flags,
+        }
+    }
+}
+
 impl Handle {
     fn from_raw(raw: usize) -> Option<Self> {
         Some(match raw {
@@ -55,10 +77,33 @@ impl MemoryScheme {
     pub fn fmap_anonymous(addr_space: &Arc<RwLock<AddrSpace>>, map: &Map) -> Result<usize> {
         let (requested_page, page_count) = crate::syscall::usercopy::validate_region(map.address, map.size)?;
 
+        if map.flags.bits() & MAP_HUGETLB == MAP_HUGETLB {
+            return Self::fmap_anonymous_huge(addr_space, map, requested_page, page_count);
+        }
+
         let page = addr_space
             .write()
-            .mmap((map.address != 0).then_some(requested_page), page_count, map.flags, |page, flags, mapper, flusher| {
-                Ok(Grant::zeroed(page, page_count, flags, mapper, flusher)?)
+            .mmap((map.address != 0).then_some(requested_page), page_count, map.flags, |page, flags, _mapper, _flusher| {
+                let mut grant = Grant::zeroed_lazy(page, page_count, flags);
+                grant.grows_down = map.flags.bits() & MAP_GROWSDOWN == MAP_GROWSDOWN;
+                Ok(grant)
+            })?;
+
+        crate::tracepoint!(Mmap, page.start_address().data(), map.size);
+        Ok(page.start_address().data())
+    }
+    fn fmap_anonymous_huge(addr_space: &Arc<RwLock<AddrSpace>>, map: &Map, requested_page: Page, page_count: usize) -> Result<usize> {
+        if !huge::SUPPORTED {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        if map.address % HUGE_PAGE_SIZE != 0 || map.size % HUGE_PAGE_SIZE != 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let page = addr_space
+            .write()
+            .mmap_aligned((map.address != 0).then_some(requested_page), page_count, HUGE_PAGE_SIZE, map.flags, |page, flags, mapper, flusher| {
+                Ok(Grant::zeroed_huge(page, page_count, flags, mapper, flusher)?)
             })?;
 
         Ok(page.start_address().data())
@@ -78,26 +123,12 @@ impl MemoryScheme {
         }
         let page_count = size.div_ceil(PAGE_SIZE);
 
-        AddrSpace::current()?.write().mmap(None, page_count, flags, |dst_page, mut page_flags, dst_mapper, dst_flusher| {
-            match memory_type {
-                // Default
-                MemoryType::Writeback => (),
-
-                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] // TODO: AARCH64
-                MemoryType::WriteCombining => page_flags = page_flags.custom_flag(EntryFlags::HUGE_PAGE.bits(), true),
-
-                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] // TODO: AARCH64
-                MemoryType::Uncacheable => page_flags = page_flags.custom_flag(EntryFlags::NO_CACHE.bits(), true),
-
-                #[cfg(target_arch = "aarch64")]
-                _ => (),
-            }
-
+        AddrSpace::current()?.write().mmap(None, page_count, flags, |dst_page, page_flags, dst_mapper, dst_flusher| {
             Grant::physmap(
                 Frame::containing_address(PhysicalAddress::new(physical_address)),
                 dst_page,
                 page_count,
-                page_flags,
+                memory_type.page_flags(page_flags),
                 dst_mapper,
                 dst_flusher,
             )
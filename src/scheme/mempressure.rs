@@ -0,0 +1,152 @@
+//! # Memory pressure
+//! Reading `mempressure:` returns a `MemoryPressure`, with the free and available pages and the
+//! current pressure level. The level changes when free memory crosses the low or critical
+//! threshold, which triggers `EVENT_READ` on every handle, so that caches can be trimmed before the
+//! OOM killer has to run. Root can set the thresholds by writing them as two `usize`s (low, then
+//! critical), in pages, where zero selects the default.
+
+use alloc::collections::BTreeMap;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::context::oom::KERNEL_RESERVE;
+use crate::event;
+use crate::memory::{free_frames, used_frames};
+use crate::sync::RwLock;
+use crate::syscall::abi::{MemoryPressure, MEMORY_PRESSURE_CRITICAL, MEMORY_PRESSURE_LOW, MEMORY_PRESSURE_NONE};
+use crate::syscall::error::*;
+use crate::syscall::flag::{EventFlags, EVENT_READ};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::{AtomicSchemeId, KernelScheme, SchemeId};
+
+static SCHEME_ID: AtomicSchemeId = AtomicSchemeId::default();
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The user ID of each handle
+static HANDLES: RwLock<BTreeMap<usize, u32>> = RwLock::new(BTreeMap::new());
+
+/// Thresholds in pages, or zero for the defaults
+static LOW: AtomicUsize = AtomicUsize::new(0);
+static CRITICAL: AtomicUsize = AtomicUsize::new(0);
+
+/// The level when it was last checked
+static LEVEL: AtomicUsize = AtomicUsize::new(MEMORY_PRESSURE_NONE);
+
+/// The low and critical thresholds, by default a tenth of memory, and twice the kernel reserve
+fn thresholds(total: usize) -> (usize, usize) {
+    let low = match LOW.load(Ordering::Relaxed) {
+        0 => total / 10,
+        low => low,
+    };
+    let critical = match CRITICAL.load(Ordering::Relaxed) {
+        0 => KERNEL_RESERVE * 2,
+        critical => critical,
+    };
+    (low, critical)
+}
+
+fn current() -> MemoryPressure {
+    let free = free_frames();
+    let total = free + used_frames();
+    let (low, critical) = thresholds(total);
+
+    let level = if free < critical {
+        MEMORY_PRESSURE_CRITICAL
+    } else if free < low {
+        MEMORY_PRESSURE_LOW
+    } else {
+        MEMORY_PRESSURE_NONE
+    };
+
+    MemoryPressure {
+        level,
+        free,
+        available: free.saturating_sub(KERNEL_RESERVE),
+        total,
+        low,
+        critical,
+    }
+}
+
+/// Notify readers if the pressure level has changed. Called after memory has been allocated or
+/// freed in bulk, without any locks held.
+pub fn update() {
+    let level = current().level;
+    if LEVEL.swap(level, Ordering::Relaxed) == level {
+        return;
+    }
+    let scheme_id = SCHEME_ID.load(Ordering::SeqCst);
+    for &id in HANDLES.read().keys() {
+        event::trigger(scheme_id, id, EVENT_READ);
+    }
+}
+
+pub struct MemPressureScheme;
+
+impl MemPressureScheme {
+    pub fn new(scheme_id: SchemeId) -> Self {
+        SCHEME_ID.store(scheme_id, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Scheme for MemPressureScheme {
+    fn open(&self, path: &str, _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if !path.trim_matches('/').is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, uid);
+        Ok(id)
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(EventFlags::empty())
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+}
+impl KernelScheme for MemPressureScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+
+        buf.copy_exactly(&current())?;
+        Ok(mem::size_of::<MemoryPressure>())
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        if *HANDLES.read().get(&id).ok_or(Error::new(EBADF))? != 0 {
+            return Err(Error::new(EPERM));
+        }
+
+        let (low_buf, critical_buf) = buf.split_at(mem::size_of::<usize>()).ok_or(Error::new(EINVAL))?;
+        let low = low_buf.read_usize()?;
+        let critical = critical_buf.read_usize()?;
+        if low != 0 && critical > low {
+            return Err(Error::new(EINVAL));
+        }
+        LOW.store(low, Ordering::Relaxed);
+        CRITICAL.store(critical, Ordering::Relaxed);
+
+        update();
+        Ok(2 * mem::size_of::<usize>())
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        buf.copy_common_bytes_from_slice(b"mempressure:")
+    }
+}
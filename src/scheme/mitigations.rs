@@ -0,0 +1,128 @@
+//! # Mitigations
+//! `mitigations:status` lists the status of each speculative execution mitigation on each CPU.
+//! `mitigations:<name>` reads as `on` or `off`, depending on whether the mitigation is enabled,
+//! and root can write either to switch those that can be switched at runtime (see
+//! `arch::mitigations`). Which are enabled at boot is chosen with `MITIGATIONS` in the environment.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::mitigations::{self, Mitigation};
+use crate::sync::RwLock;
+use crate::syscall::error::*;
+use crate::syscall::flag::{O_ACCMODE, O_RDONLY};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::KernelScheme;
+
+#[derive(Clone, Copy)]
+enum File {
+    Status,
+    Mitigation(Mitigation),
+}
+
+struct Handle {
+    file: File,
+    offset: usize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn contents(file: File) -> Vec<u8> {
+    let mut string = String::new();
+    match file {
+        File::Status => {
+            for cpu_id in mitigations::cpus() {
+                let _ = write!(string, "cpu{}:", cpu_id);
+                for mitigation in Mitigation::ALL {
+                    if let Some(status) = mitigations::status(cpu_id, mitigation) {
+                        let _ = write!(string, " {}={}", mitigation.name(), status.name());
+                    }
+                }
+                string.push('\n');
+            }
+        }
+        File::Mitigation(mitigation) => {
+            string.push_str(if mitigations::enabled(mitigation) { "on\n" } else { "off\n" });
+        }
+    }
+    string.into_bytes()
+}
+
+pub struct MitigationsScheme;
+
+impl Scheme for MitigationsScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let file = match path.trim_matches('/') {
+            "status" => File::Status,
+            name => File::Mitigation(Mitigation::from_name(name).ok_or(Error::new(ENOENT))?),
+        };
+        if flags & O_ACCMODE != O_RDONLY {
+            if uid != 0 {
+                return Err(Error::new(EACCES));
+            }
+            if !matches!(file, File::Mitigation(mitigation) if mitigation.toggleable()) {
+                return Err(Error::new(EROFS));
+            }
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { file, offset: 0 });
+        Ok(id)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+}
+impl KernelScheme for MitigationsScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let data = contents(handle.file);
+        let bytes_read = buf.copy_common_bytes_from_slice(data.get(handle.offset..).unwrap_or(&[]))?;
+        handle.offset += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        let mitigation = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Mitigation(mitigation) => mitigation,
+            File::Status => return Err(Error::new(EBADF)),
+        };
+
+        let mut bytes = [0_u8; 8];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        let enabled = match str::from_utf8(&bytes[..len]).map(str::trim) {
+            Ok("on" | "1") => true,
+            Ok("off" | "0") => false,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        if !mitigations::set_enabled(mitigation, enabled) {
+            return Err(Error::new(EROFS));
+        }
+        Ok(len)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Status => String::from("mitigations:status"),
+            File::Mitigation(mitigation) => format!("mitigations:{}", mitigation.name()),
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
@@ -15,10 +15,11 @@ use alloc::{
 };
 use syscall::CallerCtx;
 use core::sync::atomic::AtomicUsize;
-use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use spin::Once;
 
 use crate::context::file::FileDescription;
 use crate::context::{memory::AddrSpace, file::FileDescriptor};
+use crate::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use crate::syscall::error::*;
 use crate::syscall::scheme::Scheme;
 use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
@@ -26,25 +27,72 @@ use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
 #[cfg(all(feature = "acpi", any(target_arch = "x86", target_arch = "x86_64")))]
 use self::acpi::AcpiScheme;
 
+use self::audit::AuditScheme;
+use self::cpu::CpuScheme;
+#[cfg(target_arch = "x86_64")]
+use self::cpufreq::CpuFreqScheme;
 use self::debug::DebugScheme;
+use self::dump::DumpScheme;
 use self::event::EventScheme;
 use self::irq::IrqScheme;
 use self::itimer::ITimerScheme;
+#[cfg(target_arch = "x86_64")]
+use self::kexec::KexecScheme;
+use self::kmsg::KmsgScheme;
+#[cfg(target_arch = "x86_64")]
+use self::kprobe::KprobeScheme;
 use self::memory::MemoryScheme;
+use self::mempressure::MemPressureScheme;
+#[cfg(target_arch = "x86_64")]
+use self::mitigations::MitigationsScheme;
+use self::perf::PerfScheme;
+use self::physmem::PhysmemScheme;
 use self::pipe::PipeScheme;
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+use self::power::PowerScheme;
 use self::proc::ProcScheme;
+#[cfg(target_arch = "x86_64")]
+use self::profile::ProfileScheme;
 use self::root::RootScheme;
+use self::sched::SchedScheme;
 use self::serio::SerioScheme;
+use self::shm::ShmScheme;
+use self::swap::SwapScheme;
+use self::symbols::SymbolsScheme;
 use self::sys::SysScheme;
+use self::syscalls::SyscallsScheme;
+#[cfg(target_arch = "x86_64")]
+use self::thermal::ThermalScheme;
+#[cfg(feature = "test_scheme")]
+use self::test::TestScheme;
 use self::time::TimeScheme;
+use self::trace::TraceScheme;
+use self::uio::UioScheme;
+use self::watchdog::WatchdogScheme;
 
 /// When compiled with the "acpi" feature - `acpi:` - allows drivers to read a limited set of ACPI tables.
 #[cfg(all(feature = "acpi", any(target_arch = "x86", target_arch = "x86_64")))]
 pub mod acpi;
 
+/// `audit:` - records of security-relevant events, and the rules choosing them
+pub mod audit;
+
+/// `cpu:` - taking secondary CPUs offline and back online
+pub mod cpu;
+
+/// `cpufreq:` - CPU frequency scaling governors
+#[cfg(target_arch = "x86_64")]
+pub mod cpufreq;
+
 /// `debug:` - provides access to serial console
 pub mod debug;
 
+/// `dump:` - arming kernel dumps written when panicking, and reading them back
+pub mod dump;
+
+/// Directory listings with positions that survive entries being added or removed
+pub mod dir;
+
 /// `event:` - allows reading of `Event`s which are registered using `fevent`
 pub mod event;
 
@@ -54,33 +102,97 @@ pub mod irq;
 /// `itimer:` - support for getitimer and setitimer
 pub mod itimer;
 
+/// `kexec:` - loading a kernel to boot into, on demand or when panicking
+#[cfg(target_arch = "x86_64")]
+pub mod kexec;
+
 /// When `disk/live:` - embedded filesystem for live disk
 pub mod live;
 
+/// `kmsg:` - records of the kernel log, with their sequence numbers, times and levels, and its filters
+pub mod kmsg;
+
+/// `kprobe:` - probes planted on kernel functions, logging their arguments to the trace
+#[cfg(target_arch = "x86_64")]
+pub mod kprobe;
+
 /// `memory:` - a scheme for accessing physical memory
 pub mod memory;
 
+/// `mempressure:` - free memory, and events when it runs low
+pub mod mempressure;
+
+/// `mitigations:` - status and switches of speculative execution mitigations
+#[cfg(target_arch = "x86_64")]
+pub mod mitigations;
+
+/// `perf:` - hardware performance counters of contexts and CPUs, and their samples
+pub mod perf;
+
+/// `physmem:` - read-only access to firmware-reserved physical memory, for diagnostics
+pub mod physmem;
+
 /// `pipe:` - used internally by the kernel to implement `pipe`
 pub mod pipe;
 
+/// `power:` - suspend to RAM, and notifications of it for drivers
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+pub mod power;
+
 /// `proc:` - allows tracing processes and reading/writing their memory
 pub mod proc;
 
+/// `profile:` - samples of the call stacks running on each CPU, taken on timer ticks
+#[cfg(target_arch = "x86_64")]
+pub mod profile;
+
 /// `:` - allows the creation of userspace schemes, tightly dependent on `user`
 pub mod root;
 
+/// `sched:` - scheduler tunables
+pub mod sched;
+
 /// `serio:` - provides access to ps/2 devices
 pub mod serio;
 
+/// `shm:` - shared memory objects, which can be mapped by multiple processes
+pub mod shm;
+
+/// `swap:` - backing store for swapped out memory, provided by a userspace daemon
+pub mod swap;
+
+/// `symbols:` - functions of the kernel containing addresses
+pub mod symbols;
+
 /// `sys:` - system information, such as the context list and scheme list
 pub mod sys;
 
+/// `syscalls:` - counts and latencies of the syscalls made by each context
+pub mod syscalls;
+
+/// `test:` - loopback files with forced errors, delays and partial transfers, for testing callers
+#[cfg(feature = "test_scheme")]
+pub mod test;
+
+/// `thermal:` - temperatures and trip points of thermal zones
+#[cfg(target_arch = "x86_64")]
+pub mod thermal;
+
 /// `time:` - allows reading time, setting timeouts and getting events when they are met
 pub mod time;
 
+/// `trace:` - tracepoint and function tracing events, and enabling them
+pub mod trace;
+
+/// `uio:` - MMIO registers and an IRQ of a device, bundled for a userspace driver
+pub mod uio;
+
 /// A wrapper around userspace schemes, tightly dependent on `root`
 pub mod user;
 
+/// `watchdog:` - a watchdog that userspace must ping, or the system is reset
+pub mod watchdog;
+
 /// Limit on number of schemes
 pub const SCHEME_MAX_SCHEMES: usize = 65_536;
 
@@ -150,8 +262,12 @@ impl SchemeList {
         self.insert(ns, "event", |_| Arc::new(EventScheme)).unwrap();
         self.insert(ns, "itimer", |_| Arc::new(ITimerScheme::new())).unwrap();
         self.insert(ns, "memory", |_| Arc::new(MemoryScheme::new())).unwrap();
+        self.insert(ns, "mempressure", |scheme_id| Arc::new(MemPressureScheme::new(scheme_id))).unwrap();
         self.insert(ns, "pipe", |scheme_id| PipeScheme::new(scheme_id)).unwrap();
+        self.insert(ns, "shm", |scheme_id| Arc::new(ShmScheme::new(scheme_id))).unwrap();
         self.insert(ns, "sys", |_| Arc::new(SysScheme::new())).unwrap();
+        #[cfg(feature = "test_scheme")]
+        self.insert(ns, "test", |_| Arc::new(TestScheme)).unwrap();
         self.insert(ns, "time", |scheme_id| Arc::new(TimeScheme::new(scheme_id))).unwrap();
 
         ns
@@ -166,11 +282,38 @@ impl SchemeList {
         #[cfg(all(feature = "acpi", any(target_arch = "x86", target_arch = "x86_64")))] {
             self.insert(ns, "kernel/acpi", |scheme_id| Arc::new(AcpiScheme::new(scheme_id))).unwrap();
         }
+        self.insert(ns, "audit", |scheme_id| Arc::new(AuditScheme::new(scheme_id))).unwrap();
+        self.insert(ns, "cpu", |_| Arc::new(CpuScheme)).unwrap();
+        #[cfg(target_arch = "x86_64")]
+        self.insert(ns, "cpufreq", |_| Arc::new(CpuFreqScheme)).unwrap();
         self.insert(ns, "debug", |scheme_id| Arc::new(DebugScheme::new(scheme_id))).unwrap();
+        self.insert(ns, "dump", |_| Arc::new(DumpScheme)).unwrap();
         self.insert(ns, "irq", |scheme_id| Arc::new(IrqScheme::new(scheme_id))).unwrap();
+        #[cfg(target_arch = "x86_64")]
+        self.insert(ns, "kexec", |_| Arc::new(KexecScheme)).unwrap();
+        self.insert(ns, "kmsg", |_| Arc::new(KmsgScheme)).unwrap();
+        #[cfg(target_arch = "x86_64")]
+        self.insert(ns, "kprobe", |_| Arc::new(KprobeScheme)).unwrap();
+        #[cfg(target_arch = "x86_64")]
+        self.insert(ns, "mitigations", |_| Arc::new(MitigationsScheme)).unwrap();
+        self.insert(ns, "perf", |scheme_id| Arc::new(PerfScheme::new(scheme_id))).unwrap();
+        self.insert(ns, "physmem", |_| Arc::new(PhysmemScheme)).unwrap();
+        #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+        self.insert(ns, "power", |scheme_id| Arc::new(PowerScheme::new(scheme_id))).unwrap();
         self.insert(ns, "proc", |scheme_id| Arc::new(ProcScheme::new(scheme_id))).unwrap();
         self.insert(ns, "thisproc", |_| Arc::new(ProcScheme::restricted())).unwrap();
+        #[cfg(target_arch = "x86_64")]
+        self.insert(ns, "profile", |scheme_id| Arc::new(ProfileScheme::new(scheme_id))).unwrap();
+        self.insert(ns, "sched", |_| Arc::new(SchedScheme)).unwrap();
         self.insert(ns, "serio", |scheme_id| Arc::new(SerioScheme::new(scheme_id))).unwrap();
+        self.insert(ns, "swap", |_| Arc::new(SwapScheme)).unwrap();
+        self.insert(ns, "symbols", |_| Arc::new(SymbolsScheme)).unwrap();
+        self.insert(ns, "syscalls", |_| Arc::new(SyscallsScheme)).unwrap();
+        #[cfg(target_arch = "x86_64")]
+        self.insert(ns, "thermal", |scheme_id| Arc::new(ThermalScheme::new(scheme_id))).unwrap();
+        self.insert(ns, "trace", |_| Arc::new(TraceScheme)).unwrap();
+        self.insert(ns, "uio", |scheme_id| Arc::new(UioScheme::new(scheme_id))).unwrap();
+        self.insert(ns, "watchdog", |_| Arc::new(WatchdogScheme)).unwrap();
 
         if let Some(scheme) = self::live::DiskScheme::new().map(Arc::new) {
             self.insert(ns, "disk/live", move |_| scheme.clone()).unwrap();
@@ -306,6 +449,10 @@ pub trait KernelScheme: Scheme + Send + Sync + 'static {
     fn as_sigactions(&self, number: usize) -> Result<Arc<RwLock<Vec<(crate::syscall::data::SigAction, usize)>>>> {
         Err(Error::new(EBADF))
     }
+    /// The kernel side of a scheme provided by userspace
+    fn as_user_inner(&self) -> Result<Arc<user::UserInner>> {
+        Err(Error::new(EBADF))
+    }
 
     fn kfmap(&self, number: usize, addr_space: &Arc<RwLock<AddrSpace>>, map: &crate::syscall::data::Map, consume: bool) -> Result<usize> {
         Err(Error::new(EOPNOTSUPP))
@@ -0,0 +1,221 @@
+//! # Performance counters
+//! `perf:<event>/<target>[/<period>]` opens a counter of `event` (see `perf::Event` for the
+//! names), such as `perf:cycles/self` or `perf:cache-misses/cpu1/100000`. The target is `self`,
+//! the PID of a context with the same effective user, or `cpu<N>`, which needs root. Reading a
+//! counter returns the events it has counted, as a `u64`.
+//!
+//! A counter opened with a period writes a `PerfSample` to its ring every `period` events. The
+//! ring is mapped with `fmap`, a `PerfRingHeader` page followed by `perf::RING_PAGES` pages of
+//! samples, and the counter raises `EVENT_READ` when samples were written. The reader advances
+//! `tail` past the samples it has read.
+//!
+//! The counter stops when its file descriptor is closed, though its ring stays mapped until it is
+//! unmapped.
+
+use alloc::collections::BTreeMap;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::context::{self, file::{FileDescription, FileDescriptor}, ContextId};
+use crate::context::memory::{AddrSpace, Grant, GrantFileRef};
+use crate::event;
+use crate::memory::PAGE_SIZE;
+use crate::perf::{self, Counter, Event, Target};
+use crate::sync::RwLock;
+use crate::syscall::data::{Map, Stat};
+use crate::syscall::error::*;
+use crate::syscall::flag::{EventFlags, MapFlags, EVENT_READ, MODE_FILE, O_RDWR};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{validate_region, UserSliceWo};
+
+use super::{AtomicSchemeId, KernelScheme, SchemeId};
+
+static SCHEME_ID: AtomicSchemeId = AtomicSchemeId::default();
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+struct Handle {
+    counter: Arc<Counter>,
+    /// Set for the handles held by mappings of the ring, which keep it alive but do not count
+    mapping: Option<MapFlags>,
+}
+
+fn insert_handle(handle: Handle) -> usize {
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    HANDLES.write().insert(id, handle);
+    id
+}
+
+fn counter(id: usize) -> Result<Arc<Counter>> {
+    Ok(Arc::clone(&HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.counter))
+}
+
+fn parse_target(target: &str, uid: u32) -> Result<Target> {
+    if target == "self" {
+        return Ok(Target::Context(context::context_id()));
+    }
+    if let Some(cpu) = target.strip_prefix("cpu") {
+        let cpu = cpu.parse::<usize>().or(Err(Error::new(ENOENT)))?;
+        if cpu >= crate::cpu_count() {
+            return Err(Error::new(ENOENT));
+        }
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        return Ok(Target::Cpu(cpu));
+    }
+
+    let pid = ContextId::from(target.parse::<usize>().or(Err(Error::new(ENOENT)))?);
+    let id = context::pid_ns::to_global(context::pid_ns::current()?.as_deref(), pid).ok_or(Error::new(ENOENT))?;
+    let context_lock = context::contexts().get(id).ok_or(Error::new(ENOENT))?.clone();
+    if uid != 0 && context_lock.read().euid != uid {
+        return Err(Error::new(EACCES));
+    }
+    Ok(Target::Context(id))
+}
+
+/// Wake the readers of counters which wrote samples since they were last woken. Called on the
+/// timer tick of the BSP, which may have interrupted a holder of the handles.
+pub fn wake() {
+    let Some(handles) = HANDLES.try_read() else {
+        return;
+    };
+    let scheme_id = SCHEME_ID.load(Ordering::SeqCst);
+    for (&id, handle) in handles.iter().filter(|(_, handle)| handle.mapping.is_none()) {
+        if handle.counter.take_pending() {
+            event::trigger(scheme_id, id, EVENT_READ);
+        }
+    }
+}
+
+pub struct PerfScheme;
+
+impl PerfScheme {
+    pub fn new(scheme_id: SchemeId) -> Self {
+        SCHEME_ID.store(scheme_id, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Scheme for PerfScheme {
+    fn open(&self, path: &str, _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let mut parts = path.trim_matches('/').split('/');
+        let event = Event::from_name(parts.next().unwrap_or("")).ok_or(Error::new(ENOENT))?;
+        let target = parse_target(parts.next().ok_or(Error::new(ENOENT))?, uid)?;
+        let period = match parts.next() {
+            Some(period) => period.parse::<u64>().or(Err(Error::new(EINVAL)))?,
+            None => 0,
+        };
+        if parts.next().is_some() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let counter = Counter::new(event, target, period)?;
+        match target {
+            Target::Context(id) => {
+                let context_lock = context::contexts().get(id).ok_or(Error::new(ESRCH))?.clone();
+                context_lock.write().perf.push(Arc::clone(&counter));
+            }
+            Target::Cpu(_) => perf::add_cpu_counter(Arc::clone(&counter)),
+        }
+
+        Ok(insert_handle(Handle { counter, mapping: None }))
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(EventFlags::empty())
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        let handle = HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        if handle.mapping.is_some() {
+            return Ok(0);
+        }
+
+        handle.counter.close();
+        if let Target::Context(id) = handle.counter.target {
+            // The context may have exited, taking its counters with it
+            if let Some(context_lock) = context::contexts().get(id).cloned() {
+                context_lock.write().perf.retain(|counter| !Arc::ptr_eq(counter, &handle.counter));
+            }
+        }
+        Ok(0)
+    }
+}
+impl KernelScheme for PerfScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        buf.copy_exactly(&counter(id)?.count())?;
+        Ok(mem::size_of::<u64>())
+    }
+
+    fn kfmap(&self, id: usize, addr_space: &Arc<RwLock<AddrSpace>>, map: &Map, _consume: bool) -> Result<usize> {
+        let counter = counter(id)?;
+        let ring = counter.ring().ok_or(Error::new(EINVAL))?;
+        let (requested_page, page_count) = validate_region(map.address, map.size)?;
+        if map.offset % PAGE_SIZE != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let frames = ring.frames().get(map.offset / PAGE_SIZE..).ok_or(Error::new(EINVAL))?;
+        let frames = frames.get(..page_count).ok_or(Error::new(EINVAL))?;
+        let namespace = context::current()?.read().ens;
+
+        let page = addr_space.write().mmap((map.address != 0).then_some(requested_page), page_count, map.flags, |dst_page, flags, mapper, flusher| {
+            // Each grant holds its own handle to the counter, which is closed when it is unmapped
+            let number = insert_handle(Handle {
+                counter: Arc::clone(&counter),
+                mapping: Some(map.flags),
+            });
+            let desc = FileDescriptor {
+                description: Arc::new(RwLock::new(FileDescription {
+                    namespace,
+                    scheme: SCHEME_ID.load(Ordering::SeqCst),
+                    number,
+                    flags: O_RDWR,
+                })),
+                cloexec: false,
+            };
+
+            Ok(Grant::shared(frames, dst_page, flags, GrantFileRef { desc, offset: map.offset, flags: map.flags }, mapper, flusher)?)
+        })?;
+
+        Ok(page.start_address().data())
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let counter = counter(id)?;
+        let mut path = format!("perf:{}/", counter.event.name());
+        match counter.target {
+            Target::Context(id) => {
+                let pid = context::pid_ns::to_local_or_zero(context::pid_ns::current()?.as_deref(), id);
+                path.push_str(&pid.into().to_string());
+            }
+            Target::Cpu(cpu) => path.push_str(&format!("cpu{}", cpu)),
+        }
+        if counter.period > 0 {
+            path.push_str(&format!("/{}", counter.period));
+        }
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let counter = counter(id)?;
+        let size = counter.ring().map_or(mem::size_of::<u64>(), |ring| ring.frames().len() * PAGE_SIZE);
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FILE | 0o444,
+            st_size: size as u64,
+            st_blksize: PAGE_SIZE as u32,
+            ..Default::default()
+        })?;
+        Ok(0)
+    }
+}
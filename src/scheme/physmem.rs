@@ -0,0 +1,204 @@
+//! # Physical memory reads
+//! Diagnostic tools need to read physical memory the kernel does not own, like ACPI tables,
+//! framebuffers and regions left behind by a crashed kernel. `memory:` maps any physical address
+//! writable, which is more than these need, so `physmem:` allows reading only, and only within the
+//! regions the firmware memory map reported as reserved or reclaimable (see `memory::firmware`).
+//!
+//! `physmem:regions` lists the allowed regions, one per line as `base size kind`, in hexadecimal.
+//! The offset of `physmem:mem` is the physical address: seek to it and read. A read must lie
+//! entirely within one region, or it fails with `EACCES`. Only root can open the scheme, and every
+//! read is logged with the reading process and the range.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::context;
+use crate::memory::firmware::{self, Kind};
+use crate::memory::PAGE_SIZE;
+use crate::paging::huge;
+use crate::paging::{KernelMapper, Page, PageFlags, PhysicalAddress, RmmA, RmmArch, VirtualAddress};
+use crate::scheme::memory::MemoryType;
+use crate::sync::RwLock;
+use crate::syscall::error::*;
+use crate::syscall::flag::{O_ACCMODE, O_RDONLY, SEEK_CUR, SEEK_SET};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::UserSliceWo;
+
+use super::KernelScheme;
+
+/// Largest read, so that the kernel mapper is not held for long
+const MAX_READ: usize = 16 * PAGE_SIZE;
+
+#[derive(Clone, Copy)]
+enum File {
+    Regions,
+    Mem,
+}
+
+struct Handle {
+    file: File,
+    offset: usize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn regions() -> Vec<u8> {
+    let mut string = String::new();
+    for region in firmware::regions() {
+        let _ = writeln!(string, "{:#x} {:#x} {}", region.base, region.size, region.kind.name());
+    }
+    string.into_bytes()
+}
+
+/// Copy physical memory from `phys` into `buf`. Pages the physmap does not cover are mapped
+/// read-only for the copy, uncached for reserved regions, which may hold device memory, and
+/// unmapped again before returning.
+fn copy_phys(phys: usize, buf: &mut [u8], kind: Kind) -> Result<()> {
+    let mut mapper = KernelMapper::lock();
+    let mapper = mapper.get_mut().ok_or(Error::new(EAGAIN))?;
+
+    let flags = match kind {
+        Kind::Reclaim => PageFlags::new(),
+        Kind::Reserved => MemoryType::Uncacheable.page_flags(PageFlags::new()),
+    };
+
+    let first = Page::containing_address(VirtualAddress::new(phys));
+    let last = Page::containing_address(VirtualAddress::new(phys + buf.len() - 1));
+    let mut mapped = Vec::new();
+    let mut complete = true;
+    for page in Page::range_inclusive(first, last) {
+        let virt = unsafe { RmmA::phys_to_virt(PhysicalAddress::new(page.start_address().data())) };
+        if huge::translate(mapper, virt).is_some() {
+            continue;
+        }
+        match unsafe { mapper.map_phys(virt, PhysicalAddress::new(page.start_address().data()), flags) } {
+            Some(flush) => {
+                flush.flush();
+                mapped.push(virt);
+            }
+            None => {
+                complete = false;
+                break;
+            }
+        }
+    }
+
+    let result = if complete {
+        let src = unsafe { RmmA::phys_to_virt(PhysicalAddress::new(phys)) };
+        unsafe { core::ptr::copy_nonoverlapping(src.data() as *const u8, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    } else {
+        Err(Error::new(ENOMEM))
+    };
+
+    // The pages were only accessed on this CPU, with the mapper locked
+    for virt in mapped {
+        if let Some((_, _, flush)) = unsafe { mapper.unmap_phys(virt, true) } {
+            flush.flush();
+        }
+    }
+
+    result
+}
+
+pub struct PhysmemScheme;
+
+impl Scheme for PhysmemScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        let file = match path.trim_matches('/') {
+            "regions" => File::Regions,
+            "mem" => File::Mem,
+            _ => return Err(Error::new(ENOENT)),
+        };
+        if flags & O_ACCMODE != O_RDONLY {
+            return Err(Error::new(EROFS));
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { file, offset: 0 });
+        Ok(id)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<isize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let new_offset = match whence {
+            SEEK_SET => usize::try_from(pos).or(Err(Error::new(EINVAL)))?,
+            SEEK_CUR => handle.offset.checked_add_signed(pos).ok_or(Error::new(EINVAL))?,
+            // Physical memory has no end to seek from
+            _ => return Err(Error::new(EINVAL)),
+        };
+        handle.offset = new_offset;
+
+        isize::try_from(new_offset).or(Err(Error::new(EOVERFLOW)))
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+}
+impl KernelScheme for PhysmemScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let (file, offset) = {
+            let handles = HANDLES.read();
+            let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+            (handle.file, handle.offset)
+        };
+
+        let bytes_read = match file {
+            File::Regions => {
+                let data = regions();
+                buf.copy_common_bytes_from_slice(data.get(offset..).unwrap_or(&[]))?
+            }
+            File::Mem => {
+                let len = buf.len().min(MAX_READ);
+                if len == 0 {
+                    return Ok(0);
+                }
+                let end = offset.checked_add(len).ok_or(Error::new(EINVAL))?;
+                let region = firmware::region_containing(offset as u64, end as u64);
+
+                {
+                    let context_lock = context::current()?;
+                    let context = context_lock.read();
+                    log::info!("physmem: {} ({}) read {:#x}..{:#x}{}", context.id.into(), context.name, offset, end,
+                        if region.is_some() { "" } else { ", denied" });
+                }
+
+                let region = region.ok_or(Error::new(EACCES))?;
+                let mut data = vec![0_u8; len];
+                copy_phys(offset, &mut data, region.kind)?;
+                buf.limit(len).ok_or(Error::new(EINVAL))?.copy_from_slice(&data)?;
+                len
+            }
+        };
+
+        if let Some(handle) = HANDLES.write().get_mut(&id) {
+            handle.offset = offset + bytes_read;
+        }
+        Ok(bytes_read)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Regions => "physmem:regions",
+            File::Mem => "physmem:mem",
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
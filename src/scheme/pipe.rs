@@ -3,11 +3,12 @@ use core::sync::atomic::{AtomicUsize, Ordering, AtomicBool};
 use alloc::sync::Arc;
 use alloc::collections::{BTreeMap, VecDeque};
 
-use spin::{Mutex, Once, RwLock};
+use spin::Once;
 
 use crate::event;
 use crate::scheme::SchemeId;
-use crate::sync::WaitCondition;
+use crate::sync::{Mutex, RwLock, WaitCondition};
+use crate::syscall::abi::{BOOST_PIPE, F_GETLOWAT, F_SETLOWAT};
 use crate::syscall::error::{Error, Result, EAGAIN, EBADF, EINTR, EINVAL, ENOENT, EPIPE, ESPIPE};
 use crate::syscall::flag::{EventFlags, EVENT_READ, EVENT_WRITE, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK, MODE_FIFO};
 use crate::syscall::scheme::{CallerCtx, Scheme};
@@ -44,12 +45,15 @@ pub fn pipe(flags: usize) -> Result<(usize, usize)> {
     PIPES.write().insert(id, Arc::new(Pipe {
         read_flags: AtomicUsize::new(flags),
         write_flags: AtomicUsize::new(flags),
+        read_lowat: AtomicUsize::new(1),
+        write_lowat: AtomicUsize::new(1),
         queue: Mutex::new(VecDeque::new()),
         read_condition: WaitCondition::new(),
         write_condition: WaitCondition::new(),
         writer_is_alive: AtomicBool::new(true),
         reader_is_alive: AtomicBool::new(true),
         has_run_dup: AtomicBool::new(false),
+        splicing: AtomicBool::new(false),
     }));
 
     Ok((id, id | WRITE_NOT_READ_BIT))
@@ -72,6 +76,7 @@ impl Scheme for PipeScheme {
         let pipe = Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?);
 
         let flags = if is_writer_not_reader { &pipe.write_flags } else { &pipe.read_flags };
+        let lowat = if is_writer_not_reader { &pipe.write_lowat } else { &pipe.read_lowat };
 
         match cmd {
             F_GETFL => Ok(flags.load(Ordering::SeqCst)),
@@ -79,6 +84,14 @@ impl Scheme for PipeScheme {
                 flags.store(arg & !O_ACCMODE, Ordering::SeqCst);
                 Ok(0)
             },
+            F_GETLOWAT => Ok(lowat.load(Ordering::SeqCst)),
+            F_SETLOWAT => {
+                if arg == 0 || arg > MAX_QUEUE_SIZE {
+                    return Err(Error::new(EINVAL));
+                }
+                lowat.store(arg, Ordering::SeqCst);
+                Ok(0)
+            },
             _ => Err(Error::new(EINVAL))
         }
     }
@@ -89,14 +102,14 @@ impl Scheme for PipeScheme {
 
         if is_writer_not_reader && flags == EVENT_WRITE {
             // TODO: Return correct flags
-            if pipe.queue.lock().len() >= MAX_QUEUE_SIZE {
+            if MAX_QUEUE_SIZE.saturating_sub(pipe.queue.lock().len()) < pipe.write_lowat.load(Ordering::SeqCst) {
                 return Ok(EventFlags::empty());
             } else {
                 return Ok(EVENT_WRITE);
             }
         } else if flags == EVENT_READ {
             // TODO: Return correct flags
-            if pipe.queue.lock().is_empty() {
+            if pipe.queue.lock().len() < pipe.read_lowat.load(Ordering::SeqCst) {
                 return Ok(EventFlags::empty());
             } else {
                 return Ok(EVENT_READ);
@@ -147,12 +160,15 @@ impl Scheme for PipeScheme {
 pub struct Pipe {
     read_flags: AtomicUsize, // fcntl read flags
     write_flags: AtomicUsize, // fcntl write flags
+    read_lowat: AtomicUsize, // bytes available before the reader is woken
+    write_lowat: AtomicUsize, // bytes free before the writer is woken
     read_condition: WaitCondition, // signals whether there are available bytes to read
     write_condition: WaitCondition, // signals whether there is room for additional bytes
     queue: Mutex<VecDeque<u8>>,
     reader_is_alive: AtomicBool, // starts set, unset when reader closes
     writer_is_alive: AtomicBool, // starts set, unset when writer closes
     has_run_dup: AtomicBool,
+    splicing: AtomicBool, // set while bytes taken by splice may be put back, other readers wait
 }
 
 impl KernelScheme for PipeScheme {
@@ -188,16 +204,45 @@ impl KernelScheme for PipeScheme {
     }
 
     fn kread(&self, id: usize, user_buf: UserSliceWo) -> Result<usize> {
-        let (is_write_not_read, key) = from_raw_id(id);
+        read(id, user_buf, false)
+    }
+    fn kwrite(&self, id: usize, user_buf: UserSliceRo) -> Result<usize> {
+        write(id, user_buf, false)
+    }
+    fn kfstat(&self, _id: usize, buf: UserSliceWo) -> Result<usize> {
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FIFO | 0o666,
+            ..Default::default()
+        })?;
 
-        if is_write_not_read {
-            return Err(Error::new(EBADF));
-        }
-        let pipe = Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?);
+        Ok(0)
+    }
+}
 
-        loop {
-            let mut vec = pipe.queue.lock();
+/// Read from the read end `id`, without blocking if either `nonblock` or `O_NONBLOCK` is set
+pub fn read(id: usize, user_buf: UserSliceWo, nonblock: bool) -> Result<usize> {
+    read_inner(id, user_buf, nonblock, false)
+}
+
+/// Like `read`, but for `splice`, which must then call `finish_splice` with the bytes it could
+/// not pass on. Other readers wait until then, so that they cannot read past those bytes.
+pub fn splice_read(id: usize, user_buf: UserSliceWo, nonblock: bool) -> Result<usize> {
+    read_inner(id, user_buf, nonblock, true)
+}
+
+fn read_inner(id: usize, user_buf: UserSliceWo, nonblock: bool, splice: bool) -> Result<usize> {
+    let (is_write_not_read, key) = from_raw_id(id);
 
+    if is_write_not_read {
+        return Err(Error::new(EBADF));
+    }
+    let pipe = Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?);
+
+    loop {
+        let mut vec = pipe.queue.lock();
+        let splicing = pipe.splicing.load(Ordering::SeqCst);
+
+        if !splicing {
             let (s1, s2) = vec.as_slices();
             let s1_count = core::cmp::min(user_buf.len(), s1.len());
 
@@ -211,78 +256,143 @@ impl KernelScheme for PipeScheme {
             let _ = vec.drain(..bytes_read);
 
             if bytes_read > 0 {
-                event::trigger(pipe_scheme_id(), key | WRITE_NOT_READ_BIT, EVENT_WRITE);
-                pipe.write_condition.notify();
+                pipe.splicing.store(splice, Ordering::SeqCst);
+
+                // Only wake the writer once it can make enough progress, to avoid switching back
+                // and forth for every few bytes
+                if MAX_QUEUE_SIZE.saturating_sub(vec.len()) >= pipe.write_lowat.load(Ordering::SeqCst) {
+                    event::trigger(pipe_scheme_id(), key | WRITE_NOT_READ_BIT, EVENT_WRITE);
+                    pipe.write_condition.notify();
+                }
 
                 return Ok(bytes_read);
             } else if user_buf.is_empty() {
                 return Ok(0);
             }
+        }
 
-            if !pipe.writer_is_alive.load(Ordering::SeqCst) {
-                return Ok(0);
-            } else if pipe.read_flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
-                return Err(Error::new(EAGAIN));
-            } else if !pipe.read_condition.wait(vec, "PipeRead::read") {
-                return Err(Error::new(EINTR));
-            }
+        // Bytes taken by a splice may still be put back, even if the writer is gone
+        if !splicing && !pipe.writer_is_alive.load(Ordering::SeqCst) {
+            return Ok(0);
+        } else if nonblock || pipe.read_flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+            return Err(Error::new(EAGAIN));
+        } else if !pipe.read_condition.wait(vec, "PipeRead::read") {
+            return Err(Error::new(EINTR));
         }
     }
-    fn kwrite(&self, id: usize, user_buf: UserSliceRo) -> Result<usize> {
-        let (is_write_not_read, key) = from_raw_id(id);
+}
 
-        if !is_write_not_read {
-            return Err(Error::new(EBADF));
-        }
-        let pipe = Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?);
+/// Write to the write end `id`, without blocking if either `nonblock` or `O_NONBLOCK` is set
+pub fn write(id: usize, user_buf: UserSliceRo, nonblock: bool) -> Result<usize> {
+    let (is_write_not_read, key) = from_raw_id(id);
 
-        loop {
-            let mut vec = pipe.queue.lock();
+    if !is_write_not_read {
+        return Err(Error::new(EBADF));
+    }
+    let pipe = Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?);
 
-            let bytes_left = MAX_QUEUE_SIZE.saturating_sub(vec.len());
-            let bytes_to_write = core::cmp::min(bytes_left, user_buf.len());
-            let src_buf = user_buf.limit(bytes_to_write).expect("bytes_to_write <= user_buf.len()");
+    loop {
+        let mut vec = pipe.queue.lock();
 
-            const TMPBUF_SIZE: usize = 512;
-            let mut tmp_buf = [0_u8; TMPBUF_SIZE];
+        let bytes_left = MAX_QUEUE_SIZE.saturating_sub(vec.len());
+        let bytes_to_write = core::cmp::min(bytes_left, user_buf.len());
+        let src_buf = user_buf.limit(bytes_to_write).expect("bytes_to_write <= user_buf.len()");
 
-            let mut bytes_written = 0;
+        const TMPBUF_SIZE: usize = 512;
+        let mut tmp_buf = [0_u8; TMPBUF_SIZE];
 
-            // TODO: Modify VecDeque so that the unwritten portions can be accessed directly?
-            for (idx, chunk) in src_buf.in_variable_chunks(TMPBUF_SIZE).enumerate() {
-                let chunk_byte_count = match chunk.copy_common_bytes_to_slice(&mut tmp_buf) {
-                    Ok(c) => c,
-                    Err(_) if idx > 0 => break,
-                    Err(error) => return Err(error),
-                };
-                vec.extend(&tmp_buf[..chunk_byte_count]);
-                bytes_written += chunk_byte_count;
-            }
+        let mut bytes_written = 0;
 
-            if bytes_written > 0 {
-                event::trigger(pipe_scheme_id(), key, EVENT_READ);
-                pipe.read_condition.notify();
+        // TODO: Modify VecDeque so that the unwritten portions can be accessed directly?
+        for (idx, chunk) in src_buf.in_variable_chunks(TMPBUF_SIZE).enumerate() {
+            let chunk_byte_count = match chunk.copy_common_bytes_to_slice(&mut tmp_buf) {
+                Ok(c) => c,
+                Err(_) if idx > 0 => break,
+                Err(error) => return Err(error),
+            };
+            vec.extend(&tmp_buf[..chunk_byte_count]);
+            bytes_written += chunk_byte_count;
+        }
 
-                return Ok(bytes_written);
-            } else if user_buf.is_empty() {
-                return Ok(0);
+        if bytes_written > 0 {
+            if vec.len() >= pipe.read_lowat.load(Ordering::SeqCst) {
+                event::trigger_boosted(pipe_scheme_id(), key, EVENT_READ, BOOST_PIPE);
+                pipe.read_condition.notify_boosted(BOOST_PIPE);
             }
 
-            if !pipe.reader_is_alive.load(Ordering::SeqCst) {
-                return Err(Error::new(EPIPE));
-            } else if pipe.write_flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
-                return Err(Error::new(EAGAIN));
-            } else if !pipe.write_condition.wait(vec, "PipeWrite::write") {
-                return Err(Error::new(EINTR));
-            }
+            return Ok(bytes_written);
+        } else if user_buf.is_empty() {
+            return Ok(0);
+        }
+
+        if !pipe.reader_is_alive.load(Ordering::SeqCst) {
+            return Err(Error::new(EPIPE));
+        } else if nonblock || pipe.write_flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+            return Err(Error::new(EAGAIN));
+        } else if !pipe.write_condition.wait(vec, "PipeWrite::write") {
+            return Err(Error::new(EINTR));
         }
     }
-    fn kfstat(&self, _id: usize, buf: UserSliceWo) -> Result<usize> {
-        buf.copy_exactly(&Stat {
-            st_mode: MODE_FIFO | 0o666,
-            ..Default::default()
-        })?;
+}
 
-        Ok(0)
+/// End a splice from the read end `id`, putting the bytes of `user_buf`, taken with `splice_read`
+/// but not passed on, back at the front in their original order. Other readers may read again
+/// afterwards, even if copying the bytes fails.
+pub fn finish_splice(id: usize, user_buf: UserSliceRo) -> Result<()> {
+    let (is_write_not_read, key) = from_raw_id(id);
+
+    if is_write_not_read {
+        return Err(Error::new(EBADF));
+    }
+    let pipe = Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?);
+
+    let mut bytes = vec![0_u8; user_buf.len()];
+    let result = user_buf.copy_to_slice(&mut bytes);
+
+    let mut vec = pipe.queue.lock();
+    if result.is_ok() {
+        for &byte in bytes.iter().rev() {
+            vec.push_front(byte);
+        }
+    }
+    pipe.splicing.store(false, Ordering::SeqCst);
+    let readable = vec.len() >= pipe.read_lowat.load(Ordering::SeqCst) || !pipe.writer_is_alive.load(Ordering::SeqCst);
+    drop(vec);
+
+    // Readers which waited for the splice are woken even below the low watermark, as they may
+    // have waited while there was enough to read
+    if readable {
+        event::trigger(pipe_scheme_id(), key, EVENT_READ);
+    }
+    pipe.read_condition.notify();
+    result
+}
+
+/// Wait until there is room in the write end `id`, and return how many bytes can be written
+/// without blocking (unless other writers take the room first)
+pub fn reserve(id: usize, nonblock: bool) -> Result<usize> {
+    let (is_write_not_read, key) = from_raw_id(id);
+
+    if !is_write_not_read {
+        return Err(Error::new(EBADF));
+    }
+    let pipe = Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?);
+
+    loop {
+        let vec = pipe.queue.lock();
+
+        if !pipe.reader_is_alive.load(Ordering::SeqCst) {
+            return Err(Error::new(EPIPE));
+        }
+        let bytes_left = MAX_QUEUE_SIZE.saturating_sub(vec.len());
+        if bytes_left > 0 {
+            return Ok(bytes_left);
+        }
+
+        if nonblock || pipe.write_flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+            return Err(Error::new(EAGAIN));
+        } else if !pipe.write_condition.wait(vec, "PipeWrite::reserve") {
+            return Err(Error::new(EINTR));
+        }
     }
 }
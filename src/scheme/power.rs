@@ -0,0 +1,256 @@
+//! # Power
+//! `power:state` lists the sleep states that can be entered, and root can write one of them to
+//! enter it, the write returning once the system resumed: `mem`, suspend to RAM, or `disk`, suspend
+//! to disk (see `power`). The sleep types of suspend to RAM, from the `\_S3` object, are in `power:s3` as two numbers,
+//! and those of power off, from `\_S5`, in `power:s5`. The kernel evaluates them if it could load
+//! the ACPI namespace (see `acpi::aml`), and the ACPI driver can write them otherwise. Root can also write `off` to power off, or `reboot` to reboot.
+//!
+//! Drivers open `power:notify` to be told of suspend and resume, with `EVENT_READ`. Reading returns
+//! `suspend`, `resume`, `poweroff` or `reboot`, or nothing if none is pending, and after any but
+//! `resume` a driver writes `ready` once its device is quiesced, or its filesystem synced.
+//!
+//! Only root can open `power:image`. After writing `disk`, the image taken is read from it, and
+//! reading nothing instead means the system resumed from an image. Once it is stored, writing
+//! `off` powers off, or `discard` drops it. When booting again, the image is written back in whole
+//! pages, and the write of the last one restores it, only returning if it could not be.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::event;
+use crate::hibernate;
+use crate::power;
+use crate::sync::RwLock;
+use crate::syscall::error::*;
+use crate::syscall::flag::{EventFlags, EVENT_READ, O_ACCMODE, O_RDONLY};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::{AtomicSchemeId, KernelScheme, SchemeId};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Suspend,
+    Resume,
+    PowerOff,
+    Reboot,
+}
+
+#[derive(Clone, Copy)]
+enum File {
+    State,
+    S3,
+    S5,
+    Notify,
+    Image,
+}
+
+struct Handle {
+    file: File,
+    offset: usize,
+    /// The transition not yet read, for `notify`
+    pending: Option<Transition>,
+    /// Whether the driver is ready to suspend, for `notify`
+    ready: bool,
+    /// Whether an image is being written, for `image`
+    loading: bool,
+}
+
+static SCHEME_ID: AtomicSchemeId = AtomicSchemeId::default();
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+/// Tell the drivers with `notify` open of a transition
+pub fn notify(transition: Transition) {
+    let scheme_id = SCHEME_ID.load(Ordering::SeqCst);
+    let mut handles = HANDLES.write();
+    for (&id, handle) in handles.iter_mut().filter(|(_, handle)| matches!(handle.file, File::Notify)) {
+        handle.pending = Some(transition);
+        handle.ready = transition == Transition::Resume;
+        event::trigger(scheme_id, id, EVENT_READ);
+    }
+}
+
+/// Returns true once every driver with `notify` open is ready for the last transition
+pub fn all_ready() -> bool {
+    HANDLES.read().values().filter(|handle| matches!(handle.file, File::Notify)).all(|handle| handle.ready)
+}
+
+fn contents(file: File) -> Vec<u8> {
+    let mut string = String::new();
+    match file {
+        File::State => {
+            if power::can_suspend() {
+                string.push_str("mem\n");
+            }
+            if power::can_hibernate() {
+                string.push_str("disk\n");
+            }
+        }
+        File::S3 | File::S5 => {
+            let sleep_types = match file {
+                File::S3 => power::s3_sleep_types(),
+                _ => power::s5_sleep_types(),
+            };
+            if let Some((slp_typa, slp_typb)) = sleep_types {
+                let _ = writeln!(string, "{} {}", slp_typa, slp_typb);
+            }
+        }
+        File::Notify | File::Image => (),
+    }
+    string.into_bytes()
+}
+
+/// Parse SLP_TYPa and SLP_TYPb, as two numbers
+fn parse_sleep_types(string: &str) -> Option<(u8, u8)> {
+    let mut values = string.split_whitespace().map(str::parse::<u8>);
+    match (values.next(), values.next(), values.next()) {
+        (Some(Ok(slp_typa)), Some(Ok(slp_typb)), None) if slp_typa < 8 && slp_typb < 8 => Some((slp_typa, slp_typb)),
+        _ => None,
+    }
+}
+
+pub struct PowerScheme;
+
+impl PowerScheme {
+    pub fn new(scheme_id: SchemeId) -> Self {
+        SCHEME_ID.store(scheme_id, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Scheme for PowerScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let file = match path.trim_matches('/') {
+            "state" => File::State,
+            "s3" => File::S3,
+            "s5" => File::S5,
+            "notify" => File::Notify,
+            "image" => File::Image,
+            _ => return Err(Error::new(ENOENT)),
+        };
+        if (flags & O_ACCMODE != O_RDONLY || matches!(file, File::Image)) && uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { file, offset: 0, pending: None, ready: true, loading: false });
+        Ok(id)
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        let handles = HANDLES.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+        Ok(if handle.pending.is_some() { EVENT_READ } else { EventFlags::empty() })
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        let handle = HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        if handle.loading {
+            hibernate::abort_load();
+        }
+        Ok(0)
+    }
+}
+impl KernelScheme for PowerScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        if let File::Notify = handle.file {
+            let data: &[u8] = match handle.pending.take() {
+                Some(Transition::Suspend) => b"suspend\n",
+                Some(Transition::Resume) => b"resume\n",
+                Some(Transition::PowerOff) => b"poweroff\n",
+                Some(Transition::Reboot) => b"reboot\n",
+                None => b"",
+            };
+            return buf.copy_common_bytes_from_slice(data);
+        }
+        if let File::Image = handle.file {
+            let bytes_read = hibernate::read_image(handle.offset, buf)?;
+            handle.offset += bytes_read;
+            return Ok(bytes_read);
+        }
+
+        let data = contents(handle.file);
+        let bytes_read = buf.copy_common_bytes_from_slice(data.get(handle.offset..).unwrap_or(&[]))?;
+        handle.offset += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        let file = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file;
+
+        // Images are written in whole pages, and a held image only takes commands
+        if let File::Image = file {
+            if !hibernate::has_image() {
+                if let Some(handle) = HANDLES.write().get_mut(&id) {
+                    handle.loading = true;
+                }
+                let len = buf.len();
+                if hibernate::load(buf)? {
+                    if let Some(handle) = HANDLES.write().get_mut(&id) {
+                        handle.loading = false;
+                    }
+                    power::restore()?;
+                }
+                return Ok(len);
+            }
+        }
+
+        let mut bytes = [0_u8; 16];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        let string = str::from_utf8(&bytes[..len]).map_err(|_| Error::new(EINVAL))?.trim();
+        match file {
+            // The lock on the handles must not be held while suspended
+            File::State => match string {
+                "mem" => power::suspend()?,
+                "disk" => {
+                    power::hibernate()?;
+                }
+                "off" => power::power_off()?,
+                "reboot" => power::reboot()?,
+                _ => return Err(Error::new(EINVAL)),
+            },
+            File::S3 => power::set_s3_sleep_types(Some(parse_sleep_types(string).ok_or(Error::new(EINVAL))?)),
+            File::S5 => power::set_s5_sleep_types(Some(parse_sleep_types(string).ok_or(Error::new(EINVAL))?)),
+            File::Notify => match string {
+                "ready" => {
+                    if let Some(handle) = HANDLES.write().get_mut(&id) {
+                        handle.ready = true;
+                    }
+                }
+                _ => return Err(Error::new(EINVAL)),
+            },
+            File::Image => match string {
+                "off" => unsafe { crate::stop::kstop() },
+                "discard" => hibernate::discard(),
+                _ => return Err(Error::new(EINVAL)),
+            },
+        }
+        Ok(len)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::State => "power:state",
+            File::S3 => "power:s3",
+            File::S5 => "power:s5",
+            File::Notify => "power:notify",
+            File::Image => "power:image",
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
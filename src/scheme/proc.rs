@@ -1,13 +1,16 @@
 use crate::{
     arch::paging::{mapper::InactiveFlusher, Page, RmmA, RmmArch, VirtualAddress},
-    context::{self, Context, ContextId, Status, file::{FileDescription, FileDescriptor}, memory::{AddrSpace, Grant, new_addrspace, map_flags, Region}, BorrowedHtBuf},
+    audit,
+    context::{self, Context, ContextId, Status, file::{FileDescription, FileDescriptor}, memory::{AddrSpace, Grant, new_addrspace, map_flags, Region}, oom::{OOM_SCORE_ADJ_MAX, OOM_SCORE_ADJ_MIN}, BorrowedHtBuf},
     memory::PAGE_SIZE,
     ptrace,
-    scheme::{self, FileHandle, KernelScheme, SchemeId},
+    scheme::{self, FileHandle, KernelScheme, SchemeId, dir::{self, DirCursor}},
+    sync::RwLock,
     syscall::{
         FloatRegisters,
         IntRegisters,
         EnvRegisters,
+        abi::{Rusage, PTRACE_EVENT_EXEC, PTRACE_EVENT_FORK},
         data::{Map, PtraceEvent, SigAction, Stat},
         error::*,
         flag::*,
@@ -30,7 +33,7 @@ use core::{
     str,
     sync::atomic::{AtomicUsize, Ordering},
 };
-use spin::{Once, RwLock};
+use spin::Once;
 
 use super::OpenResult;
 
@@ -105,6 +108,9 @@ enum RegsKind {
     Float,
     Int,
     Env,
+    /// All floating point and vector registers, of a size that depends on the CPU (see
+    /// `Context::get_xstate`)
+    Xstate,
 }
 #[derive(Clone)]
 enum Operation {
@@ -114,6 +120,7 @@ enum Operation {
     Static(&'static str),
     Name,
     Sigstack,
+    Siginfo,
     Attr(Attr),
     Filetable { filetable: Arc<RwLock<Vec<Option<FileDescriptor>>>> },
     AddrSpace { addrspace: Arc<RwLock<AddrSpace>> },
@@ -142,11 +149,25 @@ enum Operation {
     GrantHandle { description: Arc<RwLock<FileDescription>> },
 
     SchedAffinity,
+    OomScoreAdj,
+    Usage,
+    /// Usage of the address space in pages, listed when opened, see `AddrSpaceStats`
+    MemStat,
     Sigactions(Arc<RwLock<Vec<(SigAction, usize)>>>),
     CurrentSigactions,
     AwaitingSigactionsChange(Arc<RwLock<Vec<(SigAction, usize)>>>),
 
     MmapMinAddr(Arc<RwLock<AddrSpace>>),
+    StackGrowth(Arc<RwLock<AddrSpace>>),
+    Swappable(Arc<RwLock<AddrSpace>>),
+    /// Whether the address space may have writable and executable mappings, see `check_wx`
+    AllowWx(Arc<RwLock<AddrSpace>>),
+    /// Whether the layout of the address space is randomized, see `AddrSpace::randomize`
+    Aslr(Arc<RwLock<AddrSpace>>),
+    MemLimits(Arc<RwLock<AddrSpace>>),
+
+    // The top-level directory, listing the process IDs
+    Dir,
 }
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum Attr {
@@ -156,7 +177,7 @@ enum Attr {
 }
 impl Operation {
     fn needs_child_process(&self) -> bool {
-        matches!(self, Self::Memory { .. } | Self::Regs(_) | Self::Trace | Self::Filetable { .. } | Self::AddrSpace { .. } | Self::CurrentAddrSpace | Self::CurrentFiletable | Self::Sigactions(_) | Self::CurrentSigactions | Self::AwaitingSigactionsChange(_))
+        matches!(self, Self::Memory { .. } | Self::Regs(_) | Self::Trace | Self::Filetable { .. } | Self::AddrSpace { .. } | Self::CurrentAddrSpace | Self::CurrentFiletable | Self::Sigactions(_) | Self::CurrentSigactions | Self::AwaitingSigactionsChange(_) | Self::Siginfo)
     }
     fn needs_root(&self) -> bool {
         matches!(self, Self::Attr(_))
@@ -191,6 +212,7 @@ enum OperationData {
     Trace(TraceData),
     Static(StaticData),
     Offset(usize),
+    Dir(DirCursor),
     Other,
 }
 impl OperationData {
@@ -200,6 +222,12 @@ impl OperationData {
             _ => None,
         }
     }
+    fn mem_offset(&self) -> Option<VirtualAddress> {
+        match self {
+            OperationData::Memory(data) => Some(data.offset),
+            _ => None,
+        }
+    }
     fn mem_data(&mut self) -> Option<&mut MemData> {
         match self {
             OperationData::Memory(data) => Some(data),
@@ -212,6 +240,12 @@ impl OperationData {
             _ => None,
         }
     }
+    fn dir_data(&mut self) -> Option<&mut DirCursor> {
+        match self {
+            OperationData::Dir(data) => Some(data),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -287,6 +321,16 @@ fn get_context(id: ContextId) -> Result<Arc<RwLock<Context>>> {
     context::contexts().get(id).ok_or(Error::new(ENOENT)).map(Arc::clone)
 }
 
+/// The IDs of all contexts visible in the PID namespace of the caller, listed by the top-level
+/// directory
+fn context_ids() -> Vec<String> {
+    let ns = context::pid_ns::current().ok().flatten();
+    context::contexts().iter()
+        .filter_map(|(&id, _context)| context::pid_ns::to_local(ns.as_deref(), id))
+        .map(|pid| pid.into().to_string())
+        .collect()
+}
+
 impl ProcScheme {
     fn open_inner(&self, pid: ContextId, operation_str: Option<&str>, flags: usize, uid: u32, gid: u32) -> Result<usize> {
         let operation = match operation_str {
@@ -298,17 +342,27 @@ impl ProcScheme {
             Some("regs/float") => Operation::Regs(RegsKind::Float),
             Some("regs/int") => Operation::Regs(RegsKind::Int),
             Some("regs/env") => Operation::Regs(RegsKind::Env),
+            Some("regs/xstate") => Operation::Regs(RegsKind::Xstate),
             Some("trace") => Operation::Trace,
             Some("exe") => Operation::Static("exe"),
             Some("name") => Operation::Name,
             Some("sigstack") => Operation::Sigstack,
+            Some("siginfo") => Operation::Siginfo,
             Some("uid") => Operation::Attr(Attr::Uid),
             Some("gid") => Operation::Attr(Attr::Gid),
             Some("open_via_dup") => Operation::OpenViaDup,
             Some("sigactions") => Operation::Sigactions(Arc::clone(&get_context(pid)?.read().actions)),
             Some("current-sigactions") => Operation::CurrentSigactions,
             Some("mmap-min-addr") => Operation::MmapMinAddr(Arc::clone(get_context(pid)?.read().addr_space().map_err(|_| Error::new(ENOENT))?)),
+            Some("stack-growth") => Operation::StackGrowth(Arc::clone(get_context(pid)?.read().addr_space().map_err(|_| Error::new(ENOENT))?)),
+            Some("swappable") => Operation::Swappable(Arc::clone(get_context(pid)?.read().addr_space().map_err(|_| Error::new(ENOENT))?)),
+            Some("allow-wx") => Operation::AllowWx(Arc::clone(get_context(pid)?.read().addr_space().map_err(|_| Error::new(ENOENT))?)),
+            Some("aslr") => Operation::Aslr(Arc::clone(get_context(pid)?.read().addr_space().map_err(|_| Error::new(ENOENT))?)),
+            Some("mem-limits") => Operation::MemLimits(Arc::clone(get_context(pid)?.read().addr_space().map_err(|_| Error::new(ENOENT))?)),
             Some("sched-affinity") => Operation::SchedAffinity,
+            Some("oom-score-adj") => Operation::OomScoreAdj,
+            Some("usage") => Operation::Usage,
+            Some("memstat") => Operation::MemStat,
             _ => return Err(Error::new(EINVAL))
         };
 
@@ -324,7 +378,7 @@ impl ProcScheme {
                 Operation::Memory { .. } => OperationData::Memory(MemData::default()),
                 Operation::Trace => OperationData::Trace(TraceData::default()),
                 Operation::Static(_) => OperationData::Static(StaticData::new(
-                    target.name.clone().into_owned().into_bytes().into()
+                    target.name.as_bytes().into()
                 )),
                 Operation::AddrSpace { .. } => OperationData::Offset(0),
                 _ => OperationData::Other,
@@ -339,25 +393,7 @@ impl ProcScheme {
                 let current = contexts.current().ok_or(Error::new(ESRCH))?;
                 let current = current.read();
 
-                // Are we the process?
-                if target.id != current.id {
-                    // Do we own the process?
-                    if uid != target.euid && gid != target.egid {
-                        return Err(Error::new(EPERM));
-                    }
-
-                    // Is it a subprocess of us? In the future, a capability could
-                    // bypass this check.
-                    match contexts.ancestors(target.ppid).find(|&(id, _context)| id == current.id) {
-                        Some((id, context)) => {
-                            // Paranoid sanity check, as ptrace security holes
-                            // wouldn't be fun
-                            assert_eq!(id, current.id);
-                            assert_eq!(id, context.read().id);
-                        },
-                        None => return Err(Error::new(EPERM)),
-                    }
-                }
+                ptrace::check_tracer(&contexts, &current, &target, uid, gid)?;
             } else if operation.needs_root() && (uid != 0 || gid != 0) {
                 return Err(Error::new(EPERM));
             }
@@ -373,6 +409,13 @@ impl ProcScheme {
                     data.into_bytes().into_boxed_slice()
                 }));
             }
+            if matches!(operation, Operation::MemStat) {
+                let stats = target.addr_space().map_err(|_| Error::new(ENOENT))?.read().stats();
+                data = OperationData::Static(StaticData::new(format!(
+                    "grants {}\nmapped {}\ncommitted {}\nresident {}\nborrowed {}\n",
+                    stats.grants, stats.mapped, stats.committed, stats.resident, stats.borrowed,
+                ).into_bytes().into_boxed_slice()));
+            }
         };
 
         let id = self.new_handle(Handle {
@@ -395,6 +438,8 @@ impl ProcScheme {
                 let mut target = target.write();
                 target.ptrace_stop = true;
             }
+
+            audit::record(audit::Kind::PtraceAttach, format_args!("target={}", pid.into()));
         }
 
         Ok(id)
@@ -425,6 +470,18 @@ impl ProcScheme {
         })
     }
 
+    #[cfg(target_arch = "riscv64")]
+    fn read_env_regs(&self, info: &Info) -> Result<EnvRegisters> {
+        let tp = if info.pid == context::context_id() {
+            crate::interrupt::trap::user_tp()
+        } else {
+            try_stop_context(info.pid, |context| {
+                Ok(context.arch.tp)
+            })?
+        };
+        Ok(EnvRegisters { tp })
+    }
+
     #[cfg(target_arch = "x86")]
     fn read_env_regs(&self, info: &Info) -> Result<EnvRegisters> {
         let (fsbase, gsbase) = if info.pid == context::context_id() {
@@ -445,27 +502,7 @@ impl ProcScheme {
     #[cfg(target_arch = "x86_64")]
     fn read_env_regs(&self, info: &Info) -> Result<EnvRegisters> {
         let (fsbase, gsbase) = if info.pid == context::context_id() {
-            #[cfg(not(feature = "x86_fsgsbase"))]
-            unsafe {
-                (
-                    x86::msr::rdmsr(x86::msr::IA32_FS_BASE),
-                    x86::msr::rdmsr(x86::msr::IA32_KERNEL_GSBASE),
-                )
-            }
-            #[cfg(feature = "x86_fsgsbase")]
-            unsafe {
-                use x86::bits64::segmentation::*;
-
-                (
-                    rdfsbase(),
-                    {
-                        swapgs();
-                        let gsbase = rdgsbase();
-                        swapgs();
-                        gsbase
-                    }
-                )
-            }
+            unsafe { context::read_fsgsbase() }
         } else {
             try_stop_context(info.pid, |context| {
                 Ok((context.arch.fsbase as u64, context.arch.gsbase as u64))
@@ -493,6 +530,19 @@ impl ProcScheme {
         Ok(())
     }
 
+    #[cfg(target_arch = "riscv64")]
+    fn write_env_regs(&self, info: &Info, regs: EnvRegisters) -> Result<()> {
+        if info.pid == context::context_id() {
+            unsafe { crate::interrupt::trap::set_user_tp(regs.tp) };
+        } else {
+            try_stop_context(info.pid, |context| {
+                context.arch.tp = regs.tp;
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
     #[cfg(target_arch = "x86")]
     fn write_env_regs(&self, info: &Info, regs: EnvRegisters) -> Result<()> {
         if !(RmmA::virt_is_valid(VirtualAddress::new(regs.fsbase as usize)) && RmmA::virt_is_valid(VirtualAddress::new(regs.gsbase as usize))) {
@@ -528,32 +578,9 @@ impl ProcScheme {
         }
 
         if info.pid == context::context_id() {
-            #[cfg(not(feature = "x86_fsgsbase"))]
-            unsafe {
-                x86::msr::wrmsr(x86::msr::IA32_FS_BASE, regs.fsbase as u64);
-                // We have to write to KERNEL_GSBASE, because when the kernel returns to
-                // userspace, it will have executed SWAPGS first.
-                x86::msr::wrmsr(x86::msr::IA32_KERNEL_GSBASE, regs.gsbase as u64);
-
-                match context::contexts().current().ok_or(Error::new(ESRCH))?.write().arch {
-                    ref mut arch => {
-                        arch.fsbase = regs.fsbase as usize;
-                        arch.gsbase = regs.gsbase as usize;
-                    }
-                }
-            }
-            #[cfg(feature = "x86_fsgsbase")]
-            unsafe {
-                use x86::bits64::segmentation::*;
-
-                wrfsbase(regs.fsbase);
-                swapgs();
-                wrgsbase(regs.gsbase);
-                swapgs();
-
-                // No need to update the current context; with fsgsbase enabled, these
-                // registers are automatically saved and restored.
-            }
+            let context_lock = context::current()?;
+            let mut context = context_lock.write();
+            unsafe { context::write_fsgsbase(&mut context.arch, regs.fsbase as usize, regs.gsbase as usize) };
         } else {
             try_stop_context(info.pid, |context| {
                 context.arch.fsbase = regs.fsbase as usize;
@@ -567,18 +594,51 @@ impl ProcScheme {
 
 impl Scheme for ProcScheme {
     fn open(&self, path: &str, flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        if path.trim_matches('/').is_empty() {
+            if self.access == Access::Restricted {
+                return Err(Error::new(EACCES));
+            }
+            if flags & O_DIRECTORY == 0 && flags & O_STAT == 0 {
+                return Err(Error::new(EISDIR));
+            }
+            return self.new_handle(Handle {
+                info: Info {
+                    flags,
+                    pid: context::context_id(),
+                    operation: Operation::Dir,
+                },
+                data: OperationData::Dir(DirCursor::new()),
+            });
+        }
+
         let mut parts = path.splitn(2, '/');
         let pid_str = parts.next()
             .ok_or(Error::new(ENOENT))?;
 
+        // New contexts can be given a kernel stack size, as in `new?kstack=32768`
+        let (pid_str, kstack_size) = match pid_str.split_once('?') {
+            Some((pid_str, options)) if pid_str.starts_with("new") => {
+                let size = options.strip_prefix("kstack=").ok_or(Error::new(EINVAL))?;
+                let size = size.parse::<usize>().map_err(|_| Error::new(EINVAL))?;
+                if size % PAGE_SIZE != 0 || !(context::KSTACK_SIZE_MIN..=context::KSTACK_SIZE_MAX).contains(&size) {
+                    return Err(Error::new(EINVAL));
+                }
+                (pid_str, size)
+            }
+            _ => (pid_str, context::KSTACK_SIZE_DEFAULT),
+        };
+
         let pid = if pid_str == "current" {
             context::context_id()
         } else if pid_str == "new" {
-            inherit_context()?
+            inherit_context(false, kstack_size)?
+        } else if pid_str == "new-thread" {
+            inherit_context(true, kstack_size)?
         } else if self.access == Access::Restricted {
             return Err(Error::new(EACCES));
         } else {
-            ContextId::from(pid_str.parse().map_err(|_| Error::new(ENOENT))?)
+            let pid = ContextId::from(pid_str.parse().map_err(|_| Error::new(ENOENT))?);
+            context::pid_ns::to_global(context::pid_ns::current()?.as_deref(), pid).ok_or(Error::new(ENOENT))?
         };
 
         self.open_inner(pid, parts.next(), flags, uid, gid)
@@ -588,6 +648,9 @@ impl Scheme for ProcScheme {
     fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<isize> {
         let mut handles = self.handles.write();
         let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        if let Some(cursor) = handle.data.dir_data() {
+            return cursor.seek(pos, whence);
+        }
         let mut memory = handle.data.mem_data().ok_or(Error::new(EBADF))?;
 
         let value = calc_seek_offset_usize(memory.offset.data(), pos, whence, isize::max_value() as usize)?;
@@ -635,6 +698,12 @@ impl Scheme for ProcScheme {
                             saved_regs.iret.sp_el0 = new_sp;
                         }
 
+                        #[cfg(target_arch = "riscv64")]
+                        {
+                            saved_regs.iret.sepc = new_ip;
+                            saved_regs.preserved.sp = new_sp;
+                        }
+
                         #[cfg(target_arch = "x86")]
                         {
                             saved_regs.iret.eip = new_ip;
@@ -658,9 +727,12 @@ impl Scheme for ProcScheme {
 
                     Ok(())
                 })?;
+                if handle.info.pid == context::context_id() {
+                    let _ = ptrace::send_event(crate::syscall::ptrace_event!(PTRACE_EVENT_EXEC, 0));
+                }
                 let _ = ptrace::send_event(crate::syscall::ptrace_event!(PTRACE_EVENT_ADDRSPACE_SWITCH, 0));
             }
-            Operation::AddrSpace { addrspace } | Operation::Memory { addrspace } | Operation::MmapMinAddr(addrspace) => maybe_cleanup_addr_space(addrspace),
+            Operation::AddrSpace { addrspace } | Operation::Memory { addrspace } | Operation::MmapMinAddr(addrspace) | Operation::StackGrowth(addrspace) | Operation::Swappable(addrspace) | Operation::AllowWx(addrspace) | Operation::Aslr(addrspace) | Operation::MemLimits(addrspace) => maybe_cleanup_addr_space(addrspace),
 
             Operation::AwaitingFiletableChange(new) => with_context_mut(handle.info.pid, |context: &mut Context| {
                 context.files = new;
@@ -747,6 +819,9 @@ impl KernelScheme for ProcScheme {
 
                 let requested_dst_page = (map.address != 0).then_some(requested_dst_page);
 
+                // Swapped out pages are read back before the address spaces are locked
+                drop(AddrSpace::write_populated(addrspace, Region::new(src_page.start_address(), page_count * PAGE_SIZE))?);
+
                 let mut src_addr_space = addrspace.write();
                 let src_addr_space = &mut *src_addr_space;
                 let mut dst_addr_space = dst_addr_space.write();
@@ -762,10 +837,17 @@ impl KernelScheme for ProcScheme {
                     if !first.can_have_flags(map.flags) {
                         return Err(Error::new(EACCES));
                     }
+                    // Huge pages can only be borrowed, as they would otherwise have to be split
+                    if consume && first.huge {
+                        return Err(Error::new(EINVAL));
+                    }
 
                     first.region().intersect(src_region)
                 };
 
+                // Lazily allocated pages must be backed before they can be shared or moved
+                src_addr_space.populate(src_grant_region)?;
+
                 let grant_page_count = src_grant_region.size() / PAGE_SIZE;
 
                 let src_mapper = &mut src_addr_space.table.utable;
@@ -779,7 +861,15 @@ impl KernelScheme for ProcScheme {
 
                     dst_addr_space.mmap(requested_dst_page, grant_page_count, map.flags, |dst_page, _flags, dst_mapper, dst_flusher| Grant::transfer(middle, dst_page, src_mapper, dst_mapper, InactiveFlusher::new(), dst_flusher))?
                 } else {
-                    dst_addr_space.mmap(requested_dst_page, grant_page_count, map.flags, |dst_page, flags, dst_mapper, flusher| Ok(Grant::borrow(Page::containing_address(src_grant_region.start_address()), dst_page, grant_page_count, flags, None, src_mapper, dst_mapper, flusher)?))?
+                    let page = dst_addr_space.mmap(requested_dst_page, grant_page_count, map.flags, |dst_page, flags, dst_mapper, flusher| {
+                        let mut grant = Grant::borrow(Page::containing_address(src_grant_region.start_address()), dst_page, grant_page_count, flags, None, src_mapper, dst_mapper, flusher)?;
+                        grant.lender = Some((Arc::downgrade(addrspace), src_grant_region));
+                        Ok(grant)
+                    })?;
+                    // Borrowed frames are mapped twice, and must not be swapped out until the
+                    // borrowing grant is unmapped
+                    src_addr_space.lend(src_grant_region);
+                    page
                 };
 
                 Ok(result_page.start_address().data())
@@ -806,27 +896,42 @@ impl KernelScheme for ProcScheme {
                 data.offset += len;
                 Ok(len)
             },
-            Operation::Memory { addrspace } => {
-                // Won't context switch, don't worry about the locks
+            Operation::Dir => {
+                // Don't take the context list lock with the handles locked
+                let entries = context_ids();
+
                 let mut handles = self.handles.write();
                 let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
-                let data = handle.data.mem_data().expect("operations can't change");
+                let cursor = handle.data.dir_data().expect("operations can't change");
+                cursor.read(entries, buf)
+            },
+            Operation::Memory { addrspace } => {
+                let offset = self.handles.read().get(&id).ok_or(Error::new(EBADF))?.data.mem_offset().expect("operations can't change");
 
+                // The buffer may be in the same address space, which cannot be locked while copying
+                // to it, so each page goes through a bounce buffer
+                let mut bounce = [0_u8; PAGE_SIZE];
                 let mut bytes_read = 0;
 
-                for chunk_opt in ptrace::context_memory(&mut *addrspace.write(), data.offset, buf.len()) {
-                    let (chunk, _writable) = chunk_opt.ok_or(Error::new(EFAULT))?;
-                    buf.advance(bytes_read).and_then(|buf| buf.limit(chunk.len())).ok_or(Error::new(EINVAL))?.copy_from_slice(unsafe { &*chunk })?;
-                    /*
-                    let dst_slice = &mut buf[bytes_read..bytes_read + chunk.len()];
-                    unsafe {
-                        chunk.as_mut_ptr().copy_to_nonoverlapping(dst_slice.as_mut_ptr(), dst_slice.len());
+                while bytes_read < buf.len() {
+                    let address = offset.add(bytes_read);
+                    let len = core::cmp::min(PAGE_SIZE - address.data() % PAGE_SIZE, buf.len() - bytes_read);
+                    {
+                        let mut addrspace = addrspace.write();
+                        let Some(chunk_opt) = ptrace::context_memory(&mut addrspace, address, len).next() else {
+                            break;
+                        };
+                        let (chunk, _writable) = chunk_opt.ok_or(Error::new(EFAULT))?;
+                        let chunk = unsafe { &*chunk };
+                        bounce[..chunk.len()].copy_from_slice(chunk);
                     }
-                    */
-                    bytes_read += chunk.len();
+                    buf.advance(bytes_read).and_then(|buf| buf.limit(len)).ok_or(Error::new(EINVAL))?.copy_from_slice(&bounce[..len])?;
+                    bytes_read += len;
                 }
 
-                data.offset = VirtualAddress::new(data.offset.data() + bytes_read);
+                let mut handles = self.handles.write();
+                let data = handles.get_mut(&id).ok_or(Error::new(EBADF))?.data.mem_data().expect("operations can't change");
+                data.offset = offset.add(bytes_read);
                 Ok(bytes_read)
             },
             // TODO: Support reading only a specific address range. Maybe using seek?
@@ -855,6 +960,11 @@ impl KernelScheme for ProcScheme {
                 Ok(bytes_read)
             }
 
+            Operation::Regs(RegsKind::Xstate) => {
+                // Only up to date while the context is not running
+                let xstate = try_stop_context(info.pid, |context| Ok(context.get_xstate()))?;
+                buf.copy_common_bytes_from_slice(&xstate)
+            }
             Operation::Regs(kind) => {
                 union Output {
                     float: FloatRegisters,
@@ -886,6 +996,7 @@ impl KernelScheme for ProcScheme {
                             mem::size_of::<EnvRegisters>()
                         )
                     }
+                    RegsKind::Xstate => unreachable!(),
                 };
 
                 let src_buf = unsafe {
@@ -920,7 +1031,7 @@ impl KernelScheme for ProcScheme {
 
                 // Save child processes in a list of processes to restart
                 for event in &slice[..read] {
-                    if event.cause == PTRACE_EVENT_CLONE {
+                    if event.cause == PTRACE_EVENT_CLONE || event.cause == PTRACE_EVENT_FORK {
                         data.clones.push(ContextId::from(event.a));
                     }
                 }
@@ -941,6 +1052,11 @@ impl KernelScheme for ProcScheme {
             }
             Operation::Name => read_from(buf, context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?.read().name.as_bytes(), &mut 0),
             Operation::Sigstack => read_from(buf, &context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?.read().sigstack.unwrap_or(!0).to_ne_bytes(), &mut 0),
+            // The signal most recently delivered, e.g. the fault address of a SIGSEGV
+            Operation::Siginfo => match context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?.read().siginfo {
+                Some(siginfo) => read_from(buf, &siginfo, &mut 0),
+                None => Ok(0),
+            },
             Operation::Attr(attr) => {
                 let src_buf = match (attr, &*Arc::clone(context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?).read()) {
                     (Attr::Uid, context) => context.euid.to_string(),
@@ -949,7 +1065,7 @@ impl KernelScheme for ProcScheme {
 
                 read_from(buf, &src_buf, &mut 0)
             }
-            Operation::Filetable { .. } => {
+            Operation::Filetable { .. } | Operation::MemStat => {
                 let mut handles = self.handles.write();
                 let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
                 let data = handle.data.static_data().expect("operations can't change");
@@ -960,10 +1076,45 @@ impl KernelScheme for ProcScheme {
                 buf.write_usize(addrspace.read().mmap_min)?;
                 Ok(mem::size_of::<usize>())
             }
+            Operation::StackGrowth(ref addrspace) => {
+                buf.write_usize(addrspace.read().stack_growth)?;
+                Ok(mem::size_of::<usize>())
+            }
+            Operation::Swappable(ref addrspace) => {
+                buf.write_usize(addrspace.read().swappable as usize)?;
+                Ok(mem::size_of::<usize>())
+            }
+            Operation::AllowWx(ref addrspace) => {
+                buf.write_usize(addrspace.read().allow_wx as usize)?;
+                Ok(mem::size_of::<usize>())
+            }
+            Operation::Aslr(ref addrspace) => {
+                buf.write_usize(addrspace.read().aslr as usize)?;
+                Ok(mem::size_of::<usize>())
+            }
+            Operation::MemLimits(ref addrspace) => {
+                let (max_mapped, max_committed) = {
+                    let addrspace = addrspace.read();
+                    (addrspace.max_mapped, addrspace.max_committed)
+                };
+                let (mapped_buf, committed_buf) = buf.split_at(mem::size_of::<usize>()).ok_or(Error::new(EINVAL))?;
+                mapped_buf.write_usize(max_mapped.saturating_mul(PAGE_SIZE))?;
+                committed_buf.write_usize(max_committed.saturating_mul(PAGE_SIZE))?;
+                Ok(2 * mem::size_of::<usize>())
+            }
             Operation::SchedAffinity => {
                 buf.write_usize(context::contexts().get(info.pid).ok_or(Error::new(EBADFD))?.read().sched_affinity.map_or(usize::MAX, |a| a % crate::cpu_count()))?;
                 Ok(mem::size_of::<usize>())
             }
+            Operation::OomScoreAdj => {
+                buf.write_usize(context::contexts().get(info.pid).ok_or(Error::new(EBADFD))?.read().oom_score_adj as isize as usize)?;
+                Ok(mem::size_of::<usize>())
+            }
+            Operation::Usage => {
+                let usage = context::contexts().get(info.pid).ok_or(Error::new(EBADFD))?.read().usage();
+                buf.copy_exactly(&usage)?;
+                Ok(mem::size_of::<Rusage>())
+            }
             // TODO: Replace write() with SYS_DUP_FORWARD.
             // TODO: Find a better way to switch address spaces, since they also require switching
             // the instruction and stack pointer. Maybe remove `<pid>/regs` altogether and replace it
@@ -983,25 +1134,33 @@ impl KernelScheme for ProcScheme {
         match info.operation {
             Operation::Static(_) => Err(Error::new(EBADF)),
             Operation::Memory { addrspace } => {
-                // Won't context switch, don't worry about the locks
-                let mut handles = self.handles.write();
-                let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
-                let data = handle.data.mem_data().expect("operations can't change");
+                let offset = self.handles.read().get(&id).ok_or(Error::new(EBADF))?.data.mem_offset().expect("operations can't change");
 
+                // Like reads, through a bounce buffer, as the buffer may be in the same address space
+                let mut bounce = [0_u8; PAGE_SIZE];
                 let mut bytes_written = 0;
 
-                for chunk_opt in ptrace::context_memory(&mut *addrspace.write(), data.offset, buf.len()) {
+                while bytes_written < buf.len() {
+                    let address = offset.add(bytes_written);
+                    let len = core::cmp::min(PAGE_SIZE - address.data() % PAGE_SIZE, buf.len() - bytes_written);
+                    buf.advance(bytes_written).and_then(|buf| buf.limit(len)).ok_or(Error::new(EINVAL))?.copy_to_slice(&mut bounce[..len])?;
+
+                    let mut addrspace = addrspace.write();
+                    let Some(chunk_opt) = ptrace::context_memory(&mut addrspace, address, len).next() else {
+                        break;
+                    };
                     let (chunk, writable) = chunk_opt.ok_or(Error::new(EFAULT))?;
 
                     if !writable { return Err(Error::new(EACCES)); }
 
-                    buf.advance(bytes_written).and_then(|buf| buf.limit(chunk.len())).ok_or(Error::new(EINVAL))?
-                        .copy_to_slice(unsafe { &mut *chunk })?;
-
+                    let chunk = unsafe { &mut *chunk };
+                    chunk.copy_from_slice(&bounce[..chunk.len()]);
                     bytes_written += chunk.len();
                 }
 
-                data.offset = data.offset.add(bytes_written);
+                let mut handles = self.handles.write();
+                let data = handles.get_mut(&id).ok_or(Error::new(EBADF))?.data.mem_data().expect("operations can't change");
+                data.offset = offset.add(bytes_written);
                 Ok(bytes_written)
             },
             Operation::AddrSpace { addrspace } => {
@@ -1076,6 +1235,20 @@ impl KernelScheme for ProcScheme {
                     self.write_env_regs(&info, regs)?;
                     Ok(mem::size_of::<EnvRegisters>())
                 }
+                RegsKind::Xstate => {
+                    // Larger than any XSAVE area
+                    if buf.len() > 64 * 1024 {
+                        return Err(Error::new(EINVAL));
+                    }
+                    let mut xstate = vec![0_u8; buf.len()];
+                    buf.copy_to_slice(&mut xstate)?;
+
+                    try_stop_context(info.pid, |context| if context.set_xstate(&xstate) {
+                        Ok(xstate.len())
+                    } else {
+                        Err(Error::new(EINVAL))
+                    })
+                }
             },
             Operation::Trace => {
                 let op = buf.read_u64()?;
@@ -1120,12 +1293,11 @@ impl KernelScheme for ProcScheme {
                 Ok(mem::size_of::<u64>())
             },
             Operation::Name => {
-                // TODO: What limit?
-                let mut name_buf = [0_u8; 256];
+                let mut name_buf = [0_u8; context::label::LABEL_MAX];
                 let bytes_copied = buf.copy_common_bytes_to_slice(&mut name_buf)?;
 
-                let utf8 = alloc::string::String::from_utf8(name_buf[..bytes_copied].to_vec()).map_err(|_| Error::new(EINVAL))?;
-                context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?.write().name = utf8.into();
+                let utf8 = core::str::from_utf8(&name_buf[..bytes_copied]).map_err(|_| Error::new(EINVAL))?;
+                context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?.write().name = context::Label::parse(utf8)?;
                 Ok(buf.len())
             }
             Operation::Sigstack => {
@@ -1142,8 +1314,14 @@ impl KernelScheme for ProcScheme {
                 let context_lock = Arc::clone(context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?);
 
                 match attr {
-                    Attr::Uid => context_lock.write().euid = id,
-                    Attr::Gid => context_lock.write().egid = id,
+                    Attr::Uid => {
+                        context_lock.write().euid = id;
+                        audit::record(audit::Kind::SetUid, format_args!("target={} euid={}", info.pid.into(), id));
+                    }
+                    Attr::Gid => {
+                        context_lock.write().egid = id;
+                        audit::record(audit::Kind::SetGid, format_args!("target={} egid={}", info.pid.into(), id));
+                    }
                 }
                 Ok(buf.len())
             }
@@ -1185,10 +1363,70 @@ impl KernelScheme for ProcScheme {
                 addrspace.write().mmap_min = val;
                 Ok(mem::size_of::<usize>())
             }
+            Operation::StackGrowth(ref addrspace) => {
+                let val = buf.read_usize()?;
+                if val % PAGE_SIZE != 0 || val > crate::USER_END_OFFSET { return Err(Error::new(EINVAL)); }
+                addrspace.write().stack_growth = val;
+                Ok(mem::size_of::<usize>())
+            }
+            Operation::Swappable(ref addrspace) => {
+                let val = buf.read_usize()?;
+                // Pinning memory is privileged, like mlock
+                if val == 0 && context::current()?.read().euid != 0 { return Err(Error::new(EPERM)); }
+                addrspace.write().swappable = val != 0;
+                Ok(mem::size_of::<usize>())
+            }
+            Operation::AllowWx(ref addrspace) => {
+                let val = buf.read_usize()?;
+                // Opting out of W^X is privileged, while opting back in is not
+                if val != 0 && context::current()?.read().euid != 0 { return Err(Error::new(EPERM)); }
+                addrspace.write().allow_wx = val != 0;
+                Ok(mem::size_of::<usize>())
+            }
+            Operation::Aslr(ref addrspace) => {
+                let val = buf.read_usize()?;
+                // Only affects where later mappings are placed, so mostly useful before exec
+                let mut addrspace = addrspace.write();
+                addrspace.aslr = val != 0;
+                addrspace.randomize();
+                Ok(mem::size_of::<usize>())
+            }
+            Operation::MemLimits(ref addrspace) => {
+                let (mapped_buf, committed_buf) = buf.split_at(mem::size_of::<usize>()).ok_or(Error::new(EINVAL))?;
+                // Limits are written in bytes, where usize::MAX means unlimited
+                let to_pages = |bytes: usize| if bytes == usize::MAX { usize::MAX } else { bytes / PAGE_SIZE };
+                let max_mapped = to_pages(mapped_buf.read_usize()?);
+                let max_committed = to_pages(committed_buf.read_usize()?);
+
+                let mut addrspace = addrspace.write();
+                // Raising a limit is privileged, like setrlimit beyond the hard limit
+                if (max_mapped > addrspace.max_mapped || max_committed > addrspace.max_committed) && context::current()?.read().euid != 0 {
+                    return Err(Error::new(EPERM));
+                }
+                addrspace.max_mapped = max_mapped;
+                addrspace.max_committed = max_committed;
+                Ok(2 * mem::size_of::<usize>())
+            }
             // TODO: Deduplicate code.
             Operation::SchedAffinity => {
                 let val = buf.read_usize()?;
-                context::contexts().get(info.pid).ok_or(Error::new(EBADFD))?.write().sched_affinity = if val == usize::MAX { None } else { Some(val % crate::cpu_count()) };
+                let affinity = if val == usize::MAX { None } else { Some(val % crate::cpu_count()) };
+                // A context pinned to an offline CPU would never run
+                if affinity.map_or(false, crate::hotplug::is_offline) {
+                    return Err(Error::new(EBUSY));
+                }
+                context::contexts().get(info.pid).ok_or(Error::new(EBADFD))?.write().sched_affinity = affinity;
+                Ok(mem::size_of::<usize>())
+            }
+            Operation::OomScoreAdj => {
+                let val = buf.read_usize()? as isize;
+                if val < OOM_SCORE_ADJ_MIN as isize || val > OOM_SCORE_ADJ_MAX as isize { return Err(Error::new(EINVAL)); }
+                let euid = context::current()?.read().euid;
+                let context_lock = Arc::clone(context::contexts().get(info.pid).ok_or(Error::new(EBADFD))?);
+                let mut context = context_lock.write();
+                // Like on Linux, only root may make a context less likely to be killed
+                if (val as i16) < context.oom_score_adj && euid != 0 { return Err(Error::new(EPERM)); }
+                context.oom_score_adj = val as i16;
                 Ok(mem::size_of::<usize>())
             }
 
@@ -1199,15 +1437,22 @@ impl KernelScheme for ProcScheme {
         let handles = self.handles.read();
         let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
 
-        let path = format!("proc:{}/{}", handle.info.pid.into(), match handle.info.operation {
+        if let Operation::Dir = handle.info.operation {
+            return buf.copy_common_bytes_from_slice(b"proc:");
+        }
+
+        let pid = context::pid_ns::to_local_or_zero(context::pid_ns::current()?.as_deref(), handle.info.pid);
+        let path = format!("proc:{}/{}", pid.into(), match handle.info.operation {
             Operation::Memory { .. } => "mem",
             Operation::Regs(RegsKind::Float) => "regs/float",
             Operation::Regs(RegsKind::Int) => "regs/int",
             Operation::Regs(RegsKind::Env) => "regs/env",
+            Operation::Regs(RegsKind::Xstate) => "regs/xstate",
             Operation::Trace => "trace",
             Operation::Static(path) => path,
             Operation::Name => "name",
             Operation::Sigstack => "sigstack",
+            Operation::Siginfo => "siginfo",
             Operation::Attr(Attr::Uid) => "uid",
             Operation::Attr(Attr::Gid) => "gid",
             Operation::Filetable { .. } => "filetable",
@@ -1218,7 +1463,15 @@ impl KernelScheme for ProcScheme {
             Operation::CurrentSigactions => "current-sigactions",
             Operation::OpenViaDup => "open-via-dup",
             Operation::MmapMinAddr(_) => "mmap-min-addr",
+            Operation::StackGrowth(_) => "stack-growth",
+            Operation::Swappable(_) => "swappable",
+            Operation::AllowWx(_) => "allow-wx",
+            Operation::Aslr(_) => "aslr",
+            Operation::MemLimits(_) => "mem-limits",
             Operation::SchedAffinity => "sched-affinity",
+            Operation::OomScoreAdj => "oom-score-adj",
+            Operation::Usage => "usage",
+            Operation::MemStat => "memstat",
 
             _ => return Err(Error::new(EOPNOTSUPP)),
         });
@@ -1229,7 +1482,18 @@ impl KernelScheme for ProcScheme {
         let handles = self.handles.read();
         let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
 
-        buffer.copy_exactly(&Stat {
+        if let Operation::Dir = handle.info.operation {
+            // Don't take the context list lock with the handles locked
+            drop(handles);
+            buffer.copy_exactly(&Stat {
+                st_mode: MODE_DIR | 0o555,
+                st_size: dir::listing_size(&context_ids()),
+                ..Stat::default()
+            })?;
+            return Ok(0);
+        }
+
+        let mut stat = Stat {
             st_mode: MODE_FILE | 0o666,
             st_size: match handle.data {
                 OperationData::Static(ref data) => (data.buf.len() - data.offset) as u64,
@@ -1237,11 +1501,28 @@ impl KernelScheme for ProcScheme {
             },
 
             ..Stat::default()
-        })?;
+        };
+
+        // Memory objects report their mapped size, resident size (in 512-byte blocks), and the
+        // number of references keeping them alive.
+        match handle.info.operation {
+            Operation::AddrSpace { ref addrspace } | Operation::Memory { ref addrspace } | Operation::MmapMinAddr(ref addrspace) | Operation::StackGrowth(ref addrspace) | Operation::Swappable(ref addrspace) | Operation::AllowWx(ref addrspace) | Operation::Aslr(ref addrspace) | Operation::MemLimits(ref addrspace) => {
+                let stats = addrspace.read().stats();
+                stat.st_size = (stats.mapped * PAGE_SIZE) as u64;
+                stat.st_blksize = PAGE_SIZE as u32;
+                stat.st_blocks = (stats.resident * PAGE_SIZE / 512) as u64;
+                stat.st_nlink = Arc::strong_count(addrspace) as u32;
+            }
+            Operation::GrantHandle { ref description } => {
+                stat.st_nlink = Arc::strong_count(description) as u32;
+            }
+            _ => (),
+        }
+
+        buffer.copy_exactly(&stat)?;
 
         Ok(0)
     }
-
     /// Dup is currently used to implement clone() and execve().
     fn kdup(&self, old_id: usize, raw_buf: UserSliceRo, _: CallerCtx) -> Result<OpenResult> {
         let info = {
@@ -1288,10 +1569,29 @@ impl KernelScheme for ProcScheme {
                 let (operation, is_mem) = match buf {
                     // TODO: Better way to obtain new empty address spaces, perhaps using SYS_OPEN. But
                     // in that case, what scheme?
-                    b"empty" => (Operation::AddrSpace { addrspace: new_addrspace()? }, false),
+                    b"empty" => {
+                        // Limits and disabling ASLR are kept across exec, while the W^X opt-out is
+                        // not
+                        let mut new = new_addrspace()?;
+                        {
+                            let (old, new) = (addrspace.read(), Arc::get_mut(&mut new).expect("expected new address space Arc not to be aliased").get_mut());
+                            new.max_mapped = old.max_mapped;
+                            new.max_committed = old.max_committed;
+                            if !old.aslr {
+                                new.aslr = false;
+                                new.randomize();
+                            }
+                        }
+                        (Operation::AddrSpace { addrspace: new }, false)
+                    }
                     b"exclusive" => (Operation::AddrSpace { addrspace: addrspace.write().try_clone()? }, false),
                     b"mem" => (Operation::Memory { addrspace: Arc::clone(addrspace) }, true),
                     b"mmap-min-addr" => (Operation::MmapMinAddr(Arc::clone(addrspace)), false),
+                    b"stack-growth" => (Operation::StackGrowth(Arc::clone(addrspace)), false),
+                    b"swappable" => (Operation::Swappable(Arc::clone(addrspace)), false),
+                    b"allow-wx" => (Operation::AllowWx(Arc::clone(addrspace)), false),
+                    b"aslr" => (Operation::Aslr(Arc::clone(addrspace)), false),
+                    b"mem-limits" => (Operation::MemLimits(Arc::clone(addrspace)), false),
 
                     grant_handle if grant_handle.starts_with(b"grant-") => {
                         let start_addr = usize::from_str_radix(core::str::from_utf8(&grant_handle[6..]).map_err(|_| Error::new(EINVAL))?, 16).map_err(|_| Error::new(EINVAL))?;
@@ -1334,10 +1634,15 @@ extern "C" fn clone_handler() {
     }
 }
 
-fn inherit_context() -> Result<ContextId> {
-    let new_id = {
+/// Create a new, stopped context inheriting the credentials of the current one. If `thread` is
+/// set, the new context joins the thread group of the current one, sharing its signal actions and
+/// group-directed pending signals, and stays in its PID namespace; otherwise it is placed in the
+/// PID namespace the current context creates processes in. The new context gets a kernel stack of
+/// `kstack_size` bytes.
+fn inherit_context(thread: bool, kstack_size: usize) -> Result<ContextId> {
+    let (new_id, registered) = {
         let current_context_lock = Arc::clone(context::contexts().current().ok_or(Error::new(ESRCH))?);
-        let new_context_lock = Arc::clone(context::contexts_mut().spawn(clone_handler)?);
+        let new_context_lock = Arc::clone(context::contexts_mut()?.spawn_with_kstack(clone_handler, kstack_size)?);
 
         let current_context = current_context_lock.read();
         let mut new_context = new_context_lock.write();
@@ -1355,14 +1660,38 @@ fn inherit_context() -> Result<ContextId> {
         new_context.ppid = current_context.id;
         new_context.pgid = current_context.pgid;
         new_context.umask = current_context.umask;
+        new_context.oom_score_adj = current_context.oom_score_adj;
+        new_context.name = current_context.name.clone();
 
         // TODO: Force userspace to copy sigmask. Start with "all signals blocked".
         new_context.sigmask = current_context.sigmask;
 
-        new_context.id
+        if thread {
+            new_context.thread_group = Arc::clone(&current_context.thread_group);
+            new_context.actions = Arc::clone(&current_context.actions);
+        }
+
+        // Threads stay in the PID namespace of their process
+        new_context.pid_ns = if thread {
+            current_context.pid_ns.clone()
+        } else {
+            current_context.pid_ns_for_children.clone()
+        };
+        new_context.pid_ns_for_children = current_context.pid_ns_for_children.clone();
+
+        (new_context.id, context::pid_ns::register(new_context.pid_ns.as_deref(), new_context.id))
     };
 
-    if ptrace::send_event(crate::syscall::ptrace_event!(PTRACE_EVENT_CLONE, new_id.into())).is_some() {
+    if let Err(err) = registered {
+        // The init of the PID namespace has exited, the new context never ran
+        context::contexts_mut()?.remove(new_id);
+        return Err(err);
+    }
+
+    // Processes are reported as forks to tracers that ask for it
+    let sent = (!thread && ptrace::send_event(crate::syscall::ptrace_event!(PTRACE_EVENT_FORK, new_id.into())).is_some())
+        || ptrace::send_event(crate::syscall::ptrace_event!(PTRACE_EVENT_CLONE, new_id.into())).is_some();
+    if sent {
         // Freeze the clone, allow ptrace to put breakpoints
         // to it before it starts
         let contexts = context::contexts();
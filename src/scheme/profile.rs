@@ -0,0 +1,212 @@
+//! # Profiler
+//! `profile:ctl` reads as `enabled` or `disabled`, followed by the number of samples lost as
+//! `lost <N>`, and root starts and stops the profiler (see `profile`) by writing `enable` or
+//! `disable` to it.
+//!
+//! `profile:samples` reads the `ProfileSample`s recorded on all CPUs, and `profile:cpu<N>` those
+//! recorded on CPU `N`. Reads return whole samples, which are consumed, or nothing if there are
+//! none, and `EVENT_READ` is raised when there are samples to read. Only root can open the
+//! profiler, as samples hold kernel addresses.
+//!
+//! `profile:folded` consumes the samples recorded on all CPUs when opened, and reads their call
+//! stacks as lines of `<frame>;<frame>... <count>` from the outermost frame, the input of flame
+//! graph tools. Frames in the kernel are named after their function (see `symbols`), and others
+//! given as addresses.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::mem;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::event;
+use crate::profile;
+use crate::symbols;
+use crate::sync::RwLock;
+use crate::syscall::abi::ProfileSample;
+use crate::syscall::data::Stat;
+use crate::syscall::error::*;
+use crate::syscall::flag::{EventFlags, EVENT_READ, MODE_FILE, O_ACCMODE, O_RDONLY};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::{AtomicSchemeId, KernelScheme, SchemeId};
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum File {
+    Ctl,
+    Samples,
+    Folded,
+    Cpu(usize),
+}
+
+struct Handle {
+    file: File,
+    /// The folded call stacks, consumed when opened
+    data: Vec<u8>,
+    offset: usize,
+}
+
+static SCHEME_ID: AtomicSchemeId = AtomicSchemeId::default();
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn control(command: &str) -> Result<()> {
+    match command {
+        "enable" => profile::set_enabled(true),
+        "disable" => profile::set_enabled(false),
+        _ => return Err(Error::new(EINVAL)),
+    }
+    Ok(())
+}
+
+/// Consume the samples recorded on all CPUs, as their folded call stacks
+fn folded() -> Vec<u8> {
+    let mut names = BTreeMap::<u64, String>::new();
+    let mut stacks = BTreeMap::<String, u64>::new();
+    for sample in profile::take(None, usize::MAX).iter() {
+        let mut stack = String::new();
+        for &address in sample.stack[..sample.depth as usize].iter().rev() {
+            let name = names.entry(address).or_insert_with(|| match symbols::lookup(address as usize) {
+                Some(location) => location.symbol.to_string(),
+                None => format!("{:#x}", address),
+            });
+            if !stack.is_empty() {
+                stack.push(';');
+            }
+            stack.push_str(name);
+        }
+        *stacks.entry(stack).or_insert(0) += 1;
+    }
+
+    let mut string = String::new();
+    for (stack, count) in stacks.iter() {
+        let _ = writeln!(string, "{} {}", stack, count);
+    }
+    string.into_bytes()
+}
+
+/// Wake the readers of samples. Called on the timer tick of the BSP while samples are waiting,
+/// which may have interrupted a holder of the handles.
+pub fn wake() {
+    let Some(handles) = HANDLES.try_read() else {
+        return;
+    };
+    let scheme_id = SCHEME_ID.load(Ordering::SeqCst);
+    for (&id, _) in handles.iter().filter(|(_, handle)| matches!(handle.file, File::Samples | File::Cpu(_))) {
+        event::trigger(scheme_id, id, EVENT_READ);
+    }
+}
+
+pub struct ProfileScheme;
+
+impl ProfileScheme {
+    pub fn new(scheme_id: SchemeId) -> Self {
+        SCHEME_ID.store(scheme_id, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Scheme for ProfileScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let file = match path.trim_matches('/') {
+            "ctl" => File::Ctl,
+            "samples" => File::Samples,
+            "folded" => File::Folded,
+            name => {
+                let cpu = name.strip_prefix("cpu").and_then(|cpu| cpu.parse::<usize>().ok()).ok_or(Error::new(ENOENT))?;
+                if cpu >= crate::cpu_count() {
+                    return Err(Error::new(ENOENT));
+                }
+                File::Cpu(cpu)
+            }
+        };
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        if flags & O_ACCMODE != O_RDONLY && file != File::Ctl {
+            return Err(Error::new(EROFS));
+        }
+
+        let data = if file == File::Folded { folded() } else { Vec::new() };
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { file, data, offset: 0 });
+        Ok(id)
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(EventFlags::empty())
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+impl KernelScheme for ProfileScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let file = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file;
+        let cpu = match file {
+            File::Ctl => {
+                let status = format!("{}\nlost {}\n", if profile::enabled() { "enabled" } else { "disabled" }, profile::lost());
+                return buf.copy_common_bytes_from_slice(status.as_bytes());
+            }
+            File::Folded => {
+                let mut handles = HANDLES.write();
+                let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+                let bytes_read = buf.copy_common_bytes_from_slice(handle.data.get(handle.offset..).unwrap_or(&[]))?;
+                handle.offset += bytes_read;
+                return Ok(bytes_read);
+            }
+            File::Samples => None,
+            File::Cpu(cpu) => Some(cpu),
+        };
+
+        let samples = profile::take(cpu, buf.len() / mem::size_of::<ProfileSample>());
+        let mut bytes = Vec::with_capacity(samples.len() * mem::size_of::<ProfileSample>());
+        for sample in samples.iter() {
+            bytes.extend_from_slice(sample);
+        }
+        buf.copy_common_bytes_from_slice(&bytes)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        if HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file != File::Ctl {
+            return Err(Error::new(EBADF));
+        }
+        let mut bytes = [0_u8; 16];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        control(str::from_utf8(&bytes[..len]).or(Err(Error::new(EINVAL)))?.trim())?;
+        Ok(len)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Ctl => String::from("profile:ctl"),
+            File::Samples => String::from("profile:samples"),
+            File::Folded => String::from("profile:folded"),
+            File::Cpu(cpu) => format!("profile:cpu{}", cpu),
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let handles = HANDLES.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FILE | if handle.file == File::Ctl { 0o600 } else { 0o400 },
+            st_size: handle.data.len() as u64,
+            ..Default::default()
+        })?;
+        Ok(0)
+    }
+}
@@ -1,44 +1,46 @@
 use alloc::{
     boxed::Box,
     collections::BTreeMap,
-    string::ToString,
+    string::{String, ToString},
     sync::Arc,
     vec::Vec,
 };
 use core::str;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::{Mutex, RwLock};
 
 use crate::context;
+use crate::sync::{Mutex, RwLock};
 use crate::syscall::data::Stat;
 use crate::syscall::error::*;
 use crate::syscall::flag::{EventFlags, O_CREAT, MODE_FILE, MODE_DIR};
-use crate::syscall::scheme::{calc_seek_offset_usize, Scheme};
+use crate::syscall::scheme::Scheme;
 use crate::scheme::{self, SchemeNamespace, SchemeId};
+use crate::scheme::dir::{self, DirCursor};
 use crate::scheme::user::{UserInner, UserScheme};
 use crate::syscall::usercopy::{UserSliceWo, UserSliceRo};
 
+/// Time given to a scheme provider being revoked to answer its outstanding requests
+const DEFAULT_REVOKE_TIMEOUT_MS: u64 = 1000;
+
 struct FolderInner {
-    data: Box<[u8]>,
-    pos: Mutex<usize>
+    scheme_ns: SchemeNamespace,
+    cursor: Mutex<DirCursor>
 }
 
 impl FolderInner {
-    fn read(&self, buf: UserSliceWo) -> Result<usize> {
-        let mut pos_guard = self.pos.lock();
-
-        let avail_buf = self.data.get(*pos_guard..).unwrap_or(&[]);
-        let bytes_read = buf.copy_common_bytes_from_slice(avail_buf)?;
-        *pos_guard += bytes_read;
+    /// The schemes currently in the namespace
+    fn entries(&self) -> Vec<String> {
+        let schemes = scheme::schemes();
+        schemes.iter_name(self.scheme_ns).map(|(name, _scheme_id)| name.to_string()).collect()
+    }
 
-        Ok(bytes_read)
+    fn read(&self, buf: UserSliceWo) -> Result<usize> {
+        let entries = self.entries();
+        self.cursor.lock().read(entries, buf)
     }
 
     fn seek(&self, pos: isize, whence: usize) -> Result<isize> {
-        let mut seek = self.pos.lock();
-        let new_offset = calc_seek_offset_usize(*seek, pos, whence, self.data.len())?;
-        *seek = new_offset as usize;
-        Ok(new_offset)
+        self.cursor.lock().seek(pos, whence)
     }
 }
 
@@ -67,6 +69,38 @@ impl RootScheme {
     }
 }
 
+impl RootScheme {
+    fn find_scheme(&self, name: &str) -> Result<Arc<UserInner>> {
+        let handles = self.handles.read();
+        handles.iter().find_map(|(_id, handle)| {
+            match handle {
+                Handle::Scheme(inner) => {
+                    if name == inner.name.as_ref() {
+                        return Some(inner.clone());
+                    }
+                },
+                _ => (),
+            }
+            None
+        }).ok_or(Error::new(ENOENT))
+    }
+
+    /// Forcibly revoke the scheme `name`, after giving its provider `timeout` nanoseconds to
+    /// answer outstanding requests, and remove it from the namespace so that it can be registered
+    /// again by a restarted provider
+    fn revoke(&self, name: &str, timeout: u128) -> Result<()> {
+        let inner = self.find_scheme(name)?;
+        inner.revoke(timeout)?;
+
+        let scheme_id = inner.scheme_id.load(Ordering::SeqCst);
+        let mut schemes = scheme::schemes_mut();
+        if schemes.get(scheme_id).map_or(false, |scheme| scheme.as_user_inner().map_or(false, |other| Arc::ptr_eq(&other, &inner))) {
+            schemes.remove(scheme_id);
+        }
+        Ok(())
+    }
+}
+
 impl Scheme for RootScheme {
     fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
         let path = path.trim_matches('/');
@@ -107,18 +141,9 @@ impl Scheme for RootScheme {
                 context.ens
             };
 
-            let mut data = Vec::new();
-            {
-                let schemes = scheme::schemes();
-                for (name, _scheme_id) in schemes.iter_name(scheme_ns) {
-                    data.extend_from_slice(name.as_bytes());
-                    data.push(b'\n');
-                }
-            }
-
             let inner = Arc::new(FolderInner {
-                data: data.into_boxed_slice(),
-                pos: Mutex::new(0)
+                scheme_ns,
+                cursor: Mutex::new(DirCursor::new())
             });
 
             let id = self.next_id.fetch_add(1, Ordering::SeqCst);
@@ -139,22 +164,7 @@ impl Scheme for RootScheme {
         let path = path.trim_matches('/');
 
         if uid == 0 {
-            let inner = {
-                let handles = self.handles.read();
-                handles.iter().find_map(|(_id, handle)| {
-                    match handle {
-                        Handle::Scheme(inner) => {
-                            if path == inner.name.as_ref() {
-                                return Some(inner.clone());
-                            }
-                        },
-                        _ => (),
-                    }
-                    None
-                }).ok_or(Error::new(ENOENT))?
-            };
-
-            inner.unmount()
+            self.find_scheme(path)?.unmount()
         } else {
             Err(Error::new(EACCES))
         }
@@ -262,7 +272,8 @@ impl Scheme for RootScheme {
     fn close(&self, file: usize) -> Result<usize> {
         let handle = self.handles.write().remove(&file).ok_or(Error::new(EBADF))?;
         match handle {
-            Handle::Scheme(inner) => {
+            // A revoked scheme has already been removed, and its ID may have been reused since
+            Handle::Scheme(inner) if !inner.is_revoked() => {
                 let scheme_id = inner.scheme_id.load(Ordering::SeqCst);
                 let mut schemes = scheme::schemes_mut();
                 schemes.remove(scheme_id);
@@ -304,8 +315,28 @@ impl crate::scheme::KernelScheme for RootScheme {
             Handle::Scheme(inner) => {
                 inner.write(buf)
             },
-            Handle::File(_) => {
-                Err(Error::new(EBADF))
+            // Writing `revoke [timeout in ms]` to `:name` forcibly revokes that scheme
+            Handle::File(name) => {
+                if context::current()?.read().euid != 0 {
+                    return Err(Error::new(EACCES));
+                }
+
+                let mut command_buf = [0_u8; 32];
+                let bytes_copied = buf.copy_common_bytes_to_slice(&mut command_buf)?;
+                let command = str::from_utf8(&command_buf[..bytes_copied]).map_err(|_| Error::new(EINVAL))?;
+
+                let mut parts = command.split_whitespace();
+                if parts.next() != Some("revoke") {
+                    return Err(Error::new(EINVAL));
+                }
+                let timeout_ms = match parts.next() {
+                    Some(timeout) => timeout.parse::<u64>().map_err(|_| Error::new(EINVAL))?,
+                    None => DEFAULT_REVOKE_TIMEOUT_MS,
+                };
+
+                let name = str::from_utf8(&name).map_err(|_| Error::new(EINVAL))?;
+                self.revoke(name, u128::from(timeout_ms) * 1_000_000)?;
+                Ok(bytes_copied)
             },
             Handle::Folder(_) => {
                 Err(Error::new(EBADF))
@@ -339,7 +370,7 @@ impl crate::scheme::KernelScheme for RootScheme {
                 st_mode: MODE_DIR,
                 st_uid: 0,
                 st_gid: 0,
-                st_size: inner.data.len() as u64,
+                st_size: dir::listing_size(&inner.entries()),
                 ..Default::default()
             }
         })?;
@@ -0,0 +1,108 @@
+//! # Scheduler tunables
+//! `sched:boost` holds the `SchedBoost` policy for contexts woken by input (see
+//! `context::boost`), which root can change by writing a new one.
+//!
+//! `sched:latency` holds the `SchedLatency` of the context that opened it, which joins the
+//! low-latency class (see `context::latency`) by writing a maximum latency, and leaves it by
+//! writing zero. Reading it returns the wakeup latencies measured since.
+
+use alloc::collections::BTreeMap;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::context::{self, boost, ContextId};
+use crate::context::latency::Latency;
+use crate::sync::RwLock;
+use crate::syscall::abi::{SchedBoost, SchedLatency};
+use crate::syscall::error::*;
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::KernelScheme;
+
+#[derive(Clone, Copy)]
+enum Tunable {
+    Boost,
+    /// The latency class of a context
+    Latency(ContextId),
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Tunable>> = RwLock::new(BTreeMap::new());
+
+pub struct SchedScheme;
+
+impl Scheme for SchedScheme {
+    fn open(&self, path: &str, _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        let tunable = match path.trim_matches('/') {
+            "boost" => Tunable::Boost,
+            "latency" => Tunable::Latency(context::context_id()),
+            _ => return Err(Error::new(ENOENT)),
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, tunable);
+        Ok(id)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+}
+impl KernelScheme for SchedScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        match *HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            Tunable::Boost => {
+                buf.copy_exactly(&boost::policy())?;
+                Ok(mem::size_of::<SchedBoost>())
+            }
+            Tunable::Latency(context_id) => {
+                let stats = {
+                    let contexts = context::contexts();
+                    let context = contexts.get(context_id).ok_or(Error::new(ESRCH))?.read();
+                    context.latency.as_ref().map(Latency::stats).unwrap_or_default()
+                };
+                buf.copy_exactly(&stats)?;
+                Ok(mem::size_of::<SchedLatency>())
+            }
+        }
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        match *HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            Tunable::Boost => {
+                let policy = unsafe { buf.read_exact::<SchedBoost>()? };
+                boost::set_policy(policy);
+                Ok(mem::size_of::<SchedBoost>())
+            }
+            Tunable::Latency(context_id) => {
+                let request = unsafe { buf.read_exact::<SchedLatency>()? };
+                let contexts = context::contexts();
+                let mut context = contexts.get(context_id).ok_or(Error::new(ESRCH))?.write();
+                context.latency = match request.max_latency {
+                    0 => None,
+                    max_latency => Some(Latency::new(max_latency as u128 * 1000)),
+                };
+                Ok(mem::size_of::<SchedLatency>())
+            }
+        }
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path: &[u8] = match *HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            Tunable::Boost => b"sched:boost",
+            Tunable::Latency(_) => b"sched:latency",
+        };
+        buf.copy_common_bytes_from_slice(path)
+    }
+}
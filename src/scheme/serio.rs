@@ -2,11 +2,12 @@
 //! to how status is utilized
 use core::str;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use spin::Once;
 
 use crate::event;
 use crate::scheme::*;
-use crate::sync::WaitQueue;
+use crate::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard, WaitQueue};
+use crate::syscall::abi::BOOST_SERIO;
 use crate::syscall::flag::{EventFlags, EVENT_READ, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK};
 use crate::syscall::scheme::Scheme;
 use crate::syscall::usercopy::UserSliceWo;
@@ -45,9 +46,9 @@ fn handles_mut() -> RwLockWriteGuard<'static, BTreeMap<usize, Handle>> {
 
 /// Add to the input queue
 pub fn serio_input(index: usize, data: u8) {
-    INPUT[index].call_once(init_input).send(data);
+    INPUT[index].call_once(init_input).send_boosted(data, BOOST_SERIO);
     for (id, _handle) in handles().iter() {
-        event::trigger(SCHEME_ID.load(Ordering::SeqCst), *id, EVENT_READ);
+        event::trigger_boosted(SCHEME_ID.load(Ordering::SeqCst), *id, EVENT_READ, BOOST_SERIO);
     }
 }
 
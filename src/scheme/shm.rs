@@ -0,0 +1,340 @@
+//! # Shared memory
+//! Memory objects that can be mapped by multiple processes, like POSIX shared memory. Opening
+//! `shm:` creates an anonymous object, and `shm:<name>` opens a named one, creating it if
+//! `O_CREAT` is given. Objects are sized with `ftruncate` and mapped with `fmap`. The frames of an
+//! object are freed when its name has been unlinked, and no file descriptors or mappings of it
+//! remain.
+//!
+//! Like memfd on Linux, anonymous objects can be sealed with `fcntl(F_ADD_SEALS)`, so that a
+//! process receiving one can map it without the sender resizing or writing it afterwards. Named
+//! objects are created with `F_SEAL_SEAL`, as anyone with access could otherwise seal them.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use syscall::CallerCtx;
+
+use crate::context::{self, file::{FileDescription, FileDescriptor}};
+use crate::context::memory::{AddrSpace, Grant, GrantFileRef};
+use crate::memory::{allocate_frames, deallocate_frames, Frame, PAGE_SIZE};
+use crate::paging::{RmmA, RmmArch};
+use crate::sync::{Mutex, RwLock};
+use crate::syscall::data::{Map, Stat};
+use crate::syscall::error::*;
+use crate::syscall::abi::{F_ADD_SEALS, F_GET_SEALS, F_SEAL_GROW, F_SEAL_SEAL, F_SEAL_SHRINK, F_SEAL_WRITE};
+use crate::syscall::flag::{MapFlags, F_GETFL, F_SETFL, MODE_FILE, O_CREAT, O_EXCL, O_RDWR, O_TRUNC};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{validate_region, UserSliceRo, UserSliceWo};
+
+use super::{AtomicSchemeId, KernelScheme, OpenResult, SchemeId};
+
+static SCHEME_ID: AtomicSchemeId = AtomicSchemeId::default();
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+static NAMES: RwLock<BTreeMap<Box<str>, Arc<Object>>> = RwLock::new(BTreeMap::new());
+
+struct State {
+    /// Size in bytes, the frames cover it rounded up to the page size
+    size: usize,
+    frames: Vec<Frame>,
+    /// Number of grants mapping this object, which prevent it from shrinking
+    mappings: usize,
+    /// Number of those grants which are writable, which prevent `F_SEAL_WRITE`
+    writable_mappings: usize,
+    seals: usize,
+}
+
+struct Object {
+    state: Mutex<State>,
+    uid: u32,
+    gid: u32,
+    mode: u16,
+}
+
+impl Object {
+    fn new(uid: u32, gid: u32, mode: u16, seals: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                size: 0,
+                frames: Vec::new(),
+                mappings: 0,
+                writable_mappings: 0,
+                seals,
+            }),
+            uid,
+            gid,
+            mode,
+        }
+    }
+
+    fn can_access(&self, uid: u32, gid: u32) -> bool {
+        let perm = if uid == 0 || uid == self.uid {
+            self.mode >> 6
+        } else if gid == self.gid {
+            self.mode >> 3
+        } else {
+            self.mode
+        };
+        uid == 0 || perm & 0o6 == 0o6
+    }
+
+    fn truncate(&self, len: usize) -> Result<()> {
+        let mut state = self.state.lock();
+        let page_count = len.div_ceil(PAGE_SIZE);
+
+        if (len < state.size && state.seals & F_SEAL_SHRINK != 0) || (len > state.size && state.seals & F_SEAL_GROW != 0) {
+            return Err(Error::new(EPERM));
+        }
+
+        if page_count < state.frames.len() {
+            // Frames cannot be taken away from existing mappings
+            if state.mappings > 0 {
+                return Err(Error::new(EBUSY));
+            }
+            for frame in state.frames.drain(page_count..) {
+                deallocate_frames(frame, 1);
+            }
+        }
+        while state.frames.len() < page_count {
+            let frame = allocate_frames(1).ok_or(Error::new(ENOMEM))?;
+            unsafe {
+                (RmmA::phys_to_virt(frame.start_address()).data() as *mut u8).write_bytes(0, PAGE_SIZE);
+            }
+            state.frames.push(frame);
+        }
+        state.size = len;
+        Ok(())
+    }
+
+    fn add_seals(&self, seals: usize) -> Result<()> {
+        if seals & !(F_SEAL_SEAL | F_SEAL_SHRINK | F_SEAL_GROW | F_SEAL_WRITE) != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let mut state = self.state.lock();
+        if state.seals & F_SEAL_SEAL != 0 {
+            return Err(Error::new(EPERM));
+        }
+        if seals & F_SEAL_WRITE != 0 && state.writable_mappings > 0 {
+            return Err(Error::new(EBUSY));
+        }
+        state.seals |= seals;
+        Ok(())
+    }
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        for frame in self.state.get_mut().frames.drain(..) {
+            deallocate_frames(frame, 1);
+        }
+    }
+}
+
+struct Handle {
+    object: Arc<Object>,
+    name: Option<Box<str>>,
+    /// The flags of the grant owning this handle, if it is not a file descriptor
+    mapping: Option<MapFlags>,
+}
+
+fn insert_handle(handle: Handle) -> usize {
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    HANDLES.write().insert(id, handle);
+    id
+}
+
+fn object(id: usize) -> Result<Arc<Object>> {
+    Ok(Arc::clone(&HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.object))
+}
+
+pub struct ShmScheme;
+
+impl ShmScheme {
+    pub fn new(scheme_id: SchemeId) -> Self {
+        SCHEME_ID.store(scheme_id, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Scheme for ShmScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        let name = path.trim_matches('/');
+        let mode = (flags & 0o777) as u16;
+
+        if name.is_empty() {
+            return Ok(insert_handle(Handle {
+                object: Arc::new(Object::new(uid, gid, mode, 0)),
+                name: None,
+                mapping: None,
+            }));
+        }
+
+        let object = {
+            let mut names = NAMES.write();
+            match names.get(name) {
+                Some(_) if flags & O_CREAT == O_CREAT && flags & O_EXCL == O_EXCL => return Err(Error::new(EEXIST)),
+                Some(object) => {
+                    if !object.can_access(uid, gid) {
+                        return Err(Error::new(EACCES));
+                    }
+                    Arc::clone(object)
+                }
+                None if flags & O_CREAT == O_CREAT => {
+                    let object = Arc::new(Object::new(uid, gid, mode, F_SEAL_SEAL));
+                    names.insert(name.into(), Arc::clone(&object));
+                    object
+                }
+                None => return Err(Error::new(ENOENT)),
+            }
+        };
+        if flags & O_TRUNC == O_TRUNC {
+            object.truncate(0)?;
+        }
+
+        Ok(insert_handle(Handle {
+            object,
+            name: Some(name.into()),
+            mapping: None,
+        }))
+    }
+
+    fn unlink(&self, path: &str, uid: u32, _gid: u32) -> Result<usize> {
+        let mut names = NAMES.write();
+        let object = names.get(path.trim_matches('/')).ok_or(Error::new(ENOENT))?;
+        if uid != 0 && uid != object.uid {
+            return Err(Error::new(EACCES));
+        }
+        names.remove(path.trim_matches('/'));
+        Ok(0)
+    }
+
+    fn fmap(&self, id: usize, map: &Map) -> Result<usize> {
+        self.kfmap(id, &AddrSpace::current()?, map, false)
+    }
+
+    fn ftruncate(&self, id: usize, len: usize) -> Result<usize> {
+        object(id)?.truncate(len).map(|()| 0)
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let object = object(id)?;
+        match cmd {
+            F_ADD_SEALS => object.add_seals(arg).map(|()| 0),
+            F_GET_SEALS => Ok(object.state.lock().seals),
+            // The kernel keeps the file status flags, and asks every scheme first
+            F_GETFL | F_SETFL => Ok(0),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        object(id).map(|_| 0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        let handle = HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        if let Some(flags) = handle.mapping {
+            let mut state = handle.object.state.lock();
+            state.mappings -= 1;
+            if flags.contains(MapFlags::PROT_WRITE) {
+                state.writable_mappings -= 1;
+            }
+        }
+        Ok(0)
+    }
+}
+impl KernelScheme for ShmScheme {
+    fn kdup(&self, old_id: usize, buf: UserSliceRo, _caller: CallerCtx) -> Result<OpenResult> {
+        if !buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+        let handles = HANDLES.read();
+        let handle = handles.get(&old_id).ok_or(Error::new(EBADF))?;
+        let new = Handle {
+            object: Arc::clone(&handle.object),
+            name: handle.name.clone(),
+            mapping: None,
+        };
+        drop(handles);
+
+        Ok(OpenResult::SchemeLocal(insert_handle(new)))
+    }
+
+    fn kfmap(&self, id: usize, addr_space: &Arc<RwLock<AddrSpace>>, map: &Map, _consume: bool) -> Result<usize> {
+        let object = object(id)?;
+        let (requested_page, page_count) = validate_region(map.address, map.size)?;
+        if map.offset % PAGE_SIZE != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let namespace = context::current()?.read().ens;
+
+        // TODO: MAP_PRIVATE, which requires CoW
+        let page = addr_space.write().mmap((map.address != 0).then_some(requested_page), page_count, map.flags, |dst_page, flags, mapper, flusher| {
+            let mut state = object.state.lock();
+            if map.flags.contains(MapFlags::PROT_WRITE) && state.seals & F_SEAL_WRITE != 0 {
+                return Err(Error::new(EPERM));
+            }
+            if state.frames.get(map.offset / PAGE_SIZE..).map_or(true, |frames| frames.len() < page_count) {
+                return Err(Error::new(EINVAL));
+            }
+
+            // Each grant holds its own handle to the object, which is closed when it is unmapped
+            state.mappings += 1;
+            if map.flags.contains(MapFlags::PROT_WRITE) {
+                state.writable_mappings += 1;
+            }
+            let number = insert_handle(Handle {
+                object: Arc::clone(&object),
+                name: None,
+                mapping: Some(map.flags),
+            });
+            let desc = FileDescriptor {
+                description: Arc::new(RwLock::new(FileDescription {
+                    namespace,
+                    scheme: SCHEME_ID.load(Ordering::SeqCst),
+                    number,
+                    flags: O_RDWR,
+                })),
+                cloexec: false,
+            };
+
+            let frames = &state.frames[map.offset / PAGE_SIZE..][..page_count];
+            Ok(Grant::shared(frames, dst_page, flags, GrantFileRef { desc, offset: map.offset, flags: map.flags }, mapper, flusher)?)
+        })?;
+
+        Ok(page.start_address().data())
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let object = object(id)?;
+        let (size, page_count) = {
+            let state = object.state.lock();
+            (state.size, state.frames.len())
+        };
+
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FILE | object.mode,
+            st_uid: object.uid,
+            st_gid: object.gid,
+            st_size: size as u64,
+            st_blksize: PAGE_SIZE as u32,
+            st_blocks: (page_count * PAGE_SIZE / 512) as u64,
+            st_ino: Arc::as_ptr(&object) as u64,
+            st_nlink: 1,
+            ..Default::default()
+        })?;
+        Ok(0)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let handles = HANDLES.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        buf.copy_common_bytes_from_slice(format!("shm:{}", handle.name.as_deref().unwrap_or("")).as_bytes())
+    }
+}
@@ -0,0 +1,126 @@
+//! # Swap daemon interface
+//! The backing store for swapped out memory is provided by a userspace daemon, which opens
+//! `swap:<slot count>` (once) and then serves requests in a loop. Each read returns a
+//! `SwapRequest`, followed by the page contents for `SWAP_OUT`, which the daemon stores in `slot`.
+//! For `SWAP_IN`, the daemon replies by writing the request header back, followed by the page
+//! stored in `slot`, or a `SWAP_ERROR` header if it cannot be read. Reading also reclaims memory
+//! when it is low, so the daemon should always have a read pending.
+//!
+//! Closing the file descriptor removes the backing store, losing the contents of swapped out pages.
+
+use alloc::collections::BTreeMap;
+use core::mem;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use rmm::Arch as _;
+
+use crate::context::memory::AddrSpace;
+use crate::context::swap::{self, Task};
+use crate::memory::{deallocate_frames, PAGE_SIZE};
+use crate::paging::RmmA;
+use crate::sync::RwLock;
+use crate::syscall::abi::{SwapRequest, SWAP_ERROR, SWAP_IN, SWAP_OUT};
+use crate::syscall::error::*;
+use crate::syscall::flag::{EventFlags, O_NONBLOCK};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::KernelScheme;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Open flags of the daemon's handle, of which there can only be one at a time
+static HANDLES: RwLock<BTreeMap<usize, usize>> = RwLock::new(BTreeMap::new());
+
+const HEADER_SIZE: usize = mem::size_of::<SwapRequest>();
+
+pub struct SwapScheme;
+
+impl Scheme for SwapScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        let slot_count = usize::from_str(path.trim_matches('/')).or(Err(Error::new(ENOENT)))?;
+        if slot_count == 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        swap::register(slot_count, &AddrSpace::current()?)?;
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, flags);
+        Ok(id)
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(EventFlags::empty())
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        swap::unregister();
+        Ok(0)
+    }
+}
+impl KernelScheme for SwapScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let flags = *HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+
+        let (header_buf, page_buf) = buf.split_at(HEADER_SIZE).ok_or(Error::new(EINVAL))?;
+        let page_buf = page_buf.limit(PAGE_SIZE).ok_or(Error::new(EINVAL))?;
+
+        match swap::next_task(flags & O_NONBLOCK == 0)? {
+            Task::Out { slot, frame } => {
+                let header = SwapRequest { kind: SWAP_OUT, slot, id: 0 };
+                let data = unsafe { core::slice::from_raw_parts(RmmA::phys_to_virt(frame.start_address()).data() as *const u8, PAGE_SIZE) };
+
+                if let Err(error) = header_buf.copy_from_slice(&header).and_then(|()| page_buf.copy_from_slice(data)) {
+                    swap::requeue(Task::Out { slot, frame });
+                    return Err(error);
+                }
+                deallocate_frames(frame, 1);
+                Ok(HEADER_SIZE + PAGE_SIZE)
+            }
+            Task::In { id, slot } => {
+                if let Err(error) = header_buf.copy_from_slice(&SwapRequest { kind: SWAP_IN, slot, id }) {
+                    swap::requeue(Task::In { id, slot });
+                    return Err(error);
+                }
+                Ok(HEADER_SIZE)
+            }
+        }
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+
+        let (header_buf, page_buf) = buf.split_at(HEADER_SIZE).ok_or(Error::new(EINVAL))?;
+        let header = unsafe { header_buf.read_exact::<SwapRequest>()? };
+
+        match header.kind {
+            SWAP_IN => {
+                let page_buf = page_buf.limit(PAGE_SIZE).ok_or(Error::new(EINVAL))?;
+                swap::complete(header.id, |data| page_buf.copy_to_slice(data))?;
+                Ok(HEADER_SIZE + PAGE_SIZE)
+            }
+            SWAP_ERROR => {
+                swap::fail(header.id)?;
+                Ok(HEADER_SIZE)
+            }
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        buf.copy_common_bytes_from_slice(b"swap:")
+    }
+}
@@ -0,0 +1,145 @@
+//! # Kernel symbols
+//! `symbols:<address>` reads the function of the kernel containing `address`, given in
+//! hexadecimal, as `<function>+<offset>`, and fails to open with `ENOENT` if there is none (see
+//! `symbols`).
+//!
+//! Addresses can also be resolved in bulk, such as those of the samples of `profile:`, by writing
+//! them to `symbols:`, separated by whitespace. Reading then returns a line per address, `?` for
+//! those outside of the kernel, until the next write. A write must hold whole addresses. Only
+//! root can resolve addresses, as they reveal the layout of the kernel.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::symbols;
+use crate::sync::RwLock;
+use crate::syscall::data::Stat;
+use crate::syscall::error::*;
+use crate::syscall::flag::{MODE_FILE, O_ACCMODE, O_RDONLY};
+use crate::syscall::scheme::{calc_seek_offset_usize, Scheme};
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::KernelScheme;
+
+/// Longest write of addresses
+const WRITE_MAX: usize = 64 * 1024;
+
+struct Handle {
+    /// The address given in the path
+    address: Option<usize>,
+    /// The resolved addresses
+    data: Vec<u8>,
+    offset: usize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn parse(address: &str) -> Option<usize> {
+    usize::from_str_radix(address.trim_start_matches("0x"), 16).ok()
+}
+
+fn resolve<'a>(addresses: impl Iterator<Item = &'a str>) -> Result<Vec<u8>> {
+    let mut string = String::new();
+    for address in addresses {
+        let _ = match symbols::lookup(parse(address).ok_or(Error::new(EINVAL))?) {
+            Some(location) => writeln!(string, "{}", location),
+            None => writeln!(string, "?"),
+        };
+    }
+    Ok(string.into_bytes())
+}
+
+pub struct SymbolsScheme;
+
+impl Scheme for SymbolsScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let address = match path.trim_matches('/') {
+            "" => None,
+            address => Some(parse(address).ok_or(Error::new(ENOENT))?),
+        };
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        let data = match address {
+            Some(address) => {
+                let location = symbols::lookup(address).ok_or(Error::new(ENOENT))?;
+                if flags & O_ACCMODE != O_RDONLY {
+                    return Err(Error::new(EROFS));
+                }
+                format!("{}\n", location).into_bytes()
+            }
+            None => Vec::new(),
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { address, data, offset: 0 });
+        Ok(id)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<isize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let new_offset = calc_seek_offset_usize(handle.offset, pos, whence, handle.data.len())?;
+        handle.offset = new_offset as usize;
+        Ok(new_offset)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+impl KernelScheme for SymbolsScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let bytes_read = buf.copy_common_bytes_from_slice(handle.data.get(handle.offset..).unwrap_or(&[]))?;
+        handle.offset += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        if HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.address.is_some() {
+            return Err(Error::new(EBADF));
+        }
+        let mut bytes = vec![0_u8; buf.len().min(WRITE_MAX)];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        let data = resolve(str::from_utf8(&bytes[..len]).or(Err(Error::new(EINVAL)))?.split_whitespace())?;
+
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        handle.data = data;
+        handle.offset = 0;
+        Ok(len)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.address {
+            Some(address) => format!("symbols:{:x}", address),
+            None => String::from("symbols:"),
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let handles = HANDLES.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FILE | if handle.address.is_some() { 0o400 } else { 0o600 },
+            st_size: handle.data.len() as u64,
+            ..Default::default()
+        })?;
+        Ok(0)
+    }
+}
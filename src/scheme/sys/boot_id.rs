@@ -0,0 +1,7 @@
+use alloc::vec::Vec;
+use crate::syscall::error::{Error, ENOENT, Result};
+
+pub fn resource() -> Result<Vec<u8>> {
+    let boot_id = crate::boot_id::get().ok_or(Error::new(ENOENT))?;
+    Ok(format!("{}\n", boot_id).into_bytes())
+}
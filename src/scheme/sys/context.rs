@@ -2,10 +2,23 @@ use alloc::string::String;
 use alloc::vec::Vec;
 
 use crate::context;
+use crate::memory::PAGE_SIZE;
 use crate::syscall::error::Result;
 
+fn format_size(size: usize) -> String {
+    if size >= 1024 * 1024 * 1024 {
+        format!("{} GB", size / 1024 / 1024 / 1024)
+    } else if size >= 1024 * 1024 {
+        format!("{} MB", size / 1024 / 1024)
+    } else if size >= 1024 {
+        format!("{} KB", size / 1024)
+    } else {
+        format!("{} B", size)
+    }
+}
+
 pub fn resource() -> Result<Vec<u8>> {
-    let mut string = format!("{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<12}{:<8}{}\n",
+    let mut string = format!("{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<12}{:<8}{:<8}{}\n",
                              "PID",
                              "PGID",
                              "PPID",
@@ -19,7 +32,8 @@ pub fn resource() -> Result<Vec<u8>> {
                              "CPU",
                              "AFF",
                              "TIME",
-                             "MEM",
+                             "RSS",
+                             "VSZ",
                              "NAME");
     {
         let contexts = context::contexts();
@@ -79,29 +93,18 @@ pub fn resource() -> Result<Vec<u8>> {
                 cpu_time_ns / 10_000_000
             );
 
-            let mut memory = context.kfx.len();
+            let mut resident = context.kfx.len();
             if let Some(ref kstack) = context.kstack {
-                memory += kstack.len();
+                resident += kstack.len();
             }
+            let mut virt = resident;
             if let Ok(addr_space) = context.addr_space() {
-                for grant in addr_space.read().grants.iter() {
-                    if grant.is_owned() {
-                        memory += grant.size();
-                    }
-                }
+                let stats = addr_space.read().stats();
+                resident += stats.resident * PAGE_SIZE;
+                virt += stats.mapped * PAGE_SIZE;
             }
 
-            let memory_string = if memory >= 1024 * 1024 * 1024 {
-                format!("{} GB", memory / 1024 / 1024 / 1024)
-            } else if memory >= 1024 * 1024 {
-                format!("{} MB", memory / 1024 / 1024)
-            } else if memory >= 1024 {
-                format!("{} KB", memory / 1024)
-            } else {
-                format!("{} B", memory)
-            };
-
-            string.push_str(&format!("{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<12}{:<8}{}\n",
+            string.push_str(&format!("{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<12}{:<8}{:<8}{}\n",
                                context.id.into(),
                                context.pgid.into(),
                                context.ppid.into(),
@@ -115,7 +118,8 @@ pub fn resource() -> Result<Vec<u8>> {
                                cpu_string,
                                affinity,
                                cpu_time_string,
-                               memory_string,
+                               format_size(resident),
+                               format_size(virt),
                                context.name));
         }
     }
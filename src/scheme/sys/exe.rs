@@ -4,5 +4,5 @@ use crate::context;
 use crate::syscall::error::Result;
 
 pub fn resource() -> Result<Vec<u8>> {
-    Ok(context::current()?.read().name.clone().into_owned().into_bytes())
+    Ok(context::current()?.read().name.as_bytes().to_vec())
 }
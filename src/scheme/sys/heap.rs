@@ -0,0 +1,40 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::allocator::{percpu, stats};
+use crate::syscall::error::Result;
+
+/// Size, usage and peak usage of the kernel heap in bytes, and the number of allocations ever made
+/// and currently live by requested size
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+    let stats = stats::stats();
+
+    let _ = writeln!(string, "size: {}", stats.size);
+    let _ = writeln!(string, "used: {}", stats.used);
+    let _ = writeln!(string, "peak: {}", stats.peak);
+
+    let _ = writeln!(string, "{:<8}{:<12}{}", "SIZE", "LIVE", "ALLOCS");
+    for (bucket, bucket_stats) in stats.buckets.iter().enumerate() {
+        let limit = match stats::bucket_limit(bucket) {
+            Some(limit) => format!("{}", limit),
+            None => String::from("larger"),
+        };
+        let _ = writeln!(string, "{:<8}{:<12}{}", limit, bucket_stats.live, bucket_stats.allocs);
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// The per-CPU caches of each size class of the kernel heap
+pub fn caches_resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+
+    let _ = writeln!(string, "{:<8}{:<8}{:<12}{:<12}{}", "SIZE", "CACHED", "HITS", "MISSES", "FLUSHES");
+    for stats in percpu::stats() {
+        let _ = writeln!(string, "{:<8}{:<8}{:<12}{:<12}{}", stats.size, stats.cached, stats.hits, stats.misses, stats.flushes);
+    }
+
+    Ok(string.into_bytes())
+}
@@ -0,0 +1,23 @@
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use core::fmt::Write;
+
+use crate::idle;
+use crate::syscall::error::Result;
+
+/// Number of times each CPU entered each idle state, and nanoseconds it spent in it
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+
+    for cpu_id in 0..crate::cpu_count() {
+        let _ = write!(string, "cpu{}:", cpu_id);
+        for (state, usage, time) in idle::usage(cpu_id) {
+            let _ = write!(string, " {} {} {}", state.name, usage, time);
+        }
+        string.push('\n');
+    }
+
+    Ok(string.into_bytes())
+}
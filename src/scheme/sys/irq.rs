@@ -18,3 +18,16 @@ pub fn resource() -> Result<Vec<u8>> {
 
     Ok(string.into_bytes())
 }
+
+pub fn storm_resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+
+    {
+        let storms = crate::scheme::irq::STORMS.lock();
+        for (i, storm) in storms.iter().enumerate().filter(|(_, storm)| storm.storms > 0) {
+            let _ = writeln!(string, "{}: {} storms, {} dropped{}", i, storm.storms, storm.dropped, if storm.masked_until.is_some() { ", masked" } else { "" });
+        }
+    }
+
+    Ok(string.into_bytes())
+}
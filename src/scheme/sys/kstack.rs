@@ -0,0 +1,26 @@
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use core::fmt::Write;
+
+use crate::context;
+use crate::syscall::error::Result;
+
+/// Kernel stack size of each context, and the total allocated for kernel stacks
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+    let mut total = 0;
+
+    {
+        let contexts = context::contexts();
+        for (id, context_lock) in contexts.iter() {
+            let size = context_lock.read().kstack.as_ref().map_or(0, |kstack| kstack.len());
+            total += size;
+            let _ = writeln!(string, "{}: {}", id.into(), size);
+        }
+    }
+    let _ = writeln!(string, "total: {}", total);
+
+    Ok(string.into_bytes())
+}
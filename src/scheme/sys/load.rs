@@ -0,0 +1,35 @@
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use core::fmt::Write;
+
+use crate::context::load::{self, FIXED_1};
+use crate::syscall::error::Result;
+
+/// The 1, 5 and 15 minute load averages, with two decimals like on Linux
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+
+    for (i, average) in load::loadavg().into_iter().enumerate() {
+        // Round to the nearest hundredth
+        let hundredths = (average * 100 + FIXED_1 / 2) / FIXED_1;
+        let separator = if i == 2 { '\n' } else { ' ' };
+        let _ = write!(string, "{}.{:02}{}", hundredths / 100, hundredths % 100, separator);
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Busy and idle time of each CPU in nanoseconds, and its recent utilization
+pub fn cpu_resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+
+    for cpu_id in 0..crate::cpu_count() {
+        if let Some((busy, idle, utilization)) = load::cpu_load(cpu_id) {
+            let _ = writeln!(string, "cpu{}: busy {} idle {} utilization {}.{}%", cpu_id, busy, idle, utilization / 10, utilization % 10);
+        }
+    }
+
+    Ok(string.into_bytes())
+}
@@ -2,8 +2,8 @@ use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use core::str;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::RwLock;
 
+use crate::sync::RwLock;
 use crate::syscall::data::Stat;
 use crate::syscall::error::{Error, EBADF, ENOENT, Result};
 use crate::syscall::flag::{MODE_DIR, MODE_FILE};
@@ -12,16 +12,24 @@ use crate::arch::interrupt;
 use crate::syscall::usercopy::UserSliceWo;
 
 mod block;
+mod boot_id;
 mod context;
 mod cpu;
 mod exe;
+mod heap;
+mod idle;
 mod iostat;
 mod irq;
+mod kstack;
+mod load;
 mod log;
+mod numa;
 mod scheme;
 mod scheme_num;
+mod scheme_stats;
 mod syscall;
 mod uname;
+mod wakeups;
 
 struct Handle {
     path: &'static str,
@@ -44,19 +52,37 @@ impl SysScheme {
         let mut files: BTreeMap<&'static str, SysFn> = BTreeMap::new();
 
         files.insert("block", block::resource);
+        files.insert("boot_id", boot_id::resource);
         files.insert("context", context::resource);
         files.insert("cpu", cpu::resource);
+        files.insert("cpu_load", load::cpu_resource);
         files.insert("exe", exe::resource);
+        files.insert("heap", heap::resource);
+        files.insert("heap_caches", heap::caches_resource);
+        files.insert("idle", idle::resource);
         files.insert("iostat", iostat::resource);
         files.insert("irq", irq::resource);
+        files.insert("irq_storm", irq::storm_resource);
+        files.insert("kstack", kstack::resource);
+        files.insert("loadavg", load::resource);
         files.insert("log", log::resource);
+        files.insert("numa", numa::resource);
         files.insert("scheme", scheme::resource);
         files.insert("scheme_num", scheme_num::resource);
+        files.insert("scheme_stats", scheme_stats::resource);
         files.insert("syscall", syscall::resource);
         files.insert("uname", uname::resource);
+        files.insert("version", uname::version_resource);
+        files.insert("wakeups", wakeups::resource);
         files.insert("env", || Ok(Vec::from(crate::init_env())));
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
         files.insert("spurious_irq", interrupt::irq::spurious_irq_resource);
+        #[cfg(target_arch = "x86_64")]
+        files.insert("mce", crate::arch::mce::resource);
+        #[cfg(target_arch = "x86_64")]
+        files.insert("paravirt", crate::arch::paravirt::resource);
+        #[cfg(target_arch = "x86_64")]
+        files.insert("tlb", crate::arch::tlb::stats_resource);
 
         SysScheme {
             next_id: AtomicUsize::new(0),
@@ -0,0 +1,37 @@
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use core::fmt::Write;
+
+use crate::memory::numa;
+use crate::memory::PAGE_SIZE;
+use crate::syscall::error::Result;
+
+/// The memory, CPUs and distances of each NUMA node
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+
+    let topology = match numa::topology() {
+        Some(topology) => topology,
+        None => return Ok(string.into_bytes()),
+    };
+
+    for node in 0..topology.node_count {
+        let memory = topology.ranges.iter()
+            .filter(|range| range.node == node)
+            .map(|range| range.end - range.start)
+            .sum::<usize>() * PAGE_SIZE / 1024;
+        let _ = write!(string, "node{}: memory {} KB cached {} KB cpus", node, memory, numa::node_free_frames(node) * PAGE_SIZE / 1024);
+        for (cpu_id, _) in topology.cpus.iter().filter(|(_, &cpu_node)| cpu_node == node) {
+            let _ = write!(string, " {}", cpu_id);
+        }
+        let _ = write!(string, " distances");
+        for other in 0..topology.node_count {
+            let _ = write!(string, " {}", numa::distance(node, other));
+        }
+        string.push('\n');
+    }
+
+    Ok(string.into_bytes())
+}
@@ -0,0 +1,52 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+
+use crate::common::histogram::Histogram;
+use crate::context;
+use crate::scheme;
+use crate::syscall::error::{Error, ESRCH, Result};
+
+fn write_histogram(string: &mut String, name: &str, histogram: &Histogram) {
+    let _ = writeln!(string, "  {:<8}{:<10}{:<12}{:<12}{:<12}{:<12}{}",
+        name,
+        histogram.count(),
+        histogram.mean(),
+        histogram.percentile(50),
+        histogram.percentile(90),
+        histogram.percentile(99),
+        histogram.max());
+}
+
+pub fn resource() -> Result<Vec<u8>> {
+    let scheme_ns = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.ens
+    };
+
+    let mut string = String::new();
+
+    let schemes = scheme::schemes();
+    for (name, scheme_id) in schemes.iter_name(scheme_ns) {
+        let inner = match schemes.get(*scheme_id).map(|scheme| scheme.as_user_inner()) {
+            Some(Ok(inner)) => inner,
+            _ => continue,
+        };
+        let stats = &inner.stats;
+
+        let _ = writeln!(string, "{}: in flight {}, max queue depth {}",
+            name,
+            stats.in_flight(),
+            stats.max_queue_depth.load(Ordering::Relaxed));
+        let _ = writeln!(string, "  {:<8}{:<10}{:<12}{:<12}{:<12}{:<12}{}", "", "COUNT", "MEAN", "P50", "P90", "P99", "MAX");
+        write_histogram(&mut string, "queue", &stats.queue_latency);
+        write_histogram(&mut string, "serve", &stats.service_latency);
+        write_histogram(&mut string, "total", &stats.round_trip);
+        write_histogram(&mut string, "depth", &stats.queue_depth);
+    }
+
+    Ok(string.into_bytes())
+}
@@ -1,5 +1,11 @@
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+
+use crate::syscall::abi;
 use crate::syscall::error::Result;
+use crate::syscall::uname_info;
 
 pub fn resource() -> Result<Vec<u8>> {
     Ok(format!("Redox\n\n{}\n\n{}\n",
@@ -7,3 +13,36 @@ pub fn resource() -> Result<Vec<u8>> {
                env!("TARGET").split('-').next().unwrap()).into_bytes())
 }
 
+fn field(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+/// The same information as the `uname` syscall, one `name: value` per line
+pub fn version_resource() -> Result<Vec<u8>> {
+    let uname = uname_info();
+    let mut string = String::new();
+
+    let _ = writeln!(string, "version: {}", field(&uname.release));
+    let _ = writeln!(string, "revision: {}", field(&uname.revision));
+    let _ = writeln!(string, "machine: {}", field(&uname.machine));
+    let _ = writeln!(string, "abi: {}", uname.abi_revision);
+    let _ = writeln!(string, "scheme_protocol: {}", uname.scheme_protocol);
+
+    let _ = write!(string, "features:");
+    for (bit, name) in [
+        (abi::KERNEL_FEATURE_ACPI, "acpi"),
+        (abi::KERNEL_FEATURE_MULTI_CORE, "multi_core"),
+        (abi::KERNEL_FEATURE_PTI, "pti"),
+        (abi::KERNEL_FEATURE_X86_SMAP, "x86_smap"),
+        (abi::KERNEL_FEATURE_X86_FSGSBASE, "x86_fsgsbase"),
+        (abi::KERNEL_FEATURE_SLAB, "slab"),
+    ] {
+        if uname.features & bit == bit {
+            let _ = write!(string, " {}", name);
+        }
+    }
+    string.push('\n');
+
+    Ok(string.into_bytes())
+}
@@ -0,0 +1,24 @@
+use alloc::vec::Vec;
+
+use crate::context;
+use crate::syscall::error::Result;
+
+/// The contexts with timer or IRQ wakeups, most frequently woken first
+pub fn resource() -> Result<Vec<u8>> {
+    let mut rows = Vec::new();
+    for (_, context_lock) in context::contexts().iter() {
+        let context = context_lock.read();
+        let (timer, irq, rate) = context.wakeups.counts();
+        if timer + irq > 0 {
+            rows.push((rate, context.id, timer, irq, context.name.clone()));
+        }
+    }
+    rows.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+
+    let mut string = format!("{:<6}{:<12}{:<12}{:<12}{}\n", "PID", "PER SEC", "TIMER", "IRQ", "NAME");
+    for (rate, id, timer, irq, name) in rows {
+        let rate = format!("{}.{:02}", rate / 100, rate % 100);
+        string.push_str(&format!("{:<6}{:<12}{:<12}{:<12}{}\n", id.into(), rate, timer, irq, name));
+    }
+    Ok(string.into_bytes())
+}
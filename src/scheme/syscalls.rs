@@ -0,0 +1,183 @@
+//! # Syscall statistics
+//! `syscalls:ctl` reads as `enabled` or `disabled`, and root controls the recording of syscall
+//! statistics (see `context::syscalls`) by writing to it:
+//!
+//! - `enable` starts recording
+//! - `disable` stops recording, keeping what was recorded
+//! - `reset` discards what was recorded
+//!
+//! `syscalls:all` lists the count and the latency percentiles of each syscall made by each
+//! context, in nanoseconds, and `syscalls:<pid>` those of one context, as of when they were
+//! opened.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::common::histogram::Histogram;
+use crate::context::{self, syscalls, Context, ContextId};
+use crate::sync::RwLock;
+use crate::syscall::data::Stat;
+use crate::syscall::debug;
+use crate::syscall::error::*;
+use crate::syscall::flag::{MODE_FILE, O_ACCMODE, O_RDONLY};
+use crate::syscall::scheme::{calc_seek_offset_usize, Scheme};
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+
+use super::KernelScheme;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum File {
+    Ctl,
+    All,
+    Context(ContextId),
+}
+
+struct Handle {
+    file: File,
+    /// The statistics, read when opened
+    data: Vec<u8>,
+    offset: usize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn write_histogram(string: &mut String, number: usize, histogram: &Histogram) {
+    let name = match debug::name(number) {
+        Some(name) => String::from(name),
+        None => format!("{:#x}", number),
+    };
+    let _ = writeln!(string, "  {:<16}{:<10}{:<12}{:<12}{:<12}{:<12}{}",
+        name,
+        histogram.count(),
+        histogram.mean(),
+        histogram.percentile(50),
+        histogram.percentile(90),
+        histogram.percentile(99),
+        histogram.max());
+}
+
+fn write_context(string: &mut String, context: &Context) {
+    let _ = writeln!(string, "{}: {}", context.id.into(), context.name);
+    if context.syscall_stats.iter().next().is_none() {
+        return;
+    }
+    let _ = writeln!(string, "  {:<16}{:<10}{:<12}{:<12}{:<12}{:<12}{}", "SYSCALL", "COUNT", "MEAN", "P50", "P90", "P99", "MAX");
+    for (number, histogram) in context.syscall_stats.iter() {
+        write_histogram(string, number, histogram);
+    }
+}
+
+fn contents(file: File) -> Result<Vec<u8>> {
+    let mut string = String::new();
+    match file {
+        File::Ctl => string.push_str(if syscalls::enabled() { "enabled\n" } else { "disabled\n" }),
+        File::All => {
+            for (_, context_lock) in context::contexts().iter() {
+                write_context(&mut string, &context_lock.read());
+            }
+        }
+        File::Context(id) => {
+            let context_lock = context::contexts().get(id).ok_or(Error::new(ESRCH))?.clone();
+            write_context(&mut string, &context_lock.read());
+        }
+    }
+    Ok(string.into_bytes())
+}
+
+fn control(command: &str) -> Result<()> {
+    match command {
+        "enable" => syscalls::set_enabled(true),
+        "disable" => syscalls::set_enabled(false),
+        "reset" => syscalls::reset(),
+        _ => return Err(Error::new(EINVAL)),
+    }
+    Ok(())
+}
+
+pub struct SyscallsScheme;
+
+impl Scheme for SyscallsScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let file = match path.trim_matches('/') {
+            "ctl" => File::Ctl,
+            "all" => File::All,
+            pid => File::Context(ContextId::from(pid.parse::<usize>().or(Err(Error::new(ENOENT)))?)),
+        };
+        if flags & O_ACCMODE != O_RDONLY {
+            if file != File::Ctl {
+                return Err(Error::new(EROFS));
+            }
+            if uid != 0 {
+                return Err(Error::new(EACCES));
+            }
+        }
+
+        let data = contents(file)?;
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { file, data, offset: 0 });
+        Ok(id)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<isize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let new_offset = calc_seek_offset_usize(handle.offset, pos, whence, handle.data.len())?;
+        handle.offset = new_offset as usize;
+        Ok(new_offset)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+impl KernelScheme for SyscallsScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let bytes_read = buf.copy_common_bytes_from_slice(handle.data.get(handle.offset..).unwrap_or(&[]))?;
+        handle.offset += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        if HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file != File::Ctl {
+            return Err(Error::new(EBADF));
+        }
+        let mut bytes = [0_u8; 16];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        control(str::from_utf8(&bytes[..len]).or(Err(Error::new(EINVAL)))?.trim())?;
+        Ok(len)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Ctl => String::from("syscalls:ctl"),
+            File::All => String::from("syscalls:all"),
+            File::Context(id) => format!("syscalls:{}", id.into()),
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let handles = HANDLES.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FILE | if handle.file == File::Ctl { 0o644 } else { 0o444 },
+            st_size: handle.data.len() as u64,
+            ..Default::default()
+        })?;
+        Ok(0)
+    }
+}
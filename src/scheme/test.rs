@@ -0,0 +1,396 @@
+//! # Loopback test scheme
+//! A scheme with deterministic misbehavior, for testing how callers handle the edge cases real
+//! schemes only produce occasionally, without writing a daemon for it. Each open of `test:` creates
+//! an in-memory file: what is written to it can be read back, like a regular file. The path is a
+//! list of options separated by `/`, changing how the file behaves:
+//!
+//! - `partial=<n>`: reads and writes transfer at most `n` bytes at a time
+//! - `delay=<ms>`: reads and writes sleep for `ms` milliseconds first
+//! - `fail-<call>=<errno>`: the call fails with `errno`, where `call` is one of `open`, `read`,
+//!   `write`, `seek`, `dup`, `fmap`, `fpath`, `fstat`, `fstatvfs`, `futimens`, `fsync`,
+//!   `ftruncate`, `fcntl`, `fevent` or `close`. A closed handle is still released.
+//! - `fail-after=<n>`: forced errors only start after `n` successful calls on the file
+//!
+//! For example, `test:partial=1/delay=10/fail-write=28/fail-after=100` accepts one byte every 10
+//! milliseconds, and fails with `ENOSPC` once 100 calls succeeded. `dup` shares the contents with
+//! the new handle, and applies the options in its buffer on top of the old ones. `fmap` is not
+//! supported, and fails with `EOPNOTSUPP` unless forced to fail otherwise.
+//!
+//! Only built with the `test_scheme` feature.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::mem;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use syscall::CallerCtx;
+
+use crate::context::{self, memory::AddrSpace};
+use crate::sync::{Mutex, RwLock};
+use crate::syscall::data::{Map, Stat, StatVfs, TimeSpec};
+use crate::syscall::error::*;
+use crate::syscall::flag::{EventFlags, MODE_FILE, SEEK_CUR, SEEK_END, SEEK_SET};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+use crate::time;
+
+use super::{KernelScheme, OpenResult};
+
+/// Largest file, writes beyond it fail with `ENOSPC`
+const MAX_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Call {
+    Open,
+    Read,
+    Write,
+    Seek,
+    Dup,
+    Fmap,
+    Fpath,
+    Fstat,
+    Fstatvfs,
+    Futimens,
+    Fsync,
+    Ftruncate,
+    Fcntl,
+    Fevent,
+    Close,
+}
+
+/// The calls that can be forced to fail, with their names in `fail-<call>`
+const CALLS: [(Call, &str); 15] = [
+    (Call::Open, "open"),
+    (Call::Read, "read"),
+    (Call::Write, "write"),
+    (Call::Seek, "seek"),
+    (Call::Dup, "dup"),
+    (Call::Fmap, "fmap"),
+    (Call::Fpath, "fpath"),
+    (Call::Fstat, "fstat"),
+    (Call::Fstatvfs, "fstatvfs"),
+    (Call::Futimens, "futimens"),
+    (Call::Fsync, "fsync"),
+    (Call::Ftruncate, "ftruncate"),
+    (Call::Fcntl, "fcntl"),
+    (Call::Fevent, "fevent"),
+    (Call::Close, "close"),
+];
+
+impl Call {
+    fn from_name(name: &str) -> Option<Self> {
+        CALLS.iter().find(|&&(_, call_name)| call_name == name).map(|&(call, _)| call)
+    }
+
+    fn name(self) -> &'static str {
+        CALLS.iter().find(|&&(call, _)| call == self).map_or("", |&(_, name)| name)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Options {
+    partial: Option<usize>,
+    /// In nanoseconds
+    delay: u128,
+    fail: BTreeMap<Call, i32>,
+    fail_after: usize,
+}
+
+impl Options {
+    /// Apply the options in `path` on top of these
+    fn parse(&mut self, path: &str) -> Result<()> {
+        for option in path.split('/').filter(|option| !option.is_empty()) {
+            let (key, value) = option.split_once('=').ok_or(Error::new(EINVAL))?;
+            let value = value.parse::<usize>().or(Err(Error::new(EINVAL)))?;
+            match key {
+                "partial" if value > 0 => self.partial = Some(value),
+                "delay" => self.delay = value as u128 * 1_000_000,
+                "fail-after" => self.fail_after = value,
+                _ => {
+                    let call = key.strip_prefix("fail-").and_then(Call::from_name).ok_or(Error::new(EINVAL))?;
+                    let errno = i32::try_from(value).or(Err(Error::new(EINVAL)))?;
+                    self.fail.insert(call, errno);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn path(&self) -> String {
+        let mut path = String::from("test:");
+        if let Some(partial) = self.partial {
+            path.push_str(&format!("partial={}/", partial));
+        }
+        if self.delay != 0 {
+            path.push_str(&format!("delay={}/", self.delay / 1_000_000));
+        }
+        for (call, errno) in self.fail.iter() {
+            path.push_str(&format!("fail-{}={}/", call.name(), errno));
+        }
+        if self.fail_after != 0 {
+            path.push_str(&format!("fail-after={}/", self.fail_after));
+        }
+        if path.ends_with('/') {
+            path.pop();
+        }
+        path
+    }
+
+    fn limit(&self, len: usize) -> usize {
+        self.partial.map_or(len, |partial| len.min(partial))
+    }
+}
+
+#[derive(Debug, Default)]
+struct File {
+    data: Vec<u8>,
+    /// Access and modification time, as set by `futimens`
+    times: [TimeSpec; 2],
+}
+
+struct Handle {
+    file: Arc<Mutex<File>>,
+    options: Options,
+    offset: usize,
+    /// Successful calls so far, counting towards `fail-after`
+    calls: usize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+/// Fail with the forced error of `call`, if it applies to the handle yet, and count the call
+/// otherwise
+fn enter(id: usize, call: Call) -> Result<()> {
+    let mut handles = HANDLES.write();
+    let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+    if handle.calls >= handle.options.fail_after {
+        if let Some(&errno) = handle.options.fail.get(&call) {
+            return Err(Error::new(errno));
+        }
+    }
+    handle.calls += 1;
+    Ok(())
+}
+
+/// Block the current context for the delay of the handle, if any
+fn delay(id: usize) -> Result<()> {
+    let delay = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.options.delay;
+    if delay == 0 {
+        return Ok(());
+    }
+
+    let end = time::monotonic() + delay;
+    loop {
+        {
+            let context_lock = context::current()?;
+            let mut context = context_lock.write();
+            if time::monotonic() >= end {
+                break;
+            }
+            context.wake = Some(end);
+            context.block("test delay");
+        }
+        unsafe { context::switch(); }
+    }
+    Ok(())
+}
+
+fn with_handle<T>(id: usize, f: impl FnOnce(&mut Handle) -> Result<T>) -> Result<T> {
+    let mut handles = HANDLES.write();
+    f(handles.get_mut(&id).ok_or(Error::new(EBADF))?)
+}
+
+pub struct TestScheme;
+
+impl Scheme for TestScheme {
+    fn open(&self, path: &str, _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        let mut options = Options::default();
+        options.parse(path)?;
+        if options.fail_after == 0 {
+            if let Some(&errno) = options.fail.get(&Call::Open) {
+                return Err(Error::new(errno));
+            }
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle {
+            file: Arc::new(Mutex::new(File::default())),
+            options,
+            offset: 0,
+            calls: 0,
+        });
+        Ok(id)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<isize> {
+        enter(id, Call::Seek)?;
+        with_handle(id, |handle| {
+            let len = handle.file.lock().data.len();
+            let new_offset = match whence {
+                SEEK_SET => usize::try_from(pos).ok(),
+                SEEK_CUR => handle.offset.checked_add_signed(pos),
+                SEEK_END => len.checked_add_signed(pos),
+                _ => return Err(Error::new(EINVAL)),
+            }.ok_or(Error::new(EINVAL))?;
+            handle.offset = new_offset;
+            isize::try_from(new_offset).or(Err(Error::new(EOVERFLOW)))
+        })
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        enter(id, Call::Fsync)?;
+        Ok(0)
+    }
+
+    fn ftruncate(&self, id: usize, len: usize) -> Result<usize> {
+        enter(id, Call::Ftruncate)?;
+        if len > MAX_SIZE {
+            return Err(Error::new(EFBIG));
+        }
+        with_handle(id, |handle| {
+            handle.file.lock().data.resize(len, 0);
+            Ok(0)
+        })
+    }
+
+    fn fcntl(&self, id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
+        enter(id, Call::Fcntl)?;
+        Ok(0)
+    }
+
+    fn fevent(&self, id: usize, flags: EventFlags) -> Result<EventFlags> {
+        enter(id, Call::Fevent)?;
+        // Always ready
+        Ok(flags)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        let result = enter(id, Call::Close);
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        result.map(|()| 0)
+    }
+}
+impl KernelScheme for TestScheme {
+    fn kdup(&self, old_id: usize, buf: UserSliceRo, _caller: CallerCtx) -> Result<OpenResult> {
+        enter(old_id, Call::Dup)?;
+
+        let mut bytes = [0_u8; 256];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        let path = str::from_utf8(&bytes[..len]).or(Err(Error::new(EINVAL)))?;
+
+        let handle = {
+            let handles = HANDLES.read();
+            let old = handles.get(&old_id).ok_or(Error::new(EBADF))?;
+            let mut options = old.options.clone();
+            options.parse(path)?;
+            Handle {
+                file: Arc::clone(&old.file),
+                options,
+                offset: old.offset,
+                calls: 0,
+            }
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, handle);
+        Ok(OpenResult::SchemeLocal(id))
+    }
+
+    fn kfmap(&self, id: usize, _addr_space: &Arc<RwLock<AddrSpace>>, _map: &Map, _consume: bool) -> Result<usize> {
+        enter(id, Call::Fmap)?;
+        Err(Error::new(EOPNOTSUPP))
+    }
+
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        enter(id, Call::Read)?;
+        delay(id)?;
+
+        with_handle(id, |handle| {
+            let file = handle.file.lock();
+            let data = file.data.get(handle.offset..).unwrap_or(&[]);
+            let len = handle.options.limit(data.len());
+            let bytes_read = buf.copy_common_bytes_from_slice(&data[..len])?;
+            handle.offset += bytes_read;
+            Ok(bytes_read)
+        })
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        enter(id, Call::Write)?;
+        delay(id)?;
+
+        with_handle(id, |handle| {
+            let len = handle.options.limit(buf.len());
+            let end = handle.offset.checked_add(len).ok_or(Error::new(EFBIG))?;
+            if end > MAX_SIZE {
+                return Err(Error::new(ENOSPC));
+            }
+
+            let mut file = handle.file.lock();
+            if file.data.len() < end {
+                file.data.resize(end, 0);
+            }
+            let offset = handle.offset;
+            buf.limit(len).ok_or(Error::new(EINVAL))?.copy_to_slice(&mut file.data[offset..end])?;
+            handle.offset = end;
+            Ok(len)
+        })
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        enter(id, Call::Fpath)?;
+        let path = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.options.path();
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+
+    fn kfutimens(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        enter(id, Call::Futimens)?;
+
+        let mut times = [TimeSpec::default(); 2];
+        for (i, chunk) in buf.in_exact_chunks(mem::size_of::<TimeSpec>()).take(2).enumerate() {
+            times[i] = unsafe { chunk.read_exact::<TimeSpec>()? };
+        }
+        with_handle(id, |handle| {
+            handle.file.lock().times = times;
+            Ok(0)
+        })
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        enter(id, Call::Fstat)?;
+
+        let (size, times) = with_handle(id, |handle| {
+            let file = handle.file.lock();
+            Ok((file.data.len(), file.times))
+        })?;
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FILE | 0o666,
+            st_size: size as u64,
+            st_nlink: 1,
+            st_atime: times[0].tv_sec as u64,
+            st_atime_nsec: times[0].tv_nsec as u32,
+            st_mtime: times[1].tv_sec as u64,
+            st_mtime_nsec: times[1].tv_nsec as u32,
+            ..Default::default()
+        })?;
+        Ok(0)
+    }
+
+    fn kfstatvfs(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        enter(id, Call::Fstatvfs)?;
+
+        let used = with_handle(id, |handle| Ok(handle.file.lock().data.len()))? as u64;
+        buf.copy_exactly(&StatVfs {
+            f_bsize: 1,
+            f_blocks: MAX_SIZE as u64,
+            f_bfree: MAX_SIZE as u64 - used,
+            f_bavail: MAX_SIZE as u64 - used,
+        })?;
+        Ok(0)
+    }
+}
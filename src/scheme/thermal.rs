@@ -0,0 +1,240 @@
+//! # Thermal
+//! `thermal:zones` lists each thermal zone, with its temperature, level and trip points (see
+//! `thermal`), all in millidegrees Celsius. `thermal:<zone>/temp` reads as the temperature of a
+//! zone, and `thermal:<zone>/passive`, `hot` and `critical` as its trip points, `none` if unset.
+//! Root can write trip points, and the temperature of ACPI zones, which the ACPI driver creates by
+//! opening them with `O_CREAT`.
+//!
+//! `thermal:<zone>/level` reads as `normal`, `passive`, `hot` or `critical`. It is readable with
+//! `EVENT_READ` once the level changed, and reads from the start again.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::event;
+use crate::sync::RwLock;
+use crate::syscall::error::*;
+use crate::syscall::flag::{EventFlags, EVENT_READ, O_ACCMODE, O_CREAT, O_RDONLY};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+use crate::thermal::{self, Source, Trip, Zone};
+
+use super::{AtomicSchemeId, KernelScheme, SchemeId};
+
+#[derive(Clone, Copy)]
+enum File {
+    Zones,
+    Temp(usize),
+    Trip(usize, Trip),
+    Level(usize),
+}
+
+struct Handle {
+    file: File,
+    offset: usize,
+    /// Whether the level changed since it was last read, for `level`
+    changed: bool,
+}
+
+static SCHEME_ID: AtomicSchemeId = AtomicSchemeId::default();
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+/// Tell the readers of the level of the zone at `index` that it changed. Returns false if they
+/// could not be told yet, as this may run in the timer interrupt.
+pub fn level_changed(index: usize) -> bool {
+    let scheme_id = SCHEME_ID.load(Ordering::SeqCst);
+    let Some(mut handles) = HANDLES.try_write() else {
+        return false;
+    };
+    for (&id, handle) in handles.iter_mut().filter(|(_, handle)| matches!(handle.file, File::Level(i) if i == index)) {
+        handle.changed = true;
+        event::trigger(scheme_id, id, EVENT_READ);
+    }
+    true
+}
+
+fn trip_name(trip: Trip) -> &'static str {
+    match trip {
+        Trip::Passive => "passive",
+        Trip::Hot => "hot",
+        Trip::Critical => "critical",
+    }
+}
+
+fn trip_value(zone: &Zone, trip: Trip) -> Option<i32> {
+    match trip {
+        Trip::Passive => zone.passive,
+        Trip::Hot => zone.hot,
+        Trip::Critical => zone.critical,
+    }
+}
+
+fn write_value(string: &mut String, value: Option<i32>) {
+    match value {
+        Some(value) => {
+            let _ = write!(string, "{}", value);
+        }
+        None => string.push_str("none"),
+    }
+}
+
+fn contents(file: File) -> Vec<u8> {
+    let mut string = String::new();
+    match file {
+        File::Zones => thermal::for_each_zone(|_, zone| {
+            let _ = write!(string, "{}: ", zone.name);
+            write_value(&mut string, zone.temp);
+            let _ = write!(string, " {}", zone.level.name());
+            for trip in [Trip::Passive, Trip::Hot, Trip::Critical] {
+                let _ = write!(string, " {}=", trip_name(trip));
+                write_value(&mut string, trip_value(zone, trip));
+            }
+            string.push('\n');
+        }),
+        File::Temp(index) => {
+            if let Some(temp) = thermal::with_zone(index, |zone| zone.temp) {
+                write_value(&mut string, temp);
+                string.push('\n');
+            }
+        }
+        File::Trip(index, trip) => {
+            if let Some(value) = thermal::with_zone(index, |zone| trip_value(zone, trip)) {
+                write_value(&mut string, value);
+                string.push('\n');
+            }
+        }
+        File::Level(index) => {
+            if let Some(level) = thermal::with_zone(index, |zone| zone.level) {
+                let _ = writeln!(string, "{}", level.name());
+            }
+        }
+    }
+    string.into_bytes()
+}
+
+pub struct ThermalScheme;
+
+impl ThermalScheme {
+    pub fn new(scheme_id: SchemeId) -> Self {
+        SCHEME_ID.store(scheme_id, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Scheme for ThermalScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let file = match path.trim_matches('/') {
+            "zones" => File::Zones,
+            path => {
+                let (name, item) = path.split_once('/').ok_or(Error::new(ENOENT))?;
+                let index = match thermal::find(name) {
+                    Some(index) => index,
+                    None if flags & O_CREAT == O_CREAT && uid == 0 => thermal::create(name).ok_or(Error::new(EINVAL))?,
+                    None if flags & O_CREAT == O_CREAT => return Err(Error::new(EACCES)),
+                    None => return Err(Error::new(ENOENT)),
+                };
+                match item {
+                    "temp" => File::Temp(index),
+                    "passive" => File::Trip(index, Trip::Passive),
+                    "hot" => File::Trip(index, Trip::Hot),
+                    "critical" => File::Trip(index, Trip::Critical),
+                    "level" => File::Level(index),
+                    _ => return Err(Error::new(ENOENT)),
+                }
+            }
+        };
+        if flags & O_ACCMODE != O_RDONLY {
+            if uid != 0 {
+                return Err(Error::new(EACCES));
+            }
+            let writable = match file {
+                File::Temp(index) => thermal::with_zone(index, |zone| zone.source == Source::Acpi).unwrap_or(false),
+                File::Trip(..) => true,
+                File::Zones | File::Level(_) => false,
+            };
+            if !writable {
+                return Err(Error::new(EROFS));
+            }
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { file, offset: 0, changed: false });
+        Ok(id)
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        let handles = HANDLES.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+        Ok(if handle.changed { EVENT_READ } else { EventFlags::empty() })
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+}
+impl KernelScheme for ThermalScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        if handle.changed {
+            handle.changed = false;
+            handle.offset = 0;
+        }
+
+        let data = contents(handle.file);
+        let bytes_read = buf.copy_common_bytes_from_slice(data.get(handle.offset..).unwrap_or(&[]))?;
+        handle.offset += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        let (index, trip) = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Temp(index) => (index, None),
+            File::Trip(index, trip) => (index, Some(trip)),
+            File::Zones | File::Level(_) => return Err(Error::new(EBADF)),
+        };
+
+        let mut bytes = [0_u8; 16];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        let value = match str::from_utf8(&bytes[..len]).map(str::trim) {
+            Ok("none") => None,
+            Ok(value) => Some(value.parse::<i32>().map_err(|_| Error::new(EINVAL))?),
+            Err(_) => return Err(Error::new(EINVAL)),
+        };
+        if !thermal::set(index, trip, value) {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(len)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let file = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file;
+        let path = match file {
+            File::Zones => String::from("thermal:zones"),
+            File::Temp(index) | File::Trip(index, _) | File::Level(index) => {
+                let name = thermal::with_zone(index, |zone| zone.name.clone()).unwrap_or_default();
+                let item = match file {
+                    File::Trip(_, trip) => trip_name(trip),
+                    File::Level(_) => "level",
+                    _ => "temp",
+                };
+                format!("thermal:{}/{}", name, item)
+            }
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
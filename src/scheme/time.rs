@@ -1,21 +1,52 @@
+//! # Time
+//! `time:<clock>` reads as the time of the clock, and writing a time to it registers a timeout.
+//! `time:clocksource` lists the clocksources, each with its rating, and whether it is `unstable`
+//! or the `current` one (see `time`). Root can write the name of one to use it instead of the best
+//! one, or `auto` to go back to it.
+
 use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::fmt::Write;
 use core::{mem, str};
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::RwLock;
 
 use crate::context::timeout;
 use crate::scheme::SchemeId;
+use crate::sync::RwLock;
 use crate::syscall::data::TimeSpec;
 use crate::syscall::error::*;
-use crate::syscall::flag::{CLOCK_REALTIME, CLOCK_MONOTONIC, EventFlags};
+use crate::syscall::flag::{CLOCK_REALTIME, CLOCK_MONOTONIC, EventFlags, O_ACCMODE, O_RDONLY};
 use crate::syscall::scheme::Scheme;
 use crate::syscall::usercopy::{UserSliceWo, UserSliceRo};
 use crate::time;
 
+#[derive(Clone, Copy)]
+enum Handle {
+    Clock(usize),
+    /// `clocksource`, read up to the offset
+    Clocksource(usize),
+}
+
 pub struct TimeScheme {
     scheme_id: SchemeId,
     next_id: AtomicUsize,
-    handles: RwLock<BTreeMap<usize, usize>>
+    handles: RwLock<BTreeMap<usize, Handle>>
+}
+
+fn clocksources() -> String {
+    let current = time::clocksource();
+    let mut string = String::new();
+    for (source, unstable) in time::clocksources() {
+        let _ = write!(string, "{} {}", source.name, source.rating);
+        if unstable {
+            string.push_str(" unstable");
+        }
+        if source.name == current {
+            string.push_str(" current");
+        }
+        string.push('\n');
+    }
+    string
 }
 
 impl TimeScheme {
@@ -29,17 +60,25 @@ impl TimeScheme {
 }
 
 impl Scheme for TimeScheme {
-    fn open(&self, path: &str, _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
-        let clock = path.parse::<usize>().or(Err(Error::new(ENOENT)))?;
-
-        match clock {
-            CLOCK_REALTIME => (),
-            CLOCK_MONOTONIC => (),
-            _ => return Err(Error::new(ENOENT))
-        }
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let handle = if path.trim_matches('/') == "clocksource" {
+            if flags & O_ACCMODE != O_RDONLY && uid != 0 {
+                return Err(Error::new(EACCES));
+            }
+            Handle::Clocksource(0)
+        } else {
+            let clock = path.parse::<usize>().or(Err(Error::new(ENOENT)))?;
+
+            match clock {
+                CLOCK_REALTIME => (),
+                CLOCK_MONOTONIC => (),
+                _ => return Err(Error::new(ENOENT))
+            }
+            Handle::Clock(clock)
+        };
 
         let id = self.next_id.fetch_add(1, Ordering::SeqCst);
-        self.handles.write().insert(id, clock);
+        self.handles.write().insert(id, handle);
 
         Ok(id)
     }
@@ -64,9 +103,17 @@ impl Scheme for TimeScheme {
 }
 impl crate::scheme::KernelScheme for TimeScheme {
     fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
-        let clock = {
-            let handles = self.handles.read();
-            *handles.get(&id).ok_or(Error::new(EBADF))?
+        let handle = *self.handles.read().get(&id).ok_or(Error::new(EBADF))?;
+        let clock = match handle {
+            Handle::Clock(clock) => clock,
+            Handle::Clocksource(offset) => {
+                let data = clocksources();
+                let bytes_read = buf.copy_common_bytes_from_slice(data.as_bytes().get(offset..).unwrap_or(&[]))?;
+                if let Some(Handle::Clocksource(offset)) = self.handles.write().get_mut(&id) {
+                    *offset += bytes_read;
+                }
+                return Ok(bytes_read);
+            }
         };
 
         let mut bytes_read = 0;
@@ -90,9 +137,18 @@ impl crate::scheme::KernelScheme for TimeScheme {
     }
 
     fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
-        let clock = {
-            let handles = self.handles.read();
-            *handles.get(&id).ok_or(Error::new(EBADF))?
+        let handle = *self.handles.read().get(&id).ok_or(Error::new(EBADF))?;
+        let clock = match handle {
+            Handle::Clock(clock) => clock,
+            Handle::Clocksource(_) => {
+                let mut bytes = [0_u8; 32];
+                let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+                let name = str::from_utf8(&bytes[..len]).map_err(|_| Error::new(EINVAL))?.trim();
+                if !time::choose(Some(name).filter(|&name| name != "auto")) {
+                    return Err(Error::new(EINVAL));
+                }
+                return Ok(len);
+            }
         };
 
         let mut bytes_written = 0;
@@ -108,12 +164,15 @@ impl crate::scheme::KernelScheme for TimeScheme {
         Ok(bytes_written)
     }
     fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
-        let clock = {
+        let handle = {
             let handles = self.handles.read();
             *handles.get(&id).ok_or(Error::new(EBADF))?
         };
 
-        let scheme_path = format!("time:{}", clock).into_bytes();
+        let scheme_path = match handle {
+            Handle::Clock(clock) => format!("time:{}", clock).into_bytes(),
+            Handle::Clocksource(_) => b"time:clocksource".to_vec(),
+        };
         let byte_count = core::cmp::min(buf.len(), scheme_path.len());
         buf.limit(byte_count).expect("must succeed").copy_from_slice(&scheme_path)?;
         Ok(byte_count)
@@ -0,0 +1,191 @@
+//! # Trace
+//! Root controls tracing (see `trace`) by writing to `trace:ctl`:
+//!
+//! - `enable <event>...` enables the given events, or every supported one for `all`
+//! - `disable <event>...` disables the given events, or every one for `all`
+//! - `clear` discards the events recorded so far
+//!
+//! `trace:events` lists each event as `<name> on` or `<name> off`. `trace:trace` reads the events
+//! recorded on all CPUs merged in timestamp order, and `trace:cpu<N>` those recorded on CPU `N`,
+//! as of when they were opened. Enabling `function` fails with `EOPNOTSUPP` unless the kernel was
+//! built for function tracing.
+//!
+//! `trace:fxt` reads the events recorded on all CPUs as a Fuchsia trace, to be opened in Perfetto,
+//! and fails to open with `EOPNOTSUPP` if the frequency of the timestamps is not known.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sync::RwLock;
+use crate::syscall::data::Stat;
+use crate::syscall::error::*;
+use crate::syscall::flag::{MODE_FILE, O_ACCMODE, O_RDONLY};
+use crate::syscall::scheme::{calc_seek_offset_usize, Scheme};
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+use crate::trace::{self, Event};
+
+use super::KernelScheme;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum File {
+    Ctl,
+    Events,
+    Trace,
+    Fxt,
+    Cpu(usize),
+}
+
+struct Handle {
+    file: File,
+    /// The events, read when opened
+    data: Vec<u8>,
+    offset: usize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn events() -> Vec<u8> {
+    let mut string = String::new();
+    for event in Event::ALL {
+        let _ = writeln!(string, "{} {}", event.name(), if trace::enabled(event) { "on" } else { "off" });
+    }
+    string.into_bytes()
+}
+
+fn set_enabled<'a>(names: impl Iterator<Item = &'a str>, enabled: bool) -> Result<()> {
+    let mut names = names.peekable();
+    if names.peek().is_none() {
+        return Err(Error::new(EINVAL));
+    }
+    for name in names {
+        if name == "all" {
+            for event in Event::ALL {
+                if event.supported() {
+                    trace::set_enabled(event, enabled);
+                }
+            }
+            continue;
+        }
+        let event = Event::from_name(name).ok_or(Error::new(EINVAL))?;
+        if !trace::set_enabled(event, enabled) {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+    }
+    Ok(())
+}
+
+fn control(command: &str) -> Result<()> {
+    let mut words = command.split_whitespace();
+    match words.next() {
+        Some("enable") => set_enabled(words, true),
+        Some("disable") => set_enabled(words, false),
+        Some("clear") if words.next().is_none() => {
+            trace::clear();
+            Ok(())
+        }
+        _ => Err(Error::new(EINVAL)),
+    }
+}
+
+pub struct TraceScheme;
+
+impl Scheme for TraceScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let file = match path.trim_matches('/') {
+            "ctl" => File::Ctl,
+            "events" => File::Events,
+            "trace" => File::Trace,
+            "fxt" => File::Fxt,
+            name => {
+                let cpu = name.strip_prefix("cpu").and_then(|cpu| cpu.parse::<usize>().ok()).ok_or(Error::new(ENOENT))?;
+                if cpu >= crate::cpu_count() {
+                    return Err(Error::new(ENOENT));
+                }
+                File::Cpu(cpu)
+            }
+        };
+        // Events hold kernel addresses
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        if flags & O_ACCMODE != O_RDONLY && file != File::Ctl {
+            return Err(Error::new(EROFS));
+        }
+
+        let data = match file {
+            File::Ctl => Vec::new(),
+            File::Events => events(),
+            File::Trace => trace::snapshot(None),
+            File::Fxt => trace::fxt().ok_or(Error::new(EOPNOTSUPP))?,
+            File::Cpu(cpu) => trace::snapshot(Some(cpu)),
+        };
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Handle { file, data, offset: 0 });
+        Ok(id)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<isize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let new_offset = calc_seek_offset_usize(handle.offset, pos, whence, handle.data.len())?;
+        handle.offset = new_offset as usize;
+        Ok(new_offset)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+impl KernelScheme for TraceScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let bytes_read = buf.copy_common_bytes_from_slice(handle.data.get(handle.offset..).unwrap_or(&[]))?;
+        handle.offset += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        if HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file != File::Ctl {
+            return Err(Error::new(EBADF));
+        }
+        let mut bytes = [0_u8; 256];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        control(str::from_utf8(&bytes[..len]).or(Err(Error::new(EINVAL)))?)?;
+        Ok(len)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Ctl => String::from("trace:ctl"),
+            File::Events => String::from("trace:events"),
+            File::Trace => String::from("trace:trace"),
+            File::Fxt => String::from("trace:fxt"),
+            File::Cpu(cpu) => format!("trace:cpu{}", cpu),
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let handles = HANDLES.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FILE | if handle.file == File::Ctl { 0o200 } else { 0o400 },
+            st_size: handle.data.len() as u64,
+            ..Default::default()
+        })?;
+        Ok(0)
+    }
+}
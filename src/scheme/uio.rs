@@ -0,0 +1,203 @@
+//! # UIO devices
+//! A device object bundling a region of MMIO registers with an IRQ, so that simple devices can be
+//! driven entirely from userspace. A privileged manager creates the device by opening
+//! `uio:<phys>+<size>/<irq>` (addresses in hex, the IRQ in decimal) with `O_CREAT`, which reserves
+//! the IRQ, and passes the resulting file descriptor on to the driver. The driver can then map the
+//! registers (uncacheable) with `fmap`, and read and acknowledge the IRQ with the same protocol as
+//! `irq:`. The IRQ is released when the last file descriptor is closed.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::mem;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use rmm::PhysicalAddress;
+
+use crate::arch::interrupt::{is_reserved, set_reserved};
+use crate::context::memory::{AddrSpace, Grant};
+use crate::event;
+use crate::memory::{Frame, PAGE_SIZE};
+use crate::sync::RwLock;
+use crate::syscall::data::{Map, Stat};
+use crate::syscall::error::*;
+use crate::syscall::flag::{EventFlags, EVENT_READ, MODE_CHR, O_CREAT};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{validate_region, UserSliceRo, UserSliceWo};
+
+use super::irq::{consume, irq_to_vector, BASE_IRQ_COUNT, COUNTS, TOTAL_IRQ_COUNT};
+use super::memory::MemoryType;
+use super::{AtomicSchemeId, KernelScheme, SchemeId};
+
+static SCHEME_ID: AtomicSchemeId = AtomicSchemeId::default();
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Device>> = RwLock::new(BTreeMap::new());
+
+struct Device {
+    phys: usize,
+    size: usize,
+    irq: u8,
+    /// The last IRQ count acknowledged by the driver
+    ack: AtomicUsize,
+}
+
+/// Notify the drivers of devices using `irq`, called by the IRQ scheme
+pub fn irq_trigger(irq: u8) {
+    for (id, _device) in HANDLES.read().iter().filter(|(_, device)| device.irq == irq) {
+        event::trigger(SCHEME_ID.load(Ordering::SeqCst), *id, EVENT_READ);
+    }
+}
+
+pub struct UioScheme;
+
+impl UioScheme {
+    pub fn new(scheme_id: SchemeId) -> Self {
+        SCHEME_ID.store(scheme_id, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Scheme for UioScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        // Devices can only be created, and are then shared by passing the file descriptor
+        if flags & O_CREAT == 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let (region, irq) = path.trim_start_matches('/').split_once('/').ok_or(Error::new(ENOENT))?;
+        let (phys, size) = region.split_once('+').ok_or(Error::new(ENOENT))?;
+        let phys = usize::from_str_radix(phys, 16).or(Err(Error::new(ENOENT)))?;
+        let size = usize::from_str_radix(size, 16).or(Err(Error::new(ENOENT)))?;
+        let irq = u8::from_str(irq).or(Err(Error::new(ENOENT)))?;
+
+        // TODO: Check physical_address against the real MAXPHYADDR.
+        if phys % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 || size == 0 || phys.checked_add(size).map_or(true, |end| end as u64 > 1 << 52) {
+            return Err(Error::new(EINVAL));
+        }
+        if irq >= TOTAL_IRQ_COUNT {
+            return Err(Error::new(ENOENT));
+        }
+        // Legacy IRQs are shared, like in the IRQ scheme
+        if irq >= BASE_IRQ_COUNT {
+            if is_reserved(0, irq_to_vector(irq)) {
+                return Err(Error::new(EEXIST));
+            }
+            set_reserved(0, irq_to_vector(irq), true);
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        HANDLES.write().insert(id, Device {
+            phys,
+            size,
+            irq,
+            ack: AtomicUsize::new(0),
+        });
+        Ok(id)
+    }
+
+    fn fmap(&self, id: usize, map: &Map) -> Result<usize> {
+        self.kfmap(id, &AddrSpace::current()?, map, false)
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(EventFlags::empty())
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        let device = HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        if device.irq >= BASE_IRQ_COUNT {
+            set_reserved(0, irq_to_vector(device.irq), false);
+        }
+        Ok(0)
+    }
+}
+impl KernelScheme for UioScheme {
+    fn kfmap(&self, id: usize, addr_space: &Arc<RwLock<AddrSpace>>, map: &Map, _consume: bool) -> Result<usize> {
+        let phys = {
+            let handles = HANDLES.read();
+            let device = handles.get(&id).ok_or(Error::new(EBADF))?;
+            if map.offset % PAGE_SIZE != 0 || map.offset.checked_add(map.size).map_or(true, |end| end > device.size) {
+                return Err(Error::new(EINVAL));
+            }
+            device.phys + map.offset
+        };
+        let (requested_page, page_count) = validate_region(map.address, map.size)?;
+
+        addr_space.write().mmap((map.address != 0).then_some(requested_page), page_count, map.flags, |dst_page, page_flags, dst_mapper, dst_flusher| {
+            Grant::physmap(
+                Frame::containing_address(PhysicalAddress::new(phys)),
+                dst_page,
+                page_count,
+                MemoryType::Uncacheable.page_flags(page_flags),
+                dst_mapper,
+                dst_flusher,
+            )
+        }).map(|page| page.start_address().data())
+    }
+
+    fn kread(&self, id: usize, buffer: UserSliceWo) -> Result<usize> {
+        let handles = HANDLES.read();
+        let device = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        if buffer.len() < mem::size_of::<usize>() {
+            return Err(Error::new(EINVAL));
+        }
+        let current = COUNTS.lock()[device.irq as usize];
+        if device.ack.load(Ordering::SeqCst) != current {
+            buffer.write_usize(current)?;
+            Ok(mem::size_of::<usize>())
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn kwrite(&self, id: usize, buffer: UserSliceRo) -> Result<usize> {
+        let handles = HANDLES.read();
+        let device = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        if buffer.len() < mem::size_of::<usize>() {
+            return Err(Error::new(EINVAL));
+        }
+        let ack = buffer.read_usize()?;
+        let current = COUNTS.lock()[device.irq as usize];
+        if ack == current {
+            consume(device.irq, &device.ack, ack, true);
+            Ok(mem::size_of::<usize>())
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let handles = HANDLES.read();
+        let device = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_CHR | 0o600,
+            st_size: device.size as u64,
+            st_blksize: PAGE_SIZE as u32,
+            st_ino: id as u64,
+            st_nlink: 1,
+            ..Default::default()
+        })?;
+        Ok(0)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let handles = HANDLES.read();
+        let device = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        buf.copy_common_bytes_from_slice(format!("uio:{:x}+{:x}/{}", device.phys, device.size, device.irq).as_bytes())
+    }
+}
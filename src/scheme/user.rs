@@ -1,12 +1,13 @@
 use alloc::sync::{Arc, Weak};
 use alloc::boxed::Box;
-use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use alloc::collections::{BTreeMap, VecDeque};
 use syscall::{SKMSG_FRETURNFD, CallerCtx};
-use core::sync::atomic::{AtomicBool, Ordering};
-use core::{mem, usize};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::{cmp, mem, usize};
 use core::convert::TryFrom;
-use spin::{Mutex, RwLock};
 
+use crate::common::histogram::Histogram;
 use crate::context::{self, Context, BorrowedHtBuf};
 use crate::context::file::{FileDescriptor, FileDescription};
 use crate::context::memory::{AddrSpace, DANGLING, Grant, Region, GrantFileRef};
@@ -14,16 +15,85 @@ use crate::event;
 use crate::paging::KernelMapper;
 use crate::paging::{PAGE_SIZE, Page, VirtualAddress};
 use crate::scheme::{AtomicSchemeId, SchemeId};
-use crate::sync::{WaitQueue, WaitMap};
+use crate::sync::{Mutex, RwLock, WaitMap, WaitQueue};
+use crate::time;
+use crate::syscall::abi::{
+    SchemeHandshake, SchemePacket, BOOST_USER, SCHEME_CAP_ALL, SCHEME_CAP_FMAP, SCHEME_CAP_RETURN_FD,
+    SCHEME_HANDSHAKE_MAGIC, SCHEME_PROTOCOL_V1, SCHEME_PROTOCOL_V2,
+};
 use crate::syscall::data::{Map, Packet};
 use crate::syscall::error::*;
-use crate::syscall::flag::{EventFlags, EVENT_READ, O_NONBLOCK, PROT_READ, PROT_WRITE};
+use crate::syscall::flag::{EventFlags, MapFlags, EVENT_READ, O_NONBLOCK, PROT_READ, PROT_WRITE};
 use crate::syscall::number::*;
 use crate::syscall::scheme::Scheme;
 use crate::syscall::usercopy::{UserSlice, UserSliceWo, UserSliceRo};
 
 use super::{FileHandle, OpenResult, KernelScheme, current_caller_ctx};
 
+/// How often `UserInner::revoke` checks whether the provider has drained its requests
+const REVOKE_POLL_INTERVAL: u128 = 10_000_000;
+
+/// Maximum number of requests returned by a single read of a v2 provider
+const MAX_PACKETS_PER_READ: usize = 64;
+
+/// The protocol spoken on a provider's `:name` handle, see `SchemeHandshake`
+#[derive(Clone, Copy, Debug)]
+enum Protocol {
+    /// Nothing has been read or written yet, so the provider may still ask for another version
+    Initial,
+    /// The answer to the handshake, returned by the next read
+    Answer(SchemeHandshake),
+    V1,
+    V2 { capabilities: u32 },
+}
+
+/// Convert a request to v2, where words are sign-extended so that errors keep their meaning
+fn encode(packet: &Packet) -> SchemePacket {
+    let word = |word: usize| (word as isize as i64 as u64).to_le();
+    SchemePacket {
+        id: packet.id.to_le(),
+        pid: word(packet.pid),
+        uid: packet.uid.to_le(),
+        gid: packet.gid.to_le(),
+        a: word(packet.a),
+        b: word(packet.b),
+        c: word(packet.c),
+        d: word(packet.d),
+        flags: 0,
+    }
+}
+
+/// Convert a response from v2, failing if a word does not fit
+fn decode(packet: &SchemePacket) -> Result<Packet> {
+    if packet.flags != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let word = |word: u64| isize::try_from(u64::from_le(word) as i64).map(|word| word as usize).map_err(|_| Error::new(EINVAL));
+    Ok(Packet {
+        id: u64::from_le(packet.id),
+        pid: word(packet.pid)?,
+        uid: u32::from_le(packet.uid),
+        gid: u32::from_le(packet.gid),
+        a: word(packet.a)?,
+        b: word(packet.b)?,
+        c: word(packet.c)?,
+        d: word(packet.d)?,
+    })
+}
+
+/// The kernel's answer to `handshake`
+fn answer(handshake: &SchemeHandshake) -> Result<SchemeHandshake> {
+    let version = u32::from_le(handshake.version);
+    if version < SCHEME_PROTOCOL_V1 {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(SchemeHandshake {
+        magic: SCHEME_HANDSHAKE_MAGIC.to_le(),
+        version: cmp::min(version, SCHEME_PROTOCOL_V2).to_le(),
+        capabilities: (u32::from_le(handshake.capabilities) & SCHEME_CAP_ALL).to_le(),
+    })
+}
+
 pub struct UserInner {
     root_id: SchemeId,
     handle_id: usize,
@@ -36,6 +106,9 @@ pub struct UserInner {
     fmap: Mutex<BTreeMap<u64, (Weak<RwLock<Context>>, FileDescriptor, Map)>>,
     done: WaitMap<u64, Response>,
     unmounting: AtomicBool,
+    revoked: AtomicBool,
+    protocol: Mutex<Protocol>,
+    pub stats: UserStats,
 }
 pub enum Response {
     Regular(usize),
@@ -56,6 +129,9 @@ impl UserInner {
             fmap: Mutex::new(BTreeMap::new()),
             done: WaitMap::new(),
             unmounting: AtomicBool::new(false),
+            revoked: AtomicBool::new(false),
+            protocol: Mutex::new(Protocol::Initial),
+            stats: UserStats::default(),
         }
     }
 
@@ -73,6 +149,44 @@ impl UserInner {
         Ok(0)
     }
 
+    /// Unmount the scheme, give the provider until `timeout` nanoseconds have passed to answer the
+    /// requests it has already been sent, and then fail whatever is still outstanding with EIO.
+    /// After this, every call to the scheme fails with EIO and responses from the provider are
+    /// refused. Removing the scheme from its namespace is left to the caller.
+    pub fn revoke(&self, timeout: u128) -> Result<()> {
+        self.unmount()?;
+
+        let deadline = time::monotonic() + timeout;
+        while self.stats.in_flight() > 0 && time::monotonic() < deadline {
+            // Poll, as the provider may also never wake us
+            {
+                let context_lock = context::current()?;
+                let mut context = context_lock.write();
+                context.wake = Some(cmp::min(deadline, time::monotonic() + REVOKE_POLL_INTERVAL));
+                context.block("UserInner::revoke");
+            }
+            unsafe { context::switch(); }
+        }
+
+        if self.revoked.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        self.todo.inner.lock().clear();
+        for id in self.stats.abandon() {
+            self.done.send(id, Response::Regular(Error::mux(Err(Error::new(EIO)))));
+        }
+        for (_id, (_context, desc, _map)) in mem::take(&mut *self.fmap.lock()) {
+            let _ = desc.close();
+        }
+
+        Ok(())
+    }
+
+    pub fn is_revoked(&self) -> bool {
+        self.revoked.load(Ordering::SeqCst)
+    }
+
     fn next_id(&self) -> u64 {
         let mut guard = self.next_id.lock();
         let id = *guard;
@@ -107,16 +221,32 @@ impl UserInner {
     }
 
     fn call_extended_inner(&self, packet: Packet) -> Result<Response> {
+        if self.revoked.load(Ordering::SeqCst) {
+            return Err(Error::new(EIO));
+        }
         if self.unmounting.load(Ordering::SeqCst) {
             return Err(Error::new(ENODEV));
         }
 
         let id = packet.id;
+        let start = crate::time::monotonic();
 
-        self.todo.send(packet);
+        {
+            // Queue under the timing lock, so that `queued` stays in the same order as `todo`
+            let mut timing = self.stats.timing.lock();
+            timing.queued.push_back((id, start));
+            self.todo.send(packet);
+
+            let depth = timing.queued.len();
+            self.stats.queue_depth.record(depth as u64);
+            self.stats.max_queue_depth.fetch_max(depth, Ordering::Relaxed);
+        }
         event::trigger(self.root_id, self.handle_id, EVENT_READ);
 
-        Ok(self.done.receive(&id, "UserInner::call_inner"))
+        let response = self.done.receive(&id, "UserInner::call_inner");
+        self.stats.round_trip.record((crate::time::monotonic() - start) as u64);
+
+        Ok(response)
     }
 
     /// Map a readable structure to the scheme's userspace and return the
@@ -207,9 +337,12 @@ impl UserInner {
             .split_at(core::cmp::min(align_offset, user_buf.len()))
             .expect("split must succeed");
 
+        // Swapped out pages are read back before the address spaces are locked
+        drop(AddrSpace::write_populated(&cur_space_lock, Region::new(src_page.start_address(), page_count * PAGE_SIZE))?);
+
         let mut dst_space = dst_space_lock.write();
 
-        let free_region = dst_space.grants.find_free(dst_space.mmap_min, page_count * PAGE_SIZE).ok_or(Error::new(ENOMEM))?;
+        let free_region = dst_space.find_free(page_count * PAGE_SIZE, PAGE_SIZE, MapFlags::empty()).ok_or(Error::new(ENOMEM))?;
 
         let first_dst_page = Page::containing_address(free_region.start_address());
 
@@ -258,10 +391,17 @@ impl UserInner {
 
         let (_middle_part_of_buf, tail_part_of_buf) = middle_tail_part_of_buf.split_at(middle_page_count * PAGE_SIZE).expect("split must succeed");
 
+        let middle_region = Region::new(first_middle_src_page.start_address(), middle_page_count * PAGE_SIZE);
+
         if middle_page_count > 0 {
             dst_space.mmap(Some(first_middle_dst_page), middle_page_count, map_flags, move |dst_page, page_flags, mapper, flusher| {
                 let mut cur_space = cur_space_lock.write();
-                Ok(Grant::borrow(first_middle_src_page, dst_page, middle_page_count, page_flags, None, &mut cur_space.table.utable, mapper, flusher)?)
+                cur_space.populate(middle_region)?;
+                let mut grant = Grant::borrow(first_middle_src_page, dst_page, middle_page_count, page_flags, None, &mut cur_space.table.utable, mapper, flusher)?;
+                // The pages must stay resident while the scheme has access to them
+                cur_space.lend(middle_region);
+                grant.lender = Some((Arc::downgrade(&cur_space_lock), middle_region));
+                Ok(grant)
             })?;
         }
 
@@ -314,15 +454,53 @@ impl UserInner {
         })
     }
 
+    /// Whether the provider implements `capability`, which v1 providers are assumed to
+    fn has_capability(&self, capability: u32) -> bool {
+        match *self.protocol.lock() {
+            Protocol::V2 { capabilities } => capabilities & capability == capability,
+            Protocol::Answer(answer) if u32::from_le(answer.version) == SCHEME_PROTOCOL_V2 => {
+                u32::from_le(answer.capabilities) & capability == capability
+            }
+            _ => true,
+        }
+    }
+
     pub fn read(&self, buf: UserSliceWo) -> Result<usize> {
+        let protocol = {
+            let mut protocol = self.protocol.lock();
+            if let Protocol::Initial = *protocol {
+                *protocol = Protocol::V1;
+            }
+            *protocol
+        };
+        if let Protocol::Answer(answer) = protocol {
+            buf.copy_exactly(&answer)?;
+            *self.protocol.lock() = if u32::from_le(answer.version) == SCHEME_PROTOCOL_V2 {
+                Protocol::V2 { capabilities: u32::from_le(answer.capabilities) }
+            } else {
+                Protocol::V1
+            };
+            return Ok(mem::size_of::<SchemeHandshake>());
+        }
+        let v2 = matches!(protocol, Protocol::V2 { .. });
+
         // If O_NONBLOCK is used, do not block
         let nonblock = self.flags & O_NONBLOCK == O_NONBLOCK;
         // If unmounting, do not block so that EOF can be returned immediately
         let block = !(nonblock || self.unmounting.load(Ordering::SeqCst));
 
-        match self.todo.receive_into_user(buf, block, "UserInner::read") {
+        let received = if v2 {
+            self.receive_v2(buf, block)
+        } else {
+            self.todo.receive_into_user(buf, block, "UserInner::read")
+        };
+        match received {
             // If we received requests, return them to the scheme handler
-            Ok(byte_count) => Ok(byte_count),
+            Ok(byte_count) => {
+                let packet_size = if v2 { mem::size_of::<SchemePacket>() } else { mem::size_of::<Packet>() };
+                self.stats.picked_up(byte_count / packet_size);
+                Ok(byte_count)
+            }
             // If there were no requests and we were unmounting, return EOF
             Err(Error { errno: EAGAIN }) if self.unmounting.load(Ordering::SeqCst) => Ok(0),
             // If there were no requests and O_NONBLOCK was used (EAGAIN), or some other error
@@ -331,35 +509,92 @@ impl UserInner {
         }
     }
 
+    /// Receive requests into `buf` as `SchemePacket`s
+    fn receive_v2(&self, buf: UserSliceWo, block: bool) -> Result<usize> {
+        let packet_size = mem::size_of::<SchemePacket>();
+        let mut packets = vec![Packet::default(); cmp::min(buf.len() / packet_size, MAX_PACKETS_PER_READ)];
+        if packets.is_empty() {
+            return if buf.is_empty() { Ok(0) } else { Err(Error::new(EINVAL)) };
+        }
+
+        let count = self.todo.receive_into(&mut packets, block, "UserInner::read").ok_or(Error::new(EINTR))?;
+        if count == 0 {
+            return Err(Error::new(EAGAIN));
+        }
+        for (i, chunk) in buf.in_exact_chunks(packet_size).take(count).enumerate() {
+            if let Err(error) = chunk.copy_exactly(&encode(&packets[i])) {
+                // Requeue what could not be delivered, so that its callers are not left waiting
+                let mut todo = self.todo.inner.lock();
+                for packet in packets[i..count].iter().rev() {
+                    todo.push_front(*packet);
+                }
+                return if i > 0 { Ok(i * packet_size) } else { Err(error) };
+            }
+        }
+        Ok(count * packet_size)
+    }
+
     pub fn write(&self, buf: UserSliceRo) -> Result<usize> {
+        // Responses would go to callers that have already been failed
+        if self.revoked.load(Ordering::SeqCst) {
+            return Err(Error::new(ENODEV));
+        }
+
+        let handshake = if buf.len() == mem::size_of::<SchemeHandshake>() {
+            Some(unsafe { buf.read_exact::<SchemeHandshake>()? }).filter(|handshake| u64::from_le(handshake.magic) == SCHEME_HANDSHAKE_MAGIC)
+        } else {
+            None
+        };
+        let v2 = {
+            let mut protocol = self.protocol.lock();
+            match (*protocol, handshake) {
+                (Protocol::Initial, Some(handshake)) => {
+                    *protocol = Protocol::Answer(answer(&handshake)?);
+                    return Ok(mem::size_of::<SchemeHandshake>());
+                }
+                // The protocol can only be chosen before anything else is read or written
+                (_, Some(_)) => return Err(Error::new(EINVAL)),
+                (Protocol::Initial, None) => *protocol = Protocol::V1,
+                _ => (),
+            }
+            matches!(*protocol, Protocol::V2 { .. })
+        };
+
+        let packet_size = if v2 { mem::size_of::<SchemePacket>() } else { mem::size_of::<Packet>() };
         let mut packets_read = 0;
 
-        for chunk in buf.in_exact_chunks(mem::size_of::<Packet>()) {
-            match self.handle_packet(&unsafe { chunk.read_exact::<Packet>()? }) {
+        for chunk in buf.in_exact_chunks(packet_size) {
+            let packet = if v2 {
+                unsafe { chunk.read_exact::<SchemePacket>() }.and_then(|packet| decode(&packet))
+            } else {
+                unsafe { chunk.read_exact::<Packet>() }
+            };
+            match packet.and_then(|packet| self.handle_packet(&packet)) {
                 Ok(()) => packets_read += 1,
                 Err(_) if packets_read > 0 => break,
                 Err(error) => return Err(error),
             }
         }
 
-        Ok(packets_read * mem::size_of::<Packet>())
+        Ok(packets_read * packet_size)
     }
     fn handle_packet(&self, packet: &Packet) -> Result<()> {
         if packet.id == 0 {
             // TODO: Simplify logic by using SKMSG with packet.id being ignored?
             match packet.a {
-                SYS_FEVENT => event::trigger(self.scheme_id.load(Ordering::SeqCst), packet.b, EventFlags::from_bits_truncate(packet.c)),
+                SYS_FEVENT => event::trigger_boosted(self.scheme_id.load(Ordering::SeqCst), packet.b, EventFlags::from_bits_truncate(packet.c), BOOST_USER),
                 _ => log::warn!("Unknown scheme -> kernel message {}", packet.a)
             }
         } else if Error::demux(packet.a) == Err(Error::new(ESKMSG)) {
             // The reason why the new ESKMSG mechanism was introduced, is that passing packet IDs
             // in packet.id is much cleaner than having to convert it into 1 or 2 usizes etc.
             match packet.b {
-                SKMSG_FRETURNFD => {
+                SKMSG_FRETURNFD if self.has_capability(SCHEME_CAP_RETURN_FD) => {
                     let fd = packet.c;
 
                     let desc = context::current()?.read().remove_file(FileHandle::from(fd)).ok_or(Error::new(EINVAL))?.description;
 
+                    self.stats.responded(packet.id);
                     self.done.send(packet.id, Response::Fd(desc));
                 }
                 _ => return Err(Error::new(EINVAL)),
@@ -383,6 +618,12 @@ impl UserInner {
 
                     let file_ref = GrantFileRef { desc, offset: map.offset, flags: map.flags };
 
+                    // Swapped out pages are read back before the address spaces are locked, any
+                    // error is reported when borrowing them below
+                    if let Ok(src_space_lock) = AddrSpace::current() {
+                        let _ = AddrSpace::write_populated(&src_space_lock, Region::new(src_page.start_address(), map.size.div_ceil(PAGE_SIZE) * PAGE_SIZE));
+                    }
+
                     if let Some(context_lock) = context_weak.upgrade() {
                         let context = context_lock.read();
                         let mut addr_space = context.addr_space()?.write();
@@ -391,7 +632,15 @@ impl UserInner {
                         let page_count = map.size.div_ceil(PAGE_SIZE);
 
                         let res = addr_space.mmap(dst_page, page_count, map.flags, move |dst_page, flags, mapper, flusher| {
-                            Ok(Grant::borrow(src_page, dst_page, page_count, flags, Some(file_ref), &mut AddrSpace::current()?.write().table.utable, mapper, flusher)?)
+                            let src_space_lock = AddrSpace::current()?;
+                            let mut src_space = src_space_lock.write();
+                            let src_region = Region::new(src_page.start_address(), page_count * PAGE_SIZE);
+                            src_space.populate(src_region)?;
+                            let mut grant = Grant::borrow(src_page, dst_page, page_count, flags, Some(file_ref), &mut src_space.table.utable, mapper, flusher)?;
+                            // Lent until the mapping is unmapped
+                            src_space.lend(src_region);
+                            grant.lender = Some((Arc::downgrade(&src_space_lock), src_region));
+                            Ok(grant)
                         });
                         retcode = Error::mux(res.map(|grant_start_page| {
                             addr_space.grants.funmap.insert(
@@ -410,6 +659,7 @@ impl UserInner {
                 }
             }
 
+            self.stats.responded(packet.id);
             self.done.send(packet.id, Response::Regular(retcode));
         }
 
@@ -425,6 +675,9 @@ impl UserInner {
     }
 
     fn fmap_inner(&self, file: usize, map: &Map) -> Result<usize> {
+        if !self.has_capability(SCHEME_CAP_FMAP) {
+            return Err(Error::new(EOPNOTSUPP));
+        }
         if map.address % PAGE_SIZE != 0 {
             return Err(Error::new(EINVAL));
         }
@@ -550,6 +803,63 @@ fn page_range_containing(base: usize, size: usize) -> (Page, usize, usize) {
     (first_page, (size + offset).div_ceil(PAGE_SIZE), offset)
 }
 
+/// Latency and queue depth metrics of a user scheme, which separate the time a request spends
+/// waiting for the scheme daemon from the time the daemon takes to serve it. Exposed through
+/// `sys:scheme_stats`.
+#[derive(Default)]
+pub struct UserStats {
+    /// Nanoseconds from a request being queued until the daemon reads it
+    pub queue_latency: Histogram,
+    /// Nanoseconds from the daemon reading a request until it responds
+    pub service_latency: Histogram,
+    /// Nanoseconds from a request being queued until the caller has its response
+    pub round_trip: Histogram,
+    /// Number of requests not yet read by the daemon, sampled as each request is queued
+    pub queue_depth: Histogram,
+    pub max_queue_depth: AtomicUsize,
+    timing: Mutex<RequestTiming>,
+}
+
+#[derive(Default)]
+struct RequestTiming {
+    /// Requests waiting to be read by the daemon, in queue order, with the time they were queued
+    queued: VecDeque<(u64, u128)>,
+    /// Requests read by the daemon, with the time they were read
+    serving: BTreeMap<u64, u128>,
+}
+
+impl UserStats {
+    /// Requests currently queued or being served
+    pub fn in_flight(&self) -> usize {
+        let timing = self.timing.lock();
+        timing.queued.len() + timing.serving.len()
+    }
+
+    fn picked_up(&self, count: usize) {
+        let now = crate::time::monotonic();
+        let mut timing = self.timing.lock();
+        for _ in 0..count {
+            let Some((id, queued)) = timing.queued.pop_front() else { break };
+            self.queue_latency.record((now - queued) as u64);
+            timing.serving.insert(id, now);
+        }
+    }
+
+    /// Forget every outstanding request, returning their IDs
+    fn abandon(&self) -> Vec<u64> {
+        let mut timing = self.timing.lock();
+        let mut ids: Vec<u64> = timing.queued.drain(..).map(|(id, _)| id).collect();
+        ids.extend(mem::take(&mut timing.serving).into_keys());
+        ids
+    }
+
+    fn responded(&self, id: u64) {
+        if let Some(picked_up) = self.timing.lock().serving.remove(&id) {
+            self.service_latency.record((crate::time::monotonic() - picked_up) as u64);
+        }
+    }
+}
+
 /// `UserInner` has to be wrapped
 pub struct UserScheme {
     inner: Weak<UserInner>
@@ -696,6 +1006,9 @@ impl Scheme for UserScheme {
     }
 }
 impl KernelScheme for UserScheme {
+    fn as_user_inner(&self) -> Result<Arc<UserInner>> {
+        self.inner.upgrade().ok_or(Error::new(ENODEV))
+    }
     fn kopen(&self, path: &str, flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
         let address = inner.copy_and_capture_tail(path.as_bytes())?;
@@ -0,0 +1,180 @@
+//! # Watchdog
+//! Opening `watchdog:` for writing starts the watchdog (see `watchdog`), and each write to it
+//! pings it, recording the writer. Only one handle can be open at a time. The watchdog is only
+//! stopped when the handle is closed right after writing `V`, so that a daemon that crashes does
+//! not stop it.
+//!
+//! `watchdog:timeout` reads as the timeout in seconds, which root can write. `watchdog:status`
+//! lists the hardware watchdog, whether the NMI watchdog runs, and the context that pinged last.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::context;
+use crate::sync::RwLock;
+use crate::syscall::error::*;
+use crate::syscall::flag::{O_ACCMODE, O_RDONLY};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{UserSliceRo, UserSliceWo};
+use crate::watchdog::{self, Owner};
+
+use super::KernelScheme;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum File {
+    Device,
+    Timeout,
+    Status,
+}
+
+struct Handle {
+    file: File,
+    offset: usize,
+    /// Whether `V` was written last, for `Device`
+    magic: bool,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn yes_no(value: bool) -> &'static str {
+    if value { "yes" } else { "no" }
+}
+
+fn contents(file: File) -> Vec<u8> {
+    let mut string = String::new();
+    match file {
+        File::Device => (),
+        File::Timeout => {
+            let _ = writeln!(string, "{}", watchdog::timeout());
+        }
+        File::Status => {
+            let driver = watchdog::driver();
+            let _ = writeln!(string, "driver: {}", driver.map_or("none", |driver| driver.name));
+            let _ = writeln!(string, "nmi: {}", yes_no(watchdog::nmi_running()));
+            let _ = writeln!(string, "armed: {}", yes_no(watchdog::armed()));
+            let _ = writeln!(string, "timeout: {}", watchdog::timeout());
+            match watchdog::last_ping() {
+                Some((owner, seconds)) => {
+                    let _ = writeln!(string, "last ping: {} s ago by {} ({})", seconds, owner.pid, owner.name);
+                }
+                None => string.push_str("last ping: none\n"),
+            }
+            let _ = writeln!(string, "caused last reset: {}", yes_no(watchdog::caused_reset()));
+        }
+    }
+    string.into_bytes()
+}
+
+/// The current context, as the owner of a ping
+fn owner() -> Result<Owner> {
+    let context_lock = context::current()?;
+    let context = context_lock.read();
+    Ok(Owner {
+        pid: context.id.into(),
+        name: context.name.to_string(),
+    })
+}
+
+pub struct WatchdogScheme;
+
+impl Scheme for WatchdogScheme {
+    fn open(&self, path: &str, flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let file = match path.trim_matches('/') {
+            "" => File::Device,
+            "timeout" => File::Timeout,
+            "status" => File::Status,
+            _ => return Err(Error::new(ENOENT)),
+        };
+        if flags & O_ACCMODE != O_RDONLY {
+            if uid != 0 {
+                return Err(Error::new(EACCES));
+            }
+            if file == File::Status {
+                return Err(Error::new(EROFS));
+            }
+        } else if file == File::Device {
+            return Err(Error::new(EACCES));
+        }
+
+        let mut handles = HANDLES.write();
+        if file == File::Device {
+            if handles.values().any(|handle| handle.file == File::Device) {
+                return Err(Error::new(EBUSY));
+            }
+            watchdog::start(owner()?);
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        handles.insert(id, Handle { file, offset: 0, magic: false });
+        Ok(id)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        let handle = HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        if handle.file == File::Device {
+            if handle.magic {
+                watchdog::stop();
+            } else {
+                log::warn!("watchdog: closed without writing V, not stopping");
+            }
+        }
+        Ok(0)
+    }
+}
+impl KernelScheme for WatchdogScheme {
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let data = contents(handle.file);
+        let bytes_read = buf.copy_common_bytes_from_slice(data.get(handle.offset..).unwrap_or(&[]))?;
+        handle.offset += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        let file = HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file;
+
+        let mut bytes = [0_u8; 16];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        match file {
+            File::Device => {
+                watchdog::ping(owner()?);
+                let magic = bytes[..len].contains(&b'V');
+                if let Some(handle) = HANDLES.write().get_mut(&id) {
+                    handle.magic = magic;
+                }
+            }
+            File::Timeout => {
+                let timeout = str::from_utf8(&bytes[..len])
+                    .ok()
+                    .and_then(|timeout| timeout.trim().parse::<u32>().ok())
+                    .ok_or(Error::new(EINVAL))?;
+                if !watchdog::set_timeout(timeout) {
+                    return Err(Error::new(EINVAL));
+                }
+            }
+            File::Status => return Err(Error::new(EBADF)),
+        }
+        Ok(len)
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.file {
+            File::Device => "watchdog:",
+            File::Timeout => "watchdog:timeout",
+            File::Status => "watchdog:status",
+        };
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+}
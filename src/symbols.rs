@@ -0,0 +1,152 @@
+//! # Kernel symbols
+//! Resolves addresses of kernel code to the functions containing them, for backtraces, `profile:`
+//! and `symbols:` (see `scheme::symbols`), and functions to their addresses, for `kprobe:`.
+//!
+//! The kernel embeds a compact table of its functions when built with `KERNEL_SYMBOLS` set to the
+//! output of `nm -n -S --defined-only` of a first build of it (see `build.rs`). The table is
+//! read-only data, linked after the code, so functions have the same addresses in both builds.
+//! Without it, functions are looked up in the symbol table of the kernel image loaded by the
+//! bootloader, which is slower, and fails if the image was stripped.
+
+use core::fmt;
+use core::hint::black_box;
+use core::slice;
+use core::str;
+use core::sync::atomic::Ordering;
+
+use rustc_demangle::demangle;
+
+use crate::elf::{sym, Elf};
+use crate::start::{KERNEL_BASE, KERNEL_SIZE};
+
+static TABLE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/symbols"));
+
+const MAGIC: &[u8] = b"KSYM";
+const HEADER: usize = 8;
+const RECORD: usize = 20;
+
+/// A function of the kernel
+#[derive(Clone, Copy)]
+pub struct Symbol {
+    /// The mangled name
+    pub name: &'static str,
+    pub address: usize,
+    pub size: usize,
+}
+
+impl Symbol {
+    pub fn contains(&self, address: usize) -> bool {
+        (self.address..self.address + self.size).contains(&address)
+    }
+}
+
+/// The demangled name, without its hash
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#}", demangle(self.name))
+    }
+}
+
+/// An address in a function of the kernel, formatted as `<function>+<offset>`
+#[derive(Clone, Copy)]
+pub struct Location {
+    pub symbol: Symbol,
+    pub offset: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.symbol, self.offset)
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<usize> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?) as usize)
+}
+
+/// The embedded table and its number of functions, if the kernel was built with one
+fn table() -> Option<(&'static [u8], usize)> {
+    // Its length would otherwise be known when compiling, and change the code between builds
+    let table = black_box(TABLE);
+    if table.get(..MAGIC.len())? != MAGIC {
+        return None;
+    }
+    Some((table, read_u32(table, MAGIC.len())?))
+}
+
+/// Function `index` of the embedded table
+fn entry(table: &'static [u8], count: usize, index: usize) -> Option<Symbol> {
+    let record = HEADER + index * RECORD;
+    let address = u64::from_le_bytes(table.get(record..record + 8)?.try_into().ok()?) as usize;
+    let size = read_u32(table, record + 8)?;
+    let start = HEADER + count * RECORD + read_u32(table, record + 12)?;
+    let name = table.get(start..start + read_u32(table, record + 16)?)?;
+    Some(Symbol {
+        name: str::from_utf8(name).ok()?,
+        address,
+        size,
+    })
+}
+
+/// The kernel image loaded by the bootloader
+fn kernel_elf() -> Option<Elf<'static>> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let base = KERNEL_BASE.load(Ordering::SeqCst) + crate::PHYS_OFFSET;
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    let base = KERNEL_BASE.load(Ordering::SeqCst) + crate::KERNEL_OFFSET;
+
+    let data = unsafe { slice::from_raw_parts(base as *const u8, KERNEL_SIZE.load(Ordering::SeqCst)) };
+    Elf::from(data).ok()
+}
+
+/// Call `f` with each function of the kernel, until it returns a value
+fn find_map<T>(mut f: impl FnMut(Symbol) -> Option<T>) -> Option<T> {
+    if let Some((table, count)) = table() {
+        return (0..count).filter_map(|index| entry(table, count, index)).find_map(f);
+    }
+
+    let elf = kernel_elf()?;
+    for symbol in elf.symbols()? {
+        if sym::st_type(symbol.st_info) != sym::STT_FUNC || symbol.st_size == 0 {
+            continue;
+        }
+        let value = f(Symbol {
+            name: elf.symbol_name(symbol).unwrap_or(""),
+            address: symbol.st_value as usize,
+            size: symbol.st_size as usize,
+        });
+        if value.is_some() {
+            return value;
+        }
+    }
+    None
+}
+
+/// The function containing `address`, and the offset of `address` in it
+pub fn lookup(address: usize) -> Option<Location> {
+    let symbol = match table() {
+        Some((table, count)) => {
+            // The last function starting at or before `address`
+            let (mut low, mut high) = (0, count);
+            while low < high {
+                let middle = low + (high - low) / 2;
+                if entry(table, count, middle)?.address <= address {
+                    low = middle + 1;
+                } else {
+                    high = middle;
+                }
+            }
+            entry(table, count, low.checked_sub(1)?).filter(|symbol| symbol.contains(address))?
+        }
+        None => find_map(|symbol| symbol.contains(address).then_some(symbol))?,
+    };
+    Some(Location {
+        symbol,
+        offset: address - symbol.address,
+    })
+}
+
+/// The function named `name`, mangled or demangled without its hash
+pub fn find(name: &str) -> Option<Symbol> {
+    find_map(|symbol| (symbol.name == name || format!("{}", symbol) == name).then_some(symbol))
+}
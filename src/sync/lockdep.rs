@@ -0,0 +1,535 @@
+//! # Lock dependency checker
+//! With the `lockdep` feature, the `Mutex` and `RwLock` of `sync` wrap those of `spin` to check the
+//! order in which locks are taken, and report orders which could deadlock before they do.
+//!
+//! Locks are grouped in classes by where they were created, such as the static of a global lock,
+//! or the line creating the lock of every context. Taking a lock while holding another records
+//! that the class of the held lock comes before the class of the new one. The first time this
+//! closes a cycle of classes, the cycle is reported, with the locks held by the CPU and a
+//! backtrace. Taking a lock the CPU already holds is reported too, unless both are reads of an
+//! `RwLock`. Checking stops after the first report, as the kernel is likely to deadlock soon
+//! after.
+//!
+//! Locks are tracked per CPU, as spinlocks are not held across context switches. The locks of the
+//! contexts being switched are the exception: `context::switch` leaks their guards, and
+//! `switch_finish_hook` unlocks them on the same CPU, which releases them here. Locks of the same
+//! class are not ordered, so that both of these can be held, and locks which are only tried never
+//! wait, so they are not ordered after the locks held either. Whether interrupts were enabled
+//! while holding a lock is not considered.
+//!
+//! Checking starts in `kmain`, once every CPU has its thread-local storage.
+
+use core::cell::Cell;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr::{self, null_mut};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicU64, Ordering};
+
+/// Number of lock classes
+const CLASSES: usize = 512;
+/// Words of a set of classes
+const WORDS: usize = CLASSES / 64;
+/// Number of locks a CPU can hold
+const DEPTH: usize = 32;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Where the locks of each class are created, by class number minus one
+static CLASS_LIST: [AtomicPtr<Location<'static>>; CLASSES] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const CLASS: AtomicPtr<Location<'static>> = AtomicPtr::new(null_mut());
+    [CLASS; CLASSES]
+};
+
+/// The set of classes taken while holding each class
+static AFTER: [[AtomicU64; WORDS]; CLASSES] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const WORD: AtomicU64 = AtomicU64::new(0);
+    #[allow(clippy::declare_interior_mutable_const)]
+    const SET: [AtomicU64; WORDS] = [WORD; WORDS];
+    [SET; CLASSES]
+};
+
+/// Scratch space of the search for cycles
+struct Search {
+    /// The class each class was reached from, zero if it was not
+    parent: [u16; CLASSES],
+    queue: [u16; CLASSES],
+}
+
+static SEARCH: spin::Mutex<Search> = spin::Mutex::new(Search {
+    parent: [0; CLASSES],
+    queue: [0; CLASSES],
+});
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Kind {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    class: u16,
+    /// Address of the lock
+    lock: usize,
+    kind: Kind,
+}
+
+#[thread_local]
+static HELD: [Cell<Held>; DEPTH] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const HELD: Cell<Held> = Cell::new(Held { class: 0, lock: 0, kind: Kind::Read });
+    [HELD; DEPTH]
+};
+#[thread_local]
+static HELD_COUNT: Cell<usize> = Cell::new(0);
+/// Set while the current CPU is in the checker, whose own locks, such as those taken to print a
+/// report, are not checked
+#[thread_local]
+static CHECKING: Cell<bool> = Cell::new(false);
+
+/// Start checking the order of locks
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Where the locks of `class` are created
+fn location(class: u16) -> &'static Location<'static> {
+    unsafe { &*CLASS_LIST[usize::from(class) - 1].load(Ordering::Acquire) }
+}
+
+/// The number of the class of the locks created at `location`
+fn intern(location: &'static Location<'static>) -> Option<u16> {
+    let hash = (location.line() as usize).wrapping_mul(31) + location.column() as usize + location.file().len();
+    for probe in 0..CLASSES {
+        let index = (hash + probe) % CLASSES;
+        let current = match CLASS_LIST[index].compare_exchange(null_mut(), location as *const _ as *mut _, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return Some(index as u16 + 1),
+            Err(current) => current,
+        };
+        // The same place may have several locations, in different codegen units
+        if unsafe { *current } == *location {
+            return Some(index as u16 + 1);
+        }
+    }
+    None
+}
+
+/// The class of a lock, numbered when it is first taken
+struct Class {
+    location: &'static Location<'static>,
+    number: AtomicU16,
+}
+
+impl Class {
+    #[track_caller]
+    const fn new() -> Self {
+        Self {
+            location: Location::caller(),
+            number: AtomicU16::new(0),
+        }
+    }
+
+    fn number(&self) -> Option<u16> {
+        let number = self.number.load(Ordering::Relaxed);
+        if number != 0 {
+            return Some(number);
+        }
+        let number = intern(self.location)?;
+        self.number.store(number, Ordering::Relaxed);
+        Some(number)
+    }
+}
+
+/// Record that `after` was taken while holding `before`, returning true if it was not known
+fn add(before: u16, after: u16) -> bool {
+    let (before, after) = (usize::from(before) - 1, usize::from(after) - 1);
+    let bit = 1 << (after % 64);
+    AFTER[before][after / 64].fetch_or(bit, Ordering::Relaxed) & bit == 0
+}
+
+/// Find how `to` is taken after `from`, leaving the path in `search.parent`
+fn search(search: &mut Search, from: u16, to: u16) -> bool {
+    search.parent = [0; CLASSES];
+    search.parent[usize::from(from) - 1] = from;
+    search.queue[0] = from;
+    let (mut head, mut tail) = (0, 1);
+    while head < tail {
+        let class = search.queue[head];
+        head += 1;
+        if class == to {
+            return true;
+        }
+        for (word, set) in AFTER[usize::from(class) - 1].iter().enumerate() {
+            let mut bits = set.load(Ordering::Relaxed);
+            while bits != 0 {
+                let next = (word * 64 + bits.trailing_zeros() as usize) as u16 + 1;
+                bits &= bits - 1;
+                if search.parent[usize::from(next) - 1] == 0 {
+                    search.parent[usize::from(next) - 1] = class;
+                    search.queue[tail] = next;
+                    tail += 1;
+                }
+            }
+        }
+    }
+    false
+}
+
+fn disable(reason: fmt::Arguments) {
+    ENABLED.store(false, Ordering::SeqCst);
+    println!("LOCKDEP: {}, on CPU {}", reason, crate::cpu_id());
+}
+
+/// Print the locks held by the current CPU and a backtrace, after a report
+fn print_held() {
+    println!("LOCKDEP: held locks, from the first taken:");
+    for held in HELD[..HELD_COUNT.get()].iter().map(Cell::get) {
+        println!("  {:#x} {} {}", held.lock, if held.kind == Kind::Read { "read" } else { "write" }, location(held.class));
+    }
+    unsafe { crate::interrupt::stack_trace() };
+}
+
+/// Report a cycle, closed by taking `class` while holding `held`, which is taken after it
+fn report_cycle(search: &Search, held: u16, class: u16) {
+    disable(format_args!("possible deadlock, taking {} while holding {}", location(class), location(held)));
+    println!("LOCKDEP: which was taken after it before, through:");
+    let mut path = [0_u16; CLASSES];
+    let mut len = 0;
+    let mut current = held;
+    while current != class {
+        path[len] = current;
+        len += 1;
+        current = search.parent[usize::from(current) - 1];
+    }
+    println!("  {}", location(class));
+    for &step in path[..len].iter().rev() {
+        println!("  -> {}", location(step));
+    }
+    print_held();
+}
+
+/// Check the order of the locks held before taking lock `lock` of class `class`
+fn check(class: u16, lock: usize, kind: Kind) {
+    for held in HELD[..HELD_COUNT.get()].iter().map(Cell::get) {
+        if held.lock == lock {
+            if held.kind == Kind::Read && kind == Kind::Read {
+                continue;
+            }
+            disable(format_args!("recursive locking of {:#x}, created at {}", lock, location(class)));
+            print_held();
+            return;
+        }
+        if held.class == class || !add(held.class, class) {
+            continue;
+        }
+        // The new dependency closes a cycle if the held class was taken after this one
+        let mut guard = SEARCH.lock();
+        if search(&mut guard, class, held.class) {
+            report_cycle(&guard, held.class, class);
+            return;
+        }
+    }
+}
+
+/// Record that the current CPU takes lock `lock`, checking the order first if it may wait for it
+fn acquire(class: &Class, lock: usize, kind: Kind, wait: bool) {
+    if !ENABLED.load(Ordering::Relaxed) || CHECKING.replace(true) {
+        return;
+    }
+    match class.number() {
+        Some(number) => {
+            if wait {
+                check(number, lock, kind);
+            }
+            let count = HELD_COUNT.get();
+            if count < DEPTH {
+                HELD[count].set(Held { class: number, lock, kind });
+                HELD_COUNT.set(count + 1);
+            } else {
+                disable(format_args!("more than {} locks held", DEPTH));
+            }
+        }
+        None => disable(format_args!("more than {} lock classes", CLASSES)),
+    }
+    CHECKING.set(false);
+}
+
+/// Record that the current CPU released lock `lock`
+fn release(lock: usize) {
+    if !ENABLED.load(Ordering::Relaxed) || CHECKING.replace(true) {
+        return;
+    }
+    let count = HELD_COUNT.get();
+    if let Some(index) = (0..count).rev().find(|&index| HELD[index].get().lock == lock) {
+        for index in index..count - 1 {
+            HELD[index].set(HELD[index + 1].get());
+        }
+        HELD_COUNT.set(count - 1);
+    }
+    CHECKING.set(false);
+}
+
+/// A `spin::Mutex` whose order is checked
+pub struct Mutex<T: ?Sized> {
+    class: Class,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Self {
+            class: Class::new(),
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    fn address(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        acquire(&self.class, self.address(), Kind::Write, true);
+        MutexGuard {
+            lock: self.address(),
+            inner: self.inner.lock(),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let inner = self.inner.try_lock()?;
+        acquire(&self.class, self.address(), Kind::Write, false);
+        Some(MutexGuard { lock: self.address(), inner })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// # Safety
+    /// See `spin::Mutex::force_unlock`
+    pub unsafe fn force_unlock(&self) {
+        release(self.address());
+        self.inner.force_unlock();
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    lock: usize,
+    inner: spin::MutexGuard<'a, T>,
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// Keep the mutex locked, which is still held until `force_unlock`
+    pub fn leak(this: Self) -> &'a mut T {
+        let this = ManuallyDrop::new(this);
+        spin::MutexGuard::leak(unsafe { ptr::read(&this.inner) })
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        release(self.lock);
+    }
+}
+
+/// A `spin::RwLock` whose order is checked
+pub struct RwLock<T: ?Sized> {
+    class: Class,
+    inner: spin::RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Self {
+            class: Class::new(),
+            inner: spin::RwLock::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    fn address(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        acquire(&self.class, self.address(), Kind::Read, true);
+        RwLockReadGuard {
+            lock: self.address(),
+            inner: self.inner.read(),
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        acquire(&self.class, self.address(), Kind::Write, true);
+        RwLockWriteGuard {
+            lock: self.address(),
+            inner: self.inner.write(),
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        let inner = self.inner.try_read()?;
+        acquire(&self.class, self.address(), Kind::Read, false);
+        Some(RwLockReadGuard { lock: self.address(), inner })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        let inner = self.inner.try_write()?;
+        acquire(&self.class, self.address(), Kind::Write, false);
+        Some(RwLockWriteGuard { lock: self.address(), inner })
+    }
+
+    /// # Safety
+    /// See `spin::RwLock::force_read_decrement`
+    pub unsafe fn force_read_decrement(&self) {
+        release(self.address());
+        self.inner.force_read_decrement();
+    }
+
+    /// # Safety
+    /// See `spin::RwLock::force_write_unlock`
+    pub unsafe fn force_write_unlock(&self) {
+        release(self.address());
+        self.inner.force_write_unlock();
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: usize,
+    inner: spin::RwLockReadGuard<'a, T>,
+}
+
+impl<'a, T: ?Sized> RwLockReadGuard<'a, T> {
+    /// Keep the lock read, which is still held until `force_read_decrement`
+    pub fn leak(this: Self) -> &'a T {
+        let this = ManuallyDrop::new(this);
+        spin::RwLockReadGuard::leak(unsafe { ptr::read(&this.inner) })
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        release(self.lock);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: usize,
+    inner: spin::RwLockWriteGuard<'a, T>,
+}
+
+impl<'a, T: ?Sized> RwLockWriteGuard<'a, T> {
+    /// Keep the lock written, which is still held until `force_write_unlock`
+    pub fn leak(this: Self) -> &'a mut T {
+        let this = ManuallyDrop::new(this);
+        spin::RwLockWriteGuard::leak(unsafe { ptr::read(&this.inner) })
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        release(self.lock);
+    }
+}
@@ -1,7 +1,20 @@
+pub use self::rcu::{Rcu, RcuReadGuard, RcuWriteGuard, TryClone};
+pub use self::sleep_mutex::{SleepMutex, SleepMutexGuard};
+pub use self::sleep_rwlock::{SleepRwLock, SleepRwLockReadGuard, SleepRwLockWriteGuard};
 pub use self::wait_condition::WaitCondition;
 pub use self::wait_queue::WaitQueue;
 pub use self::wait_map::WaitMap;
 
+#[cfg(feature = "lockdep")]
+pub use self::lockdep::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(not(feature = "lockdep"))]
+pub use spin::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod rcu;
+pub mod sleep_mutex;
+pub mod sleep_rwlock;
 pub mod wait_condition;
 pub mod wait_queue;
 pub mod wait_map;
@@ -0,0 +1,197 @@
+//! # Read-copy-update
+//! A value read without locking, for read-mostly data such as the context list. Writers lock each
+//! other out, change a copy of the value, and publish it when done. Readers which started before
+//! keep reading the old value, which is freed once they are all done, after a grace period.
+//!
+//! Readers are counted in one of two counters, chosen by an index. A grace period flips the index,
+//! so that new readers are counted in the other counter, and ends once the counter of the old
+//! index drops to zero. A value retired before the flip is then no longer read. Reading costs an
+//! atomic increment and decrement, never waits, and can be held across context switches, or taken
+//! while writing, which reads the value from before the write.
+//!
+//! Grace periods are not waited for: writes retire the old value, and start a grace period if none
+//! is in progress. Retired values are freed by the first write or call to `reclaim` finding their
+//! grace period over, so users call `reclaim` periodically for the last ones to be freed without a
+//! further write. They are dropped by the caller, after the writer published its value and stopped
+//! excluding other writers.
+//!
+//! Writing allocates the copy, and fails with `ENOMEM` if that is not possible. Values are copied
+//! with `TryClone`, so that the allocations of the copy itself can fail as well.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::memory::Enomem;
+use crate::sync::{Mutex, MutexGuard};
+use crate::syscall::error::{Error, ENOMEM, Result};
+
+/// A value which can be copied, failing instead of aborting if memory for the copy cannot be
+/// allocated
+pub trait TryClone: Sized {
+    fn try_clone(&self) -> core::result::Result<Self, Enomem>;
+}
+
+struct Retired<T> {
+    /// Values retired since the last grace period started
+    pending: Vec<Box<T>>,
+    /// Values retired before the grace period in progress, if any
+    draining: Vec<Box<T>>,
+    /// The index of the readers the grace period in progress waits for
+    index: usize,
+}
+
+pub struct Rcu<T> {
+    /// The published value, the initial one if null
+    current: AtomicPtr<T>,
+    initial: T,
+    /// The counter new readers use
+    index: AtomicUsize,
+    readers: [AtomicUsize; 2],
+    writer: Mutex<()>,
+    retired: Mutex<Retired<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for Rcu<T> {}
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+impl<T> Rcu<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            current: AtomicPtr::new(null_mut()),
+            initial: value,
+            index: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            writer: Mutex::new(()),
+            retired: Mutex::new(Retired {
+                pending: Vec::new(),
+                draining: Vec::new(),
+                index: 0,
+            }),
+        }
+    }
+
+    /// The published value, which is not freed while the caller is counted as a reader or
+    /// writer
+    fn load(&self) -> &T {
+        let current = self.current.load(Ordering::SeqCst);
+        if current.is_null() {
+            &self.initial
+        } else {
+            unsafe { &*current }
+        }
+    }
+
+    /// Read the value published last
+    pub fn read(&self) -> RcuReadGuard<T> {
+        let index = loop {
+            let index = self.index.load(Ordering::SeqCst);
+            self.readers[index].fetch_add(1, Ordering::SeqCst);
+            // A grace period which flipped the index meanwhile may not have seen this reader
+            if self.index.load(Ordering::SeqCst) == index {
+                break index;
+            }
+            self.readers[index].fetch_sub(1, Ordering::SeqCst);
+        };
+        RcuReadGuard {
+            rcu: self,
+            index,
+            value: self.load(),
+        }
+    }
+
+    /// Write a copy of the value, published when the guard is dropped
+    pub fn write(&self) -> Result<RcuWriteGuard<T>>
+    where
+        T: TryClone,
+    {
+        let writer = self.writer.lock();
+        let copy = Box::try_new(self.load().try_clone()?).map_err(|_| Error::new(ENOMEM))?;
+        Ok(RcuWriteGuard {
+            rcu: self,
+            writer: Some(writer),
+            copy: Some(copy),
+        })
+    }
+
+    /// Free the values whose grace period is over, and start a grace period for those retired
+    /// since. Must not be called where dropping a value is not allowed, such as in an interrupt
+    /// handler.
+    pub fn reclaim(&self) {
+        // Another writer is reclaiming
+        let Some(mut retired) = self.retired.try_lock() else {
+            return;
+        };
+        let mut freed = Vec::new();
+        loop {
+            if !retired.draining.is_empty() {
+                if self.readers[retired.index].load(Ordering::SeqCst) != 0 {
+                    break;
+                }
+                freed.append(&mut retired.draining);
+            }
+            if retired.pending.is_empty() {
+                break;
+            }
+            retired.index = self.index.fetch_xor(1, Ordering::SeqCst);
+            retired.draining = mem::take(&mut retired.pending);
+        }
+        drop(retired);
+        drop(freed);
+    }
+}
+
+pub struct RcuReadGuard<'a, T> {
+    rcu: &'a Rcu<T>,
+    index: usize,
+    value: &'a T,
+}
+
+impl<T> Deref for RcuReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> Drop for RcuReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rcu.readers[self.index].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct RcuWriteGuard<'a, T> {
+    rcu: &'a Rcu<T>,
+    writer: Option<MutexGuard<'a, ()>>,
+    copy: Option<Box<T>>,
+}
+
+impl<T> Deref for RcuWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.copy.as_ref().expect("RcuWriteGuard: no copy")
+    }
+}
+
+impl<T> DerefMut for RcuWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.copy.as_mut().expect("RcuWriteGuard: no copy")
+    }
+}
+
+impl<T> Drop for RcuWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let Some(copy) = self.copy.take() else {
+            return;
+        };
+        let old = self.rcu.current.swap(Box::into_raw(copy), Ordering::SeqCst);
+        drop(self.writer.take());
+        if !old.is_null() {
+            self.rcu.retired.lock().pending.push(unsafe { Box::from_raw(old) });
+        }
+        self.rcu.reclaim();
+    }
+}
@@ -0,0 +1,114 @@
+//! # Sleeping mutex
+//! A mutex whose waiters block their context until it is unlocked, instead of spinning, for data
+//! held for long, such as across copies of large buffers or calls into schemes.
+//!
+//! Locking switches contexts, so it must not be done while holding a spinlock or with interrupts
+//! disabled. Waiting is interrupted by signals. Unlocking wakes every waiter, and those which do
+//! not get the lock go back to sleep, so waiters are not served in order.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+use crate::sync::{Mutex, WaitCondition};
+use crate::syscall::error::*;
+
+pub struct SleepMutex<T: ?Sized> {
+    locked: Mutex<bool>,
+    condition: WaitCondition,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SleepMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SleepMutex<T> {}
+
+impl<T> SleepMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: Mutex::new(false),
+            condition: WaitCondition::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> SleepMutex<T> {
+    /// Lock the mutex, sleeping with `reason` while it is locked. Fails with `EINTR` if a signal
+    /// woke the current context first.
+    pub fn lock(&self, reason: &'static str) -> Result<SleepMutexGuard<T>> {
+        loop {
+            let mut locked = self.locked.lock();
+            if !*locked {
+                *locked = true;
+                return Ok(SleepMutexGuard { mutex: self });
+            }
+            if !self.condition.wait(locked, reason) {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
+    /// Lock the mutex if it is not locked
+    pub fn try_lock(&self) -> Option<SleepMutexGuard<T>> {
+        let mut locked = self.locked.lock();
+        if *locked {
+            return None;
+        }
+        *locked = true;
+        Some(SleepMutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        *self.locked.lock()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for SleepMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for SleepMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SleepMutex").field("locked", &self.is_locked()).finish_non_exhaustive()
+    }
+}
+
+pub struct SleepMutexGuard<'a, T: ?Sized + 'a> {
+    mutex: &'a SleepMutex<T>,
+}
+
+impl<T: ?Sized> Deref for SleepMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SleepMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SleepMutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized> Drop for SleepMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        *self.mutex.locked.lock() = false;
+        self.mutex.condition.notify();
+    }
+}
@@ -0,0 +1,181 @@
+//! # Sleeping reader-writer lock
+//! A reader-writer lock whose waiters block their context until they can take it, instead of
+//! spinning, like `SleepMutex`, with the same restrictions.
+//!
+//! Writers are preferred: once a writer waits, readers wait for it, so that a steady stream of
+//! readers cannot keep writers out.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+use crate::sync::{Mutex, WaitCondition};
+use crate::syscall::error::*;
+
+#[derive(Debug)]
+struct State {
+    readers: usize,
+    writer: bool,
+    writers_waiting: usize,
+}
+
+pub struct SleepRwLock<T: ?Sized> {
+    state: Mutex<State>,
+    condition: WaitCondition,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SleepRwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for SleepRwLock<T> {}
+
+impl<T> SleepRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: Mutex::new(State {
+                readers: 0,
+                writer: false,
+                writers_waiting: 0,
+            }),
+            condition: WaitCondition::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> SleepRwLock<T> {
+    /// Read the lock, sleeping with `reason` while it is written or a writer waits. Fails with
+    /// `EINTR` if a signal woke the current context first.
+    pub fn read(&self, reason: &'static str) -> Result<SleepRwLockReadGuard<T>> {
+        loop {
+            let mut state = self.state.lock();
+            if !state.writer && state.writers_waiting == 0 {
+                state.readers += 1;
+                return Ok(SleepRwLockReadGuard { lock: self });
+            }
+            if !self.condition.wait(state, reason) {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
+    /// Write the lock, sleeping with `reason` while it is read or written. Fails with `EINTR` if a
+    /// signal woke the current context first.
+    pub fn write(&self, reason: &'static str) -> Result<SleepRwLockWriteGuard<T>> {
+        let mut state = self.state.lock();
+        loop {
+            if !state.writer && state.readers == 0 {
+                state.writer = true;
+                return Ok(SleepRwLockWriteGuard { lock: self });
+            }
+            state.writers_waiting += 1;
+            let waited = self.condition.wait(state, reason);
+            state = self.state.lock();
+            state.writers_waiting -= 1;
+            if !waited {
+                // Readers held back by this writer may go
+                drop(state);
+                self.condition.notify();
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
+    /// Read the lock if it is not written and no writer waits
+    pub fn try_read(&self) -> Option<SleepRwLockReadGuard<T>> {
+        let mut state = self.state.lock();
+        if state.writer || state.writers_waiting > 0 {
+            return None;
+        }
+        state.readers += 1;
+        Some(SleepRwLockReadGuard { lock: self })
+    }
+
+    /// Write the lock if it is neither read nor written
+    pub fn try_write(&self) -> Option<SleepRwLockWriteGuard<T>> {
+        let mut state = self.state.lock();
+        if state.writer || state.readers > 0 {
+            return None;
+        }
+        state.writer = true;
+        Some(SleepRwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for SleepRwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for SleepRwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SleepRwLock").field("state", &*self.state.lock()).finish_non_exhaustive()
+    }
+}
+
+pub struct SleepRwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a SleepRwLock<T>,
+}
+
+impl<T: ?Sized> Deref for SleepRwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SleepRwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized> Drop for SleepRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        state.readers -= 1;
+        let last = state.readers == 0;
+        drop(state);
+        if last {
+            self.lock.condition.notify();
+        }
+    }
+}
+
+pub struct SleepRwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a SleepRwLock<T>,
+}
+
+impl<T: ?Sized> Deref for SleepRwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SleepRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SleepRwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized> Drop for SleepRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.lock().writer = false;
+        self.lock.condition.notify();
+    }
+}
@@ -1,8 +1,8 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use spin::{Mutex, MutexGuard, RwLock};
 
 use crate::context::{self, Context};
+use crate::sync::{Mutex, MutexGuard, RwLock};
 
 #[derive(Debug)]
 pub struct WaitCondition {
@@ -26,6 +26,23 @@ impl WaitCondition {
         len
     }
 
+    // Notify all waiters, boosting them if wakeups of `class` are boosted
+    pub fn notify_boosted(&self, class: usize) -> usize {
+        let deadline = match context::boost::deadline(class) {
+            Some(deadline) => deadline,
+            None => return self.notify(),
+        };
+        let mut contexts = self.contexts.lock();
+        let len = contexts.len();
+        while let Some(context_lock) = contexts.pop() {
+            let mut context = context_lock.write();
+            if context.unblock() {
+                context.boost = Some(deadline);
+            }
+        }
+        len
+    }
+
     // Notify as though a signal woke the waiters
     pub unsafe fn notify_signal(&self) -> usize {
         let contexts = self.contexts.lock();
@@ -1,8 +1,7 @@
 use alloc::collections::BTreeMap;
 use core::mem;
-use spin::Mutex;
 
-use crate::sync::WaitCondition;
+use crate::sync::{Mutex, WaitCondition};
 
 #[derive(Debug)]
 pub struct WaitMap<K, V> {
@@ -54,6 +53,26 @@ impl<K, V> WaitMap<K, V> where K: Clone + Ord {
         }
     }
 
+    /// Find the first entry accepted by `filter`, removing it unless `peek` is set. Blocks until
+    /// such an entry exists if `reason` is set, otherwise returns `None` immediately.
+    pub fn receive_matching(&self, mut filter: impl FnMut(&K, &V) -> bool, peek: bool, reason: Option<&'static str>) -> Option<(K, V)> where V: Clone {
+        loop {
+            let mut inner = self.inner.lock();
+            if let Some(key) = inner.iter().find(|(key, value)| filter(key, value)).map(|(key, _)| key.clone()) {
+                let value = if peek {
+                    inner.get(&key).cloned()
+                } else {
+                    inner.remove(&key)
+                };
+                if let Some(value) = value {
+                    return Some((key, value));
+                }
+            }
+            let reason = reason?;
+            let _ = self.condition.wait(inner, reason);
+        }
+    }
+
     pub fn receive_all(&self) -> BTreeMap<K, V> {
         let mut ret = BTreeMap::new();
         mem::swap(&mut ret, &mut *self.inner.lock());
@@ -1,8 +1,7 @@
 use alloc::collections::VecDeque;
-use spin::Mutex;
 use syscall::{EAGAIN, EINTR};
 
-use crate::sync::WaitCondition;
+use crate::sync::{Mutex, WaitCondition};
 use crate::syscall::usercopy::UserSliceWo;
 use crate::syscall::error::{Error, EINVAL, Result};
 
@@ -112,6 +111,17 @@ impl<T> WaitQueue<T> {
         len
     }
 
+    /// Like `send`, but boost the receiver if wakeups of `class` are boosted
+    pub fn send_boosted(&self, value: T, class: usize) -> usize {
+        let len = {
+            let mut inner = self.inner.lock();
+            inner.push_back(value);
+            inner.len()
+        };
+        self.condition.notify_boosted(class);
+        len
+    }
+
     pub fn send_from(&self, buf: &[T]) -> usize where T: Copy {
         let len = {
             let mut inner = self.inner.lock();
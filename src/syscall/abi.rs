@@ -0,0 +1,700 @@
+//! Syscall ABI additions that have not (yet) been upstreamed to the `syscall` crate.
+//!
+//! Syscall numbers follow the i386 Linux numbering where there is an equivalent, like most of the
+//! numbers already defined by the `syscall` crate.
+
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::AtomicU64;
+use core::{mem, slice};
+
+use syscall::flag::{PtraceFlags, PTRACE_STOP_EXIT};
+
+/// `exit_group(status)`
+pub const SYS_EXIT_GROUP: usize = 252;
+/// `futex_waitv(waiters, nr_futexes, flags, timeout)`, where unlike Linux, the timeout is relative
+/// like for `futex`
+pub const SYS_FUTEX_WAITV: usize = 449;
+/// `madvise(addr, len, advice)`
+pub const SYS_MADVISE: usize = 219;
+/// `mremap(old_address, old_size, new_size, flags, new_address)`
+pub const SYS_MREMAP: usize = 163;
+/// `gettid()`
+pub const SYS_GETTID: usize = 224;
+/// `sigqueue(pid, sig, value)`, the equivalent of `rt_sigqueueinfo` where only the value is
+/// supplied by the caller
+pub const SYS_SIGQUEUE: usize = 178;
+/// `waitid(idtype, id, info, options)`
+pub const SYS_WAITID: usize = 284;
+/// `splice(fd_in, fd_out, len, flags)`, without the offsets of Linux, as files are seeked instead
+pub const SYS_SPLICE: usize = 313;
+/// `getrusage(who, usage)`, where `usage` is a `Rusage`
+pub const SYS_GETRUSAGE: usize = 77;
+/// `uname(buf)`, where `buf` is a `Uname`
+pub const SYS_UNAME: usize = 122;
+/// `process_vm_readv(pid, local_iov, liovcnt, remote_iov, riovcnt)`, without the flags of Linux,
+/// which must be zero there. The iovecs are `IoVec`s.
+pub const SYS_PROCESS_VM_READV: usize = 347;
+/// `process_vm_writev(pid, local_iov, liovcnt, remote_iov, riovcnt)`
+pub const SYS_PROCESS_VM_WRITEV: usize = 348;
+/// `arch_prctl(code, addr)`, getting or setting FSBASE or GSBASE of the calling thread, on x86_64
+pub const SYS_ARCH_PRCTL: usize = 384;
+
+// Redox specific syscalls, numbered past the end of the Linux range
+
+/// `setlabel(pid, label, label_len)`
+pub const SYS_SETLABEL: usize = 1000;
+/// `physalloc_node(size, node)`, like `physalloc` but the memory is on the given NUMA node
+pub const SYS_PHYSALLOC_NODE: usize = 1001;
+/// `mkpidns()`, placing the processes the caller creates from then on in a new PID namespace,
+/// nested within its own, the first of them becoming its init
+pub const SYS_MKPIDNS: usize = 1002;
+
+// `waitid` ID types
+pub const P_ALL: usize = 0;
+pub const P_PID: usize = 1;
+pub const P_PGID: usize = 2;
+
+// `waitid` options, in addition to WNOHANG, WUNTRACED (WSTOPPED) and WCONTINUED
+pub const WEXITED: usize = 0x04;
+pub const WNOWAIT: usize = 0x0100_0000;
+
+// Ptrace events in addition to those of the `syscall` crate, within `PTRACE_EVENT_MASK`. Like
+// `PTRACE_EVENT_CLONE`, the forked child is stopped until the tracer continues the parent.
+
+/// A process was forked, `a` is the ID of the child. Tracers that do not ask for it get
+/// `PTRACE_EVENT_CLONE` instead, which is otherwise only sent for new threads.
+pub const PTRACE_EVENT_FORK: PtraceFlags = PtraceFlags::from_bits_truncate(0x0400);
+/// The tracee replaced its own address space, like `exec`. `PTRACE_EVENT_ADDRSPACE_SWITCH` is
+/// sent too, also when the address space of another context is replaced.
+pub const PTRACE_EVENT_EXEC: PtraceFlags = PtraceFlags::from_bits_truncate(0x0800);
+/// The tracee is exiting, `a` is the exit status. This is the exit stop, under the name Linux uses.
+pub const PTRACE_EVENT_EXIT: PtraceFlags = PTRACE_STOP_EXIT;
+
+/// Maximum number of iovecs passed to a syscall
+pub const IOV_MAX: usize = 1024;
+
+/// A memory range for `process_vm_readv` and `process_vm_writev`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct IoVec {
+    pub base: usize,
+    pub len: usize,
+}
+
+// `arch_prctl` codes
+pub const ARCH_SET_GS: usize = 0x1001;
+pub const ARCH_SET_FS: usize = 0x1002;
+pub const ARCH_GET_FS: usize = 0x1003;
+pub const ARCH_GET_GS: usize = 0x1004;
+
+// `WaitInfo::si_code` values
+pub const CLD_EXITED: i32 = 1;
+pub const CLD_KILLED: i32 = 2;
+pub const CLD_STOPPED: i32 = 5;
+pub const CLD_CONTINUED: i32 = 6;
+
+/// The siginfo-style result of `waitid`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct WaitInfo {
+    pub si_signo: i32,
+    pub si_code: i32,
+    pub si_status: i32,
+    pub si_uid: u32,
+    pub si_pid: usize,
+}
+
+impl Deref for WaitInfo {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const WaitInfo as *const u8, mem::size_of::<WaitInfo>())
+        }
+    }
+}
+
+impl DerefMut for WaitInfo {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut WaitInfo as *mut u8, mem::size_of::<WaitInfo>())
+        }
+    }
+}
+
+// `getrusage` targets
+pub const RUSAGE_SELF: usize = 0;
+pub const RUSAGE_THREAD: usize = 1;
+pub const RUSAGE_CHILDREN: usize = -1_isize as usize;
+
+/// Resource usage returned by `getrusage`, and read from `proc:<pid>/usage`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Rusage {
+    /// CPU time in nanoseconds, user and kernel time combined
+    pub cpu_time: u64,
+    /// Page faults handled without waiting for I/O, such as the first access of anonymous memory
+    pub minor_faults: u64,
+    /// Page faults that had to wait for a page to be read back, such as from swap
+    pub major_faults: u64,
+}
+
+impl Rusage {
+    pub fn add(&mut self, other: &Rusage) {
+        self.cpu_time += other.cpu_time;
+        self.minor_faults += other.minor_faults;
+        self.major_faults += other.major_faults;
+    }
+}
+
+impl Deref for Rusage {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const Rusage as *const u8, mem::size_of::<Rusage>())
+        }
+    }
+}
+
+impl DerefMut for Rusage {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut Rusage as *mut u8, mem::size_of::<Rusage>())
+        }
+    }
+}
+
+/// `Map::flags` bit requesting anonymous memory backed by huge pages, which requires the size
+/// (and address, if fixed) to be aligned to the huge page size
+pub const MAP_HUGETLB: usize = 0x0010_0000;
+/// `Map::flags` bit requesting anonymous memory for a stack, which grows downwards when a page
+/// fault occurs just below it
+pub const MAP_GROWSDOWN: usize = 0x0020_0000;
+
+/// `physalloc3` flag requesting memory below 1 MiB, in addition to `PhysallocFlags::SPACE_32` for
+/// memory below 4 GiB
+pub const PHYSALLOC_SPACE_20: usize = 0x0000_0008;
+
+// `irq:` open flags, selecting when the IRQ is acknowledged (unmasked). They are passed in the file
+// type bits of the mode, which have no meaning for IRQ handles.
+/// The driver acknowledges the IRQ by writing back the count it has read
+pub const IRQ_ACK_WRITE: usize = 0x0000;
+/// Reading a new count acknowledges the IRQ
+pub const IRQ_ACK_READ: usize = 0x1000;
+/// The IRQ is acknowledged by the kernel as soon as it has been counted
+pub const IRQ_ACK_AUTO: usize = 0x2000;
+pub const IRQ_ACK_MASK: usize = 0x3000;
+
+// `madvise` advice
+pub const MADV_NORMAL: usize = 0;
+pub const MADV_RANDOM: usize = 1;
+pub const MADV_SEQUENTIAL: usize = 2;
+/// Allocate lazily allocated memory in advance
+pub const MADV_WILLNEED: usize = 3;
+/// Free the frames of anonymous memory, which will read as zero afterwards
+pub const MADV_DONTNEED: usize = 4;
+/// Currently equivalent to `MADV_DONTNEED`, as frames are freed immediately
+pub const MADV_FREE: usize = 8;
+
+// `mremap` flags
+/// Allow moving the memory if it cannot be resized in place
+pub const MREMAP_MAYMOVE: usize = 1;
+/// Move the memory to `new_address`, which must not be mapped. Requires `MREMAP_MAYMOVE`.
+pub const MREMAP_FIXED: usize = 2;
+
+// `splice` flags
+/// Accepted for compatibility, data is always copied
+pub const SPLICE_F_MOVE: usize = 1;
+/// Do not block on the pipe, although the other file may still block
+pub const SPLICE_F_NONBLOCK: usize = 2;
+/// Accepted for compatibility, more data follows
+pub const SPLICE_F_MORE: usize = 4;
+
+// `fcntl` commands for sealing `shm:` objects
+/// Add the seals given as the argument
+pub const F_ADD_SEALS: usize = 1033;
+/// Get the current seals
+pub const F_GET_SEALS: usize = 1034;
+
+// `fcntl` commands for pipes, setting how much progress wakes up a blocked peer. On the read end,
+// the reader is woken when this many bytes are available, or the writer has closed. On the write
+// end, the writer is woken when this many bytes are free, or the reader has closed. Defaults to 1.
+pub const F_SETLOWAT: usize = 2000;
+pub const F_GETLOWAT: usize = 2001;
+
+// Seals of `shm:` objects, which can only be added
+/// Prevent adding further seals
+pub const F_SEAL_SEAL: usize = 0x01;
+/// Prevent the object from shrinking
+pub const F_SEAL_SHRINK: usize = 0x02;
+/// Prevent the object from growing
+pub const F_SEAL_GROW: usize = 0x04;
+/// Prevent writable mappings of the object. Cannot be added while writable mappings exist.
+pub const F_SEAL_WRITE: usize = 0x08;
+
+// `FutexWaitv::flags` values, of which the size is mandatory
+pub const FUTEX2_SIZE_U8: u32 = 0x00;
+pub const FUTEX2_SIZE_U16: u32 = 0x01;
+pub const FUTEX2_SIZE_U32: u32 = 0x02;
+pub const FUTEX2_SIZE_U64: u32 = 0x03;
+pub const FUTEX2_SIZE_MASK: u32 = 0x03;
+/// Accepted for compatibility, but futexes are always keyed by physical address
+pub const FUTEX2_PRIVATE: u32 = 0x80;
+
+/// Maximum number of futexes that can be waited on by a single `futex_waitv`
+pub const FUTEX_WAITV_MAX: usize = 128;
+
+/// A futex to wait on with `futex_waitv`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct FutexWaitv {
+    /// Value expected at `uaddr`, of the size given by `flags`
+    pub val: u64,
+    pub uaddr: u64,
+    pub flags: u32,
+    pub __reserved: u32,
+}
+
+/// First realtime signal, matching relibc. Realtime signals are queued once per send, while other
+/// signals are only pending once at a time.
+pub const SIGRTMIN: usize = 35;
+
+// `SigInfo::si_code` values
+pub const SI_USER: i32 = 0;
+pub const SI_KERNEL: i32 = 0x80;
+pub const SI_QUEUE: i32 = -1;
+pub const SEGV_MAPERR: i32 = 1;
+pub const SEGV_ACCERR: i32 = 2;
+pub const BUS_ADRALN: i32 = 1;
+pub const BUS_MCEERR_AR: i32 = 4;
+
+/// Information about a queued or delivered signal
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SigInfo {
+    pub si_signo: i32,
+    pub si_errno: i32,
+    pub si_code: i32,
+    pub si_uid: u32,
+    pub si_pid: usize,
+    /// Faulting address for SIGSEGV, SIGBUS, SIGILL and SIGFPE
+    pub si_addr: usize,
+    /// Value passed to `sigqueue`
+    pub si_value: usize,
+}
+
+impl SigInfo {
+    /// A signal generated by the kernel itself
+    pub fn kernel(sig: usize) -> Self {
+        SigInfo {
+            si_signo: sig as i32,
+            si_code: SI_KERNEL,
+            ..SigInfo::default()
+        }
+    }
+}
+
+impl Deref for SigInfo {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const SigInfo as *const u8, mem::size_of::<SigInfo>())
+        }
+    }
+}
+
+impl DerefMut for SigInfo {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut SigInfo as *mut u8, mem::size_of::<SigInfo>())
+        }
+    }
+}
+
+// `SwapRequest::kind` values
+/// Store the page following the request in `slot`
+pub const SWAP_OUT: usize = 1;
+/// Write the page stored in `slot` back, following a request with the same `id`
+pub const SWAP_IN: usize = 2;
+/// The page requested by `id` could not be read, failing the fault that needed it
+pub const SWAP_ERROR: usize = 3;
+
+/// A request read from, or a reply written to, `swap:`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SwapRequest {
+    pub kind: usize,
+    pub slot: usize,
+    pub id: usize,
+}
+
+impl Deref for SwapRequest {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const SwapRequest as *const u8, mem::size_of::<SwapRequest>())
+        }
+    }
+}
+
+impl DerefMut for SwapRequest {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut SwapRequest as *mut u8, mem::size_of::<SwapRequest>())
+        }
+    }
+}
+
+// `MemoryPressure::level` values
+pub const MEMORY_PRESSURE_NONE: usize = 0;
+/// Free memory is below the low threshold, caches should be trimmed
+pub const MEMORY_PRESSURE_LOW: usize = 1;
+/// Free memory is below the critical threshold, processes are about to be killed
+pub const MEMORY_PRESSURE_CRITICAL: usize = 2;
+
+/// The state of memory read from `mempressure:`, in pages
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemoryPressure {
+    pub level: usize,
+    pub free: usize,
+    /// Free pages which userspace can allocate, before the OOM killer runs
+    pub available: usize,
+    pub total: usize,
+    /// The current thresholds, in pages
+    pub low: usize,
+    pub critical: usize,
+}
+
+impl Deref for MemoryPressure {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const MemoryPressure as *const u8, mem::size_of::<MemoryPressure>())
+        }
+    }
+}
+
+impl DerefMut for MemoryPressure {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut MemoryPressure as *mut u8, mem::size_of::<MemoryPressure>())
+        }
+    }
+}
+
+// `SchedBoost::classes` bits, the sources of wakeups which boost the woken context
+/// Input from PS/2 devices (`serio:`)
+pub const BOOST_SERIO: usize = 1;
+/// Input from the serial console (`debug:`)
+pub const BOOST_DEBUG: usize = 2;
+/// Data written to a pipe
+pub const BOOST_PIPE: usize = 4;
+/// Events sent by userspace schemes, such as input and terminal daemons
+pub const BOOST_USER: usize = 8;
+
+/// The wakeup boost policy read from and written to `sched:boost`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SchedBoost {
+    /// Which wakeups boost the woken context, see `BOOST_*`
+    pub classes: usize,
+    /// How long after waking a context is preferred by the scheduler, in microseconds
+    pub duration: usize,
+}
+
+impl Deref for SchedBoost {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const SchedBoost as *const u8, mem::size_of::<SchedBoost>())
+        }
+    }
+}
+
+impl DerefMut for SchedBoost {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut SchedBoost as *mut u8, mem::size_of::<SchedBoost>())
+        }
+    }
+}
+
+/// The low-latency class membership of a context, read from `sched:latency` and written to it
+/// to join or leave the class. Only `max_latency` is written, which also resets the measurements.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SchedLatency {
+    /// Longest acceptable delay between being woken and running, in microseconds, or 0 outside of
+    /// the class
+    pub max_latency: usize,
+    /// Wakeups measured, and those that took longer than `max_latency`
+    pub wakeups: usize,
+    pub misses: usize,
+    /// Worst and most recent wakeup latency, in microseconds
+    pub worst: usize,
+    pub last: usize,
+    /// Time of the most recent miss, in microseconds since boot (`CLOCK_MONOTONIC`)
+    pub last_miss: usize,
+}
+
+impl Deref for SchedLatency {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const SchedLatency as *const u8, mem::size_of::<SchedLatency>())
+        }
+    }
+}
+
+impl DerefMut for SchedLatency {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut SchedLatency as *mut u8, mem::size_of::<SchedLatency>())
+        }
+    }
+}
+
+/// `SchemeHandshake::magic`, "SCHEMEV2" in little-endian
+pub const SCHEME_HANDSHAKE_MAGIC: u64 = u64::from_le_bytes(*b"SCHEMEV2");
+
+// Scheme protocol versions
+/// `Packet`s in the native layout
+pub const SCHEME_PROTOCOL_V1: u32 = 1;
+/// `SchemePacket`s
+pub const SCHEME_PROTOCOL_V2: u32 = 2;
+
+// `SchemeHandshake::capabilities` bits, the optional parts of the protocol the provider implements
+/// The provider handles `SYS_FMAP` requests
+pub const SCHEME_CAP_FMAP: u32 = 1;
+/// The provider returns file descriptors with `SKMSG_FRETURNFD`
+pub const SCHEME_CAP_RETURN_FD: u32 = 2;
+/// All capabilities known to this kernel
+pub const SCHEME_CAP_ALL: u32 = SCHEME_CAP_FMAP | SCHEME_CAP_RETURN_FD;
+
+/// Written by a scheme provider as the first thing on its `:name` handle, to use a protocol other
+/// than v1. The next read returns the kernel's answer, with the highest version both support and
+/// the requested capabilities the kernel knows about. All fields are little-endian.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SchemeHandshake {
+    pub magic: u64,
+    pub version: u32,
+    pub capabilities: u32,
+}
+
+impl Deref for SchemeHandshake {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const SchemeHandshake as *const u8, mem::size_of::<SchemeHandshake>())
+        }
+    }
+}
+
+impl DerefMut for SchemeHandshake {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut SchemeHandshake as *mut u8, mem::size_of::<SchemeHandshake>())
+        }
+    }
+}
+
+/// A request or response of scheme protocol v2. Unlike `Packet`, every field has the same size
+/// and (little-endian) byte order on every architecture, so the layout does not depend on how the
+/// kernel or the provider was built.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SchemePacket {
+    pub id: u64,
+    pub pid: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub a: u64,
+    pub b: u64,
+    pub c: u64,
+    pub d: u64,
+    /// Reserved for extensions, must be zero
+    pub flags: u64,
+}
+
+impl Deref for SchemePacket {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const SchemePacket as *const u8, mem::size_of::<SchemePacket>())
+        }
+    }
+}
+
+impl DerefMut for SchemePacket {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut SchemePacket as *mut u8, mem::size_of::<SchemePacket>())
+        }
+    }
+}
+
+/// Revision of the kernel ABI, incremented whenever a syscall, structure or scheme format changes
+/// in a way userspace has to know about
+pub const ABI_REVISION: u32 = 1;
+
+// `Uname::features` bits, for the features the kernel was built with
+pub const KERNEL_FEATURE_ACPI: u64 = 0x0001;
+pub const KERNEL_FEATURE_MULTI_CORE: u64 = 0x0002;
+pub const KERNEL_FEATURE_PTI: u64 = 0x0004;
+pub const KERNEL_FEATURE_X86_SMAP: u64 = 0x0008;
+pub const KERNEL_FEATURE_X86_FSGSBASE: u64 = 0x0010;
+pub const KERNEL_FEATURE_SLAB: u64 = 0x0020;
+
+/// Kernel version and features returned by `uname`. The strings are NUL padded.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Uname {
+    /// Always `Redox`
+    pub sysname: [u8; 32],
+    /// The kernel version
+    pub release: [u8; 32],
+    /// The git revision the kernel was built from, if known
+    pub revision: [u8; 32],
+    /// The architecture, like `x86_64`
+    pub machine: [u8; 32],
+    /// `KERNEL_FEATURE_*` bits
+    pub features: u64,
+    /// `ABI_REVISION`
+    pub abi_revision: u32,
+    /// Highest scheme protocol version supported, see `SchemeHandshake`
+    pub scheme_protocol: u32,
+}
+
+impl Deref for Uname {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const Uname as *const u8, mem::size_of::<Uname>())
+        }
+    }
+}
+
+impl DerefMut for Uname {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut Uname as *mut u8, mem::size_of::<Uname>())
+        }
+    }
+}
+
+/// `HibernateHeader::magic` of an image read from and written to `power:image`
+pub const HIBERNATE_MAGIC: [u8; 8] = *b"RDXHIBR1";
+
+/// The first page of a hibernation image. The frame numbers of its pages follow, padded to a page,
+/// and then the pages themselves.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct HibernateHeader {
+    pub magic: [u8; 8],
+    /// Number of pages of memory in the image
+    pub pages: usize,
+    /// Size of the whole image in bytes, including this page
+    pub size: usize,
+    /// SHA-256 of the code and read-only data of the kernel that took the image
+    pub kernel: [u8; 32],
+    /// SHA-256 of the memory map, which must be the same when restoring
+    pub memory_map: [u8; 32],
+}
+
+impl Deref for HibernateHeader {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const HibernateHeader as *const u8, mem::size_of::<HibernateHeader>())
+        }
+    }
+}
+
+impl DerefMut for HibernateHeader {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut HibernateHeader as *mut u8, mem::size_of::<HibernateHeader>())
+        }
+    }
+}
+
+/// The first page of the ring mapped from a `perf:` handle opened for sampling. The kernel writes
+/// `PerfSample`s to the pages after it and advances `head`, and the reader advances `tail` past
+/// the samples it has read. Both count samples since the ring was created, so the next sample to
+/// read is at `tail % capacity`.
+#[derive(Debug)]
+#[repr(C)]
+pub struct PerfRingHeader {
+    pub head: AtomicU64,
+    pub tail: AtomicU64,
+    /// Samples dropped because the ring was full
+    pub lost: AtomicU64,
+    /// Number of samples the ring holds
+    pub capacity: u64,
+}
+
+// `PerfSample::flags` bits
+/// The sample was taken in userspace
+pub const PERF_SAMPLE_USER: u32 = 1;
+
+/// A sample written to the ring of a `perf:` handle each time its period elapses
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct PerfSample {
+    /// Nanoseconds since boot (`CLOCK_MONOTONIC`), or 0 if the clock was being changed
+    pub time: u64,
+    /// The instruction pointer
+    pub ip: u64,
+    /// The context that was running
+    pub pid: u64,
+    /// The CPU the sample was taken on
+    pub cpu: u32,
+    /// `PERF_SAMPLE_*` bits
+    pub flags: u32,
+}
+
+/// Most entries of the call stack of a `ProfileSample`
+pub const PROFILE_DEPTH: usize = 28;
+
+// `ProfileSample::flags` bits
+/// The sample was taken in userspace
+pub const PROFILE_USER: u32 = 1;
+/// The sample was taken by an NMI, in code running with interrupts disabled
+pub const PROFILE_NMI: u32 = 2;
+
+/// A sample of the code running on a CPU, read from `profile:`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ProfileSample {
+    /// Nanoseconds since boot (`CLOCK_MONOTONIC`), or 0 if the clock was being changed
+    pub time: u64,
+    /// The context that was running
+    pub pid: u64,
+    /// The CPU the sample was taken on
+    pub cpu: u32,
+    /// `PROFILE_*` bits
+    pub flags: u32,
+    /// Number of valid entries in `stack`
+    pub depth: u64,
+    /// The instruction pointer, followed by the return addresses of the frames it was called
+    /// from, innermost first
+    pub stack: [u64; PROFILE_DEPTH],
+}
+
+impl Deref for ProfileSample {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const ProfileSample as *const u8, mem::size_of::<ProfileSample>())
+        }
+    }
+}
+
+impl DerefMut for ProfileSample {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut ProfileSample as *mut u8, mem::size_of::<ProfileSample>())
+        }
+    }
+}
@@ -4,6 +4,7 @@ use alloc::vec::Vec;
 
 use super::data::{Map, Stat, TimeSpec};
 use super::{flag::*, copy_path_to_buf};
+use super::abi::{F_ADD_SEALS, F_GET_SEALS, F_GETLOWAT, F_SETLOWAT, SYS_EXIT_GROUP, SYS_FUTEX_WAITV, SYS_GETRUSAGE, SYS_GETTID, SYS_MADVISE, SYS_MKPIDNS, SYS_MREMAP, SYS_PHYSALLOC_NODE, SYS_SETLABEL, SYS_SIGQUEUE, SYS_SPLICE, SYS_UNAME, SYS_WAITID};
 use super::number::*;
 use super::usercopy::UserSlice;
 
@@ -113,6 +114,10 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
                 F_SETFD => "F_SETFD",
                 F_SETFL => "F_SETFL",
                 F_GETFL => "F_GETFL",
+                F_ADD_SEALS => "F_ADD_SEALS",
+                F_GET_SEALS => "F_GET_SEALS",
+                F_SETLOWAT => "F_SETLOWAT",
+                F_GETLOWAT => "F_GETLOWAT",
                 _ => "UNKNOWN"
             },
             c,
@@ -182,6 +187,10 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             "exit({})",
             b
         ),
+        SYS_EXIT_GROUP => format!(
+            "exit_group({})",
+            b
+        ),
         SYS_FUTEX => format!(
             "futex({:#X} [{:?}], {}, {}, {}, {})",
             b,
@@ -191,6 +200,13 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             e,
             f
         ),
+        SYS_FUTEX_WAITV => format!(
+            "futex_waitv({:#X}, {}, {:#X}, {:?})",
+            b,
+            c,
+            d,
+            unsafe { read_struct::<TimeSpec>(e) },
+        ),
         SYS_GETEGID => format!("getegid()"),
         SYS_GETENS => format!("getens()"),
         SYS_GETEUID => format!("geteuid()"),
@@ -199,6 +215,7 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
         SYS_GETPGID => format!("getpgid()"),
         SYS_GETPID => format!("getpid()"),
         SYS_GETPPID => format!("getppid()"),
+        SYS_GETTID => format!("gettid()"),
         SYS_GETUID => format!("getuid()"),
         SYS_IOPL => format!(
             "iopl({})",
@@ -209,6 +226,12 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             b,
             c
         ),
+        SYS_SIGQUEUE => format!(
+            "sigqueue({}, {}, {:#X})",
+            b,
+            c,
+            d
+        ),
         SYS_SIGRETURN => format!("sigreturn()"),
         SYS_SIGACTION => format!(
             "sigaction({}, {:#X}, {:#X}, {:#X})",
@@ -232,12 +255,27 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             b as *const u8,
             c,
         ),
+        SYS_MKPIDNS => format!("mkpidns()"),
         SYS_MPROTECT => format!(
             "mprotect({:#X}, {}, {:?})",
             b,
             c,
             MapFlags::from_bits(d)
         ),
+        SYS_MADVISE => format!(
+            "madvise({:#X}, {}, {})",
+            b,
+            c,
+            d
+        ),
+        SYS_MREMAP => format!(
+            "mremap({:#X}, {}, {}, {:#X}, {:#X})",
+            b,
+            c,
+            d,
+            e,
+            f
+        ),
         SYS_NANOSLEEP => format!(
             "nanosleep({:?}, ({}, {}))",
             unsafe { read_struct::<TimeSpec>(b) },
@@ -252,6 +290,11 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             "physalloc3({}, {}, {})",
             b, c, d,
         ),
+        SYS_PHYSALLOC_NODE => format!(
+            "physalloc_node({}, {})",
+            b,
+            c
+        ),
         SYS_PHYSFREE => format!(
             "physfree({:#X}, {})",
             b,
@@ -272,6 +315,11 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             unsafe { read_struct::<[usize; 2]>(b) },
             c
         ),
+        SYS_SETLABEL => format!(
+            "setlabel({}, {:?})",
+            b,
+            debug_path(c, d).as_ref().map(|p| ByteStr(p.as_bytes())),
+        ),
         SYS_SETREGID => format!(
             "setregid({}, {})",
             b,
@@ -297,6 +345,29 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             c,
             WaitFlags::from_bits(d)
         ),
+        SYS_GETRUSAGE => format!(
+            "getrusage({}, {:#X})",
+            b as isize,
+            c
+        ),
+        SYS_UNAME => format!(
+            "uname({:#X})",
+            b
+        ),
+        SYS_SPLICE => format!(
+            "splice({}, {}, {}, {:#X})",
+            b,
+            c,
+            d,
+            e
+        ),
+        SYS_WAITID => format!(
+            "waitid({}, {}, {:#X}, {:#X})",
+            b,
+            c,
+            d,
+            e
+        ),
         SYS_YIELD => format!("yield()"),
         _ => format!(
             "UNKNOWN{} {:#X}({:#X}, {:#X}, {:#X}, {:#X}, {:#X})",
@@ -309,3 +380,76 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
         )
     }
 }
+
+/// The name of the syscall `a`, if known
+pub fn name(a: usize) -> Option<&'static str> {
+    Some(match a {
+        SYS_OPEN => "open",
+        SYS_RMDIR => "rmdir",
+        SYS_UNLINK => "unlink",
+        SYS_CLOSE => "close",
+        SYS_DUP => "dup",
+        SYS_DUP2 => "dup2",
+        SYS_READ => "read",
+        SYS_WRITE => "write",
+        SYS_LSEEK => "lseek",
+        SYS_FCHMOD => "fchmod",
+        SYS_FCHOWN => "fchown",
+        SYS_FCNTL => "fcntl",
+        SYS_FMAP => "fmap",
+        SYS_FUNMAP => "funmap",
+        SYS_FPATH => "fpath",
+        SYS_FRENAME => "frename",
+        SYS_FSTAT => "fstat",
+        SYS_FSTATVFS => "fstatvfs",
+        SYS_FSYNC => "fsync",
+        SYS_FTRUNCATE => "ftruncate",
+        SYS_FUTIMENS => "futimens",
+        SYS_CLOCK_GETTIME => "clock_gettime",
+        SYS_EXIT => "exit",
+        SYS_EXIT_GROUP => "exit_group",
+        SYS_FUTEX => "futex",
+        SYS_FUTEX_WAITV => "futex_waitv",
+        SYS_GETEGID => "getegid",
+        SYS_GETENS => "getens",
+        SYS_GETEUID => "geteuid",
+        SYS_GETGID => "getgid",
+        SYS_GETNS => "getns",
+        SYS_GETPGID => "getpgid",
+        SYS_GETPID => "getpid",
+        SYS_GETPPID => "getppid",
+        SYS_GETTID => "gettid",
+        SYS_GETUID => "getuid",
+        SYS_IOPL => "iopl",
+        SYS_KILL => "kill",
+        SYS_SIGQUEUE => "sigqueue",
+        SYS_SIGRETURN => "sigreturn",
+        SYS_SIGACTION => "sigaction",
+        SYS_SIGPROCMASK => "sigprocmask",
+        SYS_MKNS => "mkns",
+        SYS_MKPIDNS => "mkpidns",
+        SYS_MPROTECT => "mprotect",
+        SYS_MADVISE => "madvise",
+        SYS_MREMAP => "mremap",
+        SYS_NANOSLEEP => "nanosleep",
+        SYS_PHYSALLOC => "physalloc",
+        SYS_PHYSALLOC3 => "physalloc3",
+        SYS_PHYSALLOC_NODE => "physalloc_node",
+        SYS_PHYSFREE => "physfree",
+        SYS_PHYSMAP => "physmap",
+        SYS_VIRTTOPHYS => "virttophys",
+        SYS_PIPE2 => "pipe2",
+        SYS_SETLABEL => "setlabel",
+        SYS_SETREGID => "setregid",
+        SYS_SETRENS => "setrens",
+        SYS_SETREUID => "setreuid",
+        SYS_UMASK => "umask",
+        SYS_WAITPID => "waitpid",
+        SYS_GETRUSAGE => "getrusage",
+        SYS_UNAME => "uname",
+        SYS_SPLICE => "splice",
+        SYS_WAITID => "waitid",
+        SYS_YIELD => "yield",
+        _ => return None,
+    })
+}
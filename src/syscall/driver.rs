@@ -1,8 +1,10 @@
 use crate::interrupt::InterruptStack;
-use crate::memory::{allocate_frames_complex, deallocate_frames, Frame, PAGE_SIZE};
+use crate::memory::{allocate_frames_complex, deallocate_frames, numa, Frame, PAGE_SIZE};
+use crate::memory::zone::Zone;
 use crate::paging::{PhysicalAddress, VirtualAddress};
-use crate::context;
+use crate::context::{self, memory::{self, AddrSpace, Region}};
 use crate::scheme::memory::{MemoryScheme, MemoryType};
+use crate::syscall::abi::PHYSALLOC_SPACE_20;
 use crate::syscall::error::{Error, EFAULT, EINVAL, ENOMEM, EPERM, ESRCH, Result};
 use crate::syscall::flag::{MapFlags, PhysallocFlags, PartialAllocStrategy, PhysmapFlags};
 
@@ -54,25 +56,33 @@ pub fn iopl(level: usize, stack: &mut InterruptStack) -> Result<usize> {
     Ok(0)
 }
 
-pub fn inner_physalloc(size: usize, flags: PhysallocFlags, strategy: Option<PartialAllocStrategy>, _min: usize) -> Result<(usize, usize)> {
-    if flags.contains(PhysallocFlags::SPACE_32 | PhysallocFlags::SPACE_64) {
-        return Err(Error::new(EINVAL));
+/// The zone requested by `physalloc3` flags, of which at most one may be given
+fn zone_of(flags: PhysallocFlags, space_20: bool) -> Result<Zone> {
+    match (space_20, flags.contains(PhysallocFlags::SPACE_32), flags.contains(PhysallocFlags::SPACE_64)) {
+        (true, false, false) => Ok(Zone::Low),
+        (false, true, false) => Ok(Zone::Dma32),
+        (false, false, _) => Ok(Zone::Normal),
+        _ => Err(Error::new(EINVAL)),
     }
-    allocate_frames_complex(size.div_ceil(PAGE_SIZE), flags, strategy, size.div_ceil(PAGE_SIZE)).ok_or(Error::new(ENOMEM)).map(|(frame, count)| (frame.start_address().data(), count * PAGE_SIZE))
+}
+
+pub fn inner_physalloc(size: usize, zone: Zone, strategy: Option<PartialAllocStrategy>, _min: usize) -> Result<(usize, usize)> {
+    allocate_frames_complex(size.div_ceil(PAGE_SIZE), zone, strategy, size.div_ceil(PAGE_SIZE)).ok_or(Error::new(ENOMEM)).map(|(frame, count)| (frame.start_address().data(), count * PAGE_SIZE))
 }
 pub fn physalloc(size: usize) -> Result<usize> {
     enforce_root()?;
-    inner_physalloc(size, PhysallocFlags::SPACE_64, None, size).map(|(base, _)| base)
+    inner_physalloc(size, Zone::Normal, None, size).map(|(base, _)| base)
 }
 pub fn physalloc3(size: usize, flags_raw: usize, min_inout_usize: UserSliceRw) -> Result<usize> {
     enforce_root()?;
-    let flags = PhysallocFlags::from_bits(flags_raw & !syscall::PARTIAL_ALLOC_STRATEGY_MASK).ok_or(Error::new(EINVAL))?;
+    let flags = PhysallocFlags::from_bits(flags_raw & !(syscall::PARTIAL_ALLOC_STRATEGY_MASK | PHYSALLOC_SPACE_20)).ok_or(Error::new(EINVAL))?;
+    let zone = zone_of(flags, flags_raw & PHYSALLOC_SPACE_20 != 0)?;
     let strategy = if flags.contains(PhysallocFlags::PARTIAL_ALLOC) {
         Some(PartialAllocStrategy::from_raw(flags_raw & syscall::PARTIAL_ALLOC_STRATEGY_MASK).ok_or(Error::new(EINVAL))?)
     } else {
         None
     };
-    let (base, count) = inner_physalloc(size, flags, strategy, min_inout_usize.read_usize()?)?;
+    let (base, count) = inner_physalloc(size, zone, strategy, min_inout_usize.read_usize()?)?;
 
     // TODO: handle error
     let _ = min_inout_usize.write_usize(count);
@@ -80,6 +90,17 @@ pub fn physalloc3(size: usize, flags_raw: usize, min_inout_usize: UserSliceRw) -
     Ok(base)
 }
 
+pub fn physalloc_node(size: usize, node: usize) -> Result<usize> {
+    enforce_root()?;
+    if size == 0 {
+        return Err(Error::new(EINVAL));
+    }
+    if node >= numa::node_count() {
+        return Err(Error::new(EINVAL));
+    }
+    numa::allocate_on(node, size.div_ceil(PAGE_SIZE)).ok_or(Error::new(ENOMEM)).map(|frame| frame.start_address().data())
+}
+
 pub fn inner_physfree(physical_address: usize, size: usize) -> Result<usize> {
     deallocate_frames(Frame::containing_address(PhysicalAddress::new(physical_address)), size.div_ceil(PAGE_SIZE));
 
@@ -113,10 +134,12 @@ pub fn physmap(physical_address: usize, size: usize, flags: PhysmapFlags) -> Res
 pub fn virttophys(virtual_address: usize) -> Result<usize> {
     enforce_root()?;
 
-    let addr_space = Arc::clone(context::current()?.read().addr_space()?);
-    let addr_space = addr_space.read();
+    let addr_space_lock = Arc::clone(context::current()?.read().addr_space()?);
+
+    // Drivers commonly translate memory they have just allocated, before touching it
+    let addr_space = AddrSpace::write_populated(&addr_space_lock, Region::new(VirtualAddress::new(virtual_address), 1))?;
 
-    match addr_space.table.utable.translate(VirtualAddress::new(virtual_address)) {
+    match memory::translate(&addr_space.table.utable, VirtualAddress::new(virtual_address)) {
         Some((physical_address, _)) => Ok(physical_address.data()),
         None => Err(Error::new(EFAULT))
     }
@@ -1,17 +1,21 @@
 //! Filesystem syscalls
 use alloc::sync::Arc;
-use spin::RwLock;
+use core::cmp;
 
+use crate::audit;
 use crate::context::file::{FileDescriptor, FileDescription};
+use crate::context::memory::{AddrSpace, Grant};
 use crate::context;
 use crate::memory::PAGE_SIZE;
-use crate::scheme::{self, FileHandle, OpenResult, current_caller_ctx, KernelScheme, SchemeId};
+use crate::scheme::{self, pipe, FileHandle, OpenResult, current_caller_ctx, KernelScheme, SchemeId};
+use crate::sync::RwLock;
+use crate::syscall::abi::{SPLICE_F_MORE, SPLICE_F_MOVE, SPLICE_F_NONBLOCK};
 use crate::syscall::data::Stat;
 use crate::syscall::error::*;
 use crate::syscall::flag::*;
 use crate::syscall::scheme::CallerCtx;
 
-use super::usercopy::{UserSlice, UserSliceWo, UserSliceRo};
+use super::usercopy::{UserSlice, UserSliceRo, UserSliceRw, UserSliceWo};
 
 /*pub fn file_op(a: usize, fd: FileHandle, c: usize, d: usize) -> Result<usize> {
     let (file, pid, uid, gid) = {
@@ -97,7 +101,12 @@ pub fn open(raw_path: UserSliceRo, flags: usize) -> Result<FileHandle> {
             (scheme_id, Arc::clone(scheme))
         };
 
-        match scheme.kopen(reference, flags, CallerCtx { uid, gid, pid })? {
+        let result = scheme.kopen(reference, flags, CallerCtx { uid, gid, pid });
+        if matches!(result, Err(Error { errno: EACCES })) {
+            audit::record(audit::Kind::OpenDenied, format_args!("path={:?} flags={:#x}", path, flags));
+        }
+
+        match result? {
             OpenResult::SchemeLocal(number) => Arc::new(RwLock::new(FileDescription {
                 namespace: scheme_ns,
                 scheme: scheme_id,
@@ -280,13 +289,15 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
     let description = file.description.read();
 
     // Communicate fcntl with scheme
-    if cmd != F_DUPFD && cmd != F_GETFD && cmd != F_SETFD {
+    let scheme_result = if cmd != F_DUPFD && cmd != F_GETFD && cmd != F_SETFD {
         let scheme = {
             let schemes = scheme::schemes();
             let scheme = schemes.get(description.scheme).ok_or(Error::new(EBADF))?;
             Arc::clone(scheme)
         };
-        scheme.fcntl(description.number, cmd, arg)?;
+        Some(scheme.fcntl(description.number, cmd, arg)?)
+    } else {
+        None
     };
 
     // Perform kernel operation if scheme agrees
@@ -331,8 +342,9 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
                     file.description.write().flags = new_flags;
                     Ok(0)
                 },
+                // Commands only known to the scheme, such as F_GET_SEALS, return its result
                 _ => {
-                    Err(Error::new(EINVAL))
+                    scheme_result.ok_or(Error::new(EINVAL))
                 }
             },
             None => Err(Error::new(EBADF))
@@ -395,6 +407,102 @@ pub fn funmap(virtual_address: usize, length: usize) -> Result<usize> {
 
     let addr_space = Arc::clone(context::current()?.read().addr_space()?);
     addr_space.write().munmap(page, page_count);
+    scheme::mempressure::update();
+    crate::tracepoint!(Munmap, virtual_address, length_aligned);
 
     Ok(0)
 }
+
+/// Largest amount of data moved by a single `splice`, the capacity of a pipe
+const SPLICE_MAX: usize = 65536;
+
+/// Move up to `len` bytes from `fd_in` to `fd_out`, at least one of which must be a pipe, without
+/// a round trip through userspace. Data read from a pipe which `fd_out` does not accept is put
+/// back in order, other readers of the pipe waiting meanwhile, and data is only read into a pipe
+/// once it has room for it.
+pub fn splice(fd_in: FileHandle, fd_out: FileHandle, len: usize, flags: usize) -> Result<usize> {
+    if flags & !(SPLICE_F_MOVE | SPLICE_F_NONBLOCK | SPLICE_F_MORE) != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let nonblock = flags & SPLICE_F_NONBLOCK == SPLICE_F_NONBLOCK;
+
+    let (desc_in, desc_out) = {
+        let context_lock = context::current()?;
+        let context = context_lock.read();
+        let desc_in = *context.get_file(fd_in).ok_or(Error::new(EBADF))?.description.read();
+        let desc_out = *context.get_file(fd_out).ok_or(Error::new(EBADF))?.description.read();
+        (desc_in, desc_out)
+    };
+    let pipe_scheme_id = pipe::pipe_scheme_id();
+    if desc_in.scheme != pipe_scheme_id && desc_out.scheme != pipe_scheme_id {
+        return Err(Error::new(EINVAL));
+    }
+    if desc_in.flags & O_ACCMODE == O_WRONLY || desc_out.flags & O_ACCMODE == O_RDONLY {
+        return Err(Error::new(EBADF));
+    }
+
+    let len = cmp::min(len, SPLICE_MAX);
+    if len == 0 {
+        return Ok(0);
+    }
+
+    // The data passes through a buffer in the caller's address space, only because schemes take
+    // user memory. User schemes borrow its pages, rather than copying them again.
+    let addr_space = AddrSpace::current()?;
+    let page_count = len.div_ceil(PAGE_SIZE);
+    let page = addr_space.write().mmap(None, page_count, MapFlags::PROT_READ | MapFlags::PROT_WRITE, |page, page_flags, _mapper, _flusher| {
+        Ok(Grant::zeroed_lazy(page, page_count, page_flags))
+    })?;
+    let buf = UserSlice::rw(page.start_address().data(), len)?;
+
+    let result = if desc_in.scheme == pipe_scheme_id {
+        splice_from_pipe(desc_in.number, &desc_out, buf, nonblock)
+    } else {
+        splice_to_pipe(&desc_in, desc_out.number, buf, nonblock)
+    };
+
+    addr_space.write().munmap(page, page_count);
+    result
+}
+
+fn splice_from_pipe(pipe_number: usize, desc_out: &FileDescription, buf: UserSliceRw, nonblock: bool) -> Result<usize> {
+    let bytes_read = pipe::splice_read(pipe_number, buf.reinterpret_unchecked(), nonblock)?;
+    let data: UserSliceRo = buf.limit(bytes_read).expect("bytes_read <= buf.len()").reinterpret_unchecked();
+
+    let written = if desc_out.scheme == pipe::pipe_scheme_id() {
+        pipe::write(desc_out.number, data, nonblock)
+    } else {
+        match scheme::schemes().get(desc_out.scheme).map(Arc::clone) {
+            Some(scheme) => scheme.kwrite(desc_out.number, data),
+            None => Err(Error::new(EBADF)),
+        }
+    };
+
+    let unwritten = match written {
+        Ok(written) => data.advance(written),
+        Err(_) => Some(data),
+    };
+    // The splice must be finished whatever happened, or the pipe could not be read anymore
+    pipe::finish_splice(pipe_number, unwritten.unwrap_or(data.limit(0).expect("0 <= data.len()")))?;
+    if unwritten.is_none() {
+        return Err(Error::new(EIO));
+    }
+    written
+}
+
+fn splice_to_pipe(desc_in: &FileDescription, pipe_number: usize, buf: UserSliceRw, nonblock: bool) -> Result<usize> {
+    let room = pipe::reserve(pipe_number, nonblock)?;
+
+    let scheme = Arc::clone(scheme::schemes().get(desc_in.scheme).ok_or(Error::new(EBADF))?);
+    let buf = buf.limit(cmp::min(room, buf.len())).expect("min(room, len) <= len");
+    let bytes_read = scheme.kread(desc_in.number, buf.reinterpret_unchecked())?;
+    let data: UserSliceRo = buf.limit(bytes_read).ok_or(Error::new(EIO))?.reinterpret_unchecked();
+
+    // The data has been consumed from the source, so it must all be written, even if another
+    // writer has taken the room in the meantime
+    let mut written = 0;
+    while written < bytes_read {
+        written += pipe::write(pipe_number, data.advance(written).expect("written < len"), false)?;
+    }
+    Ok(written)
+}
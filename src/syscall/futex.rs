@@ -4,26 +4,57 @@
 //! For more information about futexes, please read [this](https://eli.thegreenplace.net/2018/basics-of-futexes/) blog post, and the [futex(2)](http://man7.org/linux/man-pages/man2/futex.2.html) man page
 use alloc::collections::VecDeque;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use rmm::Arch;
 use core::intrinsics;
-use spin::RwLock;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::context::{self, memory::AddrSpace, Context};
+use crate::context::{self, memory::{self, AddrSpace, Region}, Context};
 use crate::memory::PhysicalAddress;
 use crate::paging::{Page, VirtualAddress};
+use crate::sync::RwLock;
 use crate::time;
 
+use crate::syscall::abi::{FutexWaitv, FUTEX2_PRIVATE, FUTEX2_SIZE_MASK, FUTEX_WAITV_MAX};
 use crate::syscall::data::TimeSpec;
-use crate::syscall::error::{Error, Result, EAGAIN, EFAULT, EINVAL, ESRCH};
+use crate::syscall::error::{Error, Result, EAGAIN, EFAULT, EINTR, EINVAL, ESRCH, ETIMEDOUT};
 use crate::syscall::flag::{FUTEX_REQUEUE, FUTEX_WAIT, FUTEX_WAIT64, FUTEX_WAKE};
 
-use super::usercopy::UserSlice;
+use super::usercopy::{UserSlice, UserSliceRo};
 
 type FutexList = VecDeque<FutexEntry>;
 
 pub struct FutexEntry {
     target_physaddr: PhysicalAddress,
     context_lock: Arc<RwLock<Context>>,
+    /// Set for entries of a `futex_waitv`, to the index of this futex in the waiter array, and
+    /// the woken index shared by all entries of that wait
+    waitv: Option<(usize, Arc<AtomicUsize>)>,
+}
+
+impl FutexEntry {
+    /// Claim the wakeup of this entry, which fails if another futex of the same `futex_waitv` was
+    /// already woken
+    fn claim(&self) -> bool {
+        match self.waitv {
+            Some((index, ref woken)) => woken.compare_exchange(usize::MAX, index, Ordering::SeqCst, Ordering::SeqCst).is_ok(),
+            None => true,
+        }
+    }
+}
+
+/// Atomically load a futex word of `size` bytes, which must be naturally aligned
+unsafe fn load_word(physaddr: PhysicalAddress, size: usize) -> u64 {
+    // On systems where virtual memory is not abundant, we might instead add an atomic usercopy
+    // function.
+    let accessible_addr = crate::paging::RmmA::phys_to_virt(physaddr).data();
+
+    match size {
+        1 => u64::from(intrinsics::atomic_load_seqcst::<u8>(accessible_addr as *const u8)),
+        2 => u64::from(intrinsics::atomic_load_seqcst::<u16>(accessible_addr as *const u16)),
+        4 => u64::from(intrinsics::atomic_load_seqcst::<u32>(accessible_addr as *const u32)),
+        _ => intrinsics::atomic_load_seqcst::<u64>(accessible_addr as *const u64),
+    }
 }
 
 // TODO: Process-private futexes? In that case, put the futex table in each AddrSpace.
@@ -39,7 +70,7 @@ fn validate_and_translate_virt(space: &AddrSpace, addr: VirtualAddress) -> Optio
     let page = Page::containing_address(addr);
     let off = addr.data() - page.start_address().data();
 
-    let (frame, _) = space.table.utable.translate(page.start_address())?;
+    let (frame, _) = memory::translate(&space.table.utable, page.start_address())?;
 
     Some(frame.add(off))
 }
@@ -47,6 +78,9 @@ fn validate_and_translate_virt(space: &AddrSpace, addr: VirtualAddress) -> Optio
 pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> Result<usize> {
     let addr_space_lock = Arc::clone(context::current()?.read().addr_space()?);
 
+    // The futex may be in lazily allocated memory that has not been touched yet
+    drop(AddrSpace::write_populated(&addr_space_lock, Region::new(VirtualAddress::new(addr), core::mem::size_of::<usize>()))?);
+
     // Keep the address space locked so we can safely read from the physical address. Unlock it
     // before context switching.
     let addr_space_guard = addr_space_lock.read();
@@ -66,33 +100,18 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> R
 
                 let context_lock = context::current()?;
 
-                let (fetched, expected) = if op == FUTEX_WAIT {
-                    // Must be aligned, otherwise it could cross a page boundary and mess up the
-                    // (simpler) validation we did in the first place.
-                    if addr % 4 != 0 {
-                        return Err(Error::new(EINVAL));
-                    }
-
-                    // On systems where virtual memory is not abundant, we might instead add an
-                    // atomic usercopy function.
-                    let accessible_addr = unsafe { crate::paging::RmmA::phys_to_virt(target_physaddr) }.data();
-
-                    (
-                        u64::from(unsafe {
-                            intrinsics::atomic_load_seqcst::<u32>(accessible_addr as *const u32)
-                        }),
-                        u64::from(val as u32),
-                    )
+                let (size, expected) = if op == FUTEX_WAIT {
+                    (4, u64::from(val as u32))
                 } else {
                     // op == FUTEX_WAIT64
-                    if addr % 8 != 0 {
-                        return Err(Error::new(EINVAL));
-                    }
-                    (
-                        unsafe { intrinsics::atomic_load_seqcst::<u64>(addr as *const u64) },
-                        val as u64,
-                    )
+                    (8, val as u64)
                 };
+                // Must be aligned, otherwise it could cross a page boundary and mess up the
+                // (simpler) validation we did in the first place.
+                if addr % size != 0 {
+                    return Err(Error::new(EINVAL));
+                }
+                let fetched = unsafe { load_word(target_physaddr, size) };
                 if fetched != expected {
                     return Err(Error::new(EAGAIN));
                 }
@@ -114,6 +133,7 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> R
                 futexes.push_back(FutexEntry {
                     target_physaddr,
                     context_lock,
+                    waitv: None,
                 });
             }
 
@@ -153,9 +173,11 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> R
                         continue;
                     }
                     if let Some(futex) = futexes.swap_remove_back(i) {
-                        let mut context_guard = futex.context_lock.write();
-                        context_guard.unblock();
-                        woken += 1;
+                        if futex.claim() {
+                            let mut context_guard = futex.context_lock.write();
+                            context_guard.unblock();
+                            woken += 1;
+                        }
                     }
                 }
             }
@@ -181,8 +203,10 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> R
                         i += 1;
                     }
                     if let Some(futex) = futexes.swap_remove_back(i) {
-                        futex.context_lock.write().unblock();
-                        woken += 1;
+                        if futex.claim() {
+                            futex.context_lock.write().unblock();
+                            woken += 1;
+                        }
                     }
                 }
                 while i < futexes.len() && requeued < val2 {
@@ -199,3 +223,97 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> R
         _ => Err(Error::new(EINVAL)),
     }
 }
+
+/// Wait until any of the futexes in `waiters` is woken, returning its index. Every futex word can
+/// be 8, 16, 32 or 64 bits wide, as given by its flags.
+pub fn futex_waitv(waiters: UserSliceRo, flags: usize, timeout: Option<UserSliceRo>) -> Result<usize> {
+    if flags != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let count = waiters.len() / core::mem::size_of::<FutexWaitv>();
+    if count == 0 || count > FUTEX_WAITV_MAX {
+        return Err(Error::new(EINVAL));
+    }
+    let timeout_opt = timeout.map(|buf| unsafe { buf.read_exact::<TimeSpec>() }).transpose()?;
+
+    let mut words = Vec::with_capacity(count);
+    for chunk in waiters.in_exact_chunks(core::mem::size_of::<FutexWaitv>()) {
+        let waiter = unsafe { chunk.read_exact::<FutexWaitv>()? };
+        if waiter.flags & !(FUTEX2_SIZE_MASK | FUTEX2_PRIVATE) != 0 || waiter.__reserved != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let size = 1_usize << (waiter.flags & FUTEX2_SIZE_MASK);
+        let addr = waiter.uaddr as usize;
+        if addr % size != 0 || (size < 8 && waiter.val >> (size * 8) != 0) {
+            return Err(Error::new(EINVAL));
+        }
+        words.push((addr, size, waiter.val));
+    }
+
+    let addr_space_lock = Arc::clone(context::current()?.read().addr_space()?);
+    for &(addr, size, _) in &words {
+        drop(AddrSpace::write_populated(&addr_space_lock, Region::new(VirtualAddress::new(addr), size))?);
+    }
+    let addr_space_guard = addr_space_lock.read();
+
+    let mut targets = Vec::with_capacity(count);
+    for &(addr, size, expected) in &words {
+        let target_physaddr = validate_and_translate_virt(&*addr_space_guard, VirtualAddress::new(addr)).ok_or(Error::new(EFAULT))?;
+        targets.push((target_physaddr, size, expected));
+    }
+
+    let context_lock = context::current()?;
+    let woken = Arc::new(AtomicUsize::new(usize::MAX));
+    let mut end_opt = None;
+
+    {
+        let mut futexes = FUTEXES.write();
+
+        for &(target_physaddr, size, expected) in &targets {
+            if unsafe { load_word(target_physaddr, size) } != expected {
+                return Err(Error::new(EAGAIN));
+            }
+        }
+
+        {
+            let mut context = context_lock.write();
+
+            if let Some(timeout) = timeout_opt {
+                let end = time::monotonic()
+                    + (timeout.tv_sec as u128 * time::NANOS_PER_SEC)
+                    + (timeout.tv_nsec as u128);
+                context.wake = Some(end);
+                end_opt = Some(end);
+            }
+
+            context.block("futex_waitv");
+        }
+
+        for (index, &(target_physaddr, _, _)) in targets.iter().enumerate() {
+            futexes.push_back(FutexEntry {
+                target_physaddr,
+                context_lock: Arc::clone(&context_lock),
+                waitv: Some((index, Arc::clone(&woken))),
+            });
+        }
+    }
+
+    drop(addr_space_guard);
+
+    unsafe {
+        context::switch();
+    }
+
+    // Remove the entries that were not woken, which also prevents any later wakeup from claiming
+    // this wait
+    FUTEXES.write().retain(|futex| !futex.waitv.as_ref().map_or(false, |(_, entry_woken)| Arc::ptr_eq(entry_woken, &woken)));
+    context_lock.write().wake = None;
+
+    match woken.load(Ordering::SeqCst) {
+        usize::MAX => match end_opt {
+            Some(end) if time::monotonic() >= end => Err(Error::new(ETIMEDOUT)),
+            _ => Err(Error::new(EINTR)),
+        },
+        index => Ok(index),
+    }
+}
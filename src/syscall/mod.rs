@@ -21,7 +21,7 @@ pub use self::syscall::{
 
 pub use self::driver::*;
 pub use self::fs::*;
-pub use self::futex::futex;
+pub use self::futex::{futex, futex_waitv};
 pub use self::privilege::*;
 pub use self::process::*;
 pub use self::time::*;
@@ -30,6 +30,7 @@ pub use self::usercopy::validate_region;
 use self::data::{Map, SigAction, TimeSpec};
 use self::error::{Error, Result, ENOSYS};
 use self::flag::{MapFlags, PhysmapFlags, WaitFlags};
+use self::abi::*;
 use self::number::*;
 
 use crate::context::ContextId;
@@ -38,6 +39,9 @@ use crate::interrupt::InterruptStack;
 use crate::scheme::{FileHandle, SchemeNamespace, memory::MemoryScheme};
 use crate::syscall::usercopy::UserSlice;
 
+/// ABI additions not yet in the syscall crate
+pub mod abi;
+
 /// Debug
 pub mod debug;
 
@@ -129,13 +133,32 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, stack
                 ).map(|()| 0),
                 SYS_CLOCK_GETTIME => clock_gettime(b, UserSlice::wo(c, core::mem::size_of::<TimeSpec>())?).map(|()| 0),
                 SYS_FUTEX => futex(b, c, d, e, f),
+                SYS_FUTEX_WAITV => futex_waitv(
+                    UserSlice::ro(b, c.checked_mul(core::mem::size_of::<FutexWaitv>()).ok_or(Error::new(EOVERFLOW))?)?,
+                    d,
+                    UserSlice::ro(e, core::mem::size_of::<TimeSpec>())?.none_if_null(),
+                ),
                 SYS_GETPID => getpid().map(ContextId::into),
                 SYS_GETPGID => getpgid(ContextId::from(b)).map(ContextId::into),
                 SYS_GETPPID => getppid().map(ContextId::into),
+                SYS_GETTID => gettid().map(ContextId::into),
 
                 SYS_EXIT => exit((b & 0xFF) << 8),
+                SYS_EXIT_GROUP => exit_group((b & 0xFF) << 8),
                 SYS_KILL => kill(ContextId::from(b), c),
+                SYS_SIGQUEUE => sigqueue(ContextId::from(b), c, d),
                 SYS_WAITPID => waitpid(ContextId::from(b), if c == 0 { None } else { Some(UserSlice::wo(c, core::mem::size_of::<usize>())?) }, WaitFlags::from_bits_truncate(d)).map(ContextId::into),
+                SYS_SPLICE => splice(FileHandle::from(b), FileHandle::from(c), d, e),
+                SYS_GETRUSAGE => getrusage(b, UserSlice::wo(c, core::mem::size_of::<Rusage>())?).map(|()| 0),
+                SYS_UNAME => uname(UserSlice::wo(b, core::mem::size_of::<Uname>())?).map(|()| 0),
+                SYS_ARCH_PRCTL => arch_prctl(b, c),
+                SYS_PROCESS_VM_READV | SYS_PROCESS_VM_WRITEV => process_vm_rw(
+                    ContextId::from(b),
+                    UserSlice::ro(c, d.checked_mul(core::mem::size_of::<IoVec>()).ok_or(Error::new(EOVERFLOW))?)?,
+                    UserSlice::ro(e, f.checked_mul(core::mem::size_of::<IoVec>()).ok_or(Error::new(EOVERFLOW))?)?,
+                    a == SYS_PROCESS_VM_WRITEV,
+                ),
+                SYS_WAITID => waitid(b, c, UserSlice::wo(d, core::mem::size_of::<WaitInfo>())?.none_if_null(), e).map(|()| 0),
                 SYS_IOPL => iopl(b, stack),
                 SYS_GETEGID => getegid(),
                 SYS_GETENS => getens(),
@@ -144,8 +167,12 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, stack
                 SYS_GETNS => getns(),
                 SYS_GETUID => getuid(),
                 SYS_MPROTECT => mprotect(b, c, MapFlags::from_bits_truncate(d)),
+                SYS_MADVISE => madvise(b, c, d),
+                SYS_MREMAP => mremap(b, c, d, e, f),
                 SYS_MKNS => mkns(UserSlice::ro(b, c.checked_mul(core::mem::size_of::<[usize; 2]>()).ok_or(Error::new(EOVERFLOW))?)?),
+                SYS_MKPIDNS => mkpidns(),
                 SYS_SETPGID => setpgid(ContextId::from(b), ContextId::from(c)),
+                SYS_SETLABEL => setlabel(ContextId::from(b), UserSlice::ro(c, d)?).map(|()| 0),
                 SYS_SETREUID => setreuid(b as u32, c as u32),
                 SYS_SETRENS => setrens(SchemeNamespace::from(b), SchemeNamespace::from(c)),
                 SYS_SETREGID => setregid(b as u32, c as u32),
@@ -164,6 +191,7 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, stack
                 SYS_PIPE2 => pipe2(UserSlice::wo(b, 2 * core::mem::size_of::<usize>())?, c).map(|()| 0),
                 SYS_PHYSALLOC => physalloc(b),
                 SYS_PHYSALLOC3 => physalloc3(b, c, UserSlice::rw(d, core::mem::size_of::<usize>())?),
+                SYS_PHYSALLOC_NODE => physalloc_node(b, c),
                 SYS_PHYSFREE => physfree(b, c),
                 SYS_PHYSMAP => physmap(b, c, PhysmapFlags::from_bits_truncate(d)),
                 SYS_UMASK => umask(b),
@@ -212,6 +240,8 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, stack
         0
     };
 
+    let stats_start = crate::context::syscalls::enabled().then(crate::time::monotonic);
+
     // The next lines set the current syscall in the context struct, then once the inner() function
     // completes, we set the current syscall to none.
     //
@@ -225,13 +255,24 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize, stack
         }
     }
 
+    crate::tracepoint!(SyscallEnter, a, b);
     let result = inner(a, b, c, d, e, f, stack);
+    crate::tracepoint!(SyscallExit, a, match result {
+        Ok(value) => value,
+        Err(ref err) => -err.errno as usize,
+    });
 
     {
         let contexts = crate::context::contexts();
         if let Some(context_lock) = contexts.current() {
             let mut context = context_lock.write();
             context.syscall = None;
+            if let Some(start) = stats_start {
+                context.syscall_stats.record(a, (crate::time::monotonic() - start) as u64);
+            }
+            if let Some(ref stack) = context.kstack {
+                stack.check_canary(context.id);
+            }
         }
     }
 
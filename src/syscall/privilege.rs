@@ -1,6 +1,8 @@
 use alloc::vec::Vec;
 
+use crate::audit;
 use crate::context;
+use crate::context::pid_ns::PidNamespace;
 use crate::scheme::{self, SchemeNamespace};
 use crate::syscall::error::*;
 
@@ -77,9 +79,29 @@ pub fn mkns(mut user_buf: UserSliceRo) -> Result<usize> {
     }
 
     let to = scheme::schemes_mut().make_ns(from, names)?;
+    audit::record(audit::Kind::Namespace, format_args!("mkns from={} to={}", from.into(), to.into()));
     Ok(to.into())
 }
 
+/// Create a PID namespace nested within that of the caller, in which the processes it creates are
+/// placed from then on. Like `mkns`, this is restricted to root.
+pub fn mkpidns() -> Result<usize> {
+    let context_lock = context::current()?;
+    let mut context = context_lock.write();
+
+    if context.euid != 0 {
+        return Err(Error::new(EACCES));
+    }
+
+    let pid_ns = PidNamespace::new(context.pid_ns.clone())?;
+    let level = pid_ns.level;
+    context.pid_ns_for_children = Some(pid_ns);
+    drop(context);
+
+    audit::record(audit::Kind::Namespace, format_args!("mkpidns level={}", level));
+    Ok(0)
+}
+
 pub fn setregid(rgid: u32, egid: u32) -> Result<usize> {
     let contexts = context::contexts();
     let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
@@ -121,6 +143,8 @@ pub fn setregid(rgid: u32, egid: u32) -> Result<usize> {
             return Err(Error::new(EPERM));
         };
 
+    let old = (context.rgid, context.egid);
+
     if setrgid {
         context.rgid = rgid;
     }
@@ -129,6 +153,13 @@ pub fn setregid(rgid: u32, egid: u32) -> Result<usize> {
         context.egid = egid;
     }
 
+    let new = (context.rgid, context.egid);
+    drop(context);
+    drop(contexts);
+    if old != new {
+        audit::record(audit::Kind::SetGid, format_args!("rgid={}->{} egid={}->{}", old.0, new.0, old.1, new.1));
+    }
+
     Ok(0)
 }
 
@@ -185,6 +216,8 @@ pub fn setrens(rns: SchemeNamespace, ens: SchemeNamespace) -> Result<usize> {
             return Err(Error::new(EPERM));
         };
 
+    let old = (context.rns, context.ens);
+
     if setrns {
         context.rns = rns;
     }
@@ -193,6 +226,13 @@ pub fn setrens(rns: SchemeNamespace, ens: SchemeNamespace) -> Result<usize> {
         context.ens = ens;
     }
 
+    let new = (context.rns, context.ens);
+    drop(context);
+    drop(contexts);
+    if old != new {
+        audit::record(audit::Kind::Namespace, format_args!("setrens rns={}->{} ens={}->{}", old.0.into(), new.0.into(), old.1.into(), new.1.into()));
+    }
+
     Ok(0)
 }
 
@@ -237,6 +277,8 @@ pub fn setreuid(ruid: u32, euid: u32) -> Result<usize> {
             return Err(Error::new(EPERM));
         };
 
+    let old = (context.ruid, context.euid);
+
     if setruid {
         context.ruid = ruid;
     }
@@ -245,5 +287,12 @@ pub fn setreuid(ruid: u32, euid: u32) -> Result<usize> {
         context.euid = euid;
     }
 
+    let new = (context.ruid, context.euid);
+    drop(context);
+    drop(contexts);
+    if old != new {
+        audit::record(audit::Kind::SetUid, format_args!("ruid={}->{} euid={}->{}", old.0, new.0, old.1, new.1));
+    }
+
     Ok(0)
 }
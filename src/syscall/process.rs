@@ -2,24 +2,30 @@ use alloc::{
     sync::Arc,
     vec::Vec,
 };
-use core::mem;
+use core::{mem, ptr};
 
-use spin::{RwLock, RwLockWriteGuard};
-
-use crate::context::{Context, ContextId, memory::AddrSpace, WaitpidKey};
+use crate::context::{Context, ContextId, Label, memory::AddrSpace, WaitpidKey};
+use crate::context::label::LABEL_MAX;
+use crate::context::pid_ns;
+use crate::context::thread_group::ThreadGroup;
 
 use crate::Bootstrap;
+use crate::common::sha256;
 use crate::context;
 use crate::interrupt;
 use crate::paging::mapper::{InactiveFlusher, PageFlushAll};
-use crate::paging::{Page, PageFlags, VirtualAddress, PAGE_SIZE};
+use crate::paging::{Page, PageFlags, RmmArch, VirtualAddress, PAGE_SIZE};
 use crate::ptrace;
 use crate::start::usermode;
+use crate::sync::{RwLock, RwLockWriteGuard};
 use crate::syscall::data::SigAction;
 use crate::syscall::error::*;
-use crate::syscall::flag::{wifcontinued, wifstopped, MapFlags,
-    PTRACE_STOP_EXIT, SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK,
-    SIGCONT, SIGTERM, WaitFlags, WCONTINUED, WNOHANG, WUNTRACED};
+use crate::syscall::abi::{self, IoVec, Rusage, SigInfo, Uname, WaitInfo, CLD_CONTINUED, CLD_EXITED, CLD_KILLED, CLD_STOPPED,
+    MREMAP_FIXED, MREMAP_MAYMOVE, P_ALL, P_PGID, P_PID, RUSAGE_CHILDREN, RUSAGE_SELF, RUSAGE_THREAD,
+    SI_QUEUE, SI_USER, WEXITED, WNOWAIT};
+use crate::syscall::flag::{wexitstatus, wifcontinued, wifsignaled, wifstopped, wstopsig, wtermsig,
+    MapFlags, PTRACE_STOP_EXIT, SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK,
+    SIGCHLD, SIGCONT, SIGKILL, SIGTERM, WaitFlags, WCONTINUED, WNOHANG, WUNTRACED};
 use crate::syscall::ptrace_event;
 
 use super::usercopy::{UserSliceWo, UserSliceRo};
@@ -62,12 +68,35 @@ fn empty<'lock>(context_lock: &'lock RwLock<Context>, mut context: RwLockWriteGu
     context
 }
 
+/// Wait until the threads of `thread_group` other than the exiting context `pid` have exited, so
+/// that the status of the group is only reported once all of it is gone
+fn wait_for_threads(context_lock: &RwLock<Context>, thread_group: &ThreadGroup, pid: ContextId) {
+    loop {
+        let exit_status = thread_group.exit_status.lock();
+        // The signals killing the group would otherwise keep waking this context
+        context_lock.write().pending.clear();
+        thread_group.pending.lock().clear();
+
+        let remaining = context::contexts().iter().any(|(&id, other_lock)| {
+            let other = other_lock.read();
+            id != pid && ptr::eq(&*other.thread_group, thread_group) && !matches!(other.status, context::Status::Exited(_))
+        });
+        if !remaining {
+            break;
+        }
+        thread_group.exited.wait(exit_status, "exit");
+    }
+}
+
 pub fn exit(status: usize) -> ! {
     ptrace::breakpoint_callback(PTRACE_STOP_EXIT, Some(ptrace_event!(PTRACE_STOP_EXIT, status)));
 
     {
         let context_lock = context::current().expect("exit failed to find context");
 
+        // If the thread group is exiting as a whole, every thread reports the group status
+        let status = context_lock.read().thread_group.exit_status.lock().unwrap_or(status);
+
         let close_files;
         let pid = {
             let mut context = context_lock.write();
@@ -91,6 +120,20 @@ pub fn exit(status: usize) -> ! {
             }
         }
 
+        // The init of a PID namespace takes every context left in it along
+        {
+            let ns = context_lock.read().pid_ns.clone();
+            if let Some(ns) = ns.filter(|ns| pid_ns::is_init(ns, pid)) {
+                let members = pid_ns::kill(&ns);
+                let contexts = context::contexts();
+                for member in members.into_iter().filter(|&member| member != pid) {
+                    if let Some(context_lock) = contexts.get(member) {
+                        context_lock.write().pending.push(SigInfo::kernel(SIGKILL));
+                    }
+                }
+            }
+        }
+
         // Files must be closed while context is valid so that messages can be passed
         for (_fd, file_opt) in close_files.into_iter().enumerate() {
             if let Some(file) = file_opt {
@@ -116,6 +159,17 @@ pub fn exit(status: usize) -> ! {
             }
         }
 
+        // The group ends with its last thread, whether the leader called `exit` or `exit_group`,
+        // and the leader reports its status then
+        let thread_group = Arc::clone(&context_lock.read().thread_group);
+        let status = if pid == thread_group.tgid {
+            wait_for_threads(&context_lock, &thread_group, pid);
+            // Another thread may have called `exit_group` meanwhile
+            thread_group.exit_status.lock().unwrap_or(status)
+        } else {
+            status
+        };
+
         let (vfork, children) = {
             let mut context = context_lock.write();
 
@@ -130,6 +184,14 @@ pub fn exit(status: usize) -> ! {
 
             (vfork, children)
         };
+        // The leader may be waiting for this thread to report the status of the group
+        {
+            let _exit_status = thread_group.exit_status.lock();
+            thread_group.exited.notify();
+        }
+        // A context waiting for memory may have killed this one
+        context::oom::released();
+        crate::scheme::mempressure::update();
 
         {
             let contexts = context::contexts();
@@ -164,47 +226,197 @@ pub fn exit(status: usize) -> ! {
     unreachable!();
 }
 
+/// Terminate every thread in the thread group of the current context, with `status` as the exit
+/// status of the group. The group leader reports it once the other threads have exited.
+pub fn exit_group(status: usize) -> ! {
+    {
+        let (id, thread_group) = {
+            let context_lock = context::current().expect("exit_group failed to find context");
+            let context = context_lock.read();
+            (context.id, Arc::clone(&context.thread_group))
+        };
+
+        thread_group.exit_status.lock().get_or_insert(status);
+
+        let contexts = context::contexts();
+        for (&other_id, context_lock) in contexts.iter() {
+            if other_id == id {
+                continue;
+            }
+            let mut context = context_lock.write();
+            if Arc::ptr_eq(&context.thread_group, &thread_group) {
+                context.pending.push(SigInfo::kernel(SIGKILL));
+            }
+        }
+    }
+
+    exit(status);
+}
+
 pub fn getpid() -> Result<ContextId> {
     let contexts = context::contexts();
     let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
     let context = context_lock.read();
-    Ok(context.id)
+    Ok(pid_ns::to_local_or_zero(context.pid_ns.as_deref(), context.tgid()))
+}
+
+pub fn gettid() -> Result<ContextId> {
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    Ok(pid_ns::to_local_or_zero(context.pid_ns.as_deref(), context.id))
+}
+
+pub fn getrusage(who: usize, usage_out: UserSliceWo) -> Result<()> {
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+
+    let usage = match who {
+        RUSAGE_THREAD => context_lock.read().usage(),
+        RUSAGE_SELF => {
+            let tgid = context_lock.read().tgid();
+            let mut usage = Rusage::default();
+            for (_id, context_lock) in contexts.iter() {
+                let context = context_lock.read();
+                if context.tgid() == tgid {
+                    usage.add(&context.usage());
+                    usage.add(&context.threads_usage);
+                }
+            }
+            usage
+        }
+        RUSAGE_CHILDREN => {
+            let tgid = context_lock.read().tgid();
+            contexts.get(tgid).unwrap_or(context_lock).read().children_usage
+        }
+        _ => return Err(Error::new(EINVAL)),
+    };
+    drop(contexts);
+
+    usage_out.copy_exactly(&usage)
+}
+
+/// The kernel version, and the features it was built with
+pub fn uname_info() -> Uname {
+    fn copy_str(dst: &mut [u8], src: &str) {
+        let len = src.len().min(dst.len());
+        dst[..len].copy_from_slice(&src.as_bytes()[..len]);
+    }
+
+    let mut uname = Uname::default();
+    copy_str(&mut uname.sysname, "Redox");
+    copy_str(&mut uname.release, env!("CARGO_PKG_VERSION"));
+    copy_str(&mut uname.revision, env!("GIT_REVISION"));
+    copy_str(&mut uname.machine, env!("TARGET").split('-').next().unwrap());
+
+    let features = [
+        (cfg!(feature = "acpi"), abi::KERNEL_FEATURE_ACPI),
+        (cfg!(feature = "multi_core"), abi::KERNEL_FEATURE_MULTI_CORE),
+        (cfg!(feature = "pti"), abi::KERNEL_FEATURE_PTI),
+        (cfg!(feature = "x86_smap"), abi::KERNEL_FEATURE_X86_SMAP),
+        (cfg!(feature = "x86_fsgsbase"), abi::KERNEL_FEATURE_X86_FSGSBASE),
+        (cfg!(feature = "slab"), abi::KERNEL_FEATURE_SLAB),
+    ];
+    uname.features = features.iter().filter(|(enabled, _)| *enabled).fold(0, |features, (_, bit)| features | bit);
+    uname.abi_revision = abi::ABI_REVISION;
+    uname.scheme_protocol = abi::SCHEME_PROTOCOL_V2;
+    uname
+}
+
+pub fn uname(buf: UserSliceWo) -> Result<()> {
+    buf.copy_exactly(&uname_info())
+}
+
+/// Get or set FSBASE or GSBASE of the calling thread, which TLS and green thread runtimes use.
+/// `addr` is the new base for `ARCH_SET_*`, and where to write the current one for `ARCH_GET_*`.
+#[cfg(target_arch = "x86_64")]
+pub fn arch_prctl(code: usize, addr: usize) -> Result<usize> {
+    use crate::paging::RmmA;
+
+    let context_lock = context::current()?;
+    let mut context = context_lock.write();
+    let (fsbase, gsbase) = unsafe { context::read_fsgsbase() };
+
+    match code {
+        abi::ARCH_SET_FS | abi::ARCH_SET_GS => {
+            // Writing a non-canonical address would fault
+            if !RmmA::virt_is_valid(VirtualAddress::new(addr)) {
+                return Err(Error::new(EINVAL));
+            }
+            let (fsbase, gsbase) = if code == abi::ARCH_SET_FS { (addr, gsbase) } else { (fsbase, addr) };
+            unsafe { context::write_fsgsbase(&mut context.arch, fsbase, gsbase) };
+        }
+        abi::ARCH_GET_FS | abi::ARCH_GET_GS => {
+            drop(context);
+            let base = if code == abi::ARCH_GET_FS { fsbase } else { gsbase };
+            UserSliceWo::wo(addr, mem::size_of::<usize>())?.write_usize(base)?;
+        }
+        _ => return Err(Error::new(EINVAL)),
+    }
+    Ok(0)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn arch_prctl(_code: usize, _addr: usize) -> Result<usize> {
+    Err(Error::new(ENOSYS))
 }
 
 pub fn getpgid(pid: ContextId) -> Result<ContextId> {
+    let ns = pid_ns::current()?;
     let contexts = context::contexts();
     let context_lock = if pid.into() == 0 {
         contexts.current().ok_or(Error::new(ESRCH))?
     } else {
+        let pid = pid_ns::to_global(ns.as_deref(), pid).ok_or(Error::new(ESRCH))?;
         contexts.get(pid).ok_or(Error::new(ESRCH))?
     };
     let context = context_lock.read();
-    Ok(context.pgid)
+    Ok(pid_ns::to_local_or_zero(ns.as_deref(), context.pgid))
 }
 
 pub fn getppid() -> Result<ContextId> {
     let contexts = context::contexts();
     let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
     let context = context_lock.read();
-    Ok(context.ppid)
+    // The parent of the init of a PID namespace is outside of it
+    Ok(pid_ns::to_local_or_zero(context.pid_ns.as_deref(), context.ppid))
 }
 
 pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
-    let (ruid, euid, current_pgid) = {
+    send_signal(pid, sig, SI_USER, 0)
+}
+
+/// Queue a signal carrying `value`, which the receiver can read back from its `SigInfo`
+pub fn sigqueue(pid: ContextId, sig: usize, value: usize) -> Result<usize> {
+    send_signal(pid, sig, SI_QUEUE, value)
+}
+
+fn send_signal(pid: ContextId, sig: usize, code: i32, value: usize) -> Result<usize> {
+    let (ruid, euid, current_pgid, current_tgid, ns) = {
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
-        (context.ruid, context.euid, context.pgid)
+        (context.ruid, context.euid, context.pgid, context.tgid(), context.pid_ns.clone())
+    };
+    let ns = ns.as_deref();
+
+    let info = SigInfo {
+        si_signo: sig as i32,
+        si_code: code,
+        si_uid: ruid,
+        si_value: value,
+        ..SigInfo::default()
     };
 
     if sig < 0x7F {
         let mut found = 0;
         let mut sent = 0;
+        let mut overflowed = false;
 
         {
             let contexts = context::contexts();
 
-            let send = |context: &mut context::Context| -> bool {
+            let mut send = |context: &mut context::Context| -> bool {
                 if euid == 0
                 || euid == context.ruid
                 || ruid == context.ruid
@@ -212,8 +424,19 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
                     // If sig = 0, test that process exists and can be
                     // signalled, but don't send any signal.
                     if sig != 0 {
+                        // The sender is seen from the namespace of the receiver
+                        let info = SigInfo {
+                            si_pid: pid_ns::to_local_or_zero(context.pid_ns.as_deref(), current_tgid).into(),
+                            ..info
+                        };
                         //TODO: sigprocmask
-                        context.pending.push_back(sig as u8);
+                        // Signals sent to the group leader are directed at the whole group
+                        let queued = if context.is_group_leader() {
+                            context.thread_group.pending.lock().push(info)
+                        } else {
+                            context.pending.push(info)
+                        };
+                        overflowed |= !queued;
                         // Convert stopped processes to blocked if sending SIGCONT
                         if sig == SIGCONT {
                             if let context::Status::Stopped(_sig) = context.status {
@@ -229,7 +452,7 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
 
             if pid.into() as isize > 0 {
                 // Send to a single process
-                if let Some(context_lock) = contexts.get(pid) {
+                if let Some(context_lock) = pid_ns::to_global(ns, pid).and_then(|pid| contexts.get(pid)) {
                     let mut context = context_lock.write();
 
                     found += 1;
@@ -238,11 +461,16 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
                     }
                 }
             } else if pid.into() as isize == -1 {
-                // Send to every process with permission, except for init
-                for (_id, context_lock) in contexts.iter() {
+                // Send to every process with permission in the namespace, except for init, and
+                // the kernel in the root namespace
+                let first = if ns.is_some() { 1 } else { 2 };
+                for (&id, context_lock) in contexts.iter() {
+                    if pid_ns::to_local(ns, id).map_or(true, |local| local.into() <= first) {
+                        continue;
+                    }
                     let mut context = context_lock.write();
 
-                    if context.id.into() > 2 {
+                    if context.is_group_leader() {
                         found += 1;
 
                         if send(&mut context) {
@@ -252,16 +480,19 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
                 }
             } else {
                 let pgid = if pid.into() == 0 {
-                    current_pgid
+                    Some(current_pgid)
                 } else {
-                    ContextId::from(-(pid.into() as isize) as usize)
+                    pid_ns::to_global(ns, ContextId::from((pid.into() as isize).unsigned_abs()))
                 };
 
                 // Send to every process in the process group whose ID
-                for (_id, context_lock) in contexts.iter() {
+                for (&id, context_lock) in contexts.iter() {
+                    if pid_ns::to_local(ns, id).is_none() {
+                        continue;
+                    }
                     let mut context = context_lock.write();
 
-                    if context.pgid == pgid {
+                    if Some(context.pgid) == pgid && context.is_group_leader() {
                         found += 1;
 
                         if send(&mut context) {
@@ -276,6 +507,9 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
             Err(Error::new(ESRCH))
         } else if sent == 0 {
             Err(Error::new(EPERM))
+        } else if overflowed && pid.into() as isize > 0 {
+            // The realtime signal queue of the target is full
+            Err(Error::new(EAGAIN))
         } else {
             // Switch to ensure delivery to self
             unsafe { context::switch(); }
@@ -296,7 +530,125 @@ pub fn mprotect(address: usize, size: usize, flags: MapFlags) -> Result<usize> {
     AddrSpace::current()?.write().mprotect(Page::containing_address(VirtualAddress::new(address)), size / PAGE_SIZE, flags).map(|()| 0)
 }
 
+pub fn madvise(address: usize, size: usize, advice: usize) -> Result<usize> {
+    if address % PAGE_SIZE != 0 { return Err(Error::new(EINVAL)); }
+    if address.saturating_add(size) > crate::USER_END_OFFSET { return Err(Error::new(ENOMEM)); }
+    if size == 0 { return Ok(0); }
+
+    AddrSpace::current()?.write().madvise(Page::containing_address(VirtualAddress::new(address)), size.div_ceil(PAGE_SIZE), advice).map(|()| 0)
+}
+
+pub fn mremap(old_address: usize, old_size: usize, new_size: usize, flags: usize, new_address: usize) -> Result<usize> {
+    if old_address % PAGE_SIZE != 0 || old_size == 0 || new_size == 0 { return Err(Error::new(EINVAL)); }
+    if flags & !(MREMAP_MAYMOVE | MREMAP_FIXED) != 0 { return Err(Error::new(EINVAL)); }
+    if old_address.saturating_add(old_size) > crate::USER_END_OFFSET { return Err(Error::new(EFAULT)); }
+    if new_size > crate::USER_END_OFFSET { return Err(Error::new(EINVAL)); }
+
+    let new = if flags & MREMAP_FIXED == MREMAP_FIXED {
+        if flags & MREMAP_MAYMOVE == 0 || new_address % PAGE_SIZE != 0 { return Err(Error::new(EINVAL)); }
+        Some(Page::containing_address(VirtualAddress::new(new_address)))
+    } else {
+        None
+    };
+
+    AddrSpace::current()?.write().mremap(
+        Page::containing_address(VirtualAddress::new(old_address)),
+        old_size.div_ceil(PAGE_SIZE),
+        new_size.div_ceil(PAGE_SIZE),
+        flags,
+        new,
+    ).map(|page| page.start_address().data())
+}
+
+/// Read the `IoVec`s of `process_vm_readv` and `process_vm_writev`
+fn read_iovecs(iovecs: UserSliceRo) -> Result<Vec<IoVec>> {
+    if iovecs.len() > abi::IOV_MAX * mem::size_of::<IoVec>() {
+        return Err(Error::new(EINVAL));
+    }
+    iovecs.in_exact_chunks(mem::size_of::<IoVec>()).map(|iovec| {
+        let (base, len) = iovec.split_at(mem::size_of::<usize>()).ok_or(Error::new(EINVAL))?;
+        Ok(IoVec { base: base.read_usize()?, len: len.read_usize()? })
+    }).collect()
+}
+
+/// Advance `iovec` by `len` bytes, moving on to the next one of `next` once it is complete
+fn advance_iovec(iovec: &mut Option<IoVec>, next: &mut impl Iterator<Item = IoVec>, len: usize) {
+    if let Some(current) = iovec {
+        current.base += len;
+        current.len -= len;
+        if current.len == 0 {
+            *iovec = next.next();
+        }
+    }
+}
+
+/// Copy between the memory of the calling process, described by `local`, and that of `pid`,
+/// described by `remote`, without stopping `pid`. The caller must be allowed to trace `pid`. Only
+/// pages currently mapped in `pid` are accessed, and the transfer ends at the first other one,
+/// returning the bytes copied until then. Memory is copied through a kernel buffer, so that the
+/// memory of the caller is never accessed with the address space of `pid` locked.
+pub fn process_vm_rw(pid: ContextId, local: UserSliceRo, remote: UserSliceRo, write: bool) -> Result<usize> {
+    let pid = pid_ns::to_global(pid_ns::current()?.as_deref(), pid).ok_or(Error::new(ESRCH))?;
+    let addr_space = {
+        let contexts = context::contexts();
+        let current = contexts.current().ok_or(Error::new(ESRCH))?.read();
+        if pid == current.id {
+            Arc::clone(current.addr_space()?)
+        } else {
+            let target = contexts.get(pid).ok_or(Error::new(ESRCH))?.read();
+            if current.euid != 0 {
+                ptrace::check_tracer(&contexts, &current, &target, current.euid, current.egid)?;
+            }
+            Arc::clone(target.addr_space()?)
+        }
+    };
+    let locals = read_iovecs(local)?;
+    let remotes = read_iovecs(remote)?;
+
+    let mut buffer = vec![0_u8; PAGE_SIZE];
+    let mut locals = locals.into_iter().filter(|iovec| iovec.len != 0);
+    let mut remotes = remotes.into_iter().filter(|iovec| iovec.len != 0);
+    let (mut local, mut remote) = (locals.next(), remotes.next());
+    let mut transferred = 0;
+
+    while let (Some(local_iovec), Some(remote_iovec)) = (local.as_mut(), remote.as_mut()) {
+        // At most up to the end of the remote page
+        let len = local_iovec.len.min(remote_iovec.len).min(PAGE_SIZE - remote_iovec.base % PAGE_SIZE);
+        let buffer = &mut buffer[..len];
+
+        if write {
+            UserSliceRo::ro(local_iovec.base, len)?.copy_to_slice(buffer)?;
+        }
+        let copied = {
+            let mut addr_space = addr_space.write();
+            match ptrace::context_memory(&mut addr_space, VirtualAddress::new(remote_iovec.base), len).next().flatten() {
+                Some((chunk, writable)) if chunk.len() == len && (writable || !write) => {
+                    let chunk = unsafe { &mut *chunk };
+                    if write { chunk.copy_from_slice(buffer) } else { buffer.copy_from_slice(chunk) }
+                    true
+                }
+                _ => false,
+            }
+        };
+        if !copied {
+            return if transferred == 0 { Err(Error::new(EFAULT)) } else { Ok(transferred) };
+        }
+        if !write {
+            UserSliceWo::wo(local_iovec.base, len)?.copy_from_slice(buffer)?;
+        }
+        transferred += len;
+
+        advance_iovec(&mut local, &mut locals, len);
+        advance_iovec(&mut remote, &mut remotes, len);
+    }
+
+    Ok(transferred)
+}
+
 pub fn setpgid(pid: ContextId, pgid: ContextId) -> Result<usize> {
+    let ns = pid_ns::current()?;
+    let pid = if pid.into() == 0 { pid } else { pid_ns::to_global(ns.as_deref(), pid).ok_or(Error::new(ESRCH))? };
+    let pgid = if pgid.into() == 0 { pgid } else { pid_ns::to_global(ns.as_deref(), pgid).ok_or(Error::new(EPERM))? };
     let contexts = context::contexts();
 
     let current_pid = {
@@ -324,6 +676,41 @@ pub fn setpgid(pid: ContextId, pgid: ContextId) -> Result<usize> {
     }
 }
 
+/// Set the debug label of a context in the caller's thread group, or of one of its children. A pid
+/// of zero refers to the calling context.
+pub fn setlabel(pid: ContextId, label: UserSliceRo) -> Result<()> {
+    if label.len() > LABEL_MAX {
+        return Err(Error::new(ENAMETOOLONG));
+    }
+    let mut label_buf = [0_u8; LABEL_MAX];
+    let label_buf = &mut label_buf[..label.len()];
+    label.copy_to_slice(label_buf)?;
+    let label = Label::parse(core::str::from_utf8(label_buf).map_err(|_| Error::new(EINVAL))?)?;
+
+    let pid = if pid.into() == 0 { pid } else { pid_ns::to_global(pid_ns::current()?.as_deref(), pid).ok_or(Error::new(ESRCH))? };
+    let contexts = context::contexts();
+
+    let (current_pid, current_tgid) = {
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        (context.id, context.tgid())
+    };
+
+    let context_lock = if pid.into() == 0 {
+        contexts.current().ok_or(Error::new(ESRCH))?
+    } else {
+        contexts.get(pid).ok_or(Error::new(ESRCH))?
+    };
+
+    let mut context = context_lock.write();
+    if context.tgid() == current_tgid || context.ppid == current_pid {
+        context.name = label;
+        Ok(())
+    } else {
+        Err(Error::new(EPERM))
+    }
+}
+
 pub fn sigaction(sig: usize, act_opt: Option<UserSliceRo>, oldact_opt: Option<UserSliceWo>, restorer: usize) -> Result<()> {
     if sig == 0 || sig > 0x7F {
         return Err(Error::new(EINVAL));
@@ -428,41 +815,79 @@ fn reap(pid: ContextId) -> Result<ContextId> {
         interrupt::pause();
     }
 
-    let mut contexts = context::contexts_mut();
+    let mut contexts = context::contexts_mut()?;
     let context_lock = contexts.remove(pid).ok_or(Error::new(ESRCH))?;
-    {
+    let (usage, tgid) = {
         let context = context_lock.write();
+        pid_ns::unregister(context.pid_ns.as_deref(), pid);
+        let mut usage = context.usage();
+        usage.add(&context.threads_usage);
+        usage.add(&context.children_usage);
+        let tgid = (!context.is_group_leader()).then(|| context.tgid());
         empty(&context_lock, context, true);
-    }
+        (usage, tgid)
+    };
     drop(context_lock);
 
+    // The usage of a thread is part of its process, and that of a process is part of the process
+    // reaping it, both kept by the group leader
+    let leader_lock = match tgid {
+        Some(tgid) => contexts.get(tgid),
+        None => contexts.current().map(|reaper_lock| {
+            let tgid = reaper_lock.read().tgid();
+            contexts.get(tgid).unwrap_or(reaper_lock)
+        }),
+    };
+    if let Some(leader_lock) = leader_lock {
+        let mut leader = leader_lock.write();
+        if tgid.is_some() {
+            leader.threads_usage.add(&usage);
+        } else {
+            leader.children_usage.add(&usage);
+        }
+    }
+
     Ok(pid)
 }
 
 pub fn waitpid(pid: ContextId, status_ptr: Option<UserSliceWo>, flags: WaitFlags) -> Result<ContextId> {
-    let (ppid, waitpid) = {
+    let (ppid, waitpid, ns) = {
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
-        (context.id, Arc::clone(&context.waitpid))
+        (context.id, Arc::clone(&context.waitpid), context.pid_ns.clone())
     };
+    let ns = ns.as_deref();
+
+    // Translate the PID, or the negated process group ID, from the namespace of the caller
+    let pid = match pid.into() as isize {
+        0 => pid,
+        id if id > 0 => pid_ns::to_global(ns, pid).ok_or(Error::new(ECHILD))?,
+        id => {
+            let pgid = pid_ns::to_global(ns, ContextId::from(id.unsigned_abs())).ok_or(Error::new(ECHILD))?;
+            ContextId::from((pgid.into() as isize).wrapping_neg() as usize)
+        }
+    };
+
     let write_status = |value| status_ptr.map(|ptr| ptr.write_usize(value)).unwrap_or(Ok(()));
 
     let grim_reaper = |w_pid: ContextId, status: usize| -> Option<Result<ContextId>> {
+        // Reaping releases the PID, so it is translated first
+        let local_pid = pid_ns::to_local_or_zero(ns, w_pid);
         if wifcontinued(status) {
             if flags & WCONTINUED == WCONTINUED {
-                Some(write_status(status).map(|()| w_pid))
+                Some(write_status(status).map(|()| local_pid))
             } else {
                 None
             }
         } else if wifstopped(status) {
             if flags & WUNTRACED == WUNTRACED {
-                Some(write_status(status).map(|()| w_pid))
+                Some(write_status(status).map(|()| local_pid))
             } else {
                 None
             }
         } else {
-            Some(write_status(status).and_then(|()| reap(w_pid)))
+            Some(write_status(status).and_then(|()| reap(w_pid)).map(|_| local_pid))
         }
     };
 
@@ -578,9 +1003,162 @@ pub fn waitpid(pid: ContextId, status_ptr: Option<UserSliceWo>, flags: WaitFlags
     }
 }
 
+/// Extended version of `waitpid`, reporting the result as a [`WaitInfo`] and allowing the child
+/// to be left in a waitable state using `WNOWAIT`.
+pub fn waitid(idtype: usize, id: usize, info_opt: Option<UserSliceWo>, options: usize) -> Result<()> {
+    let flags = WaitFlags::from_bits_truncate(options);
+    let want_exited = options & WEXITED == WEXITED;
+    let want_stopped = flags & WUNTRACED == WUNTRACED;
+    let want_continued = flags & WCONTINUED == WCONTINUED;
+    let nowait = options & WNOWAIT == WNOWAIT;
+
+    if !(want_exited || want_stopped || want_continued) {
+        return Err(Error::new(EINVAL));
+    }
+
+    let (ppid, waitpid, ns) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        (context.id, Arc::clone(&context.waitpid), context.pid_ns.clone())
+    };
+    let ns = ns.as_deref();
+
+    let id = match idtype {
+        P_ALL => ContextId::from(0),
+        P_PID | P_PGID => pid_ns::to_global(ns, ContextId::from(id)).ok_or(Error::new(ECHILD))?,
+        _ => return Err(Error::new(EINVAL)),
+    };
+    let key = match idtype {
+        P_PID => Some(WaitpidKey { pid: Some(id), pgid: None }),
+        P_PGID => Some(WaitpidKey { pid: None, pgid: Some(id) }),
+        _ => None,
+    };
+
+    // Check for existence of a matching child
+    {
+        let contexts = context::contexts();
+        let found = contexts.iter().any(|(_id, context_lock)| {
+            let context = context_lock.read();
+            context.ppid == ppid && match idtype {
+                P_PID => context.id == id,
+                P_PGID => context.pgid == id,
+                _ => true,
+            }
+        });
+
+        if ! found {
+            return Err(Error::new(ECHILD));
+        }
+    }
+
+    let filter = |w_key: &WaitpidKey, &(_w_pid, status): &(ContextId, usize)| {
+        let wanted = if wifcontinued(status) {
+            want_continued
+        } else if wifstopped(status) {
+            want_stopped
+        } else {
+            want_exited
+        };
+        wanted && key.map_or(true, |key| key == *w_key)
+    };
+    let reason = (flags & WNOHANG != WNOHANG).then_some("waitid");
+
+    let Some((_w_key, (w_pid, status))) = waitpid.receive_matching(filter, nowait, reason) else {
+        // WNOHANG and no child was waitable
+        if let Some(info) = info_opt {
+            info.copy_exactly(&WaitInfo::default())?;
+        }
+        return Ok(());
+    };
+
+    let (si_code, si_status) = if wifcontinued(status) {
+        (CLD_CONTINUED, SIGCONT)
+    } else if wifstopped(status) {
+        (CLD_STOPPED, wstopsig(status))
+    } else if wifsignaled(status) {
+        (CLD_KILLED, wtermsig(status))
+    } else {
+        (CLD_EXITED, wexitstatus(status))
+    };
+    let si_uid = context::contexts().get(w_pid).map_or(0, |context| context.read().ruid);
+
+    if let Some(info) = info_opt {
+        info.copy_exactly(&WaitInfo {
+            si_signo: SIGCHLD as i32,
+            si_code,
+            si_status: si_status as i32,
+            si_uid,
+            si_pid: pid_ns::to_local_or_zero(ns, w_pid).into(),
+        })?;
+    }
+
+    if !nowait && matches!(si_code, CLD_EXITED | CLD_KILLED) {
+        reap(w_pid)?;
+    }
+
+    Ok(())
+}
+
+/// Find the SHA-256 digest the bootstrap image is expected to have. A digest embedded at build
+/// time (`REDOX_BOOTSTRAP_SHA256`) takes precedence over the `BOOTSTRAP_SHA256` variable passed by
+/// the bootloader in the kernel environment.
+fn expected_bootstrap_digest(env: &'static [u8]) -> Option<(&'static str, &'static str)> {
+    if let Some(hex) = option_env!("REDOX_BOOTSTRAP_SHA256") {
+        return Some((hex, "kernel"));
+    }
+
+    core::str::from_utf8(env).unwrap_or("").lines().find_map(|line| {
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("");
+
+        (name == "BOOTSTRAP_SHA256").then_some((value, "bootloader"))
+    })
+}
+
+/// Verify the bootstrap image before it is mapped and jumped to. With the `bootstrap_verify`
+/// feature, a missing or mismatching digest is fatal; otherwise it is only reported.
+fn verify_bootstrap(bootstrap: &Bootstrap) {
+    fn fail(message: core::fmt::Arguments) {
+        if cfg!(feature = "bootstrap_verify") {
+            panic!("bootstrap verification failed: {}", message);
+        } else {
+            log::error!("!!! BOOTSTRAP VERIFICATION FAILED: {} !!!", message);
+        }
+    }
+
+    let Some((hex, source)) = expected_bootstrap_digest(bootstrap.env) else {
+        if cfg!(feature = "bootstrap_verify") {
+            fail(format_args!("no expected digest was provided"));
+        } else {
+            log::warn!("No bootstrap digest provided, skipping verification");
+        }
+        return;
+    };
+    let Some(expected) = sha256::parse_hex(hex) else {
+        fail(format_args!("invalid digest {:?} from {}", hex, source));
+        return;
+    };
+
+    let image = unsafe {
+        let base = crate::paging::RmmA::phys_to_virt(bootstrap.base.start_address());
+        core::slice::from_raw_parts(base.data() as *const u8, bootstrap.size)
+    };
+    let actual = sha256::digest(image);
+
+    if actual == expected {
+        log::info!("Bootstrap digest verified ({} bytes, digest from {})", bootstrap.size, source);
+    } else {
+        fail(format_args!("digest mismatch (expected {}, got {})", sha256::Hex(&expected), sha256::Hex(&actual)));
+    }
+}
+
 pub unsafe fn usermode_bootstrap(bootstrap: &Bootstrap) -> ! {
     assert_ne!(bootstrap.page_count, 0);
 
+    verify_bootstrap(bootstrap);
+
     {
         let addr_space = Arc::clone(context::contexts().current()
             .expect("expected a context to exist when executing init")
@@ -590,6 +1168,9 @@ pub unsafe fn usermode_bootstrap(bootstrap: &Bootstrap) -> ! {
         let mut addr_space = addr_space.write();
         let addr_space = &mut *addr_space;
 
+        // The bootstrap image is flat, without segments that could be mapped with their own
+        // permissions, so it is the one mapping exempt from W^X. The programs it loads get new
+        // address spaces, which enforce it (see `AddrSpace::check_wx`).
         let mut grant = context::memory::Grant::physmap(
             bootstrap.base.clone(),
             Page::containing_address(VirtualAddress::new(0)),
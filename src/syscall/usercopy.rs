@@ -1,9 +1,10 @@
-use crate::paging::{Page, VirtualAddress};
-use crate::memory::PAGE_SIZE;
+use rmm::Arch;
 
-use crate::arch::{arch_copy_to_user, arch_copy_from_user};
+use crate::context::memory::{self, AddrSpace};
+use crate::paging::{Page, RmmA, VirtualAddress};
+use crate::memory::PAGE_SIZE;
 
-use crate::syscall::error::{Error, EINVAL, EFAULT, Result};
+use crate::syscall::error::{Error, EFAULT, EINTR, EINVAL, ENOMEM, Result};
 
 #[derive(Clone, Copy)]
 pub struct UserSlice<const READ: bool, const WRITE: bool> {
@@ -80,11 +81,7 @@ impl<const WRITE: bool> UserSlice<true, WRITE> {
             return Err(Error::new(EINVAL));
         }
 
-        if unsafe { arch_copy_from_user(slice.as_mut_ptr() as usize, self.base, self.len) } == 0 {
-            Ok(())
-        } else {
-            Err(Error::new(EFAULT))
-        }
+        copy_through_window(self.base, slice.as_mut_ptr(), self.len, false)
     }
     pub unsafe fn read_exact<T>(self) -> Result<T> {
         let mut t: T = core::mem::zeroed();
@@ -127,11 +124,7 @@ impl<const READ: bool> UserSlice<READ, true> {
             return Err(Error::new(EINVAL));
         }
 
-        if unsafe { arch_copy_to_user(self.base, slice.as_ptr() as usize, self.len) } == 0 {
-            Ok(())
-        } else {
-            Err(Error::new(EFAULT))
-        }
+        copy_through_window(self.base, slice.as_ptr() as *mut u8, self.len, true)
     }
     pub fn copy_common_bytes_from_slice(self, slice: &[u8]) -> Result<usize> {
         let min = core::cmp::min(self.len(), slice.len());
@@ -172,6 +165,60 @@ impl UserSliceRw {
     }
 }
 
+/// Copy between user memory at `base` and kernel memory at `kernel`, through the kernel's mapping
+/// of all physical memory rather than the user mapping.
+///
+/// Pages are translated and copied while the address space is read locked, and unmapping requires
+/// the write lock, so another thread of the same process cannot unmap and free a frame in the
+/// middle of the copy, and stale TLB entries of other CPUs are never used. Pages of lazily allocated
+/// grants that are not present yet are allocated with the lock released, and the copy resumes
+/// after them. Anything else that is not present, or not writable when copying to userspace, fails
+/// with `EFAULT`. The caller must therefore not hold the address space lock.
+fn copy_through_window(base: usize, kernel: *mut u8, len: usize, to_user: bool) -> Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let addr_space_lock = AddrSpace::current().map_err(|_| Error::new(EFAULT))?;
+
+    let mut copied = 0;
+    while copied < len {
+        let addr_space = addr_space_lock.read();
+        while copied < len {
+            let address = VirtualAddress::new(base + copied);
+            let page = Page::containing_address(address);
+            let offset = address.data() - page.start_address().data();
+            let chunk = core::cmp::min(PAGE_SIZE - offset, len - copied);
+
+            let (frame, flags) = match memory::translate(&addr_space.table.utable, page.start_address()) {
+                Some(translation) => translation,
+                None => break,
+            };
+            if to_user && !flags.has_write() {
+                return Err(Error::new(EFAULT));
+            }
+            let window = unsafe { RmmA::phys_to_virt(frame.add(offset)) }.data() as *mut u8;
+
+            unsafe {
+                if to_user {
+                    core::ptr::copy_nonoverlapping(kernel.add(copied), window, chunk);
+                } else {
+                    core::ptr::copy_nonoverlapping(window, kernel.add(copied), chunk);
+                }
+            }
+            copied += chunk;
+        }
+        drop(addr_space);
+
+        if copied < len {
+            memory::demand_page(VirtualAddress::new(base + copied)).map_err(|error| match error.errno {
+                EINTR | ENOMEM => error,
+                _ => Error::new(EFAULT),
+            })?;
+        }
+    }
+    Ok(())
+}
+
 fn is_kernel_mem(slice: &[u8]) -> bool {
     (slice.as_ptr() as usize) >= crate::USER_END_OFFSET && (slice.as_ptr() as usize).checked_add(slice.len()).is_some()
 }
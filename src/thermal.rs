@@ -0,0 +1,290 @@
+//! # Thermal zones
+//! Temperatures and trip points of thermal zones, in millidegrees Celsius. Zones come from the
+//! digital thermal sensors of each core and of the package, polled by the kernel (see
+//! `arch::thermal`), or from the ACPI thermal zones, which the ACPI driver creates and reports the
+//! temperature of through `thermal:`, evaluating their objects through `acpi:aml`. It polls them as `_TZP`
+//! asks, or evaluates `_TMP` when notified of a change.
+//!
+//! Each zone has optional `passive`, `hot` and `critical` trip points, from `_PSV`, `_HOT` and
+//! `_CRT` for ACPI zones, and below TjMax for the sensors. A zone reaches the highest trip point
+//! its temperature is at, and leaves it once `HYSTERESIS` below. Readers of `thermal:` are told of
+//! each change. While a zone is at its passive trip point, the fastest CPU frequency allowed is
+//! lowered one state every `POLL_TICKS` (see `cpufreq::set_limit`), and raised again once none
+//! is. The hot trip point is left to userspace, to suspend the system for example. Reaching the
+//! critical one powers off right away, before the hardware cuts power.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::arch::thermal as sensor;
+use crate::cpufreq;
+use crate::log::{error, info, warn};
+use crate::scheme::thermal as scheme;
+use crate::sync::RwLock;
+
+/// Timer ticks between polls of the sensors, about a second
+const POLL_TICKS: usize = 250;
+
+/// How far below a trip point a zone leaves it, in millidegrees
+const HYSTERESIS: i32 = 2000;
+
+/// Default passive trip point of the sensors, below TjMax, in millidegrees
+const SENSOR_PASSIVE: i32 = 10_000;
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Level {
+    Normal,
+    Passive,
+    Hot,
+    Critical,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Normal => "normal",
+            Level::Passive => "passive",
+            Level::Hot => "hot",
+            Level::Critical => "critical",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Source {
+    /// The sensor of a core, read by its CPU
+    Core(usize),
+    Package,
+    /// Reported by the ACPI driver
+    Acpi,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Trip {
+    Passive,
+    Hot,
+    Critical,
+}
+
+impl Trip {
+    fn level(self) -> Level {
+        match self {
+            Trip::Passive => Level::Passive,
+            Trip::Hot => Level::Hot,
+            Trip::Critical => Level::Critical,
+        }
+    }
+}
+
+pub struct Zone {
+    pub name: String,
+    pub source: Source,
+    pub temp: Option<i32>,
+    pub passive: Option<i32>,
+    pub hot: Option<i32>,
+    pub critical: Option<i32>,
+    pub level: Level,
+    /// Whether readers still have to be told of a change of level
+    notify: bool,
+}
+
+impl Zone {
+    fn trip(&self, trip: Trip) -> Option<i32> {
+        match trip {
+            Trip::Passive => self.passive,
+            Trip::Hot => self.hot,
+            Trip::Critical => self.critical,
+        }
+    }
+
+    /// The level of the zone at its current temperature
+    fn next_level(&self) -> Level {
+        let Some(temp) = self.temp else {
+            return Level::Normal;
+        };
+        let mut level = Level::Normal;
+        for trip in [Trip::Passive, Trip::Hot, Trip::Critical] {
+            let threshold = match self.trip(trip) {
+                // Staying at a level until below its hysteresis
+                Some(point) if trip.level() <= self.level => point - HYSTERESIS,
+                Some(point) => point,
+                None => continue,
+            };
+            if temp >= threshold {
+                level = trip.level();
+            }
+        }
+        level
+    }
+}
+
+/// Zones, which keep their index, as they are never removed
+static ZONES: RwLock<Vec<Zone>> = RwLock::new(Vec::new());
+
+/// Set once a critical trip point was reached, as powering off cannot be undone
+static CRITICAL: AtomicBool = AtomicBool::new(false);
+
+/// Number of sensors with a zone, as those of the APs are detected after polling started
+static SENSOR_ZONES: AtomicUsize = AtomicUsize::new(0);
+
+#[thread_local]
+static POLL_COUNTDOWN: Cell<usize> = Cell::new(POLL_TICKS);
+
+fn add_sensor_zones(zones: &mut Vec<Zone>) {
+    let cpus = crate::cpu_count().min(sensor::CPUS);
+    let sensors = (0..cpus).filter(|&cpu_id| sensor::tj_max(cpu_id).is_some()).count() + usize::from(sensor::has_package());
+    if SENSOR_ZONES.load(Ordering::Relaxed) == sensors {
+        return;
+    }
+    if sensor::has_package() && !zones.iter().any(|zone| zone.source == Source::Package) {
+        let passive = sensor::tj_max(0).map(|tj_max| tj_max * 1000 - SENSOR_PASSIVE);
+        zones.push(zone(String::from("package"), Source::Package, passive));
+    }
+    for cpu_id in 0..cpus {
+        let Some(tj_max) = sensor::tj_max(cpu_id) else {
+            continue;
+        };
+        if !zones.iter().any(|zone| zone.source == Source::Core(cpu_id)) {
+            zones.push(zone(format!("cpu{}", cpu_id), Source::Core(cpu_id), Some(tj_max * 1000 - SENSOR_PASSIVE)));
+        }
+    }
+    SENSOR_ZONES.store(sensors, Ordering::Relaxed);
+}
+
+fn zone(name: String, source: Source, passive: Option<i32>) -> Zone {
+    Zone { name, source, temp: None, passive, hot: None, critical: None, level: Level::Normal, notify: false }
+}
+
+/// Update the levels of the zones, throttle while any is at its passive trip point, and tell
+/// readers of changes. Returns the name of a zone at its critical trip point, if any.
+fn evaluate(zones: &mut [Zone], throttle: bool) -> Option<String> {
+    let mut passive = false;
+    let mut critical = None;
+    for (index, zone) in zones.iter_mut().enumerate() {
+        let level = zone.next_level();
+        if level != zone.level {
+            match level {
+                Level::Normal => info!("Thermal zone {} back to normal", zone.name),
+                Level::Critical => (),
+                _ => warn!("Thermal zone {} reached its {} trip point at {} millidegrees", zone.name, level.name(), zone.temp.unwrap_or(0)),
+            }
+            zone.level = level;
+            zone.notify = true;
+        }
+        if zone.notify && scheme::level_changed(index) {
+            zone.notify = false;
+        }
+        passive |= level >= Level::Passive;
+        if level == Level::Critical && critical.is_none() {
+            critical = Some(zone.name.clone());
+        }
+    }
+
+    if throttle {
+        let limit = cpufreq::limit();
+        if passive {
+            cpufreq::set_limit(limit + 1);
+        } else if limit > 0 {
+            cpufreq::set_limit(limit - 1);
+        }
+    }
+    critical
+}
+
+/// Power off, as a zone reached its critical trip point. No lock may be held.
+fn critical(name: String) {
+    if CRITICAL.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    error!("Thermal zone {} reached its critical trip point, powering off", name);
+    unsafe { crate::stop::kstop() };
+}
+
+/// Read the sensors of the current CPU every `POLL_TICKS`, and on the BSP update the zones. Called
+/// on each timer tick, once the interrupt is acknowledged, so the locks may already be held on
+/// this CPU, in which case the zones are updated on the next poll.
+pub fn poll() {
+    let countdown = POLL_COUNTDOWN.get();
+    if countdown > 1 {
+        POLL_COUNTDOWN.set(countdown - 1);
+        return;
+    }
+    POLL_COUNTDOWN.set(POLL_TICKS);
+
+    let cpu_id = crate::cpu_id();
+    unsafe { sensor::poll(cpu_id) };
+    if cpu_id != 0 {
+        return;
+    }
+
+    let critical = {
+        let Some(mut zones) = ZONES.try_write() else {
+            return;
+        };
+        add_sensor_zones(&mut zones);
+        for zone in zones.iter_mut() {
+            match zone.source {
+                Source::Core(cpu_id) => zone.temp = sensor::core(cpu_id),
+                Source::Package => zone.temp = sensor::package(),
+                Source::Acpi => (),
+            }
+        }
+        evaluate(&mut zones, true)
+    };
+    if let Some(name) = critical {
+        self::critical(name);
+    }
+}
+
+/// Run `f` on each zone
+pub fn for_each_zone(mut f: impl FnMut(usize, &Zone)) {
+    for (index, zone) in ZONES.read().iter().enumerate() {
+        f(index, zone);
+    }
+}
+
+/// Run `f` on the zone at `index`
+pub fn with_zone<T>(index: usize, f: impl FnOnce(&Zone) -> T) -> Option<T> {
+    ZONES.read().get(index).map(f)
+}
+
+/// The index of the zone called `name`
+pub fn find(name: &str) -> Option<usize> {
+    ZONES.read().iter().position(|zone| zone.name == name)
+}
+
+/// Create an ACPI zone called `name`, returning its index
+pub fn create(name: &str) -> Option<usize> {
+    let mut zones = ZONES.write();
+    if name.is_empty() || name.contains('/') || name == "zones" || zones.iter().any(|zone| zone.name == name) {
+        return None;
+    }
+    zones.push(zone(String::from(name), Source::Acpi, None));
+    Some(zones.len() - 1)
+}
+
+/// Set the temperature of the ACPI zone at `index`, or one of the trip points of any zone, in
+/// millidegrees, and update its level
+pub fn set(index: usize, trip: Option<Trip>, value: Option<i32>) -> bool {
+    let critical = {
+        let mut zones = ZONES.write();
+        let Some(zone) = zones.get_mut(index) else {
+            return false;
+        };
+        match trip {
+            None if zone.source == Source::Acpi => zone.temp = value,
+            None => return false,
+            Some(Trip::Passive) => zone.passive = value,
+            Some(Trip::Hot) => zone.hot = value,
+            Some(Trip::Critical) => zone.critical = value,
+        }
+        // Throttling is only stepped when polling, at a steady pace
+        evaluate(&mut zones, false)
+    };
+    if let Some(name) = critical {
+        self::critical(name);
+    }
+    true
+}
@@ -1,16 +1,261 @@
-use spin::Mutex;
+//! # Clocksources
+//! The monotonic clock is read from the best clocksource registered, by rating, or the one chosen
+//! through `time:clocksource`. Without any, it counts the ticks of the system timer, interpolated
+//! by `arch::time::counter`. Switching sources, or resuming from suspend to RAM, continues the
+//! clock from where it was.
+//!
+//! Sources that may drift, or stop in idle states, such as the TSC, are checked by a watchdog every
+//! `WATCHDOG_TICKS` against the best source that is not, or the ticks. One that drifted more than
+//! `WATCHDOG_SKEW` from it is marked unstable, and never used again.
+
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::log::warn;
+use crate::sync::{Mutex, RwLock};
 
 pub const NANOS_PER_SEC: u128 = 1_000_000_000;
 
+/// Timer ticks between checks of the watchdog, about half a second
+const WATCHDOG_TICKS: usize = 125;
+
+/// Largest drift from the watchdog allowed, in parts per thousand of the time between checks
+const WATCHDOG_SKEW: u128 = 10;
+
 /// Kernel start time, measured in (seconds, nanoseconds) since Unix epoch
 pub static START: Mutex<u128> = Mutex::new(0);
-/// Kernel up time, measured in (seconds, nanoseconds) since `START_TIME`
+/// Nanoseconds counted by the ticks of the system timer
 pub static OFFSET: Mutex<u128> = Mutex::new(0);
 
-pub fn monotonic() -> u128 {
+#[derive(Clone, Copy, Debug)]
+pub struct Clocksource {
+    pub name: &'static str,
+    /// How good the source is, the best being used: 100 for the ticks, 250 for a counter on the
+    /// chipset, 300 for one in the CPU, and 400 for one that is also known to be stable
+    pub rating: u32,
+    /// Nanoseconds counted, from any start, never going backwards
+    pub read: fn() -> u128,
+    /// Whether the watchdog checks the source
+    pub verify: bool,
+}
+
+fn ticks() -> u128 {
     *OFFSET.lock() + crate::arch::time::counter()
 }
 
+/// The ticks of the system timer, always available
+pub const TICK: Clocksource = Clocksource {
+    name: "tick",
+    rating: 100,
+    read: ticks,
+    verify: false,
+};
+
+struct Entry {
+    source: Clocksource,
+    unstable: bool,
+}
+
+struct Clock {
+    sources: Vec<Entry>,
+    /// Index of the source in use, `None` for the ticks
+    current: Option<usize>,
+    /// Name of the source chosen through `time:clocksource`
+    chosen: Option<&'static str>,
+    /// The monotonic clock when the source was last switched to, and what it read then
+    offset: u128,
+    base: u128,
+    /// Index of the source the watchdog checks against, and what both read at the last check
+    watchdog: Option<(Option<usize>, u128, u128)>,
+}
+
+impl Clock {
+    fn source(&self, index: Option<usize>) -> Clocksource {
+        index.and_then(|index| self.sources.get(index)).map_or(TICK, |entry| entry.source)
+    }
+
+    fn now(&self) -> u128 {
+        self.offset + (self.source(self.current).read)().saturating_sub(self.base)
+    }
+
+    /// The source to use: the chosen one, if stable, or else the best
+    fn best(&self) -> Option<usize> {
+        if let Some(chosen) = self.chosen {
+            if let Some(index) = self.sources.iter().position(|entry| entry.source.name == chosen && !entry.unstable) {
+                return Some(index);
+            }
+            if chosen == TICK.name {
+                return None;
+            }
+        }
+        self.sources
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !entry.unstable && entry.source.rating > TICK.rating)
+            .max_by_key(|(_, entry)| entry.source.rating)
+            .map(|(index, _)| index)
+    }
+
+    /// Continue the clock from `now` with the source at `index`
+    fn switch(&mut self, index: Option<usize>, now: u128) {
+        self.current = index;
+        self.offset = now;
+        self.base = (self.source(index).read)();
+        self.watchdog = None;
+    }
+
+    fn select(&mut self) {
+        let best = self.best();
+        if best != self.current {
+            let now = self.now();
+            self.switch(best, now);
+        }
+    }
+}
+
+static CLOCK: RwLock<Clock> = RwLock::new(Clock {
+    sources: Vec::new(),
+    current: None,
+    chosen: None,
+    offset: 0,
+    base: 0,
+    watchdog: None,
+});
+
+/// The monotonic clock when suspended to RAM, which the counters do not keep
+static SUSPENDED_AT: AtomicU64 = AtomicU64::new(0);
+
+#[thread_local]
+static WATCHDOG_COUNTDOWN: Cell<usize> = Cell::new(WATCHDOG_TICKS);
+
+pub fn monotonic() -> u128 {
+    CLOCK.read().now()
+}
+
+/// The monotonic clock, or `None` if it is being changed, as when logging while switching sources
+pub fn try_monotonic() -> Option<u128> {
+    CLOCK.try_read().map(|clock| clock.now())
+}
+
 pub fn realtime() -> u128 {
     *START.lock() + monotonic()
 }
+
+/// Add a source, switching to it if it is the best
+pub fn register(source: Clocksource) {
+    let mut clock = CLOCK.write();
+    if clock.sources.iter().any(|entry| entry.source.name == source.name) || source.name == TICK.name {
+        return;
+    }
+    clock.sources.push(Entry { source, unstable: false });
+    clock.select();
+}
+
+/// The name of the source in use
+pub fn clocksource() -> &'static str {
+    let clock = CLOCK.read();
+    clock.source(clock.current).name
+}
+
+/// The ticks and each source registered, and whether it is unstable
+pub fn clocksources() -> Vec<(Clocksource, bool)> {
+    let clock = CLOCK.read();
+    let mut sources = Vec::with_capacity(clock.sources.len() + 1);
+    sources.push((TICK, false));
+    sources.extend(clock.sources.iter().map(|entry| (entry.source, entry.unstable)));
+    sources
+}
+
+/// Use the source called `name`, or the best one if `None`. Returns false if it is unknown or
+/// unstable.
+pub fn choose(name: Option<&str>) -> bool {
+    let mut clock = CLOCK.write();
+    clock.chosen = match name {
+        Some(name) if name == TICK.name => Some(TICK.name),
+        Some(name) => match clock.sources.iter().find(|entry| entry.source.name == name && !entry.unstable) {
+            Some(entry) => Some(entry.source.name),
+            None => return false,
+        },
+        None => None,
+    };
+    clock.select();
+    true
+}
+
+/// Save the monotonic clock before suspend to RAM
+pub fn suspend() {
+    SUSPENDED_AT.store(monotonic() as u64, Ordering::Relaxed);
+}
+
+/// Continue the monotonic clock from where it was suspended, once the system timer runs again
+pub fn resume() {
+    let mut clock = CLOCK.write();
+    let current = clock.current;
+    clock.switch(current, u128::from(SUSPENDED_AT.load(Ordering::Relaxed)));
+}
+
+/// Check the source in use against the watchdog every `WATCHDOG_TICKS`, if it needs to. Called on
+/// each timer tick, and skipped if the clock is being read or switched on another CPU.
+pub fn watchdog() {
+    let countdown = WATCHDOG_COUNTDOWN.get();
+    if countdown > 1 {
+        WATCHDOG_COUNTDOWN.set(countdown - 1);
+        return;
+    }
+    WATCHDOG_COUNTDOWN.set(WATCHDOG_TICKS);
+    if crate::cpu_id() != 0 {
+        return;
+    }
+
+    let unstable = {
+        let Some(mut clock) = CLOCK.try_write() else {
+            return;
+        };
+        let Some(current) = clock.current else {
+            return;
+        };
+        let source = clock.source(Some(current));
+        if !source.verify {
+            return;
+        }
+        let reference = clock
+            .sources
+            .iter()
+            .enumerate()
+            .filter(|&(index, entry)| index != current && !entry.unstable && !entry.source.verify)
+            .max_by_key(|(_, entry)| entry.source.rating)
+            .map(|(index, _)| index);
+
+        let now = (source.read)();
+        let reference_now = (clock.source(reference).read)();
+        let last = clock.watchdog.replace((reference, now, reference_now));
+        match last {
+            Some((last_reference, last, reference_last)) if last_reference == reference => {
+                let elapsed = now.saturating_sub(last);
+                let reference_elapsed = reference_now.saturating_sub(reference_last);
+                if elapsed.abs_diff(reference_elapsed) * 1000 > reference_elapsed * WATCHDOG_SKEW {
+                    if let Some(entry) = clock.sources.get_mut(current) {
+                        entry.unstable = true;
+                    }
+                    clock.select();
+                    Some((source.name, elapsed, clock.source(reference).name, reference_elapsed))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    };
+
+    if let Some((name, elapsed, reference, reference_elapsed)) = unstable {
+        warn!(
+            "Clocksource {} is unstable, counting {} ns while {} counted {} ns, switched to {}",
+            name,
+            elapsed,
+            reference,
+            reference_elapsed,
+            clocksource()
+        );
+    }
+}
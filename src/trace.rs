@@ -0,0 +1,572 @@
+//! # Tracing
+//! Tracepoints, placed with `tracepoint!` in the scheduler, syscall, IRQ and memory paths, record
+//! an event with two arguments in a ring per CPU, if that event is enabled. A disabled tracepoint
+//! costs a load and a branch, and the arguments are not evaluated. Events are enabled and the
+//! rings are read through `trace:` (see `scheme::trace`).
+//!
+//! With the `function_tracer` feature on x86_64, and the kernel built with
+//! `RUSTFLAGS="-Z instrument-mcount"`, every function that is not inlined calls `mcount` on entry,
+//! which records the `function` event with the address in the function and its return address.
+//! While that event is disabled, `mcount` returns after one comparison. It must not be enabled
+//! while CPUs are brought online, as they run instrumented code before their thread-local storage
+//! is set up.
+//!
+//! The events can also be read as a Fuchsia trace (see `fxt`), which Perfetto and the Fuchsia
+//! trace viewer open, with context switches and wakeups as scheduler records, IRQs and deferred
+//! work as durations on the context they interrupted, and the other events as instants.
+//!
+//! Like the journal, each ring is only written by its own CPU, so recording is lock-free and safe
+//! from interrupt handlers, and reading while other CPUs are still running may observe partially
+//! written entries, which are skipped.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::context::{self, Status};
+use crate::journal::{self, timestamp};
+
+/// Number of CPUs with a ring, events on other CPUs are not recorded
+const CPUS: usize = 32;
+/// Number of entries per CPU
+const ENTRIES: usize = 2048;
+
+/// Record `$event`, a variant of `Event`, with the arguments `$a`, `$b` and optionally `$c` if it
+/// is enabled
+#[macro_export]
+macro_rules! tracepoint {
+    ($event:ident, $a:expr, $b:expr) => {
+        $crate::tracepoint!($event, $a, $b, 0)
+    };
+    ($event:ident, $a:expr, $b:expr, $c:expr) => {
+        if $crate::trace::enabled($crate::trace::Event::$event) {
+            $crate::trace::record($crate::trace::Event::$event, $a as usize, $b as usize, $c as usize);
+        }
+    };
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(usize)]
+pub enum Event {
+    /// Switched from context `a`, left in the `SwitchState` `c`, to context `b`
+    SchedSwitch = 1,
+    /// Context `a` was unblocked
+    SchedWakeup = 2,
+    /// Syscall `a` was entered, with `b` as its first argument
+    SyscallEnter = 3,
+    /// Syscall `a` returned `b`
+    SyscallExit = 4,
+    /// Page fault at address `a`, with the error code `b`
+    PageFault = 5,
+    /// `b` frames were allocated at physical address `a`
+    FrameAlloc = 6,
+    /// `b` frames were freed at physical address `a`
+    FrameFree = 7,
+    /// Anonymous memory of `b` bytes was mapped at address `a`
+    Mmap = 8,
+    /// `b` bytes were unmapped at address `a`
+    Munmap = 9,
+    /// IRQ `a` was delivered
+    Irq = 10,
+    /// Function containing address `a` was called from address `b`
+    Function = 11,
+    /// IRQ `a` was handled
+    IrqExit = 12,
+    /// Deferred work `a`, a `Work` value, started
+    WorkBegin = 13,
+    /// Deferred work `a` finished, after doing `b` items
+    WorkEnd = 14,
+    /// The kprobe at address `a` was hit, with `b` and `c` in the first two argument registers
+    Kprobe = 15,
+    /// The next three argument registers of the last `Kprobe`, in `a`, `b` and `c`
+    KprobeArgs = 16,
+}
+
+impl Event {
+    pub const ALL: [Event; 16] = [
+        Event::SchedSwitch,
+        Event::SchedWakeup,
+        Event::SyscallEnter,
+        Event::SyscallExit,
+        Event::PageFault,
+        Event::FrameAlloc,
+        Event::FrameFree,
+        Event::Mmap,
+        Event::Munmap,
+        Event::Irq,
+        Event::Function,
+        Event::IrqExit,
+        Event::WorkBegin,
+        Event::WorkEnd,
+        Event::Kprobe,
+        Event::KprobeArgs,
+    ];
+
+    fn from_raw(raw: usize) -> Option<Self> {
+        Self::ALL.into_iter().find(|&event| event as usize == raw)
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::SchedSwitch => "sched_switch",
+            Event::SchedWakeup => "sched_wakeup",
+            Event::SyscallEnter => "syscall_enter",
+            Event::SyscallExit => "syscall_exit",
+            Event::PageFault => "page_fault",
+            Event::FrameAlloc => "frame_alloc",
+            Event::FrameFree => "frame_free",
+            Event::Mmap => "mmap",
+            Event::Munmap => "munmap",
+            Event::Irq => "irq",
+            Event::Function => "function",
+            Event::IrqExit => "irq_exit",
+            Event::WorkBegin => "work_begin",
+            Event::WorkEnd => "work_end",
+            Event::Kprobe => "kprobe",
+            Event::KprobeArgs => "kprobe_args",
+        }
+    }
+
+    /// Returns true if the event can be recorded by this kernel
+    pub fn supported(self) -> bool {
+        match self {
+            Event::Function => cfg!(all(feature = "function_tracer", target_arch = "x86_64")),
+            Event::Kprobe | Event::KprobeArgs => cfg!(target_arch = "x86_64"),
+            _ => true,
+        }
+    }
+
+    const fn bit(self) -> u64 {
+        1 << self as usize
+    }
+}
+
+/// The state a context was left in when switched away from
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(usize)]
+pub enum SwitchState {
+    /// Preempted
+    Runnable = 0,
+    Blocked = 1,
+    Stopped = 2,
+    Exited = 3,
+}
+
+impl SwitchState {
+    pub fn of(status: &Status) -> Self {
+        match status {
+            Status::Runnable => SwitchState::Runnable,
+            Status::Blocked => SwitchState::Blocked,
+            Status::Stopped(_) => SwitchState::Stopped,
+            Status::Exited(_) => SwitchState::Exited,
+        }
+    }
+
+    fn from_raw(raw: usize) -> Self {
+        match raw {
+            1 => SwitchState::Blocked,
+            2 => SwitchState::Stopped,
+            3 => SwitchState::Exited,
+            _ => SwitchState::Runnable,
+        }
+    }
+
+    /// The letter used for the state by `ps` and ftrace
+    fn letter(self) -> char {
+        match self {
+            SwitchState::Runnable => 'R',
+            SwitchState::Blocked => 'S',
+            SwitchState::Stopped => 'T',
+            SwitchState::Exited => 'X',
+        }
+    }
+}
+
+/// Deferred work, done outside of the context it is for
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(usize)]
+pub enum Work {
+    /// Triggering the timeouts that expired, on a timer tick
+    Timeouts = 1,
+}
+
+impl Work {
+    fn name(raw: usize) -> &'static str {
+        match raw {
+            1 => "timeouts",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Bit `1 << event` is set if the event is enabled
+static ENABLED: AtomicU64 = AtomicU64::new(0);
+
+/// Returns true if `event` is enabled
+#[inline(always)]
+pub fn enabled(event: Event) -> bool {
+    ENABLED.load(Ordering::Relaxed) & event.bit() != 0
+}
+
+/// Enable or disable `event`, returning false if it is not supported
+pub fn set_enabled(event: Event, enabled: bool) -> bool {
+    if enabled && !event.supported() {
+        return false;
+    }
+    if enabled {
+        ENABLED.fetch_or(event.bit(), Ordering::Relaxed);
+    } else {
+        ENABLED.fetch_and(!event.bit(), Ordering::Relaxed);
+    }
+    true
+}
+
+struct Entry {
+    time: AtomicU64,
+    event: AtomicUsize,
+    context: AtomicUsize,
+    a: AtomicUsize,
+    b: AtomicUsize,
+    c: AtomicUsize,
+}
+
+struct Ring {
+    /// Total number of events recorded, the next entry is at `head % ENTRIES`
+    head: AtomicUsize,
+    /// Events before this one were cleared
+    start: AtomicUsize,
+    entries: [Entry; ENTRIES],
+}
+
+impl Ring {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ENTRY: Entry = Entry {
+            time: AtomicU64::new(0),
+            event: AtomicUsize::new(0),
+            context: AtomicUsize::new(0),
+            a: AtomicUsize::new(0),
+            b: AtomicUsize::new(0),
+            c: AtomicUsize::new(0),
+        };
+        Self {
+            head: AtomicUsize::new(0),
+            start: AtomicUsize::new(0),
+            entries: [ENTRY; ENTRIES],
+        }
+    }
+
+    /// The range of events that can still be read
+    fn range(&self) -> (usize, usize) {
+        let head = self.head.load(Ordering::Acquire);
+        let start = self.start.load(Ordering::Relaxed);
+        (head.saturating_sub(ENTRIES).max(start), head)
+    }
+}
+
+static RINGS: [Ring; CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const RING: Ring = Ring::new();
+    [RING; CPUS]
+};
+
+/// Record `event` in the ring of the current CPU, use `tracepoint!` instead
+pub fn record(event: Event, a: usize, b: usize, c: usize) {
+    let ring = match RINGS.get(crate::cpu_id()) {
+        Some(ring) => ring,
+        None => return,
+    };
+
+    let head = ring.head.load(Ordering::Relaxed);
+    let entry = &ring.entries[head % ENTRIES];
+    // Invalidate the entry while it is being written
+    entry.event.store(0, Ordering::Relaxed);
+    entry.time.store(timestamp(), Ordering::Relaxed);
+    entry.context.store(crate::context::context_id().into(), Ordering::Relaxed);
+    entry.a.store(a, Ordering::Relaxed);
+    entry.b.store(b, Ordering::Relaxed);
+    entry.c.store(c, Ordering::Relaxed);
+    entry.event.store(event as usize, Ordering::Release);
+    ring.head.store(head.wrapping_add(1), Ordering::Release);
+}
+
+/// Discard the events recorded so far
+pub fn clear() {
+    for ring in RINGS.iter() {
+        ring.start.store(ring.head.load(Ordering::Acquire), Ordering::Relaxed);
+    }
+}
+
+fn format_entry(string: &mut String, cpu: usize, event: Event, entry: &Entry) {
+    let time = entry.time.load(Ordering::Relaxed);
+    let context = entry.context.load(Ordering::Relaxed);
+    let (a, b, c) = (entry.a.load(Ordering::Relaxed), entry.b.load(Ordering::Relaxed), entry.c.load(Ordering::Relaxed));
+
+    let _ = write!(string, "{:>20} cpu{} context {}: {}: ", time, cpu, context, event.name());
+    let _ = match event {
+        Event::SchedSwitch => writeln!(string, "prev={} prev_state={} next={}", a, SwitchState::from_raw(c).letter(), b),
+        Event::SchedWakeup => writeln!(string, "context={}", a),
+        Event::SyscallEnter => writeln!(string, "nr={:#x} arg={:#x}", a, b),
+        Event::SyscallExit => writeln!(string, "nr={:#x} ret={:#x}", a, b),
+        Event::PageFault => writeln!(string, "address={:#x} error={:#x}", a, b),
+        Event::FrameAlloc | Event::FrameFree => writeln!(string, "frame={:#x} count={}", a, b),
+        Event::Mmap | Event::Munmap => writeln!(string, "address={:#x} size={:#x}", a, b),
+        Event::Irq | Event::IrqExit => writeln!(string, "irq={}", a),
+        Event::Function => writeln!(string, "ip={:#x} parent={:#x}", a, b),
+        Event::WorkBegin => writeln!(string, "work={}", Work::name(a)),
+        Event::WorkEnd => writeln!(string, "work={} items={}", Work::name(a), b),
+        Event::Kprobe => writeln!(string, "address={:#x} rdi={:#x} rsi={:#x}", a, b, c),
+        Event::KprobeArgs => writeln!(string, "rdx={:#x} rcx={:#x} r8={:#x}", a, b, c),
+    };
+}
+
+/// Call `f` with the events recorded on `cpu`, or on all CPUs merged in timestamp order
+fn for_each_entry(cpu: Option<usize>, mut f: impl FnMut(usize, Event, &Entry)) {
+    // Entries of each ring are visited from the oldest to the newest
+    let mut cursors = [(0, 0); CPUS];
+    for (id, (cursor, ring)) in cursors.iter_mut().zip(RINGS.iter()).enumerate() {
+        if cpu.map_or(true, |cpu| cpu == id) {
+            *cursor = ring.range();
+        }
+    }
+
+    loop {
+        let next = cursors.iter().enumerate()
+            .filter(|(_, (next, end))| next < end)
+            .map(|(cpu, &(next, _))| (cpu, RINGS[cpu].entries[next % ENTRIES].time.load(Ordering::Relaxed)))
+            .min_by_key(|&(_, time)| time);
+        let cpu = match next {
+            Some((cpu, _)) => cpu,
+            None => break,
+        };
+
+        let entry = &RINGS[cpu].entries[cursors[cpu].0 % ENTRIES];
+        // Entries being written are skipped
+        if let Some(event) = Event::from_raw(entry.event.load(Ordering::Acquire)) {
+            f(cpu, event, entry);
+        }
+        cursors[cpu].0 += 1;
+    }
+}
+
+/// The events recorded on `cpu`, one per line, or those of all CPUs merged in timestamp order
+pub fn snapshot(cpu: Option<usize>) -> Vec<u8> {
+    let mut string = String::new();
+    for_each_entry(cpu, |cpu, event, entry| format_entry(&mut string, cpu, event, entry));
+    string.into_bytes()
+}
+
+// Record types of the Fuchsia trace format
+const FXT_INITIALIZATION: u64 = 1;
+const FXT_EVENT: u64 = 4;
+const FXT_KERNEL_OBJECT: u64 = 7;
+const FXT_SCHEDULER: u64 = 8;
+/// The magic number record, which starts a trace
+const FXT_MAGIC: u64 = 0x0016_5478_4604_0010;
+
+// Event types
+const FXT_INSTANT: u64 = 0;
+const FXT_DURATION_BEGIN: u64 = 2;
+const FXT_DURATION_END: u64 = 3;
+
+// Scheduler event types
+const FXT_CONTEXT_SWITCH: u64 = 1;
+const FXT_THREAD_WAKEUP: u64 = 2;
+
+// Argument types
+const FXT_UINT64: u64 = 4;
+const FXT_KOID: u64 = 8;
+
+// Kernel object types
+const ZX_OBJ_TYPE_PROCESS: u64 = 1;
+const ZX_OBJ_TYPE_THREAD: u64 = 2;
+
+// Thread states
+const ZX_THREAD_STATE_RUNNING: u64 = 1;
+const ZX_THREAD_STATE_SUSPENDED: u64 = 2;
+const ZX_THREAD_STATE_BLOCKED: u64 = 3;
+const ZX_THREAD_STATE_DEAD: u64 = 5;
+
+/// A trace in the Fuchsia trace format, made of little-endian 64-bit words. Strings are inline,
+/// and each context is both a process and a thread, whose koid is its ID.
+struct Fxt {
+    bytes: Vec<u8>,
+}
+
+impl Fxt {
+    fn word(&mut self, word: u64) {
+        self.bytes.extend_from_slice(&word.to_le_bytes());
+    }
+
+    /// Words taken by `string` inline
+    fn words(string: &str) -> u64 {
+        (string.len() as u64 + 7) / 8
+    }
+
+    /// The reference to `string` inline, after the fields of the record
+    fn reference(string: &str) -> u64 {
+        if string.is_empty() {
+            0
+        } else {
+            0x8000 | string.len() as u64
+        }
+    }
+
+    fn string(&mut self, string: &str) {
+        self.bytes.extend_from_slice(string.as_bytes());
+        self.bytes.resize(self.bytes.len() + (8 - string.len() % 8) % 8, 0);
+    }
+
+    fn argument(&mut self, kind: u64, name: &str, value: u64) {
+        self.word(kind | (2 + Self::words(name)) << 4 | Self::reference(name) << 16);
+        self.string(name);
+        self.word(value);
+    }
+
+    fn event(&mut self, kind: u64, time: u64, context: u64, category: &str, name: &str, arguments: &[(&str, u64)]) {
+        let size = 4 + Self::words(category) + Self::words(name)
+            + arguments.iter().map(|&(name, _)| 2 + Self::words(name)).sum::<u64>();
+        self.word(FXT_EVENT | size << 4 | kind << 16 | (arguments.len() as u64) << 20
+            | Self::reference(category) << 32 | Self::reference(name) << 48);
+        self.word(time);
+        self.word(context);
+        self.word(context);
+        self.string(category);
+        self.string(name);
+        for &(name, value) in arguments {
+            self.argument(FXT_UINT64, name, value);
+        }
+    }
+
+    /// Name the context `id`, as a process and as its thread
+    fn context(&mut self, id: u64, name: &str) {
+        self.word(FXT_KERNEL_OBJECT | (2 + Self::words(name)) << 4 | ZX_OBJ_TYPE_PROCESS << 16 | Self::reference(name) << 24);
+        self.word(id);
+        self.string(name);
+
+        let size = 2 + Self::words(name) + 2 + Self::words("process");
+        self.word(FXT_KERNEL_OBJECT | size << 4 | ZX_OBJ_TYPE_THREAD << 16 | Self::reference(name) << 24 | 1 << 40);
+        self.word(id);
+        self.string(name);
+        self.argument(FXT_KOID, "process", id);
+    }
+
+    fn switch(&mut self, time: u64, cpu: usize, state: SwitchState, prev: u64, next: u64) {
+        let state = match state {
+            SwitchState::Runnable => ZX_THREAD_STATE_RUNNING,
+            SwitchState::Blocked => ZX_THREAD_STATE_BLOCKED,
+            SwitchState::Stopped => ZX_THREAD_STATE_SUSPENDED,
+            SwitchState::Exited => ZX_THREAD_STATE_DEAD,
+        };
+        self.word(FXT_SCHEDULER | 4 << 4 | (cpu as u64) << 20 | state << 36 | FXT_CONTEXT_SWITCH << 60);
+        self.word(time);
+        self.word(prev);
+        self.word(next);
+    }
+
+    fn wakeup(&mut self, time: u64, cpu: usize, context: u64) {
+        self.word(FXT_SCHEDULER | 3 << 4 | (cpu as u64) << 20 | FXT_THREAD_WAKEUP << 60);
+        self.word(time);
+        self.word(context);
+    }
+}
+
+/// The events recorded on all CPUs as a Fuchsia trace, preceded by the names of the contexts that
+/// still exist, or `None` if the frequency of timestamps is not known
+pub fn fxt() -> Option<Vec<u8>> {
+    let frequency = journal::frequency()?;
+    let mut fxt = Fxt { bytes: Vec::new() };
+    fxt.word(FXT_MAGIC);
+    fxt.word(FXT_INITIALIZATION | 2 << 4);
+    fxt.word(frequency);
+
+    for (_, context_lock) in context::contexts().iter() {
+        let context = context_lock.read();
+        fxt.context(context.id.into() as u64, &context.name);
+    }
+
+    for_each_entry(None, |cpu, event, entry| {
+        let time = entry.time.load(Ordering::Relaxed);
+        let context = entry.context.load(Ordering::Relaxed) as u64;
+        let (a, b, c) = (entry.a.load(Ordering::Relaxed), entry.b.load(Ordering::Relaxed), entry.c.load(Ordering::Relaxed));
+        match event {
+            Event::SchedSwitch => fxt.switch(time, cpu, SwitchState::from_raw(c), a as u64, b as u64),
+            Event::SchedWakeup => fxt.wakeup(time, cpu, a as u64),
+            Event::Irq => fxt.event(FXT_DURATION_BEGIN, time, context, "irq", "irq", &[("irq", a as u64)]),
+            Event::IrqExit => fxt.event(FXT_DURATION_END, time, context, "irq", "irq", &[("irq", a as u64)]),
+            Event::WorkBegin => fxt.event(FXT_DURATION_BEGIN, time, context, "work", Work::name(a), &[]),
+            Event::WorkEnd => fxt.event(FXT_DURATION_END, time, context, "work", Work::name(a), &[("items", b as u64)]),
+            _ => fxt.event(FXT_INSTANT, time, context, "kernel", event.name(), &[("a", a as u64), ("b", b as u64), ("c", c as u64)]),
+        }
+    });
+    Some(fxt.bytes)
+}
+
+#[cfg(all(feature = "function_tracer", target_arch = "x86_64"))]
+mod function {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::{Event, ENABLED};
+
+    /// Set while the current CPU is recording a function, so that functions called to record it
+    /// are not recorded in turn
+    #[thread_local]
+    static RECORDING: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn function(ip: usize, parent: usize) {
+        if RECORDING.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        super::record(Event::Function, ip, parent, 0);
+        RECORDING.store(false, Ordering::Relaxed);
+    }
+
+    /// Called on entry to every instrumented function, after its frame is set up. Preserves the
+    /// registers holding the arguments of that function.
+    #[naked]
+    #[no_mangle]
+    pub unsafe extern "C" fn mcount() {
+        core::arch::asm!(
+            "
+            test qword ptr [rip + {enabled}], {bit}
+            jz 2f
+
+            push rax
+            push rcx
+            push rdx
+            push rsi
+            push rdi
+            push r8
+            push r9
+            push r10
+            push r11
+
+            // The address mcount returns to, and the one the function returns to
+            mov rdi, [rsp + 9 * 8]
+            mov rsi, [rbp + 8]
+            call {function}
+
+            pop r11
+            pop r10
+            pop r9
+            pop r8
+            pop rdi
+            pop rsi
+            pop rdx
+            pop rcx
+            pop rax
+        2:
+            ret
+            ",
+            enabled = sym ENABLED,
+            bit = const Event::Function.bit(),
+            function = sym function,
+            options(noreturn),
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! # Watchdog
+//! Once userspace opens `watchdog:`, it must write to it at least every `timeout` seconds, or the
+//! system is reset. The deadline is checked by the kernel on the timer interrupt, which logs the
+//! context that pinged last before resetting, so that a hung daemon can be told from a hung kernel.
+//!
+//! A hardware watchdog, such as the Intel TCO timer or an SP805, resets the system even if the
+//! kernel hangs with interrupts disabled. It counts down the timeout, or its longest one, and is
+//! restarted by each ping, and by the timer interrupt while the deadline has not passed. Without
+//! one, the NMI watchdog of the architecture, if any, calls `nmi` periodically, and resets the
+//! system once the timer interrupt has not run for `timeout` seconds.
+
+use alloc::string::String;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use spin::Once;
+
+use crate::log::{error, info};
+use crate::sync::Mutex;
+use crate::time::{self, NANOS_PER_SEC};
+
+/// Timer ticks between checks of the deadline, about half a second
+const POLL_TICKS: usize = 125;
+
+/// Timeout until changed through `watchdog:timeout`, in seconds
+pub const DEFAULT_TIMEOUT: u32 = 60;
+
+/// Longest timeout, in seconds
+pub const MAX_TIMEOUT: u32 = 3600;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Driver {
+    pub name: &'static str,
+    /// Longest timeout the hardware counts, in seconds
+    pub max_timeout: u32,
+    /// Start counting down from the timeout, in seconds, or restart with a new one
+    pub start: fn(u32),
+    pub stop: fn(),
+    /// Restart the count
+    pub ping: fn(),
+}
+
+/// The context that pinged last
+#[derive(Clone, Debug)]
+pub struct Owner {
+    pub pid: usize,
+    pub name: String,
+}
+
+static DRIVER: Once<Driver> = Once::new();
+
+static ARMED: AtomicBool = AtomicBool::new(false);
+/// Set once the deadline passed, so that it is only reported once
+static EXPIRED: AtomicBool = AtomicBool::new(false);
+static TIMEOUT: AtomicU32 = AtomicU32::new(DEFAULT_TIMEOUT);
+/// The monotonic clock when last pinged
+static LAST_PING: AtomicU64 = AtomicU64::new(0);
+static OWNER: Mutex<Option<Owner>> = Mutex::new(None);
+
+/// Whether the last reset was caused by the hardware watchdog
+static CAUSED_RESET: AtomicBool = AtomicBool::new(false);
+
+/// Checks of the deadline by the timer interrupt, for the NMI watchdog to see it still runs
+static POLLS: AtomicUsize = AtomicUsize::new(0);
+/// Whether the NMI watchdog runs, and the checks seen by it last, and for how long they have not
+/// changed, in nanoseconds
+static NMI: AtomicBool = AtomicBool::new(false);
+static NMI_POLLS: AtomicUsize = AtomicUsize::new(0);
+static NMI_STALLED: AtomicU64 = AtomicU64::new(0);
+
+#[thread_local]
+static POLL_COUNTDOWN: Cell<usize> = Cell::new(POLL_TICKS);
+
+/// Use a hardware watchdog, unless one is already used. `caused_reset` tells whether it reset the
+/// system last.
+pub fn register(driver: Driver, caused_reset: bool) {
+    if DRIVER.is_completed() {
+        return;
+    }
+    DRIVER.call_once(|| driver);
+    CAUSED_RESET.store(caused_reset, Ordering::Relaxed);
+    info!("Watchdog: {}, up to {} s", driver.name, driver.max_timeout);
+    if caused_reset {
+        error!("Watchdog: {} reset the system last", driver.name);
+    }
+}
+
+/// The hardware watchdog used
+pub fn driver() -> Option<Driver> {
+    DRIVER.get().copied()
+}
+
+/// Whether the last reset was caused by the hardware watchdog
+pub fn caused_reset() -> bool {
+    CAUSED_RESET.load(Ordering::Relaxed)
+}
+
+/// Tell that the NMI watchdog of the architecture runs
+pub fn nmi_started() {
+    NMI.store(true, Ordering::Relaxed);
+}
+
+/// Whether the NMI watchdog of the architecture runs
+pub fn nmi_running() -> bool {
+    NMI.load(Ordering::Relaxed)
+}
+
+pub fn armed() -> bool {
+    ARMED.load(Ordering::SeqCst)
+}
+
+pub fn timeout() -> u32 {
+    TIMEOUT.load(Ordering::Relaxed)
+}
+
+/// The context that pinged last, and how many seconds ago
+pub fn last_ping() -> Option<(Owner, u64)> {
+    let owner = OWNER.lock().clone()?;
+    Some((owner, since_ping() / NANOS_PER_SEC as u64))
+}
+
+fn since_ping() -> u64 {
+    (time::monotonic() as u64).saturating_sub(LAST_PING.load(Ordering::SeqCst))
+}
+
+/// The timeout to count on the hardware watchdog
+fn hardware_timeout(driver: &Driver) -> u32 {
+    timeout().min(driver.max_timeout)
+}
+
+/// Start counting down the timeout, pinged by `owner`
+pub fn start(owner: Owner) {
+    ping(owner);
+    EXPIRED.store(false, Ordering::SeqCst);
+    ARMED.store(true, Ordering::SeqCst);
+    if let Some(driver) = DRIVER.get() {
+        (driver.start)(hardware_timeout(driver));
+    }
+}
+
+/// Stop counting down, as userspace is shutting down on purpose
+pub fn stop() {
+    ARMED.store(false, Ordering::SeqCst);
+    if let Some(driver) = DRIVER.get() {
+        (driver.stop)();
+    }
+}
+
+/// Restart the count, as `owner` is alive
+pub fn ping(owner: Owner) {
+    LAST_PING.store(time::monotonic() as u64, Ordering::SeqCst);
+    *OWNER.lock() = Some(owner);
+    if let Some(driver) = DRIVER.get().filter(|_| armed()) {
+        (driver.ping)();
+    }
+}
+
+/// Stop the hardware watchdog before suspend to RAM, leaving the deadline as is
+pub fn suspend() {
+    if let Some(driver) = DRIVER.get().filter(|_| armed()) {
+        (driver.stop)();
+    }
+}
+
+/// Start the hardware watchdog again after suspend to RAM
+pub fn resume() {
+    if let Some(driver) = DRIVER.get().filter(|_| armed()) {
+        (driver.start)(hardware_timeout(driver));
+    }
+}
+
+/// Change the timeout, in seconds. Returns false if it is out of range.
+pub fn set_timeout(timeout: u32) -> bool {
+    if !(1..=MAX_TIMEOUT).contains(&timeout) {
+        return false;
+    }
+    TIMEOUT.store(timeout, Ordering::Relaxed);
+    if let Some(driver) = DRIVER.get().filter(|_| armed()) {
+        (driver.start)(hardware_timeout(driver));
+    }
+    true
+}
+
+extern "C" {
+    fn kreset() -> !;
+}
+
+/// Log the context that pinged last, and reset the system
+fn expire(reason: &str) {
+    if EXPIRED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let seconds = u64::from(timeout());
+    // The lock may be held by a CPU that hung
+    match OWNER.try_lock().as_deref() {
+        Some(Some(owner)) => error!(
+            "Watchdog: {}, last pinged more than {} s ago by {} ({}), resetting",
+            reason, seconds, owner.pid, owner.name
+        ),
+        _ => error!("Watchdog: {}, last pinged more than {} s ago, resetting", reason, seconds),
+    }
+    unsafe { kreset() };
+}
+
+/// Check the deadline every `POLL_TICKS`, and restart the hardware watchdog if its longest timeout
+/// is shorter. Called on each timer tick.
+pub fn poll() {
+    let countdown = POLL_COUNTDOWN.get();
+    if countdown > 1 {
+        POLL_COUNTDOWN.set(countdown - 1);
+        return;
+    }
+    POLL_COUNTDOWN.set(POLL_TICKS);
+    if crate::cpu_id() != 0 {
+        return;
+    }
+
+    POLLS.fetch_add(1, Ordering::Relaxed);
+    if !armed() {
+        return;
+    }
+    if since_ping() > u64::from(timeout()) * NANOS_PER_SEC as u64 {
+        expire("not pinged by userspace");
+    } else if let Some(driver) = DRIVER.get().filter(|driver| timeout() > driver.max_timeout) {
+        (driver.ping)();
+    }
+}
+
+/// Reset the system once the timer interrupt has not checked the deadline for the timeout, called
+/// by the NMI watchdog every `period` nanoseconds. Only used without a hardware watchdog.
+pub fn nmi(period: u64) {
+    if !armed() || DRIVER.is_completed() {
+        return;
+    }
+    let polls = POLLS.load(Ordering::Relaxed);
+    if NMI_POLLS.swap(polls, Ordering::Relaxed) != polls {
+        NMI_STALLED.store(0, Ordering::Relaxed);
+        return;
+    }
+    let stalled = NMI_STALLED.fetch_add(period, Ordering::Relaxed) + period;
+    if stalled > u64::from(timeout()) * NANOS_PER_SEC as u64 {
+        expire("the timer interrupt stopped");
+    }
+}